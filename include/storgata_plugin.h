@@ -0,0 +1,71 @@
+/*
+ * The C ABI of StorgataDB plugins, version 1.
+ *
+ * A plugin is a shared library exporting the three functions below, loaded with --plugin.
+ * Its commands are declared as reads, run against the local replica of the node the client
+ * is connected to, or as writes, replicated through Raft and run on every node. Write commands
+ * must be deterministic (no clocks, randomness or I/O) and may only change the key at their key
+ * position. Every node of a group must load the same plugins.
+ *
+ * The node calls the plugin from several threads at once; plugins must not keep state across
+ * calls other than immutable data.
+ */
+#ifndef STORGATA_PLUGIN_H
+#define STORGATA_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define STORGATA_PLUGIN_ABI_VERSION 1
+
+#define STORGATA_KIND_READ 0
+#define STORGATA_KIND_WRITE 1
+
+#define STORGATA_REPLY_NIL 0
+#define STORGATA_REPLY_STATUS 1
+#define STORGATA_REPLY_BULK 2
+#define STORGATA_REPLY_INTEGER 3
+#define STORGATA_REPLY_ERROR 4
+
+typedef struct {
+    /* case-insensitive, a null name ends the list of commands */
+    const char *name;
+    uint32_t kind;
+    /* as in Redis, counting the name: N for exactly N arguments, -N for at least N */
+    int32_t arity;
+    /* position of the key, counting the name, 0 for none */
+    int32_t key_index;
+} StorgataCommandSpec;
+
+typedef struct {
+    const uint8_t *data;
+    size_t len;
+} StorgataBuf;
+
+typedef struct {
+    void *ctx;
+    /* 1 and the string value at key, valid until the next callback; 0 if missing; -1 on error */
+    int32_t (*get)(void *ctx, const uint8_t *key, size_t key_len, StorgataBuf *out);
+    /* 0, or -1 on error, such as a read command writing */
+    int32_t (*put)(void *ctx, const uint8_t *key, size_t key_len, const uint8_t *value,
+                   size_t value_len);
+    /* 1 if the key was deleted, 0 if it is missing, -1 on error */
+    int32_t (*del)(void *ctx, const uint8_t *key, size_t key_len);
+    /* set the reply to the client, the last one set is sent */
+    void (*reply)(void *ctx, uint32_t kind, int64_t integer, const uint8_t *data, size_t len);
+} StorgataHost;
+
+/* Must return STORGATA_PLUGIN_ABI_VERSION */
+uint32_t storgata_plugin_abi_version(void);
+
+/* The commands of the plugin, valid for as long as the plugin is loaded */
+const StorgataCommandSpec *storgata_plugin_commands(void);
+
+/*
+ * Run a command, the name as declared. Once a callback returns -1 the command fails with the
+ * error of the node, whatever the plugin replies.
+ */
+void storgata_plugin_execute(const StorgataHost *host, const char *name, const StorgataBuf *args,
+                             size_t nargs);
+
+#endif
@@ -0,0 +1,386 @@
+//! Access control lists, in the spirit of Redis ACLs. A client authenticates as a user with AUTH
+//! and may then only run the command categories and touch the keys its rules allow. Permissions
+//! are checked by the connection before a command reaches the storage or the sync layer.
+//!
+//! Users are loaded from an ACL file with one `user <name> <rule>...` line per user, and can be
+//! changed at runtime with ACL SETUSER. Like in Redis, they are local to the node and not replicated.
+
+use crate::pubsub::glob_match;
+use crate::resp_codec::RespValue;
+use anyhow::Context;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// The user new connections are authenticated as, if it is enabled and needs no password
+pub(crate) const DEFAULT_USER: &str = "default";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Category {
+    Read,
+    Write,
+    Admin,
+    PubSub,
+}
+
+const CATEGORIES: [Category; 4] = [
+    Category::Read,
+    Category::Write,
+    Category::Admin,
+    Category::PubSub,
+];
+
+impl Category {
+    fn parse(name: &str) -> Option<Self> {
+        CATEGORIES
+            .into_iter()
+            .find(|category| category.name() == name)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Category::Read => "read",
+            Category::Write => "write",
+            Category::Admin => "admin",
+            Category::PubSub => "pubsub",
+        }
+    }
+}
+
+/// An ACL subcommand, run against the node the client is connected to
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum AclOp {
+    WhoAmI,
+    List,
+    GetUser(String),
+    // User, Rules
+    SetUser(String, Vec<String>),
+}
+
+impl Debug for AclOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AclOp::WhoAmI => write!(f, "WHOAMI"),
+            AclOp::List => write!(f, "LIST"),
+            AclOp::GetUser(name) => write!(f, "GETUSER {}", name),
+            // the rules may hold passwords, keep them out of the logs
+            AclOp::SetUser(name, _) => write!(f, "SETUSER {} ...", name),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct User {
+    enabled: bool,
+    // any password is accepted
+    nopass: bool,
+    // SHA-256 of the passwords, hex encoded
+    passwords: BTreeSet<String>,
+    categories: BTreeSet<Category>,
+    // commands allowed or denied on top of the categories, lowercase
+    allowed_commands: BTreeSet<String>,
+    denied_commands: BTreeSet<String>,
+    key_patterns: Vec<String>,
+}
+
+impl User {
+    /// Apply one rule of the Redis ACL syntax
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let syntax_error = || format!("Error in ACL SETUSER modifier '{}': Syntax error", rule);
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".to_string()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" | "+@all" => {
+                self.categories = CATEGORIES.into_iter().collect();
+                self.denied_commands.clear();
+            }
+            "nocommands" | "-@all" => {
+                self.categories.clear();
+                self.allowed_commands.clear();
+            }
+            "reset" => *self = User::default(),
+            _ => {
+                // passwords and key patterns are case sensitive
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.nopass = false;
+                    self.passwords.insert(hash_password(password));
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    self.passwords.remove(&hash_password(password));
+                } else if let Some(hash) = rule.strip_prefix('#') {
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(syntax_error());
+                    }
+                    self.nopass = false;
+                    self.passwords.insert(hash.to_lowercase());
+                } else if let Some(pattern) = rule.strip_prefix('~') {
+                    self.key_patterns.push(pattern.to_string());
+                } else if let Some(name) = rule.strip_prefix("+@") {
+                    let category =
+                        Category::parse(&name.to_lowercase()).ok_or_else(syntax_error)?;
+                    self.categories.insert(category);
+                } else if let Some(name) = rule.strip_prefix("-@") {
+                    let category =
+                        Category::parse(&name.to_lowercase()).ok_or_else(syntax_error)?;
+                    self.categories.remove(&category);
+                } else if let Some(command) = rule.strip_prefix('+').filter(|c| !c.is_empty()) {
+                    let command = command.to_lowercase();
+                    self.denied_commands.remove(&command);
+                    self.allowed_commands.insert(command);
+                } else if let Some(command) = rule.strip_prefix('-').filter(|c| !c.is_empty()) {
+                    let command = command.to_lowercase();
+                    self.allowed_commands.remove(&command);
+                    self.denied_commands.insert(command);
+                } else {
+                    return Err(syntax_error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    fn commands(&self) -> String {
+        let mut rules = if self.categories.len() == CATEGORIES.len() {
+            vec!["+@all".to_string()]
+        } else {
+            let mut rules = vec!["-@all".to_string()];
+            rules.extend(self.categories.iter().map(|c| format!("+@{}", c.name())));
+            rules
+        };
+        rules.extend(self.allowed_commands.iter().map(|c| format!("+{}", c)));
+        rules.extend(self.denied_commands.iter().map(|c| format!("-{}", c)));
+        rules.join(" ")
+    }
+
+    /// The rules that recreate the user, as listed by ACL LIST
+    fn rules(&self) -> String {
+        let mut rules: Vec<String> = self.flags().into_iter().map(String::from).collect();
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        rules.extend(
+            self.key_patterns
+                .iter()
+                .map(|pattern| format!("~{}", pattern)),
+        );
+        rules.push(self.commands());
+        rules.join(" ")
+    }
+
+    fn can_run(&self, command: &str, category: Category) -> bool {
+        if self.denied_commands.contains(command) {
+            return false;
+        }
+        self.allowed_commands.contains(command) || self.categories.contains(&category)
+    }
+
+    fn can_access(&self, key: &[u8]) -> bool {
+        self.key_patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), key))
+    }
+}
+
+fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The users of the node
+pub(crate) struct Acl {
+    users: RwLock<BTreeMap<String, Arc<User>>>,
+    // bumped by every change of the users, for the connections to read theirs again
+    version: AtomicU64,
+}
+
+/// The user of a connection as it last read it, so that checking its commands takes no lock
+/// until the users change
+pub(crate) struct Permissions {
+    version: u64,
+    name: String,
+    // None if there was no such user
+    user: Option<Arc<User>>,
+}
+
+impl Default for Acl {
+    /// Only the default user, which may run everything without a password, like a Redis
+    /// server without ACLs
+    fn default() -> Self {
+        let mut default_user = User::default();
+        for rule in ["on", "nopass", "allkeys", "allcommands"] {
+            default_user.apply(rule).unwrap();
+        }
+        Self {
+            users: RwLock::new(BTreeMap::from([(
+                DEFAULT_USER.to_string(),
+                Arc::new(default_user),
+            )])),
+            version: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Acl {
+    /// Load the users of an ACL file. The default user keeps its permissive rules unless the
+    /// file defines it.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read ACL file {}", path.display()))?;
+        let acl = Self::default();
+        for (number, line) in content.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                None => continue,
+                Some(word) if word.starts_with('#') => continue,
+                Some("user") => {}
+                Some(_) => anyhow::bail!(
+                    "{}:{}: line should start with 'user'",
+                    path.display(),
+                    number + 1
+                ),
+            }
+            let Some(name) = words.next() else {
+                anyhow::bail!("{}:{}: missing user name", path.display(), number + 1);
+            };
+            let mut user = User::default();
+            for rule in words {
+                user.apply(rule)
+                    .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), number + 1, e))?;
+            }
+            acl.users
+                .write()
+                .unwrap()
+                .insert(name.to_string(), Arc::new(user));
+        }
+        Ok(acl)
+    }
+
+    /// The user a new connection starts as, if it does not have to authenticate
+    pub(crate) fn auto_login(&self) -> Option<String> {
+        let users = self.users.read().unwrap();
+        let user = users.get(DEFAULT_USER)?;
+        (user.enabled && user.nopass).then(|| DEFAULT_USER.to_string())
+    }
+
+    pub(crate) fn authenticate(&self, name: &str, password: &str) -> bool {
+        let users = self.users.read().unwrap();
+        users.get(name).is_some_and(|user| {
+            user.enabled && (user.nopass || user.passwords.contains(&hash_password(password)))
+        })
+    }
+
+    /// Check that the user may run the command on the keys, returning the error to reply otherwise.
+    /// The user is read again into `permissions` only if the users changed since.
+    pub(crate) fn check(
+        &self,
+        permissions: &mut Option<Permissions>,
+        name: &str,
+        command: &str,
+        category: Category,
+        keys: &[&Vec<u8>],
+    ) -> Result<(), String> {
+        let version = self.version.load(Ordering::Acquire);
+        let read = permissions
+            .as_ref()
+            .is_some_and(|read| read.version == version && read.name == name);
+        if !read {
+            *permissions = Some(Permissions {
+                version,
+                name: name.to_string(),
+                user: self.users.read().unwrap().get(name).cloned(),
+            });
+        }
+        // a user disabled or deleted after the client authenticated loses every permission
+        let user = permissions
+            .as_ref()
+            .and_then(|read| read.user.as_deref())
+            .filter(|user| user.enabled);
+        if !user.is_some_and(|user| user.can_run(command, category)) {
+            return Err(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                name, command
+            ));
+        }
+        if user.is_some_and(|user| keys.iter().any(|key| !user.can_access(key))) {
+            return Err("NOPERM No permissions to access a key".to_string());
+        }
+        Ok(())
+    }
+
+    /// Create the user if needed and apply the rules, all of them or none
+    pub(crate) fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users
+            .get(name)
+            .map(|user| User::clone(user))
+            .unwrap_or_default();
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        users.insert(name.to_string(), Arc::new(user));
+        self.version.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// The properties of the user, as replied to ACL GETUSER
+    pub(crate) fn get_user(&self, name: &str) -> Option<RespValue> {
+        let users = self.users.read().unwrap();
+        let user = users.get(name)?;
+        let bulk = |s: String| RespValue::BulkString(Some(Bytes::from(s)));
+        let field =
+            |name: &'static str| RespValue::BulkString(Some(Bytes::from_static(name.as_bytes())));
+        let array = |items: Vec<String>| RespValue::Array(items.into_iter().map(bulk).collect());
+        Some(RespValue::Map(vec![
+            (
+                field("flags"),
+                array(user.flags().into_iter().map(String::from).collect()),
+            ),
+            (
+                field("passwords"),
+                array(user.passwords.iter().cloned().collect()),
+            ),
+            (field("commands"), bulk(user.commands())),
+            (
+                field("keys"),
+                bulk(
+                    user.key_patterns
+                        .iter()
+                        .map(|pattern| format!("~{}", pattern))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+            ),
+        ]))
+    }
+
+    /// Every user with its rules, as replied to ACL LIST
+    pub(crate) fn list(&self) -> Vec<String> {
+        let users = self.users.read().unwrap();
+        users
+            .iter()
+            .map(|(name, user)| format!("user {} {}", name, user.rules()))
+            .collect()
+    }
+}
@@ -0,0 +1,124 @@
+//! ADMIN BROADCAST: run an admin command, such as CONFIG SET, on every node of the group and
+//! reply with the reply of each node, so that a fleet-wide change takes a single call. ADMIN
+//! MAINTENANCE is in crate::maintenance.
+//!
+//! Admin commands are local to the node they run on and not replicated, so the node sends the
+//! command to each peer as a client would, on the kv address given with `--peer-kv-addr`, and
+//! runs it itself the same way over an in-memory connection. The command runs on every node as
+//! the user the client authenticated as, with the same password: like in Redis, users are local
+//! to the node, and a node not knowing the user replies with its error.
+
+use crate::resp_codec::{RespCodec, RespValue};
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// A node not replying within this time is reported as unreachable
+pub(crate) const BROADCAST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An ADMIN subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum AdminOp {
+    // Name and arguments of the command to run on every node
+    Broadcast(Vec<Vec<u8>>),
+    // On or off
+    Maintenance(bool),
+}
+
+/// A peer of the group, as ADMIN BROADCAST reaches it
+#[derive(Clone, Debug)]
+pub(crate) struct Peer {
+    // its Raft address, which names the node as in CLUSTER KEYOWNER
+    pub(crate) node: String,
+    // where it serves clients
+    pub(crate) kv_addr: String,
+}
+
+/// Pair the Raft addresses of the peers with the kv addresses they serve clients on, given in
+/// the same order, leaving this node out. No peer is reached without kv addresses.
+pub(crate) fn peers(
+    self_addr: &str,
+    peer_addr: &[String],
+    peer_kv_addr: &[String],
+) -> anyhow::Result<Vec<Peer>> {
+    if peer_kv_addr.is_empty() {
+        return Ok(Vec::new());
+    }
+    if peer_kv_addr.len() != peer_addr.len() {
+        bail!(
+            "--peer-kv-addr needs one address per --peer-addr, got {} for {}",
+            peer_kv_addr.len(),
+            peer_addr.len()
+        );
+    }
+    Ok(peer_addr
+        .iter()
+        .zip(peer_kv_addr)
+        .filter(|(node, _)| *node != self_addr)
+        .map(|(node, kv_addr)| Peer {
+            node: node.clone(),
+            kv_addr: kv_addr.clone(),
+        })
+        .collect())
+}
+
+/// Run the command on the peer, returning its reply or an error reply if it cannot be reached
+pub(crate) async fn call_peer(
+    peer: &Peer,
+    credentials: Option<&(String, String)>,
+    args: &[Vec<u8>],
+) -> RespValue {
+    let reply = tokio::time::timeout(BROADCAST_TIMEOUT, async {
+        let stream = TcpStream::connect(&peer.kv_addr).await?;
+        stream.set_nodelay(true)?;
+        call(stream, credentials, args).await
+    })
+    .await
+    .unwrap_or_else(|_| Err(anyhow!("no reply after {:?}", BROADCAST_TIMEOUT)));
+    reply.unwrap_or_else(|e| {
+        RespValue::Error(format!("ERR could not reach {}: {}", peer.kv_addr, e))
+    })
+}
+
+/// Authenticate with the credentials, if any, then run the command and read its reply
+pub(crate) async fn call<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    credentials: Option<&(String, String)>,
+    args: &[Vec<u8>],
+) -> anyhow::Result<RespValue> {
+    let mut framed = Framed::new(stream, RespCodec::new());
+    if let Some((user, password)) = credentials {
+        framed
+            .send(command(&[b"AUTH", user.as_bytes(), password.as_bytes()]))
+            .await?;
+        if let error @ RespValue::Error(_) = next(&mut framed).await? {
+            return Ok(error);
+        }
+    }
+    let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+    framed.send(command(&args)).await?;
+    next(&mut framed).await
+}
+
+pub(crate) async fn next<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, RespCodec>,
+) -> anyhow::Result<RespValue> {
+    match framed.next().await {
+        Some(Ok(reply)) => Ok(reply),
+        Some(Err(e)) => Err(anyhow!("{}", e)),
+        None => Err(anyhow!("connection closed")),
+    }
+}
+
+pub(crate) fn command(args: &[&[u8]]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::BulkString(Some(Bytes::copy_from_slice(arg))))
+            .collect(),
+    )
+}
@@ -0,0 +1,290 @@
+//! A frozen snapshot of the keyspace served read-only on a port of its own, so that long-running
+//! scans and exports neither contend with the live traffic nor see the keyspace change under them.
+//!
+//! SNAPSHOT TAKE freezes the data files right away, or right after the entry of a chosen index is
+//! applied, the index being the number of entries the node applied (`raft_entries_applied` in
+//! INFO). The files are hard-linked into a directory of the snapshot, which keeps them if the
+//! storage removes them, and the file still appended to is then replaced by a copy of its part
+//! written before the snapshot, off the apply path. The storage engine is opened on that
+//! directory, so the analytics port serves the reads of the live port with the same code.
+//!
+//! A snapshot expires after `--analytics-ttl-secs`, or is released by SNAPSHOT RELEASE or by
+//! taking the next one: its connections are closed and its files removed, so that a forgotten
+//! consumer never holds on to the disk space of files the storage removed.
+
+use crate::keyspace::Snapshot;
+use bitcask_engine_rs::bitcask::BitCask;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// A SNAPSHOT subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum SnapshotOp {
+    // Index to take the snapshot at, now if None
+    Take(Option<u64>),
+    Release,
+    // Seconds the read view of the connection lasts, the most allowed if None
+    Open(Option<u64>),
+    // Handle of the view, that of the connection if None
+    Close(Option<u64>),
+}
+
+#[derive(Default)]
+struct State {
+    // the snapshot served
+    current: Option<Arc<Frozen>>,
+    // the snapshot being taken, replacing the current one once ready
+    next: Next,
+}
+
+#[derive(Default)]
+enum Next {
+    #[default]
+    None,
+    // taken once the entry of the index is applied
+    Pending(u64),
+    // the files are frozen, the one still appended to is being copied
+    Preparing {
+        seq: u64,
+        index: u64,
+    },
+    Failed(u64, String),
+}
+
+impl State {
+    fn release_current(&mut self) {
+        if let Some(frozen) = self.current.take() {
+            frozen.released.send_replace(true);
+        }
+    }
+}
+
+/// A snapshot ready to be served
+pub(crate) struct Frozen {
+    pub(crate) storage: BitCask,
+    pub(crate) dir: PathBuf,
+    seq: u64,
+    index: u64,
+    taken: Instant,
+    expires: Instant,
+    released: watch::Sender<bool>,
+}
+
+impl Frozen {
+    /// Resolves once the snapshot expired or was released, its connections are closed then
+    pub(crate) async fn closed(&self) {
+        let mut released = self.released.subscribe();
+        let _ =
+            tokio::time::timeout_at(self.expires, released.wait_for(|released| *released)).await;
+    }
+}
+
+impl Drop for Frozen {
+    // the last connection reading the snapshot is gone
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            warn!("Analytics: could not remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+pub(crate) struct Analytics {
+    // where the directories of the snapshots are
+    dir: PathBuf,
+    ttl: Duration,
+    state: Mutex<State>,
+    // names the directory of the next snapshot, a released one may still be read
+    next_seq: AtomicU64,
+}
+
+impl Analytics {
+    pub(crate) fn new(dir: &Path, ttl: Duration) -> std::io::Result<Self> {
+        // the snapshots of a previous run are not served anymore
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            ttl,
+            state: Mutex::new(State::default()),
+            next_seq: AtomicU64::new(0),
+        })
+    }
+
+    /// Take a snapshot at the index, or right away. `applied` is the number of entries applied
+    /// so far. The current snapshot is served until the new one is ready.
+    pub(crate) fn take(
+        self: &Arc<Self>,
+        index: Option<u64>,
+        applied: u64,
+        data_dir: &Path,
+    ) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.next, Next::Pending(_) | Next::Preparing { .. }) {
+            return Err("ERR a snapshot is already being taken".to_string());
+        }
+        match index {
+            Some(index) if index <= applied => Err(format!(
+                "ERR entry {} is already applied, the last applied is {}",
+                index, applied
+            )),
+            Some(index) => {
+                info!(
+                    "Analytics: taking a snapshot once entry {} is applied",
+                    index
+                );
+                state.next = Next::Pending(index);
+                Ok(())
+            }
+            None => {
+                self.freeze(&mut state, applied, data_dir);
+                Ok(())
+            }
+        }
+    }
+
+    /// Called by the apply path once the entry of the index is applied
+    pub(crate) fn applied(self: &Arc<Self>, index: u64, data_dir: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.next, Next::Pending(pending) if pending == index) {
+            self.freeze(&mut state, index, data_dir);
+        }
+    }
+
+    /// Release the current snapshot, and give up on the next one
+    pub(crate) fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(frozen) = &state.current {
+            info!(
+                "Analytics: releasing the snapshot at entry {}",
+                frozen.index
+            );
+        }
+        state.release_current();
+        state.next = Next::None;
+    }
+
+    /// The snapshot to serve, if one is ready
+    pub(crate) fn current(&self) -> Option<Arc<Frozen>> {
+        self.state.lock().unwrap().current.clone()
+    }
+
+    pub(crate) fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut info = match &state.current {
+            None => "snapshot_status:none\r\n".to_string(),
+            Some(frozen) => format!(
+                "snapshot_status:ready\r\nsnapshot_index:{}\r\nsnapshot_age_secs:{}\r\nsnapshot_expires_in_secs:{}\r\n",
+                frozen.index,
+                frozen.taken.elapsed().as_secs(),
+                frozen.expires.saturating_duration_since(Instant::now()).as_secs(),
+            ),
+        };
+        match &state.next {
+            Next::None => {}
+            Next::Pending(index) => info.push_str(&format!(
+                "next_snapshot_status:pending\r\nnext_snapshot_index:{}\r\n",
+                index
+            )),
+            Next::Preparing { index, .. } => info.push_str(&format!(
+                "next_snapshot_status:preparing\r\nnext_snapshot_index:{}\r\n",
+                index
+            )),
+            Next::Failed(index, e) => info.push_str(&format!(
+                "next_snapshot_status:failed\r\nnext_snapshot_index:{}\r\nnext_snapshot_error:{}\r\n",
+                index, e
+            )),
+        }
+        info
+    }
+
+    /// Freeze the data files, in the apply path when the snapshot is taken at an index
+    fn freeze(self: &Arc<Self>, state: &mut State, index: u64, data_dir: &Path) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let dir = self.dir.join(seq.to_string());
+        let taken = Instant::now();
+        match Snapshot::take(data_dir).and_then(|snapshot| snapshot.link(&dir)) {
+            Ok(snapshot) => {
+                state.next = Next::Preparing { seq, index };
+                let analytics = self.clone();
+                tokio::task::spawn_blocking(move || {
+                    analytics.prepare(snapshot, dir, seq, index, taken)
+                });
+            }
+            Err(e) => {
+                warn!(
+                    "Analytics: could not take a snapshot at entry {}: {}",
+                    index, e
+                );
+                let _ = std::fs::remove_dir_all(&dir);
+                state.next = Next::Failed(index, e.to_string());
+            }
+        }
+    }
+
+    /// Copy the file still appended to and open the storage of the snapshot
+    fn prepare(
+        self: Arc<Self>,
+        snapshot: Snapshot,
+        dir: PathBuf,
+        seq: u64,
+        index: u64,
+        taken: Instant,
+    ) {
+        let storage = snapshot
+            .detach()
+            .map_err(|e| e.to_string())
+            .and_then(|()| BitCask::new(&dir).map_err(|e| e.to_string()));
+        let mut state = self.state.lock().unwrap();
+        if !matches!(state.next, Next::Preparing { seq: preparing, .. } if preparing == seq) {
+            // released while it was prepared
+            let _ = std::fs::remove_dir_all(&dir);
+            return;
+        }
+        match storage {
+            Ok(storage) => {
+                info!("Analytics: serving the snapshot at entry {}", index);
+                let expires = taken + self.ttl;
+                state.release_current();
+                state.current = Some(Arc::new(Frozen {
+                    storage,
+                    dir,
+                    seq,
+                    index,
+                    taken,
+                    expires,
+                    released: watch::channel(false).0,
+                }));
+                state.next = Next::None;
+                let analytics = self.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep_until(expires).await;
+                    analytics.expire(seq);
+                });
+            }
+            Err(e) => {
+                warn!(
+                    "Analytics: could not prepare the snapshot at entry {}: {}",
+                    index, e
+                );
+                let _ = std::fs::remove_dir_all(&dir);
+                state.next = Next::Failed(index, e);
+            }
+        }
+    }
+
+    fn expire(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(frozen) = state.current.as_ref().filter(|frozen| frozen.seq == seq) {
+            info!("Analytics: the snapshot at entry {} expired", frozen.index);
+            state.release_current();
+        }
+    }
+}
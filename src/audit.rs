@@ -0,0 +1,90 @@
+//! The audit log: a JSON line per write committed for a client of the node, with when, who,
+//! what and at which index of the log, for the data modification trails compliance asks for.
+//! It is kept apart from the debug log of `logger.rs`, in files of its own under --audit-dir,
+//! rotated on --audit-rotation.
+//!
+//! Each node logs the writes of its own clients once they are committed and applied, whatever
+//! their outcome: those aborted by NX or XX, or failing as they apply, are data modification
+//! attempts too. The keys under secure delete are logged without their key.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::warn;
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_appender::rolling::{self, RollingFileAppender};
+use uuid::Uuid;
+
+/// When the audit log moves on to a new file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub(crate) enum Rotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// A committed write, as the audit log records it
+pub(crate) struct Record<'a> {
+    pub(crate) client_id: u64,
+    pub(crate) addr: String,
+    pub(crate) name: String,
+    pub(crate) user: Option<&'a str>,
+    pub(crate) command: &'static str,
+    pub(crate) db: u32,
+    // None for a command without a key, or one under secure delete
+    pub(crate) key: Option<&'a [u8]>,
+    pub(crate) request_id: [u8; 16],
+    // the index of the log the node applied once the write was
+    pub(crate) index: u64,
+    pub(crate) outcome: &'static str,
+}
+
+pub(crate) struct Audit {
+    // written by a thread of its own, which never drops a line when it falls behind
+    writer: Mutex<NonBlocking>,
+    // flushes what is left to write once the node stops
+    _guard: WorkerGuard,
+}
+
+impl Audit {
+    pub(crate) fn open(dir: &Path, rotation: Rotation) -> anyhow::Result<Self> {
+        let rotation = match rotation {
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+            Rotation::Never => rolling::Rotation::NEVER,
+        };
+        let appender = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix("audit")
+            .filename_suffix("log")
+            .build(dir)?;
+        let (writer, guard) = NonBlockingBuilder::default().lossy(false).finish(appender);
+        Ok(Self {
+            writer: Mutex::new(writer),
+            _guard: guard,
+        })
+    }
+
+    pub(crate) fn record(&self, record: Record) {
+        let line = json!({
+            "timestamp": crate::value::now(),
+            "client_id": record.client_id,
+            "addr": record.addr,
+            "name": record.name,
+            "user": record.user,
+            "command": record.command,
+            "db": record.db,
+            "key": record.key.map(|key| key.escape_ascii().to_string()),
+            "request_id": Uuid::from_bytes(record.request_id).to_string(),
+            "index": record.index,
+            "outcome": record.outcome,
+        });
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(writer, "{}", line) {
+            warn!("Audit: could not record a write: {}", e);
+        }
+    }
+}
@@ -0,0 +1,746 @@
+//! Scheduled backups, so that no cron job outside the node is needed. On the times of a
+//! cron-like schedule, in UTC, or when BGSAVE asks for one, the node takes a snapshot of its data
+//! files as for analytics and copies it to a directory of its own under `--backup-dir`, named
+//! after the unix time of the backup. A backup is written under a temporary name and renamed once
+//! complete, so a directory named after a time always holds a whole backup; the oldest ones are
+//! removed past `--backup-retain`. A node started on a copy of a full backup serves the keyspace
+//! as of the backup: the data files hold the index of the last log entry the node applied, so it
+//! resumes the log from there.
+//!
+//! With `--backup-full-every`, the backups between two full ones are incremental: the data files
+//! are append-only, so a backup only copies what was appended to them since the previous backup,
+//! and an `INCREMENT` file names that backup and the offset each copy starts at. An incremental
+//! backup only builds on a backup taken since the node started, as the files cannot have been
+//! replaced while it runs, and the retention keeps the backups the kept ones build on.
+//! `--restore-from` lays a backup and those it builds on back into a data directory.
+//!
+//! With `--backup-s3-url`, each backup is also uploaded to an S3-compatible store, under a prefix
+//! named after its time, followed by a `SHA256SUMS` manifest of its files: a backup without one
+//! is incomplete. The store keeps as many backups as the local directory, and `--restore-from`
+//! bootstraps a node with no data yet from one of them, checking every file against the manifest.
+
+use crate::keyspace::Snapshot;
+use crate::s3::{self, Location, S3};
+use anyhow::{anyhow, bail};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Extension of a backup being written
+const PARTIAL_EXT: &str = "partial";
+
+/// Object listing the files of an uploaded backup with their SHA-256, uploaded last
+const MANIFEST: &str = "SHA256SUMS";
+
+/// File of an incremental backup naming the backup it builds on, then each data file it holds
+/// with the offset in the data file the copy starts at
+const INCREMENT: &str = "INCREMENT";
+
+/// When backups are taken: minute, hour, day of month, month and day of week, as in crontab.
+/// Each field is `*`, a number, a range `a-b`, any of them with a step `/n`, or a list of those
+/// separated by commas. As in cron, when both days are restricted either of them matches.
+#[derive(Clone, Debug)]
+pub(crate) struct Schedule {
+    expr: String,
+    // a bit per allowed value
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    // Sunday is 0
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub(crate) fn parse(expr: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            bail!(
+                "a backup schedule has 5 fields, minute hour day month weekday: '{}'",
+                expr
+            );
+        };
+        let mut weekdays_bits = field(weekdays, 0, 7)?;
+        // Sunday is also 7
+        if weekdays_bits & (1 << 7) != 0 {
+            weekdays_bits |= 1;
+        }
+        Ok(Self {
+            expr: expr.to_string(),
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekdays_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    /// The first time of the schedule after the unix time, None if it never comes
+    pub(crate) fn next_after(&self, secs: u64) -> Option<u64> {
+        let mut minute = secs / 60 + 1;
+        // even February 29 comes within 8 years, a schedule not due by then never is
+        let horizon = minute + 8 * 366 * 24 * 60;
+        while minute < horizon {
+            let days = minute / (24 * 60);
+            if !self.matches_day(days) {
+                minute = (days + 1) * 24 * 60;
+                continue;
+            }
+            let (hour, min) = (minute / 60 % 24, minute % 60);
+            if self.hours & (1 << hour) != 0 && self.minutes & (1 << min) != 0 {
+                return Some(minute * 60);
+            }
+            minute += 1;
+        }
+        None
+    }
+
+    fn matches_day(&self, days: u64) -> bool {
+        let (_, month, day) = date(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4) % 7;
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        }
+    }
+}
+
+/// The allowed values of a field of the schedule, a bit per value
+fn field(spec: &str, min: u64, max: u64) -> anyhow::Result<u64> {
+    let invalid = || anyhow!("invalid backup schedule field '{}'", spec);
+    let mut bits = 0u64;
+    for item in spec.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().map_err(|_| invalid())?),
+            None => (item, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| invalid())?,
+                    end.parse().map_err(|_| invalid())?,
+                ),
+                // like cron, `n/step` runs from n to the end
+                None if step > 1 => (range.parse().map_err(|_| invalid())?, max),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    (value, value)
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// The year, the month and the day of the month of a day counted from the unix epoch, in the
+/// proleptic Gregorian calendar
+pub(crate) fn date(days: u64) -> (u64, u64, u64) {
+    // shifted so that years start in March and the leap day comes last
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + (month <= 2) as u64;
+    (year, month, day)
+}
+
+/// A backup the next one may build on
+struct Taken {
+    time: u64,
+    // length of each data file the backup copied up to, by name
+    lengths: BTreeMap<String, u64>,
+    // moves of the storage by a compaction before the backup
+    moves: u64,
+    // backups since the last full one
+    increments: usize,
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    // unix time of the last backup taken, its size and whether it was incremental
+    last_success: Option<(u64, u64, bool)>,
+    last_duration: Duration,
+    failures: u64,
+    // of the last attempt, cleared by a success
+    last_error: Option<String>,
+    // the last backup taken since the node started, unless one failed since
+    base: Option<Taken>,
+}
+
+pub(crate) struct Backups {
+    dir: PathBuf,
+    // None when only BGSAVE takes backups
+    schedule: Option<Schedule>,
+    retain: usize,
+    full_every: usize,
+    // where each backup is uploaded to as well
+    remote: Option<Remote>,
+    state: Mutex<State>,
+}
+
+impl Backups {
+    pub(crate) fn new(
+        dir: &Path,
+        schedule: Option<Schedule>,
+        retain: usize,
+        full_every: usize,
+        remote: Option<Remote>,
+    ) -> std::io::Result<Self> {
+        // created by the first backup, so a node never backed up leaves no directory behind
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries.collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // backups interrupted by a crash are never completed
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == PARTIAL_EXT) {
+                warn!("Backup: removing the incomplete {}", path.display());
+                std::fs::remove_dir_all(&path)?;
+            }
+        }
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            schedule,
+            retain,
+            full_every,
+            remote,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Take the backups on the times of the schedule, if any, until the node stops
+    pub(crate) async fn run(self: Arc<Self>, data_dir: PathBuf) {
+        let Some(schedule) = &self.schedule else {
+            return;
+        };
+        info!(
+            "Backup: taking backups on '{}' into {}",
+            schedule.expr,
+            self.dir.display()
+        );
+        loop {
+            let now = unix_time();
+            let Some(next) = schedule.next_after(now.as_secs()) else {
+                warn!("Backup: the schedule '{}' never comes", schedule.expr);
+                return;
+            };
+            tokio::time::sleep(Duration::from_secs(next) - now).await;
+            if !self.begin() {
+                warn!("Backup: skipping the scheduled backup, one is in progress");
+                continue;
+            }
+            let backups = self.clone();
+            let data_dir = data_dir.clone();
+            // a backup taking longer than the schedule period makes the next one wait
+            let _ = tokio::task::spawn_blocking(move || backups.take(&data_dir)).await;
+        }
+    }
+
+    /// Take a backup in the background, as BGSAVE asks, unless one is in progress
+    pub(crate) fn start(self: Arc<Self>, data_dir: PathBuf) -> bool {
+        if !self.begin() {
+            return false;
+        }
+        tokio::task::spawn_blocking(move || self.take(&data_dir));
+        true
+    }
+
+    /// Where the backups are taken into
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The unix time of the latest complete backup, 0 if there is none, for LASTSAVE
+    pub(crate) fn last_save(&self) -> u64 {
+        self.backups()
+            .ok()
+            .and_then(|times| times.last().copied())
+            .unwrap_or_default()
+    }
+
+    /// Mark a backup as in progress, unless one already is
+    fn begin(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        !std::mem::replace(&mut state.running, true)
+    }
+
+    /// Copy a snapshot of the data files into a new backup, upload it, then apply the retention.
+    /// The backup was marked as in progress.
+    fn take(&self, data_dir: &Path) {
+        let started = Instant::now();
+        let mut time = unix_time().as_secs();
+        // a backup asked for in the second of the previous one
+        while self.dir.join(time.to_string()).exists() {
+            time += 1;
+        }
+        let base = {
+            let mut state = self.state.lock().unwrap();
+            state
+                .base
+                .take()
+                .filter(|base| base.increments + 1 < self.full_every)
+        };
+        let result = (|| {
+            let (taken, bytes) = self.copy(data_dir, time, base)?;
+            self.prune()?;
+            if let Some(remote) = &self.remote {
+                remote.upload(&self.dir.join(time.to_string()), time)?;
+                remote.prune(self.retain)?;
+            }
+            anyhow::Ok((taken, bytes))
+        })();
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.last_duration = started.elapsed();
+        match result {
+            Ok((taken, bytes)) => {
+                let incremental = taken.increments > 0;
+                info!(
+                    "Backup: {} bytes backed up {}in {:?}",
+                    bytes,
+                    if incremental { "incrementally " } else { "" },
+                    started.elapsed()
+                );
+                state.last_success = Some((time, bytes, incremental));
+                state.last_error = None;
+                state.base = Some(taken);
+            }
+            Err(e) => {
+                warn!("Backup: failed: {}", e);
+                state.failures += 1;
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Copy the data files whole, or only what was appended to them since the base backup,
+    /// returning the backup taken and the number of bytes copied
+    fn copy(
+        &self,
+        data_dir: &Path,
+        time: u64,
+        base: Option<Taken>,
+    ) -> std::io::Result<(Taken, u64)> {
+        std::fs::create_dir_all(&self.dir)?;
+        let partial = self.dir.join(format!("{}.{}", time, PARTIAL_EXT));
+        let copied = Snapshot::take(data_dir).and_then(|snapshot| {
+            // a compaction rewriting the files since the base makes the backup a full one
+            let base = base
+                .filter(|base| base.moves == snapshot.moves() && snapshot.extends(&base.lengths));
+            let since = base
+                .as_ref()
+                .map(|base| base.lengths.clone())
+                .unwrap_or_default();
+            let lengths = snapshot.copy(&partial, &since)?;
+            if let Some(base) = &base {
+                let mut increment = format!("base {}\n", base.time);
+                for (name, len) in &lengths {
+                    let from = since.get(name).copied().unwrap_or(0);
+                    if *len > from || !since.contains_key(name) {
+                        increment.push_str(&format!("{} {}\n", name, from));
+                    }
+                }
+                let mut file = File::create(partial.join(INCREMENT))?;
+                file.write_all(increment.as_bytes())?;
+                file.sync_all()?;
+            }
+            let bytes = lengths
+                .iter()
+                .map(|(name, len)| len - since.get(name).copied().unwrap_or(0))
+                .sum();
+            let taken = Taken {
+                time,
+                lengths,
+                moves: snapshot.moves(),
+                increments: base.map_or(0, |base| base.increments + 1),
+            };
+            Ok((taken, bytes))
+        });
+        match copied {
+            Ok(copied) => {
+                std::fs::rename(&partial, self.dir.join(time.to_string()))?;
+                Ok(copied)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    /// The times of the complete backups, oldest first
+    fn backups(&self) -> std::io::Result<Vec<u64>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut times: Vec<u64> = entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        times.sort_unstable();
+        Ok(times)
+    }
+
+    fn prune(&self) -> std::io::Result<()> {
+        let backups: Vec<(u64, bool)> = self
+            .backups()?
+            .into_iter()
+            .map(|time| {
+                let full = !self.dir.join(time.to_string()).join(INCREMENT).exists();
+                (time, full)
+            })
+            .collect();
+        for (time, _) in expired(&backups, self.retain) {
+            info!("Backup: removing the backup of {}", time);
+            std::fs::remove_dir_all(self.dir.join(time.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Render the `backup` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let (last_time, last_bytes, last_incremental) = state.last_success.unwrap_or_default();
+        let mut info = format!(
+            "backup_schedule:{}\r\nbackup_dir:{}\r\nbackup_full_every:{}\r\nbackup_in_progress:{}\r\nbackups_kept:{}\r\nbackup_last_success_time:{}\r\nbackup_last_bytes:{}\r\nbackup_last_incremental:{}\r\nbackup_last_duration_ms:{}\r\nbackup_failures:{}\r\n",
+            self.schedule.as_ref().map_or("", |schedule| &schedule.expr),
+            self.dir.display(),
+            self.full_every,
+            state.running as u8,
+            self.backups().map_or(0, |times| times.len()),
+            last_time,
+            last_bytes,
+            last_incremental as u8,
+            state.last_duration.as_millis(),
+            state.failures,
+        );
+        if let Some(remote) = &self.remote {
+            info.push_str(&format!("backup_s3_url:{}\r\n", remote.location));
+        }
+        if let Some(e) = &state.last_error {
+            info.push_str(&format!("backup_last_error:{}\r\n", e));
+        }
+        info
+    }
+}
+
+/// The backups past the newest `retain`, given the complete ones oldest first with whether they
+/// are full, but for those the kept incremental backups build on
+fn expired(backups: &[(u64, bool)], retain: usize) -> &[(u64, bool)] {
+    let mut cut = backups.len().saturating_sub(retain);
+    // an incremental backup builds on the one before it, back to a full one
+    while cut > 0 && backups.get(cut).is_some_and(|(_, full)| !full) {
+        cut -= 1;
+    }
+    &backups[..cut]
+}
+
+/// The backup an incremental backup builds on, and the offset in the data file each of its
+/// files starts at. None for a full backup.
+fn increment(backup: &Path) -> anyhow::Result<Option<(u64, BTreeMap<String, u64>)>> {
+    let text = match std::fs::read_to_string(backup.join(INCREMENT)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid = || anyhow!("invalid {} in {}", INCREMENT, backup.display());
+    let mut lines = text.lines();
+    let base = lines
+        .next()
+        .and_then(|line| line.strip_prefix("base "))
+        .and_then(|time| time.parse().ok())
+        .ok_or_else(invalid)?;
+    let offsets = lines
+        .map(|line| {
+            let (name, offset) = line.rsplit_once(' ')?;
+            Some((name.to_string(), offset.parse().ok()?))
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+    Ok(Some((base, offsets)))
+}
+
+/// An S3-compatible store backups are uploaded to, a prefix per backup
+pub(crate) struct Remote {
+    s3: S3,
+    location: Location,
+}
+
+impl Remote {
+    pub(crate) fn new(s3: S3, location: Location) -> Self {
+        Self { s3, location }
+    }
+
+    /// Upload the files of the backup, then the manifest that completes it
+    fn upload(&self, dir: &Path, time: u64) -> anyhow::Result<()> {
+        let backup = self.location.join(&time.to_string());
+        let mut names: Vec<String> = std::fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<_>>()?;
+        names.sort();
+        let mut manifest = String::new();
+        for name in names {
+            let (_, sha256) = self.s3.upload(
+                &backup.bucket,
+                &backup.key(&name),
+                &mut File::open(dir.join(&name))?,
+            )?;
+            // the format of sha256sum, to check a downloaded backup with `sha256sum -c`
+            manifest.push_str(&format!("{}  {}\n", s3::hex(&sha256), name));
+        }
+        self.s3
+            .put(&backup.bucket, &backup.key(MANIFEST), manifest.as_bytes())?;
+        info!("Backup: uploaded to {}", backup);
+        Ok(())
+    }
+
+    /// Remove the backups past the newest `retain` complete ones but for those they build on,
+    /// and the incomplete ones older than the kept ones
+    fn prune(&self, retain: usize) -> anyhow::Result<()> {
+        let keys = self
+            .s3
+            .list(&self.location.bucket, &self.location.key(""))?;
+        let backups: Vec<(u64, bool)> = complete_backups(&self.location, &keys)
+            .into_iter()
+            .map(|time| {
+                let increment = self.location.join(&time.to_string()).key(INCREMENT);
+                (time, !keys.contains(&increment))
+            })
+            .collect();
+        let expired = expired(&backups, retain);
+        let Some(&(oldest_kept, _)) = backups.get(expired.len()) else {
+            return Ok(());
+        };
+        for key in &keys {
+            if key_time(&self.location, key).is_some_and(|(time, _)| time < oldest_kept) {
+                self.s3.delete(&self.location.bucket, key)?;
+            }
+        }
+        for (time, _) in expired {
+            info!(
+                "Backup: removing the backup of {} from {}",
+                time, self.location
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The time of the backup an object under the location belongs to, and its name in the backup
+fn key_time<'a>(location: &Location, key: &'a str) -> Option<(u64, &'a str)> {
+    let path = key.strip_prefix(&location.key(""))?;
+    let (time, name) = path.split_once('/')?;
+    Some((time.parse().ok()?, name))
+}
+
+/// The times of the complete backups among the keys under the location, oldest first
+fn complete_backups(location: &Location, keys: &[String]) -> Vec<u64> {
+    let times: BTreeSet<u64> = keys
+        .iter()
+        .filter_map(|key| key_time(location, key))
+        .filter(|(_, name)| *name == MANIFEST)
+        .map(|(time, _)| time)
+        .collect();
+    times.into_iter().collect()
+}
+
+/// Where a node is restored from: a directory of backups or an S3 prefix of backups, the latest
+/// of which is restored, or a backup in one of them
+pub(crate) enum Store {
+    Dir(PathBuf),
+    S3(S3, Location),
+}
+
+/// Lay the backup and those it builds on into the data directory, unless it already holds data
+pub(crate) fn restore(store: &Store, data_dir: &Path) -> anyhow::Result<()> {
+    if std::fs::read_dir(data_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        info!(
+            "Restore: {} already holds data, not restoring",
+            data_dir.display()
+        );
+        return Ok(());
+    }
+    let restoring = data_dir.with_extension(PARTIAL_EXT);
+    let downloads = data_dir.with_extension("download");
+    for dir in [&restoring, &downloads] {
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+    }
+    let (dir, time, downloaded) = match store {
+        Store::Dir(path) => match path
+            .file_name()
+            .and_then(|name| name.to_str()?.parse().ok())
+        {
+            Some(time) => (
+                path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+                time,
+                false,
+            ),
+            None => {
+                let latest = std::fs::read_dir(path)?
+                    .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                    .max()
+                    .ok_or_else(|| anyhow!("no backup in {}", path.display()))?;
+                (path.clone(), latest, false)
+            }
+        },
+        Store::S3(s3, from) => {
+            let time = download(s3, from, &downloads)?;
+            (downloads.clone(), time, true)
+        }
+    };
+    let mut chain = vec![time];
+    while let Some((base, _)) = increment(&dir.join(chain[chain.len() - 1].to_string()))? {
+        chain.push(base);
+    }
+    chain.reverse();
+    info!(
+        "Restore: laying the backups of {:?} from {} into {}",
+        chain,
+        dir.display(),
+        data_dir.display()
+    );
+    std::fs::create_dir_all(&restoring)?;
+    for time in &chain {
+        let backup = dir.join(time.to_string());
+        let offsets = increment(&backup)?.map(|(_, offsets)| offsets);
+        for entry in std::fs::read_dir(&backup)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name == INCREMENT {
+                continue;
+            }
+            let target = restoring.join(name.as_ref());
+            match &offsets {
+                // the files of a full backup are moved when they are copies already
+                None if downloaded => std::fs::rename(&path, &target)?,
+                None => {
+                    std::fs::copy(&path, &target)?;
+                }
+                Some(offsets) => {
+                    let offset = offsets
+                        .get(name.as_ref())
+                        .ok_or_else(|| anyhow!("{} of {} has no offset", name, backup.display()))?;
+                    let mut file = OpenOptions::new().create(true).append(true).open(&target)?;
+                    if file.metadata()?.len() != *offset {
+                        bail!(
+                            "{} of {} starts at {}, but the backups before it end at {}",
+                            name,
+                            backup.display(),
+                            offset,
+                            file.metadata()?.len()
+                        );
+                    }
+                    std::io::copy(&mut File::open(&path)?, &mut file)?;
+                }
+            }
+        }
+    }
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(&restoring)? {
+        let file = File::open(entry?.path())?;
+        file.sync_all()?;
+        bytes += file.metadata()?.len();
+    }
+    if data_dir.exists() {
+        std::fs::remove_dir(data_dir)?;
+    }
+    std::fs::rename(&restoring, data_dir)?;
+    if downloaded {
+        std::fs::remove_dir_all(&downloads)?;
+    }
+    info!("Restore: {} bytes restored", bytes);
+    Ok(())
+}
+
+/// Download the backup at the location, or the latest one under it, with those it builds on into
+/// a directory per backup, checking each file against the manifest. Returns the time of the
+/// backup.
+fn download(s3: &S3, from: &Location, dir: &Path) -> anyhow::Result<u64> {
+    let keys = s3.list(&from.bucket, &from.key(""))?;
+    let (root, time) = if keys.contains(&from.key(MANIFEST)) {
+        let (parent, time) = from.prefix.rsplit_once('/').unwrap_or(("", &from.prefix));
+        let time = time
+            .parse()
+            .map_err(|_| anyhow!("{} is not named after the time of a backup", from))?;
+        let root = Location {
+            bucket: from.bucket.clone(),
+            prefix: parent.to_string(),
+        };
+        (root, time)
+    } else {
+        let latest = complete_backups(from, &keys)
+            .pop()
+            .ok_or_else(|| anyhow!("no complete backup under {}", from))?;
+        (from.clone(), latest)
+    };
+    let mut next = Some(time);
+    while let Some(time) = next {
+        let backup = root.join(&time.to_string());
+        info!("Restore: downloading {}", backup);
+        let mut manifest = Vec::new();
+        s3.get(&backup.bucket, &backup.key(MANIFEST), &mut manifest)
+            .map_err(|e| anyhow!("no complete backup at {}: {}", backup, e))?;
+        let local = dir.join(time.to_string());
+        std::fs::create_dir_all(&local)?;
+        for line in String::from_utf8_lossy(&manifest).lines() {
+            let (sha256, name) = line
+                .split_once("  ")
+                .filter(|(_, name)| !name.is_empty() && !name.contains('/') && *name != "..")
+                .ok_or_else(|| anyhow!("invalid line in the manifest of {}: '{}'", backup, line))?;
+            let path = local.join(name);
+            let mut file = File::create(&path)?;
+            s3.get(&backup.bucket, &backup.key(name), &mut file)?;
+            file.sync_all()?;
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut File::open(&path)?, &mut hasher)?;
+            if s3::hex(&hasher.finalize()) != sha256 {
+                bail!("{} of {} does not match the manifest", name, backup);
+            }
+        }
+        next = increment(&local)?.map(|(base, _)| base);
+    }
+    Ok(time)
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
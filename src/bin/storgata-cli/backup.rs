@@ -0,0 +1,346 @@
+//! Backup archives: a point-in-time copy of the data directory of a node as a single file, to
+//! seed a new node from.
+//!
+//! `backup` has the node take a backup with BGSAVE, waits for LASTSAVE to move past the previous
+//! one, then packs the latest backup of `--backup-dir`, with the backups it builds on, into the
+//! data files they lay out. The data files hold the index of the last log entry the node applied,
+//! so a node started on them resumes the log from there. `restore` unpacks an archive into the
+//! data directory of a node that holds no data yet.
+//!
+//! An archive is a `STORGATA-BACKUP 1` line and a line with the time of the backup, then for each
+//! data file a line with its name and length, its bytes and a line with their SHA-256, and an
+//! `END` line.
+
+use crate::client::{Client, Reply};
+use anyhow::{anyhow, bail, Context};
+use clap::Args;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const HEADER: &str = "STORGATA-BACKUP 1";
+const TRAILER: &str = "END";
+
+/// Names the backup an incremental one builds on and the offset each of its copies starts at
+const INCREMENT: &str = "INCREMENT";
+
+/// Extension of an archive or a data directory being written, renamed into place once complete
+const PARTIAL_EXT: &str = "partial";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Args, Debug)]
+pub(crate) struct BackupArgs {
+    /// Kv address of the node to take a backup with BGSAVE first. The latest backup already in
+    /// --backup-dir is packed when unset.
+    #[arg(long)]
+    addr: Option<String>,
+
+    /// --backup-dir of the node, on this host
+    #[arg(long)]
+    backup_dir: PathBuf,
+
+    /// The archive to write
+    #[arg(long)]
+    pub(crate) out: PathBuf,
+
+    /// How long the node has to complete the backup
+    #[arg(long, default_value_t = 600)]
+    timeout_secs: u64,
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct RestoreArgs {
+    /// The archive to unpack
+    #[arg(long)]
+    from: PathBuf,
+
+    /// --directory of the new node, missing or empty
+    #[arg(long)]
+    pub(crate) directory: PathBuf,
+}
+
+/// What `backup` packed or `restore` unpacked
+pub(crate) struct Archived {
+    pub(crate) time: u64,
+    pub(crate) files: usize,
+    pub(crate) bytes: u64,
+}
+
+/// Have the node take a backup if asked to, then pack the latest one into the archive
+pub(crate) async fn backup(args: &BackupArgs) -> anyhow::Result<Archived> {
+    if let Some(addr) = &args.addr {
+        let mut client = Client::connect(addr)
+            .await
+            .with_context(|| format!("Could not connect to {}", addr))?;
+        save(&mut client, Duration::from_secs(args.timeout_secs)).await?;
+    }
+    let time = latest(&args.backup_dir)?;
+    let files = lay_out(&args.backup_dir, time)?;
+    let bytes = pack(time, &files, &args.out)
+        .with_context(|| format!("Could not write {}", args.out.display()))?;
+    Ok(Archived {
+        time,
+        files: files.len(),
+        bytes,
+    })
+}
+
+/// Unpack the archive into the data directory of a new node
+pub(crate) fn restore(args: &RestoreArgs) -> anyhow::Result<Archived> {
+    if args.directory.exists() && fs::read_dir(&args.directory)?.next().is_some() {
+        bail!("{} is not empty", args.directory.display());
+    }
+    let partial = args.directory.with_extension(PARTIAL_EXT);
+    if partial.exists() {
+        // left by an earlier attempt that failed
+        fs::remove_dir_all(&partial)?;
+    }
+    fs::create_dir_all(&partial)?;
+    let (time, files, bytes) = unpack(&args.from, &partial)
+        .with_context(|| format!("Could not unpack {}", args.from.display()))?;
+    File::open(&partial)?.sync_all()?;
+    if args.directory.exists() {
+        fs::remove_dir(&args.directory)?;
+    }
+    fs::rename(&partial, &args.directory)?;
+    if let Some(parent) = args
+        .directory
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(Archived { time, files, bytes })
+}
+
+/// Start a backup with BGSAVE and wait for LASTSAVE to report it
+async fn save(client: &mut Client, timeout: Duration) -> anyhow::Result<()> {
+    loop {
+        let before = last_save(client).await?;
+        match client.call(&[b"BGSAVE"]).await? {
+            Reply::Simple(_) => return wait(client, before, timeout).await,
+            // wait for the backup in progress, then take ours
+            Reply::Error(e) if e.contains("in progress") => wait(client, before, timeout).await?,
+            reply => bail!("BGSAVE failed: {:?}", reply),
+        }
+    }
+}
+
+/// Wait for a backup newer than `before` to complete
+async fn wait(client: &mut Client, before: u64, timeout: Duration) -> anyhow::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if last_save(client).await? > before {
+            return Ok(());
+        }
+        let info = match client.call(&[b"INFO", b"backup"]).await? {
+            Reply::Bulk(Some(info)) => String::from_utf8_lossy(&info).into_owned(),
+            reply => bail!("INFO backup failed: {:?}", reply),
+        };
+        let field = |name: &str| {
+            info.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::to_string)
+        };
+        // LASTSAVE is read first, so a backup completing in between is not taken for a failure
+        if field("backup_in_progress").as_deref() == Some("0") && last_save(client).await? <= before
+        {
+            bail!(
+                "the backup failed: {}",
+                field("backup_last_error").unwrap_or_default()
+            );
+        }
+        if Instant::now() > deadline {
+            bail!("the backup did not complete within {:?}", timeout);
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn last_save(client: &mut Client) -> anyhow::Result<u64> {
+    match client.call(&[b"LASTSAVE"]).await? {
+        Reply::Integer(time) => Ok(time as u64),
+        reply => bail!("LASTSAVE failed: {:?}", reply),
+    }
+}
+
+/// The time of the latest complete backup in the directory, backups in progress having another
+/// name
+fn latest(dir: &Path) -> anyhow::Result<u64> {
+    fs::read_dir(dir)
+        .with_context(|| format!("Could not read {}", dir.display()))?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .max()
+        .ok_or_else(|| anyhow!("no backup in {}", dir.display()))
+}
+
+/// The data files the backup and those it builds on lay out, each as the copies it is made of
+fn lay_out(dir: &Path, time: u64) -> anyhow::Result<BTreeMap<String, Vec<PathBuf>>> {
+    let mut chain = vec![(time, increment(&dir.join(time.to_string()))?)];
+    while let Some((base, _)) = &chain[chain.len() - 1].1 {
+        let base = *base;
+        chain.push((base, increment(&dir.join(base.to_string()))?));
+    }
+    let mut files: BTreeMap<String, (Vec<PathBuf>, u64)> = BTreeMap::new();
+    for (time, offsets) in chain.into_iter().rev() {
+        let backup = dir.join(time.to_string());
+        for entry in fs::read_dir(&backup)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name == INCREMENT {
+                continue;
+            }
+            let len = path.metadata()?.len();
+            match &offsets {
+                None => {
+                    files.insert(name.to_string(), (vec![path], len));
+                }
+                Some((_, offsets)) => {
+                    let offset = offsets
+                        .get(name.as_ref())
+                        .ok_or_else(|| anyhow!("{} of {} has no offset", name, backup.display()))?;
+                    let (copies, end) = files.entry(name.to_string()).or_default();
+                    if end != offset {
+                        bail!(
+                            "{} of {} starts at {}, but the backups before it end at {}",
+                            name,
+                            backup.display(),
+                            offset,
+                            end
+                        );
+                    }
+                    copies.push(path);
+                    *end += len;
+                }
+            }
+        }
+    }
+    Ok(files
+        .into_iter()
+        .map(|(name, (copies, _))| (name, copies))
+        .collect())
+}
+
+/// The base and offsets of an incremental backup, None for a full one
+fn increment(backup: &Path) -> anyhow::Result<Option<(u64, BTreeMap<String, u64>)>> {
+    let text = match fs::read_to_string(backup.join(INCREMENT)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let invalid = || anyhow!("invalid {} in {}", INCREMENT, backup.display());
+    let mut lines = text.lines();
+    let base = lines
+        .next()
+        .and_then(|line| line.strip_prefix("base "))
+        .and_then(|time| time.parse().ok())
+        .ok_or_else(invalid)?;
+    let offsets = lines
+        .map(|line| {
+            let (name, offset) = line.rsplit_once(' ')?;
+            Some((name.to_string(), offset.parse().ok()?))
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(invalid)?;
+    Ok(Some((base, offsets)))
+}
+
+/// Write the data files into a partial archive, then move it into place, returning the bytes of
+/// the data files
+fn pack(time: u64, files: &BTreeMap<String, Vec<PathBuf>>, out: &Path) -> anyhow::Result<u64> {
+    let partial = out.with_extension(PARTIAL_EXT);
+    let mut archive = BufWriter::new(File::create(&partial)?);
+    writeln!(archive, "{}\n{}", HEADER, time)?;
+    let mut bytes = 0;
+    for (name, copies) in files {
+        let mut len = 0;
+        for copy in copies {
+            len += copy.metadata()?.len();
+        }
+        writeln!(archive, "{} {}", name, len)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        for copy in copies {
+            let mut copy = File::open(copy)?;
+            loop {
+                let n = copy.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                archive.write_all(&buf[..n])?;
+            }
+        }
+        writeln!(archive, "\n{:x}", hasher.finalize())?;
+        bytes += len;
+    }
+    writeln!(archive, "{}", TRAILER)?;
+    archive.into_inner()?.sync_all()?;
+    fs::rename(&partial, out)?;
+    Ok(bytes)
+}
+
+/// Write the data files of the archive into the directory, checking each against its SHA-256,
+/// returning the time of the backup and how many files and bytes were written
+fn unpack(from: &Path, dir: &Path) -> anyhow::Result<(u64, usize, u64)> {
+    let mut archive = BufReader::new(File::open(from)?);
+    let mut line = String::new();
+    let mut next_line = |archive: &mut BufReader<File>| -> anyhow::Result<String> {
+        line.clear();
+        if archive.read_line(&mut line)? == 0 {
+            bail!("the archive is truncated");
+        }
+        Ok(line.trim_end_matches('\n').to_string())
+    };
+    if next_line(&mut archive)? != HEADER {
+        bail!("not a backup archive");
+    }
+    let time = next_line(&mut archive)?
+        .parse()
+        .map_err(|_| anyhow!("invalid backup time"))?;
+    let (mut files, mut bytes) = (0, 0);
+    loop {
+        let entry = next_line(&mut archive)?;
+        if entry == TRAILER {
+            return Ok((time, files, bytes));
+        }
+        let (name, len) = entry
+            .rsplit_once(' ')
+            .and_then(|(name, len)| Some((name, len.parse::<u64>().ok()?)))
+            .ok_or_else(|| anyhow!("invalid entry '{}'", entry))?;
+        // a name from the archive must not reach outside the directory
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            bail!("invalid file name '{}'", name);
+        }
+        let mut hasher = Sha256::new();
+        let mut file = File::create(dir.join(name))?;
+        let mut data = (&mut archive).take(len);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = data.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n])?;
+        }
+        if data.limit() != 0 {
+            bail!("the archive is truncated");
+        }
+        // the line break ending the data
+        if !next_line(&mut archive)?.is_empty() {
+            bail!("{} is longer than its entry says", name);
+        }
+        if next_line(&mut archive)? != format!("{:x}", hasher.finalize()) {
+            bail!("{} does not match its SHA-256", name);
+        }
+        file.sync_all()?;
+        files += 1;
+        bytes += len;
+    }
+}
@@ -0,0 +1,231 @@
+//! Load generator: SETs and GETs in a given ratio against a running cluster, from many
+//! connections at once, each pipelining its commands, reporting the throughput and the latency
+//! percentiles of each, so that regressions in the sync layer and the codec show up as numbers.
+//!
+//! The keys are written once, then every connection sends batches of `--pipeline` commands, each
+//! on a key picked at random, until the time is up. A command takes as long as its batch, from
+//! sending it to reading its last reply, as redis-benchmark counts it. The connections are spread
+//! over the nodes in turn: the SETs of a follower go through the leader, its GETs do not.
+
+use crate::client::{Client, Reply};
+use crate::soak::Rng;
+use anyhow::{anyhow, bail};
+use clap::Args;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Args, Debug)]
+pub(crate) struct BenchArgs {
+    /// Kv addresses of the nodes to send the commands to
+    #[arg(long, num_args = 1.., value_delimiter = ' ', default_value = "127.0.0.1:6379")]
+    addr: Vec<String>,
+
+    /// SETs to GETs, as `sets:gets`
+    #[arg(long, default_value = "1:10", value_parser = parse_ratio)]
+    ratio: (u32, u32),
+
+    /// Number of keys written, then picked at random
+    #[arg(long, default_value_t = 10_000)]
+    keys: usize,
+
+    /// Size in bytes of each value
+    #[arg(long, default_value_t = 64)]
+    value_size: usize,
+
+    /// Commands each connection sends before reading their replies
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pipeline: u64,
+
+    /// Connections sending commands at once
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+    connections: u64,
+
+    /// How long the load runs
+    #[arg(long, default_value_t = 10)]
+    duration_secs: u64,
+}
+
+fn parse_ratio(ratio: &str) -> anyhow::Result<(u32, u32)> {
+    let (sets, gets) = ratio
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected sets:gets, such as 1:10"))?;
+    let ratio = (sets.parse()?, gets.parse()?);
+    if ratio == (0, 0) {
+        bail!("the ratio needs SETs or GETs");
+    }
+    Ok(ratio)
+}
+
+/// What one kind of command did
+#[derive(Default)]
+struct Tally {
+    // microseconds, one per command answered
+    latencies: Vec<u64>,
+    errors: u64,
+}
+
+impl Tally {
+    fn merge(&mut self, other: Tally) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+
+    /// The latency under which the share of the commands are, in milliseconds
+    fn percentile(&self, share: f64) -> f64 {
+        let rank = ((self.latencies.len() as f64 * share).ceil() as usize).max(1);
+        self.latencies[rank - 1] as f64 / 1000.0
+    }
+}
+
+/// The outcome of a benchmark
+pub(crate) struct Report {
+    sets: Tally,
+    gets: Tally,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn line(
+        f: &mut fmt::Formatter<'_>,
+        name: &str,
+        tally: &Tally,
+        elapsed: Duration,
+    ) -> fmt::Result {
+        if tally.latencies.is_empty() {
+            return writeln!(f, "{}: none answered, errors: {}", name, tally.errors);
+        }
+        writeln!(
+            f,
+            "{}: {:>9.0}/s, p50: {:.3} ms, p90: {:.3} ms, p99: {:.3} ms, p99.9: {:.3} ms, max: {:.3} ms, errors: {}",
+            name,
+            tally.latencies.len() as f64 / elapsed.as_secs_f64(),
+            tally.percentile(0.5),
+            tally.percentile(0.9),
+            tally.percentile(0.99),
+            tally.percentile(0.999),
+            tally.percentile(1.0),
+            tally.errors
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Self::line(f, "SET", &self.sets, self.elapsed)?;
+        Self::line(f, "GET", &self.gets, self.elapsed)?;
+        write!(
+            f,
+            "total: {:.0} commands/s over {:.1} s",
+            (self.sets.latencies.len() + self.gets.latencies.len()) as f64
+                / self.elapsed.as_secs_f64(),
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+fn key_name(key: usize) -> Vec<u8> {
+    format!("bench:{}", key).into_bytes()
+}
+
+pub(crate) async fn run(args: BenchArgs) -> anyhow::Result<Report> {
+    if args.keys == 0 {
+        bail!("--keys must be at least 1");
+    }
+    let value = vec![b'x'; args.value_size];
+    let mut client = Client::connect(&args.addr[0]).await?;
+    let keys: Vec<Vec<u8>> = (0..args.keys).map(key_name).collect();
+    for batch in keys.chunks(100) {
+        let commands: Vec<Vec<&[u8]>> = batch
+            .iter()
+            .map(|key| vec![&b"SET"[..], key, &value])
+            .collect();
+        for reply in client.pipeline(&commands).await? {
+            if let Reply::Error(e) = reply {
+                bail!("SET failed: {}", e);
+            }
+        }
+    }
+
+    let mut connections = Vec::new();
+    for connection in 0..args.connections as usize {
+        connections.push(Client::connect(&args.addr[connection % args.addr.len()]).await?);
+    }
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.duration_secs);
+    let tasks: Vec<_> = connections
+        .into_iter()
+        .enumerate()
+        .map(|(connection, client)| {
+            let load = Load {
+                ratio: args.ratio,
+                keys: args.keys,
+                value: value.clone(),
+                pipeline: args.pipeline as usize,
+                deadline,
+            };
+            tokio::spawn(load.run(client, connection as u64))
+        })
+        .collect();
+    let mut report = Report {
+        sets: Tally::default(),
+        gets: Tally::default(),
+        elapsed: Duration::ZERO,
+    };
+    for task in tasks {
+        let (sets, gets) = task.await??;
+        report.sets.merge(sets);
+        report.gets.merge(gets);
+    }
+    report.elapsed = started.elapsed();
+    report.sets.latencies.sort_unstable();
+    report.gets.latencies.sort_unstable();
+    Ok(report)
+}
+
+/// The commands one connection sends
+struct Load {
+    ratio: (u32, u32),
+    keys: usize,
+    value: Vec<u8>,
+    pipeline: usize,
+    deadline: Instant,
+}
+
+impl Load {
+    /// Send batches until the deadline, returning what the SETs and the GETs did
+    async fn run(self, mut client: Client, stream: u64) -> anyhow::Result<(Tally, Tally)> {
+        let mut rng = Rng::new(stream);
+        let (mut sets, mut gets) = (Tally::default(), Tally::default());
+        let share = (self.ratio.0 + self.ratio.1) as usize;
+        while Instant::now() < self.deadline {
+            let batch: Vec<(bool, Vec<u8>)> = (0..self.pipeline)
+                .map(|_| {
+                    let set = rng.below(share) < self.ratio.0 as usize;
+                    (set, key_name(rng.below(self.keys)))
+                })
+                .collect();
+            let commands: Vec<Vec<&[u8]>> = batch
+                .iter()
+                .map(|(set, key)| match set {
+                    true => vec![&b"SET"[..], key, &self.value],
+                    false => vec![&b"GET"[..], key],
+                })
+                .collect();
+            let sent = Instant::now();
+            let replies = client.pipeline(&commands).await?;
+            let latency = sent.elapsed().as_micros() as u64;
+            for ((set, _), reply) in batch.iter().zip(replies) {
+                let tally = match set {
+                    true => &mut sets,
+                    false => &mut gets,
+                };
+                match reply {
+                    Reply::Error(_) => tally.errors += 1,
+                    _ => tally.latencies.push(latency),
+                }
+            }
+        }
+        Ok((sets, gets))
+    }
+}
@@ -0,0 +1,99 @@
+//! A minimal RESP2 client, just enough to send commands and read their replies
+
+use anyhow::{anyhow, bail};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+pub(crate) struct Client {
+    stream: BufReader<TcpStream>,
+}
+
+impl Client {
+    pub(crate) async fn connect(addr: &str) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Send a command as an array of bulk strings and wait for its reply
+    pub(crate) async fn call(&mut self, args: &[&[u8]]) -> anyhow::Result<Reply> {
+        let mut request = Vec::new();
+        encode(&mut request, args);
+        self.stream.get_mut().write_all(&request).await?;
+        self.read_reply().await
+    }
+
+    /// Send the commands at once and wait for their replies, in order
+    pub(crate) async fn pipeline(&mut self, commands: &[Vec<&[u8]>]) -> anyhow::Result<Vec<Reply>> {
+        let mut request = Vec::new();
+        for args in commands {
+            encode(&mut request, args);
+        }
+        self.stream.get_mut().write_all(&request).await?;
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            replies.push(self.read_reply().await?);
+        }
+        Ok(replies)
+    }
+
+    async fn read_line(&mut self) -> anyhow::Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            bail!("connection closed");
+        }
+        Ok(line.trim_end_matches("\r\n").to_string())
+    }
+
+    async fn read_reply(&mut self) -> anyhow::Result<Reply> {
+        let line = self.read_line().await?;
+        let (kind, rest) = line
+            .split_at_checked(1)
+            .ok_or_else(|| anyhow!("empty reply"))?;
+        match kind {
+            "+" => Ok(Reply::Simple(rest.to_string())),
+            "-" => Ok(Reply::Error(rest.to_string())),
+            ":" => Ok(Reply::Integer(rest.parse()?)),
+            "$" => {
+                let len: i64 = rest.parse()?;
+                if len < 0 {
+                    return Ok(Reply::Bulk(None));
+                }
+                let mut data = vec![0u8; len as usize + 2];
+                self.stream.read_exact(&mut data).await?;
+                data.truncate(len as usize);
+                Ok(Reply::Bulk(Some(data)))
+            }
+            "*" => {
+                let len: i64 = rest.parse()?;
+                let mut items = Vec::new();
+                for _ in 0..len.max(0) {
+                    items.push(Box::pin(self.read_reply()).await?);
+                }
+                Ok(Reply::Array(items))
+            }
+            _ => bail!("unexpected reply {:?}", line),
+        }
+    }
+}
+
+/// Append the command, as an array of bulk strings, to the request
+fn encode(request: &mut Vec<u8>, args: &[&[u8]]) {
+    request.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        request.extend_from_slice(arg);
+        request.extend_from_slice(b"\r\n");
+    }
+}
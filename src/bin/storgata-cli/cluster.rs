@@ -0,0 +1,111 @@
+//! Cluster bootstrap: turn a standalone node into the first member of a Raft cluster.
+//!
+//! raft-lite can neither install a snapshot nor start from a log prefix, so the cluster starts
+//! with an empty log and every member starts from the same keyspace instead: the standalone node
+//! keeps its data directory and joins as the first member, and `cluster init` copies its data
+//! files into the empty data directory of each other member. The node records the first entry of
+//! the log it applied, so the members take the new log for a new one and apply it whole on top
+//! of the copied keyspace.
+
+use anyhow::{bail, Context};
+use clap::{Args, Subcommand};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Extension of the data files of bitcask-engine-rs, the only files a data directory holds
+const DATA_FILE_EXT: &str = "bitcask";
+
+/// Extension of a member directory being filled, renamed into place once complete
+const PARTIAL_EXT: &str = "partial";
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum ClusterCommand {
+    /// Copy the keyspace of a stopped standalone node into the data directories of the other
+    /// members of a new cluster
+    Init(InitArgs),
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct InitArgs {
+    /// --directory of the standalone node, which must be stopped
+    #[arg(long)]
+    directory: PathBuf,
+
+    /// --directory of another member, missing or empty
+    #[arg(long = "member-directory", num_args = 1.., required = true)]
+    member_directories: Vec<PathBuf>,
+}
+
+/// What `cluster init` copied
+pub(crate) struct Seeded {
+    pub(crate) members: usize,
+    pub(crate) files: usize,
+    pub(crate) bytes: u64,
+}
+
+/// Copy the data files of the standalone node into each member directory
+pub(crate) fn init(args: &InitArgs) -> anyhow::Result<Seeded> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&args.directory)
+        .with_context(|| format!("Could not read {}", args.directory.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == DATA_FILE_EXT) {
+            files.push(path);
+        }
+    }
+    if files.is_empty() {
+        bail!("{} holds no data files", args.directory.display());
+    }
+    files.sort();
+    // check every member before copying to any, so that a mistake leaves nothing half done
+    for member in &args.member_directories {
+        if member == &args.directory {
+            bail!(
+                "{} is the directory of the standalone node",
+                member.display()
+            );
+        }
+        if member.exists() && fs::read_dir(member)?.next().is_some() {
+            bail!("{} is not empty", member.display());
+        }
+    }
+    let mut bytes = 0;
+    for member in &args.member_directories {
+        bytes +=
+            seed(&files, member).with_context(|| format!("Could not fill {}", member.display()))?;
+    }
+    Ok(Seeded {
+        members: args.member_directories.len(),
+        files: files.len(),
+        bytes,
+    })
+}
+
+/// Copy the files into a partial directory, then move it into place, returning the bytes copied
+fn seed(files: &[PathBuf], member: &Path) -> anyhow::Result<u64> {
+    let partial = member.with_extension(PARTIAL_EXT);
+    if partial.exists() {
+        // left by an earlier attempt that failed
+        fs::remove_dir_all(&partial)?;
+    }
+    fs::create_dir_all(&partial)?;
+    let mut bytes = 0;
+    for file in files {
+        let to = partial.join(file.file_name().unwrap_or_default());
+        bytes += fs::copy(file, &to)?;
+        File::open(&to)?.sync_all()?;
+    }
+    File::open(&partial)?.sync_all()?;
+    if member.exists() {
+        fs::remove_dir(member)?;
+    }
+    fs::rename(&partial, member)?;
+    if let Some(parent) = member
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(bytes)
+}
@@ -0,0 +1,103 @@
+//! Operator tooling that talks to StorgataDB nodes over RESP, or works on their data directories
+//! while they are stopped
+
+use clap::{Parser, Subcommand};
+
+mod backup;
+mod bench;
+mod client;
+mod cluster;
+mod read_bench;
+mod soak;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Have a node take a backup, then pack it into a single archive
+    Backup(backup::BackupArgs),
+
+    /// Unpack a backup archive into the data directory of a new node
+    Restore(backup::RestoreArgs),
+
+    /// Work on the data directories of a cluster whose nodes are stopped
+    Cluster {
+        #[command(subcommand)]
+        command: cluster::ClusterCommand,
+    },
+
+    /// Write checksummed values, read them back from every node, restart nodes along the way,
+    /// and report every consistency violation seen
+    Soak(soak::SoakArgs),
+
+    /// Measure the GET throughput of a node with one client, then more at once, and fail unless
+    /// it scales with them
+    ReadBench(read_bench::ReadBenchArgs),
+
+    /// Send SETs and GETs in a ratio from many pipelining connections, and report their
+    /// throughput and latency percentiles
+    Bench(bench::BenchArgs),
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        Command::Backup(args) => {
+            let archived = backup::backup(&args).await?;
+            println!(
+                "Packed the backup of {} ({} data files, {} bytes) into {}.",
+                archived.time,
+                archived.files,
+                archived.bytes,
+                args.out.display()
+            );
+        }
+        Command::Restore(args) => {
+            let archived = backup::restore(&args)?;
+            println!(
+                "Unpacked the backup of {} ({} data files, {} bytes) into {}. Start the node on \
+                 it: it resumes the log from the last entry applied before the backup.",
+                archived.time,
+                archived.files,
+                archived.bytes,
+                args.directory.display()
+            );
+        }
+        Command::Cluster {
+            command: cluster::ClusterCommand::Init(args),
+        } => {
+            let seeded = cluster::init(&args)?;
+            println!(
+                "Copied {} data files ({} bytes) into {} member directories. Start every member, \
+                 the standalone node with its own directory, with the same --peer-addr and \
+                 --peer-kv-addr and without --standalone.",
+                seeded.files, seeded.bytes, seeded.members
+            );
+        }
+        Command::Soak(args) => {
+            let report = soak::run(args).await?;
+            println!("{}", report);
+            if !report.violations.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::ReadBench(args) => {
+            let report = read_bench::run(args).await?;
+            println!("{}", report);
+            if !report.scaled() {
+                std::process::exit(1);
+            }
+        }
+        Command::Bench(args) => {
+            let report = bench::run(args).await?;
+            println!("{}", report);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,182 @@
+//! Read benchmark: GET throughput with one client, then with more at once, to check that reads
+//! scale with the cores of the node instead of waiting on a lock they share.
+//!
+//! The keys are written once, then each round runs its number of clients at once, doubling from
+//! one up to `--clients`, each sending GETs of the keys in turn over its own connection. The
+//! scaling of a round is its throughput over that of one client, and its efficiency the scaling
+//! over its clients: the benchmark fails if the last round is below `--min-efficiency`. A client
+//! on the host of the node takes cores from it, so the threshold is loose by default.
+//!
+//! The node serves the GETs from its storage at the local read consistency, the default one,
+//! while the others measure their barriers through the group as well.
+
+use crate::client::{Client, Reply};
+use anyhow::bail;
+use clap::Args;
+use std::fmt;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Args, Debug)]
+pub(crate) struct ReadBenchArgs {
+    /// Kv address of the node to read from
+    #[arg(long, default_value = "127.0.0.1:6379")]
+    addr: String,
+
+    /// Clients reading at once in the last round, the cores of this host by default
+    #[arg(long)]
+    clients: Option<usize>,
+
+    /// How long each round reads
+    #[arg(long, default_value_t = 5)]
+    round_secs: u64,
+
+    /// Number of keys written, then read
+    #[arg(long, default_value_t = 1000)]
+    keys: usize,
+
+    /// Size in bytes of each value
+    #[arg(long, default_value_t = 64)]
+    value_size: usize,
+
+    /// Efficiency of the last round, its scaling over its clients, below which the benchmark
+    /// fails
+    #[arg(long, default_value_t = 0.5)]
+    min_efficiency: f64,
+}
+
+/// The GETs of a round
+struct Round {
+    clients: usize,
+    gets: u64,
+    elapsed: Duration,
+}
+
+impl Round {
+    fn throughput(&self) -> f64 {
+        self.gets as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// The outcome of a read benchmark
+pub(crate) struct Report {
+    rounds: Vec<Round>,
+    min_efficiency: f64,
+}
+
+impl Report {
+    /// The efficiency of a round, against the round of one client
+    fn efficiency(&self, round: &Round) -> f64 {
+        round.throughput() / self.rounds[0].throughput() / round.clients as f64
+    }
+
+    /// Whether the last round scaled as required
+    pub(crate) fn scaled(&self) -> bool {
+        let last = self.rounds.last().unwrap();
+        self.efficiency(last) >= self.min_efficiency
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for round in &self.rounds {
+            writeln!(
+                f,
+                "clients: {:>3}, GETs/s: {:>10.0}, scaling: {:>5.2}, efficiency: {:.2}",
+                round.clients,
+                round.throughput(),
+                round.throughput() / self.rounds[0].throughput(),
+                self.efficiency(round)
+            )?;
+        }
+        write!(
+            f,
+            "{} the minimum efficiency of {:.2}",
+            match self.scaled() {
+                true => "meets",
+                false => "falls short of",
+            },
+            self.min_efficiency
+        )
+    }
+}
+
+fn key_name(key: usize) -> Vec<u8> {
+    format!("read-bench:{}", key).into_bytes()
+}
+
+pub(crate) async fn run(args: ReadBenchArgs) -> anyhow::Result<Report> {
+    if args.keys == 0 {
+        bail!("--keys must be at least 1");
+    }
+    let clients = match args.clients {
+        Some(clients) => clients.max(1),
+        None => std::thread::available_parallelism()?.get(),
+    };
+    let mut client = Client::connect(&args.addr).await?;
+    let value = vec![b'x'; args.value_size];
+    for key in 0..args.keys {
+        match client.call(&[b"SET", &key_name(key), &value]).await? {
+            Reply::Simple(_) => {}
+            reply => bail!("SET failed: {:?}", reply),
+        }
+    }
+
+    let mut rounds = Vec::new();
+    let mut round_clients = 1;
+    loop {
+        rounds.push(round(&args, round_clients).await?);
+        if round_clients == clients {
+            break;
+        }
+        round_clients = (round_clients * 2).min(clients);
+    }
+    Ok(Report {
+        rounds,
+        min_efficiency: args.min_efficiency,
+    })
+}
+
+/// Read with `clients` connections at once for a round
+async fn round(args: &ReadBenchArgs, clients: usize) -> anyhow::Result<Round> {
+    let mut connections = Vec::new();
+    for _ in 0..clients {
+        connections.push(Client::connect(&args.addr).await?);
+    }
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.round_secs);
+    let tasks: Vec<_> = connections
+        .into_iter()
+        .enumerate()
+        .map(|(client, connection)| {
+            tokio::spawn(read_loop(connection, client, args.keys, deadline))
+        })
+        .collect();
+    let mut gets = 0;
+    for task in tasks {
+        gets += task.await??;
+    }
+    Ok(Round {
+        clients,
+        gets,
+        elapsed: started.elapsed(),
+    })
+}
+
+/// GET the keys in turn, from a key of its own, until the deadline, returning how many
+async fn read_loop(
+    mut connection: Client,
+    client: usize,
+    keys: usize,
+    deadline: Instant,
+) -> anyhow::Result<u64> {
+    let mut gets = 0;
+    while Instant::now() < deadline {
+        let key = key_name((client + gets as usize) % keys);
+        match connection.call(&[b"GET", &key]).await? {
+            Reply::Bulk(Some(_)) => gets += 1,
+            reply => bail!("GET failed: {:?}", reply),
+        }
+    }
+    Ok(gets)
+}
@@ -0,0 +1,471 @@
+//! Soak test: a workload that checks its own results.
+//!
+//! Writers give every key values of increasing sequence numbers, each value carrying a checksum,
+//! while a reader per node reads the keys back. Reads may lag behind the writes on a follower,
+//! but a value that fails its checksum or was never written is a violation. When the soak starts
+//! the nodes itself, it also kills and restarts them on a schedule. Once the workload stops, every
+//! node must converge, within the settle time, to a value at least as recent as the last one
+//! acknowledged for each key, or the acknowledged write was lost.
+
+use crate::client::{Client, Reply};
+use clap::Args;
+use crc::{Crc, CRC_32_CKSUM};
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::process::{Child, Command};
+use tokio::time::{sleep, timeout, Instant};
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const RETRY_PAUSE: Duration = Duration::from_millis(100);
+
+#[derive(Args, Debug)]
+pub(crate) struct SoakArgs {
+    /// Kv addresses of the nodes of a running cluster, which are never restarted
+    #[arg(
+        long,
+        num_args = 1..,
+        value_delimiter = ' ',
+        required_unless_present = "local_nodes",
+        conflicts_with = "local_nodes"
+    )]
+    nodes: Vec<String>,
+
+    /// Start this many nodes on the loopback interface, killing and restarting them along the way
+    #[arg(long)]
+    local_nodes: Option<usize>,
+
+    /// The server binary the local nodes run, storgata-db next to this binary by default
+    #[arg(long)]
+    server_bin: Option<PathBuf>,
+
+    /// Where the local nodes keep their data, wiped before they start
+    #[arg(long, default_value = "./data/soak")]
+    work_dir: PathBuf,
+
+    /// Node i of the local nodes serves clients on this port + 2i and Raft on the next one
+    #[arg(long, default_value_t = 17000)]
+    base_port: u16,
+
+    /// How long the workload runs
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+
+    /// Number of keys written
+    #[arg(long, default_value_t = 100)]
+    keys: usize,
+
+    /// Size in bytes of the payload of each value
+    #[arg(long, default_value_t = 64)]
+    value_size: usize,
+
+    /// Pause between killing a local node and restarting it, then between the restart and the
+    /// next kill. 0 never kills nodes.
+    #[arg(long, default_value_t = 10)]
+    kill_interval_secs: u64,
+
+    /// How long the nodes have to converge once the workload stops
+    #[arg(long, default_value_t = 30)]
+    settle_secs: u64,
+}
+
+/// The outcome of a soak test
+#[derive(Default)]
+pub(crate) struct Report {
+    writes_acked: u64,
+    writes_failed: u64,
+    reads: u64,
+    reads_failed: u64,
+    // behind the last acknowledged write, which followers may be
+    stale_reads: u64,
+    kills: u64,
+    pub(crate) violations: Vec<String>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "writes: {} acknowledged, {} failed",
+            self.writes_acked, self.writes_failed
+        )?;
+        writeln!(
+            f,
+            "reads: {} ok, {} failed, {} stale",
+            self.reads, self.reads_failed, self.stale_reads
+        )?;
+        writeln!(f, "nodes killed: {}", self.kills)?;
+        writeln!(f, "violations: {}", self.violations.len())?;
+        for violation in &self.violations {
+            writeln!(f, "  {}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sequence numbers written to a key. Writes that failed may still have been applied, so
+/// any sequence number up to the last attempted one may be read.
+#[derive(Clone, Copy, Default)]
+struct KeyState {
+    acked: u64,
+    attempted: u64,
+}
+
+struct Shared {
+    keys: Mutex<Vec<KeyState>>,
+    report: Mutex<Report>,
+    value_size: usize,
+}
+
+impl Shared {
+    fn key_state(&self, key: usize) -> KeyState {
+        self.keys.lock().unwrap()[key]
+    }
+
+    fn violation(&self, violation: String) {
+        self.report.lock().unwrap().violations.push(violation);
+    }
+}
+
+/// xorshift64, plenty for picking keys and filling payloads
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(stream: u64) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        Self((nanos ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+fn key_name(key: usize) -> Vec<u8> {
+    format!("soak:{}", key).into_bytes()
+}
+
+/// `<seq>:<payload>:<crc>`, the checksum covering everything before it
+fn encode(seq: u64, rng: &mut Rng, size: usize) -> Vec<u8> {
+    let payload: String = (0..size)
+        .map(|_| char::from(b'a' + rng.below(26) as u8))
+        .collect();
+    let body = format!("{}:{}", seq, payload);
+    format!("{}:{:08x}", body, CRC32.checksum(body.as_bytes())).into_bytes()
+}
+
+/// The sequence number of a value, if its checksum holds
+fn decode(value: &[u8]) -> Result<u64, String> {
+    let value = std::str::from_utf8(value).map_err(|_| "value is not UTF-8".to_string())?;
+    let (body, crc) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("value {:?} has no checksum", value))?;
+    if u32::from_str_radix(crc, 16) != Ok(CRC32.checksum(body.as_bytes())) {
+        return Err(format!("value {:?} fails its checksum", value));
+    }
+    body.split(':')
+        .next()
+        .and_then(|seq| seq.parse().ok())
+        .ok_or_else(|| format!("value {:?} has no sequence number", value))
+}
+
+/// Send a command to a node, connecting first if needed. The connection is dropped on failure.
+async fn call(conn: &mut Option<Client>, addr: &str, args: &[&[u8]]) -> anyhow::Result<Reply> {
+    let result = timeout(REQUEST_TIMEOUT, async {
+        if conn.is_none() {
+            *conn = Some(Client::connect(addr).await?);
+        }
+        conn.as_mut().unwrap().call(args).await
+    })
+    .await
+    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+    if result.is_err() {
+        *conn = None;
+    }
+    result
+}
+
+/// Nodes started by the soak itself, so it can kill and restart them
+struct LocalCluster {
+    server_bin: PathBuf,
+    work_dir: PathBuf,
+    base_port: u16,
+    children: Vec<Option<Child>>,
+}
+
+impl LocalCluster {
+    fn new(args: &SoakArgs, nodes: usize) -> anyhow::Result<Self> {
+        let server_bin = match &args.server_bin {
+            Some(path) => path.clone(),
+            None => std::env::current_exe()?.with_file_name("storgata-db"),
+        };
+        if args.work_dir.exists() {
+            std::fs::remove_dir_all(&args.work_dir)?;
+        }
+        Ok(Self {
+            server_bin,
+            work_dir: args.work_dir.clone(),
+            base_port: args.base_port,
+            children: (0..nodes).map(|_| None).collect(),
+        })
+    }
+
+    fn kv_addr(&self, node: usize) -> String {
+        format!("127.0.0.1:{}", self.base_port as usize + 2 * node)
+    }
+
+    fn raft_addr(&self, node: usize) -> String {
+        format!("127.0.0.1:{}", self.base_port as usize + 2 * node + 1)
+    }
+
+    fn start(&mut self, node: usize) -> anyhow::Result<()> {
+        // the node keeps its data and logs relative to its working directory
+        let dir = self.work_dir.join(format!("node{}", node));
+        std::fs::create_dir_all(&dir)?;
+        let peers: Vec<String> = (0..self.children.len())
+            .map(|peer| self.raft_addr(peer))
+            .collect();
+        let child = Command::new(&self.server_bin)
+            .current_dir(dir)
+            .arg("--self-addr")
+            .arg(self.raft_addr(node))
+            .arg("--kv-addr")
+            .arg(self.kv_addr(node))
+            .arg("--peer-addr")
+            .args(peers)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        self.children[node] = Some(child);
+        Ok(())
+    }
+
+    async fn kill(&mut self, node: usize) -> anyhow::Result<()> {
+        if let Some(mut child) = self.children[node].take() {
+            child.kill().await?;
+        }
+        Ok(())
+    }
+
+    fn down(&self) -> Option<usize> {
+        self.children.iter().position(Option::is_none)
+    }
+}
+
+pub(crate) async fn run(args: SoakArgs) -> anyhow::Result<Report> {
+    let mut cluster = match args.local_nodes {
+        Some(nodes) => {
+            let mut cluster = LocalCluster::new(&args, nodes)?;
+            for node in 0..nodes {
+                cluster.start(node)?;
+            }
+            Some(cluster)
+        }
+        None => None,
+    };
+    let nodes: Vec<String> = match &cluster {
+        Some(cluster) => (0..cluster.children.len())
+            .map(|node| cluster.kv_addr(node))
+            .collect(),
+        None => args.nodes.clone(),
+    };
+    for addr in &nodes {
+        wait_ready(addr).await?;
+    }
+
+    let shared = Arc::new(Shared {
+        keys: Mutex::new(vec![KeyState::default(); args.keys]),
+        report: Mutex::new(Report::default()),
+        value_size: args.value_size,
+    });
+    let deadline = Instant::now() + Duration::from_secs(args.duration_secs);
+    let mut tasks = Vec::new();
+    // writers own disjoint keys, so that the writes to a key are never concurrent
+    for writer in 0..nodes.len() {
+        let owned: Vec<usize> = (writer..args.keys).step_by(nodes.len()).collect();
+        tasks.push(tokio::spawn(write_loop(
+            shared.clone(),
+            nodes.clone(),
+            writer,
+            owned,
+            deadline,
+        )));
+    }
+    for (node, addr) in nodes.iter().enumerate() {
+        tasks.push(tokio::spawn(read_loop(
+            shared.clone(),
+            addr.clone(),
+            node,
+            args.keys,
+            deadline,
+        )));
+    }
+
+    if let Some(cluster) = &mut cluster {
+        if args.kill_interval_secs > 0 {
+            let mut rng = Rng::new(u64::MAX);
+            let interval = Duration::from_secs(args.kill_interval_secs);
+            while Instant::now() + interval < deadline {
+                sleep(interval).await;
+                match cluster.down() {
+                    Some(node) => cluster.start(node)?,
+                    None => {
+                        cluster.kill(rng.below(nodes.len())).await?;
+                        shared.report.lock().unwrap().kills += 1;
+                    }
+                }
+            }
+            if let Some(node) = cluster.down() {
+                cluster.start(node)?;
+            }
+        }
+    }
+    for task in tasks {
+        task.await?;
+    }
+
+    settle(&shared, &nodes, Duration::from_secs(args.settle_secs)).await;
+    let report = std::mem::take(&mut *shared.report.lock().unwrap());
+    Ok(report)
+}
+
+/// Wait for a node to answer PING
+async fn wait_ready(addr: &str) -> anyhow::Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let mut conn = None;
+    loop {
+        match call(&mut conn, addr, &[b"PING"]).await {
+            Ok(Reply::Simple(_)) => return Ok(()),
+            _ if Instant::now() > deadline => anyhow::bail!("{} is not answering", addr),
+            _ => sleep(RETRY_PAUSE).await,
+        }
+    }
+}
+
+async fn write_loop(
+    shared: Arc<Shared>,
+    nodes: Vec<String>,
+    writer: usize,
+    keys: Vec<usize>,
+    deadline: Instant,
+) {
+    let mut rng = Rng::new(writer as u64);
+    // writes go to every node in turn, followers forward them to the leader
+    let mut node = writer;
+    let mut conn = None;
+    while Instant::now() < deadline && !keys.is_empty() {
+        let key = keys[rng.below(keys.len())];
+        let seq = {
+            let mut states = shared.keys.lock().unwrap();
+            states[key].attempted += 1;
+            states[key].attempted
+        };
+        let value = encode(seq, &mut rng, shared.value_size);
+        let reply = call(
+            &mut conn,
+            &nodes[node % nodes.len()],
+            &[b"SET", &key_name(key), &value],
+        )
+        .await;
+        if let Ok(Reply::Simple(_)) = reply {
+            let mut states = shared.keys.lock().unwrap();
+            states[key].acked = states[key].acked.max(seq);
+            shared.report.lock().unwrap().writes_acked += 1;
+        } else {
+            shared.report.lock().unwrap().writes_failed += 1;
+            conn = None;
+            node += 1;
+            sleep(RETRY_PAUSE).await;
+        }
+    }
+}
+
+async fn read_loop(shared: Arc<Shared>, addr: String, node: usize, keys: usize, deadline: Instant) {
+    let mut rng = Rng::new((keys + node) as u64);
+    let mut conn = None;
+    while Instant::now() < deadline && keys > 0 {
+        let key = rng.below(keys);
+        let before = shared.key_state(key);
+        let reply = call(&mut conn, &addr, &[b"GET", &key_name(key)]).await;
+        let seq = match reply {
+            Ok(Reply::Bulk(Some(value))) => match decode(&value) {
+                Ok(seq) => Some(seq),
+                Err(e) => {
+                    shared.violation(format!("node {} key {}: {}", addr, key, e));
+                    continue;
+                }
+            },
+            Ok(Reply::Bulk(None)) => None,
+            _ => {
+                shared.report.lock().unwrap().reads_failed += 1;
+                sleep(RETRY_PAUSE).await;
+                continue;
+            }
+        };
+        // the write may have been attempted while the read was in flight
+        let after = shared.key_state(key);
+        if let Some(seq) = seq.filter(|seq| *seq > after.attempted) {
+            shared.violation(format!(
+                "node {} key {}: read sequence {} that was never written",
+                addr, key, seq
+            ));
+            continue;
+        }
+        let mut report = shared.report.lock().unwrap();
+        report.reads += 1;
+        if seq.unwrap_or(0) < before.acked {
+            report.stale_reads += 1;
+        }
+    }
+}
+
+/// Wait for every node to hold, for every key, a value at least as recent as the last one
+/// acknowledged. What is still missing at the end of the settle time was lost.
+async fn settle(shared: &Shared, nodes: &[String], settle: Duration) {
+    let deadline = Instant::now() + settle;
+    let mut conns: Vec<Option<Client>> = nodes.iter().map(|_| None).collect();
+    let keys = shared.keys.lock().unwrap().len();
+    loop {
+        let mut missing = Vec::new();
+        for (node, addr) in nodes.iter().enumerate() {
+            for key in 0..keys {
+                let state = shared.key_state(key);
+                let seq = match call(&mut conns[node], addr, &[b"GET", &key_name(key)]).await {
+                    Ok(Reply::Bulk(Some(value))) => decode(&value).ok(),
+                    _ => None,
+                };
+                if seq.unwrap_or(0) < state.acked {
+                    missing.push(format!(
+                        "node {} key {}: holds sequence {} but {} was acknowledged",
+                        addr,
+                        key,
+                        seq.map_or("none".to_string(), |seq| seq.to_string()),
+                        state.acked
+                    ));
+                }
+            }
+        }
+        if missing.is_empty() {
+            return;
+        }
+        if Instant::now() > deadline {
+            shared.report.lock().unwrap().violations.extend(missing);
+            return;
+        }
+        sleep(Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,147 @@
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+
+/// A bitmap is a string value, its bit 0 the most significant bit of the first byte, so that it
+/// reads back with GET as in Redis. SETBIT grows the string in the apply path, so every replica
+/// computes the same value; the other commands read the local replica.
+///
+/// Offsets stop at 2^32 bits, a 512 MB string, as in Redis.
+pub(crate) const MAX_OFFSET: u64 = (1 << 32) - 1;
+
+/// The unit the range of BITCOUNT and BITPOS counts in
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum Unit {
+    Byte,
+    Bit,
+}
+
+/// The range of BITCOUNT and BITPOS, both ends included and negative ones counting from the end
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct Range {
+    pub(crate) start: i64,
+    // up to the end of the string without one
+    pub(crate) end: Option<i64>,
+    pub(crate) unit: Unit,
+}
+
+/// Set or clear the bit at `offset`, zero-extending the string to reach it, and return the bit
+/// it held. The value keeps its expiry.
+pub(crate) fn set(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    offset: u64,
+    bit: bool,
+    now: u64,
+) -> Result<u8, BitCaskError> {
+    let raw = storage.get(key).filter(|raw| !value::expired(raw, now));
+    let (mut bytes, deadline) = match &raw {
+        Some(raw) => (value::string(raw)?.into_owned(), value::deadline(raw)),
+        None => (Vec::new(), None),
+    };
+    let index = (offset / 8) as usize;
+    if bytes.len() <= index {
+        bytes.resize(index + 1, 0);
+    }
+    let mask = 0x80 >> (offset % 8);
+    let old = bytes[index] & mask != 0;
+    if bit {
+        bytes[index] |= mask;
+    } else {
+        bytes[index] &= !mask;
+    }
+    storage.put(
+        key,
+        &value::encode_expiring(ValueType::String, &bytes, deadline),
+    )?;
+    Ok(old as u8)
+}
+
+/// The bit at `offset`, 0 past the end of the string
+pub(crate) fn get(bytes: &[u8], offset: u64) -> u8 {
+    match bytes.get((offset / 8) as usize) {
+        Some(byte) => (byte >> (7 - offset % 8)) & 1,
+        None => 0,
+    }
+}
+
+/// The bits set in the range, the whole string without one
+pub(crate) fn count(bytes: &[u8], range: Option<Range>) -> u64 {
+    let Some((first, last)) = bits(bytes.len(), range) else {
+        return 0;
+    };
+    let (first_byte, last_byte) = ((first / 8) as usize, (last / 8) as usize);
+    bytes[first_byte..=last_byte]
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| {
+            let mut byte = *byte;
+            // the bits of the edge bytes outside the range
+            if i == 0 {
+                byte &= 0xff >> (first % 8);
+            }
+            if first_byte + i == last_byte {
+                byte &= 0xff << (7 - last % 8);
+            }
+            byte.count_ones() as u64
+        })
+        .sum()
+}
+
+/// The offset of the first bit of the value in the range, -1 if there is none. Without an end to
+/// the range, a string with no clear bit has one right past its end, as strings read as padded
+/// with zeros.
+pub(crate) fn position(bytes: &[u8], bit: bool, range: Option<Range>) -> i64 {
+    let padded = !bit && range.is_none_or(|range| range.end.is_none());
+    let Some((first, last)) = bits(bytes.len(), range) else {
+        // an empty string is all padding
+        return if padded && bytes.is_empty() { 0 } else { -1 };
+    };
+    // the whole bytes without the bit are skipped at once
+    let other = if bit { 0x00 } else { 0xff };
+    let mut offset = first;
+    while offset <= last {
+        let byte = bytes[(offset / 8) as usize];
+        if offset % 8 == 0 && offset + 7 <= last && byte == other {
+            offset += 8;
+            continue;
+        }
+        if ((byte >> (7 - offset % 8)) & 1 == 1) == bit {
+            return offset as i64;
+        }
+        offset += 1;
+    }
+    if padded {
+        last as i64 + 1
+    } else {
+        -1
+    }
+}
+
+/// The first and last bit of the range in a string of `len` bytes, clamped to the string as
+/// GETRANGE clamps its offsets, None if it is empty
+fn bits(len: usize, range: Option<Range>) -> Option<(u64, u64)> {
+    let Some(range) = range else {
+        return (len > 0).then(|| (0, len as u64 * 8 - 1));
+    };
+    let len = match range.unit {
+        Unit::Byte => len as i64,
+        Unit::Bit => len as i64 * 8,
+    };
+    let end = range.end.unwrap_or(-1);
+    let start = if range.start < 0 {
+        len + range.start
+    } else {
+        range.start
+    }
+    .max(0);
+    let end = if end < 0 { len + end } else { end }.min(len - 1);
+    if len == 0 || start > end {
+        return None;
+    }
+    Some(match range.unit {
+        Unit::Byte => (start as u64 * 8, end as u64 * 8 + 7),
+        Unit::Bit => (start as u64, end as u64),
+    })
+}
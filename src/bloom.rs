@@ -0,0 +1,168 @@
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+
+/// Defaults of RedisBloom when a filter is created implicitly by BF.ADD
+const DEFAULT_ERROR_RATE: f64 = 0.01;
+const DEFAULT_CAPACITY: u64 = 100;
+const EXPANSION: u64 = 2;
+/// Each new layer gets a tighter error rate, so the compound rate stays below the target
+const TIGHTENING_RATIO: f64 = 0.5;
+
+#[derive(Serialize, Deserialize)]
+struct Layer {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    capacity: u64,
+    count: u64,
+}
+
+impl Layer {
+    fn new(capacity: u64, error_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * error_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let num_hashes = ((-error_rate.log2()).ceil() as u32).max(1);
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            capacity,
+            count: 0,
+        }
+    }
+
+    /// Bit positions of an item, by double hashing
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = u64> + '_ {
+        let h1 = hash(item, 0xcbf2_9ce4_8422_2325);
+        let h2 = hash(item, 0x8422_2325_cbf2_9ce4) | 1;
+        (0..self.num_hashes as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|pos| self.bits[(pos / 64) as usize] & (1 << (pos % 64)) != 0)
+    }
+
+    fn insert(&mut self, item: &[u8]) {
+        let positions: Vec<u64> = self.positions(item).collect();
+        for pos in positions {
+            self.bits[(pos / 64) as usize] |= 1 << (pos % 64);
+        }
+        self.count += 1;
+    }
+}
+
+/// FNV-1a with a murmur finalizer. The hash is part of the stored format and every replica
+/// must compute the same bits, so it cannot depend on the std hasher.
+pub(crate) fn hash(item: &[u8], seed: u64) -> u64 {
+    let mut h = seed;
+    for byte in item {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// A scalable bloom filter is stored as a single bincode-encoded value under its key.
+/// When the last layer is full, a larger layer with a tighter error rate is stacked on top.
+#[derive(Serialize, Deserialize)]
+struct BloomFilter {
+    error_rate: f64,
+    layers: Vec<Layer>,
+}
+
+impl BloomFilter {
+    fn new() -> Self {
+        Self {
+            error_rate: DEFAULT_ERROR_RATE,
+            layers: vec![Layer::new(
+                DEFAULT_CAPACITY,
+                DEFAULT_ERROR_RATE * TIGHTENING_RATIO,
+            )],
+        }
+    }
+
+    fn contains(&self, item: &[u8]) -> bool {
+        self.layers.iter().any(|layer| layer.contains(item))
+    }
+
+    /// Returns false if the item may already be in the filter
+    fn add(&mut self, item: &[u8]) -> bool {
+        if self.contains(item) {
+            return false;
+        }
+        let last = self.layers.last().unwrap();
+        if last.count >= last.capacity {
+            let error_rate = self.error_rate * TIGHTENING_RATIO.powi(self.layers.len() as i32 + 1);
+            let layer = Layer::new(last.capacity * EXPANSION, error_rate);
+            self.layers.push(layer);
+        }
+        self.layers.last_mut().unwrap().insert(item);
+        true
+    }
+}
+
+/// The filter with the deadline it expires at, if any
+fn load(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    now: u64,
+) -> Result<Option<(BloomFilter, Option<u64>)>, BitCaskError> {
+    // an expired value is no longer the key's, whatever its type
+    match storage.get(key).filter(|raw| !value::expired(raw, now)) {
+        Some(raw) => bincode::deserialize::<BloomFilter>(value::expect(&raw, ValueType::Bloom)?)
+            .map(|filter| Some((filter, value::deadline(&raw))))
+            .map_err(|_| BitCaskError::CorruptedData("value is not a bloom filter".to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Store the filter, keeping the deadline it was loaded with
+fn store(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    filter: &BloomFilter,
+    deadline: Option<u64>,
+) -> Result<(), BitCaskError> {
+    let payload =
+        bincode::serialize(filter).map_err(|e| BitCaskError::UnexpectedError(e.into()))?;
+    storage.put(
+        key,
+        &value::encode_expiring(ValueType::Bloom, &payload, deadline),
+    )
+}
+
+/// Add items to the filter, creating it with the default parameters if needed.
+/// For each item, returns whether it was newly added.
+pub(crate) fn add(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    items: &[Vec<u8>],
+    now: u64,
+) -> Result<Vec<bool>, BitCaskError> {
+    let (mut filter, deadline) =
+        load(storage, key, now)?.unwrap_or_else(|| (BloomFilter::new(), None));
+    let added: Vec<bool> = items.iter().map(|item| filter.add(item)).collect();
+    if added.iter().any(|added| *added) {
+        store(storage, key, &filter, deadline)?;
+    }
+    Ok(added)
+}
+
+/// Whether the item may have been added to the filter
+pub(crate) fn exists(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    item: &[u8],
+    now: u64,
+) -> Result<bool, BitCaskError> {
+    Ok(load(storage, key, now)?.is_some_and(|(filter, _)| filter.contains(item)))
+}
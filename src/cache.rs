@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Mutex;
+
+/// Shards a cache is split into at most, so that reads of different keys rarely wait for one
+/// another on the lock of an LRU
+const MAX_SHARDS: usize = 16;
+/// Bytes of values a shard of the read cache holds at least, smaller caches have fewer shards
+const MIN_SHARD_BYTES: usize = 1024 * 1024;
+/// Keys a shard of the negative cache holds at least
+const MIN_SHARD_KEYS: usize = 1024;
+
+struct Entry {
+    value: Vec<u8>,
+    last_used: u64,
+}
+
+struct Lru {
+    entries: HashMap<Vec<u8>, Entry>,
+    // least recently used first
+    order: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+    bytes: usize,
+    // bumped by every invalidation, see `ReadCache::ticket`
+    generation: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl Lru {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            generation: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.last_used);
+            entry.last_used = clock;
+            self.order.insert(clock, key.to_vec());
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+            self.bytes -= key.len() + entry.value.len();
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.remove(key);
+        self.clock += 1;
+        self.entries.insert(
+            key.to_vec(),
+            Entry {
+                value: value.to_vec(),
+                last_used: self.clock,
+            },
+        );
+        self.order.insert(self.clock, key.to_vec());
+        self.bytes += key.len() + value.len();
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, oldest)) = self.order.pop_first() {
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.bytes -= oldest.len() + entry.value.len();
+            self.evictions += 1;
+        }
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        self.generation += 1;
+        self.remove(key);
+    }
+}
+
+/// The LRUs of a cache, each holding the keys hashing to it and bounded by its share of the
+/// capacity. Invalidations are counted per shard, as a ticket only covers the key it is for.
+struct Shards {
+    lrus: Vec<Mutex<Lru>>,
+    hasher: RandomState,
+}
+
+impl Shards {
+    fn new(capacity: usize, min_shard_capacity: usize) -> Self {
+        let shards = (capacity / min_shard_capacity).clamp(1, MAX_SHARDS);
+        Self {
+            lrus: (0..shards).map(|_| Mutex::new(Lru::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// The LRU of `key`
+    fn of(&self, key: &[u8]) -> &Mutex<Lru> {
+        &self.lrus[self.hasher.hash_one(key) as usize % self.lrus.len()]
+    }
+
+    /// The capacity of each shard
+    fn capacity(&self, capacity: usize) -> usize {
+        capacity / self.lrus.len()
+    }
+
+    fn totals(&self) -> Totals {
+        let mut totals = Totals::default();
+        for lru in &self.lrus {
+            let lru = lru.lock().unwrap();
+            totals.bytes += lru.bytes;
+            totals.entries += lru.entries.len();
+            totals.hits += lru.hits;
+            totals.misses += lru.misses;
+            totals.evictions += lru.evictions;
+        }
+        totals
+    }
+}
+
+/// The counters of the shards of a cache, added up for INFO
+#[derive(Default)]
+struct Totals {
+    bytes: usize,
+    entries: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl Totals {
+    fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+}
+
+/// Proof that a value was read from the storage after a given invalidation generation of its shard
+struct Ticket(u64);
+
+/// In-memory LRU cache of recently read values, bounded by the total size of keys and values.
+///
+/// The apply path invalidates every key it writes. A reader takes a ticket before going to the
+/// storage, and its value is only cached if no invalidation happened in between, so a value read
+/// just before a write can never be cached after that write invalidated the key.
+pub(crate) struct ReadCache {
+    capacity: usize,
+    shards: Shards,
+}
+
+impl ReadCache {
+    pub(crate) fn new(capacity_bytes: usize) -> Self {
+        Self {
+            capacity: capacity_bytes,
+            shards: Shards::new(capacity_bytes, MIN_SHARD_BYTES),
+        }
+    }
+
+    /// Return the cached value of `key`, or load it with `load` and cache it
+    pub(crate) fn get_or_load(
+        &self,
+        key: &[u8],
+        load: impl FnOnce() -> Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        if let Some(value) = self.get(key) {
+            return Some(value);
+        }
+        let ticket = self.ticket(key);
+        let value = load();
+        if let Some(value) = &value {
+            self.insert(key, value, ticket);
+        }
+        value
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut lru = self.shards.of(key).lock().unwrap();
+        match lru.entries.get(key) {
+            Some(entry) => {
+                let value = entry.value.clone();
+                lru.hits += 1;
+                lru.touch(key);
+                Some(value)
+            }
+            None => {
+                lru.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Take a ticket before reading a missed key from the storage
+    fn ticket(&self, key: &[u8]) -> Ticket {
+        Ticket(self.shards.of(key).lock().unwrap().generation)
+    }
+
+    /// Cache a value read from the storage, unless a write invalidated keys since `ticket`
+    fn insert(&self, key: &[u8], value: &[u8], ticket: Ticket) {
+        let size = key.len() + value.len();
+        let capacity = self.shards.capacity(self.capacity);
+        if size > capacity {
+            return;
+        }
+        let mut lru = self.shards.of(key).lock().unwrap();
+        if lru.generation != ticket.0 {
+            return;
+        }
+        lru.remove(key);
+        while lru.bytes + size > capacity {
+            lru.evict_oldest();
+        }
+        lru.insert(key, value);
+    }
+
+    /// Drop the cached value of a key written by the apply path
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        self.shards.of(key).lock().unwrap().invalidate(key);
+    }
+
+    /// Render the read cache lines of the `cache` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let totals = self.shards.totals();
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "read_cache_capacity_bytes:{}\r\nread_cache_used_bytes:{}\r\nread_cache_entries:{}\r\nread_cache_hits:{}\r\nread_cache_misses:{}\r\nread_cache_hit_rate:{:.4}\r\nread_cache_evictions:{}\r\n",
+            self.capacity,
+            totals.bytes,
+            totals.entries,
+            totals.hits,
+            totals.misses,
+            totals.hit_rate(),
+            totals.evictions,
+        );
+        out
+    }
+}
+
+/// In-memory LRU cache of keys recently found missing, bounded by the number of keys.
+///
+/// Shields the storage from workloads hammering keys that do not exist. Invalidation from the
+/// apply path and the ticket check work as for `ReadCache`.
+pub(crate) struct NegativeCache {
+    capacity: usize,
+    shards: Shards,
+}
+
+impl NegativeCache {
+    pub(crate) fn new(capacity_keys: usize) -> Self {
+        Self {
+            capacity: capacity_keys,
+            shards: Shards::new(capacity_keys, MIN_SHARD_KEYS),
+        }
+    }
+
+    /// Return `None` if `key` is known to be missing, or load it with `load`
+    /// and remember it as missing if it is
+    pub(crate) fn get_or_load(
+        &self,
+        key: &[u8],
+        load: impl FnOnce() -> Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        let shard = self.shards.of(key);
+        let ticket = {
+            let mut lru = shard.lock().unwrap();
+            if lru.entries.contains_key(key) {
+                lru.hits += 1;
+                lru.touch(key);
+                return None;
+            }
+            lru.misses += 1;
+            Ticket(lru.generation)
+        };
+        let value = load();
+        let capacity = self.shards.capacity(self.capacity);
+        if value.is_none() && capacity > 0 {
+            let mut lru = shard.lock().unwrap();
+            if lru.generation == ticket.0 {
+                while lru.entries.len() >= capacity {
+                    lru.evict_oldest();
+                }
+                lru.insert(key, &[]);
+            }
+        }
+        value
+    }
+
+    /// Forget that a key written by the apply path was missing
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        self.shards.of(key).lock().unwrap().invalidate(key);
+    }
+
+    /// Render the negative cache lines of the `cache` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let totals = self.shards.totals();
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "negative_cache_capacity_keys:{}\r\nnegative_cache_entries:{}\r\nnegative_cache_hits:{}\r\nnegative_cache_misses:{}\r\nnegative_cache_hit_rate:{:.4}\r\nnegative_cache_evictions:{}\r\n",
+            self.capacity,
+            totals.entries,
+            totals.hits,
+            totals.misses,
+            totals.hit_rate(),
+            totals.evictions,
+        );
+        out
+    }
+}
@@ -0,0 +1,61 @@
+//! Writes too large for a single Raft entry. A node replicates and applies its entries one after
+//! the other, so a 100MB SET holds up the writes of every client until it is through. With
+//! `--proposal-oversize split`, the connection proposes the value of such a SET in chunks, each
+//! an entry of its own staged under a reserved prefix hidden from KEYS, waiting for each chunk to
+//! be applied before proposing the next so that the writes of the other clients go between them.
+//! A last, small entry assembles the chunks into the value and sets it like the SET would have.
+//!
+//! The chunks of a SET that fails half way are deleted with a replicated DEL.
+
+use crate::sync_layer::RequestId;
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use uuid::Uuid;
+
+/// Keys holding the chunks staged for a write, not a prefix clients are expected to use
+pub(crate) const KEY_PREFIX: &[u8] = b"\xffchunk:";
+
+/// The key chunk `index` of the write `id` is staged under
+pub(crate) fn key(id: &RequestId, index: u32) -> Vec<u8> {
+    let id = Uuid::from_bytes(*id).simple().to_string();
+    [
+        KEY_PREFIX,
+        id.as_bytes(),
+        b":",
+        index.to_string().as_bytes(),
+    ]
+    .concat()
+}
+
+/// Stage chunk `index` of the write `id`
+pub(crate) fn stage(
+    storage: &mut BitCask,
+    id: &RequestId,
+    index: u32,
+    bytes: &Vec<u8>,
+) -> Result<(), BitCaskError> {
+    storage.put(&key(id, index), bytes)
+}
+
+/// Take the `chunks` staged for the write `id` out of the storage, joined in order
+pub(crate) fn assemble(
+    storage: &mut BitCask,
+    id: &RequestId,
+    chunks: u32,
+) -> Result<Vec<u8>, BitCaskError> {
+    let mut value = Vec::new();
+    for index in 0..chunks {
+        let Some(bytes) = storage.get(&key(id, index)) else {
+            return Err(BitCaskError::CorruptedData(format!(
+                "chunk {} of {} is missing",
+                index,
+                Uuid::from_bytes(*id)
+            )));
+        };
+        value.extend_from_slice(&bytes);
+    }
+    for index in 0..chunks {
+        storage.delete(&key(id, index))?;
+    }
+    Ok(value)
+}
@@ -1,9 +1,24 @@
-use clap::Parser;
+use crate::audit::Rotation;
+use crate::clients;
+use crate::compression::Compression;
+use crate::config::{Consistency, Oversize};
+use crate::config_file::{self, Deprecated};
+use crate::memory::Policy;
+use crate::resp_codec::Limits;
+use crate::s3::S3;
+use crate::units;
+use anyhow::anyhow;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use std::path::{Path, PathBuf};
 
 #[derive(Parser, Clone, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
+    /// Path to a configuration file, in TOML, setting flags by their long name.
+    /// Precedence: command line flags, then environment variables, then the file, then defaults.
+    #[arg(long, env = "STORGATA_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Raft: Ip:port of all kv servers
     /// at least one peer address is required
     /// usage:
@@ -12,22 +27,88 @@ pub struct Args {
     #[arg(short = 'p', long, env, num_args = 1.., value_delimiter = ' ')]
     peer_addr: Vec<String>,
 
-    /// Raft: Ip address of the server.
-    #[arg(short = 'a', long, env)]
-    self_addr: String,
+    /// Raft: Ip address of the server. Named after its first --kv-addr with --standalone or
+    /// --replica-of.
+    #[arg(short = 'a', long, env, required_unless_present_any = ["standalone", "replica_of"])]
+    self_addr: Option<String>,
+
+    /// Run a single node without Raft, for local development and tests: writes are applied as
+    /// soon as they are proposed and no peer is configured. Commands behave as in a group.
+    #[arg(long, env, conflicts_with_all = ["peer_addr", "peer_kv_addr"])]
+    standalone: bool,
+
+    /// Id of the node, from 0 to 32767, kept in the high bits of the ids of its clients so that
+    /// they are unique across the cluster. Derived from --self-addr when unset; give every node
+    /// its own to rule out two of them sharing one.
+    #[arg(long, env, value_parser = clap::value_parser!(u16).range(0..=crate::clients::MAX_NODE_ID as i64))]
+    node_id: Option<u16>,
+
+    /// Run as a read replica outside the Raft group, tailing the committed log of the first of
+    /// these members, given by their kv address, that is reachable. Writes are refused.
+    #[arg(
+        long,
+        env,
+        num_args = 1..,
+        value_delimiter = ' ',
+        conflicts_with_all = ["peer_addr", "peer_kv_addr", "standalone"]
+    )]
+    replica_of: Vec<String>,
+
+    /// Start as a warm standby, replicating and applying every entry but refusing the commands
+    /// of the clients until PROMOTE.
+    #[arg(long, env)]
+    standby: bool,
+
+    /// User a read replica authenticates as on the members it tails, allowed to run RAFT TAIL.
+    #[arg(long, env, requires = "replica_password")]
+    replica_user: Option<String>,
+
+    /// Password of --replica-user.
+    #[arg(long, env, requires = "replica_user")]
+    replica_password: Option<Secret>,
+
+    /// Ip addresses the kv server listens on, one accept loop per address
+    /// usage:
+    /// ./kv-rs --kv-addr 0.0.0.0:6379 --kv-addr [::]:6379
+    #[arg(short = 'k', long, env, num_args = 1.., value_delimiter = ' ', default_value = "0.0.0.0:6379")]
+    kv_addr: Vec<String>,
+
+    /// Ip addresses the kv server listens on over TLS, besides --kv-addr, with --tls-cert and
+    /// --tls-key
+    #[arg(long, env, num_args = 1.., value_delimiter = ' ', requires_all = ["tls_cert", "tls_key"])]
+    tls_addr: Vec<String>,
+
+    /// PEM file of the certificate chain the node presents on --tls-addr, its own first
+    #[arg(long, env, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of --tls-cert
+    #[arg(long, env, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 
-    /// Ip address of the kv server.
-    #[arg(short = 'k', long, env, default_value = "0.0.0.0:6379")]
-    kv_addr: String,
+    /// PEM file of the certificate authorities the clients on --tls-addr must present a
+    /// certificate of, for mutual TLS. Clients need no certificate when unset.
+    #[arg(long, env, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
 
     /// Relative path to the server's data directory.
     #[arg(short = 'd', long, env, default_value = "./data/kv_server/storage")]
     directory: PathBuf,
 
-    /// Relative path to the server's raft state file.
+    /// Relative path to the server's raft state file. raft-lite 0.2.6 reads it as the node starts
+    /// but never writes it: the log is kept in memory and sent again by the group on a restart.
     #[arg(short = 'r', long, env, default_value = "./data/raft/raft_state")]
     raft_state_file: PathBuf,
 
+    /// Size of the latest committed Raft entries the node keeps in memory for RAFT LOG, e.g.
+    /// 16mb. None are kept with 0.
+    #[arg(long, env, default_value_t = 16 * 1024 * 1024, value_parser = units::bytes)]
+    raft_log_kept: u64,
+
+    /// Relative path to the file the cumulative statistics of INFO are saved to.
+    #[arg(long, env, default_value = "./data/stats")]
+    stats_file: PathBuf,
+
     /// Set the log level.
     #[arg(long = "ll", long, env, default_value = "debug")]
     log_level: String,
@@ -35,6 +116,341 @@ pub struct Args {
     /// Logging filter
     #[arg(long, env, default_value = "tokio=error,tarpc=error,raft_lite=info")]
     rust_log: String,
+
+    /// Length in seconds of the sliding window used for SLO tracking.
+    #[arg(long, env, default_value_t = 60, value_parser = units::secs)]
+    slo_window_secs: u64,
+
+    /// Log an alert when the write error rate (errors and timeouts) over the SLO window exceeds this ratio, e.g. 0.01.
+    #[arg(long, env)]
+    slo_write_error_threshold: Option<f64>,
+
+    /// Rate in MB/s at which the background scrubber re-reads and verifies stored entries.
+    /// The scrubber is disabled when unset.
+    #[arg(long, env)]
+    scrub_rate_mb: Option<f64>,
+
+    /// Pause in seconds between two full scrubber passes.
+    #[arg(long, env, default_value_t = 3600, value_parser = units::secs)]
+    scrub_interval_secs: u64,
+
+    /// Pause in seconds between two passes counting the keys by when they expire, for INFO
+    /// expiry. The forecast is disabled when 0.
+    #[arg(long, env, default_value_t = 300, value_parser = units::secs)]
+    expiry_forecast_interval_secs: u64,
+
+    /// Pause in seconds between two checks of whether the data files are worth compacting.
+    /// Scheduled compactions are disabled when 0, COMPACT still runs one.
+    #[arg(long, env, default_value_t = 600, value_parser = units::secs)]
+    compaction_interval_secs: u64,
+
+    /// Share of the bytes of the data files, from 0 to 1, held by overwritten or deleted values
+    /// and tombstones, from which a scheduled compaction runs.
+    #[arg(long, env, default_value_t = 0.5)]
+    compaction_dead_ratio: f64,
+
+    /// Number of data files from which a scheduled compaction runs, whatever their dead bytes.
+    /// No limit when 0.
+    #[arg(long, env, default_value_t = 0)]
+    compaction_max_files: usize,
+
+    /// Size limit of the in-memory LRU cache of values read by GET, e.g. 64mb.
+    /// The cache is disabled when unset.
+    #[arg(long, env, value_parser = units::bytes)]
+    read_cache_size: Option<u64>,
+
+    /// Deprecated, --read-cache-size in MB.
+    #[arg(long, env, hide = true)]
+    read_cache_mb: Option<u64>,
+
+    /// Maximum number of keys remembered as missing by the negative lookup cache of GET.
+    /// The cache is disabled when unset.
+    #[arg(long, env)]
+    negative_cache_keys: Option<usize>,
+
+    /// Bytes the keys of the clients and their values may take, approximately, before writes
+    /// evict keys or are rejected. 0 sets no bound and tracks no key, so that CONFIG SET
+    /// maxmemory only bounds a node started with one.
+    #[arg(long, env, default_value_t = 0, value_parser = units::bytes)]
+    maxmemory: u64,
+
+    /// What is evicted over --maxmemory: `noeviction` rejects the writes with -OOM,
+    /// `allkeys-lru` and `allkeys-random` evict any key, `volatile-ttl` the keys expiring first.
+    #[arg(long, env, value_enum, default_value = "noeviction")]
+    maxmemory_policy: Policy,
+
+    /// Path to an ACL file with one `user <name> <rules>...` line per user.
+    /// Without it, clients run every command as the default user, without a password.
+    #[arg(long, env)]
+    acl_file: Option<PathBuf>,
+
+    /// Milliseconds a client waits for a write to be replicated and applied before it times out.
+    #[arg(long, env, default_value_t = 10_000, value_parser = units::nonzero(units::millis))]
+    write_timeout_ms: u64,
+
+    /// Maximum number of writes proposed together as a single Raft entry. Every node must run a
+    /// version reading batches before it is raised above 1, which proposes each write alone.
+    #[arg(long, env, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_max_entries: u64,
+
+    /// Size in bytes past which no more writes join a batch.
+    #[arg(long, env, default_value_t = 1024 * 1024, value_parser = units::bytes)]
+    batch_max_bytes: u64,
+
+    /// Microseconds a batch waits for more writes before it is proposed, 0 proposing the writes
+    /// already queued right away.
+    #[arg(long, env, default_value_t = 0)]
+    batch_linger_us: u64,
+
+    /// Wait for more writes only under load: the wait doubles, up to --batch-linger-us, while the
+    /// batches fill up and halves while writes go alone.
+    #[arg(long, env)]
+    batch_adaptive: bool,
+
+    /// Leave a SET out of its batch when a later SET of the same key is queued in it, answering
+    /// both with the result of the later one, for hot keys overwritten faster than they replicate.
+    #[arg(long, env)]
+    batch_coalesce: bool,
+
+    /// Maximum number of clients connected at once, further connections are refused.
+    #[arg(long, env, alias = "maxclients", default_value_t = 10_000)]
+    max_clients: usize,
+
+    /// Seconds a client may stay idle, sending no command, before its connection is closed. 0
+    /// never closes them. Subscribers and monitors wait for the node and are never idle.
+    #[arg(long, env, default_value_t = 0, value_parser = units::secs)]
+    timeout: u64,
+
+    /// Writes of a single connection waiting for their reply past which its further writes are
+    /// rejected with BUSY, so that a pipelining client cannot hold the proposal queue alone. 0
+    /// never rejects them.
+    #[arg(long, env, default_value_t = 128)]
+    max_in_flight_writes: usize,
+
+    /// Writes of all the connections waiting to be proposed past which further writes are
+    /// rejected with BUSY until the queue drains.
+    #[arg(long, env, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    proposal_queue_len: u64,
+
+    /// Longest bulk string a client may send, in bytes. Past it, or the following limits, the
+    /// connection is closed with a protocol error.
+    #[arg(long, env, default_value_t = 512 * 1024 * 1024, value_parser = units::bytes_usize)]
+    proto_max_bulk_len: usize,
+
+    /// Most elements of an array a client may send.
+    #[arg(long, env, default_value_t = 1024 * 1024)]
+    proto_max_array_len: usize,
+
+    /// Deepest nesting of arrays a client may send.
+    #[arg(long, env, default_value_t = 32)]
+    proto_max_nesting: usize,
+
+    /// Committed writes waiting to be applied past which the node sheds load, rejecting writes
+    /// with BUSY and pausing the scrubber until the backlog drains. 0 never sheds load.
+    #[arg(long, env, default_value_t = 10_000)]
+    busy_apply_lag: usize,
+
+    /// Microseconds a command must take, until its reply is ready, to be logged in the slow log.
+    /// 0 logs every command, a negative value disables the slow log.
+    #[arg(long, env, default_value_t = 10_000, allow_negative_numbers = true)]
+    slowlog_log_slower_than: i64,
+
+    /// Number of commands the slow log keeps, the oldest are dropped first.
+    #[arg(long, env, default_value_t = 128)]
+    slowlog_max_len: usize,
+
+    /// Number of databases a connection may SELECT, numbered from 0. Every node must be given the
+    /// same number.
+    #[arg(long, env, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+    databases: u32,
+
+    /// Seconds FLUSHALL CONFIRM may take after FLUSHALL SCHEDULE, past which the flush must be
+    /// scheduled again.
+    #[arg(long, env, default_value_t = 60, value_parser = units::nonzero(units::secs))]
+    flushall_confirm_secs: u64,
+
+    /// Seconds a read view of SNAPSHOT OPEN lasts at most. A view keeps the data files a
+    /// compaction replaced until it is closed.
+    #[arg(long, env, default_value_t = 60, value_parser = units::nonzero(units::secs))]
+    read_view_max_secs: u64,
+
+    /// Percent of the EX or PX time of SET and GETEX added to it at random, at most, so that
+    /// values set with the same TTL do not all expire in the same second. 0 adds none.
+    #[arg(long, env, default_value_t = 0, value_parser = clap::value_parser!(u64).range(0..=100))]
+    ttl_jitter_percent: u64,
+
+    /// How long a key set with SET ... EPHEMERAL outlives its connection if the node of the
+    /// connection goes away with it, in milliseconds. The node renews the lease while the
+    /// connection stays open, and deletes the key as soon as it closes.
+    #[arg(long, env, default_value_t = 10_000, value_parser = units::nonzero(units::millis))]
+    ephemeral_lease_ms: u64,
+
+    /// Consistency of the reads not choosing theirs with GET ... CONSISTENCY. `local` serves what
+    /// the node applied so far, which may lag behind the leader; `leader` and `linearizable` wait
+    /// for the writes committed anywhere before the read started, at the cost of a Raft round trip.
+    #[arg(long, env, value_enum, default_value = "local")]
+    read_consistency: Consistency,
+
+    /// Size in bytes above which a write is not proposed as a single Raft entry, which every
+    /// other write would queue behind while it replicates. 0 proposes writes of any size.
+    #[arg(long, env, default_value_t = 0, value_parser = units::bytes)]
+    proposal_max_bytes: u64,
+
+    /// What becomes of a write above --proposal-max-bytes: `reject` replies an error, `split`
+    /// proposes the value of a SET in chunks of half that size, other writes being rejected.
+    #[arg(long, env, value_enum, default_value = "reject")]
+    proposal_oversize: Oversize,
+
+    /// Codec compressing the values of SET of at least --compression-min-bytes before they are
+    /// proposed, `lz4` or `zstd`. Values stored either way are read whatever the codec is now.
+    #[arg(long, env, value_enum, default_value = "none")]
+    compression: Compression,
+
+    /// Size in bytes from which the values of SET are compressed.
+    #[arg(long, env, default_value_t = 1024, value_parser = units::bytes_usize)]
+    compression_min_bytes: usize,
+
+    /// Paths of plugins to load, shared libraries adding commands through the C ABI of
+    /// include/storgata_plugin.h. Every node must load the same plugins.
+    #[arg(long, env, num_args = 1.., value_delimiter = ' ')]
+    plugin: Vec<PathBuf>,
+
+    /// Experimental features to turn on on this node, until FEATURE DISABLE turns them off for
+    /// the whole group. FEATURE LIST names them.
+    #[arg(long, env, num_args = 1.., value_delimiter = ' ')]
+    enable_features: Vec<String>,
+
+    /// Features to turn off on this node, until FEATURE ENABLE turns them on for the whole group.
+    #[arg(long, env, num_args = 1.., value_delimiter = ' ')]
+    disable_features: Vec<String>,
+
+    /// Key prefixes to break the command metrics down by, as name=prefix, such as
+    /// billing=billing: for the keys of an application, in INFO prefixstats. At most 32.
+    #[arg(long, env, num_args = 1.., value_delimiter = ' ')]
+    metrics_key_prefix: Vec<String>,
+
+    /// Key prefixes under secure delete: the commands on their keys are kept out of the history
+    /// of the node and the values they no longer hold are overwritten in the data files.
+    #[arg(long, env, num_args = 1.., value_delimiter = ' ')]
+    secure_delete_prefix: Vec<String>,
+
+    /// Pause in seconds between two passes overwriting the values of secure delete.
+    #[arg(long, env, default_value_t = 3600, value_parser = units::secs)]
+    secure_delete_interval_secs: u64,
+
+    /// Key signing the bundles of EXPORT with HMAC-SHA256, EXPORT is disabled when unset.
+    #[arg(long, env)]
+    export_signing_key: Option<String>,
+
+    /// Seconds an export waits after the previous one on the node.
+    #[arg(long, env, default_value_t = 60, value_parser = units::secs)]
+    export_min_interval_secs: u64,
+
+    /// Ip:port the kv servers of --peer-addr serve clients on, in the same order, for ADMIN
+    /// BROADCAST to reach them. Broadcasts only run on this node when unset.
+    #[arg(long, env, num_args = 1.., value_delimiter = ' ')]
+    peer_kv_addr: Vec<String>,
+
+    /// Ip address a frozen snapshot of the node, taken with SNAPSHOT TAKE, is served read-only on,
+    /// for analytical scans. Disabled when unset.
+    #[arg(long, env)]
+    analytics_addr: Option<String>,
+
+    /// Ip address the gRPC API of the node is served on, besides RESP. Needs a build with the
+    /// `grpc` feature. Disabled when unset.
+    #[arg(long, env)]
+    grpc_addr: Option<String>,
+
+    /// Ip address the HTTP gateway of the node is served on, with `GET`, `PUT` and `DELETE
+    /// /keys/{key}` and `POST /batch`. Disabled when unset.
+    #[arg(long, env)]
+    http_addr: Option<String>,
+
+    /// Milliseconds the barrier entry replicated by `/readyz` of the HTTP gateway may take to be
+    /// applied, past which the node is reported as not ready, having no leader to commit it.
+    #[arg(long, env, default_value_t = 2000, value_parser = units::nonzero(units::millis))]
+    ready_timeout_ms: u64,
+
+    /// Committed entries waiting to be applied past which `/readyz` and `/raftz` report the node
+    /// as still catching up with the log.
+    #[arg(long, env, default_value_t = 1000)]
+    ready_max_apply_lag: usize,
+
+    /// Relative path to the directory holding the files of the snapshots served for analytics.
+    #[arg(long, env, default_value = "./data/analytics")]
+    analytics_dir: PathBuf,
+
+    /// Seconds after which a snapshot served for analytics expires, closing its connections.
+    #[arg(long, env, default_value_t = 3600, value_parser = units::secs)]
+    analytics_ttl_secs: u64,
+
+    /// When to back up the data files, as a crontab schedule in UTC: minute hour day month
+    /// weekday, e.g. "0 3 * * *" for every night at 3. Only BGSAVE takes backups when unset.
+    #[arg(long, env)]
+    backup_schedule: Option<String>,
+
+    /// Relative path to the directory the backups are written to, one directory per backup.
+    #[arg(long, env, default_value = "./backups")]
+    backup_dir: PathBuf,
+
+    /// Number of backups kept, the oldest are removed first.
+    #[arg(long, env, default_value_t = 7, value_parser = clap::value_parser!(u64).range(1..))]
+    backup_retain: u64,
+
+    /// Every how many backups one copies the data files whole, the others copying what was
+    /// appended to them since the previous backup. The first backup after a start is whole.
+    #[arg(long, env, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    backup_full_every: u64,
+
+    /// S3 URL, s3://bucket/prefix, each backup is also uploaded to, under the time of the backup.
+    /// Give each node a prefix of its own.
+    #[arg(long, env)]
+    backup_s3_url: Option<String>,
+
+    /// Backup, or directory of the backups of a node to restore the latest of, laid into
+    /// --directory on startup unless it holds data already. An S3 URL, s3://bucket/prefix/<time>
+    /// or s3://bucket/prefix, is downloaded from the store.
+    #[arg(long, env)]
+    restore_from: Option<String>,
+
+    /// Redis RDB file whose string keys are set, with their expiry, once the node joined its
+    /// group, after which it shuts down. Keys of other types are skipped.
+    #[arg(long, env)]
+    import_rdb: Option<PathBuf>,
+
+    /// Base URL of the S3-compatible store of --backup-s3-url and --restore-from, e.g.
+    /// http://localhost:9000. Only plain HTTP is supported.
+    #[arg(long, env)]
+    s3_endpoint: Option<String>,
+
+    /// Region the requests to the S3-compatible store are signed for.
+    #[arg(long, env, default_value = "us-east-1")]
+    s3_region: String,
+
+    /// Access key id of the S3-compatible store.
+    #[arg(long, env = "AWS_ACCESS_KEY_ID")]
+    s3_access_key_id: Option<String>,
+
+    /// Secret access key of the S3-compatible store.
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY")]
+    s3_secret_access_key: Option<Secret>,
+
+    /// Base URL of an OpenTelemetry collector, e.g. http://localhost:4318, the spans of the
+    /// commands are exported to over OTLP/HTTP. Needs a build with the `otlp` feature.
+    #[arg(long, env)]
+    otlp_endpoint: Option<String>,
+
+    /// Relative path to the directory the audit log is written to, a JSON line per write
+    /// committed for a client of the node. Disabled when unset.
+    #[arg(long, env)]
+    audit_dir: Option<PathBuf>,
+
+    /// When the audit log moves on to a new file.
+    #[arg(long, env, value_enum, default_value = "daily")]
+    audit_rotation: Rotation,
+
+    #[arg(skip)]
+    deprecated: Vec<&'static Deprecated>,
 }
 
 impl Args {
@@ -47,7 +463,32 @@ impl Args {
     }
 
     pub fn self_addr(&self) -> String {
-        self.self_addr.clone()
+        self.self_addr
+            .clone()
+            .unwrap_or_else(|| self.kv_addr[0].clone())
+    }
+
+    pub fn node_id(&self) -> u16 {
+        self.node_id
+            .unwrap_or_else(|| clients::node_id(&self.self_addr()))
+    }
+
+    pub fn standalone(&self) -> bool {
+        self.standalone
+    }
+
+    pub fn replica_of(&self) -> &[String] {
+        &self.replica_of
+    }
+
+    pub fn standby(&self) -> bool {
+        self.standby
+    }
+
+    pub fn replica_credentials(&self) -> Option<(String, String)> {
+        let user = self.replica_user.clone()?;
+        let password = self.replica_password.as_ref()?.0.clone();
+        Some((user, password))
     }
 
     pub fn peer_addr(&self) -> Vec<String> {
@@ -62,11 +503,388 @@ impl Args {
         self.raft_state_file.clone()
     }
 
-    pub fn kv_addr(&self) -> String {
+    pub fn raft_log_kept(&self) -> u64 {
+        self.raft_log_kept
+    }
+
+    pub fn stats_file(&self) -> PathBuf {
+        self.stats_file.clone()
+    }
+
+    pub fn kv_addr(&self) -> Vec<String> {
         self.kv_addr.clone()
     }
+
+    pub fn tls_addr(&self) -> &[String] {
+        &self.tls_addr
+    }
+
+    pub fn tls_cert(&self) -> Option<&Path> {
+        self.tls_cert.as_deref()
+    }
+
+    pub fn tls_key(&self) -> Option<&Path> {
+        self.tls_key.as_deref()
+    }
+
+    pub fn tls_ca(&self) -> Option<&Path> {
+        self.tls_ca.as_deref()
+    }
+
+    pub fn slo_window_secs(&self) -> u64 {
+        self.slo_window_secs
+    }
+
+    pub fn slo_write_error_threshold(&self) -> Option<f64> {
+        self.slo_write_error_threshold
+    }
+
+    pub fn scrub_rate_mb(&self) -> Option<f64> {
+        self.scrub_rate_mb
+    }
+
+    pub fn scrub_interval_secs(&self) -> u64 {
+        self.scrub_interval_secs
+    }
+
+    pub fn expiry_forecast_interval_secs(&self) -> u64 {
+        self.expiry_forecast_interval_secs
+    }
+
+    pub fn compaction_interval_secs(&self) -> u64 {
+        self.compaction_interval_secs
+    }
+
+    pub fn compaction_dead_ratio(&self) -> f64 {
+        self.compaction_dead_ratio
+    }
+
+    pub fn compaction_max_files(&self) -> usize {
+        self.compaction_max_files
+    }
+
+    /// In bytes, from the deprecated --read-cache-mb unless --read-cache-size is given
+    pub fn read_cache_size(&self) -> Option<u64> {
+        self.read_cache_size
+            .or(self.read_cache_mb.map(|mb| mb * 1024 * 1024))
+    }
+
+    pub fn negative_cache_keys(&self) -> Option<usize> {
+        self.negative_cache_keys
+    }
+
+    pub fn maxmemory(&self) -> u64 {
+        self.maxmemory
+    }
+
+    pub(crate) fn maxmemory_policy(&self) -> Policy {
+        self.maxmemory_policy
+    }
+
+    pub fn acl_file(&self) -> Option<&Path> {
+        self.acl_file.as_deref()
+    }
+
+    pub fn write_timeout_ms(&self) -> u64 {
+        self.write_timeout_ms
+    }
+
+    pub fn batch_max_entries(&self) -> usize {
+        self.batch_max_entries as usize
+    }
+
+    pub fn batch_max_bytes(&self) -> usize {
+        self.batch_max_bytes as usize
+    }
+
+    pub fn batch_linger_us(&self) -> u64 {
+        self.batch_linger_us
+    }
+
+    pub fn batch_adaptive(&self) -> bool {
+        self.batch_adaptive
+    }
+
+    pub fn batch_coalesce(&self) -> bool {
+        self.batch_coalesce
+    }
+
+    pub fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+
+    pub fn max_in_flight_writes(&self) -> usize {
+        self.max_in_flight_writes
+    }
+
+    pub fn proposal_queue_len(&self) -> usize {
+        self.proposal_queue_len as usize
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    pub(crate) fn proto_limits(&self) -> Limits {
+        Limits {
+            max_bulk_len: self.proto_max_bulk_len,
+            max_array_len: self.proto_max_array_len,
+            max_nesting: self.proto_max_nesting,
+        }
+    }
+
+    pub fn busy_apply_lag(&self) -> usize {
+        self.busy_apply_lag
+    }
+
+    pub fn slowlog_log_slower_than(&self) -> i64 {
+        self.slowlog_log_slower_than
+    }
+
+    pub fn slowlog_max_len(&self) -> usize {
+        self.slowlog_max_len
+    }
+
+    pub fn databases(&self) -> u32 {
+        self.databases
+    }
+
+    pub fn flushall_confirm_secs(&self) -> u64 {
+        self.flushall_confirm_secs
+    }
+
+    pub fn read_view_max_secs(&self) -> u64 {
+        self.read_view_max_secs
+    }
+
+    pub fn ttl_jitter_percent(&self) -> u64 {
+        self.ttl_jitter_percent
+    }
+
+    pub fn ephemeral_lease_ms(&self) -> u64 {
+        self.ephemeral_lease_ms
+    }
+
+    pub(crate) fn read_consistency(&self) -> Consistency {
+        self.read_consistency
+    }
+
+    pub fn proposal_max_bytes(&self) -> u64 {
+        self.proposal_max_bytes
+    }
+
+    pub(crate) fn proposal_oversize(&self) -> Oversize {
+        self.proposal_oversize
+    }
+
+    pub(crate) fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn compression_min_bytes(&self) -> usize {
+        self.compression_min_bytes
+    }
+
+    pub fn plugin(&self) -> &[PathBuf] {
+        &self.plugin
+    }
+
+    pub fn enable_features(&self) -> &[String] {
+        &self.enable_features
+    }
+
+    pub fn metrics_key_prefix(&self) -> &[String] {
+        &self.metrics_key_prefix
+    }
+
+    pub fn secure_delete_prefix(&self) -> &[String] {
+        &self.secure_delete_prefix
+    }
+
+    pub fn secure_delete_interval_secs(&self) -> u64 {
+        self.secure_delete_interval_secs
+    }
+
+    pub fn export_signing_key(&self) -> Option<&str> {
+        self.export_signing_key.as_deref()
+    }
+
+    pub fn export_min_interval_secs(&self) -> u64 {
+        self.export_min_interval_secs
+    }
+
+    pub fn disable_features(&self) -> &[String] {
+        &self.disable_features
+    }
+
+    pub fn peer_kv_addr(&self) -> &[String] {
+        &self.peer_kv_addr
+    }
+
+    pub fn analytics_addr(&self) -> Option<&str> {
+        self.analytics_addr.as_deref()
+    }
+
+    pub fn grpc_addr(&self) -> Option<&str> {
+        self.grpc_addr.as_deref()
+    }
+
+    pub fn http_addr(&self) -> Option<&str> {
+        self.http_addr.as_deref()
+    }
+
+    pub fn ready_timeout_ms(&self) -> u64 {
+        self.ready_timeout_ms
+    }
+
+    pub fn ready_max_apply_lag(&self) -> usize {
+        self.ready_max_apply_lag
+    }
+
+    pub fn backup_schedule(&self) -> Option<&str> {
+        self.backup_schedule.as_deref()
+    }
+
+    pub fn backup_dir(&self) -> &Path {
+        &self.backup_dir
+    }
+
+    pub fn backup_retain(&self) -> usize {
+        self.backup_retain as usize
+    }
+
+    pub fn backup_full_every(&self) -> usize {
+        self.backup_full_every as usize
+    }
+
+    pub fn backup_s3_url(&self) -> Option<&str> {
+        self.backup_s3_url.as_deref()
+    }
+
+    pub fn restore_from(&self) -> Option<&str> {
+        self.restore_from.as_deref()
+    }
+
+    pub fn import_rdb(&self) -> Option<&Path> {
+        self.import_rdb.as_deref()
+    }
+
+    /// The client of the S3-compatible store backups are uploaded to and restored from
+    pub(crate) fn s3(&self) -> anyhow::Result<S3> {
+        let missing = |flag| anyhow!("{} is needed to reach the S3-compatible store", flag);
+        S3::new(
+            self.s3_endpoint
+                .as_deref()
+                .ok_or_else(|| missing("--s3-endpoint"))?,
+            &self.s3_region,
+            self.s3_access_key_id
+                .as_deref()
+                .ok_or_else(|| missing("--s3-access-key-id"))?,
+            &self
+                .s3_secret_access_key
+                .as_ref()
+                .ok_or_else(|| missing("--s3-secret-access-key"))?
+                .0,
+        )
+    }
+
+    pub fn analytics_dir(&self) -> &Path {
+        &self.analytics_dir
+    }
+
+    pub fn analytics_ttl_secs(&self) -> u64 {
+        self.analytics_ttl_secs
+    }
+
+    pub fn otlp_endpoint(&self) -> Option<&str> {
+        self.otlp_endpoint.as_deref()
+    }
+
+    pub fn audit_dir(&self) -> Option<&Path> {
+        self.audit_dir.as_deref()
+    }
+
+    pub(crate) fn audit_rotation(&self) -> Rotation {
+        self.audit_rotation
+    }
+
+    pub fn config(&self) -> Option<&Path> {
+        self.config.as_deref()
+    }
+
+    /// The deprecated settings given, by flag, environment or configuration file
+    pub(crate) fn deprecated(&self) -> &[&'static Deprecated] {
+        &self.deprecated
+    }
 }
 
-pub fn parse_args() -> Args {
-    Args::parse()
+/// The flags of the matches, noting the deprecated ones given
+fn from_matches(matches: ArgMatches) -> anyhow::Result<Args> {
+    let mut args = Args::from_arg_matches(&matches)?;
+    args.deprecated = config_file::DEPRECATED
+        .iter()
+        .filter(|deprecated| {
+            matches
+                .value_source(&deprecated.name.replace('-', "_"))
+                .is_some()
+        })
+        .collect();
+    Ok(args)
+}
+
+/// A flag kept out of the logs
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl std::str::FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(redacted)")
+    }
+}
+
+pub fn parse_args() -> anyhow::Result<Args> {
+    if let Some(path) = config_path() {
+        let flags: Vec<(String, String)> = Args::command()
+            .get_arguments()
+            .filter(|arg| arg.get_id() != "config")
+            .filter_map(|arg| {
+                let env = arg.get_env()?.to_str()?;
+                Some((arg.get_long()?.to_string(), env.to_string()))
+            })
+            .collect();
+        config_file::apply(&path, &flags)?;
+    }
+    from_matches(Args::command().get_matches())
+}
+
+/// Parse the flags from `args`, the first being the name of the program, rather than the command
+/// line, as an application embedding a node does. No configuration file is read.
+pub fn parse_args_from<I, T>(args: I) -> anyhow::Result<Args>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    from_matches(Args::command().try_get_matches_from(args)?)
+}
+
+/// The configuration file, looked up before the flags are parsed as it provides some of them
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("STORGATA_CONFIG").map(PathBuf::from)
 }
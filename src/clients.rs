@@ -0,0 +1,279 @@
+//! Registry of the client connections of the node, so operators can list them with CLIENT LIST
+//! and evict misbehaving ones with CLIENT KILL without restarting the node.
+//!
+//! Each client keeps its last commands, their names and keys but never their values, so that
+//! what a client did right before an error or a protocol desync can be told from CLIENT INFO ID
+//! <id> HISTORY or from the log of the connection error.
+//!
+//! Client ids are unique across the cluster: the id of the node is kept in their high bits, the
+//! count of its connections in the low ones, so that an id seen in a slow log entry or a log line
+//! tells which node to run CLIENT KILL or CLIENT INFO ID on.
+
+use crc::{Crc, CRC_16_XMODEM};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+pub(crate) type ClientId = u64;
+
+/// Bits of a client id counting the connections of its node, the bits above hold the node id
+const NODE_SHIFT: u32 = 48;
+/// Ids of nodes are below it, so that client ids stay positive RESP integers
+pub(crate) const MAX_NODE_ID: u16 = 0x7fff;
+
+/// The id of a node not given --node-id, from its Raft address, so that it is the same across
+/// restarts
+pub(crate) fn node_id(self_addr: &str) -> u16 {
+    Crc::<u16>::new(&CRC_16_XMODEM).checksum(self_addr.as_bytes()) & MAX_NODE_ID
+}
+
+/// Commands kept per client, the oldest make room for the newest
+const HISTORY_LEN: usize = 16;
+
+/// A CLIENT subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum ClientOp {
+    Id,
+    GetName,
+    SetName(String),
+    List,
+    Kill(KillFilter),
+    // Client, the calling one if None, and whether to reply with its history
+    Info(Option<ClientId>, bool),
+    // Whether the replies to the next commands come with the time spent in each stage
+    Trace(bool),
+    // Whether the keys read are pushed an invalidation once written
+    Tracking(bool),
+    // Whether the replies to the writes come with the index of the log applied once answered
+    Watermark(bool),
+}
+
+/// The clients CLIENT KILL evicts, those matching every given criterion
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct KillFilter {
+    pub(crate) id: Option<ClientId>,
+    pub(crate) addr: Option<String>,
+    // SKIPME: leave the calling client alone, yes unless told otherwise
+    pub(crate) skip_me: bool,
+    // `CLIENT KILL addr`: replies OK, or an error if no client has the address
+    pub(crate) legacy: bool,
+}
+
+struct ClientState {
+    name: String,
+    last_command: &'static str,
+    last_interaction: Instant,
+    history: VecDeque<Recorded>,
+}
+
+struct Recorded {
+    name: &'static str,
+    key: Option<Vec<u8>>,
+    at: Instant,
+}
+
+/// A registered connection
+pub(crate) struct Client {
+    pub(crate) id: ClientId,
+    addr: SocketAddr,
+    created: Instant,
+    state: Mutex<ClientState>,
+    in_flight_writes: AtomicUsize,
+    // index of the Raft entry of the last write the node applied for the client, for WAIT
+    last_write: AtomicU64,
+    killed: CancellationToken,
+}
+
+impl Client {
+    pub(crate) fn record_command(&self, name: &'static str, key: Option<&[u8]>) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.last_command = name;
+        state.last_interaction = now;
+        if state.history.len() == HISTORY_LEN {
+            state.history.pop_front();
+        }
+        state.history.push_back(Recorded {
+            name,
+            key: key.map(<[u8]>::to_vec),
+            at: now,
+        });
+    }
+
+    /// The last commands of the client, oldest first, as `age=<secs> cmd=<name> key=<key>`
+    pub(crate) fn history(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .history
+            .iter()
+            .map(|recorded| {
+                let mut line = format!(
+                    "age={} cmd={}",
+                    recorded.at.elapsed().as_secs(),
+                    recorded.name
+                );
+                if let Some(key) = &recorded.key {
+                    let _ = write!(line, " key={}", key.escape_ascii());
+                }
+                line
+            })
+            .collect()
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub(crate) fn name(&self) -> String {
+        self.state.lock().unwrap().name.clone()
+    }
+
+    pub(crate) fn set_name(&self, name: String) {
+        self.state.lock().unwrap().name = name;
+    }
+
+    pub(crate) fn write_started(&self) {
+        self.in_flight_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn write_finished(&self) {
+        self.in_flight_writes.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Writes proposed for the client and not answered yet
+    pub(crate) fn in_flight_writes(&self) -> usize {
+        self.in_flight_writes.load(Ordering::Relaxed)
+    }
+
+    /// The write was applied by the node, as the entry at the index or an earlier one
+    pub(crate) fn write_applied(&self, index: u64) {
+        self.last_write.fetch_max(index, Ordering::Relaxed);
+    }
+
+    /// Index of the Raft entry of the last write applied for the client, 0 before any
+    pub(crate) fn last_write(&self) -> u64 {
+        self.last_write.load(Ordering::Relaxed)
+    }
+
+    /// Close the connection once the replies to the commands of the client are sent
+    pub(crate) fn close(&self) {
+        self.killed.cancel();
+    }
+
+    /// Resolves once the client is killed
+    pub(crate) fn killed(&self) -> WaitForCancellationFuture<'_> {
+        self.killed.cancelled()
+    }
+
+    /// One line of CLIENT LIST, the reply to CLIENT INFO
+    pub(crate) fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        format!(
+            "id={} node={} addr={} name={} age={} idle={} cmd={} in-flight-writes={}\n",
+            self.id,
+            self.id >> NODE_SHIFT,
+            self.addr,
+            state.name,
+            self.created.elapsed().as_secs(),
+            state.last_interaction.elapsed().as_secs(),
+            state.last_command,
+            self.in_flight_writes(),
+        )
+    }
+}
+
+pub(crate) struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<ClientId, Arc<Client>>>,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Clients {
+    pub(crate) fn new(node_id: u16) -> Self {
+        Self {
+            // like Redis, ids start at 1, past the id of the node, and are never reused
+            next_id: AtomicU64::new((u64::from(node_id.min(MAX_NODE_ID)) << NODE_SHIFT) + 1),
+            clients: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn register(&self, addr: SocketAddr) -> Arc<Client> {
+        let now = Instant::now();
+        let client = Arc::new(Client {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            created: now,
+            state: Mutex::new(ClientState {
+                name: String::new(),
+                last_command: "NULL",
+                last_interaction: now,
+                history: VecDeque::with_capacity(HISTORY_LEN),
+            }),
+            in_flight_writes: AtomicUsize::new(0),
+            last_write: AtomicU64::new(0),
+            killed: CancellationToken::new(),
+        });
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client.id, client.clone());
+        client
+    }
+
+    pub(crate) fn deregister(&self, id: ClientId) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn get(&self, id: ClientId) -> Option<Arc<Client>> {
+        self.clients.lock().unwrap().get(&id).cloned()
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// The clients section of INFO
+    pub(crate) fn info(&self) -> String {
+        format!("connected_clients:{}\r\n", self.count())
+    }
+
+    /// The CLIENT LIST reply, one line per client
+    pub(crate) fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut out = String::new();
+        for client in clients.values() {
+            let _ = write!(out, "{}", client.info());
+        }
+        out
+    }
+
+    /// Kill the clients matching the filter on behalf of `me`, returning how many were killed
+    pub(crate) fn kill(&self, filter: &KillFilter, me: ClientId) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for client in clients.values() {
+            if filter.id.is_some_and(|id| id != client.id)
+                || filter
+                    .addr
+                    .as_ref()
+                    .is_some_and(|addr| *addr != client.addr.to_string())
+                || (filter.skip_me && client.id == me)
+            {
+                continue;
+            }
+            client.killed.cancel();
+            killed += 1;
+        }
+        killed
+    }
+}
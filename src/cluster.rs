@@ -0,0 +1,59 @@
+//! Key to slot mapping, the same as Redis Cluster, so that clients and operators can already
+//! reason about slots. StorgataDB runs a single Raft group: it owns every slot and replicates it
+//! to every node, and no slot is ever migrating.
+
+use crate::resp_codec::RespValue;
+use bytes::Bytes;
+use crc::{Crc, CRC_16_XMODEM};
+use serde::{Deserialize, Serialize};
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+const SLOTS: u16 = 16384;
+/// The only shard, until keys are partitioned across Raft groups
+const SHARD: i64 = 0;
+
+/// A CLUSTER subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum ClusterOp {
+    // Key
+    KeySlot(Vec<u8>),
+    // Key
+    KeyOwner(Vec<u8>),
+}
+
+/// The slot of a key. Only the part between the first `{` and the next `}` is hashed, if it is
+/// not empty, so that related keys can be kept in the same slot.
+pub(crate) fn key_slot(key: &[u8]) -> u16 {
+    let hashed = key
+        .iter()
+        .position(|b| *b == b'{')
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            tag.iter()
+                .position(|b| *b == b'}')
+                .map(|close| &tag[..close])
+        })
+        .filter(|tag| !tag.is_empty())
+        .unwrap_or(key);
+    CRC16.checksum(hashed) % SLOTS
+}
+
+/// Which slot, shard and nodes own the key, as replied to CLUSTER KEYOWNER
+pub(crate) fn owner(key: &[u8], nodes: &[String]) -> RespValue {
+    let field =
+        |name: &'static str| RespValue::BulkString(Some(Bytes::from_static(name.as_bytes())));
+    RespValue::Map(vec![
+        (field("slot"), RespValue::Integer(key_slot(key) as i64)),
+        (field("shard"), RespValue::Integer(SHARD)),
+        (
+            field("nodes"),
+            RespValue::Array(
+                nodes
+                    .iter()
+                    .map(|node| RespValue::BulkString(Some(Bytes::from(node.clone()))))
+                    .collect(),
+            ),
+        ),
+        (field("migrating"), RespValue::Boolean(false)),
+    ])
+}
@@ -1,67 +1,685 @@
-use crate::resp_codec::{convert_bulk_string_to_string, RespValue};
-use crate::sync_layer::{RequestId, Syncable};
-use bitcask_engine_rs::bitcask::{BitCask, KVStorage, PutOption};
+use crate::acl::{Acl, AclOp, Category};
+use crate::admin::{AdminOp, Peer};
+use crate::analytics::{Analytics, SnapshotOp};
+use crate::audit::Audit;
+use crate::backup::Backups;
+use crate::bitmap::{self, Unit};
+use crate::bloom;
+use crate::cache::{NegativeCache, ReadCache};
+use crate::chunk;
+use crate::clients::{ClientOp, Clients, KillFilter};
+use crate::cluster::ClusterOp;
+use crate::compaction::Compaction;
+use crate::compat;
+use crate::compression::Compression;
+use crate::config::{Config, ConfigOp, Consistency};
+use crate::database;
+use crate::dump;
+use crate::ephemeral::{self, EphemeralOp};
+use crate::expiry::ExpiryForecast;
+use crate::export::Exports;
+use crate::flush::{self, FlushAllOp, Flusher};
+use crate::failpoint;
+use crate::feature::{Feature, FeatureOp, Features};
+use crate::hooks::Hooks;
+use crate::keyspace;
+use crate::list;
+use crate::list::End;
+use crate::lock::{LockOp, Locks};
+use crate::maintenance::Maintenance;
+use crate::memory::{self, Memory};
+use crate::monitor::Monitor;
+use crate::overload::Overload;
+use crate::plugin;
+use crate::prefix_stats::PrefixStats;
+use crate::procedure::{ProcOp, Procedures};
+use crate::pubsub::Broker;
+use crate::raft_log::{CommittedLog, RaftOp};
+use crate::script::{self, Call, ScriptOp};
+use crate::shred::SecureDelete;
+use crate::shutdown::{SaveMode, Shutdown};
+use crate::resp_codec::{convert_bulk_string_to_string, format_double, RespValue};
+use crate::sketch;
+use crate::slo::CommandFamily;
+use crate::slowlog::{SlowLog, SlowLogOp};
+use crate::standby::Standby;
+use crate::stats::Stats;
+use crate::sync_layer::{self, RaftStats, RequestId, Syncable};
+use crate::value::{self, ValueType};
+use crate::zset;
+use crate::zset::ScoreBound;
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::info;
 use uuid::Uuid;
 use bitcask_engine_rs::error::BitCaskError;
 
+/// Node-local state shared by the apply path and the client connections
+#[derive(Clone)]
+pub(crate) struct NodeContext {
+    pub(crate) acl: Arc<Acl>,
+    pub(crate) clients: Arc<Clients>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) slowlog: Arc<SlowLog>,
+    // command metrics per key prefix, for INFO prefixstats
+    pub(crate) prefix_stats: Arc<PrefixStats>,
+    pub(crate) monitor: Arc<Monitor>,
+    pub(crate) config: Arc<Config>,
+    // where the storage keeps its data files, for the consumers of keyspace snapshots
+    pub(crate) data_dir: PathBuf,
+    // the nodes of the Raft group, this one first
+    pub(crate) nodes: Vec<String>,
+    // the other nodes and where they serve clients, for ADMIN BROADCAST
+    pub(crate) peers: Vec<Peer>,
+    // the members a read replica tails, none on a member
+    pub(crate) replica_of: Vec<String>,
+    // how many databases SELECT picks from
+    pub(crate) databases: u32,
+    // how long a read view of SNAPSHOT OPEN lasts at most
+    pub(crate) read_view_max: Duration,
+    pub(crate) raft_stats: Arc<RaftStats>,
+    // the latest committed entries, for RAFT LOG
+    pub(crate) raft_log: Arc<CommittedLog>,
+    pub(crate) overload: Arc<Overload>,
+    pub(crate) hooks: Arc<Hooks>,
+    pub(crate) shutdown: Arc<Shutdown>,
+    pub(crate) procedures: Arc<Procedures>,
+    pub(crate) features: Arc<Features>,
+    // the locks taken with NOTIFY, to publish their expiry
+    pub(crate) locks: Arc<Locks>,
+    pub(crate) secure_delete: Arc<SecureDelete>,
+    pub(crate) exports: Arc<Exports>,
+    pub(crate) flusher: Arc<Flusher>,
+    // None without an analytics port
+    pub(crate) analytics: Option<Arc<Analytics>>,
+    // taken on the schedule, if any, and by BGSAVE
+    pub(crate) backups: Arc<Backups>,
+    // run on the thresholds, if scheduled, and by COMPACT
+    pub(crate) compaction: Arc<Compaction>,
+    // counted by a pass per interval, if any
+    pub(crate) expiry: Arc<ExpiryForecast>,
+    // when the node started, for the uptime in INFO
+    pub(crate) started: Instant,
+    pub(crate) broker: Arc<Broker>,
+    pub(crate) read_cache: Option<Arc<ReadCache>>,
+    pub(crate) negative_cache: Option<Arc<NegativeCache>>,
+    // None without --maxmemory
+    pub(crate) memory: Option<Arc<Memory>>,
+    // refusing the clients until PROMOTE with --standby
+    pub(crate) standby: Arc<Standby>,
+    // redirecting the clients to the peers while on
+    pub(crate) maintenance: Arc<Maintenance>,
+    // None without --audit-dir
+    pub(crate) audit: Option<Arc<Audit>>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub(crate) struct PutOptionSerde {
     pub(crate) nx: bool,
     pub(crate) xx: bool,
+    pub(crate) expiry: Option<Expiry>,
+    // unix time in milliseconds on the node the command was sent to, so that every node agrees
+    // on which values had expired when it is applied
+    pub(crate) now: u64,
+    // the codec the value was compressed with on that node, None if it was not
+    pub(crate) compression: Option<Compression>,
 }
 
-impl PutOptionSerde {
-    pub fn nx() -> Option<Self> {
-        Some(Self {
-            nx: true,
-            xx: false,
-        })
-    }
-
-    pub fn xx() -> Option<Self> {
-        Some(Self {
-            nx: false,
-            xx: true,
-        })
-    }
-}
-
-impl From<PutOptionSerde> for PutOption {
-    fn from(put_option: PutOptionSerde) -> Self {
-        Self {
-            nx: put_option.nx,
-            xx: put_option.xx,
-        }
-    }
+/// When the value set by SET, or read by GETEX, expires
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub(crate) enum Expiry {
+    // EX, PX, EXAT or PXAT, as a unix time in milliseconds
+    At(u64),
+    // KEEPTTL: when the value replaced expires, if it does
+    Keep,
+    // PERSIST, GETEX only: never
+    Persist,
 }
 
 pub(crate) enum Cmd {
     /// Get the value of key. If the key does not exist the special value nil is returned.
     /// An error is returned if the value stored at key is not a string, because GET only handles string values.
     Get(GetCmd),
+    /// Get the values of several keys as of a single entry of the log, with its index.
+    MGetSnapshot(MGetSnapshotCmd),
     /// Set key to hold the `string` value. If key already holds a value, it is overwritten, regardless of its type.
     Set(SetCmd),
+    /// Get the substring of the string value stored at key, between the `start` and `end`
+    /// offsets (both inclusive). Negative offsets count from the end of the string.
+    GetRange(GetRangeCmd),
+    /// Get the value of key and delete the key, if its value is a string.
+    GetDel(GetDelCmd),
+    /// Set key to new if it holds the string expected, as the write is applied on every node.
+    Cas(CasCmd),
+    /// Get the value of key and optionally set its expiry, or remove it with PERSIST.
+    GetEx(GetExCmd),
+    /// Set or clear the bit at offset in the string value stored at key, growing it with zeros.
+    SetBit(SetBitCmd),
+    /// Return the bit at offset in the string value stored at key, served from the local replica.
+    GetBit(GetBitCmd),
+    /// Count the set bits of the string value stored at key, served from the local replica.
+    BitCount(BitCountCmd),
+    /// Return the offset of the first bit set or clear in the string value stored at key, served
+    /// from the local replica.
+    BitPos(BitPosCmd),
     Del(DelCmd),
+    /// Copy the value of source, whatever its type, to destination, unless it exists and REPLACE
+    /// is not given.
+    Copy(CopyCmd),
+    /// Move the value of source to destination, overwriting it.
+    Rename(RenameCmd),
+    /// Move the value of source to destination, only if destination does not exist.
+    RenameNx(RenameCmd),
+    /// Return the value of key, whatever its type, serialized for RESTORE, served from the local
+    /// replica.
+    Dump(DumpCmd),
+    /// Set key to a value serialized by DUMP, with a TTL in milliseconds, 0 for none, unless it
+    /// exists and REPLACE is not given.
+    Restore(RestoreCmd),
+    /// Insert all the specified values at the head of the list stored at key.
+    LPush(PushCmd),
+    /// Insert all the specified values at the tail of the list stored at key.
+    RPush(PushCmd),
+    /// Remove and return the first elements of the list stored at key.
+    LPop(PopCmd),
+    /// Remove and return the last elements of the list stored at key.
+    RPop(PopCmd),
+    /// Return the specified elements of the list stored at key, served from the local replica.
+    LRange(LRangeCmd),
+    /// Return the length of the list stored at key, served from the local replica.
+    LLen(LLenCmd),
+    /// Add all the specified members with the specified scores to the sorted set stored at key.
+    ZAdd(ZAddCmd),
+    /// Remove the specified members from the sorted set stored at key.
+    ZRem(ZRemCmd),
+    /// Return the score of member in the sorted set at key, served from the local replica.
+    ZScore(ZScoreCmd),
+    /// Return the specified range of members by rank, served from the local replica.
+    ZRange(ZRangeCmd),
+    /// Return all the members with a score between min and max, served from the local replica.
+    ZRangeByScore(ZRangeByScoreCmd),
+    /// Add an item to the bloom filter at key, creating the filter if it does not exist.
+    BfAdd(BfAddCmd),
+    /// Add one or more items to the bloom filter at key, creating the filter if it does not exist.
+    BfMAdd(BfMAddCmd),
+    /// Check whether an item may exist in the bloom filter at key, served from the local replica.
+    BfExists(BfExistsCmd),
+    /// Create a count-min sketch of the given width and depth at key.
+    CmsInitByDim(CmsInitByDimCmd),
+    /// Increase the count of one or more items in the count-min sketch at key.
+    CmsIncrBy(CmsIncrByCmd),
+    /// Return the estimated count of one or more items, served from the local replica.
+    CmsQuery(CmsQueryCmd),
+    /// Create a Top-K list keeping the k most frequent items at key.
+    TopKReserve(TopKReserveCmd),
+    /// Count one or more items in the Top-K list at key.
+    TopKAdd(TopKAddCmd),
+    /// Return the items of the Top-K list at key, served from the local replica.
+    TopKList(TopKListCmd),
+    /// Return all the keys matching the glob-style pattern, served from the local replica.
+    Keys(KeysCmd),
+    /// Return the number of keys, served from the local replica.
+    DbSize(DbSizeCmd),
+    /// Delete every key of every database, or of the selected one with FLUSHDB, on every node of
+    /// the cluster, in the background with ASYNC.
+    Flush(FlushCmd),
+    /// Select the database the later commands of the connection run in.
+    Select(SelectCmd),
+    /// Swap the keys of two databases, on every node of the cluster.
+    SwapDb(SwapDbCmd),
+    /// Take a backup of the data files in the background, into --backup-dir.
+    BgSave(BgSaveCmd),
+    /// Return the unix time of the latest complete backup.
+    LastSave(LastSaveCmd),
+    /// Compact the data files in the background, reclaiming overwritten and deleted values.
+    Compact(CompactCmd),
+    /// Turn a warm standby into serving the clients.
+    Promote(PromoteCmd),
+    /// Post a message to the given channel, on every node of the cluster.
+    Publish(PublishCmd),
+    /// Subscribe the client to the specified channels.
+    Subscribe(SubscribeCmd),
+    /// Unsubscribe the client from the given channels, or from all of them if none is given.
+    Unsubscribe(UnsubscribeCmd),
+    /// Subscribe the client to the given glob-style patterns.
+    PSubscribe(SubscribeCmd),
+    /// Unsubscribe the client from the given patterns, or from all of them if none is given.
+    PUnsubscribe(UnsubscribeCmd),
+    /// Post a message to the given shard channel, on the nodes of the shard owning its slot.
+    SPublish(PublishCmd),
+    /// Subscribe the client to the specified shard channels, which must share a slot.
+    SSubscribe(SubscribeCmd),
+    /// Unsubscribe the client from the given shard channels, or from all of them if none is given.
+    SUnsubscribe(UnsubscribeCmd),
+    /// Switch the connection to the given protocol version and return the server properties.
+    Hello(HelloCmd),
+    /// Return information and statistics about the server.
+    Info(InfoCmd),
+    /// Report the slot of a key and which shard and nodes own it.
+    Cluster(ClusterCmd),
+    /// Inspect, name and kill the client connections of the node.
+    Client(ClientCmd),
+    Config(ConfigCmd),
+    /// Debugging and testing helpers.
+    Debug(DebugCmd),
+    /// Authenticate the connection as the given user, or as the default user.
+    Auth(AuthCmd),
+    /// Inspect and change the users of the node the client is connected to.
+    Acl(AclCmd),
+    /// Load, call and delete the WebAssembly stored procedures, on every node of the cluster.
+    Proc(ProcCmd),
+    /// Run a Lua script, given by its source or its SHA1 digest, on every node of the cluster.
+    Eval(EvalCmd),
+    /// Load, check and flush the Lua scripts of the cluster.
+    Script(ScriptCmd),
+    /// Take the lock of a key for a time unless it is held, replying a fencing token.
+    Lock(LockCmd),
+    /// Release the lock of a key taken with the given fencing token.
+    Unlock(UnlockCmd),
+    /// Run one write or another, depending on whether a key exists, at once on every node.
+    If(IfCmd),
+    /// Stop the node the client is connected to, saving its statistics unless NOSAVE is given.
+    Shutdown(ShutdownCmd),
+    Snapshot(SnapshotCmd),
+    SlowLog(SlowLogCmd),
+    /// Read the latest committed entries of the Raft log.
+    Raft(RaftCmd),
+    /// Collect the keys matching a pattern with their values and history, signed.
+    Export(ExportCmd),
+    /// Stream every command the node runs to this connection.
+    Monitor,
+    /// Run an admin command on every node of the cluster.
+    Admin(AdminCmd),
+    /// Turn experimental subsystems on and off, on every node of the cluster.
+    Feature(FeatureCmd),
+    /// Wait until the previous writes of the connection are held by enough other nodes.
+    Wait(WaitCmd),
     Ping,
+    /// A command added by a plugin.
+    Plugin(PluginCmd),
+    /// A known command whose arguments do not parse, with why.
+    Rejected(anyhow::Error),
     Unknown,
 }
 
+pub(crate) struct PluginCmd {
+    pub(crate) name: &'static str,
+    pub(crate) args: Vec<RespValue>,
+}
+
 pub(crate) struct GetCmd {
     pub(crate) key: RespValue,
+    // CONSISTENCY: overrides the read-consistency setting for this read
+    pub(crate) consistency: Option<Consistency>,
+}
+
+pub(crate) struct MGetSnapshotCmd {
+    pub(crate) keys: Vec<RespValue>,
 }
 
 pub(crate) struct SetCmd {
     pub(crate) key: RespValue,
     pub(crate) value: RespValue,
-    // could be NX or XX
+    // NX, XX and the expiry, None without any option
     pub(crate) option: Option<PutOptionSerde>,
+    // GET: reply with the old value instead of OK
+    pub(crate) get: bool,
+    // ID: request id chosen by the client, so a retry carries the same id as the first attempt
+    pub(crate) id: Option<RequestId>,
+    // EX or PX in milliseconds, which the ttl jitter lengthens, unlike EXAT and PXAT
+    pub(crate) ttl: Option<u64>,
+    // IFCHANGED: not replicated if the node already holds the value
+    pub(crate) if_changed: bool,
+    // EPHEMERAL: owned by the connection, deleted once it closes
+    pub(crate) ephemeral: bool,
+    // the connection owning the key, as its node leases it before it is proposed
+    pub(crate) owner: Option<RequestId>,
+}
+
+pub(crate) struct GetRangeCmd {
+    pub(crate) key: RespValue,
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+}
+
+pub(crate) struct GetDelCmd {
+    pub(crate) key: RespValue,
+    // time of this node, unix milliseconds, against which the value may have expired
+    pub(crate) now: u64,
+}
+
+pub(crate) struct CasCmd {
+    pub(crate) key: RespValue,
+    pub(crate) expected: RespValue,
+    pub(crate) new: RespValue,
+    // time of this node, unix milliseconds, against which the value may have expired
+    pub(crate) now: u64,
+}
+
+pub(crate) struct GetExCmd {
+    pub(crate) key: RespValue,
+    // None leaves the expiry of the value as it is
+    pub(crate) expiry: Option<Expiry>,
+    // EX or PX in milliseconds, which the ttl jitter lengthens, unlike EXAT and PXAT
+    pub(crate) ttl: Option<u64>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct SetBitCmd {
+    pub(crate) key: RespValue,
+    pub(crate) offset: u64,
+    pub(crate) bit: bool,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct GetBitCmd {
+    pub(crate) key: RespValue,
+    pub(crate) offset: u64,
+}
+
+pub(crate) struct BitCountCmd {
+    pub(crate) key: RespValue,
+    // the whole string without one
+    pub(crate) range: Option<bitmap::Range>,
+}
+
+pub(crate) struct BitPosCmd {
+    pub(crate) key: RespValue,
+    pub(crate) bit: bool,
+    pub(crate) range: Option<bitmap::Range>,
 }
 
 pub(crate) struct DelCmd {
+    pub(crate) keys: Vec<RespValue>,
+    // time of this node, unix milliseconds, against which the values may have expired
+    pub(crate) now: u64,
+}
+
+pub(crate) struct CopyCmd {
+    pub(crate) source: RespValue,
+    pub(crate) destination: RespValue,
+    // REPLACE: overwrite the destination if it exists
+    pub(crate) replace: bool,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct RenameCmd {
+    pub(crate) source: RespValue,
+    pub(crate) destination: RespValue,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct DumpCmd {
+    pub(crate) key: RespValue,
+}
+
+pub(crate) struct RestoreCmd {
+    pub(crate) key: RespValue,
+    pub(crate) serialized: RespValue,
+    // unix time in milliseconds, from the TTL or ABSTTL
+    pub(crate) deadline: Option<u64>,
+    // REPLACE: overwrite the key if it exists
+    pub(crate) replace: bool,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct PushCmd {
+    pub(crate) key: RespValue,
+    pub(crate) values: Vec<RespValue>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct PopCmd {
+    pub(crate) key: RespValue,
+    // None means the reply is a single element instead of an array
+    pub(crate) count: Option<usize>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct LRangeCmd {
+    pub(crate) key: RespValue,
+    pub(crate) start: i64,
+    pub(crate) stop: i64,
+}
+
+pub(crate) struct LLenCmd {
+    pub(crate) key: RespValue,
+}
+
+pub(crate) struct ZAddCmd {
+    pub(crate) key: RespValue,
+    pub(crate) members: Vec<(f64, RespValue)>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct ZRemCmd {
+    pub(crate) key: RespValue,
+    pub(crate) members: Vec<RespValue>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct ZScoreCmd {
+    pub(crate) key: RespValue,
+    pub(crate) member: RespValue,
+}
+
+pub(crate) struct ZRangeCmd {
+    pub(crate) key: RespValue,
+    pub(crate) start: i64,
+    pub(crate) stop: i64,
+    pub(crate) with_scores: bool,
+}
+
+pub(crate) struct ZRangeByScoreCmd {
+    pub(crate) key: RespValue,
+    pub(crate) min: ScoreBound,
+    pub(crate) max: ScoreBound,
+    pub(crate) with_scores: bool,
+}
+
+pub(crate) struct BfAddCmd {
+    pub(crate) key: RespValue,
+    pub(crate) item: RespValue,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct BfMAddCmd {
+    pub(crate) key: RespValue,
+    pub(crate) items: Vec<RespValue>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct BfExistsCmd {
+    pub(crate) key: RespValue,
+    pub(crate) item: RespValue,
+}
+
+pub(crate) struct CmsInitByDimCmd {
+    pub(crate) key: RespValue,
+    pub(crate) width: u32,
+    pub(crate) depth: u32,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct CmsIncrByCmd {
+    pub(crate) key: RespValue,
+    pub(crate) increments: Vec<(RespValue, u64)>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct CmsQueryCmd {
+    pub(crate) key: RespValue,
+    pub(crate) items: Vec<RespValue>,
+}
+
+pub(crate) struct TopKReserveCmd {
     pub(crate) key: RespValue,
+    pub(crate) k: u32,
+    pub(crate) width: u32,
+    pub(crate) depth: u32,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct TopKAddCmd {
+    pub(crate) key: RespValue,
+    pub(crate) items: Vec<RespValue>,
+    pub(crate) now: u64,
+}
+
+pub(crate) struct TopKListCmd {
+    pub(crate) key: RespValue,
+    pub(crate) with_count: bool,
+}
+
+pub(crate) struct KeysCmd {
+    pub(crate) pattern: RespValue,
+}
+
+pub(crate) struct DbSizeCmd;
+
+pub(crate) struct FlushCmd {
+    // ASYNC
+    pub(crate) lazy: bool,
+    // the step of a FLUSHALL, None for FLUSHDB
+    pub(crate) step: Option<FlushAllOp>,
+}
+
+pub(crate) struct SelectCmd {
+    pub(crate) db: u32,
+}
+
+pub(crate) struct SwapDbCmd {
+    pub(crate) first: u32,
+    pub(crate) second: u32,
+}
+
+pub(crate) struct BgSaveCmd;
+
+pub(crate) struct LastSaveCmd;
+
+pub(crate) struct CompactCmd;
+
+pub(crate) struct PromoteCmd;
+
+pub(crate) struct PublishCmd {
+    pub(crate) channel: RespValue,
+    pub(crate) message: RespValue,
+}
+
+pub(crate) struct SubscribeCmd {
+    pub(crate) channels: Vec<RespValue>,
+}
+
+pub(crate) struct UnsubscribeCmd {
+    // empty means all of them
+    pub(crate) channels: Vec<RespValue>,
+}
+
+pub(crate) struct HelloCmd {
+    // None keeps the current protocol
+    pub(crate) protover: Option<i64>,
+    // the user and password to authenticate as, as AUTH does
+    pub(crate) auth: Option<(String, String)>,
+    pub(crate) setname: Option<String>,
+}
+
+pub(crate) struct ProcCmd {
+    pub(crate) op: ProcOp,
+}
+
+pub(crate) struct EvalCmd {
+    pub(crate) op: ScriptOp,
+}
+
+pub(crate) struct ScriptCmd {
+    pub(crate) op: ScriptOp,
+}
+
+pub(crate) struct LockCmd {
+    pub(crate) op: LockOp,
+}
+
+pub(crate) struct UnlockCmd {
+    pub(crate) op: LockOp,
+}
+
+pub(crate) struct IfCmd {
+    pub(crate) key: Vec<u8>,
+    // unix time in milliseconds, expired keys do not exist
+    pub(crate) now: u64,
+    pub(crate) then: Box<Cmd>,
+    pub(crate) otherwise: Option<Box<Cmd>>,
+}
+
+pub(crate) struct ShutdownCmd {
+    pub(crate) mode: SaveMode,
+}
+
+pub(crate) struct SnapshotCmd {
+    pub(crate) op: SnapshotOp,
+}
+
+pub(crate) struct SlowLogCmd {
+    pub(crate) op: SlowLogOp,
+}
+
+pub(crate) struct RaftCmd {
+    pub(crate) op: RaftOp,
+}
+
+pub(crate) struct ExportCmd {
+    pub(crate) pattern: RespValue,
+}
+
+pub(crate) struct FeatureCmd {
+    pub(crate) op: FeatureOp,
+}
+
+pub(crate) struct InfoCmd {
+    pub(crate) section: Option<String>,
+}
+
+pub(crate) struct ClusterCmd {
+    pub(crate) op: ClusterOp,
+}
+
+pub(crate) struct ClientCmd {
+    pub(crate) op: ClientOp,
+}
+
+pub(crate) struct ConfigCmd {
+    pub(crate) op: ConfigOp,
+}
+
+pub(crate) struct DebugCmd {
+    pub(crate) op: DebugOp,
+}
+
+pub(crate) struct AuthCmd {
+    // None is the default user
+    pub(crate) username: Option<RespValue>,
+    pub(crate) password: RespValue,
+}
+
+pub(crate) struct AclCmd {
+    pub(crate) op: AclOp,
+}
+
+pub(crate) struct MonitorCmd;
+
+pub(crate) struct AdminCmd {
+    pub(crate) op: AdminOp,
+}
+
+pub(crate) struct WaitCmd {
+    pub(crate) replicas: usize,
+    // milliseconds, 0 waits for as long as it takes
+    pub(crate) timeout: u64,
 }
 
 pub(crate) struct PingCmd;
@@ -70,14 +688,215 @@ impl Debug for Cmd {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Cmd::Get(cmd) => write!(f, "GET {:?}", cmd.key),
+            Cmd::MGetSnapshot(cmd) => write!(f, "MGETSNAPSHOT {:?}", cmd.keys),
             Cmd::Set(cmd) => write!(f, "SET {:?} {:?}", cmd.key, cmd.value),
-            Cmd::Del(cmd) => write!(f, "DEL {:?}", cmd.key),
+            Cmd::GetRange(cmd) => write!(f, "GETRANGE {:?} {} {}", cmd.key, cmd.start, cmd.end),
+            Cmd::GetDel(cmd) => write!(f, "GETDEL {:?}", cmd.key),
+            Cmd::Cas(cmd) => write!(f, "CAS {:?} {:?} {:?}", cmd.key, cmd.expected, cmd.new),
+            Cmd::GetEx(cmd) => write!(f, "GETEX {:?} {:?}", cmd.key, cmd.expiry),
+            Cmd::SetBit(cmd) => write!(f, "SETBIT {:?} {} {}", cmd.key, cmd.offset, cmd.bit as u8),
+            Cmd::GetBit(cmd) => write!(f, "GETBIT {:?} {}", cmd.key, cmd.offset),
+            Cmd::BitCount(cmd) => write!(f, "BITCOUNT {:?} {:?}", cmd.key, cmd.range),
+            Cmd::BitPos(cmd) => {
+                write!(f, "BITPOS {:?} {} {:?}", cmd.key, cmd.bit as u8, cmd.range)
+            }
+            Cmd::Del(cmd) => write!(f, "DEL {:?}", cmd.keys),
+            Cmd::Copy(cmd) => write!(f, "COPY {:?} {:?}", cmd.source, cmd.destination),
+            Cmd::Rename(cmd) => write!(f, "RENAME {:?} {:?}", cmd.source, cmd.destination),
+            Cmd::RenameNx(cmd) => write!(f, "RENAMENX {:?} {:?}", cmd.source, cmd.destination),
+            Cmd::Dump(cmd) => write!(f, "DUMP {:?}", cmd.key),
+            Cmd::Restore(cmd) => write!(f, "RESTORE {:?} {:?}", cmd.key, cmd.deadline),
+            Cmd::LPush(cmd) => write!(f, "LPUSH {:?} {:?}", cmd.key, cmd.values),
+            Cmd::RPush(cmd) => write!(f, "RPUSH {:?} {:?}", cmd.key, cmd.values),
+            Cmd::LPop(cmd) => write!(f, "LPOP {:?} {:?}", cmd.key, cmd.count),
+            Cmd::RPop(cmd) => write!(f, "RPOP {:?} {:?}", cmd.key, cmd.count),
+            Cmd::LRange(cmd) => write!(f, "LRANGE {:?} {} {}", cmd.key, cmd.start, cmd.stop),
+            Cmd::LLen(cmd) => write!(f, "LLEN {:?}", cmd.key),
+            Cmd::ZAdd(cmd) => write!(f, "ZADD {:?} {:?}", cmd.key, cmd.members),
+            Cmd::ZRem(cmd) => write!(f, "ZREM {:?} {:?}", cmd.key, cmd.members),
+            Cmd::ZScore(cmd) => write!(f, "ZSCORE {:?} {:?}", cmd.key, cmd.member),
+            Cmd::ZRange(cmd) => write!(f, "ZRANGE {:?} {} {}", cmd.key, cmd.start, cmd.stop),
+            Cmd::ZRangeByScore(cmd) => {
+                write!(f, "ZRANGEBYSCORE {:?} {:?} {:?}", cmd.key, cmd.min, cmd.max)
+            }
+            Cmd::BfAdd(cmd) => write!(f, "BF.ADD {:?} {:?}", cmd.key, cmd.item),
+            Cmd::BfMAdd(cmd) => write!(f, "BF.MADD {:?} {:?}", cmd.key, cmd.items),
+            Cmd::BfExists(cmd) => write!(f, "BF.EXISTS {:?} {:?}", cmd.key, cmd.item),
+            Cmd::CmsInitByDim(cmd) => {
+                write!(f, "CMS.INITBYDIM {:?} {} {}", cmd.key, cmd.width, cmd.depth)
+            }
+            Cmd::CmsIncrBy(cmd) => write!(f, "CMS.INCRBY {:?} {:?}", cmd.key, cmd.increments),
+            Cmd::CmsQuery(cmd) => write!(f, "CMS.QUERY {:?} {:?}", cmd.key, cmd.items),
+            Cmd::TopKReserve(cmd) => write!(
+                f,
+                "TOPK.RESERVE {:?} {} {} {}",
+                cmd.key, cmd.k, cmd.width, cmd.depth
+            ),
+            Cmd::TopKAdd(cmd) => write!(f, "TOPK.ADD {:?} {:?}", cmd.key, cmd.items),
+            Cmd::TopKList(cmd) => write!(f, "TOPK.LIST {:?}", cmd.key),
+            Cmd::Keys(cmd) => write!(f, "KEYS {:?}", cmd.pattern),
+            Cmd::DbSize(_) => write!(f, "DBSIZE"),
+            Cmd::Flush(cmd) => match &cmd.step {
+                Some(step) => write!(f, "FLUSHALL {:?}", step),
+                None => write!(f, "FLUSHDB{}", if cmd.lazy { " ASYNC" } else { "" }),
+            },
+            Cmd::Select(cmd) => write!(f, "SELECT {}", cmd.db),
+            Cmd::SwapDb(cmd) => write!(f, "SWAPDB {} {}", cmd.first, cmd.second),
+            Cmd::BgSave(_) => write!(f, "BGSAVE"),
+            Cmd::LastSave(_) => write!(f, "LASTSAVE"),
+            Cmd::Compact(_) => write!(f, "COMPACT"),
+            Cmd::Promote(_) => write!(f, "PROMOTE"),
+            Cmd::Publish(cmd) => write!(f, "PUBLISH {:?} {:?}", cmd.channel, cmd.message),
+            Cmd::Subscribe(cmd) => write!(f, "SUBSCRIBE {:?}", cmd.channels),
+            Cmd::Unsubscribe(cmd) => write!(f, "UNSUBSCRIBE {:?}", cmd.channels),
+            Cmd::PSubscribe(cmd) => write!(f, "PSUBSCRIBE {:?}", cmd.channels),
+            Cmd::PUnsubscribe(cmd) => write!(f, "PUNSUBSCRIBE {:?}", cmd.channels),
+            Cmd::SPublish(cmd) => write!(f, "SPUBLISH {:?} {:?}", cmd.channel, cmd.message),
+            Cmd::SSubscribe(cmd) => write!(f, "SSUBSCRIBE {:?}", cmd.channels),
+            Cmd::SUnsubscribe(cmd) => write!(f, "SUNSUBSCRIBE {:?}", cmd.channels),
+            Cmd::Hello(cmd) => write!(
+                f,
+                "HELLO {:?} {:?} {:?}",
+                cmd.protover,
+                cmd.auth.as_ref().map(|(user, _)| user),
+                cmd.setname
+            ),
+            Cmd::Info(cmd) => write!(f, "INFO {:?}", cmd.section),
+            Cmd::Cluster(cmd) => write!(f, "CLUSTER {:?}", cmd.op),
+            Cmd::Client(cmd) => write!(f, "CLIENT {:?}", cmd.op),
+            Cmd::Config(cmd) => write!(f, "CONFIG {:?}", cmd.op),
+            Cmd::Debug(cmd) => write!(f, "DEBUG {:?}", cmd.op),
+            Cmd::Auth(cmd) => write!(f, "AUTH {:?} <redacted>", cmd.username),
+            Cmd::Acl(cmd) => write!(f, "ACL {:?}", cmd.op),
+            Cmd::Proc(cmd) => write!(f, "PROC {:?}", cmd.op),
+            Cmd::Eval(cmd) => write!(f, "EVAL {:?}", cmd.op),
+            Cmd::Script(cmd) => write!(f, "SCRIPT {:?}", cmd.op),
+            Cmd::Lock(cmd) => write!(f, "LOCK {:?}", cmd.op),
+            Cmd::Unlock(cmd) => write!(f, "UNLOCK {:?}", cmd.op),
+            Cmd::If(cmd) => write!(
+                f,
+                "IF EXISTS {:?} THEN {:?} ELSE {:?}",
+                String::from_utf8_lossy(&cmd.key),
+                cmd.then,
+                cmd.otherwise
+            ),
+            Cmd::Shutdown(cmd) => write!(f, "SHUTDOWN {:?}", cmd.mode),
+            Cmd::Snapshot(cmd) => write!(f, "SNAPSHOT {:?}", cmd.op),
+            Cmd::SlowLog(cmd) => write!(f, "SLOWLOG {:?}", cmd.op),
+            Cmd::Raft(cmd) => write!(f, "RAFT {:?}", cmd.op),
+            Cmd::Export(cmd) => write!(f, "EXPORT {:?}", cmd.pattern),
+            Cmd::Monitor => write!(f, "MONITOR"),
+            Cmd::Admin(cmd) => write!(f, "ADMIN {:?}", cmd.op),
+            Cmd::Feature(cmd) => write!(f, "FEATURE {:?}", cmd.op),
+            Cmd::Wait(cmd) => write!(f, "WAIT {} {}", cmd.replicas, cmd.timeout),
             Cmd::Ping => write!(f, "PING"),
+            Cmd::Plugin(cmd) => write!(f, "{} {:?}", cmd.name.to_uppercase(), cmd.args),
+            Cmd::Rejected(e) => write!(f, "Rejected {}", e),
             Cmd::Unknown => write!(f, "Unknown"),
         }
     }
 }
 
+impl Cmd {
+    /// Lease an EPHEMERAL SET to the connection for `lease` milliseconds from now, as its expiry
+    pub(crate) fn lease(&mut self, connection: RequestId, lease: u64) {
+        if let Cmd::Set(cmd) = self {
+            if let (true, Some(option)) = (cmd.ephemeral, cmd.option.as_mut()) {
+                option.expiry = Some(Expiry::At(option.now.saturating_add(lease)));
+                cmd.owner = Some(connection);
+            }
+        }
+    }
+
+    /// Lengthen the EX or PX time of SET and GETEX by up to `percent` of it, at random, so that
+    /// values set with the same TTL do not all expire at once. The node the command is sent to
+    /// draws the jitter, so every node stores the same deadline.
+    pub(crate) fn jitter_ttl(&mut self, percent: u64) {
+        let (ttl, expiry) = match self {
+            Cmd::Set(cmd) => (cmd.ttl, cmd.option.as_mut().and_then(|o| o.expiry.as_mut())),
+            Cmd::GetEx(cmd) => (cmd.ttl, cmd.expiry.as_mut()),
+            _ => return,
+        };
+        if let (Some(ttl), Some(Expiry::At(deadline))) = (ttl, expiry) {
+            let bound = ttl.saturating_mul(percent) / 100;
+            if bound > 0 {
+                // the random bits of a v4 uuid, as the request ids draw them
+                let random = Uuid::new_v4().as_u64_pair().1;
+                *deadline = deadline.saturating_add(random % (bound + 1));
+            }
+        }
+    }
+
+    /// Whether the command is a SET IFCHANGED
+    pub(crate) fn if_changed(&self) -> bool {
+        matches!(self, Cmd::Set(cmd) if cmd.if_changed)
+    }
+
+    /// The request id the client chose with ID, to retry the command under it
+    pub(crate) fn client_id(&self) -> Option<RequestId> {
+        match self {
+            Cmd::Set(cmd) => cmd.id,
+            _ => None,
+        }
+    }
+}
+
+/// A parser rejecting an argument with the error Redis replies for it, such as a number out of
+/// range; the other rejections reply the arity or syntax error of the command
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct Rejected(pub(crate) String);
+
+/// Replied to an argument that should be an integer, or is out of the range of the command
+pub(crate) const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+
+/// The error replied to a command that does not parse, worded as Redis words it
+pub(crate) fn rejection(frame: &RespValue, error: &anyhow::Error) -> String {
+    if let Some(Rejected(reply)) = error.downcast_ref::<Rejected>() {
+        return reply.clone();
+    }
+    let args: Vec<String> = match frame {
+        RespValue::Array(args) => args
+            .iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(Some(bytes)) => String::from_utf8_lossy(bytes).into_owned(),
+                _ => String::new(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let Some((name, args)) = args.split_first() else {
+        return "ERR Protocol error: expected a command".to_string();
+    };
+    let Some(arity) = crate::schema::arity(&name.to_lowercase()) else {
+        // the arguments are quoted up to 128 bytes, as Redis quotes them
+        let mut quoted = String::new();
+        for arg in args {
+            if quoted.len() >= 128 {
+                break;
+            }
+            let room = 128 - quoted.len();
+            let end = (0..=arg.len().min(room))
+                .rev()
+                .find(|&end| arg.is_char_boundary(end))
+                .unwrap_or(0);
+            quoted.push_str(&format!("'{}' ", &arg[..end]));
+        }
+        return format!(
+            "ERR unknown command '{}', with args beginning with: {}",
+            name, quoted
+        );
+    };
+    let given = args.len() as i32 + 1;
+    if (arity >= 0 && given != arity) || given < -arity {
+        return format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        );
+    }
+    "ERR syntax error".to_string()
+}
+
 pub(crate) trait ParseCmd {
     fn parse(value: RespValue) -> anyhow::Result<Self>
         where
@@ -88,9 +907,21 @@ impl ParseCmd for GetCmd {
     fn parse(value: RespValue) -> anyhow::Result<Self> {
         match value {
             RespValue::Array(mut arr) => {
-                if arr.len() == 1 {
+                if arr.len() == 1 || arr.len() == 3 {
                     let key = arr.remove(0);
-                    Ok(Self { key })
+                    let consistency = match (arr.pop(), arr.pop()) {
+                        (None, None) => None,
+                        (Some(RespValue::BulkString(level)), Some(RespValue::BulkString(option)))
+                            if option.as_deref() == Some(b"CONSISTENCY") =>
+                        {
+                            let level = convert_bulk_string_to_string(level);
+                            Some(Consistency::parse(&level).ok_or_else(|| {
+                                anyhow::anyhow!("Invalid consistency level {}", level)
+                            })?)
+                        }
+                        _ => return Err(anyhow::anyhow!("Invalid GET command")),
+                    };
+                    Ok(Self { key, consistency })
                 } else {
                     Err(anyhow::anyhow!("Invalid GET command"))
                 }
@@ -100,33 +931,94 @@ impl ParseCmd for GetCmd {
     }
 }
 
+impl ParseCmd for MGetSnapshotCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if !arr.is_empty() => Ok(Self { keys: arr }),
+            _ => Err(anyhow::anyhow!("Invalid MGETSNAPSHOT command")),
+        }
+    }
+}
+
 impl ParseCmd for SetCmd {
     fn parse(value: RespValue) -> anyhow::Result<Self> {
         match value {
             RespValue::Array(mut arr) => {
-                if arr.len() == 2 || arr.len() == 3 {
+                if arr.len() >= 2 {
                     let key = arr.remove(0);
                     let value = arr.remove(0);
-                    let option = if arr.len() == 1 {
-                        let option = arr.remove(0);
-                        match option {
+                    let (mut nx, mut xx) = (false, false);
+                    let mut expiry = None;
+                    let mut get = false;
+                    let mut id = None;
+                    let mut ttl = None;
+                    let mut if_changed = false;
+                    let mut ephemeral = false;
+                    let now = value::now();
+                    let mut args = arr.into_iter();
+                    while let Some(arg) = args.next() {
+                        // options are case-insensitive, as for Redis
+                        let arg = match arg {
                             RespValue::BulkString(bytes) => {
-                                let option = convert_bulk_string_to_string(bytes);
-                                match option.as_str() {
-                                    "NX" => Ok(PutOptionSerde::nx()),
-                                    "XX" => Ok(PutOptionSerde::xx()),
-                                    _ => Err(anyhow::anyhow!("Invalid SET command")),
+                                convert_bulk_string_to_string(bytes).to_ascii_uppercase()
+                            }
+                            _ => return Err(anyhow::anyhow!("Invalid SET command")),
+                        };
+                        // with NX or GET the reply depends on the old value, not only the write
+                        match arg.as_str() {
+                            "NX" if !nx && !xx && !if_changed => nx = true,
+                            "XX" if !nx && !xx => xx = true,
+                            "GET" if !get && !if_changed && !ephemeral => get = true,
+                            "IFCHANGED" if !if_changed && !nx && !get && !ephemeral => {
+                                if_changed = true
+                            }
+                            // the lease is the expiry of an ephemeral key
+                            "EPHEMERAL"
+                                if !ephemeral && !get && !if_changed && expiry.is_none() =>
+                            {
+                                ephemeral = true
+                            }
+                            "KEEPTTL" if expiry.is_none() && !ephemeral => {
+                                expiry = Some(Expiry::Keep)
+                            }
+                            "EX" | "PX" | "EXAT" | "PXAT" if expiry.is_none() && !ephemeral => {
+                                let deadline = expiry_deadline(&arg, args.next(), now, "SET")?;
+                                if matches!(arg.as_str(), "EX" | "PX") {
+                                    ttl = Some(deadline - now);
                                 }
+                                expiry = Some(Expiry::At(deadline));
                             }
-                            _ => Err(anyhow::anyhow!("Invalid SET command")),
+                            "ID" if id.is_none() => {
+                                let uuid = match args.next() {
+                                    Some(RespValue::BulkString(bytes)) => {
+                                        convert_bulk_string_to_string(bytes)
+                                    }
+                                    _ => return Err(anyhow::anyhow!("Invalid SET command")),
+                                };
+                                id = Some(*Uuid::parse_str(&uuid)?.as_bytes());
+                            }
+                            _ => return Err(anyhow::anyhow!("Invalid SET command")),
                         }
-                    } else {
-                        Ok(None)
-                    };
-                    match option {
-                        Ok(option) => Ok(Self { key, value, option }),
-                        Err(e) => Err(e),
                     }
+                    let option = (nx || xx || get || expiry.is_some() || ephemeral)
+                        .then_some(PutOptionSerde {
+                            nx,
+                            xx,
+                            expiry,
+                            now,
+                            compression: None,
+                        });
+                    Ok(Self {
+                        key,
+                        value,
+                        option,
+                        get,
+                        id,
+                        ttl,
+                        if_changed,
+                        ephemeral,
+                        owner: None,
+                    })
                 } else {
                     Err(anyhow::anyhow!("Invalid SET command"))
                 }
@@ -136,143 +1028,3553 @@ impl ParseCmd for SetCmd {
     }
 }
 
-impl ParseCmd for DelCmd {
+/// The deadline an EX, PX, EXAT or PXAT option of the command sets, in unix milliseconds
+fn expiry_deadline(
+    option: &str,
+    time: Option<RespValue>,
+    now: u64,
+    command: &str,
+) -> anyhow::Result<u64> {
+    let time = match time {
+        Some(time) => convert_bulk_string_to_number::<u64>(time)?,
+        None => return Err(anyhow::anyhow!("Invalid {} command", command)),
+    };
+    let invalid = || {
+        Rejected(format!(
+            "ERR invalid expire time in '{}' command",
+            command.to_lowercase()
+        ))
+    };
+    if time == 0 {
+        return Err(invalid().into());
+    }
+    // relative times are from the clock of this node
+    let deadline = match option {
+        "EX" => time.checked_mul(1000).and_then(|ms| ms.checked_add(now)),
+        "PX" => time.checked_add(now),
+        "EXAT" => time.checked_mul(1000),
+        _ => Some(time),
+    };
+    deadline.ok_or_else(|| invalid().into())
+}
+
+impl ParseCmd for GetRangeCmd {
     fn parse(value: RespValue) -> anyhow::Result<Self> {
         match value {
             RespValue::Array(mut arr) => {
-                if arr.len() == 1 {
+                if arr.len() == 3 {
                     let key = arr.remove(0);
-                    Ok(Self { key })
+                    let start = convert_bulk_string_to_number::<i64>(arr.remove(0))?;
+                    let end = convert_bulk_string_to_number::<i64>(arr.remove(0))?;
+                    Ok(Self { key, start, end })
                 } else {
-                    Err(anyhow::anyhow!("Invalid DEL command"))
+                    Err(anyhow::anyhow!("Invalid GETRANGE command"))
                 }
             }
-            _ => Err(anyhow::anyhow!("Invalid DEL command")),
+            _ => Err(anyhow::anyhow!("Invalid GETRANGE command")),
         }
     }
 }
 
-impl ParseCmd for PingCmd {
+impl ParseCmd for GetDelCmd {
     fn parse(value: RespValue) -> anyhow::Result<Self> {
         match value {
-            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
-            _ => Err(anyhow::anyhow!("Invalid PING command")),
+            RespValue::Array(mut arr) if arr.len() == 1 => Ok(Self {
+                key: arr.remove(0),
+                now: value::now(),
+            }),
+            _ => Err(anyhow::anyhow!("Invalid GETDEL command")),
         }
     }
 }
 
-impl From<RespValue> for Cmd {
-    fn from(value: RespValue) -> Self {
+impl ParseCmd for CasCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
         match value {
-            RespValue::Array(mut arr)
-            if arr.iter().all(|v| matches!(v, RespValue::BulkString(_))) =>
-                {
-                    if let RespValue::BulkString(cmd_bytes) = arr.remove(0) {
-                        let cmd = convert_bulk_string_to_string(cmd_bytes);
-                        match cmd.as_str() {
-                            "GET" => match GetCmd::parse(RespValue::Array(arr)) {
-                                Ok(cmd) => Cmd::Get(cmd),
-                                Err(_) => Cmd::Unknown,
-                            },
-                            "SET" => match SetCmd::parse(RespValue::Array(arr)) {
-                                Ok(cmd) => Cmd::Set(cmd),
-                                Err(_) => Cmd::Unknown,
-                            },
-                            "DEL" => match DelCmd::parse(RespValue::Array(arr)) {
-                                Ok(cmd) => Cmd::Del(cmd),
-                                Err(_) => Cmd::Unknown,
-                            },
-                            "PING" => match PingCmd::parse(RespValue::Array(arr)) {
-                                Ok(_) => Cmd::Ping,
-                                _ => Cmd::Unknown,
-                            },
-                            _ => Cmd::Unknown,
+            RespValue::Array(arr) if arr.len() == 3 => {
+                let [key, expected, new] = <[RespValue; 3]>::try_from(arr).unwrap();
+                Ok(Self {
+                    key,
+                    expected,
+                    new,
+                    now: value::now(),
+                })
+            }
+            _ => Err(anyhow::anyhow!("Invalid CAS command")),
+        }
+    }
+}
+
+impl ParseCmd for GetExCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if !arr.is_empty() => {
+                let key = arr.remove(0);
+                let now = value::now();
+                let mut expiry = None;
+                let mut ttl = None;
+                let mut args = arr.into_iter();
+                while let Some(arg) = args.next() {
+                    // options are case-insensitive, as for Redis
+                    let arg = match arg {
+                        RespValue::BulkString(bytes) => {
+                            convert_bulk_string_to_string(bytes).to_ascii_uppercase()
                         }
-                    } else {
-                        Cmd::Unknown
+                        _ => return Err(anyhow::anyhow!("Invalid GETEX command")),
+                    };
+                    match arg.as_str() {
+                        "PERSIST" if expiry.is_none() => expiry = Some(Expiry::Persist),
+                        "EX" | "PX" | "EXAT" | "PXAT" if expiry.is_none() => {
+                            let deadline = expiry_deadline(&arg, args.next(), now, "GETEX")?;
+                            if matches!(arg.as_str(), "EX" | "PX") {
+                                ttl = Some(deadline - now);
+                            }
+                            expiry = Some(Expiry::At(deadline));
+                        }
+                        _ => return Err(anyhow::anyhow!("Invalid GETEX command")),
                     }
                 }
-            _ => Cmd::Unknown,
+                Ok(Self {
+                    key,
+                    expiry,
+                    ttl,
+                    now,
+                })
+            }
+            _ => Err(anyhow::anyhow!("Invalid GETEX command")),
         }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub(crate) enum InnerCmd {
-    // String is request id
-    Get(RequestId, Vec<u8>),
+impl ParseCmd for SetBitCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 3 => {
+                let key = arr.remove(0);
+                let offset = convert_bulk_string_to_offset(arr.remove(0))?;
+                let bit = convert_bulk_string_to_bit(arr.remove(0))?;
+                Ok(Self {
+                    key,
+                    offset,
+                    bit,
+                    now: value::now(),
+                })
+            }
+            _ => Err(anyhow::anyhow!("Invalid SETBIT command")),
+        }
+    }
+}
+
+impl ParseCmd for GetBitCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 2 => {
+                let key = arr.remove(0);
+                let offset = convert_bulk_string_to_offset(arr.remove(0))?;
+                Ok(Self { key, offset })
+            }
+            _ => Err(anyhow::anyhow!("Invalid GETBIT command")),
+        }
+    }
+}
+
+impl ParseCmd for BitCountCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            // a range needs both its ends
+            RespValue::Array(mut arr) if matches!(arr.len(), 1 | 3 | 4) => {
+                let key = arr.remove(0);
+                let range = convert_bulk_strings_to_bit_range(arr)?;
+                Ok(Self { key, range })
+            }
+            _ => Err(anyhow::anyhow!("Invalid BITCOUNT command")),
+        }
+    }
+}
+
+impl ParseCmd for BitPosCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if (2..=5).contains(&arr.len()) => {
+                let key = arr.remove(0);
+                let bit = convert_bulk_string_to_bit(arr.remove(0))?;
+                let range = convert_bulk_strings_to_bit_range(arr)?;
+                Ok(Self { key, bit, range })
+            }
+            _ => Err(anyhow::anyhow!("Invalid BITPOS command")),
+        }
+    }
+}
+
+impl ParseCmd for DelCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if !arr.is_empty() => Ok(Self {
+                keys: arr,
+                now: value::now(),
+            }),
+            _ => Err(anyhow::anyhow!("Invalid DEL command")),
+        }
+    }
+}
+
+impl ParseCmd for CopyCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 2 || arr.len() == 3 => {
+                let source = arr.remove(0);
+                let destination = arr.remove(0);
+                let replace = match arr.pop() {
+                    None => false,
+                    Some(RespValue::BulkString(Some(option)))
+                        if option.eq_ignore_ascii_case(b"REPLACE") =>
+                    {
+                        true
+                    }
+                    Some(_) => return Err(anyhow::anyhow!("Invalid COPY command")),
+                };
+                Ok(Self {
+                    source,
+                    destination,
+                    replace,
+                    now: value::now(),
+                })
+            }
+            _ => Err(anyhow::anyhow!("Invalid COPY command")),
+        }
+    }
+}
+
+impl ParseCmd for DumpCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 1 => Ok(Self { key: arr.remove(0) }),
+            _ => Err(anyhow::anyhow!("Invalid DUMP command")),
+        }
+    }
+}
+
+impl ParseCmd for RestoreCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() >= 3 => {
+                let key = arr.remove(0);
+                let ttl = convert_bulk_string_to_number::<u64>(arr.remove(0))?;
+                let serialized = arr.remove(0);
+                let now = value::now();
+                let (mut replace, mut absttl) = (false, false);
+                let mut args = arr.into_iter();
+                while let Some(arg) = args.next() {
+                    let arg = match arg {
+                        RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                        _ => return Err(anyhow::anyhow!("Invalid RESTORE command")),
+                    };
+                    match arg.to_uppercase().as_str() {
+                        "REPLACE" => replace = true,
+                        "ABSTTL" => absttl = true,
+                        // the eviction hints of Redis, which the node does not evict by
+                        "IDLETIME" | "FREQ" => {
+                            let Some(hint) = args.next() else {
+                                return Err(anyhow::anyhow!("Invalid RESTORE command"));
+                            };
+                            convert_bulk_string_to_number::<u64>(hint)?;
+                        }
+                        _ => return Err(anyhow::anyhow!("Invalid RESTORE command")),
+                    }
+                }
+                let deadline = match ttl {
+                    0 => None,
+                    ttl if absttl => Some(ttl),
+                    ttl => Some(now.saturating_add(ttl)),
+                };
+                Ok(Self {
+                    key,
+                    serialized,
+                    deadline,
+                    replace,
+                    now,
+                })
+            }
+            _ => Err(anyhow::anyhow!("Invalid RESTORE command")),
+        }
+    }
+}
+
+impl ParseCmd for RenameCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 2 => Ok(Self {
+                source: arr.remove(0),
+                destination: arr.remove(0),
+                now: value::now(),
+            }),
+            _ => Err(anyhow::anyhow!("Invalid RENAME command")),
+        }
+    }
+}
+
+impl ParseCmd for PushCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() >= 2 {
+                    let key = arr.remove(0);
+                    Ok(Self {
+                        key,
+                        values: arr,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid PUSH command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid PUSH command")),
+        }
+    }
+}
+
+impl ParseCmd for PopCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 1 || arr.len() == 2 {
+                    let key = arr.remove(0);
+                    let count = if arr.len() == 1 {
+                        Some(convert_bulk_string_to_number::<usize>(arr.remove(0))?)
+                    } else {
+                        None
+                    };
+                    Ok(Self {
+                        key,
+                        count,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid POP command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid POP command")),
+        }
+    }
+}
+
+impl ParseCmd for LRangeCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 3 {
+                    let key = arr.remove(0);
+                    let start = convert_bulk_string_to_number::<i64>(arr.remove(0))?;
+                    let stop = convert_bulk_string_to_number::<i64>(arr.remove(0))?;
+                    Ok(Self { key, start, stop })
+                } else {
+                    Err(anyhow::anyhow!("Invalid LRANGE command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid LRANGE command")),
+        }
+    }
+}
+
+impl ParseCmd for LLenCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 1 {
+                    let key = arr.remove(0);
+                    Ok(Self { key })
+                } else {
+                    Err(anyhow::anyhow!("Invalid LLEN command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid LLEN command")),
+        }
+    }
+}
+
+impl ParseCmd for ZAddCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                // key followed by at least one score/member pair
+                if arr.len() >= 3 && arr.len() % 2 == 1 {
+                    let key = arr.remove(0);
+                    let mut members = Vec::with_capacity(arr.len() / 2);
+                    let mut iter = arr.into_iter();
+                    while let (Some(score), Some(member)) = (iter.next(), iter.next()) {
+                        members.push((convert_bulk_string_to_score(score)?, member));
+                    }
+                    Ok(Self {
+                        key,
+                        members,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid ZADD command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid ZADD command")),
+        }
+    }
+}
+
+impl ParseCmd for ZRemCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() >= 2 {
+                    let key = arr.remove(0);
+                    Ok(Self {
+                        key,
+                        members: arr,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid ZREM command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid ZREM command")),
+        }
+    }
+}
+
+impl ParseCmd for ZScoreCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 2 {
+                    let key = arr.remove(0);
+                    let member = arr.remove(0);
+                    Ok(Self { key, member })
+                } else {
+                    Err(anyhow::anyhow!("Invalid ZSCORE command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid ZSCORE command")),
+        }
+    }
+}
+
+impl ParseCmd for ZRangeCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 3 || arr.len() == 4 {
+                    let key = arr.remove(0);
+                    let start = convert_bulk_string_to_number::<i64>(arr.remove(0))?;
+                    let stop = convert_bulk_string_to_number::<i64>(arr.remove(0))?;
+                    let with_scores = parse_with_scores(arr)?;
+                    Ok(Self {
+                        key,
+                        start,
+                        stop,
+                        with_scores,
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid ZRANGE command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid ZRANGE command")),
+        }
+    }
+}
+
+impl ParseCmd for ZRangeByScoreCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 3 || arr.len() == 4 {
+                    let key = arr.remove(0);
+                    let min = convert_bulk_string_to_score_bound(arr.remove(0))?;
+                    let max = convert_bulk_string_to_score_bound(arr.remove(0))?;
+                    let with_scores = parse_with_scores(arr)?;
+                    Ok(Self {
+                        key,
+                        min,
+                        max,
+                        with_scores,
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid ZRANGEBYSCORE command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid ZRANGEBYSCORE command")),
+        }
+    }
+}
+
+/// Parse the optional trailing WITHSCORES flag of the ZRANGE family
+fn parse_with_scores(arr: Vec<RespValue>) -> anyhow::Result<bool> {
+    parse_flag(arr, "WITHSCORES")
+}
+
+/// Parse an optional trailing flag such as WITHSCORES
+fn parse_flag(mut arr: Vec<RespValue>, flag: &str) -> anyhow::Result<bool> {
+    match arr.pop() {
+        None => Ok(false),
+        Some(RespValue::BulkString(bytes)) => {
+            if convert_bulk_string_to_string(bytes).to_uppercase() == flag {
+                Ok(true)
+            } else {
+                Err(anyhow::anyhow!("Syntax error"))
+            }
+        }
+        Some(_) => Err(anyhow::anyhow!("Syntax error")),
+    }
+}
+
+impl ParseCmd for BfAddCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 2 {
+                    let key = arr.remove(0);
+                    let item = arr.remove(0);
+                    Ok(Self {
+                        key,
+                        item,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid BF.ADD command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid BF.ADD command")),
+        }
+    }
+}
+
+impl ParseCmd for BfMAddCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() >= 2 {
+                    let key = arr.remove(0);
+                    Ok(Self {
+                        key,
+                        items: arr,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid BF.MADD command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid BF.MADD command")),
+        }
+    }
+}
+
+impl ParseCmd for BfExistsCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 2 {
+                    let key = arr.remove(0);
+                    let item = arr.remove(0);
+                    Ok(Self { key, item })
+                } else {
+                    Err(anyhow::anyhow!("Invalid BF.EXISTS command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid BF.EXISTS command")),
+        }
+    }
+}
+
+impl ParseCmd for CmsInitByDimCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 3 {
+                    let key = arr.remove(0);
+                    let width = convert_bulk_string_to_dimension(arr.remove(0))?;
+                    let depth = convert_bulk_string_to_dimension(arr.remove(0))?;
+                    Ok(Self {
+                        key,
+                        width,
+                        depth,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid CMS.INITBYDIM command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid CMS.INITBYDIM command")),
+        }
+    }
+}
+
+impl ParseCmd for CmsIncrByCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                // key followed by at least one item/increment pair
+                if arr.len() >= 3 && arr.len() % 2 == 1 {
+                    let key = arr.remove(0);
+                    let mut increments = Vec::with_capacity(arr.len() / 2);
+                    let mut iter = arr.into_iter();
+                    while let (Some(item), Some(increment)) = (iter.next(), iter.next()) {
+                        increments.push((item, convert_bulk_string_to_number::<u64>(increment)?));
+                    }
+                    Ok(Self {
+                        key,
+                        increments,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid CMS.INCRBY command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid CMS.INCRBY command")),
+        }
+    }
+}
+
+impl ParseCmd for CmsQueryCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() >= 2 {
+                    let key = arr.remove(0);
+                    Ok(Self { key, items: arr })
+                } else {
+                    Err(anyhow::anyhow!("Invalid CMS.QUERY command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid CMS.QUERY command")),
+        }
+    }
+}
+
+impl ParseCmd for TopKReserveCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                // the decay of RedisBloom's HeavyKeeper is accepted and ignored
+                if arr.len() == 2 || arr.len() == 5 {
+                    let key = arr.remove(0);
+                    let k = convert_bulk_string_to_dimension(arr.remove(0))?;
+                    let (width, depth) = if arr.is_empty() {
+                        (sketch::DEFAULT_TOPK_WIDTH, sketch::DEFAULT_TOPK_DEPTH)
+                    } else {
+                        let width = convert_bulk_string_to_dimension(arr.remove(0))?;
+                        let depth = convert_bulk_string_to_dimension(arr.remove(0))?;
+                        convert_bulk_string_to_number::<f64>(arr.remove(0))?;
+                        (width, depth)
+                    };
+                    Ok(Self {
+                        key,
+                        k,
+                        width,
+                        depth,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid TOPK.RESERVE command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid TOPK.RESERVE command")),
+        }
+    }
+}
+
+impl ParseCmd for TopKAddCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() >= 2 {
+                    let key = arr.remove(0);
+                    Ok(Self {
+                        key,
+                        items: arr,
+                        now: value::now(),
+                    })
+                } else {
+                    Err(anyhow::anyhow!("Invalid TOPK.ADD command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid TOPK.ADD command")),
+        }
+    }
+}
+
+impl ParseCmd for TopKListCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 1 || arr.len() == 2 {
+                    let key = arr.remove(0);
+                    let with_count = parse_flag(arr, "WITHCOUNT")?;
+                    Ok(Self { key, with_count })
+                } else {
+                    Err(anyhow::anyhow!("Invalid TOPK.LIST command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid TOPK.LIST command")),
+        }
+    }
+}
+
+impl ParseCmd for KeysCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 1 => Ok(Self {
+                pattern: arr.remove(0),
+            }),
+            _ => Err(anyhow::anyhow!("Invalid KEYS command")),
+        }
+    }
+}
+
+impl ParseCmd for DbSizeCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
+            _ => Err(anyhow::anyhow!("Invalid DBSIZE command")),
+        }
+    }
+}
+
+impl FlushCmd {
+    /// FLUSHALL if `all`, FLUSHDB otherwise
+    fn parse(value: RespValue, all: bool) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid FLUSHALL command"));
+        };
+        let args = match &arr[..] {
+            [RespValue::BulkString(Some(step)), RespValue::BulkString(Some(token))]
+                if all && step.eq_ignore_ascii_case(b"CONFIRM") =>
+            {
+                let token = String::from_utf8(token.to_vec())?;
+                return Ok(Self {
+                    lazy: false,
+                    step: Some(FlushAllOp::Confirm(token)),
+                });
+            }
+            [RespValue::BulkString(Some(step)), args @ ..]
+                if all && step.eq_ignore_ascii_case(b"SCHEDULE") =>
+            {
+                args
+            }
+            args => args,
+        };
+        let lazy = match args {
+            [] => false,
+            [RespValue::BulkString(Some(mode))] if mode.eq_ignore_ascii_case(b"ASYNC") => true,
+            [RespValue::BulkString(Some(mode))] if mode.eq_ignore_ascii_case(b"SYNC") => false,
+            _ => return Err(anyhow::anyhow!("Invalid FLUSHALL command")),
+        };
+        let step = match (all, args.len() < arr.len()) {
+            (false, _) => None,
+            (true, true) => Some(FlushAllOp::Schedule(lazy)),
+            (true, false) => Some(FlushAllOp::Unconfirmed(lazy)),
+        };
+        Ok(Self { lazy, step })
+    }
+}
+
+impl ParseCmd for SelectCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 1 => Ok(Self {
+                db: convert_bulk_string_to_number(arr.remove(0))?,
+            }),
+            _ => Err(anyhow::anyhow!("Invalid SELECT command")),
+        }
+    }
+}
+
+impl ParseCmd for SwapDbCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 2 => Ok(Self {
+                first: convert_bulk_string_to_number(arr.remove(0))?,
+                second: convert_bulk_string_to_number(arr.remove(0))?,
+            }),
+            _ => Err(anyhow::anyhow!("Invalid SWAPDB command")),
+        }
+    }
+}
+
+impl ParseCmd for BgSaveCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
+            _ => Err(anyhow::anyhow!("Invalid BGSAVE command")),
+        }
+    }
+}
+
+impl ParseCmd for LastSaveCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
+            _ => Err(anyhow::anyhow!("Invalid LASTSAVE command")),
+        }
+    }
+}
+
+impl ParseCmd for CompactCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
+            _ => Err(anyhow::anyhow!("Invalid COMPACT command")),
+        }
+    }
+}
+
+impl ParseCmd for PromoteCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
+            _ => Err(anyhow::anyhow!("Invalid PROMOTE command")),
+        }
+    }
+}
+
+impl ParseCmd for PublishCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.len() == 2 {
+                    let channel = arr.remove(0);
+                    let message = arr.remove(0);
+                    Ok(Self { channel, message })
+                } else {
+                    Err(anyhow::anyhow!("Invalid PUBLISH command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid PUBLISH command")),
+        }
+    }
+}
+
+impl ParseCmd for SubscribeCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if !arr.is_empty() => Ok(Self { channels: arr }),
+            _ => Err(anyhow::anyhow!("Invalid SUBSCRIBE command")),
+        }
+    }
+}
+
+impl ParseCmd for UnsubscribeCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) => Ok(Self { channels: arr }),
+            _ => Err(anyhow::anyhow!("Invalid UNSUBSCRIBE command")),
+        }
+    }
+}
+
+impl ParseCmd for HelloCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid HELLO command"));
+        };
+        let mut args = arr.into_iter().map(|arg| match arg {
+            RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+            _ => String::new(),
+        });
+        let mut hello = Self {
+            protover: None,
+            auth: None,
+            setname: None,
+        };
+        let Some(protover) = args.next() else {
+            return Ok(hello);
+        };
+        hello.protover = Some(protover.parse().map_err(|_| {
+            Rejected("ERR Protocol version is not an integer or out of range".to_string())
+        })?);
+        // AUTH and SETNAME only go after a protocol version, as in Redis
+        let invalid = || anyhow::anyhow!("Invalid HELLO command");
+        while let Some(option) = args.next() {
+            match option.to_uppercase().as_str() {
+                "AUTH" => hello.auth = Some(args.next().zip(args.next()).ok_or_else(invalid)?),
+                "SETNAME" => hello.setname = Some(args.next().ok_or_else(invalid)?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(hello)
+    }
+}
+
+impl ParseCmd for InfoCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) => {
+                if arr.is_empty() {
+                    Ok(Self { section: None })
+                } else if arr.len() == 1 {
+                    match arr.remove(0) {
+                        RespValue::BulkString(bytes) => Ok(Self {
+                            section: Some(convert_bulk_string_to_string(bytes).to_lowercase()),
+                        }),
+                        _ => Err(anyhow::anyhow!("Invalid INFO command")),
+                    }
+                } else {
+                    Err(anyhow::anyhow!("Invalid INFO command"))
+                }
+            }
+            _ => Err(anyhow::anyhow!("Invalid INFO command")),
+        }
+    }
+}
+
+impl ParseCmd for ProcCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid PROC command"));
+        };
+        let mut args = convert_bulk_strings_to_vec(arr)?.into_iter();
+        let subcommand = String::from_utf8_lossy(&args.next().unwrap_or_default()).to_uppercase();
+        let name = String::from_utf8(args.next().unwrap_or_default())?;
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Invalid PROC command"));
+        }
+        let args: Vec<Vec<u8>> = args.collect();
+        let op = match (subcommand.as_str(), args.len()) {
+            ("LOAD", 1) => ProcOp::Load(name, args.into_iter().next().unwrap()),
+            ("CALL", n) if n >= 1 => {
+                let mut args = args.into_iter();
+                let key = args.next().unwrap();
+                ProcOp::Call(name, key, args.collect())
+            }
+            ("DELETE", 0) => ProcOp::Delete(name),
+            _ => return Err(anyhow::anyhow!("Invalid PROC command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl EvalCmd {
+    /// EVALSHA if `sha`, the script being given by its digest, EVAL otherwise
+    fn parse(value: RespValue, sha: bool) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid EVAL command"));
+        };
+        let mut args = convert_bulk_strings_to_vec(arr)?.into_iter();
+        let script = args.next().ok_or_else(|| anyhow::anyhow!("Invalid EVAL command"))?;
+        let numkeys: usize = String::from_utf8(args.next().unwrap_or_default())?.parse()?;
+        let mut keys: Vec<Vec<u8>> = args.collect();
+        if numkeys > keys.len() {
+            return Err(anyhow::anyhow!("Invalid EVAL command"));
+        }
+        let args = keys.split_off(numkeys);
+        // the database is the one of the client, given as the command is served
+        let call = Call { keys, args, db: 0, now: value::now() };
+        let op = match sha {
+            true => ScriptOp::EvalSha(String::from_utf8(script)?.to_lowercase(), call),
+            false => ScriptOp::Eval(script, call),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for ScriptCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid SCRIPT command"));
+        };
+        let mut args = convert_bulk_strings_to_vec(arr)?.into_iter();
+        let subcommand = String::from_utf8_lossy(&args.next().unwrap_or_default()).to_uppercase();
+        let args: Vec<Vec<u8>> = args.collect();
+        let op = match (subcommand.as_str(), &args[..]) {
+            ("LOAD", [script]) => ScriptOp::Load(script.clone()),
+            ("EXISTS", digests) if !digests.is_empty() => ScriptOp::Exists(
+                digests
+                    .iter()
+                    .map(|digest| String::from_utf8_lossy(digest).to_lowercase())
+                    .collect(),
+            ),
+            // scripts are flushed in the apply path either way
+            ("FLUSH", []) => ScriptOp::Flush,
+            ("FLUSH", [mode])
+                if mode.eq_ignore_ascii_case(b"ASYNC") || mode.eq_ignore_ascii_case(b"SYNC") =>
+            {
+                ScriptOp::Flush
+            }
+            _ => return Err(anyhow::anyhow!("Invalid SCRIPT command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for LockCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid LOCK command"));
+        };
+        let args = convert_bulk_strings_to_vec(arr)?;
+        let (key, ttl, notify) = match &args[..] {
+            [key, ttl] => (key, ttl, false),
+            [key, ttl, notify] if notify.eq_ignore_ascii_case(b"NOTIFY") => (key, ttl, true),
+            _ => return Err(anyhow::anyhow!("Invalid LOCK command")),
+        };
+        let ttl: u64 = String::from_utf8(ttl.clone())?.parse()?;
+        if ttl == 0 {
+            return Err(anyhow::anyhow!("Invalid LOCK command"));
+        }
+        let op = LockOp::Lock(key.clone(), ttl, notify, value::now());
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for UnlockCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid UNLOCK command"));
+        };
+        let [key, token] = &convert_bulk_strings_to_vec(arr)?[..] else {
+            return Err(anyhow::anyhow!("Invalid UNLOCK command"));
+        };
+        let token: u64 = String::from_utf8(token.clone())?.parse()?;
+        let op = LockOp::Unlock(key.clone(), token, value::now());
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for IfCmd {
+    /// `IF EXISTS <key> THEN <command> [ELSE <command>]`, the first ELSE ending the THEN command
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid IF command"));
+        };
+        let is = |arg: &RespValue, word: &str| match arg {
+            RespValue::BulkString(Some(arg)) => arg.eq_ignore_ascii_case(word.as_bytes()),
+            _ => false,
+        };
+        let [exists, key, then, args @ ..] = &arr[..] else {
+            return Err(anyhow::anyhow!("Invalid IF command"));
+        };
+        if !is(exists, "EXISTS") || !is(then, "THEN") {
+            return Err(anyhow::anyhow!("Invalid IF command"));
+        }
+        let (then, otherwise) = match args.iter().position(|arg| is(arg, "ELSE")) {
+            Some(at) => (&args[..at], Some(&args[at + 1..])),
+            None => (args, None),
+        };
+        let branch = |args: &[RespValue]| match args.is_empty() {
+            true => Err(anyhow::anyhow!("Invalid IF command")),
+            false => Ok(Box::new(Cmd::from(RespValue::Array(args.to_vec())))),
+        };
+        Ok(Self {
+            key: convert_bulk_string_to_vec(key.clone())?,
+            now: value::now(),
+            then: branch(then)?,
+            otherwise: otherwise.map(branch).transpose()?,
+        })
+    }
+}
+
+impl ParseCmd for ShutdownCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid SHUTDOWN command"));
+        };
+        let args: Vec<String> = arr
+            .into_iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes).to_uppercase(),
+                _ => String::new(),
+            })
+            .collect();
+        let mode = match args.as_slice() {
+            [] => SaveMode::Default,
+            [mode] if mode == "SAVE" => SaveMode::Save,
+            [mode] if mode == "NOSAVE" => SaveMode::NoSave,
+            _ => return Err(anyhow::anyhow!("Invalid SHUTDOWN command")),
+        };
+        Ok(Self { mode })
+    }
+}
+
+impl ParseCmd for SnapshotCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid SNAPSHOT command"));
+        };
+        let args: Vec<String> = arr
+            .into_iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                _ => String::new(),
+            })
+            .collect();
+        let op = match args.as_slice() {
+            [subcommand] if subcommand.eq_ignore_ascii_case("TAKE") => SnapshotOp::Take(None),
+            [subcommand, index] if subcommand.eq_ignore_ascii_case("TAKE") => {
+                SnapshotOp::Take(Some(index.parse()?))
+            }
+            [subcommand] if subcommand.eq_ignore_ascii_case("RELEASE") => SnapshotOp::Release,
+            [subcommand] if subcommand.eq_ignore_ascii_case("OPEN") => SnapshotOp::Open(None),
+            [subcommand, secs] if subcommand.eq_ignore_ascii_case("OPEN") => {
+                SnapshotOp::Open(Some(secs.parse()?))
+            }
+            [subcommand] if subcommand.eq_ignore_ascii_case("CLOSE") => SnapshotOp::Close(None),
+            [subcommand, handle] if subcommand.eq_ignore_ascii_case("CLOSE") => {
+                SnapshotOp::Close(Some(handle.parse()?))
+            }
+            _ => return Err(anyhow::anyhow!("Invalid SNAPSHOT command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for SlowLogCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid SLOWLOG command"));
+        };
+        let args: Vec<String> = arr
+            .into_iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                _ => String::new(),
+            })
+            .collect();
+        let op = match args.as_slice() {
+            // like Redis, the ten newest entries by default
+            [subcommand] if subcommand.eq_ignore_ascii_case("GET") => SlowLogOp::Get(10),
+            [subcommand, count] if subcommand.eq_ignore_ascii_case("GET") => {
+                SlowLogOp::Get(count.parse()?)
+            }
+            [subcommand] if subcommand.eq_ignore_ascii_case("LEN") => SlowLogOp::Len,
+            [subcommand] if subcommand.eq_ignore_ascii_case("RESET") => SlowLogOp::Reset,
+            _ => return Err(anyhow::anyhow!("Invalid SLOWLOG command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for RaftCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid RAFT command"));
+        };
+        let args: Vec<String> = arr
+            .into_iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                _ => String::new(),
+            })
+            .collect();
+        let op = match args.as_slice() {
+            [subcommand, from] if subcommand.eq_ignore_ascii_case("LOG") => {
+                RaftOp::Log(from.parse()?, None)
+            }
+            [subcommand, from, to] if subcommand.eq_ignore_ascii_case("LOG") => {
+                RaftOp::Log(from.parse()?, Some(to.parse()?))
+            }
+            [subcommand, from] if subcommand.eq_ignore_ascii_case("TAIL") => {
+                RaftOp::Tail(from.parse()?)
+            }
+            _ => return Err(anyhow::anyhow!("Invalid RAFT command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for ExportCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 1 => Ok(Self {
+                pattern: arr.remove(0),
+            }),
+            _ => Err(anyhow::anyhow!("Invalid EXPORT command")),
+        }
+    }
+}
+
+impl ParseCmd for ClusterCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 2 => {
+                let subcommand = match arr.remove(0) {
+                    RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                    _ => return Err(anyhow::anyhow!("Invalid CLUSTER command")),
+                };
+                let key = convert_bulk_string_to_vec(arr.remove(0))?;
+                let op = match subcommand.to_uppercase().as_str() {
+                    "KEYSLOT" => ClusterOp::KeySlot(key),
+                    "KEYOWNER" => ClusterOp::KeyOwner(key),
+                    _ => return Err(anyhow::anyhow!("Invalid CLUSTER command")),
+                };
+                Ok(Self { op })
+            }
+            _ => Err(anyhow::anyhow!("Invalid CLUSTER command")),
+        }
+    }
+}
+
+impl ParseCmd for ClientCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid CLIENT command"));
+        };
+        let mut args = arr.into_iter().map(|arg| match arg {
+            RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+            _ => String::new(),
+        });
+        let subcommand = args.next().unwrap_or_default().to_uppercase();
+        let args: Vec<String> = args.collect();
+        let op = match (subcommand.as_str(), args.len()) {
+            ("ID", 0) => ClientOp::Id,
+            ("GETNAME", 0) => ClientOp::GetName,
+            ("SETNAME", 1) => ClientOp::SetName(args[0].clone()),
+            ("LIST", 0) => ClientOp::List,
+            ("TRACE", 1) if args[0].eq_ignore_ascii_case("ON") => ClientOp::Trace(true),
+            ("TRACE", 1) if args[0].eq_ignore_ascii_case("OFF") => ClientOp::Trace(false),
+            ("TRACKING", 1) if args[0].eq_ignore_ascii_case("ON") => ClientOp::Tracking(true),
+            ("TRACKING", 1) if args[0].eq_ignore_ascii_case("OFF") => ClientOp::Tracking(false),
+            ("WATERMARK", 1) if args[0].eq_ignore_ascii_case("ON") => ClientOp::Watermark(true),
+            ("WATERMARK", 1) if args[0].eq_ignore_ascii_case("OFF") => ClientOp::Watermark(false),
+            ("INFO", 0) => ClientOp::Info(None, false),
+            ("INFO", 1) if args[0].eq_ignore_ascii_case("HISTORY") => ClientOp::Info(None, true),
+            ("INFO", 2 | 3)
+                if args[0].eq_ignore_ascii_case("ID")
+                    && args.get(2).is_none_or(|arg| arg.eq_ignore_ascii_case("HISTORY")) =>
+            {
+                ClientOp::Info(Some(args[1].parse()?), args.len() == 3)
+            }
+            ("KILL", 1) => ClientOp::Kill(KillFilter {
+                addr: Some(args[0].clone()),
+                legacy: true,
+                ..Default::default()
+            }),
+            ("KILL", n) if n % 2 == 0 => {
+                let mut filter = KillFilter {
+                    skip_me: true,
+                    ..Default::default()
+                };
+                for pair in args.chunks(2) {
+                    match (pair[0].to_uppercase().as_str(), pair[1].as_str()) {
+                        ("ID", id) => filter.id = Some(id.parse()?),
+                        ("ADDR", addr) => filter.addr = Some(addr.to_string()),
+                        ("SKIPME", "yes") => filter.skip_me = true,
+                        ("SKIPME", "no") => filter.skip_me = false,
+                        _ => return Err(anyhow::anyhow!("Invalid CLIENT KILL filter")),
+                    }
+                }
+                ClientOp::Kill(filter)
+            }
+            _ => return Err(anyhow::anyhow!("Invalid CLIENT command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for ConfigCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid CONFIG command"));
+        };
+        let mut args = arr.into_iter().map(|arg| match arg {
+            RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+            _ => String::new(),
+        });
+        let subcommand = args.next().unwrap_or_default().to_uppercase();
+        let args: Vec<String> = args.collect();
+        let op = match (subcommand.as_str(), args.len()) {
+            ("GET", 1) => ConfigOp::Get(args[0].clone()),
+            ("REWRITE", 0) => ConfigOp::Rewrite,
+            ("SET", n) if n > 0 && n % 2 == 0 => ConfigOp::Set(
+                args.chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect(),
+            ),
+            _ => return Err(anyhow::anyhow!("Invalid CONFIG command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for DebugCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid DEBUG command"));
+        };
+        let mut args = arr.into_iter().map(|arg| match arg {
+            RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+            _ => String::new(),
+        });
+        let subcommand = args.next().unwrap_or_default().to_uppercase();
+        let args: Vec<String> = args.collect();
+        let op = match (subcommand.as_str(), args.as_slice()) {
+            ("FAILPOINT", [name, action]) => {
+                let action = match action.to_uppercase().as_str() {
+                    "OFF" => failpoint::Action::Off,
+                    "PANIC" => failpoint::Action::Panic,
+                    "ERROR" => failpoint::Action::Error,
+                    _ => return Err(anyhow::anyhow!("Invalid DEBUG FAILPOINT action")),
+                };
+                DebugOp::FailPoint(name.to_lowercase(), action)
+            }
+            ("FAILPOINT", [name, action, ms]) if action.eq_ignore_ascii_case("SLEEP") => {
+                DebugOp::FailPoint(name.to_lowercase(), failpoint::Action::Sleep(ms.parse()?))
+            }
+            ("SLEEP", [secs]) => DebugOp::Sleep(Duration::try_from_secs_f64(secs.parse()?)?),
+            ("RECOVERY-BENCH", []) => DebugOp::RecoveryBench,
+            _ => return Err(anyhow::anyhow!("Invalid DEBUG command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for AuthCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() == 1 => Ok(Self {
+                username: None,
+                password: arr.remove(0),
+            }),
+            RespValue::Array(mut arr) if arr.len() == 2 => Ok(Self {
+                username: Some(arr.remove(0)),
+                password: arr.remove(0),
+            }),
+            _ => Err(anyhow::anyhow!("Invalid AUTH command")),
+        }
+    }
+}
+
+impl ParseCmd for AclCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid ACL command"));
+        };
+        let mut args = arr.into_iter().map(|arg| match arg {
+            RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+            _ => String::new(),
+        });
+        let subcommand = args.next().unwrap_or_default().to_uppercase();
+        let args: Vec<String> = args.collect();
+        let op = match (subcommand.as_str(), args.len()) {
+            ("WHOAMI", 0) => AclOp::WhoAmI,
+            ("LIST", 0) => AclOp::List,
+            ("GETUSER", 1) => AclOp::GetUser(args[0].clone()),
+            ("SETUSER", n) if n >= 1 => AclOp::SetUser(args[0].clone(), args[1..].to_vec()),
+            _ => return Err(anyhow::anyhow!("Invalid ACL command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for MonitorCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
+            _ => Err(anyhow::anyhow!("Invalid MONITOR command")),
+        }
+    }
+}
+
+impl ParseCmd for FeatureCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        let RespValue::Array(arr) = value else {
+            return Err(anyhow::anyhow!("Invalid FEATURE command"));
+        };
+        let args: Vec<String> = arr
+            .into_iter()
+            .map(|arg| match arg {
+                RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                _ => String::new(),
+            })
+            .collect();
+        let op = match args.as_slice() {
+            [subcommand, name] if subcommand.eq_ignore_ascii_case("ENABLE") => {
+                FeatureOp::Enable(name.clone())
+            }
+            [subcommand, name] if subcommand.eq_ignore_ascii_case("DISABLE") => {
+                FeatureOp::Disable(name.clone())
+            }
+            [subcommand] if subcommand.eq_ignore_ascii_case("LIST") => FeatureOp::List,
+            _ => return Err(anyhow::anyhow!("Invalid FEATURE command")),
+        };
+        Ok(Self { op })
+    }
+}
+
+impl ParseCmd for AdminCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(mut arr) if arr.len() >= 2 => {
+                let subcommand = match arr.remove(0) {
+                    RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                    _ => String::new(),
+                };
+                let op = match subcommand.to_uppercase().as_str() {
+                    "BROADCAST" => AdminOp::Broadcast(convert_bulk_strings_to_vec(arr)?),
+                    "MAINTENANCE" if arr.len() == 1 => {
+                        match convert_bulk_string_to_vec(arr.remove(0))?.to_ascii_uppercase() {
+                            state if state == b"ON" => AdminOp::Maintenance(true),
+                            state if state == b"OFF" => AdminOp::Maintenance(false),
+                            _ => return Err(anyhow::anyhow!("Invalid ADMIN command")),
+                        }
+                    }
+                    _ => return Err(anyhow::anyhow!("Invalid ADMIN command")),
+                };
+                Ok(Self { op })
+            }
+            _ => Err(anyhow::anyhow!("Invalid ADMIN command")),
+        }
+    }
+}
+
+impl ParseCmd for WaitCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.len() == 2 => {
+                let mut args = arr.into_iter().map(|arg| match arg {
+                    RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes),
+                    _ => String::new(),
+                });
+                Ok(Self {
+                    replicas: args.next().unwrap_or_default().parse()?,
+                    timeout: args.next().unwrap_or_default().parse()?,
+                })
+            }
+            _ => Err(anyhow::anyhow!("Invalid WAIT command")),
+        }
+    }
+}
+
+impl ParseCmd for PingCmd {
+    fn parse(value: RespValue) -> anyhow::Result<Self> {
+        match value {
+            RespValue::Array(arr) if arr.is_empty() => Ok(Self),
+            _ => Err(anyhow::anyhow!("Invalid PING command")),
+        }
+    }
+}
+
+impl From<RespValue> for Cmd {
+    fn from(value: RespValue) -> Self {
+        match compat::rewrite(value) {
+            RespValue::Array(mut arr)
+            if !arr.is_empty() && arr.iter().all(|v| matches!(v, RespValue::BulkString(_))) =>
+                {
+                    if let RespValue::BulkString(cmd_bytes) = arr.remove(0) {
+                        let cmd = convert_bulk_string_to_string(cmd_bytes);
+                        match cmd.as_str() {
+                            "GET" => match GetCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Get(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "MGETSNAPSHOT" => match MGetSnapshotCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::MGetSnapshot(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SET" => match SetCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Set(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "GETRANGE" => match GetRangeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::GetRange(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "GETDEL" => match GetDelCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::GetDel(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "CAS" => match CasCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Cas(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "GETEX" => match GetExCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::GetEx(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SETBIT" => match SetBitCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::SetBit(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "GETBIT" => match GetBitCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::GetBit(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "BITCOUNT" => match BitCountCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::BitCount(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "BITPOS" => match BitPosCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::BitPos(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            // the storage reclaims nothing as a key is deleted, UNLINK has
+                            // nothing to defer
+                            "DEL" | "UNLINK" => match DelCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Del(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "COPY" => match CopyCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Copy(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "RENAME" => match RenameCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Rename(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "RENAMENX" => match RenameCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::RenameNx(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "DUMP" => match DumpCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Dump(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "RESTORE" => match RestoreCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Restore(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "LPUSH" => match PushCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::LPush(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "RPUSH" => match PushCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::RPush(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "LPOP" => match PopCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::LPop(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "RPOP" => match PopCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::RPop(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "LRANGE" => match LRangeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::LRange(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "LLEN" => match LLenCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::LLen(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "ZADD" => match ZAddCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::ZAdd(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "ZREM" => match ZRemCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::ZRem(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "ZSCORE" => match ZScoreCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::ZScore(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "ZRANGE" => match ZRangeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::ZRange(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "ZRANGEBYSCORE" => match ZRangeByScoreCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::ZRangeByScore(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "BF.ADD" => match BfAddCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::BfAdd(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "BF.MADD" => match BfMAddCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::BfMAdd(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "BF.EXISTS" => match BfExistsCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::BfExists(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "CMS.INITBYDIM" => match CmsInitByDimCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::CmsInitByDim(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "CMS.INCRBY" => match CmsIncrByCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::CmsIncrBy(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "CMS.QUERY" => match CmsQueryCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::CmsQuery(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "TOPK.RESERVE" => match TopKReserveCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::TopKReserve(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "TOPK.ADD" => match TopKAddCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::TopKAdd(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "TOPK.LIST" => match TopKListCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::TopKList(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "KEYS" => match KeysCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Keys(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "DBSIZE" => match DbSizeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::DbSize(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "FLUSHALL" => match FlushCmd::parse(RespValue::Array(arr), true) {
+                                Ok(cmd) => Cmd::Flush(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "FLUSHDB" => match FlushCmd::parse(RespValue::Array(arr), false) {
+                                Ok(cmd) => Cmd::Flush(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SELECT" => match SelectCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Select(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SWAPDB" => match SwapDbCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::SwapDb(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "BGSAVE" => match BgSaveCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::BgSave(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "COMPACT" => match CompactCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Compact(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "PROMOTE" => match PromoteCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Promote(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "LASTSAVE" => match LastSaveCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::LastSave(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "PUBLISH" => match PublishCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Publish(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SUBSCRIBE" => match SubscribeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Subscribe(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "UNSUBSCRIBE" => match UnsubscribeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Unsubscribe(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "PSUBSCRIBE" => match SubscribeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::PSubscribe(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "PUNSUBSCRIBE" => match UnsubscribeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::PUnsubscribe(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SPUBLISH" => match PublishCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::SPublish(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SSUBSCRIBE" => match SubscribeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::SSubscribe(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SUNSUBSCRIBE" => match UnsubscribeCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::SUnsubscribe(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "HELLO" => match HelloCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Hello(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "INFO" => match InfoCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Info(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "CLUSTER" => match ClusterCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Cluster(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "CLIENT" => match ClientCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Client(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "CONFIG" => match ConfigCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Config(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "DEBUG" => match DebugCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Debug(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "AUTH" => match AuthCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Auth(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "ACL" => match AclCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Acl(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "PROC" => match ProcCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Proc(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "EVAL" => match EvalCmd::parse(RespValue::Array(arr), false) {
+                                Ok(cmd) => Cmd::Eval(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "EVALSHA" => match EvalCmd::parse(RespValue::Array(arr), true) {
+                                Ok(cmd) => Cmd::Eval(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SCRIPT" => match ScriptCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Script(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "LOCK" => match LockCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Lock(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "UNLOCK" => match UnlockCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Unlock(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "IF" => match IfCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::If(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SHUTDOWN" => match ShutdownCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Shutdown(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SNAPSHOT" => match SnapshotCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Snapshot(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "SLOWLOG" => match SlowLogCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::SlowLog(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "RAFT" => match RaftCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Raft(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "EXPORT" => match ExportCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Export(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "MONITOR" => match MonitorCmd::parse(RespValue::Array(arr)) {
+                                Ok(_) => Cmd::Monitor,
+                                _ => Cmd::Unknown,
+                            },
+                            "ADMIN" => match AdminCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Admin(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "FEATURE" => match FeatureCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Feature(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "WAIT" => match WaitCmd::parse(RespValue::Array(arr)) {
+                                Ok(cmd) => Cmd::Wait(cmd),
+                                Err(e) => Cmd::Rejected(e),
+                            },
+                            "PING" => match PingCmd::parse(RespValue::Array(arr)) {
+                                Ok(_) => Cmd::Ping,
+                                _ => Cmd::Unknown,
+                            },
+                            _ => match plugin::command(&cmd) {
+                                Some(command) if command.accepts(arr.len()) => {
+                                    Cmd::Plugin(PluginCmd {
+                                        name: command.name,
+                                        args: arr,
+                                    })
+                                }
+                                _ => Cmd::Unknown,
+                            },
+                        }
+                    } else {
+                        Cmd::Unknown
+                    }
+                }
+            _ => Cmd::Unknown,
+        }
+    }
+}
+
+/// A DEBUG subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum DebugOp {
+    // Failpoint, Action
+    FailPoint(String, failpoint::Action),
+    // Pause the connection, to test the timeouts of clients
+    Sleep(Duration),
+    // Time the phases of a startup on the data of the node
+    RecoveryBench,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum InnerCmd {
+    // String is request id
+    // Key, Consistency (None for the read-consistency setting)
+    Get(RequestId, Vec<u8>, Option<Consistency>),
     // Key, Value, isNX
     Put(RequestId, Vec<u8>, Vec<u8>, Option<PutOptionSerde>),
-    Del(RequestId, Vec<u8>),
+    // SET with the GET option: Key, Value, isNX
+    SetGet(RequestId, Vec<u8>, Vec<u8>, Option<PutOptionSerde>),
+    // A chunk of the value of a SET too large for an entry: Request id of the SET, Index, Bytes
+    Chunk(RequestId, RequestId, u32, Vec<u8>),
+    // SET of the value staged in chunks under its request id: Key, Number of chunks, isNX
+    PutChunked(RequestId, Vec<u8>, u32, Option<PutOptionSerde>),
+    // Key, Start, End
+    GetRange(RequestId, Vec<u8>, i64, i64),
+    // Key, Time of the node the command was sent to
+    GetDel(RequestId, Vec<u8>, u64),
+    // Key, Expiry (None to leave it as is), Time of the node the command was sent to
+    GetEx(RequestId, Vec<u8>, Option<Expiry>, u64),
+    // Key, Offset, Bit, Time of the node the command was sent to
+    SetBit(RequestId, Vec<u8>, u64, bool, u64),
+    // Key, Offset
+    GetBit(RequestId, Vec<u8>, u64),
+    // Key, Range (None for the whole string)
+    BitCount(RequestId, Vec<u8>, Option<bitmap::Range>),
+    // Key, Bit, Range (None for the whole string)
+    BitPos(RequestId, Vec<u8>, bool, Option<bitmap::Range>),
+    // Keys, Time of the node the command was sent to
+    Del(RequestId, Vec<Vec<u8>>, u64),
+    // Source, Destination, REPLACE, Time of the node the command was sent to
+    Copy(RequestId, Vec<u8>, Vec<u8>, bool, u64),
+    // Source, Destination, NX (RENAMENX), Time of the node the command was sent to
+    Rename(RequestId, Vec<u8>, Vec<u8>, bool, u64),
+    // Key
+    Dump(RequestId, Vec<u8>),
+    // Key, Serialized value, Deadline, REPLACE, Time of the node the command was sent to
+    Restore(RequestId, Vec<u8>, Vec<u8>, Option<u64>, bool, u64),
+    // Key, Values, End to push to, Time of the node the command was sent to
+    Push(RequestId, Vec<u8>, Vec<Vec<u8>>, End, u64),
+    // Key, Count (None for a single element reply), End to pop from, Time of the node the command was sent to
+    Pop(RequestId, Vec<u8>, Option<usize>, End, u64),
+    // Key, Start, Stop
+    LRange(RequestId, Vec<u8>, i64, i64),
+    LLen(RequestId, Vec<u8>),
+    // Key, (Score, Member) pairs, Time of the node the command was sent to
+    ZAdd(RequestId, Vec<u8>, Vec<(f64, Vec<u8>)>, u64),
+    // Key, Members, Time of the node the command was sent to
+    ZRem(RequestId, Vec<u8>, Vec<Vec<u8>>, u64),
+    // Key, Member
+    ZScore(RequestId, Vec<u8>, Vec<u8>),
+    // Key, Start, Stop, WITHSCORES
+    ZRange(RequestId, Vec<u8>, i64, i64, bool),
+    // Key, Min, Max, WITHSCORES
+    ZRangeByScore(RequestId, Vec<u8>, ScoreBound, ScoreBound, bool),
+    // Key, Items, whether the reply is an array (BF.MADD), Time of the node the command was sent to
+    BfAdd(RequestId, Vec<u8>, Vec<Vec<u8>>, bool, u64),
+    // Key, Item
+    BfExists(RequestId, Vec<u8>, Vec<u8>),
+    // Key, Width, Depth, Time of the node the command was sent to
+    CmsInitByDim(RequestId, Vec<u8>, u32, u32, u64),
+    // Key, (Item, Increment) pairs, Time of the node the command was sent to
+    CmsIncrBy(RequestId, Vec<u8>, Vec<(Vec<u8>, u64)>, u64),
+    // Key, Items
+    CmsQuery(RequestId, Vec<u8>, Vec<Vec<u8>>),
+    // Key, K, Width, Depth, Time of the node the command was sent to
+    TopKReserve(RequestId, Vec<u8>, u32, u32, u32, u64),
+    // Key, Items, Time of the node the command was sent to
+    TopKAdd(RequestId, Vec<u8>, Vec<Vec<u8>>, u64),
+    // Key, WITHCOUNT
+    TopKList(RequestId, Vec<u8>, bool),
+    // Pattern
+    Keys(RequestId, Vec<u8>),
+    DbSize(RequestId),
+    // Database (None for every one), ASYNC
+    Flush(RequestId, Option<u32>, bool),
+    // Database
+    Select(u32),
+    // Databases
+    SwapDb(RequestId, u32, u32),
+    BgSave,
+    LastSave,
+    Compact,
+    Promote,
+    // Channel, Message
+    Publish(RequestId, Vec<u8>, Vec<u8>),
+    // Channels
+    Subscribe(Vec<Vec<u8>>),
+    // Channels, empty means all of them
+    Unsubscribe(Vec<Vec<u8>>),
+    // Patterns
+    PSubscribe(Vec<Vec<u8>>),
+    // Patterns, empty means all of them
+    PUnsubscribe(Vec<Vec<u8>>),
+    // Shard channel, Message
+    SPublish(RequestId, Vec<u8>, Vec<u8>),
+    // Shard channels
+    SSubscribe(Vec<Vec<u8>>),
+    // Shard channels, empty means all of them
+    SUnsubscribe(Vec<Vec<u8>>),
+    // Protocol version, the user and password to authenticate as, and the client name
+    Hello(Option<i64>, Option<(String, String)>, Option<String>),
+    // Section
+    Info(Option<String>),
+    Cluster(ClusterOp),
+    Client(ClientOp),
+    Config(ConfigOp),
+    Debug(DebugOp),
+    // User, Password
+    Auth(Option<String>, String),
+    Acl(AclOp),
+    Proc(RequestId, ProcOp),
+    Shutdown(SaveMode),
+    Snapshot(SnapshotOp),
+    SlowLog(SlowLogOp),
+    Raft(RaftOp),
+    // Pattern
+    Export(Vec<u8>),
+    Monitor,
+    Admin(AdminOp),
+    Feature(RequestId, FeatureOp),
+    // Replicas, timeout in milliseconds
+    Wait(usize, u64),
     Ping,
+    // Name, Arguments
+    Plugin(RequestId, String, Vec<Vec<u8>>),
+    // Replicated ahead of a linearizable read, applying it changes nothing
+    ReadBarrier(RequestId),
+    // Keys, picked by the maxmemory policy of the node proposing it
+    Evict(RequestId, Vec<Vec<u8>>),
+    Script(RequestId, ScriptOp),
+    Lock(RequestId, LockOp),
+    // Key whose existence picks the write, unix time in milliseconds, the write if it exists, the
+    // one otherwise
+    If(RequestId, Vec<u8>, u64, Box<InnerCmd>, Option<Box<InnerCmd>>),
+    // Keys
+    MGetSnapshot(RequestId, Vec<Vec<u8>>),
+    Ephemeral(RequestId, EphemeralOp),
+    FlushAll(RequestId, FlushAllOp),
+    // Key, Expected, New, Time of the node the command was sent to
+    Cas(RequestId, Vec<u8>, Vec<u8>, Vec<u8>, u64),
 }
 
 impl Debug for InnerCmd {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            InnerCmd::Get(_, key) => write!(f, "GET {:?}", key),
+            InnerCmd::Get(_, key, _) => write!(f, "GET {:?}", key),
+            InnerCmd::MGetSnapshot(_, keys) => write!(f, "MGETSNAPSHOT {:?}", keys),
             InnerCmd::Put(_, key, value, op) => {
                 if let Some(op) = op {
                     write!(f, "SET {:?} {:?} with option {:?}", key, value, op)
                 } else {
-                    write!(f, "SET {:?} {:?}", key, value)
+                    write!(f, "SET {:?} {:?}", key, value)
+                }
+            }
+            InnerCmd::SetGet(_, key, value, _) => write!(f, "SET {:?} {:?} GET", key, value),
+            InnerCmd::Chunk(_, id, index, bytes) => {
+                write!(f, "CHUNK {} {} of {} bytes", Uuid::from_bytes(*id), index, bytes.len())
+            }
+            InnerCmd::PutChunked(_, key, chunks, op) => {
+                write!(f, "SET {:?} from {} chunks with option {:?}", key, chunks, op)
+            }
+            InnerCmd::GetRange(_, key, start, end) => {
+                write!(f, "GETRANGE {:?} {} {}", key, start, end)
+            }
+            InnerCmd::GetDel(_, key, _) => write!(f, "GETDEL {:?}", key),
+            InnerCmd::Cas(_, key, expected, new, _) => {
+                write!(f, "CAS {:?} {:?} {:?}", key, expected, new)
+            }
+            InnerCmd::GetEx(_, key, expiry, _) => write!(f, "GETEX {:?} {:?}", key, expiry),
+            InnerCmd::SetBit(_, key, offset, bit, _) => {
+                write!(f, "SETBIT {:?} {} {}", key, offset, *bit as u8)
+            }
+            InnerCmd::GetBit(_, key, offset) => write!(f, "GETBIT {:?} {}", key, offset),
+            InnerCmd::BitCount(_, key, range) => write!(f, "BITCOUNT {:?} {:?}", key, range),
+            InnerCmd::BitPos(_, key, bit, range) => {
+                write!(f, "BITPOS {:?} {} {:?}", key, *bit as u8, range)
+            }
+            InnerCmd::Del(_, keys, _) => write!(f, "DEL {:?}", keys),
+            InnerCmd::Copy(_, source, destination, replace, _) => {
+                write!(f, "COPY {:?} {:?} replace {}", source, destination, replace)
+            }
+            InnerCmd::Rename(_, source, destination, nx, _) => {
+                write!(f, "RENAME {:?} {:?} nx {}", source, destination, nx)
+            }
+            InnerCmd::Dump(_, key) => write!(f, "DUMP {:?}", key),
+            InnerCmd::Restore(_, key, _, deadline, replace, _) => {
+                write!(f, "RESTORE {:?} deadline {:?} replace {}", key, deadline, replace)
+            }
+            InnerCmd::Push(_, key, values, end, _) => write!(f, "PUSH {:?} {:?} {:?}", end, key, values),
+            InnerCmd::Pop(_, key, count, end, _) => write!(f, "POP {:?} {:?} {:?}", end, key, count),
+            InnerCmd::LRange(_, key, start, stop) => write!(f, "LRANGE {:?} {} {}", key, start, stop),
+            InnerCmd::LLen(_, key) => write!(f, "LLEN {:?}", key),
+            InnerCmd::ZAdd(_, key, members, _) => write!(f, "ZADD {:?} {:?}", key, members),
+            InnerCmd::ZRem(_, key, members, _) => write!(f, "ZREM {:?} {:?}", key, members),
+            InnerCmd::ZScore(_, key, member) => write!(f, "ZSCORE {:?} {:?}", key, member),
+            InnerCmd::ZRange(_, key, start, stop, _) => {
+                write!(f, "ZRANGE {:?} {} {}", key, start, stop)
+            }
+            InnerCmd::ZRangeByScore(_, key, min, max, _) => {
+                write!(f, "ZRANGEBYSCORE {:?} {:?} {:?}", key, min, max)
+            }
+            InnerCmd::BfAdd(_, key, items, _, _) => write!(f, "BF.ADD {:?} {:?}", key, items),
+            InnerCmd::BfExists(_, key, item) => write!(f, "BF.EXISTS {:?} {:?}", key, item),
+            InnerCmd::CmsInitByDim(_, key, width, depth, _) => {
+                write!(f, "CMS.INITBYDIM {:?} {} {}", key, width, depth)
+            }
+            InnerCmd::CmsIncrBy(_, key, increments, _) => {
+                write!(f, "CMS.INCRBY {:?} {:?}", key, increments)
+            }
+            InnerCmd::CmsQuery(_, key, items) => write!(f, "CMS.QUERY {:?} {:?}", key, items),
+            InnerCmd::TopKReserve(_, key, k, width, depth, _) => {
+                write!(f, "TOPK.RESERVE {:?} {} {} {}", key, k, width, depth)
+            }
+            InnerCmd::TopKAdd(_, key, items, _) => write!(f, "TOPK.ADD {:?} {:?}", key, items),
+            InnerCmd::TopKList(_, key, _) => write!(f, "TOPK.LIST {:?}", key),
+            InnerCmd::Keys(_, pattern) => write!(f, "KEYS {:?}", pattern),
+            InnerCmd::DbSize(_) => write!(f, "DBSIZE"),
+            InnerCmd::Flush(_, db, lazy) => match db {
+                Some(db) => write!(f, "FLUSHDB {}{}", db, if *lazy { " ASYNC" } else { "" }),
+                None => write!(f, "FLUSHALL{}", if *lazy { " ASYNC" } else { "" }),
+            },
+            InnerCmd::FlushAll(_, op) => write!(f, "FLUSHALL {:?}", op),
+            InnerCmd::Select(db) => write!(f, "SELECT {}", db),
+            InnerCmd::SwapDb(_, first, second) => write!(f, "SWAPDB {} {}", first, second),
+            InnerCmd::BgSave => write!(f, "BGSAVE"),
+            InnerCmd::LastSave => write!(f, "LASTSAVE"),
+            InnerCmd::Compact => write!(f, "COMPACT"),
+            InnerCmd::Promote => write!(f, "PROMOTE"),
+            InnerCmd::Publish(_, channel, message) => {
+                write!(f, "PUBLISH {:?} {:?}", channel, message)
+            }
+            InnerCmd::Subscribe(channels) => write!(f, "SUBSCRIBE {:?}", channels),
+            InnerCmd::Unsubscribe(channels) => write!(f, "UNSUBSCRIBE {:?}", channels),
+            InnerCmd::PSubscribe(patterns) => write!(f, "PSUBSCRIBE {:?}", patterns),
+            InnerCmd::PUnsubscribe(patterns) => write!(f, "PUNSUBSCRIBE {:?}", patterns),
+            InnerCmd::SPublish(_, channel, message) => {
+                write!(f, "SPUBLISH {:?} {:?}", channel, message)
+            }
+            InnerCmd::SSubscribe(channels) => write!(f, "SSUBSCRIBE {:?}", channels),
+            InnerCmd::SUnsubscribe(channels) => write!(f, "SUNSUBSCRIBE {:?}", channels),
+            InnerCmd::Hello(protover, auth, setname) => write!(
+                f,
+                "HELLO {:?} {:?} {:?}",
+                protover,
+                auth.as_ref().map(|(user, _)| user),
+                setname
+            ),
+            InnerCmd::Info(section) => write!(f, "INFO {:?}", section),
+            InnerCmd::Cluster(op) => write!(f, "CLUSTER {:?}", op),
+            InnerCmd::Client(op) => write!(f, "CLIENT {:?}", op),
+            InnerCmd::Config(op) => write!(f, "CONFIG {:?}", op),
+            InnerCmd::Debug(op) => write!(f, "DEBUG {:?}", op),
+            InnerCmd::Auth(user, _) => write!(f, "AUTH {:?} <redacted>", user),
+            InnerCmd::Acl(op) => write!(f, "ACL {:?}", op),
+            InnerCmd::Proc(_, ProcOp::Load(name, wasm)) => {
+                write!(f, "PROC LOAD {} <{} bytes>", name, wasm.len())
+            }
+            InnerCmd::Proc(_, op) => write!(f, "PROC {:?}", op),
+            InnerCmd::Script(_, ScriptOp::Eval(script, call)) => {
+                write!(f, "EVAL {:?} {:?}", String::from_utf8_lossy(script), call)
+            }
+            InnerCmd::Script(_, ScriptOp::Load(script)) => {
+                write!(f, "SCRIPT LOAD {:?}", String::from_utf8_lossy(script))
+            }
+            InnerCmd::Script(_, op) => write!(f, "SCRIPT {:?}", op),
+            InnerCmd::Lock(_, op) => write!(f, "{:?}", op),
+            InnerCmd::Ephemeral(_, op) => write!(f, "{:?}", op),
+            InnerCmd::If(_, key, _, then, otherwise) => write!(
+                f,
+                "IF EXISTS {:?} THEN {:?} ELSE {:?}",
+                String::from_utf8_lossy(key),
+                then,
+                otherwise
+            ),
+            InnerCmd::Shutdown(mode) => write!(f, "SHUTDOWN {:?}", mode),
+            InnerCmd::Snapshot(op) => write!(f, "SNAPSHOT {:?}", op),
+            InnerCmd::SlowLog(op) => write!(f, "SLOWLOG {:?}", op),
+            InnerCmd::Raft(op) => write!(f, "RAFT {:?}", op),
+            InnerCmd::Export(pattern) => write!(f, "EXPORT {:?}", pattern),
+            InnerCmd::Monitor => write!(f, "MONITOR"),
+            InnerCmd::Admin(op) => write!(f, "ADMIN {:?}", op),
+            InnerCmd::Feature(_, op) => write!(f, "FEATURE {:?}", op),
+            InnerCmd::Wait(replicas, timeout) => write!(f, "WAIT {} {}", replicas, timeout),
+            InnerCmd::Ping => write!(f, "PING"),
+            InnerCmd::Plugin(_, name, args) => write!(f, "{} {:?}", name.to_uppercase(), args),
+            InnerCmd::ReadBarrier(_) => write!(f, "READ BARRIER"),
+            InnerCmd::Evict(_, keys) => write!(f, "EVICT {:?}", keys),
+        }
+    }
+}
+
+impl Syncable for InnerCmd {
+    type Output = RespValue;
+    type Context = NodeContext;
+
+    fn handle(
+        &self,
+        storage: &mut BitCask,
+        context: &NodeContext,
+    ) -> Result<RespValue, BitCaskError> {
+        // the keys of an ASYNC flush are deleted before anything written after it
+        context.flusher.wait();
+        let result = self.apply(storage, context);
+        context.hooks.after_apply(self, &result);
+        if let Some(analytics) = &context.analytics {
+            // the entry is counted as applied once it is handled
+            analytics.applied(context.raft_stats.applied_entries() + 1, &context.data_dir);
+        }
+        self.written(storage, context);
+        result
+    }
+
+    fn get_request_id(&self) -> RequestId {
+        match self {
+            InnerCmd::Get(id, _, _) => *id,
+            InnerCmd::MGetSnapshot(id, _) => *id,
+            InnerCmd::Put(id, _, _, _) => *id,
+            InnerCmd::Chunk(id, _, _, _) => *id,
+            InnerCmd::PutChunked(id, _, _, _) => *id,
+            InnerCmd::SetGet(id, _, _, _) => *id,
+            InnerCmd::GetRange(id, _, _, _) => *id,
+            InnerCmd::GetDel(id, _, _) => *id,
+            InnerCmd::Cas(id, _, _, _, _) => *id,
+            InnerCmd::GetEx(id, _, _, _) => *id,
+            InnerCmd::SetBit(id, _, _, _, _) => *id,
+            InnerCmd::GetBit(id, _, _) => *id,
+            InnerCmd::BitCount(id, _, _) => *id,
+            InnerCmd::BitPos(id, _, _, _) => *id,
+            InnerCmd::Del(id, _, _) => *id,
+            InnerCmd::Copy(id, _, _, _, _) => *id,
+            InnerCmd::Rename(id, _, _, _, _) => *id,
+            InnerCmd::Dump(id, _) => *id,
+            InnerCmd::Restore(id, _, _, _, _, _) => *id,
+            InnerCmd::Push(id, _, _, _, _) => *id,
+            InnerCmd::Pop(id, _, _, _, _) => *id,
+            InnerCmd::LRange(id, _, _, _) => *id,
+            InnerCmd::LLen(id, _) => *id,
+            InnerCmd::ZAdd(id, _, _, _) => *id,
+            InnerCmd::ZRem(id, _, _, _) => *id,
+            InnerCmd::ZScore(id, _, _) => *id,
+            InnerCmd::ZRange(id, _, _, _, _) => *id,
+            InnerCmd::ZRangeByScore(id, _, _, _, _) => *id,
+            InnerCmd::BfAdd(id, _, _, _, _) => *id,
+            InnerCmd::BfExists(id, _, _) => *id,
+            InnerCmd::CmsInitByDim(id, _, _, _, _) => *id,
+            InnerCmd::CmsIncrBy(id, _, _, _) => *id,
+            InnerCmd::CmsQuery(id, _, _) => *id,
+            InnerCmd::TopKReserve(id, _, _, _, _, _) => *id,
+            InnerCmd::TopKAdd(id, _, _, _) => *id,
+            InnerCmd::TopKList(id, _, _) => *id,
+            InnerCmd::Keys(id, _) => *id,
+            InnerCmd::DbSize(id) => *id,
+            InnerCmd::Flush(id, _, _) => *id,
+            InnerCmd::FlushAll(id, _) => *id,
+            InnerCmd::SwapDb(id, _, _) => *id,
+            InnerCmd::Publish(id, _, _) => *id,
+            InnerCmd::SPublish(id, _, _) => *id,
+            InnerCmd::Subscribe(_)
+            | InnerCmd::Unsubscribe(_)
+            | InnerCmd::PSubscribe(_)
+            | InnerCmd::PUnsubscribe(_)
+            | InnerCmd::SSubscribe(_)
+            | InnerCmd::SUnsubscribe(_) => panic!("Subscription commands do not have request id"),
+            InnerCmd::Hello(_, _, _) => panic!("Hello command does not have request id"),
+            InnerCmd::Select(_) => panic!("Select command does not have request id"),
+            InnerCmd::BgSave => panic!("BgSave command does not have request id"),
+            InnerCmd::LastSave => panic!("LastSave command does not have request id"),
+            InnerCmd::Compact => panic!("Compact command does not have request id"),
+            InnerCmd::Promote => panic!("Promote command does not have request id"),
+            InnerCmd::Info(_) => panic!("Info command does not have request id"),
+            InnerCmd::Cluster(_) => panic!("Cluster command does not have request id"),
+            InnerCmd::Client(_) => panic!("Client command does not have request id"),
+            InnerCmd::Config(_) => panic!("Config command does not have request id"),
+            InnerCmd::Debug(_) => panic!("Debug command does not have request id"),
+            InnerCmd::Auth(_, _) | InnerCmd::Acl(_) => {
+                panic!("Access control commands do not have request id")
+            }
+            InnerCmd::Proc(id, _) => *id,
+            InnerCmd::Shutdown(_) => panic!("Shutdown command does not have request id"),
+            InnerCmd::Snapshot(_) => panic!("Snapshot command does not have request id"),
+            InnerCmd::SlowLog(_) => panic!("SlowLog command does not have request id"),
+            InnerCmd::Raft(_) => panic!("Raft command does not have request id"),
+            InnerCmd::Export(_) => panic!("Export command does not have request id"),
+            InnerCmd::Monitor => panic!("Monitor command does not have request id"),
+            InnerCmd::Admin(_) => panic!("Admin command does not have request id"),
+            InnerCmd::Feature(id, _) => *id,
+            InnerCmd::Wait(_, _) => panic!("Wait command does not have request id"),
+            InnerCmd::Ping => panic!("Ping command does not have request id"),
+            InnerCmd::Plugin(id, _, _) => *id,
+            InnerCmd::ReadBarrier(id) => *id,
+            InnerCmd::Evict(id, _) => *id,
+            InnerCmd::Script(id, _) => *id,
+            InnerCmd::Lock(id, _) => *id,
+            InnerCmd::If(id, _, _, _, _) => *id,
+            InnerCmd::Ephemeral(id, _) => *id,
+        }
+    }
+
+    fn overwrites(&self) -> Option<&[u8]> {
+        match self {
+            InnerCmd::Put(_, key, _, None) => Some(key),
+            // NX and XX depend on the value replaced, KEEPTTL keeps its expiry
+            InnerCmd::Put(_, key, _, Some(option))
+                if !option.nx && !option.xx && !matches!(option.expiry, Some(Expiry::Keep)) =>
+            {
+                Some(key)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl InnerCmd {
+    pub(crate) fn family(&self) -> CommandFamily {
+        match self {
+            InnerCmd::Get(_, _, _)
+            | InnerCmd::GetRange(_, _, _, _)
+            | InnerCmd::GetBit(_, _, _)
+            | InnerCmd::BitCount(_, _, _)
+            | InnerCmd::BitPos(_, _, _, _)
+            | InnerCmd::LRange(_, _, _, _)
+            | InnerCmd::LLen(_, _)
+            | InnerCmd::ZScore(_, _, _)
+            | InnerCmd::ZRange(_, _, _, _, _)
+            | InnerCmd::ZRangeByScore(_, _, _, _, _)
+            | InnerCmd::BfExists(_, _, _)
+            | InnerCmd::CmsQuery(_, _, _)
+            | InnerCmd::TopKList(_, _, _)
+            | InnerCmd::Dump(_, _)
+            | InnerCmd::Keys(_, _)
+            | InnerCmd::DbSize(_)
+            | InnerCmd::MGetSnapshot(_, _)
+            | InnerCmd::Script(_, ScriptOp::Exists(_)) => CommandFamily::Read,
+            InnerCmd::Put(_, _, _, _)
+            | InnerCmd::SetGet(_, _, _, _)
+            | InnerCmd::Chunk(_, _, _, _)
+            | InnerCmd::PutChunked(_, _, _, _)
+            | InnerCmd::GetDel(_, _, _)
+            | InnerCmd::Cas(_, _, _, _, _)
+            | InnerCmd::GetEx(_, _, _, _)
+            | InnerCmd::SetBit(_, _, _, _, _)
+            | InnerCmd::Del(_, _, _)
+            | InnerCmd::Copy(_, _, _, _, _)
+            | InnerCmd::Rename(_, _, _, _, _)
+            | InnerCmd::Restore(_, _, _, _, _, _)
+            | InnerCmd::Flush(_, _, _)
+            | InnerCmd::FlushAll(_, _)
+            | InnerCmd::SwapDb(_, _, _)
+            | InnerCmd::Push(_, _, _, _, _)
+            | InnerCmd::Pop(_, _, _, _, _)
+            | InnerCmd::ZAdd(_, _, _, _)
+            | InnerCmd::ZRem(_, _, _, _)
+            | InnerCmd::BfAdd(_, _, _, _, _)
+            | InnerCmd::CmsInitByDim(_, _, _, _, _)
+            | InnerCmd::CmsIncrBy(_, _, _, _)
+            | InnerCmd::TopKReserve(_, _, _, _, _, _)
+            | InnerCmd::TopKAdd(_, _, _, _)
+            | InnerCmd::Publish(_, _, _)
+            | InnerCmd::SPublish(_, _, _)
+            | InnerCmd::Proc(_, _)
+            | InnerCmd::Script(_, _)
+            | InnerCmd::Lock(_, _)
+            | InnerCmd::If(_, _, _, _, _)
+            | InnerCmd::Ephemeral(_, _)
+            | InnerCmd::Feature(_, FeatureOp::Enable(_) | FeatureOp::Disable(_)) => {
+                CommandFamily::Write
+            }
+            InnerCmd::Subscribe(_)
+            | InnerCmd::Unsubscribe(_)
+            | InnerCmd::PSubscribe(_)
+            | InnerCmd::PUnsubscribe(_)
+            | InnerCmd::SSubscribe(_)
+            | InnerCmd::SUnsubscribe(_)
+            | InnerCmd::Hello(_, _, _)
+            | InnerCmd::Select(_)
+            | InnerCmd::BgSave
+            | InnerCmd::LastSave
+            | InnerCmd::Compact
+            | InnerCmd::Promote
+            | InnerCmd::Info(_)
+            | InnerCmd::Cluster(_)
+            | InnerCmd::Client(_)
+            | InnerCmd::Config(_)
+            | InnerCmd::Debug(_)
+            | InnerCmd::Auth(_, _)
+            | InnerCmd::Acl(_)
+            | InnerCmd::Shutdown(_)
+            | InnerCmd::Snapshot(_)
+            | InnerCmd::SlowLog(_)
+            | InnerCmd::Raft(_)
+            | InnerCmd::Export(_)
+            | InnerCmd::Monitor
+            | InnerCmd::Admin(_)
+            | InnerCmd::Feature(_, FeatureOp::List)
+            | InnerCmd::Wait(_, _)
+            | InnerCmd::Ping
+            | InnerCmd::ReadBarrier(_)
+            | InnerCmd::Evict(_, _) => CommandFamily::Other,
+            InnerCmd::Plugin(_, name, _) => match plugin::command(name) {
+                Some(command) if command.write => CommandFamily::Write,
+                _ => CommandFamily::Read,
+            },
+        }
+    }
+
+    /// The ACL category of the command, None for the commands every client may run
+    pub(crate) fn category(&self) -> Option<Category> {
+        match self {
+            InnerCmd::Auth(_, _)
+            | InnerCmd::Acl(AclOp::WhoAmI)
+            | InnerCmd::Cluster(ClusterOp::KeySlot(_))
+            | InnerCmd::Client(
+                ClientOp::Id
+                | ClientOp::GetName
+                | ClientOp::SetName(_)
+                | ClientOp::Info(None, _)
+                | ClientOp::Trace(_)
+                | ClientOp::Tracking(_)
+                | ClientOp::Watermark(_),
+            )
+            | InnerCmd::Hello(_, _, _)
+            | InnerCmd::Select(_)
+            | InnerCmd::Wait(_, _)
+            | InnerCmd::Ping => None,
+            InnerCmd::Publish(_, _, _)
+            | InnerCmd::Subscribe(_)
+            | InnerCmd::Unsubscribe(_)
+            | InnerCmd::PSubscribe(_)
+            | InnerCmd::PUnsubscribe(_)
+            | InnerCmd::SPublish(_, _, _)
+            | InnerCmd::SSubscribe(_)
+            | InnerCmd::SUnsubscribe(_) => Some(Category::PubSub),
+            // the views of the connection only read
+            InnerCmd::Snapshot(SnapshotOp::Open(_) | SnapshotOp::Close(_)) => Some(Category::Read),
+            InnerCmd::Info(_)
+            | InnerCmd::Cluster(_)
+            | InnerCmd::Client(_)
+            | InnerCmd::Config(_)
+            | InnerCmd::Debug(_)
+            | InnerCmd::Acl(_)
+            | InnerCmd::Shutdown(_)
+            | InnerCmd::Snapshot(_)
+            | InnerCmd::SlowLog(_)
+            | InnerCmd::Raft(_)
+            | InnerCmd::Export(_)
+            | InnerCmd::Monitor
+            | InnerCmd::Admin(_)
+            | InnerCmd::Feature(_, _)
+            | InnerCmd::Flush(_, _, _)
+            | InnerCmd::FlushAll(_, _)
+            | InnerCmd::SwapDb(_, _, _)
+            | InnerCmd::BgSave
+            | InnerCmd::LastSave
+            | InnerCmd::Compact
+            | InnerCmd::Promote => Some(Category::Admin),
+            _ => match self.family() {
+                CommandFamily::Read => Some(Category::Read),
+                _ => Some(Category::Write),
+            },
+        }
+    }
+
+    /// The name of the command as sent by the client, lowercase
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            InnerCmd::Get(_, _, _) => "get",
+            InnerCmd::MGetSnapshot(_, _) => "mgetsnapshot",
+            InnerCmd::Put(_, _, _, _)
+            | InnerCmd::SetGet(_, _, _, _)
+            | InnerCmd::PutChunked(_, _, _, _) => "set",
+            InnerCmd::Chunk(_, _, _, _) => "chunk",
+            InnerCmd::GetRange(_, _, _, _) => "getrange",
+            InnerCmd::GetDel(_, _, _) => "getdel",
+            InnerCmd::Cas(_, _, _, _, _) => "cas",
+            InnerCmd::GetEx(_, _, _, _) => "getex",
+            InnerCmd::SetBit(_, _, _, _, _) => "setbit",
+            InnerCmd::GetBit(_, _, _) => "getbit",
+            InnerCmd::BitCount(_, _, _) => "bitcount",
+            InnerCmd::BitPos(_, _, _, _) => "bitpos",
+            InnerCmd::Del(_, _, _) => "del",
+            InnerCmd::Copy(_, _, _, _, _) => "copy",
+            InnerCmd::Rename(_, _, _, false, _) => "rename",
+            InnerCmd::Rename(_, _, _, true, _) => "renamenx",
+            InnerCmd::Dump(_, _) => "dump",
+            InnerCmd::Restore(_, _, _, _, _, _) => "restore",
+            InnerCmd::Push(_, _, _, End::Left, _) => "lpush",
+            InnerCmd::Push(_, _, _, End::Right, _) => "rpush",
+            InnerCmd::Pop(_, _, _, End::Left, _) => "lpop",
+            InnerCmd::Pop(_, _, _, End::Right, _) => "rpop",
+            InnerCmd::LRange(_, _, _, _) => "lrange",
+            InnerCmd::LLen(_, _) => "llen",
+            InnerCmd::ZAdd(_, _, _, _) => "zadd",
+            InnerCmd::ZRem(_, _, _, _) => "zrem",
+            InnerCmd::ZScore(_, _, _) => "zscore",
+            InnerCmd::ZRange(_, _, _, _, _) => "zrange",
+            InnerCmd::ZRangeByScore(_, _, _, _, _) => "zrangebyscore",
+            InnerCmd::BfAdd(_, _, _, false, _) => "bf.add",
+            InnerCmd::BfAdd(_, _, _, true, _) => "bf.madd",
+            InnerCmd::BfExists(_, _, _) => "bf.exists",
+            InnerCmd::CmsInitByDim(_, _, _, _, _) => "cms.initbydim",
+            InnerCmd::CmsIncrBy(_, _, _, _) => "cms.incrby",
+            InnerCmd::CmsQuery(_, _, _) => "cms.query",
+            InnerCmd::TopKReserve(_, _, _, _, _, _) => "topk.reserve",
+            InnerCmd::TopKAdd(_, _, _, _) => "topk.add",
+            InnerCmd::TopKList(_, _, _) => "topk.list",
+            InnerCmd::Keys(_, _) => "keys",
+            InnerCmd::DbSize(_) => "dbsize",
+            InnerCmd::Flush(_, None, _) | InnerCmd::FlushAll(_, _) => "flushall",
+            InnerCmd::Flush(_, Some(_), _) => "flushdb",
+            InnerCmd::Select(_) => "select",
+            InnerCmd::SwapDb(_, _, _) => "swapdb",
+            InnerCmd::BgSave => "bgsave",
+            InnerCmd::LastSave => "lastsave",
+            InnerCmd::Compact => "compact",
+            InnerCmd::Promote => "promote",
+            InnerCmd::Publish(_, _, _) => "publish",
+            InnerCmd::Subscribe(_) => "subscribe",
+            InnerCmd::Unsubscribe(_) => "unsubscribe",
+            InnerCmd::PSubscribe(_) => "psubscribe",
+            InnerCmd::PUnsubscribe(_) => "punsubscribe",
+            InnerCmd::SPublish(_, _, _) => "spublish",
+            InnerCmd::SSubscribe(_) => "ssubscribe",
+            InnerCmd::SUnsubscribe(_) => "sunsubscribe",
+            InnerCmd::Hello(_, _, _) => "hello",
+            InnerCmd::Info(_) => "info",
+            InnerCmd::Cluster(_) => "cluster",
+            InnerCmd::Client(_) => "client",
+            InnerCmd::Config(_) => "config",
+            InnerCmd::Debug(_) => "debug",
+            InnerCmd::Auth(_, _) => "auth",
+            InnerCmd::Acl(_) => "acl",
+            InnerCmd::Proc(_, _) => "proc",
+            InnerCmd::Shutdown(_) => "shutdown",
+            InnerCmd::Snapshot(_) => "snapshot",
+            InnerCmd::SlowLog(_) => "slowlog",
+            InnerCmd::Raft(_) => "raft",
+            InnerCmd::Export(_) => "export",
+            InnerCmd::Monitor => "monitor",
+            InnerCmd::Admin(_) => "admin",
+            InnerCmd::Feature(_, _) => "feature",
+            InnerCmd::Wait(_, _) => "wait",
+            InnerCmd::Ping => "ping",
+            InnerCmd::Plugin(_, name, _) => plugin::command(name).map_or("plugin", |c| c.name),
+            InnerCmd::ReadBarrier(_) => "readbarrier",
+            InnerCmd::Evict(_, _) => "evict",
+            InnerCmd::Script(_, ScriptOp::Eval(_, _)) => "eval",
+            InnerCmd::Script(_, ScriptOp::EvalSha(_, _)) => "evalsha",
+            InnerCmd::Script(_, _) => "script",
+            InnerCmd::Lock(_, LockOp::Lock(_, _, _, _)) => "lock",
+            InnerCmd::Lock(_, LockOp::Unlock(_, _, _)) => "unlock",
+            InnerCmd::If(_, _, _, _, _) => "if",
+            InnerCmd::Ephemeral(_, EphemeralOp::Set(_, _, _, _)) => "set",
+            InnerCmd::Ephemeral(_, _) => "ephemeral",
+        }
+    }
+
+    /// Invalidate the keys the write changed in the caches, and count their size
+    fn written(&self, storage: &BitCask, context: &NodeContext) {
+        // invalidate after the write so no reader can cache the previous value again
+        for key in self.written_keys() {
+            if let Some(read_cache) = &context.read_cache {
+                read_cache.invalidate(key);
+            }
+            if let Some(negative_cache) = &context.negative_cache {
+                negative_cache.invalidate(key);
+            }
+            if let Some(memory) = &context.memory {
+                memory.written(storage, key);
+            }
+            context.broker.invalidate(key);
+        }
+    }
+
+    /// Apply a replicated write to the storage
+    fn apply(
+        &self,
+        storage: &mut BitCask,
+        context: &NodeContext,
+    ) -> Result<RespValue, BitCaskError> {
+        // the keys and values under secure delete are kept out of the logs
+        let hidden = context.secure_delete.hides(self);
+        match self {
+            InnerCmd::Put(_, key, value, option) => {
+                set(storage, key, value, option.as_ref())?;
+                if !hidden {
+                    info!("SET {:?} -> {:?}", key, value);
+                }
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::Chunk(_, id, index, bytes) => {
+                chunk::stage(storage, id, *index, bytes)?;
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::PutChunked(id, key, chunks, option) => {
+                // the chunks are gone even when NX or XX keeps the value from being set
+                let value = chunk::assemble(storage, id, *chunks)?;
+                set(storage, key, &value, option.as_ref())?;
+                if !hidden {
+                    info!("SET {:?} -> {} bytes from {} chunks", key, value.len(), chunks);
+                }
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::SetGet(_, key, value, option) => {
+                // always given with GET, entries from before expiry have none
+                let now = option.as_ref().map_or(0, |option| option.now);
+                let old = match storage.get(key).filter(|raw| !value::expired(raw, now)) {
+                    Some(raw) => Some(value::string(&raw)?.into_owned()),
+                    None => None,
+                };
+                match set(storage, key, value, option.as_ref()) {
+                    // the NX/XX condition failing still replies with the old value
+                    Ok(()) | Err(BitCaskError::KeyExists) | Err(BitCaskError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+                if !hidden {
+                    info!("SET {:?} -> {:?} GET", key, value);
+                }
+                Ok(RespValue::BulkString(old.map(Bytes::from)))
+            }
+            InnerCmd::GetDel(_, key, now) => {
+                let Some(raw) = storage.get(key).filter(|raw| !value::expired(raw, *now)) else {
+                    return Ok(RespValue::BulkString(None));
+                };
+                // a value of another type is left in place
+                let old = value::string(&raw)?.into_owned();
+                storage.delete(key)?;
+                if !hidden {
+                    info!("GETDEL {:?}", key);
+                }
+                Ok(RespValue::BulkString(Some(Bytes::from(old))))
+            }
+            InnerCmd::Cas(_, key, expected, new, now) => {
+                let current = match storage.get(key).filter(|raw| !value::expired(raw, *now)) {
+                    // a value of another type is left in place
+                    Some(raw) => Some(value::string(&raw)?.into_owned()),
+                    None => None,
+                };
+                if current.as_ref() != Some(expected) {
+                    if !hidden {
+                        info!("CAS {:?} -> not swapped", key);
+                    }
+                    return Ok(RespValue::Integer(0));
+                }
+                // as SET, the expiry of the old value goes with it
+                set(storage, key, new, None)?;
+                if !hidden {
+                    info!("CAS {:?} -> {:?}", key, new);
+                }
+                Ok(RespValue::Integer(1))
+            }
+            InnerCmd::GetEx(_, key, expiry, now) => {
+                let Some(raw) = storage.get(key).filter(|raw| !value::expired(raw, *now)) else {
+                    return Ok(RespValue::BulkString(None));
+                };
+                let payload = value::string(&raw)?.into_owned();
+                let deadline = match expiry {
+                    Some(Expiry::At(deadline)) => Some(Some(*deadline)),
+                    Some(Expiry::Persist) if value::deadline(&raw).is_some() => Some(None),
+                    _ => None,
+                };
+                if let Some(deadline) = deadline {
+                    // a compressed value stays so
+                    let raw = value::with_deadline(&raw, ValueType::String, deadline)?;
+                    storage.put(key, &raw)?;
+                    if !hidden {
+                        info!("GETEX {:?} -> expires at {:?}", key, deadline);
+                    }
+                }
+                Ok(RespValue::BulkString(Some(Bytes::from(payload))))
+            }
+            InnerCmd::SetBit(_, key, offset, bit, now) => {
+                let old = bitmap::set(storage, key, *offset, *bit, *now)?;
+                if !hidden {
+                    info!("SETBIT {:?} {} {} -> {}", key, offset, *bit as u8, old);
+                }
+                Ok(RespValue::Integer(old as i64))
+            }
+            InnerCmd::Del(_, keys, now) => {
+                let mut deleted = 0;
+                for key in keys {
+                    // deleting writes a tombstone, even for a key that is not there
+                    if let Some(raw) = storage.get(key) {
+                        storage.delete(key)?;
+                        // an expired value is deleted all the same, but was no longer the key's
+                        if !value::expired(&raw, *now) {
+                            deleted += 1;
+                        }
+                    }
+                }
+                if !hidden {
+                    info!("DEL {:?} -> {}", keys, deleted);
+                }
+                Ok(RespValue::Integer(deleted))
+            }
+            InnerCmd::Copy(_, source, destination, replace, now) => {
+                if source == destination {
+                    let e = anyhow::anyhow!("source and destination objects are the same");
+                    return Err(e.into());
+                }
+                let live = |raw: &Vec<u8>| !value::expired(raw, *now);
+                let Some(raw) = storage.get(source).filter(live) else {
+                    return Ok(RespValue::Integer(0));
+                };
+                if !replace && storage.get(destination).is_some_and(|raw| live(&raw)) {
+                    return Ok(RespValue::Integer(0));
+                }
+                // copied as stored, with its type and expiry
+                storage.put(destination, &raw)?;
+                if !hidden {
+                    info!("COPY {:?} -> {:?}", source, destination);
+                }
+                Ok(RespValue::Integer(1))
+            }
+            InnerCmd::Rename(_, source, destination, nx, now) => {
+                let Some(raw) = storage.get(source).filter(|raw| !value::expired(raw, *now)) else {
+                    return Err(anyhow::anyhow!("no such key").into());
+                };
+                // RENAMENX onto the source itself finds the destination taken
+                if *nx && storage.get(destination).is_some_and(|raw| !value::expired(&raw, *now)) {
+                    return Ok(RespValue::Integer(0));
+                }
+                if source != destination {
+                    storage.put(destination, &raw)?;
+                    storage.delete(source)?;
+                }
+                if !hidden {
+                    info!("RENAME {:?} -> {:?}", source, destination);
+                }
+                if *nx {
+                    Ok(RespValue::Integer(1))
+                } else {
+                    Ok(RespValue::SimpleString("OK".to_string()))
                 }
             }
-            InnerCmd::Del(_, key) => write!(f, "DEL {:?}", key),
-            InnerCmd::Ping => write!(f, "PING"),
+            InnerCmd::Restore(_, key, serialized, deadline, replace, now) => {
+                let (value_type, payload) = dump::deserialize(serialized)?;
+                let old = storage.get(key);
+                if !replace && old.as_ref().is_some_and(|raw| !value::expired(raw, *now)) {
+                    return Err(dump::busy_key());
+                }
+                // a value restored expired replaces the key with nothing
+                if deadline.is_some_and(|deadline| deadline <= *now) {
+                    if old.is_some() {
+                        storage.delete(key)?;
+                    }
+                } else {
+                    let raw = value::encode_expiring(value_type, payload, *deadline);
+                    storage.put(key, &raw)?;
+                }
+                if !hidden {
+                    info!("RESTORE {:?} -> {:?} expiring at {:?}", key, value_type, deadline);
+                }
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::Push(_, key, values, end, now) => {
+                let len = list::push(storage, key, values, *end, *now)?;
+                if !hidden {
+                    info!("PUSH {:?} {:?} -> len {}", end, key, len);
+                }
+                Ok(RespValue::Integer(len as i64))
+            }
+            InnerCmd::Pop(_, key, count, end, now) => {
+                let popped = list::pop(storage, key, count.unwrap_or(1), *end, *now)?;
+                if !hidden {
+                    info!("POP {:?} {:?} -> {:?}", end, key, popped);
+                }
+                match (popped, count) {
+                    (Some(mut popped), None) => Ok(RespValue::BulkString(popped.pop().map(Bytes::from))),
+                    (Some(popped), Some(_)) => Ok(RespValue::Array(
+                        popped
+                            .into_iter()
+                            .map(|v| RespValue::BulkString(Some(v.into())))
+                            .collect(),
+                    )),
+                    (None, None) => Ok(RespValue::BulkString(None)),
+                    (None, Some(_)) => Ok(RespValue::NullArray),
+                }
+            }
+            InnerCmd::ZAdd(_, key, members, now) => {
+                let added = zset::add(storage, key, members, *now)?;
+                if !hidden {
+                    info!("ZADD {:?} -> added {}", key, added);
+                }
+                Ok(RespValue::Integer(added as i64))
+            }
+            InnerCmd::ZRem(_, key, members, now) => {
+                let removed = zset::remove(storage, key, members, *now)?;
+                if !hidden {
+                    info!("ZREM {:?} -> removed {}", key, removed);
+                }
+                Ok(RespValue::Integer(removed as i64))
+            }
+            InnerCmd::BfAdd(_, key, items, multi, now) => {
+                let added = bloom::add(storage, key, items, *now)?;
+                if !hidden {
+                    info!("BF.ADD {:?} -> {:?}", key, added);
+                }
+                let mut replies: Vec<RespValue> = added
+                    .into_iter()
+                    .map(|added| RespValue::Integer(added as i64))
+                    .collect();
+                if *multi {
+                    Ok(RespValue::Array(replies))
+                } else {
+                    Ok(replies.remove(0))
+                }
+            }
+            InnerCmd::CmsInitByDim(_, key, width, depth, now) => {
+                sketch::cms_init(storage, key, *width, *depth, *now)?;
+                if !hidden {
+                    info!("CMS.INITBYDIM {:?} {} {}", key, width, depth);
+                }
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::CmsIncrBy(_, key, increments, now) => {
+                let estimates = sketch::cms_incr_by(storage, key, increments, *now)?;
+                if !hidden {
+                    info!("CMS.INCRBY {:?} -> {:?}", key, estimates);
+                }
+                Ok(integer_array(estimates))
+            }
+            InnerCmd::TopKReserve(_, key, k, width, depth, now) => {
+                sketch::topk_reserve(storage, key, *k, *width, *depth, *now)?;
+                if !hidden {
+                    info!("TOPK.RESERVE {:?} {} {} {}", key, k, width, depth);
+                }
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::TopKAdd(_, key, items, now) => {
+                let expelled = sketch::topk_add(storage, key, items, *now)?;
+                if !hidden {
+                    info!("TOPK.ADD {:?} -> expelled {:?}", key, expelled);
+                }
+                Ok(RespValue::Array(
+                    expelled
+                        .into_iter()
+                        .map(|item| RespValue::BulkString(item.map(Bytes::from)))
+                        .collect(),
+                ))
+            }
+            InnerCmd::Publish(_, channel, message) => {
+                // every node delivers to its own subscribers, the reply counts the local ones
+                let receivers = context.broker.publish(channel, message);
+                Ok(RespValue::Integer(receivers as i64))
+            }
+            InnerCmd::SPublish(_, channel, message) => {
+                // the nodes of the group owning the slot deliver to their own subscribers
+                let receivers = context.broker.spublish(channel, message);
+                Ok(RespValue::Integer(receivers as i64))
+            }
+            InnerCmd::Proc(_, op) => {
+                let reply = context.procedures.apply(storage, op)?;
+                if !hidden {
+                    info!("PROC {:?} -> {:?}", op, reply);
+                }
+                Ok(reply)
+            }
+            InnerCmd::Script(_, op) => {
+                let reply = script::apply(storage, context, op)?;
+                if !hidden {
+                    info!("{:?} -> {:?}", self, reply);
+                }
+                Ok(reply)
+            }
+            InnerCmd::Ephemeral(_, op) => {
+                let reply = ephemeral::apply(storage, op)?;
+                if !hidden {
+                    info!("{:?} -> {:?}", op, reply);
+                }
+                Ok(reply)
+            }
+            InnerCmd::Lock(_, op) => {
+                let reply = context.locks.apply(storage, &context.broker, op)?;
+                if !hidden {
+                    info!("{:?} -> {:?}", op, reply);
+                }
+                Ok(reply)
+            }
+            InnerCmd::If(_, key, now, then, otherwise) => {
+                let exists = storage.get(key).is_some_and(|raw| !value::expired(&raw, *now));
+                let branch = match exists {
+                    true => then,
+                    false => match otherwise {
+                        Some(otherwise) => otherwise,
+                        None => return Ok(RespValue::BulkString(None)),
+                    },
+                };
+                let reply = branch.apply(storage, context);
+                branch.written(storage, context);
+                reply
+            }
+            InnerCmd::Feature(_, op) => {
+                context.features.apply(storage, op)?;
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::Flush(_, db, lazy) => {
+                let read_cache = context.read_cache.clone();
+                context
+                    .flusher
+                    .flush(storage, &context.data_dir, read_cache, *db, *lazy)?;
+                // the keys of an ASYNC flush count as deleted already
+                if let Some(memory) = &context.memory {
+                    memory.flushed(*db);
+                }
+                context.broker.invalidate_all();
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::FlushAll(_, FlushAllOp::Fenced(lazy, epoch)) => {
+                let read_cache = context.read_cache.clone();
+                let epoch = context.flusher.flush_all(
+                    storage,
+                    &context.data_dir,
+                    read_cache,
+                    *lazy,
+                    *epoch,
+                )?;
+                if let Some(memory) = &context.memory {
+                    memory.flushed(None);
+                }
+                context.broker.invalidate_all();
+                context
+                    .broker
+                    .publish(flush::EPOCH_CHANNEL, epoch.to_string().as_bytes());
+                info!("FLUSHALL -> flush epoch {}", epoch);
+                Ok(RespValue::Integer(epoch as i64))
+            }
+            InnerCmd::SwapDb(_, first, second) => {
+                let caches = (context.read_cache.as_deref(), context.negative_cache.as_deref());
+                database::swap(storage, &context.data_dir, *first, *second, caches)?;
+                if let Some(memory) = &context.memory {
+                    memory.swapped(*first, *second);
+                }
+                context.broker.invalidate_all();
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            InnerCmd::Plugin(_, name, args) => {
+                let reply = plugin::execute(name, args, plugin::Storage::Write(storage))?;
+                if !hidden {
+                    info!("{} {:?} -> {:?}", name.to_uppercase(), args, reply);
+                }
+                Ok(reply)
+            }
+            // the writes committed before it are applied by now
+            InnerCmd::ReadBarrier(_) => Ok(RespValue::SimpleString("OK".to_string())),
+            InnerCmd::Evict(_, keys) => {
+                let evicted = memory::evict(storage, keys)?;
+                if let Some(memory) = &context.memory {
+                    memory.evicted(evicted);
+                }
+                if !hidden {
+                    info!("EVICT {:?} -> {}", keys, evicted);
+                }
+                Ok(RespValue::Integer(evicted as i64))
+            }
+            _ => panic!("Command should not be handled by sync layer"),
         }
     }
-}
 
-impl Syncable for InnerCmd {
-    fn handle(&self, storage: &mut BitCask) -> Result<(), BitCaskError> {
+    /// The keys modified by a write command, several for DEL and RENAME
+    pub(crate) fn written_keys(&self) -> Vec<&Vec<u8>> {
         match self {
-            InnerCmd::Put(_, key, value, option) => {
-                let option = option.clone();
-                let option = option.map(|op| op.into());
-                storage.put_with_option(key, value, option)?;
-                info!("SET {:?} -> {:?}", key, value);
-                Ok(())
+            InnerCmd::Del(_, keys, _)
+            | InnerCmd::Evict(_, keys)
+            | InnerCmd::Ephemeral(_, EphemeralOp::Renew(keys, _, _, _))
+            | InnerCmd::Ephemeral(_, EphemeralOp::Release(keys, _)) => keys.iter().collect(),
+            InnerCmd::Rename(_, source, destination, _, _) => vec![source, destination],
+            InnerCmd::Copy(_, _, destination, _, _) => vec![destination],
+            InnerCmd::Restore(_, key, _, _, _, _) => vec![key],
+            InnerCmd::Put(_, key, _, _)
+            | InnerCmd::SetGet(_, key, _, _)
+            | InnerCmd::PutChunked(_, key, _, _)
+            | InnerCmd::GetDel(_, key, _)
+            | InnerCmd::Cas(_, key, _, _, _)
+            | InnerCmd::GetEx(_, key, _, _)
+            | InnerCmd::SetBit(_, key, _, _, _)
+            | InnerCmd::Push(_, key, _, _, _)
+            | InnerCmd::Pop(_, key, _, _, _)
+            | InnerCmd::ZAdd(_, key, _, _)
+            | InnerCmd::ZRem(_, key, _, _)
+            | InnerCmd::BfAdd(_, key, _, _, _)
+            | InnerCmd::CmsInitByDim(_, key, _, _, _)
+            | InnerCmd::CmsIncrBy(_, key, _, _)
+            | InnerCmd::TopKReserve(_, key, _, _, _, _)
+            | InnerCmd::TopKAdd(_, key, _, _)
+            | InnerCmd::Ephemeral(_, EphemeralOp::Set(key, _, _, _)) => vec![key],
+            InnerCmd::Proc(_, op) => op.key().into_iter().collect(),
+            // those the script declares, it may write others
+            InnerCmd::Script(_, op) => op.keys(),
+            InnerCmd::Plugin(_, name, args) => plugin::command(name)
+                .filter(|command| command.write)
+                .and_then(|command| command.key(args))
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Every key read or written by the command
+    pub(crate) fn keys(&self) -> Vec<&Vec<u8>> {
+        match self {
+            InnerCmd::Del(_, keys, _) | InnerCmd::MGetSnapshot(_, keys) => keys.iter().collect(),
+            InnerCmd::Copy(_, source, destination, _, _)
+            | InnerCmd::Rename(_, source, destination, _, _) => vec![source, destination],
+            InnerCmd::If(_, key, _, then, otherwise) => std::iter::once(key)
+                .chain(then.keys())
+                .chain(otherwise.iter().flat_map(|otherwise| otherwise.keys()))
+                .collect(),
+            _ => self.key().into_iter().collect(),
+        }
+    }
+
+    /// The key read or written by the command, the first one for DEL and the source for COPY
+    /// and RENAME
+    pub(crate) fn key(&self) -> Option<&Vec<u8>> {
+        match self {
+            InnerCmd::Copy(_, key, _, _, _)
+            | InnerCmd::Get(_, key, _)
+            | InnerCmd::GetRange(_, key, _, _)
+            | InnerCmd::GetBit(_, key, _)
+            | InnerCmd::BitCount(_, key, _)
+            | InnerCmd::BitPos(_, key, _, _)
+            | InnerCmd::LRange(_, key, _, _)
+            | InnerCmd::LLen(_, key)
+            | InnerCmd::ZScore(_, key, _)
+            | InnerCmd::ZRange(_, key, _, _, _)
+            | InnerCmd::ZRangeByScore(_, key, _, _, _)
+            | InnerCmd::BfExists(_, key, _)
+            | InnerCmd::CmsQuery(_, key, _)
+            | InnerCmd::TopKList(_, key, _)
+            | InnerCmd::Dump(_, key) => Some(key),
+            InnerCmd::Lock(_, op) => Some(op.key()),
+            InnerCmd::If(_, key, _, _, _) => Some(key),
+            InnerCmd::MGetSnapshot(_, keys) => keys.first(),
+            InnerCmd::Plugin(_, name, args) => {
+                plugin::command(name).and_then(|command| command.key(args))
             }
-            InnerCmd::Del(_, key) => {
-                storage.delete(key)?;
-                info!("DEL {:?}", key);
-                Ok(())
+            _ => self.written_keys().into_iter().next(),
+        }
+    }
+
+    /// Every key read or written by the command, to move them to a database
+    fn keys_mut(&mut self) -> Vec<&mut Vec<u8>> {
+        match self {
+            InnerCmd::Del(_, keys, _) | InnerCmd::MGetSnapshot(_, keys) => keys.iter_mut().collect(),
+            InnerCmd::Copy(_, source, destination, _, _)
+            | InnerCmd::Rename(_, source, destination, _, _) => vec![source, destination],
+            InnerCmd::Get(_, key, _)
+            | InnerCmd::Put(_, key, _, _)
+            | InnerCmd::SetGet(_, key, _, _)
+            | InnerCmd::PutChunked(_, key, _, _)
+            | InnerCmd::GetRange(_, key, _, _)
+            | InnerCmd::GetDel(_, key, _)
+            | InnerCmd::Cas(_, key, _, _, _)
+            | InnerCmd::GetEx(_, key, _, _)
+            | InnerCmd::SetBit(_, key, _, _, _)
+            | InnerCmd::GetBit(_, key, _)
+            | InnerCmd::BitCount(_, key, _)
+            | InnerCmd::BitPos(_, key, _, _)
+            | InnerCmd::Push(_, key, _, _, _)
+            | InnerCmd::Pop(_, key, _, _, _)
+            | InnerCmd::LRange(_, key, _, _)
+            | InnerCmd::LLen(_, key)
+            | InnerCmd::ZAdd(_, key, _, _)
+            | InnerCmd::ZRem(_, key, _, _)
+            | InnerCmd::ZScore(_, key, _)
+            | InnerCmd::ZRange(_, key, _, _, _)
+            | InnerCmd::ZRangeByScore(_, key, _, _, _)
+            | InnerCmd::BfAdd(_, key, _, _, _)
+            | InnerCmd::BfExists(_, key, _)
+            | InnerCmd::CmsInitByDim(_, key, _, _, _)
+            | InnerCmd::CmsIncrBy(_, key, _, _)
+            | InnerCmd::CmsQuery(_, key, _)
+            | InnerCmd::TopKReserve(_, key, _, _, _, _)
+            | InnerCmd::TopKAdd(_, key, _, _)
+            | InnerCmd::TopKList(_, key, _)
+            | InnerCmd::Dump(_, key)
+            | InnerCmd::Restore(_, key, _, _, _, _)
+            | InnerCmd::Ephemeral(_, EphemeralOp::Set(key, _, _, _)) => vec![key],
+            InnerCmd::Proc(_, op) => op.key_mut().into_iter().collect(),
+            InnerCmd::Script(_, op) => op.keys_mut(),
+            InnerCmd::Lock(_, op) => vec![op.key_mut()],
+            InnerCmd::If(_, key, _, _, _) => vec![key],
+            InnerCmd::Plugin(_, name, args) => plugin::command(name)
+                .and_then(|command| command.key_mut(args))
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Run the command in database `db`: its keys become those stored for the database, and
+    /// FLUSHDB flushes it. KEYS, DBSIZE and EXPORT are given the database as they are served.
+    pub(crate) fn select(&mut self, db: u32) {
+        if let InnerCmd::Flush(_, Some(flushed), _) = self {
+            *flushed = db;
+        }
+        if let InnerCmd::Script(_, op) = self {
+            op.select(db);
+        }
+        if let InnerCmd::If(_, _, _, then, otherwise) = self {
+            then.select(db);
+            if let Some(otherwise) = otherwise {
+                otherwise.select(db);
             }
-            _ => panic!("Command should not be handled by sync layer"),
+        }
+        if db == 0 {
+            return;
+        }
+        for key in self.keys_mut() {
+            *key = database::key(db, key);
         }
     }
 
-    fn get_request_id(&self) -> RequestId {
+    /// The experimental subsystem the command runs, which it needs enabled
+    pub(crate) fn feature(&self) -> Option<Feature> {
         match self {
-            InnerCmd::Get(id, _) => *id,
-            InnerCmd::Put(id, _, _, _) => *id,
-            InnerCmd::Del(id, _) => *id,
-            InnerCmd::Ping => panic!("Ping command does not have request id"),
+            InnerCmd::Hello(Some(3), _, _) => Some(Feature::Resp3),
+            // leaving shard channels is fine once the feature is disabled
+            InnerCmd::SPublish(_, _, _) | InnerCmd::SSubscribe(_) => Some(Feature::ShardedPubSub),
+            _ => None,
         }
     }
-}
 
-impl InnerCmd {
+    /// Whether the command may be sent by a client that has active subscriptions
+    pub(crate) fn allowed_in_subscriber_mode(&self) -> bool {
+        matches!(
+            self,
+            InnerCmd::Subscribe(_)
+                | InnerCmd::Unsubscribe(_)
+                | InnerCmd::PSubscribe(_)
+                | InnerCmd::PUnsubscribe(_)
+                | InnerCmd::SSubscribe(_)
+                | InnerCmd::SUnsubscribe(_)
+                | InnerCmd::Ping
+        )
+    }
+
+    /// Evaluate a read command against the local storage.
+    /// Reads are not synchronized with peers, the reply reflects what this replica has applied.
+    pub(crate) fn read(&self, storage: &BitCask) -> Result<RespValue, BitCaskError> {
+        match self {
+            InnerCmd::Dump(_, key) => {
+                let Some(raw) = storage.get(key).filter(|raw| !value::expired(raw, value::now()))
+                else {
+                    return Ok(RespValue::BulkString(None));
+                };
+                Ok(RespValue::BulkString(Some(Bytes::from(dump::serialize(&raw)?))))
+            }
+            InnerCmd::GetRange(_, key, start, end) => {
+                let raw = storage
+                    .get(key)
+                    .filter(|raw| !value::expired(raw, value::now()))
+                    .unwrap_or_default();
+                let value = value::string(&raw)?;
+                let range = string_range(value.len(), *start, *end);
+                Ok(RespValue::BulkString(Some(Bytes::copy_from_slice(&value[range]))))
+            }
+            InnerCmd::GetBit(_, key, _)
+            | InnerCmd::BitCount(_, key, _)
+            | InnerCmd::BitPos(_, key, _, _) => {
+                let raw = storage
+                    .get(key)
+                    .filter(|raw| !value::expired(raw, value::now()))
+                    .unwrap_or_default();
+                let bytes = value::string(&raw)?;
+                Ok(RespValue::Integer(match self {
+                    InnerCmd::GetBit(_, _, offset) => bitmap::get(&bytes, *offset) as i64,
+                    InnerCmd::BitCount(_, _, range) => bitmap::count(&bytes, *range) as i64,
+                    InnerCmd::BitPos(_, _, bit, range) => bitmap::position(&bytes, *bit, *range),
+                    _ => unreachable!(),
+                }))
+            }
+            InnerCmd::LRange(_, key, start, stop) => {
+                let values = list::range(storage, key, *start, *stop, value::now())?;
+                Ok(RespValue::Array(
+                    values
+                        .into_iter()
+                        .map(|v| RespValue::BulkString(Some(v.into())))
+                        .collect(),
+                ))
+            }
+            InnerCmd::LLen(_, key) => Ok(RespValue::Integer(list::len(storage, key, value::now())? as i64)),
+            InnerCmd::ZScore(_, key, member) => Ok(match zset::score(storage, key, member, value::now())? {
+                Some(score) => RespValue::Double(score),
+                None => RespValue::BulkString(None),
+            }),
+            InnerCmd::ZRange(_, key, start, stop, with_scores) => Ok(zset_reply(
+                zset::range(storage, key, *start, *stop, value::now())?,
+                *with_scores,
+            )),
+            InnerCmd::ZRangeByScore(_, key, min, max, with_scores) => Ok(zset_reply(
+                zset::range_by_score(storage, key, *min, *max, value::now())?,
+                *with_scores,
+            )),
+            InnerCmd::BfExists(_, key, item) => Ok(RespValue::Integer(
+                bloom::exists(storage, key, item, value::now())? as i64,
+            )),
+            InnerCmd::CmsQuery(_, key, items) => {
+                Ok(integer_array(sketch::cms_query(storage, key, items, value::now())?))
+            }
+            InnerCmd::TopKList(_, key, with_count) => {
+                let mut reply = Vec::new();
+                for (count, item) in sketch::topk_list(storage, key, value::now())? {
+                    reply.push(RespValue::BulkString(Some(item.into())));
+                    if *with_count {
+                        reply.push(RespValue::Integer(count as i64));
+                    }
+                }
+                Ok(RespValue::Array(reply))
+            }
+            InnerCmd::Plugin(_, name, args) => {
+                plugin::execute(name, args, plugin::Storage::Read(storage))
+            }
+            InnerCmd::Script(_, ScriptOp::Exists(digests)) => Ok(script::exists(storage, digests)),
+            InnerCmd::MGetSnapshot(_, keys) => {
+                // no entry is applied while the keys are read, they are as of the index recorded
+                let _writing = keyspace::writing();
+                let index = sync_layer::applied_index(storage);
+                let now = value::now();
+                let values = keys
+                    .iter()
+                    .map(|key| {
+                        // nil for a value of another type, as MGET
+                        let value = storage
+                            .get(key)
+                            .filter(|raw| !value::expired(raw, now))
+                            .and_then(|raw| value::string_bytes(raw).ok());
+                        RespValue::BulkString(value)
+                    })
+                    .collect();
+                Ok(RespValue::Array(vec![
+                    RespValue::Integer(index as i64),
+                    RespValue::Array(values),
+                ]))
+            }
+            _ => panic!("Command is not a local read"),
+        }
+    }
+
+    /// Run a command a script calls, in the entry of the script and in its database: reads see
+    /// the writes of the script before them, and writes are applied right away
+    pub(crate) fn run_in_script(
+        mut self,
+        storage: &mut BitCask,
+        context: &NodeContext,
+        db: u32,
+    ) -> Result<RespValue, BitCaskError> {
+        self.select(db);
+        match &self {
+            InnerCmd::Get(_, key, _) => {
+                let Some(raw) = storage.get(key).filter(|raw| !value::expired(raw, value::now()))
+                else {
+                    return Ok(RespValue::BulkString(None));
+                };
+                Ok(RespValue::BulkString(Some(value::string_bytes(raw)?)))
+            }
+            // served from snapshots of the data files, which lag the writes of the script
+            InnerCmd::Keys(_, _)
+            | InnerCmd::DbSize(_)
+            | InnerCmd::Chunk(_, _, _, _)
+            | InnerCmd::PutChunked(_, _, _, _)
+            | InnerCmd::Proc(_, _)
+            | InnerCmd::Script(_, _) => Err(BitCaskError::UnexpectedError(anyhow::anyhow!(
+                "'{}' is not allowed from scripts",
+                self.name()
+            ))),
+            _ if self.category() == Some(Category::Read) => self.read(storage),
+            _ if self.family() == CommandFamily::Write
+                && matches!(self.category(), Some(Category::Write | Category::PubSub)) =>
+            {
+                let reply = self.apply(storage, context);
+                self.written(storage, context);
+                match reply {
+                    // due to NX or XX option
+                    Err(BitCaskError::KeyExists) | Err(BitCaskError::KeyNotFound) => {
+                        Ok(RespValue::BulkString(None))
+                    }
+                    reply => reply,
+                }
+            }
+            _ => Err(BitCaskError::UnexpectedError(anyhow::anyhow!(
+                "'{}' is not allowed from scripts",
+                self.name()
+            ))),
+        }
+    }
+
+    /// Whether the write takes no memory, so that it is admitted over maxmemory
+    pub(crate) fn frees_memory(&self) -> bool {
+        matches!(
+            self,
+            InnerCmd::Del(_, _, _)
+                | InnerCmd::GetDel(_, _, _)
+                | InnerCmd::Pop(_, _, _, _, _)
+                | InnerCmd::ZRem(_, _, _, _)
+                | InnerCmd::Rename(_, _, _, _, _)
+                | InnerCmd::Flush(_, _, _)
+                | InnerCmd::FlushAll(_, _)
+                | InnerCmd::SwapDb(_, _, _)
+                | InnerCmd::Publish(_, _, _)
+                | InnerCmd::SPublish(_, _, _)
+                | InnerCmd::Evict(_, _)
+                | InnerCmd::Lock(_, LockOp::Unlock(_, _, _))
+                | InnerCmd::Ephemeral(_, EphemeralOp::Release(_, _))
+        ) || matches!(
+            self,
+            InnerCmd::If(_, _, _, then, otherwise)
+                if then.frees_memory() && otherwise.as_ref().is_none_or(|o| o.frees_memory())
+        )
+    }
+
+    /// Whether the write would store the value the local replica already holds, for SET
+    /// IFCHANGED not to replicate it. Not knowing the leader, the node compares with what it
+    /// applied so far.
+    pub(crate) fn unchanged(&self, storage: &BitCask) -> bool {
+        let InnerCmd::Put(_, key, value, option) = self else {
+            return false;
+        };
+        let now = option.as_ref().map_or_else(value::now, |option| option.now);
+        let Some(old) = storage.get(key).filter(|raw| !value::expired(raw, now)) else {
+            return false;
+        };
+        let deadline = match option.as_ref().and_then(|option| option.expiry) {
+            Some(Expiry::At(deadline)) => Some(deadline),
+            Some(Expiry::Keep) => value::deadline(&old),
+            Some(Expiry::Persist) | None => None,
+        };
+        // compressed or not, the value is compared as the client set it. A legacy value without
+        // a header is rewritten with one.
+        value::stored_type(&old) == Some(ValueType::String)
+            && value::deadline(&old) == deadline
+            && value::string(&old).is_ok_and(|old| *old == **value)
+    }
+
+    /// The command with the value of a SET compressed as --compression asks, if it shrinks
+    pub(crate) fn compress(mut self, config: &Config) -> Self {
+        let (InnerCmd::Put(_, _, value, option) | InnerCmd::SetGet(_, _, value, option)) =
+            &mut self
+        else {
+            return self;
+        };
+        if value.len() < config.compression_min_bytes() {
+            return self;
+        }
+        let codec = config.compression();
+        if let Some(compressed) = codec.compress(value) {
+            *value = compressed;
+            let option = option.get_or_insert_with(|| PutOptionSerde {
+                nx: false,
+                xx: false,
+                expiry: None,
+                now: value::now(),
+                compression: None,
+            });
+            option.compression = Some(codec);
+        }
+        self
+    }
+
     pub(crate) fn new(cmd: Cmd) -> anyhow::Result<Self> {
         let new_uuid = Uuid::new_v4();
         let id: RequestId = *new_uuid.as_bytes();
         match cmd {
             Cmd::Get(cmd) => {
                 let key = convert_bulk_string_to_vec(cmd.key)?;
-                Ok(Self::Get(id, key))
+                Ok(Self::Get(id, key, cmd.consistency))
+            }
+            Cmd::MGetSnapshot(cmd) => {
+                let keys = convert_bulk_strings_to_vec(cmd.keys)?;
+                Ok(Self::MGetSnapshot(id, keys))
             }
             Cmd::Set(cmd) => {
                 let key = convert_bulk_string_to_vec(cmd.key)?;
                 let value = convert_bulk_string_to_vec(cmd.value)?;
                 let option = cmd.option;
-                Ok(Self::Put(id, key, value, option))
+                let id = cmd.id.unwrap_or(id);
+                if cmd.ephemeral {
+                    // only a connection owns keys, not a script
+                    let (Some(owner), Some(option)) = (cmd.owner, option) else {
+                        return Err(anyhow::anyhow!("EPHEMERAL needs a connection"));
+                    };
+                    Ok(Self::Ephemeral(id, EphemeralOp::Set(key, value, owner, option)))
+                } else if cmd.get {
+                    Ok(Self::SetGet(id, key, value, option))
+                } else {
+                    Ok(Self::Put(id, key, value, option))
+                }
+            }
+            Cmd::GetRange(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::GetRange(id, key, cmd.start, cmd.end))
+            }
+            Cmd::GetDel(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::GetDel(id, key, cmd.now))
+            }
+            Cmd::Cas(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let expected = convert_bulk_string_to_vec(cmd.expected)?;
+                let new = convert_bulk_string_to_vec(cmd.new)?;
+                Ok(Self::Cas(id, key, expected, new, cmd.now))
+            }
+            Cmd::GetEx(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::GetEx(id, key, cmd.expiry, cmd.now))
+            }
+            Cmd::SetBit(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::SetBit(id, key, cmd.offset, cmd.bit, cmd.now))
+            }
+            Cmd::GetBit(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::GetBit(id, key, cmd.offset))
+            }
+            Cmd::BitCount(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::BitCount(id, key, cmd.range))
+            }
+            Cmd::BitPos(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::BitPos(id, key, cmd.bit, cmd.range))
+            }
+            Cmd::Copy(cmd) => {
+                let source = convert_bulk_string_to_vec(cmd.source)?;
+                let destination = convert_bulk_string_to_vec(cmd.destination)?;
+                Ok(Self::Copy(id, source, destination, cmd.replace, cmd.now))
+            }
+            Cmd::Rename(cmd) => {
+                let source = convert_bulk_string_to_vec(cmd.source)?;
+                let destination = convert_bulk_string_to_vec(cmd.destination)?;
+                Ok(Self::Rename(id, source, destination, false, cmd.now))
+            }
+            Cmd::Dump(cmd) => Ok(Self::Dump(id, convert_bulk_string_to_vec(cmd.key)?)),
+            Cmd::Restore(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let serialized = convert_bulk_string_to_vec(cmd.serialized)?;
+                Ok(Self::Restore(id, key, serialized, cmd.deadline, cmd.replace, cmd.now))
+            }
+            Cmd::RenameNx(cmd) => {
+                let source = convert_bulk_string_to_vec(cmd.source)?;
+                let destination = convert_bulk_string_to_vec(cmd.destination)?;
+                Ok(Self::Rename(id, source, destination, true, cmd.now))
             }
             Cmd::Del(cmd) => {
+                let keys = convert_bulk_strings_to_vec(cmd.keys)?;
+                Ok(Self::Del(id, keys, cmd.now))
+            }
+            Cmd::LPush(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let values = convert_bulk_strings_to_vec(cmd.values)?;
+                Ok(Self::Push(id, key, values, End::Left, cmd.now))
+            }
+            Cmd::RPush(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let values = convert_bulk_strings_to_vec(cmd.values)?;
+                Ok(Self::Push(id, key, values, End::Right, cmd.now))
+            }
+            Cmd::LPop(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::Pop(id, key, cmd.count, End::Left, cmd.now))
+            }
+            Cmd::RPop(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::Pop(id, key, cmd.count, End::Right, cmd.now))
+            }
+            Cmd::LRange(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::LRange(id, key, cmd.start, cmd.stop))
+            }
+            Cmd::LLen(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::LLen(id, key))
+            }
+            Cmd::ZAdd(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let members = cmd
+                    .members
+                    .into_iter()
+                    .map(|(score, member)| Ok((score, convert_bulk_string_to_vec(member)?)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(Self::ZAdd(id, key, members, cmd.now))
+            }
+            Cmd::ZRem(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let members = convert_bulk_strings_to_vec(cmd.members)?;
+                Ok(Self::ZRem(id, key, members, cmd.now))
+            }
+            Cmd::ZScore(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let member = convert_bulk_string_to_vec(cmd.member)?;
+                Ok(Self::ZScore(id, key, member))
+            }
+            Cmd::ZRange(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::ZRange(id, key, cmd.start, cmd.stop, cmd.with_scores))
+            }
+            Cmd::ZRangeByScore(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::ZRangeByScore(
+                    id,
+                    key,
+                    cmd.min,
+                    cmd.max,
+                    cmd.with_scores,
+                ))
+            }
+            Cmd::BfAdd(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let item = convert_bulk_string_to_vec(cmd.item)?;
+                Ok(Self::BfAdd(id, key, vec![item], false, cmd.now))
+            }
+            Cmd::BfMAdd(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let items = convert_bulk_strings_to_vec(cmd.items)?;
+                Ok(Self::BfAdd(id, key, items, true, cmd.now))
+            }
+            Cmd::BfExists(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let item = convert_bulk_string_to_vec(cmd.item)?;
+                Ok(Self::BfExists(id, key, item))
+            }
+            Cmd::CmsInitByDim(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::CmsInitByDim(id, key, cmd.width, cmd.depth, cmd.now))
+            }
+            Cmd::CmsIncrBy(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let increments = cmd
+                    .increments
+                    .into_iter()
+                    .map(|(item, increment)| Ok((convert_bulk_string_to_vec(item)?, increment)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(Self::CmsIncrBy(id, key, increments, cmd.now))
+            }
+            Cmd::CmsQuery(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let items = convert_bulk_strings_to_vec(cmd.items)?;
+                Ok(Self::CmsQuery(id, key, items))
+            }
+            Cmd::TopKReserve(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                Ok(Self::TopKReserve(id, key, cmd.k, cmd.width, cmd.depth, cmd.now))
+            }
+            Cmd::TopKAdd(cmd) => {
+                let key = convert_bulk_string_to_vec(cmd.key)?;
+                let items = convert_bulk_strings_to_vec(cmd.items)?;
+                Ok(Self::TopKAdd(id, key, items, cmd.now))
+            }
+            Cmd::TopKList(cmd) => {
                 let key = convert_bulk_string_to_vec(cmd.key)?;
-                Ok(Self::Del(id, key))
+                Ok(Self::TopKList(id, key, cmd.with_count))
+            }
+            Cmd::Keys(cmd) => {
+                let pattern = convert_bulk_string_to_vec(cmd.pattern)?;
+                Ok(Self::Keys(id, pattern))
+            }
+            Cmd::DbSize(_) => Ok(Self::DbSize(id)),
+            Cmd::Flush(FlushCmd {
+                step: Some(step), ..
+            }) => Ok(Self::FlushAll(id, step)),
+            // FLUSHDB in database 0 until the connection selects another one
+            Cmd::Flush(cmd) => Ok(Self::Flush(id, Some(0), cmd.lazy)),
+            Cmd::Select(cmd) => Ok(Self::Select(cmd.db)),
+            Cmd::SwapDb(cmd) => Ok(Self::SwapDb(id, cmd.first, cmd.second)),
+            Cmd::BgSave(_) => Ok(Self::BgSave),
+            Cmd::LastSave(_) => Ok(Self::LastSave),
+            Cmd::Compact(_) => Ok(Self::Compact),
+            Cmd::Promote(_) => Ok(Self::Promote),
+            Cmd::Publish(cmd) => {
+                let channel = convert_bulk_string_to_vec(cmd.channel)?;
+                let message = convert_bulk_string_to_vec(cmd.message)?;
+                Ok(Self::Publish(id, channel, message))
+            }
+            Cmd::Subscribe(cmd) => {
+                let channels = convert_bulk_strings_to_vec(cmd.channels)?;
+                Ok(Self::Subscribe(channels))
+            }
+            Cmd::Unsubscribe(cmd) => {
+                let channels = convert_bulk_strings_to_vec(cmd.channels)?;
+                Ok(Self::Unsubscribe(channels))
+            }
+            Cmd::PSubscribe(cmd) => {
+                let channels = convert_bulk_strings_to_vec(cmd.channels)?;
+                Ok(Self::PSubscribe(channels))
+            }
+            Cmd::PUnsubscribe(cmd) => {
+                let channels = convert_bulk_strings_to_vec(cmd.channels)?;
+                Ok(Self::PUnsubscribe(channels))
+            }
+            Cmd::SPublish(cmd) => {
+                let channel = convert_bulk_string_to_vec(cmd.channel)?;
+                let message = convert_bulk_string_to_vec(cmd.message)?;
+                Ok(Self::SPublish(id, channel, message))
+            }
+            Cmd::SSubscribe(cmd) => {
+                let channels = convert_bulk_strings_to_vec(cmd.channels)?;
+                Ok(Self::SSubscribe(channels))
+            }
+            Cmd::SUnsubscribe(cmd) => {
+                let channels = convert_bulk_strings_to_vec(cmd.channels)?;
+                Ok(Self::SUnsubscribe(channels))
             }
+            Cmd::Hello(cmd) => Ok(Self::Hello(cmd.protover, cmd.auth, cmd.setname)),
+            Cmd::Info(cmd) => Ok(Self::Info(cmd.section)),
+            Cmd::Cluster(cmd) => Ok(Self::Cluster(cmd.op)),
+            Cmd::Client(cmd) => Ok(Self::Client(cmd.op)),
+            Cmd::Config(cmd) => Ok(Self::Config(cmd.op)),
+            Cmd::Debug(cmd) => Ok(Self::Debug(cmd.op)),
+            Cmd::Auth(cmd) => {
+                let username = match cmd.username {
+                    Some(RespValue::BulkString(bytes)) => Some(convert_bulk_string_to_string(bytes)),
+                    Some(_) => return Err(anyhow::anyhow!("Invalid AUTH command")),
+                    None => None,
+                };
+                match cmd.password {
+                    RespValue::BulkString(bytes) => {
+                        Ok(Self::Auth(username, convert_bulk_string_to_string(bytes)))
+                    }
+                    _ => Err(anyhow::anyhow!("Invalid AUTH command")),
+                }
+            }
+            Cmd::Acl(cmd) => Ok(Self::Acl(cmd.op)),
+            Cmd::Proc(cmd) => Ok(Self::Proc(id, cmd.op)),
+            Cmd::Eval(cmd) => Ok(Self::Script(id, cmd.op)),
+            Cmd::Script(cmd) => Ok(Self::Script(id, cmd.op)),
+            Cmd::Lock(cmd) => Ok(Self::Lock(id, cmd.op)),
+            Cmd::Unlock(cmd) => Ok(Self::Lock(id, cmd.op)),
+            Cmd::If(cmd) => {
+                // writes of the clients only, proposed as they are
+                let branch = |cmd: Cmd| -> anyhow::Result<Box<Self>> {
+                    let inner_cmd = Self::new(cmd)?;
+                    let nested = matches!(
+                        inner_cmd,
+                        Self::Chunk(_, _, _, _)
+                            | Self::PutChunked(_, _, _, _)
+                            | Self::If(_, _, _, _, _)
+                    );
+                    match inner_cmd.family() == CommandFamily::Write
+                        && inner_cmd.category() == Some(Category::Write)
+                        && !nested
+                    {
+                        true => Ok(Box::new(inner_cmd)),
+                        false => {
+                            Err(anyhow::anyhow!("'{}' is not allowed in IF", inner_cmd.name()))
+                        }
+                    }
+                };
+                let otherwise = cmd.otherwise.map(|otherwise| branch(*otherwise)).transpose()?;
+                Ok(Self::If(id, cmd.key, cmd.now, branch(*cmd.then)?, otherwise))
+            }
+            Cmd::Shutdown(cmd) => Ok(Self::Shutdown(cmd.mode)),
+            Cmd::Snapshot(cmd) => Ok(Self::Snapshot(cmd.op)),
+            Cmd::SlowLog(cmd) => Ok(Self::SlowLog(cmd.op)),
+            Cmd::Raft(cmd) => Ok(Self::Raft(cmd.op)),
+            Cmd::Export(cmd) => Ok(Self::Export(convert_bulk_string_to_vec(cmd.pattern)?)),
+            Cmd::Monitor => Ok(Self::Monitor),
+            Cmd::Admin(cmd) => Ok(Self::Admin(cmd.op)),
+            Cmd::Feature(cmd) => Ok(Self::Feature(id, cmd.op)),
+            Cmd::Wait(cmd) => Ok(Self::Wait(cmd.replicas, cmd.timeout)),
             Cmd::Ping => Ok(Self::Ping),
+            Cmd::Plugin(cmd) => Ok(Self::Plugin(
+                id,
+                cmd.name.to_string(),
+                convert_bulk_strings_to_vec(cmd.args)?,
+            )),
+            Cmd::Rejected(e) => Err(e),
             Cmd::Unknown => Err(anyhow::anyhow!("Unknown command")),
         }
     }
@@ -280,9 +4582,153 @@ impl InnerCmd {
 
 fn convert_bulk_string_to_vec(bulk_string: RespValue) -> anyhow::Result<Vec<u8>> {
     match bulk_string {
-        RespValue::BulkString(Some(bytes)) => Ok(bytes),
+        RespValue::BulkString(Some(bytes)) => Ok(bytes.into()),
         RespValue::BulkString(None) => Err(anyhow::anyhow!("None bulk string")),
         _ => Err(anyhow::anyhow!("Invalid bulk string")),
     }
 }
 
+fn convert_bulk_strings_to_vec(bulk_strings: Vec<RespValue>) -> anyhow::Result<Vec<Vec<u8>>> {
+    bulk_strings
+        .into_iter()
+        .map(convert_bulk_string_to_vec)
+        .collect()
+}
+
+fn convert_bulk_string_to_number<T: std::str::FromStr>(bulk_string: RespValue) -> anyhow::Result<T> {
+    match bulk_string {
+        RespValue::BulkString(bytes) => convert_bulk_string_to_string(bytes)
+            .parse::<T>()
+            .map_err(|_| Rejected(NOT_AN_INTEGER.to_string()).into()),
+        _ => Err(anyhow::anyhow!("Invalid bulk string")),
+    }
+}
+
+/// A bit offset of SETBIT or GETBIT, within the size of a string
+fn convert_bulk_string_to_offset(bulk_string: RespValue) -> anyhow::Result<u64> {
+    let out_of_range =
+        || Rejected("ERR bit offset is not an integer or out of range".to_string());
+    let offset = convert_bulk_string_to_number::<u64>(bulk_string).map_err(|_| out_of_range())?;
+    if offset > bitmap::MAX_OFFSET {
+        return Err(out_of_range().into());
+    }
+    Ok(offset)
+}
+
+fn convert_bulk_string_to_bit(bulk_string: RespValue) -> anyhow::Result<bool> {
+    match bulk_string {
+        RespValue::BulkString(Some(bit)) if &bit[..] == b"0" => Ok(false),
+        RespValue::BulkString(Some(bit)) if &bit[..] == b"1" => Ok(true),
+        _ => Err(Rejected("ERR bit is not an integer or out of range".to_string()).into()),
+    }
+}
+
+/// The range of BITCOUNT or BITPOS, from `start [end [BYTE|BIT]]`, None without a start
+fn convert_bulk_strings_to_bit_range(
+    args: Vec<RespValue>,
+) -> anyhow::Result<Option<bitmap::Range>> {
+    let mut args = args.into_iter();
+    let Some(start) = args.next() else {
+        return Ok(None);
+    };
+    let start = convert_bulk_string_to_number::<i64>(start)?;
+    let end = args
+        .next()
+        .map(convert_bulk_string_to_number::<i64>)
+        .transpose()?;
+    let unit = match args.next() {
+        None => Unit::Byte,
+        Some(RespValue::BulkString(Some(unit))) if unit.eq_ignore_ascii_case(b"BYTE") => Unit::Byte,
+        Some(RespValue::BulkString(Some(unit))) if unit.eq_ignore_ascii_case(b"BIT") => Unit::Bit,
+        Some(_) => return Err(anyhow::anyhow!("Syntax error")),
+    };
+    Ok(Some(bitmap::Range { start, end, unit }))
+}
+
+fn convert_bulk_string_to_score(bulk_string: RespValue) -> anyhow::Result<f64> {
+    match bulk_string {
+        RespValue::BulkString(bytes) => zset::parse_score(&convert_bulk_string_to_string(bytes)),
+        _ => Err(anyhow::anyhow!("Invalid bulk string")),
+    }
+}
+
+fn convert_bulk_string_to_score_bound(bulk_string: RespValue) -> anyhow::Result<ScoreBound> {
+    match bulk_string {
+        RespValue::BulkString(bytes) => ScoreBound::parse(&convert_bulk_string_to_string(bytes)),
+        _ => Err(anyhow::anyhow!("Invalid bulk string")),
+    }
+}
+
+/// Sketch dimensions must be positive
+fn convert_bulk_string_to_dimension(bulk_string: RespValue) -> anyhow::Result<u32> {
+    match convert_bulk_string_to_number::<u32>(bulk_string)? {
+        0 => Err(anyhow::anyhow!("Dimension must be positive")),
+        dimension => Ok(dimension),
+    }
+}
+
+fn integer_array(values: Vec<u64>) -> RespValue {
+    RespValue::Array(
+        values
+            .into_iter()
+            .map(|value| RespValue::Integer(value as i64))
+            .collect(),
+    )
+}
+
+/// SET: write the string value with the deadline its options give it, failing with KeyExists or
+/// KeyNotFound if the NX or XX condition does not hold. An expired value counts as missing.
+pub(crate) fn set(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    value: &[u8],
+    option: Option<&PutOptionSerde>,
+) -> Result<(), BitCaskError> {
+    let Some(option) = option else {
+        return storage.put(key, &value::encode(ValueType::String, value));
+    };
+    let old = storage.get(key).filter(|raw| !value::expired(raw, option.now));
+    if option.nx && old.is_some() {
+        return Err(BitCaskError::KeyExists);
+    }
+    if option.xx && old.is_none() {
+        return Err(BitCaskError::KeyNotFound);
+    }
+    let deadline = match option.expiry {
+        Some(Expiry::At(deadline)) => Some(deadline),
+        Some(Expiry::Keep) => old.as_deref().and_then(value::deadline),
+        Some(Expiry::Persist) | None => None,
+    };
+    let raw = match option.compression {
+        Some(codec) => value::encode_compressed(codec, value, deadline),
+        None => value::encode_expiring(ValueType::String, value, deadline),
+    };
+    storage.put(key, &raw)
+}
+
+/// Resolve GETRANGE offsets against a string of `len` bytes. Unlike list indexes, offsets
+/// out of range are clamped to the string rather than making the range empty.
+fn string_range(len: usize, start: i64, end: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    if start < 0 && end < 0 && start > end {
+        return 0..0;
+    }
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+    if start > end {
+        return 0..0;
+    }
+    start as usize..end as usize + 1
+}
+
+/// Members of a sorted set as a flat array, interleaved with their scores if requested
+fn zset_reply(members: Vec<(f64, Vec<u8>)>, with_scores: bool) -> RespValue {
+    let mut reply = Vec::with_capacity(members.len() * if with_scores { 2 } else { 1 });
+    for (score, member) in members {
+        reply.push(RespValue::BulkString(Some(member.into())));
+        if with_scores {
+            reply.push(RespValue::BulkString(Some(format_double(score).into())));
+        }
+    }
+    RespValue::Array(reply)
+}
@@ -0,0 +1,311 @@
+//! Compaction of the data files, on a schedule and by COMPACT. The storage only appends, so the
+//! values overwritten or deleted and the tombstones stay in its files until bitcask-engine-rs
+//! rewrites the live entries of all but the file it appends to into a new directory, where the
+//! storage moves to. The node keeps its storage in `--directory` and `compacted` within it, in
+//! turns, removing the files a compaction leaves behind once no snapshot reads them. Snapshots,
+//! for KEYS, backups or FLUSHALL, wait for a compaction in progress.
+//!
+//! The storage moved to `compacted` once it holds the `LIVE` marker, written once the compaction
+//! is done and removed once the storage moves back. A node stopping in between starts on the
+//! files it moved from, which are removed only afterwards: they miss the entries applied since,
+//! which the node applies again as the log of the group is sent to it.
+//!
+//! Two habits of the engine are worked around. It finds a file by its number, so the new
+//! directory gets an empty file for each number the compaction merged into the first file, one
+//! more each time, which a restart removes by renumbering the files. It indexes its files in the
+//! order the directory lists them, so a value written during the compaction may lose to the older
+//! one of the first file: the keys written in the later files are set again as these have them,
+//! between two writes, once the storage moved, and on startup.
+
+use crate::cmd::NodeContext;
+use crate::keyspace::{self, Snapshot};
+use crate::scrubber::DATA_FILE_EXT;
+use anyhow::bail;
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// The directory within the data directory the storage moves to every other compaction
+const COMPACTED_DIR: &str = "compacted";
+/// Marks the storage as being in the compacted directory
+const LIVE: &str = "LIVE";
+/// Data files, empty ones included, from which compactions wait for a restart to renumber them,
+/// the engine keeping each open
+const MAX_OPEN_FILES: usize = 512;
+
+/// Open the storage of the data directory, in the compacted directory if a compaction moved it
+/// there before the node stopped, the other one being cleared of the files left behind
+pub(crate) fn open(data_dir: &Path) -> anyhow::Result<BitCask> {
+    std::fs::create_dir_all(data_dir)?;
+    let compacted = data_dir.join(COMPACTED_DIR);
+    if compacted.join(LIVE).exists() {
+        info!("Compaction: the storage is in {}", compacted.display());
+        keyspace::moving(data_dir).moved(&compacted).remove()?;
+    } else if compacted.exists() {
+        info!(
+            "Compaction: removing the interrupted compaction in {}",
+            compacted.display()
+        );
+        std::fs::remove_dir_all(&compacted)?;
+    }
+    renumber(data_dir)?;
+    let mut storage = BitCask::new(keyspace::storage_dir(data_dir))?;
+    let repaired = repair(&mut storage, data_dir)?;
+    if repaired > 0 {
+        info!(
+            "Compaction: {} keys set again as the data files have them",
+            repaired
+        );
+    }
+    Ok(storage)
+}
+
+/// Number the data files from 0 without the empty ones, keeping their order
+fn renumber(data_dir: &Path) -> std::io::Result<()> {
+    let files = keyspace::data_files(data_dir)?;
+    let mut next = 0;
+    for (path, len) in files {
+        if len == 0 {
+            std::fs::remove_file(&path)?;
+            continue;
+        }
+        let numbered = path.with_file_name(format!("{}.{}", next, DATA_FILE_EXT));
+        if numbered != path {
+            std::fs::rename(&path, &numbered)?;
+        }
+        next += 1;
+    }
+    Ok(())
+}
+
+/// Set the keys written in the files after the first one again where the storage has another
+/// value, returning how many. Nothing else writes meanwhile.
+fn repair(storage: &mut BitCask, data_dir: &Path) -> anyhow::Result<usize> {
+    let mut repaired = 0;
+    for (key, value) in Snapshot::take(data_dir)?.latest_after_first()? {
+        match value {
+            Some(value) if storage.get(&key).as_ref() != Some(&value) => {
+                storage.put(&key, &value)?
+            }
+            None if storage.get(&key).is_some() => storage.delete(&key)?,
+            _ => continue,
+        }
+        repaired += 1;
+    }
+    Ok(repaired)
+}
+
+#[derive(Default)]
+struct State {
+    running: bool,
+    compactions: u64,
+    // unix time of the last compaction and the bytes it reclaimed
+    last_success: Option<(u64, u64)>,
+    last_duration: Duration,
+    failures: u64,
+    // of the last attempt, cleared by a success
+    last_error: Option<String>,
+}
+
+pub(crate) struct Compaction {
+    // None when only COMPACT compacts the files
+    interval: Option<Duration>,
+    // share of dead bytes in the files from which a scheduled compaction runs
+    dead_ratio: f64,
+    // data files from which a scheduled compaction runs, 0 for no limit
+    max_files: usize,
+    state: Mutex<State>,
+}
+
+impl Compaction {
+    pub(crate) fn new(interval: Option<Duration>, dead_ratio: f64, max_files: usize) -> Self {
+        Self {
+            interval,
+            dead_ratio,
+            max_files,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Compact the files once they cross a threshold, checked on the interval, if any, until the
+    /// node stops
+    pub(crate) async fn run(self: Arc<Self>, storage: BitCask, context: NodeContext) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        loop {
+            tokio::time::sleep(interval).await;
+            let compaction = self.clone();
+            let storage = storage.clone();
+            let context = context.clone();
+            let _ = tokio::task::spawn_blocking(move || match compaction.due(&context.data_dir) {
+                Ok(Some(reason)) if compaction.begin() => {
+                    info!("Compaction: starting, {}", reason);
+                    compaction.compact(storage, &context);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Compaction: could not measure the data files: {}", e),
+            })
+            .await;
+        }
+    }
+
+    /// Compact the files in the background, as COMPACT asks, unless a compaction is in progress
+    pub(crate) fn start(self: Arc<Self>, storage: BitCask, context: NodeContext) -> bool {
+        if !self.begin() {
+            return false;
+        }
+        tokio::task::spawn_blocking(move || self.compact(storage, &context));
+        true
+    }
+
+    /// Mark a compaction as in progress, unless one already is
+    fn begin(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        !std::mem::replace(&mut state.running, true)
+    }
+
+    /// Why the files are worth compacting, None if they are not
+    fn due(&self, data_dir: &Path) -> std::io::Result<Option<String>> {
+        let snapshot = Snapshot::take(data_dir)?;
+        let files = keyspace::data_files(data_dir)?;
+        // not counting those a restart removes
+        let written = files.iter().filter(|(_, len)| *len > 0).count();
+        if self.max_files > 0 && written >= self.max_files {
+            return Ok(Some(format!("{} data files", written)));
+        }
+        let (_, bytes) = snapshot.size();
+        if bytes == 0 {
+            return Ok(None);
+        }
+        let dead = 1.0 - snapshot.live_bytes()? as f64 / bytes as f64;
+        Ok((dead >= self.dead_ratio)
+            .then(|| format!("{:.0}% of {} bytes dead", dead * 100.0, bytes)))
+    }
+
+    /// Move the storage to the other directory, compacting its files on the way. The compaction
+    /// was marked as in progress.
+    fn compact(&self, storage: BitCask, context: &NodeContext) {
+        let started = Instant::now();
+        let result = compact(storage, context);
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        state.last_duration = started.elapsed();
+        match result {
+            Ok((before, after)) => {
+                info!(
+                    "Compaction: {} bytes of data files down to {} in {:?}",
+                    before,
+                    after,
+                    started.elapsed()
+                );
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                state.compactions += 1;
+                state.last_success = Some((time, before.saturating_sub(after)));
+                state.last_error = None;
+            }
+            Err(e) => {
+                warn!("Compaction: failed: {}", e);
+                state.failures += 1;
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Render the `compaction` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let (last_time, last_reclaimed) = state.last_success.unwrap_or_default();
+        let mut info = format!(
+            "compaction_interval_secs:{}\r\ncompaction_dead_ratio:{}\r\ncompaction_max_files:{}\r\ncompaction_in_progress:{}\r\ncompactions:{}\r\ncompaction_last_success_time:{}\r\ncompaction_last_reclaimed_bytes:{}\r\ncompaction_last_duration_ms:{}\r\ncompaction_failures:{}\r\n",
+            self.interval.map_or(0, |interval| interval.as_secs()),
+            self.dead_ratio,
+            self.max_files,
+            state.running as u8,
+            state.compactions,
+            last_time,
+            last_reclaimed,
+            state.last_duration.as_millis(),
+            state.failures,
+        );
+        if let Some(e) = &state.last_error {
+            info.push_str(&format!("compaction_last_error:{}\r\n", e));
+        }
+        info
+    }
+}
+
+/// Compact the files of the storage into the directory it is not in, returning the bytes of the
+/// data files before and after
+fn compact(mut storage: BitCask, context: &NodeContext) -> anyhow::Result<(u64, u64)> {
+    let data_dir = context.data_dir.as_path();
+    let bytes = |files: &[(PathBuf, u64)]| files.iter().map(|(_, len)| len).sum::<u64>();
+    let files = keyspace::data_files(data_dir)?;
+    if files.len() >= MAX_OPEN_FILES {
+        bail!(
+            "{} data files, restart the node to renumber them",
+            files.len()
+        );
+    }
+    let compacted = data_dir.join(COMPACTED_DIR);
+    let to = match keyspace::storage_dir(data_dir) == data_dir {
+        true => compacted.as_path(),
+        false => data_dir,
+    };
+    // the snapshots wait for the storage to be in the directory they list
+    let moving = keyspace::moving(data_dir);
+    let moved = placeholders(to, files.len())
+        .map_err(anyhow::Error::from)
+        .and_then(|()| Ok(storage.compact_to_new_dir(to)?));
+    if let Err(e) = moved {
+        // the storage stays where it is, without the files written for it elsewhere
+        let _ = match to == data_dir {
+            true => remove_data_files(data_dir),
+            false => std::fs::remove_dir_all(&compacted),
+        };
+        return Err(e);
+    }
+    if to == compacted {
+        File::create(compacted.join(LIVE))?.sync_all()?;
+    } else {
+        std::fs::remove_file(compacted.join(LIVE))?;
+    }
+    let left = moving.moved(to);
+    {
+        let _writing = keyspace::writing();
+        context.flusher.wait();
+        repair(&mut storage, data_dir)?;
+    }
+    left.remove()?;
+    if to == data_dir {
+        std::fs::remove_dir_all(&compacted)?;
+    }
+    Ok((bytes(&files), bytes(&keyspace::data_files(data_dir)?)))
+}
+
+/// Lay an empty file in `dir` for each of the files after the first one the compaction merges,
+/// one of them being the file the storage appends to once it is full. The files the storage
+/// starts are copied over them.
+fn placeholders(dir: &Path, files: usize) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for number in 1..=files {
+        File::create(dir.join(format!("{}.{}", number, DATA_FILE_EXT)))?;
+    }
+    Ok(())
+}
+
+fn remove_data_files(dir: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == DATA_FILE_EXT) {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,33 @@
+//! Deprecated Redis commands, rewritten into their modern equivalent before dispatch so that
+//! clients written against old Redis versions keep working unchanged.
+//!
+//! HMSET is not covered, as there is no hash type to map it to.
+
+use crate::resp_codec::RespValue;
+use bytes::Bytes;
+
+/// Rewrite a deprecated command into the command replacing it, leaving any other value as is
+pub(crate) fn rewrite(value: RespValue) -> RespValue {
+    let RespValue::Array(mut arr) = value else {
+        return value;
+    };
+    let name = match arr.first() {
+        Some(RespValue::BulkString(Some(name))) => name.clone(),
+        _ => return RespValue::Array(arr),
+    };
+    match &name[..] {
+        // SUBSTR key start end, renamed GETRANGE in Redis 2.0
+        b"SUBSTR" => arr[0] = bulk("GETRANGE"),
+        // GETSET key value, replaced by SET key value GET in Redis 6.2
+        b"GETSET" if arr.len() == 3 => {
+            arr[0] = bulk("SET");
+            arr.push(bulk("GET"));
+        }
+        _ => {}
+    }
+    RespValue::Array(arr)
+}
+
+fn bulk(s: &'static str) -> RespValue {
+    RespValue::BulkString(Some(Bytes::from_static(s.as_bytes())))
+}
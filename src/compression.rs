@@ -0,0 +1,60 @@
+//! --compression: string values of at least --compression-min-bytes are compressed on the node
+//! the write is sent to, before it is proposed, so the entry replicates the compressed bytes and
+//! every node stores the same ones. The value records the codec in its header, see
+//! crate::value, so values stored compressed or not, and with either codec, are all read back
+//! whatever the setting is now.
+
+use bitcask_engine_rs::error::BitCaskError;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// zstd level, its default, trading ratio for speed as the write path needs
+const ZSTD_LEVEL: i32 = 3;
+
+/// How string values are compressed. The discriminants are recorded in the stored values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub(crate) enum Compression {
+    None = 0,
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl Compression {
+    pub(crate) fn parse(codec: &str) -> Option<Self> {
+        match codec.to_lowercase().as_str() {
+            "none" => Some(Self::None),
+            "lz4" => Some(Self::Lz4),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Lz4 => "lz4",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// The value compressed, None without a codec or when it would not be smaller
+    pub(crate) fn compress(self, value: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Self::None => return None,
+            Self::Lz4 => lz4_flex::compress_prepend_size(value),
+            Self::Zstd => zstd::bulk::compress(value, ZSTD_LEVEL).ok()?,
+        };
+        (compressed.len() < value.len()).then_some(compressed)
+    }
+}
+
+/// The value compressed with the codec of the given tag
+pub(crate) fn decompress(codec: u8, compressed: &[u8]) -> Result<Vec<u8>, BitCaskError> {
+    let corrupted =
+        |e: &dyn std::fmt::Display| BitCaskError::CorruptedData(format!("compressed value: {}", e));
+    match codec {
+        1 => lz4_flex::decompress_size_prepended(compressed).map_err(|e| corrupted(&e)),
+        2 => zstd::stream::decode_all(compressed).map_err(|e| corrupted(&e)),
+        _ => Err(corrupted(&format!("unknown codec {}", codec))),
+    }
+}
@@ -0,0 +1,438 @@
+//! Settings that can be changed at runtime with CONFIG SET, without restarting the node. They
+//! start from the command line, and CONFIG REWRITE persists the changes into the configuration
+//! file the node started with. Sizes and durations take units, see crate::units.
+
+use crate::cli::Args;
+use crate::compression::Compression;
+use crate::config_file;
+use crate::memory::Policy;
+use crate::pubsub::glob_match;
+use crate::units;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+/// A CONFIG subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum ConfigOp {
+    // Pattern
+    Get(String),
+    // Name and value pairs
+    Set(Vec<(String, String)>),
+    Rewrite,
+}
+
+/// How fresh a read is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub(crate) enum Consistency {
+    // what the node applied so far, which may lag behind the rest of the group
+    Local,
+    // what the leader would serve. The node does not know the leader, so it waits for a barrier
+    // like a linearizable read.
+    Leader,
+    // every write committed before the read started, on any node
+    Linearizable,
+}
+
+impl Consistency {
+    pub(crate) fn parse(level: &str) -> Option<Self> {
+        match level.to_lowercase().as_str() {
+            "local" => Some(Self::Local),
+            "leader" => Some(Self::Leader),
+            "linearizable" => Some(Self::Linearizable),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::Leader => "leader",
+            Self::Linearizable => "linearizable",
+        }
+    }
+
+    /// Whether the read waits for a barrier entry replicated through the sync layer
+    pub(crate) fn needs_barrier(&self) -> bool {
+        *self != Self::Local
+    }
+}
+
+/// What becomes of a write too large to be proposed as a single Raft entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Oversize {
+    // the client gets an error
+    Reject,
+    // the value of a SET is proposed in chunks, other writes are rejected
+    Split,
+}
+
+impl Oversize {
+    pub(crate) fn parse(policy: &str) -> Option<Self> {
+        match policy.to_lowercase().as_str() {
+            "reject" => Some(Self::Reject),
+            "split" => Some(Self::Split),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Split => "split",
+        }
+    }
+}
+
+/// Applies a log level to the logger
+pub(crate) type LogReload = Box<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>;
+
+pub(crate) struct Config {
+    log_level: Mutex<String>,
+    write_timeout_ms: AtomicU64,
+    max_clients: AtomicUsize,
+    // in seconds, 0 never closes idle connections
+    timeout: AtomicU64,
+    // 0 never sheds load
+    busy_apply_lag: AtomicUsize,
+    // in microseconds, negative disables the slow log
+    slowlog_log_slower_than: AtomicI64,
+    slowlog_max_len: AtomicUsize,
+    // 0 adds no jitter
+    ttl_jitter_percent: AtomicU64,
+    // in milliseconds, for the keys set EPHEMERAL
+    ephemeral_lease_ms: AtomicU64,
+    // a Consistency as u8, read by every read command not choosing its own
+    read_consistency: AtomicU8,
+    // in bytes, 0 proposes writes of any size
+    proposal_max_bytes: AtomicU64,
+    proposal_oversize: Mutex<Oversize>,
+    // in bytes, 0 for no bound
+    maxmemory: AtomicU64,
+    // a Policy as u8
+    maxmemory_policy: AtomicU8,
+    // a Compression as u8
+    compression: AtomicU8,
+    compression_min_bytes: AtomicUsize,
+    // None when no logger is set up
+    log_reload: Option<LogReload>,
+    // the configuration file CONFIG REWRITE writes, None without one
+    file: Option<PathBuf>,
+    // the settings CONFIG SET changed, by name
+    changed: Mutex<BTreeSet<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: Mutex::new("debug".to_string()),
+            write_timeout_ms: AtomicU64::new(10_000),
+            max_clients: AtomicUsize::new(10_000),
+            timeout: AtomicU64::new(0),
+            busy_apply_lag: AtomicUsize::new(10_000),
+            slowlog_log_slower_than: AtomicI64::new(10_000),
+            slowlog_max_len: AtomicUsize::new(128),
+            ttl_jitter_percent: AtomicU64::new(0),
+            ephemeral_lease_ms: AtomicU64::new(10_000),
+            read_consistency: AtomicU8::new(Consistency::Local as u8),
+            proposal_max_bytes: AtomicU64::new(0),
+            proposal_oversize: Mutex::new(Oversize::Reject),
+            maxmemory: AtomicU64::new(0),
+            maxmemory_policy: AtomicU8::new(Policy::NoEviction as u8),
+            compression: AtomicU8::new(Compression::None as u8),
+            compression_min_bytes: AtomicUsize::new(1024),
+            log_reload: None,
+            file: None,
+            changed: Mutex::default(),
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn new(args: &Args, log_reload: Option<LogReload>) -> Self {
+        Self {
+            log_level: Mutex::new(args.log_level()),
+            write_timeout_ms: AtomicU64::new(args.write_timeout_ms()),
+            max_clients: AtomicUsize::new(args.max_clients()),
+            timeout: AtomicU64::new(args.timeout()),
+            busy_apply_lag: AtomicUsize::new(args.busy_apply_lag()),
+            slowlog_log_slower_than: AtomicI64::new(args.slowlog_log_slower_than()),
+            slowlog_max_len: AtomicUsize::new(args.slowlog_max_len()),
+            ttl_jitter_percent: AtomicU64::new(args.ttl_jitter_percent()),
+            ephemeral_lease_ms: AtomicU64::new(args.ephemeral_lease_ms()),
+            read_consistency: AtomicU8::new(args.read_consistency() as u8),
+            proposal_max_bytes: AtomicU64::new(args.proposal_max_bytes()),
+            proposal_oversize: Mutex::new(args.proposal_oversize()),
+            maxmemory: AtomicU64::new(args.maxmemory()),
+            maxmemory_policy: AtomicU8::new(args.maxmemory_policy() as u8),
+            compression: AtomicU8::new(args.compression() as u8),
+            compression_min_bytes: AtomicUsize::new(args.compression_min_bytes()),
+            log_reload,
+            file: args.config().map(PathBuf::from),
+            changed: Mutex::default(),
+        }
+    }
+
+    /// How long a client waits for a write to be replicated and applied
+    pub(crate) fn write_timeout(&self) -> Duration {
+        Duration::from_millis(self.write_timeout_ms.load(Ordering::Relaxed))
+    }
+
+    /// How many clients may be connected at once
+    pub(crate) fn max_clients(&self) -> usize {
+        self.max_clients.load(Ordering::Relaxed)
+    }
+
+    /// How long a client may stay idle before its connection is closed, None for ever
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        let secs = self.timeout.load(Ordering::Relaxed);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    /// How many committed entries may wait to be applied before the node sheds load, 0 for never
+    pub(crate) fn busy_apply_lag(&self) -> usize {
+        self.busy_apply_lag.load(Ordering::Relaxed)
+    }
+
+    /// How long a command must take to be logged in the slow log, None when it is disabled
+    pub(crate) fn slowlog_log_slower_than(&self) -> Option<Duration> {
+        let micros = self.slowlog_log_slower_than.load(Ordering::Relaxed);
+        u64::try_from(micros).ok().map(Duration::from_micros)
+    }
+
+    /// How many entries the slow log keeps
+    pub(crate) fn slowlog_max_len(&self) -> usize {
+        self.slowlog_max_len.load(Ordering::Relaxed)
+    }
+
+    /// Percent of a relative expiry added to it at random, at most
+    pub(crate) fn ttl_jitter_percent(&self) -> u64 {
+        self.ttl_jitter_percent.load(Ordering::Relaxed)
+    }
+
+    /// How long the keys set EPHEMERAL outlive their connection, at most, in milliseconds
+    pub(crate) fn ephemeral_lease_ms(&self) -> u64 {
+        self.ephemeral_lease_ms.load(Ordering::Relaxed)
+    }
+
+    /// How fresh the reads not choosing their consistency are
+    pub(crate) fn read_consistency(&self) -> Consistency {
+        Consistency::value_variants()[self.read_consistency.load(Ordering::Relaxed) as usize]
+    }
+
+    /// How large a write may be to be proposed as a single Raft entry, None for any size
+    pub(crate) fn proposal_max_bytes(&self) -> Option<u64> {
+        let bytes = self.proposal_max_bytes.load(Ordering::Relaxed);
+        (bytes > 0).then_some(bytes)
+    }
+
+    /// What becomes of the writes larger than proposal-max-bytes
+    pub(crate) fn proposal_oversize(&self) -> Oversize {
+        *self.proposal_oversize.lock().unwrap()
+    }
+
+    /// How many bytes the keys may take before writes evict or are rejected, None for any
+    pub(crate) fn maxmemory(&self) -> Option<u64> {
+        let bytes = self.maxmemory.load(Ordering::Relaxed);
+        (bytes > 0).then_some(bytes)
+    }
+
+    /// What is evicted over maxmemory
+    pub(crate) fn maxmemory_policy(&self) -> Policy {
+        Policy::value_variants()[self.maxmemory_policy.load(Ordering::Relaxed) as usize]
+    }
+
+    /// How the values of SET are compressed before they are proposed
+    pub(crate) fn compression(&self) -> Compression {
+        Compression::value_variants()[self.compression.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Size in bytes from which the values of SET are compressed
+    pub(crate) fn compression_min_bytes(&self) -> usize {
+        self.compression_min_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The settings whose name matches the pattern, in name order
+    pub(crate) fn get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        [
+            ("busy-apply-lag", self.busy_apply_lag().to_string()),
+            ("compression", self.compression().name().to_string()),
+            (
+                "compression-min-bytes",
+                self.compression_min_bytes().to_string(),
+            ),
+            ("ephemeral-lease-ms", self.ephemeral_lease_ms().to_string()),
+            ("loglevel", self.log_level.lock().unwrap().clone()),
+            ("maxclients", self.max_clients().to_string()),
+            (
+                "maxmemory",
+                self.maxmemory.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "maxmemory-policy",
+                self.maxmemory_policy().name().to_string(),
+            ),
+            (
+                "proposal-max-bytes",
+                self.proposal_max_bytes.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "proposal-oversize",
+                self.proposal_oversize().name().to_string(),
+            ),
+            (
+                "read-consistency",
+                self.read_consistency().name().to_string(),
+            ),
+            (
+                "slowlog-log-slower-than",
+                self.slowlog_log_slower_than
+                    .load(Ordering::Relaxed)
+                    .to_string(),
+            ),
+            ("slowlog-max-len", self.slowlog_max_len().to_string()),
+            ("timeout", self.timeout.load(Ordering::Relaxed).to_string()),
+            ("ttl-jitter-percent", self.ttl_jitter_percent().to_string()),
+            (
+                "write-timeout",
+                self.write_timeout_ms.load(Ordering::Relaxed).to_string(),
+            ),
+        ]
+        .into_iter()
+        .filter(|(name, _)| glob_match(pattern.to_lowercase().as_bytes(), name.as_bytes()))
+        .collect()
+    }
+
+    /// Change settings, all of them or none if one of the changes is rejected
+    pub(crate) fn set(&self, changes: &[(String, String)]) -> Result<(), String> {
+        for (name, value) in changes {
+            self.validate(&name.to_lowercase(), value)?;
+        }
+        for (name, value) in changes {
+            match name.to_lowercase().as_str() {
+                "loglevel" => {
+                    let level = value.to_lowercase();
+                    if let Some(log_reload) = &self.log_reload {
+                        log_reload(&level).map_err(|e| format!("ERR CONFIG SET failed: {}", e))?;
+                    }
+                    *self.log_level.lock().unwrap() = level;
+                }
+                "maxclients" => self
+                    .max_clients
+                    .store(value.parse().unwrap(), Ordering::Relaxed),
+                "write-timeout" => self
+                    .write_timeout_ms
+                    .store(units::millis(value).unwrap(), Ordering::Relaxed),
+                "timeout" => self
+                    .timeout
+                    .store(units::secs(value).unwrap(), Ordering::Relaxed),
+                "busy-apply-lag" => self
+                    .busy_apply_lag
+                    .store(value.parse().unwrap(), Ordering::Relaxed),
+                "slowlog-log-slower-than" => self
+                    .slowlog_log_slower_than
+                    .store(value.parse().unwrap(), Ordering::Relaxed),
+                "slowlog-max-len" => self
+                    .slowlog_max_len
+                    .store(value.parse().unwrap(), Ordering::Relaxed),
+                "ttl-jitter-percent" => self
+                    .ttl_jitter_percent
+                    .store(value.parse().unwrap(), Ordering::Relaxed),
+                "ephemeral-lease-ms" => self
+                    .ephemeral_lease_ms
+                    .store(units::millis(value).unwrap(), Ordering::Relaxed),
+                "read-consistency" => self
+                    .read_consistency
+                    .store(Consistency::parse(value).unwrap() as u8, Ordering::Relaxed),
+                "proposal-max-bytes" => self
+                    .proposal_max_bytes
+                    .store(units::bytes(value).unwrap(), Ordering::Relaxed),
+                "proposal-oversize" => {
+                    *self.proposal_oversize.lock().unwrap() = Oversize::parse(value).unwrap()
+                }
+                "compression" => self
+                    .compression
+                    .store(Compression::parse(value).unwrap() as u8, Ordering::Relaxed),
+                "compression-min-bytes" => self
+                    .compression_min_bytes
+                    .store(units::bytes_usize(value).unwrap(), Ordering::Relaxed),
+                "maxmemory" => self
+                    .maxmemory
+                    .store(units::bytes(value).unwrap(), Ordering::Relaxed),
+                "maxmemory-policy" => self
+                    .maxmemory_policy
+                    .store(Policy::parse(value).unwrap() as u8, Ordering::Relaxed),
+                _ => unreachable!("validated above"),
+            }
+            self.changed.lock().unwrap().insert(name.to_lowercase());
+        }
+        Ok(())
+    }
+
+    /// Write the settings CONFIG SET changed into the configuration file, by their flag names
+    pub(crate) fn rewrite(&self) -> Result<(), String> {
+        let Some(file) = &self.file else {
+            return Err("ERR The server is running without a config file".to_string());
+        };
+        let changed = self.changed.lock().unwrap();
+        let settings: Vec<(String, String)> = self
+            .get("*")
+            .into_iter()
+            .filter(|(name, _)| changed.contains(*name))
+            .map(|(name, value)| (flag(name).to_string(), value))
+            .collect();
+        config_file::rewrite(file, &settings)
+            .map_err(|e| format!("ERR Rewriting config file: {}", e))
+    }
+
+    fn validate(&self, name: &str, value: &str) -> Result<(), String> {
+        let valid = match name {
+            "loglevel" => LOG_LEVELS.contains(&value.to_lowercase().as_str()),
+            "maxclients" => value.parse::<usize>().is_ok_and(|max| max > 0),
+            "write-timeout" => units::millis(value).is_ok_and(|ms| ms > 0),
+            "timeout" => units::secs(value).is_ok(),
+            "busy-apply-lag" => value.parse::<usize>().is_ok(),
+            "slowlog-log-slower-than" => value.parse::<i64>().is_ok(),
+            "slowlog-max-len" => value.parse::<usize>().is_ok(),
+            "ttl-jitter-percent" => value.parse::<u64>().is_ok_and(|percent| percent <= 100),
+            "ephemeral-lease-ms" => units::millis(value).is_ok_and(|ms| ms > 0),
+            "read-consistency" => Consistency::parse(value).is_some(),
+            "proposal-max-bytes" => units::bytes(value).is_ok(),
+            "proposal-oversize" => Oversize::parse(value).is_some(),
+            "compression" => Compression::parse(value).is_some(),
+            "compression-min-bytes" => units::bytes_usize(value).is_ok(),
+            "maxmemory" => units::bytes(value).is_ok(),
+            "maxmemory-policy" => Policy::parse(value).is_some(),
+            _ => {
+                return Err(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ))
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!(
+                "ERR Invalid argument '{}' for CONFIG SET '{}'",
+                value, name
+            ))
+        }
+    }
+}
+
+/// The flag a setting starts from, by its name in CONFIG
+fn flag(name: &str) -> &str {
+    match name {
+        "loglevel" => "log-level",
+        "maxclients" => "max-clients",
+        "write-timeout" => "write-timeout-ms",
+        name => name,
+    }
+}
@@ -0,0 +1,376 @@
+//! Configuration files, in TOML: `key = value` settings at the top level, where a value is a
+//! string, a number, a boolean or an array of those. Keys are the long names of the command line
+//! flags, with dashes or underscores. Tables are rejected, and YAML is not read.
+//!
+//! The file is the lowest-precedence source after the defaults: a value it sets is only used when
+//! neither the command line flag nor the environment variable of the setting is given. It is
+//! applied by exporting the values as the environment variables the flags already read, unless
+//! they are set.
+//!
+//! A file states the version of the settings it is written for with `version = <n>`, 1 when it
+//! does not: version 2 renamed the settings of DEPRECATED, which a file of version 1 may still
+//! set, with a warning as the node starts. CONFIG REWRITE brings a file to the current version as
+//! it writes the settings changed at runtime into it, keeping its other lines and comments.
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+use toml_edit::{DocumentMut, Item, Key};
+
+/// The version of the settings this release reads files of, and writes
+pub(crate) const VERSION: u64 = 2;
+
+/// A setting renamed, still read from the files of version 1 and the flags, with a warning
+#[derive(Debug)]
+pub(crate) struct Deprecated {
+    pub(crate) name: &'static str,
+    pub(crate) replacement: &'static str,
+    // the value of the replacement for a value of the setting
+    pub(crate) migrate: fn(&str) -> String,
+}
+
+/// The settings renamed in version 2, once sizes took units
+pub(crate) const DEPRECATED: &[Deprecated] = &[Deprecated {
+    name: "read-cache-mb",
+    replacement: "read-cache-size",
+    migrate: in_mb,
+}];
+
+fn in_mb(mb: &str) -> String {
+    format!("{}mb", mb)
+}
+
+/// A configuration file: its version and its settings, checked against the flags once read
+#[derive(Deserialize)]
+struct File {
+    version: Option<u64>,
+    #[serde(flatten)]
+    settings: BTreeMap<String, toml::Value>,
+}
+
+// flag names and values in their command line form
+type Settings = Vec<(String, String)>;
+
+/// Parse the file into its version and its settings
+fn parse(content: &str) -> Result<(Option<u64>, Settings), String> {
+    let file: File = toml::from_str(content).map_err(|e| e.to_string())?;
+    let settings = file
+        .settings
+        .into_iter()
+        .map(|(key, value)| {
+            let value = flag_value(&value).map_err(|e| format!("`{}`: {}", key, e))?;
+            Ok((key.replace('_', "-"), value))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((file.version, settings))
+}
+
+/// Export the settings of the file as the environment variables of their flags, unless set.
+/// Must run before any thread is spawned.
+pub(crate) fn apply(path: &Path, flags: &[(String, String)]) -> anyhow::Result<()> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("could not read {}: {}", path.display(), e))?;
+    let (version, settings) =
+        parse(&content).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let version = version.unwrap_or(1);
+    if !(1..=VERSION).contains(&version) {
+        anyhow::bail!(
+            "{}: version {} is not one this release reads, 1 to {}",
+            path.display(),
+            version,
+            VERSION
+        );
+    }
+    for (key, value) in settings {
+        if let Some(deprecated) = DEPRECATED.iter().find(|deprecated| deprecated.name == key) {
+            if version >= 2 {
+                anyhow::bail!(
+                    "{}: `{}` is `{}` since version 2",
+                    path.display(),
+                    key,
+                    deprecated.replacement
+                );
+            }
+        }
+        let (_, env) = flags
+            .iter()
+            .find(|(flag, _)| *flag == key)
+            .ok_or_else(|| anyhow::anyhow!("{}: unknown setting `{}`", path.display(), key))?;
+        if std::env::var_os(env).is_none() {
+            std::env::set_var(env, value);
+        }
+    }
+    Ok(())
+}
+
+/// Write the settings, flag names and values in their command line form, into the file: in the
+/// place of the lines setting them, at its end for the others. The file is brought to the current
+/// version on the way, its deprecated settings renamed, and replaced as a whole once written.
+pub(crate) fn rewrite(path: &Path, settings: &[(String, String)]) -> std::io::Result<()> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let values: BTreeMap<String, toml::Value> =
+        toml::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+    let mut document: DocumentMut = content
+        .parse()
+        .map_err(|e: toml_edit::TomlError| invalid(e.to_string()))?;
+    let root = document.as_table_mut();
+    // put back in the same order, so that a setting renamed keeps its place
+    let entries: Vec<(Key, Item)> = root
+        .iter()
+        .filter_map(|(name, _)| root.get_key_value(name))
+        .map(|(key, item)| (key.clone(), item.clone()))
+        .collect();
+    root.clear();
+    if !values.contains_key("version") {
+        root.insert("version", toml_edit::value(VERSION as i64));
+    }
+    let mut written = HashSet::new();
+    for (key, item) in entries {
+        let mut name = key.get().replace('_', "-");
+        let mut rewritten = None;
+        if name == "version" {
+            rewritten = Some(VERSION.to_string());
+        } else if let Some(deprecated) =
+            DEPRECATED.iter().find(|deprecated| deprecated.name == name)
+        {
+            // a value the node could not read is left for the next start to reject
+            if let Some(value) = values
+                .get(key.get())
+                .and_then(|value| flag_value(value).ok())
+            {
+                name = deprecated.replacement.to_string();
+                rewritten = Some((deprecated.migrate)(&value));
+            }
+        }
+        if let Some((_, value)) = settings.iter().find(|(setting, _)| *setting == name) {
+            rewritten = Some(value.clone());
+        }
+        let (Some(value), Item::Value(old)) = (rewritten, &item) else {
+            root.insert_formatted(&key, item);
+            continue;
+        };
+        // the comments of the line stay with it
+        let mut renamed = Key::new(name.clone());
+        *renamed.leaf_decor_mut() = key.leaf_decor().clone();
+        let mut value = toml_value(&value);
+        *value.decor_mut() = old.decor().clone();
+        root.insert_formatted(&renamed, Item::Value(value));
+        written.insert(name);
+    }
+    for (key, value) in settings {
+        if !written.contains(key) {
+            root.insert(key, Item::Value(toml_value(value)));
+        }
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".rewrite");
+    let mut file = std::fs::File::create(&temporary)?;
+    file.write_all(document.to_string().as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+/// A value in the form its flag takes it, arrays separated by spaces
+fn flag_value(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(string) => Ok(string.clone()),
+        toml::Value::Integer(integer) => Ok(integer.to_string()),
+        toml::Value::Float(float) => Ok(float.to_string()),
+        toml::Value::Boolean(boolean) => Ok(boolean.to_string()),
+        toml::Value::Datetime(datetime) => Ok(datetime.to_string()),
+        toml::Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::Array(_) | toml::Value::Table(_) => {
+                        Err("arrays hold strings, numbers or booleans".to_string())
+                    }
+                    item => flag_value(item),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(items.join(" "))
+        }
+        toml::Value::Table(_) => Err("tables are not supported".to_string()),
+    }
+}
+
+/// A value in the form of the file: integers and booleans as they are, other values as strings
+fn toml_value(value: &str) -> toml_edit::Value {
+    match value.parse::<i64>() {
+        Ok(integer) if integer.to_string() == value => integer.into(),
+        _ => match value.parse::<bool>() {
+            Ok(boolean) => boolean.into(),
+            Err(_) => value.into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewritten(content: &str, settings: &[(&str, &str)]) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("storgata.toml");
+        std::fs::write(&path, content).unwrap();
+        let settings: Vec<(String, String)> = settings
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        rewrite(&path, &settings).unwrap();
+        std::fs::read_to_string(&path).unwrap()
+    }
+
+    #[test]
+    fn rewrite_bumps_the_version() {
+        assert_eq!(
+            rewritten("max-clients = 10\n", &[]),
+            "version = 2\nmax-clients = 10\n"
+        );
+        assert_eq!(
+            rewritten("version = 1\nmax-clients = 10\n", &[]),
+            "version = 2\nmax-clients = 10\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_migrates_deprecated_settings() {
+        assert_eq!(
+            rewritten("read_cache_mb = 64\n", &[]),
+            "version = 2\nread-cache-size = \"64mb\"\n"
+        );
+        // a changed setting replaces the value of the deprecated one
+        assert_eq!(
+            rewritten("read-cache-mb = 64\n", &[("read-cache-size", "1gb")]),
+            "version = 2\nread-cache-size = \"1gb\"\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_keeps_comments_and_other_lines() {
+        let content = "# the node\n\
+                       version = 1\n\
+                       self-addr = \"10.0.0.1:8080\" # not a # comment\n\
+                       \n\
+                       max-clients = 10 # lowered for tests\n";
+        assert_eq!(
+            rewritten(content, &[("max-clients", "20"), ("log-level", "info")]),
+            "# the node\n\
+             version = 2\n\
+             self-addr = \"10.0.0.1:8080\" # not a # comment\n\
+             \n\
+             max-clients = 20 # lowered for tests\n\
+             log-level = \"info\"\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_creates_a_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("storgata.toml");
+        rewrite(
+            &path,
+            &[("write-timeout-ms".to_string(), "5000".to_string())],
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "version = 2\nwrite-timeout-ms = 5000\n"
+        );
+    }
+
+    #[test]
+    fn rewritten_values_read_back() {
+        for value in [
+            "plain",
+            "with \"quotes\"",
+            "a\\b",
+            "tab\tand\nline",
+            "# not a comment",
+            "10",
+            "010",
+            "true",
+            "yes",
+        ] {
+            let (_, settings) = parse(&format!("value = {}", toml_value(value))).unwrap();
+            assert_eq!(settings, [("value".to_string(), value.to_string())]);
+        }
+    }
+
+    fn parsed(content: &str) -> Vec<(String, String)> {
+        parse(content).unwrap().1
+    }
+
+    #[test]
+    fn parse_reads_quoted_and_escaped_strings() {
+        assert_eq!(
+            parsed("a = \"x \\\"y\\\" \\\\ \\t \\u00e9\"\nb = 'C:\\dir'\n\"c_d\" = \"\"\n"),
+            [
+                ("a".to_string(), "x \"y\" \\ \t \u{e9}".to_string()),
+                ("b".to_string(), "C:\\dir".to_string()),
+                ("c-d".to_string(), String::new()),
+            ]
+        );
+        assert!(parse("a = \"unterminated\n").is_err());
+        assert!(parse("a = \"\\q\"\n").is_err());
+    }
+
+    #[test]
+    fn parse_ends_comments_outside_of_strings() {
+        assert_eq!(
+            parsed("# the node\na = \"# kept\" # dropped\nb = 'x#y'#dropped\n"),
+            [
+                ("a".to_string(), "# kept".to_string()),
+                ("b".to_string(), "x#y".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_reads_arrays_and_scalars_as_flags_take_them() {
+        assert_eq!(
+            parsed(
+                "peer-addr = [\n  \"10.0.0.1:8080\", # the first\n  \"10.0.0.2:8080\",\n]\n\
+                 max-clients = 5_000\nratio = 0.5\ntls = true\nversion = 2\n"
+            ),
+            [
+                ("max-clients".to_string(), "5000".to_string()),
+                (
+                    "peer-addr".to_string(),
+                    "10.0.0.1:8080 10.0.0.2:8080".to_string()
+                ),
+                ("ratio".to_string(), "0.5".to_string()),
+                ("tls".to_string(), "true".to_string()),
+            ]
+        );
+        assert_eq!(parse("version = 2\n").unwrap().0, Some(2));
+        assert!(parse("a = [[1], [2]]\n").is_err());
+        assert!(parse("[node]\nmax-clients = 10\n").is_err());
+        assert!(parse("a = {b = 1}\n").is_err());
+        assert!(parse("a = 1\na = 2\n").is_err());
+    }
+
+    #[test]
+    fn apply_rejects_unknown_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("storgata.toml");
+        let flags = [(
+            "config-file-test".to_string(),
+            "STORGATA_CONFIG_FILE_TEST".to_string(),
+        )];
+        std::fs::write(&path, "version = 2\nconfig_file_test = [1, 'two']\n").unwrap();
+        apply(&path, &flags).unwrap();
+        assert_eq!(std::env::var("STORGATA_CONFIG_FILE_TEST").unwrap(), "1 two");
+        std::fs::write(&path, "config-file-test = 1\nunknown_setting = 2\n").unwrap();
+        let e = apply(&path, &flags).unwrap_err();
+        assert!(e.to_string().ends_with("unknown setting `unknown-setting`"));
+        std::fs::write(&path, "version = 3\n").unwrap();
+        assert!(apply(&path, &flags).is_err());
+    }
+}
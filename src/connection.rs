@@ -1,175 +1,2287 @@
+use crate::acl::{AclOp, Category, Permissions, DEFAULT_USER};
+use crate::admin::{self, AdminOp};
+use crate::analytics::SnapshotOp;
+use crate::audit::Record;
+use crate::chunk;
+use crate::clients::{Client, ClientOp};
+use crate::cluster::{self, ClusterOp};
 use crate::cmd;
-use crate::cmd::InnerCmd;
-use crate::resp_codec::{RespCodec, RespValue};
-use crate::sync_layer::SyncRequest;
+use crate::cmd::{DebugOp, InnerCmd, NodeContext};
+use crate::config::{ConfigOp, Consistency, Oversize};
+use crate::database;
+use crate::dump;
+use crate::ephemeral::EphemeralOp;
+use crate::failpoint;
+use crate::feature::{FeatureOp, Features};
+use crate::flush::{self, FlushAllOp};
+use crate::keyspace::{self, Snapshot};
+use crate::memory::{self, Memory};
+use crate::monitor::Monitoring;
+use crate::overload;
+use crate::prefix_stats::Tally;
+use crate::pubsub::{glob_match, Subscription};
+use crate::raft_log::{self, RaftOp};
+use crate::recovery;
+use crate::resp_codec::{Limits, Protocol, RespCodec, RespValue};
+use crate::script;
+use crate::scrubber::ScrubStats;
+use crate::shred::Logged;
+use crate::slo::{CommandFamily, Outcome, SloTracker};
+use crate::slowlog::SlowLogOp;
+use crate::stages::Stages;
+use crate::standby;
+use crate::stats::Stats;
+use crate::sync_layer::{self, RequestId, SyncRequest, Syncable};
+use crate::value;
+use crate::view::{self, View};
+use crate::wait;
 use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use std::collections::HashSet;
+use std::future::{ready, Future};
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::io::{BufReader, ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio::time::{timeout, Duration};
-use tracing::info;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf, ReadHalf};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{info, info_span, warn, Instrument, Span};
+use uuid::Uuid;
 
 #[derive(Error, Debug)]
 pub(crate) enum ConnectionError {
     #[error("Incomplete data")]
     IncompleteData,
-    #[error("Unrecognized type")]
+    #[error("Protocol error: unrecognized type")]
     UnrecognizedType,
+    #[error("Protocol error: unbalanced quotes in request")]
+    UnbalancedQuotes,
+    #[error("Protocol error: {0}")]
+    ProtocolLimit(&'static str),
     #[error("IO error")]
     IoError(#[from] std::io::Error),
-    #[error("Int unable to parse")]
+    #[error("Protocol error: invalid length")]
     ParseIntError(#[from] std::num::ParseIntError),
-    #[error("Not valid UTF8")]
-    Utf8Error(#[from] std::string::FromUtf8Error),
+    #[error("Protocol error: invalid UTF8")]
+    Utf8Error(#[from] std::str::Utf8Error),
 }
 
-pub(crate) struct Connection {
-    reader: BufReader<ReadHalf<TcpStream>>,
-    writer: WriteHalf<TcpStream>,
-    codec: RespCodec,
+/// Replies queued ahead of the writer before the connection stops reading commands
+const MAX_QUEUED_REPLIES: usize = 1024;
+
+/// AUTH or HELLO AUTH with credentials no enabled user has
+const WRONGPASS: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+
+/// CLIENT SETNAME or HELLO SETNAME with a name that would break the fields of CLIENT LIST
+const INVALID_CLIENT_NAME: &str =
+    "ERR Client names cannot contain spaces, newlines or special characters.";
+
+/// SELECT or SWAPDB naming a database past --databases
+const DB_OUT_OF_RANGE: &str = "ERR DB index is out of range";
+
+/// A reply queued for the writer, in the order of the commands
+enum Outgoing {
+    Ready(RespValue),
+    /// Computed once the replies queued before it are, e.g. a write waiting for the sync layer
+    Pending(BoxFuture<'static, RespValue>),
+    /// Encode the following replies with this protocol
+    Protocol(Protocol),
+}
+
+/// A client stream counting the bytes read and written in the node statistics
+struct Metered<S> {
+    stream: S,
+    stats: Arc<Stats>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.stream).poll_read(cx, buf);
+        self.stats.net_input(buf.filled().len() - before);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.stats.net_output(written);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Write the queued replies in order. Replies are only flushed once the next one is not ready,
+/// so the replies to pipelined commands share writes to the socket.
+async fn write_replies<W: AsyncWrite + Unpin>(
+    mut writer: FramedWrite<W, RespCodec>,
+    mut replies: mpsc::Receiver<Outgoing>,
+) -> Result<(), ConnectionError> {
+    while let Some(outgoing) = replies.recv().await {
+        let msg = match outgoing {
+            Outgoing::Ready(msg) => msg,
+            Outgoing::Pending(mut reply) => match futures::poll!(&mut reply) {
+                Poll::Ready(msg) => msg,
+                Poll::Pending => {
+                    writer.flush().await?;
+                    reply.await
+                }
+            },
+            Outgoing::Protocol(protocol) => {
+                writer.encoder_mut().set_protocol(protocol);
+                continue;
+            }
+        };
+        writer.feed(msg).await?;
+        if replies.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await
+}
+
+/// Reply to a command that failed against the storage
+pub(crate) fn error_reply(e: &BitCaskError) -> RespValue {
+    if value::is_wrong_type(e) || dump::is_busy_key(e) || script::is_no_script(e) {
+        // a Redis error code, clients match on it
+        RespValue::Error(e.to_string())
+    } else {
+        RespValue::Error(format!("ERR {}", e))
+    }
+}
+
+/// The reply to a write with the index of the log the node applied once it was, for CLIENT
+/// WATERMARK: a read served by any node that applied as far sees the write
+fn with_applied(applied: u64, msg: RespValue) -> RespValue {
+    RespValue::Attribute(
+        vec![(
+            RespValue::BulkString(Some(Bytes::from_static(b"applied"))),
+            RespValue::Integer(applied as i64),
+        )],
+        Box::new(msg),
+    )
+}
+
+/// Names show up in CLIENT LIST, which separates fields with spaces
+fn valid_client_name(name: &str) -> bool {
+    name.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Serve an in-memory connection, which ADMIN BROADCAST and the HTTP gateway run their commands
+/// on this node with
+pub(crate) fn serve_local(mut connection: Connection<DuplexStream>) -> BoxFuture<'static, ()> {
+    Box::pin(async move {
+        let addr = connection.client.addr();
+        if let Err(e) = connection.handle(addr).await {
+            warn!("Local connection error: {}", e);
+        }
+    })
+}
+
+/// The next command shown to a monitoring connection, never if it is not monitoring
+async fn monitored(monitor: &mut Option<Monitoring>) -> String {
+    if let Some(monitor) = monitor {
+        loop {
+            match monitor.recv().await {
+                Ok(line) => return line,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Monitor: client is too slow, dropped {} commands", skipped)
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// A client connection. Commands are decoded and dispatched as they arrive, without waiting
+/// for the replies of earlier commands, and a writer task sends the replies back in order.
+/// The connection is generic over the stream, so plain TCP and any wrapping of it are served alike.
+pub(crate) struct Connection<S> {
+    reader: FramedRead<ReadHalf<Metered<S>>, RespCodec>,
+    replies: mpsc::Sender<Outgoing>,
+    // protocol of the replies queued so far
+    protocol: Protocol,
+    // fires once the last queued read ran, later writes must not be applied before it
+    read_barrier: Option<oneshot::Receiver<()>>,
     storage_handle: BitCask,
     sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+    slo: Arc<SloTracker>,
+    scrub_stats: Arc<ScrubStats>,
+    subscription: Subscription,
+    // the user the client authenticated as, None until it does
+    user: Option<String>,
+    // what the user may run, as last read from the ACL
+    permissions: Option<Permissions>,
+    // the user and password of the last AUTH, which ADMIN BROADCAST runs its command with
+    credentials: Option<(String, String)>,
+    // this connection in the registry of the node
+    client: Arc<Client>,
+    // serving a snapshot for analytics, which only reads
+    read_only: bool,
+    // writes waiting for their reply past which the next ones are rejected, 0 for no limit
+    max_in_flight_writes: usize,
+    // the commands the node runs, once the client asked to MONITOR them
+    monitor: Option<Monitoring>,
+    // the command being handled, as received and when, until the slow log sees it
+    command: Option<(std::time::Instant, RespValue)>,
+    // the command being handled if its key is under a metrics prefix, until its reply is ready
+    tally: Option<Tally>,
+    // whether the command being handled is a SET IFCHANGED
+    if_changed: bool,
+    // the request id the client chose for the command being handled, with ID
+    client_id: Option<RequestId>,
+    // the database SELECT picked, 0 until then
+    db: u32,
+    // whether the replies come with the stages of their command, per CLIENT TRACE
+    trace: bool,
+    // the stages of the command being handled if traced, until its reply is queued
+    stages: Option<Stages>,
+    // whether the replies to the writes come with the applied index, per CLIENT WATERMARK
+    watermark: bool,
+    // identifies the connection as the owner of the keys it sets EPHEMERAL
+    session: RequestId,
+    // the keys set EPHEMERAL, as stored, whose lease the connection renews until it closes
+    ephemeral: HashSet<Vec<u8>>,
+    // the read view of SNAPSHOT OPEN, until SNAPSHOT CLOSE
+    view: Option<View>,
+    context: NodeContext,
 }
 
-impl Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection<S> {
     pub(crate) fn new(
-        stream: TcpStream,
+        stream: S,
+        addr: SocketAddr,
         storage_handle: BitCask,
         sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+        slo: Arc<SloTracker>,
+        scrub_stats: Arc<ScrubStats>,
+        context: NodeContext,
     ) -> Self {
+        context.stats.connection_received();
+        let stream = Metered {
+            stream,
+            stats: context.stats.clone(),
+        };
         let (reader, writer) = tokio::io::split(stream);
-        let buf_reader = tokio::io::BufReader::new(reader);
+        let (replies, queued) = mpsc::channel(MAX_QUEUED_REPLIES);
+        // the writer drains the queue after the connection is dropped, then closes the socket
+        tokio::spawn(async move {
+            if let Err(e) = write_replies(FramedWrite::new(writer, RespCodec::new()), queued).await
+            {
+                info!("Could not write replies: {:?}", e);
+            }
+        });
         Self {
-            reader: buf_reader,
-            writer,
+            reader: FramedRead::new(reader, RespCodec::new()),
+            replies,
+            protocol: Protocol::Resp2,
+            read_barrier: None,
             storage_handle,
-            codec: RespCodec::new(),
             sync_request_tx,
+            slo,
+            scrub_stats,
+            subscription: context.broker.subscription(),
+            user: context.acl.auto_login(),
+            permissions: None,
+            credentials: None,
+            client: context.clients.register(addr),
+            read_only: false,
+            max_in_flight_writes: 0,
+            monitor: None,
+            command: None,
+            tally: None,
+            if_changed: false,
+            client_id: None,
+            db: 0,
+            trace: false,
+            stages: None,
+            watermark: false,
+            session: *Uuid::new_v4().as_bytes(),
+            ephemeral: HashSet::new(),
+            view: None,
+            context,
         }
     }
 
+    /// Only serve the reads, from a snapshot of the analytics port
+    pub(crate) fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Close the connection with a protocol error once the client sends past the limits
+    pub(crate) fn limits(mut self, limits: Limits) -> Self {
+        self.reader.decoder_mut().set_limits(limits);
+        self
+    }
+
+    /// Reject the writes with BUSY while that many wait for their reply
+    pub(crate) fn max_in_flight_writes(mut self, max: usize) -> Self {
+        self.max_in_flight_writes = max;
+        self
+    }
+
     #[tracing::instrument(level = "debug", skip(self))]
     pub(crate) async fn handle(&mut self, addr: SocketAddr) -> Result<(), ConnectionError> {
         info!("Handling connection from {}", addr);
+        // this connection is already registered
+        if self.context.clients.count() > self.context.config.max_clients() {
+            let msg = RespValue::Error("ERR max number of clients reached".to_string());
+            self.reply(msg).await?;
+            return Ok(());
+        }
+        // when the client last sent a command
+        let mut active = Instant::now();
+        // when the leases of the keys set EPHEMERAL were last renewed
+        let mut renewed = Instant::now();
         loop {
-            match self.codec.decode(&mut self.reader).await {
-                Ok(res) => {
-                    let cmd = cmd::Cmd::from(res.clone());
+            let lease = Duration::from_millis(self.context.config.ephemeral_lease_ms());
+            // subscribers and monitors wait for the node, they are never idle
+            let idle = self
+                .context
+                .config
+                .timeout()
+                .filter(|_| !self.subscription.is_active() && self.monitor.is_none());
+            let view_deadline = self.view.as_ref().and_then(View::deadline);
+            let frame = tokio::select! {
+                // in subscriber mode, or while tracking keys, push the published messages and the
+                // invalidations until the client sends a command; a partially received command
+                // stays buffered in the framed reader
+                msg = self.subscription.recv(),
+                    if self.subscription.is_active() || self.subscription.is_tracking() => {
+                    self.reply(msg).await?;
+                    continue;
+                }
+                // a monitor is sent the commands the same way
+                line = monitored(&mut self.monitor) => {
+                    self.reply(RespValue::SimpleString(line)).await?;
+                    continue;
+                }
+                frame = self.reader.next() => frame,
+                _ = self.client.killed() => None,
+                // renewed well before they lapse, a renewal lost in a leader change is retried
+                _ = tokio::time::sleep_until(renewed + lease / 3), if !self.ephemeral.is_empty() => {
+                    self.renew_ephemeral(lease).await;
+                    renewed = Instant::now();
+                    continue;
+                }
+                // the files of a view are released once its time is up
+                _ = tokio::time::sleep_until(view_deadline.unwrap_or(active)), if view_deadline.is_some() => {
+                    if let Some(view) = &mut self.view {
+                        view.expire();
+                    }
+                    continue;
+                }
+                // a client that went away without closing its connection would hold it forever
+                _ = tokio::time::sleep_until(active + idle.unwrap_or_default()), if idle.is_some() => {
+                    // nor is a client waiting for its writes
+                    if self.client.in_flight_writes() > 0 {
+                        active = Instant::now();
+                        continue;
+                    }
+                    info!("Closing connection {} idle for {:?}", addr, idle.unwrap_or_default());
+                    return Ok(());
+                }
+            };
+            match frame {
+                // like Redis, an empty or null multibulk is no command, and is not replied to
+                Some(Ok(RespValue::Array(args))) if args.is_empty() => continue,
+                Some(Ok(RespValue::NullArray)) => continue,
+                Some(Ok(res)) => {
+                    active = Instant::now();
+                    let started = std::time::Instant::now();
+                    let mut cmd = cmd::Cmd::from(res.clone());
+                    cmd.jitter_ttl(self.context.config.ttl_jitter_percent());
+                    cmd.lease(self.session, self.context.config.ephemeral_lease_ms());
+                    self.if_changed = cmd.if_changed();
+                    self.client_id = cmd.client_id();
                     // the command could be well formatted but unknown
                     let parsed_inner_cmd = InnerCmd::new(cmd);
                     // if unknown command, here we will get an error
                     match parsed_inner_cmd {
                         Ok(inner_cmd) => {
-                            self.handle_valid_cmd(inner_cmd).await?;
+                            if self.trace {
+                                let stages = Stages::start(started);
+                                stages.mark("parse");
+                                self.stages = Some(stages);
+                            }
+                            // a trace per command rather than per connection, which may last days
+                            let span = info_span!(
+                                parent: None,
+                                "command",
+                                name = inner_cmd.name(),
+                                client_id = self.client.id
+                            );
+                            // the slow log and the monitors never see the keys under secure delete
+                            self.tally = self.context.prefix_stats.tally(
+                                inner_cmd.key().map(Vec::as_slice),
+                                inner_cmd.name(),
+                                started,
+                                &res,
+                            );
+                            if !self.context.secure_delete.hides(&inner_cmd) {
+                                self.command = Some((started, res));
+                            }
+                            self.handle_valid_cmd(inner_cmd).instrument(span).await?;
+                            self.stages = None;
+                            self.tally = None;
+                            self.if_changed = false;
+                            self.client_id = None;
+                            // unless its reply is deferred, the command is done
+                            if let Some((started, frame)) = self.command.take() {
+                                self.context.slowlog.record(
+                                    &self.context.config,
+                                    started.elapsed(),
+                                    &frame,
+                                    &self.client,
+                                );
+                            }
                         }
-                        Err(_) => {
-                            let msg = RespValue::Error(format!("Err unknown command {:?}", res));
+                        Err(e) => {
+                            let msg = RespValue::Error(cmd::rejection(&res, &e));
                             // encode error must be IO error, so we can safely return here
-                            self.codec.encode(&mut self.writer, &msg).await?;
+                            self.reply(msg).await?;
                         }
                     }
                 }
-                // Could be EOF or other errors
-                Err(e) => {
-                    match e {
-                        // If IO error is encountered, the connection should be closed
-                        ConnectionError::IoError(e) => {
-                            return if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                                info!("Connection closed by client");
-                                Ok(())
-                            } else {
-                                Err(e.into())
-                            }
-                        }
-                        // Else, just log the error to client and continue since the command is not well formatted
-                        _ => {
-                            let msg = RespValue::Error(format!("Err {:?}", e));
-                            self.codec.encode(&mut self.writer, &msg).await?;
-                        }
-                    }
+                None => {
+                    info!("Connection closed by client");
+                    return Ok(());
+                }
+                // most TLS clients close the socket without a close_notify, which is no error
+                Some(Err(ConnectionError::IoError(e)))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    info!("Connection closed by client");
+                    return Ok(());
+                }
+                // If IO error is encountered, the connection should be closed
+                Some(Err(ConnectionError::IoError(e))) => {
+                    self.log_history(&e);
+                    return Err(e.into());
+                }
+                // Else the stream is out of sync with the protocol, so like Redis reply with
+                // the error and close the connection
+                Some(Err(e)) => {
+                    self.log_history(&e);
+                    let msg = RespValue::Error(format!("ERR {}", e));
+                    self.reply(msg).await?;
+                    return Ok(());
                 }
             }
         }
     }
 
+    /// Log the last commands of the client, to tell what it did right before the error
+    fn log_history(&self, error: &dyn std::fmt::Display) {
+        warn!(
+            "Connection {} of client {} failed with {}, after [{}]",
+            self.client.addr(),
+            self.client.id,
+            error,
+            self.client.history().join(", ")
+        );
+    }
+
     pub(crate) async fn handle_valid_cmd(
         &mut self,
-        inner_cmd: InnerCmd,
+        mut inner_cmd: InnerCmd,
     ) -> Result<(), ConnectionError> {
-        info!("Handling command: {:?}", inner_cmd);
-        match inner_cmd {
-            InnerCmd::Get(_, key) => {
-                self.handle_read(key).await?;
+        info!(
+            "Handling command: {:?}",
+            self.context.secure_delete.logged(&inner_cmd)
+        );
+        let key = inner_cmd.key().map(Vec::as_slice);
+        // the command itself is still recorded, so that the client shows what it was doing
+        let key = key.filter(|key| !self.context.secure_delete.covers(key));
+        self.client.record_command(inner_cmd.name(), key);
+        self.context.stats.command_processed();
+        let family = inner_cmd.family();
+        // permissions are checked before the command reaches the storage or the sync layer
+        if let Err(e) = self.check_permissions(&inner_cmd) {
+            self.reply(RespValue::Error(e)).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        if self.read_only && !matches!(inner_cmd.category(), None | Some(Category::Read)) {
+            let msg = RespValue::Error(format!(
+                "ERR '{}' is not served from a snapshot, only reads are",
+                inner_cmd.name()
+            ));
+            self.reply(msg).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        if self.context.standby.is_active()
+            && matches!(
+                inner_cmd.category(),
+                Some(Category::Read | Category::Write | Category::PubSub)
+            )
+        {
+            self.reply(RespValue::Error(standby::STANDBY.to_string()))
+                .await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        if self.context.maintenance.is_on()
+            && matches!(
+                inner_cmd.category(),
+                Some(Category::Read | Category::Write | Category::PubSub)
+            )
+        {
+            let key = inner_cmd.key().map(Vec::as_slice);
+            let msg = self.context.maintenance.redirect(&self.context.peers, key);
+            self.reply(RespValue::Error(msg)).await?;
+            self.slo.record(family, Outcome::Error);
+            // the client reconnects elsewhere once it has the replies to its earlier commands
+            self.client.close();
+            return Ok(());
+        }
+        if !self.context.replica_of.is_empty() && family == CommandFamily::Write {
+            let msg = RespValue::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            );
+            self.reply(msg).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        if let Err(e) = self
+            .context
+            .hooks
+            .before_execute(&inner_cmd, self.user.as_deref())
+        {
+            self.reply(RespValue::Error(e)).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        if let Some(feature) = inner_cmd.feature() {
+            if !self.context.features.enabled(feature) {
+                let msg = RespValue::Error(format!(
+                    "ERR feature '{}' is disabled, FEATURE ENABLE turns it on",
+                    feature.name()
+                ));
+                self.reply(msg).await?;
+                self.slo.record(family, Outcome::Error);
+                return Ok(());
             }
-            InnerCmd::Put(_, _, _, _) | InnerCmd::Del(_, _) => {
-                self.handle_write(inner_cmd).await?;
+        }
+        // RESP3 clients may run any command while subscribed, as pushed messages are told apart
+        if self.subscription.is_active()
+            && self.protocol == Protocol::Resp2
+            && !inner_cmd.allowed_in_subscriber_mode()
+        {
+            let msg = RespValue::Error(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING are allowed in this context",
+                inner_cmd.name()
+            ));
+            self.reply(msg).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        // as in Redis, the admin commands are not shown to monitors
+        if !matches!(inner_cmd.category(), Some(Category::Admin)) {
+            if let Some((_, frame)) = &self.command {
+                self.context.monitor.feed(frame, self.client.addr());
             }
-            InnerCmd::Ping => {
-                self.handle_ping().await?;
+        }
+        // the ACL and the hooks saw the keys as the client sent them
+        inner_cmd.select(self.db);
+        // released once the connection closes, whether the SET succeeds or not
+        if let InnerCmd::Ephemeral(_, EphemeralOp::Set(key, _, _, _)) = &inner_cmd {
+            self.ephemeral.insert(key.clone());
+        }
+        if family == CommandFamily::Read {
+            for key in inner_cmd.keys() {
+                self.subscription.track(key);
             }
         }
+        // the reads a view serves are read from it, as of when it was opened
+        if self.view.is_some()
+            && matches!(
+                inner_cmd,
+                InnerCmd::Get(_, _, _) | InnerCmd::MGetSnapshot(_, _) | InnerCmd::Keys(_, _)
+            )
+        {
+            return self.handle_view_read(family, inner_cmd).await;
+        }
+        // the other reads of the keyspace fail, rather than quietly reading it live
+        if self.view.is_some()
+            && family == CommandFamily::Read
+            && !matches!(inner_cmd, InnerCmd::Script(_, _))
+        {
+            let msg = RespValue::Error(format!(
+                "ERR '{}' is not read from the read view, SNAPSHOT CLOSE it first",
+                inner_cmd.name()
+            ));
+            self.reply(msg).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        // reads and writes record their outcome once their reply is computed
+        let outcome = match inner_cmd {
+            InnerCmd::Get(_, key, consistency) => {
+                return self.handle_read(family, key, consistency).await
+            }
+            InnerCmd::Keys(_, pattern) => return self.handle_keys(family, pattern).await,
+            InnerCmd::DbSize(_) => return self.handle_dbsize(family).await,
+            InnerCmd::SwapDb(_, first, second) if first.max(second) >= self.context.databases => {
+                self.reply(RespValue::Error(DB_OUT_OF_RANGE.to_string()))
+                    .await?;
+                Outcome::Error
+            }
+            InnerCmd::GetRange(_, _, _, _)
+            | InnerCmd::GetBit(_, _, _)
+            | InnerCmd::BitCount(_, _, _)
+            | InnerCmd::BitPos(_, _, _, _)
+            | InnerCmd::LRange(_, _, _, _)
+            | InnerCmd::LLen(_, _)
+            | InnerCmd::ZScore(_, _, _)
+            | InnerCmd::ZRange(_, _, _, _, _)
+            | InnerCmd::ZRangeByScore(_, _, _, _, _)
+            | InnerCmd::BfExists(_, _, _)
+            | InnerCmd::CmsQuery(_, _, _)
+            | InnerCmd::TopKList(_, _, _)
+            | InnerCmd::MGetSnapshot(_, _)
+            | InnerCmd::Dump(_, _) => return self.handle_local_read(family, inner_cmd).await,
+            InnerCmd::Plugin(_, _, _) | InnerCmd::Script(_, _) if family == CommandFamily::Read => {
+                return self.handle_local_read(family, inner_cmd).await
+            }
+            InnerCmd::Put(_, _, _, _)
+            | InnerCmd::SetGet(_, _, _, _)
+            | InnerCmd::Chunk(_, _, _, _)
+            | InnerCmd::PutChunked(_, _, _, _)
+            | InnerCmd::GetDel(_, _, _)
+            | InnerCmd::Cas(_, _, _, _, _)
+            | InnerCmd::GetEx(_, _, _, _)
+            | InnerCmd::SetBit(_, _, _, _, _)
+            | InnerCmd::Del(_, _, _)
+            | InnerCmd::Copy(_, _, _, _, _)
+            | InnerCmd::Rename(_, _, _, _, _)
+            | InnerCmd::Restore(_, _, _, _, _, _)
+            | InnerCmd::Flush(_, _, _)
+            | InnerCmd::SwapDb(_, _, _)
+            | InnerCmd::Push(_, _, _, _, _)
+            | InnerCmd::Pop(_, _, _, _, _)
+            | InnerCmd::ZAdd(_, _, _, _)
+            | InnerCmd::ZRem(_, _, _, _)
+            | InnerCmd::BfAdd(_, _, _, _, _)
+            | InnerCmd::CmsInitByDim(_, _, _, _, _)
+            | InnerCmd::CmsIncrBy(_, _, _, _)
+            | InnerCmd::TopKReserve(_, _, _, _, _, _)
+            | InnerCmd::TopKAdd(_, _, _, _)
+            | InnerCmd::Publish(_, _, _)
+            | InnerCmd::SPublish(_, _, _)
+            | InnerCmd::Proc(_, _)
+            | InnerCmd::Script(_, _)
+            | InnerCmd::Lock(_, _)
+            | InnerCmd::If(_, _, _, _, _)
+            | InnerCmd::Ephemeral(_, _)
+            | InnerCmd::Plugin(_, _, _) => {
+                // replied to in turn, like a write, though nothing is proposed
+                if self.if_changed && self.unchanged(&inner_cmd) {
+                    self.context.stats.write_skipped_unchanged();
+                    let reply = RespValue::SimpleString("OK".to_string());
+                    return self.defer(family, ready((reply, Outcome::Success))).await;
+                }
+                // deletions free space and relieve the node, they are never shed
+                if self.context.overload.shedding()
+                    && !matches!(inner_cmd, InnerCmd::Del(_, _, _) | InnerCmd::Flush(_, _, _))
+                {
+                    self.context.overload.write_shed();
+                    self.reply(RespValue::Error(overload::BUSY.to_string()))
+                        .await?;
+                    Outcome::Error
+                } else if let Some(busy) = self.backpressure() {
+                    self.context.overload.write_shed();
+                    self.reply(RespValue::Error(busy.to_string())).await?;
+                    Outcome::Error
+                } else if self.context.raft_stats.halted() {
+                    self.reply(RespValue::Error(sync_layer::CLUSTERDOWN.to_string()))
+                        .await?;
+                    Outcome::Error
+                } else {
+                    return self.handle_write(family, inner_cmd).await;
+                }
+            }
+            InnerCmd::Subscribe(_)
+            | InnerCmd::Unsubscribe(_)
+            | InnerCmd::PSubscribe(_)
+            | InnerCmd::PUnsubscribe(_)
+            | InnerCmd::SSubscribe(_)
+            | InnerCmd::SUnsubscribe(_) => self.handle_subscription(inner_cmd).await?,
+            InnerCmd::Hello(protover, auth, setname) => {
+                self.handle_hello(protover, auth, setname).await?
+            }
+            InnerCmd::Select(db) => self.handle_select(db).await?,
+            InnerCmd::BgSave => self.handle_bgsave().await?,
+            InnerCmd::LastSave => self.handle_lastsave().await?,
+            InnerCmd::Compact => self.handle_compact().await?,
+            InnerCmd::Promote => self.handle_promote().await?,
+            InnerCmd::Info(section) => self.handle_info(section).await?,
+            InnerCmd::Cluster(op) => self.handle_cluster(op).await?,
+            InnerCmd::Client(op) => self.handle_client(op).await?,
+            InnerCmd::Config(op) => self.handle_config(op).await?,
+            InnerCmd::Debug(op) => self.handle_debug(op).await?,
+            InnerCmd::Auth(user, password) => self.handle_auth(user, password).await?,
+            InnerCmd::Acl(op) => self.handle_acl(op).await?,
+            InnerCmd::Shutdown(mode) => {
+                // the connection is closed by the exit, without a reply as in Redis
+                self.context.shutdown.request(mode);
+                Outcome::Success
+            }
+            InnerCmd::Snapshot(SnapshotOp::Open(secs)) => {
+                return self.handle_view_open(family, secs).await
+            }
+            InnerCmd::Snapshot(op) => self.handle_snapshot(op).await?,
+            InnerCmd::SlowLog(op) => self.handle_slowlog(op).await?,
+            InnerCmd::Raft(op) => self.handle_raft(op).await?,
+            InnerCmd::Export(pattern) => return self.handle_export(family, pattern).await,
+            InnerCmd::Monitor => self.handle_monitor().await?,
+            InnerCmd::Admin(op) => return self.handle_admin(family, op).await,
+            InnerCmd::Feature(id, op) => return self.handle_feature(family, id, op).await,
+            InnerCmd::FlushAll(id, op) => return self.handle_flushall(family, id, op).await,
+            InnerCmd::Wait(replicas, timeout) => {
+                return self.handle_wait(family, replicas, timeout).await
+            }
+            InnerCmd::Ping => self.handle_ping().await?,
+            InnerCmd::ReadBarrier(_) | InnerCmd::Evict(_, _) => {
+                unreachable!("never sent by clients")
+            }
+        };
+        self.slo.record(family, outcome);
         Ok(())
     }
 
+    /// Check that the user of the connection may run the command, returning the error to reply otherwise
+    fn check_permissions(&mut self, inner_cmd: &InnerCmd) -> Result<(), String> {
+        // no ACL opens the keys of the node, nor those of another database, to a client
+        if inner_cmd.keys().iter().any(|key| database::reserved(key)) {
+            return Err(database::RESERVED.to_string());
+        }
+        let Some(user) = &self.user else {
+            return match inner_cmd {
+                InnerCmd::Auth(_, _) | InnerCmd::Hello(_, _, _) => Ok(()),
+                _ => Err("NOAUTH Authentication required.".to_string()),
+            };
+        };
+        match inner_cmd.category() {
+            Some(category) => self.context.acl.check(
+                &mut self.permissions,
+                user,
+                inner_cmd.name(),
+                category,
+                &inner_cmd.keys(),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Queue a reply for the writer
+    async fn reply(&mut self, msg: RespValue) -> Result<(), ConnectionError> {
+        if let Some(tally) = self.tally.take() {
+            self.context.prefix_stats.record(tally, &msg, self.protocol);
+        }
+        let msg = match self.stages.take() {
+            Some(stages) => stages.attach(msg),
+            None => msg,
+        };
+        self.queue(Outgoing::Ready(msg)).await
+    }
+
+    /// Queue a reply computed once the replies to the earlier commands are, then record its outcome
+    async fn defer(
+        &mut self,
+        family: CommandFamily,
+        reply: impl Future<Output = (RespValue, Outcome)> + Send + 'static,
+    ) -> Result<(), ConnectionError> {
+        let slo = self.slo.clone();
+        let stages = self.stages.take();
+        // only what the reply needs is cloned, not the whole context, as every read comes here
+        let tally = self
+            .tally
+            .take()
+            .map(|tally| (tally, self.context.prefix_stats.clone()));
+        let command = self.command.take().map(|command| {
+            let (slowlog, config) = (&self.context.slowlog, &self.context.config);
+            (command, slowlog.clone(), config.clone())
+        });
+        let (protocol, client) = (self.protocol, self.client.clone());
+        // the span of the command lasts until its reply is computed
+        self.queue(Outgoing::Pending(Box::pin(
+            async move {
+                let (msg, outcome) = reply.await;
+                slo.record(family, outcome);
+                if let Some((tally, prefix_stats)) = tally {
+                    prefix_stats.record(tally, &msg, protocol);
+                }
+                if let Some(((started, frame), slowlog, config)) = command {
+                    slowlog.record(&config, started.elapsed(), &frame, &client);
+                }
+                match stages {
+                    Some(stages) => stages.attach(msg),
+                    None => msg,
+                }
+            }
+            .instrument(Span::current()),
+        )))
+        .await
+    }
+
+    /// Queue a read, computed in turn like any deferred reply. Unless the consistency, or the
+    /// read-consistency setting if None, is local, an entry is replicated first and the read
+    /// waits for the node to apply it: the writes committed anywhere before the read started are
+    /// applied by then.
+    async fn defer_read(
+        &mut self,
+        family: CommandFamily,
+        consistency: Option<Consistency>,
+        read: impl FnOnce() -> (RespValue, Outcome) + Send + 'static,
+    ) -> Result<(), ConnectionError> {
+        let consistency = consistency.unwrap_or_else(|| self.context.config.read_consistency());
+        // a snapshot served for analytics never sees later writes anyway, and a read replica has
+        // no say in the order of the log, it serves what it applied so far
+        let replica = !self.context.replica_of.is_empty();
+        let applied = if consistency.needs_barrier() && !self.read_only && !replica {
+            let deadline = Instant::now() + self.context.config.write_timeout();
+            let inner_cmd = InnerCmd::ReadBarrier(*Uuid::new_v4().as_bytes());
+            let round_trip = info_span!(
+                "read_barrier",
+                request_id = %Uuid::from_bytes(inner_cmd.get_request_id())
+            );
+            Some((
+                self.propose(inner_cmd, deadline, &round_trip).await,
+                deadline,
+            ))
+        } else {
+            None
+        };
+        let (done, barrier) = oneshot::channel();
+        self.read_barrier = Some(barrier);
+        let stages = self.stages.clone();
+        self.defer(family, async move {
+            if let Some((applied, deadline)) = applied {
+                match timeout_at(deadline, applied).await {
+                    Ok(Ok(_)) => {}
+                    // the sync layer dropped the answer channel before the deadline
+                    Ok(Err(_)) if Instant::now() < deadline => {
+                        return (
+                            RespValue::Error(sync_layer::CLUSTERDOWN.to_string()),
+                            Outcome::Error,
+                        )
+                    }
+                    _ => {
+                        return (
+                            RespValue::Error("Request timeout".to_string()),
+                            Outcome::Timeout,
+                        )
+                    }
+                }
+            }
+            let reply = read();
+            if let Some(stages) = stages {
+                stages.mark("read");
+            }
+            let _ = done.send(());
+            reply
+        })
+        .await
+    }
+
+    async fn set_protocol(&mut self, protocol: Protocol) -> Result<(), ConnectionError> {
+        self.protocol = protocol;
+        self.queue(Outgoing::Protocol(protocol)).await
+    }
+
+    async fn queue(&mut self, outgoing: Outgoing) -> Result<(), ConnectionError> {
+        // the writer only stops early if the socket can no longer be written
+        self.replies
+            .send(outgoing)
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe).into())
+    }
+
     /// Read the value from the storage and send it back to the client
-    /// We don't need to synchronize the read operation with peers
-    pub(crate) async fn handle_read(&mut self, key: Vec<u8>) -> Result<(), ConnectionError> {
-        let value = self.storage_handle.get(&key);
-        // value could be None, and it will be encoded as `$-1`
-        let msg = RespValue::BulkString(value);
-        // encode Error must be IO error, so we can safely return here
-        self.codec.encode(&mut self.writer, &msg).await?;
-        Ok(())
+    /// The read is only synchronized with peers at a consistency stronger than local, but it is
+    /// deferred until the earlier commands of the client are replied to, so it observes their
+    /// writes, and the later writes of the client wait for it
+    pub(crate) async fn handle_read(
+        &mut self,
+        family: CommandFamily,
+        key: Vec<u8>,
+        consistency: Option<Consistency>,
+    ) -> Result<(), ConnectionError> {
+        let storage_handle = self.storage_handle.clone();
+        let read_cache = self.context.read_cache.clone();
+        let negative_cache = self.context.negative_cache.clone();
+        let memory = self.lru_memory();
+        self.defer_read(family, consistency, move || {
+            if let Some(memory) = &memory {
+                memory.accessed(&key);
+            }
+            let load = || match &negative_cache {
+                Some(negative_cache) => {
+                    negative_cache.get_or_load(&key, || storage_handle.get(&key))
+                }
+                None => storage_handle.get(&key),
+            };
+            let stored = match &read_cache {
+                Some(read_cache) => read_cache.get_or_load(&key, load),
+                None => load(),
+            };
+            // an expired value is left in the storage until the key is written again
+            let Some(raw) = stored.filter(|raw| !value::expired(raw, value::now())) else {
+                // encoded as `$-1`
+                return (RespValue::BulkString(None), Outcome::Success);
+            };
+            match value::string_bytes(raw) {
+                Ok(payload) => (RespValue::BulkString(Some(payload)), Outcome::Success),
+                Err(e) => (error_reply(&e), Outcome::Error),
+            }
+        })
+        .await
+    }
+
+    /// Evaluate a read command of a non-string type against the local storage
+    /// Like GET, these reads are only synchronized with peers per the read-consistency setting
+    pub(crate) async fn handle_local_read(
+        &mut self,
+        family: CommandFamily,
+        inner_cmd: InnerCmd,
+    ) -> Result<(), ConnectionError> {
+        let storage_handle = self.storage_handle.clone();
+        let memory = self.lru_memory();
+        self.defer_read(family, None, move || {
+            if let (Some(memory), Some(key)) = (&memory, inner_cmd.key()) {
+                memory.accessed(key);
+            }
+            match inner_cmd.read(&storage_handle) {
+                Ok(msg) => (msg, Outcome::Success),
+                Err(e) => (error_reply(&e), Outcome::Error),
+            }
+        })
+        .await
+    }
+
+    /// The maxmemory bound when the reads stamp the access time of their key, for allkeys-lru
+    fn lru_memory(&self) -> Option<Arc<Memory>> {
+        self.context
+            .memory
+            .as_ref()
+            .filter(|memory| memory.tracks_access())
+            .cloned()
+    }
+
+    /// Send back the keys matching the pattern, from a snapshot of the local keyspace
+    pub(crate) async fn handle_keys(
+        &mut self,
+        family: CommandFamily,
+        pattern: Vec<u8>,
+    ) -> Result<(), ConnectionError> {
+        let data_dir = self.context.data_dir.clone();
+        let db = self.db;
+        self.defer_read(family, None, move || {
+            let keys = Snapshot::take(&data_dir).and_then(|snapshot| {
+                let now = value::now();
+                let mut keys = Vec::new();
+                // the values are read for their deadline
+                for entry in snapshot.iter()? {
+                    let (stored, raw) = entry?;
+                    let Some(key) = database::of(db, &stored) else {
+                        continue;
+                    };
+                    if glob_match(&pattern, key) && !value::expired(&raw, now) {
+                        keys.push(RespValue::BulkString(Some(key.to_vec().into())));
+                    }
+                }
+                Ok(keys)
+            });
+            match keys {
+                Ok(keys) => (RespValue::Array(keys), Outcome::Success),
+                Err(e) => (RespValue::Error(format!("ERR {}", e)), Outcome::Error),
+            }
+        })
+        .await
+    }
+
+    /// Send back the number of keys, counted in a snapshot of the local keyspace as KEYS lists
+    /// them: the storage counts the deleted keys too
+    pub(crate) async fn handle_dbsize(
+        &mut self,
+        family: CommandFamily,
+    ) -> Result<(), ConnectionError> {
+        let data_dir = self.context.data_dir.clone();
+        let db = self.db;
+        self.defer_read(family, None, move || {
+            let size = Snapshot::take(&data_dir).and_then(|snapshot| {
+                let now = value::now();
+                let mut size = 0;
+                for entry in snapshot.iter()? {
+                    let (key, raw) = entry?;
+                    if database::of(db, &key).is_some() && !value::expired(&raw, now) {
+                        size += 1;
+                    }
+                }
+                Ok(size)
+            });
+            match size {
+                Ok(size) => (RespValue::Integer(size), Outcome::Success),
+                Err(e) => (RespValue::Error(format!("ERR {}", e)), Outcome::Error),
+            }
+        })
+        .await
     }
 
     /// Write the value to the storage and send the response back to the client
     /// We need to synchronize the write operation with peers to guarantee consistency
+    /// The request is proposed right away unless a read queued before it has yet to run,
+    /// so pipelined writes are replicated concurrently
     pub(crate) async fn handle_write(
         &mut self,
+        family: CommandFamily,
         inner_cmd: InnerCmd,
     ) -> Result<(), ConnectionError> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        let sync_request = SyncRequest::new(inner_cmd.clone(), tx);
-        info!("Sending sync request: {:?}", sync_request);
-        self.sync_request_tx
-            .send(sync_request)
-            .await
-            .expect("Could not send sync request");
-        // waiting for the response from the sync layer for 10 seconds
-        match timeout(Duration::from_secs(10), rx).await {
-            Ok(Ok(res)) => {
-                match res {
-                    Ok(_) => {
-                        info!("Sync request {:?} is successful", inner_cmd);
-                        let msg = RespValue::SimpleString("OK".to_string());
-                        self.codec.encode(&mut self.writer, &msg).await?;
+        // a pipelined read must not observe a write sent after it
+        if let Some(barrier) = self.read_barrier.take() {
+            let _ = barrier.await;
+        }
+        // from the proposal to the answer, the sync layer applies the entry in a child span
+        let round_trip = info_span!(
+            "sync_round_trip",
+            request_id = %Uuid::from_bytes(inner_cmd.get_request_id())
+        );
+        // compressed before it is proposed, so every node stores the same bytes
+        let inner_cmd = inner_cmd.compress(&self.context.config);
+        let inner_cmd = match self.admit(inner_cmd, &round_trip).await {
+            Ok(inner_cmd) => inner_cmd,
+            Err(reply) => return self.defer(family, ready((reply, Outcome::Error))).await,
+        };
+        // waiting for the response from the sync layer for the write timeout from now, however long
+        // the replies to the earlier commands take. The sync layer drops the request if it is
+        // still queued by then.
+        let deadline = Instant::now() + self.context.config.write_timeout();
+        let rx = self.propose(inner_cmd.clone(), deadline, &round_trip).await;
+        let client = self.client.clone();
+        let raft_stats = self.context.raft_stats.clone();
+        let watermark = self.watermark;
+        let hidden = self.context.secure_delete.hides(&inner_cmd);
+        let audit = self
+            .context
+            .audit
+            .clone()
+            .map(|audit| (audit, self.user.clone(), self.db));
+        client.write_started();
+        let reply = async move {
+            let answer = timeout_at(deadline, rx).await;
+            client.write_finished();
+            match answer {
+                Ok(Ok(res)) => {
+                    // the entry is counted as applied before it is answered
+                    let applied = raft_stats.applied_entries();
+                    client.write_applied(applied);
+                    let (msg, outcome, audited) = match res {
+                        Ok(msg) => {
+                            info!(
+                                "Sync request {:?} is successful",
+                                Logged::new(&inner_cmd, hidden)
+                            );
+                            (msg, Outcome::Success, "ok")
+                        }
+                        Err(BitCaskError::KeyExists) | Err(BitCaskError::KeyNotFound) => {
+                            // due to NX or XX option
+                            info!("Write operation is aborted");
+                            (RespValue::BulkString(None), Outcome::Success, "aborted")
+                        }
+                        Err(e) => {
+                            info!("Write operation failed: {}", e);
+                            (error_reply(&e), Outcome::Error, "error")
+                        }
+                    };
+                    if let Some((audit, user, db)) = audit {
+                        audit.record(Record {
+                            client_id: client.id,
+                            addr: client.addr().to_string(),
+                            name: client.name(),
+                            user: user.as_deref(),
+                            command: inner_cmd.name(),
+                            db,
+                            key: inner_cmd
+                                .key()
+                                .filter(|_| !hidden)
+                                .and_then(|key| database::of(db, key)),
+                            request_id: inner_cmd.get_request_id(),
+                            index: applied,
+                            outcome: audited,
+                        });
                     }
-                    Err(_) => {
-                        // might be due to NX or XX option
-                        info!("Write operation is aborted");
-                        let msg = RespValue::BulkString(None);
-                        self.codec.encode(&mut self.writer, &msg).await?;
+                    match watermark {
+                        true => (with_applied(applied, msg), outcome),
+                        false => (msg, outcome),
                     }
                 }
+                // dropped by the sync layer as its deadline passed
+                Ok(Err(_)) if Instant::now() >= deadline => (
+                    RespValue::Error("Request timeout".to_string()),
+                    Outcome::Timeout,
+                ),
+                // the sync layer stopped, dropping the answer channel
+                Ok(Err(_)) => (
+                    RespValue::Error(sync_layer::CLUSTERDOWN.to_string()),
+                    Outcome::Error,
+                ),
+                Err(_) => (
+                    RespValue::Error("Request timeout".to_string()),
+                    Outcome::Timeout,
+                ),
             }
-            Ok(Err(_)) => {
-                let msg = RespValue::Error("Request timeout".to_string());
-                self.codec.encode(&mut self.writer, &msg).await?;
+        };
+        self.defer(family, reply.instrument(round_trip)).await
+    }
+
+    /// The write to propose for the command, once the value of a SET larger than
+    /// proposal-max-bytes is proposed in chunks, or the error to reply when it is too large or
+    /// the node is over maxmemory
+    async fn admit(
+        &mut self,
+        inner_cmd: InnerCmd,
+        round_trip: &Span,
+    ) -> Result<InnerCmd, RespValue> {
+        if !inner_cmd.frees_memory() {
+            self.make_room(round_trip).await?;
+        }
+        let Some(max) = self.context.config.proposal_max_bytes() else {
+            return Ok(inner_cmd);
+        };
+        let size = bincode::serialized_size(&inner_cmd).unwrap_or_default();
+        if size <= max {
+            return Ok(inner_cmd);
+        }
+        match (self.context.config.proposal_oversize(), inner_cmd) {
+            (Oversize::Split, InnerCmd::Put(id, key, value, option)) => {
+                let size = (max / 2).max(1) as usize;
+                let chunks = self.propose_chunks(id, &value, size, round_trip).await?;
+                info!("SET of {} bytes proposed in {} chunks", value.len(), chunks);
+                Ok(InnerCmd::PutChunked(id, key, chunks, option))
             }
-            Err(_) => {
-                let msg = RespValue::Error("Internal error".to_string());
-                self.codec.encode(&mut self.writer, &msg).await?;
+            (_, inner_cmd) => {
+                info!("{} of {} bytes rejected", inner_cmd.name(), size);
+                Err(RespValue::Error(format!(
+                    "ERR write of {} bytes is larger than proposal-max-bytes {}",
+                    size, max
+                )))
             }
         }
+    }
+
+    /// Propose the eviction of the keys the maxmemory policy picks while the keys take more than
+    /// maxmemory, or reply -OOM when it picks none
+    async fn make_room(&mut self, round_trip: &Span) -> Result<(), RespValue> {
+        let Some(memory) = self.context.memory.clone() else {
+            return Ok(());
+        };
+        if !memory.over() {
+            return Ok(());
+        }
+        let Some(keys) = memory.victims() else {
+            info!("Write rejected over maxmemory");
+            return Err(RespValue::Error(memory::OOM.to_string()));
+        };
+        let deadline = Instant::now() + self.context.config.write_timeout();
+        let evict = InnerCmd::Evict(*Uuid::new_v4().as_bytes(), keys);
+        let rx = self.propose(evict, deadline, round_trip).await;
+        // the write goes on once the eviction is applied, whether it freed enough or not
+        match timeout_at(deadline, rx).await {
+            Ok(Ok(Ok(_))) => Ok(()),
+            Ok(Ok(Err(e))) => Err(error_reply(&e)),
+            Ok(Err(_)) if Instant::now() < deadline => {
+                Err(RespValue::Error(sync_layer::CLUSTERDOWN.to_string()))
+            }
+            _ => Err(RespValue::Error("Request timeout".to_string())),
+        }
+    }
+
+    /// Propose the value of the SET `id` in chunks of `size` bytes, each once the previous one is
+    /// applied, returning how many there are
+    async fn propose_chunks(
+        &mut self,
+        id: RequestId,
+        value: &[u8],
+        size: usize,
+        round_trip: &Span,
+    ) -> Result<u32, RespValue> {
+        let mut staged = Vec::new();
+        for (index, bytes) in value.chunks(size).enumerate() {
+            let index = index as u32;
+            let deadline = Instant::now() + self.context.config.write_timeout();
+            let chunk = InnerCmd::Chunk(*Uuid::new_v4().as_bytes(), id, index, bytes.to_vec());
+            let rx = self.propose(chunk, deadline, round_trip).await;
+            let answer = timeout_at(deadline, rx).await;
+            // a chunk left unanswered may still be applied
+            staged.push(chunk::key(&id, index));
+            let error = match answer {
+                Ok(Ok(Ok(_))) => continue,
+                Ok(Ok(Err(e))) => error_reply(&e),
+                Ok(Err(_)) if Instant::now() < deadline => {
+                    RespValue::Error(sync_layer::CLUSTERDOWN.to_string())
+                }
+                _ => RespValue::Error("Request timeout".to_string()),
+            };
+            // nobody waits for the chunks to be deleted
+            let deadline = Instant::now() + self.context.config.write_timeout();
+            let del = InnerCmd::Del(*Uuid::new_v4().as_bytes(), staged, value::now());
+            drop(self.propose(del, deadline, round_trip).await);
+            return Err(error);
+        }
+        Ok(staged.len() as u32)
+    }
+
+    /// Whether a SET IFCHANGED would store the value the node already holds. Its earlier writes
+    /// must have been applied for the node to hold what the client last set.
+    fn unchanged(&self, inner_cmd: &InnerCmd) -> bool {
+        self.client.in_flight_writes() == 0 && inner_cmd.unchanged(&self.storage_handle)
+    }
+
+    /// Why the next write is rejected, if it is: the connection has too many writes waiting for
+    /// their reply, or the proposal queue shared by all the connections is full
+    fn backpressure(&self) -> Option<&'static str> {
+        if self.max_in_flight_writes > 0
+            && self.client.in_flight_writes() >= self.max_in_flight_writes
+        {
+            Some(overload::BUSY_IN_FLIGHT)
+        } else if self.sync_request_tx.capacity() == 0 {
+            Some(overload::BUSY_PROPOSALS)
+        } else {
+            None
+        }
+    }
+
+    /// Hand the command to the sync layer, which answers once the entry is replicated and applied
+    async fn propose(
+        &mut self,
+        inner_cmd: InnerCmd,
+        deadline: Instant,
+        round_trip: &Span,
+    ) -> oneshot::Receiver<Result<RespValue, BitCaskError>> {
+        // marked before the sync layer may take the request and mark the next stage
+        if let Some(stages) = &self.stages {
+            stages.mark("queue");
+        }
+        // the request id lets client logs be matched with the traces of the request
+        info!(
+            "Sending sync request: {} {:?}",
+            Uuid::from_bytes(inner_cmd.get_request_id()),
+            self.context.secure_delete.logged(&inner_cmd)
+        );
+        let (tx, rx) = oneshot::channel();
+        // the chunks of a SET ID are proposed under ids of their own, only the SET is retried
+        let identified = self.client_id == Some(inner_cmd.get_request_id());
+        let sync_request = SyncRequest::new(
+            inner_cmd,
+            tx,
+            deadline,
+            round_trip.clone(),
+            self.stages.clone(),
+        )
+        .identified(identified);
+        // if the sync layer is gone, the request is dropped with its answer channel
+        if let Err(e) = self
+            .sync_request_tx
+            .send(sync_request)
+            .instrument(round_trip.clone())
+            .await
+        {
+            warn!("Could not send sync request: {}", e);
+        }
+        rx
+    }
+
+    /// Extend the leases of the keys the connection set EPHEMERAL, without waiting for the answer
+    async fn renew_ephemeral(&mut self, lease: Duration) {
+        let keys = self.ephemeral.iter().cloned().collect();
+        let op = EphemeralOp::Renew(keys, self.session, lease.as_millis() as u64, value::now());
+        let renew = InnerCmd::Ephemeral(*Uuid::new_v4().as_bytes(), op);
+        let deadline = Instant::now() + self.context.config.write_timeout();
+        let round_trip = info_span!("sync_round_trip", request_id = "ephemeral renewal");
+        let rx = self.propose(renew, deadline, &round_trip).await;
+        // the sync layer skips a request nobody waits for
+        tokio::spawn(async move {
+            if !matches!(timeout_at(deadline, rx).await, Ok(Ok(Ok(_)))) {
+                warn!("Could not renew the leases of ephemeral keys");
+            }
+        });
+    }
+
+    /// Run the later commands of the connection in the database
+    pub(crate) async fn handle_select(&mut self, db: u32) -> Result<Outcome, ConnectionError> {
+        if db >= self.context.databases {
+            self.reply(RespValue::Error(DB_OUT_OF_RANGE.to_string()))
+                .await?;
+            return Ok(Outcome::Error);
+        }
+        self.db = db;
+        self.reply(RespValue::SimpleString("OK".to_string()))
+            .await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Start a backup of the data files in the background, unless one is in progress
+    pub(crate) async fn handle_bgsave(&mut self) -> Result<Outcome, ConnectionError> {
+        let backups = self.context.backups.clone();
+        if !backups.start(self.context.data_dir.clone()) {
+            self.reply(RespValue::Error(
+                "ERR Background save already in progress".to_string(),
+            ))
+            .await?;
+            return Ok(Outcome::Error);
+        }
+        self.reply(RespValue::SimpleString(
+            "Background saving started".to_string(),
+        ))
+        .await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Reply the unix time of the latest complete backup, 0 if there is none
+    pub(crate) async fn handle_compact(&mut self) -> Result<Outcome, ConnectionError> {
+        let compaction = self.context.compaction.clone();
+        if !compaction.start(self.storage_handle.clone(), self.context.clone()) {
+            self.reply(RespValue::Error(
+                "ERR Background compaction already in progress".to_string(),
+            ))
+            .await?;
+            return Ok(Outcome::Error);
+        }
+        self.reply(RespValue::SimpleString(
+            "Background compaction started".to_string(),
+        ))
+        .await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Turn the standby into serving the clients
+    pub(crate) async fn handle_promote(&mut self) -> Result<Outcome, ConnectionError> {
+        if !self.context.standby.promote() {
+            self.reply(RespValue::Error(
+                "ERR the node is not a standby".to_string(),
+            ))
+            .await?;
+            return Ok(Outcome::Error);
+        }
+        self.reply(RespValue::SimpleString("OK".to_string()))
+            .await?;
+        Ok(Outcome::Success)
+    }
+
+    pub(crate) async fn handle_lastsave(&mut self) -> Result<Outcome, ConnectionError> {
+        let last_save = self.context.backups.last_save();
+        self.reply(RespValue::Integer(last_save as i64)).await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Switch the protocol of the connection and send the server properties back to the client
+    pub(crate) async fn handle_hello(
+        &mut self,
+        protover: Option<i64>,
+        auth: Option<(String, String)>,
+        setname: Option<String>,
+    ) -> Result<Outcome, ConnectionError> {
+        // nothing changes unless every argument is valid, as in Redis
+        let error = match (protover, &auth, &setname) {
+            (Some(protover), _, _) if protover != 2 && protover != 3 => {
+                Some("NOPROTO unsupported protocol version")
+            }
+            (_, Some((user, password)), _) if !self.context.acl.authenticate(user, password) => {
+                Some(WRONGPASS)
+            }
+            (_, _, Some(name)) if !valid_client_name(name) => Some(INVALID_CLIENT_NAME),
+            _ => None,
+        };
+        if let Some(error) = error {
+            self.reply(RespValue::Error(error.to_string())).await?;
+            return Ok(Outcome::Error);
+        }
+        if let Some((user, password)) = auth {
+            self.user = Some(user.clone());
+            self.credentials = Some((user, password));
+        }
+        if let Some(name) = setname {
+            self.client.set_name(name);
+        }
+        match protover {
+            Some(3) => self.set_protocol(Protocol::Resp3).await?,
+            Some(_) => self.set_protocol(Protocol::Resp2).await?,
+            None => {}
+        }
+        let proto = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let role = if self.context.replica_of.is_empty() {
+            "master"
+        } else {
+            "replica"
+        };
+        let field =
+            |name: &'static str| RespValue::BulkString(Some(Bytes::from_static(name.as_bytes())));
+        let msg = RespValue::Map(vec![
+            (field("server"), field("storgata-db")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), RespValue::Integer(proto)),
+            (field("mode"), field("standalone")),
+            (field("role"), field(role)),
+            (field("modules"), RespValue::Array(vec![])),
+        ]);
+        self.reply(msg).await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Report where a key lives in the cluster
+    pub(crate) async fn handle_cluster(
+        &mut self,
+        op: ClusterOp,
+    ) -> Result<Outcome, ConnectionError> {
+        let msg = match op {
+            ClusterOp::KeySlot(key) => RespValue::Integer(cluster::key_slot(&key) as i64),
+            ClusterOp::KeyOwner(key) => cluster::owner(&key, &self.context.nodes),
+        };
+        self.reply(msg).await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Read or clear the slow log of the node
+    pub(crate) async fn handle_slowlog(
+        &mut self,
+        op: SlowLogOp,
+    ) -> Result<Outcome, ConnectionError> {
+        let slowlog = &self.context.slowlog;
+        let msg = match op {
+            SlowLogOp::Get(count) => slowlog.get(count),
+            SlowLogOp::Len => RespValue::Integer(slowlog.len() as i64),
+            SlowLogOp::Reset => {
+                slowlog.reset();
+                RespValue::SimpleString("OK".to_string())
+            }
+        };
+        self.reply(msg).await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Read the latest committed entries of the Raft log
+    pub(crate) async fn handle_raft(&mut self, op: RaftOp) -> Result<Outcome, ConnectionError> {
+        let msg = match op {
+            RaftOp::Log(from, to) => {
+                raft_log::log::<InnerCmd>(&*self.context.raft_log, from, to, |inner_cmd| {
+                    self.context.secure_delete.hides(inner_cmd)
+                })
+            }
+            RaftOp::Tail(from) => {
+                raft_log::tail::<InnerCmd>(&self.context.raft_log, from, |inner_cmd| {
+                    inner_cmd.get_request_id()
+                })
+            }
+        };
+        self.reply(msg).await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Reply with the keys matching the pattern, their values and history, signed
+    pub(crate) async fn handle_export(
+        &mut self,
+        family: CommandFamily,
+        pattern: Vec<u8>,
+    ) -> Result<(), ConnectionError> {
+        if let Err(e) = self.context.exports.admit() {
+            self.reply(RespValue::Error(e)).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        let context = self.context.clone();
+        let db = self.db;
+        let by = format!(
+            "user {} from {}",
+            self.user.as_deref().unwrap_or(DEFAULT_USER),
+            self.client.addr()
+        );
+        self.defer_read(family, None, move || {
+            let exported = context.exports.export(
+                &context.nodes[0],
+                &context.data_dir,
+                &*context.raft_log,
+                (db, &pattern),
+                |inner_cmd| context.secure_delete.hides(inner_cmd),
+                &by,
+            );
+            match exported {
+                Ok((bundle, signature)) => (
+                    RespValue::Array(vec![
+                        RespValue::BulkString(Some(bundle.into())),
+                        RespValue::BulkString(Some(signature.into())),
+                    ]),
+                    Outcome::Success,
+                ),
+                Err(e) => (RespValue::Error(format!("ERR {}", e)), Outcome::Error),
+            }
+        })
+        .await
+    }
+
+    /// Stream the commands the node runs to this connection, until it closes
+    pub(crate) async fn handle_monitor(&mut self) -> Result<Outcome, ConnectionError> {
+        if self.monitor.is_none() {
+            self.monitor = Some(self.context.monitor.subscribe());
+        }
+        self.reply(RespValue::SimpleString("OK".to_string()))
+            .await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Run an admin command on this node and its peers, replying with the reply of each node
+    pub(crate) async fn handle_admin(
+        &mut self,
+        family: CommandFamily,
+        op: AdminOp,
+    ) -> Result<(), ConnectionError> {
+        let args = match op {
+            AdminOp::Broadcast(args) => args,
+            AdminOp::Maintenance(on) => {
+                self.context.maintenance.set(on);
+                self.reply(RespValue::SimpleString("OK".to_string()))
+                    .await?;
+                self.slo.record(family, Outcome::Success);
+                return Ok(());
+            }
+        };
+        let frame = RespValue::Array(
+            args.iter()
+                .map(|arg| RespValue::BulkString(Some(Bytes::copy_from_slice(arg))))
+                .collect(),
+        );
+        // the command is checked here rather than on every node
+        let error = match InnerCmd::new(cmd::Cmd::from(frame)) {
+            Err(_) => Some("ERR unknown command to broadcast".to_string()),
+            Ok(inner_cmd) if !matches!(inner_cmd.category(), Some(Category::Admin)) => {
+                Some(format!(
+                    "ERR only admin commands are broadcast, not '{}'",
+                    inner_cmd.name()
+                ))
+            }
+            Ok(InnerCmd::Admin(_) | InnerCmd::Monitor | InnerCmd::Shutdown(_)) => Some(format!(
+                "ERR '{}' cannot be broadcast",
+                args[0].escape_ascii()
+            )),
+            Ok(inner_cmd) => self.check_permissions(&inner_cmd).err(),
+        };
+        if let Some(e) = error {
+            self.reply(RespValue::Error(e)).await?;
+            self.slo.record(family, Outcome::Error);
+            return Ok(());
+        }
+        let (stream, local) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve_local(Connection::new(
+            local,
+            self.client.addr(),
+            self.storage_handle.clone(),
+            self.sync_request_tx.clone(),
+            self.slo.clone(),
+            self.scrub_stats.clone(),
+            self.context.clone(),
+        )));
+        let credentials = self.credentials.clone();
+        let node = self.context.nodes[0].clone();
+        let peers = self.context.peers.clone();
+        self.defer(family, async move {
+            let credentials = credentials.as_ref();
+            let local = async {
+                admin::call(stream, credentials, &args)
+                    .await
+                    .unwrap_or_else(|e| RespValue::Error(format!("ERR {}", e)))
+            };
+            let remote = futures::future::join_all(
+                peers
+                    .iter()
+                    .map(|peer| admin::call_peer(peer, credentials, &args)),
+            );
+            let (local, remote) = futures::join!(local, remote);
+            let bulk = |node: String| RespValue::BulkString(Some(node.into()));
+            let replies = std::iter::once((bulk(node), local))
+                .chain(
+                    peers
+                        .into_iter()
+                        .zip(remote)
+                        .map(|(peer, reply)| (bulk(peer.node), reply)),
+                )
+                .collect();
+            // the outcome on each node is in its reply
+            (RespValue::Map(replies), Outcome::Success)
+        })
+        .await
+    }
+
+    /// Reply with how many other nodes hold the previous writes of the client, once enough do or
+    /// the timeout passes
+    pub(crate) async fn handle_wait(
+        &mut self,
+        family: CommandFamily,
+        replicas: usize,
+        timeout: u64,
+    ) -> Result<(), ConnectionError> {
+        let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout));
+        let client = self.client.clone();
+        let credentials = self.credentials.clone();
+        let nodes = self.context.nodes.len();
+        let peers = self.context.peers.clone();
+        // deferred, the earlier writes of the client are applied by the time it runs
+        self.defer(family, async move {
+            let held = wait::wait(
+                nodes,
+                &peers,
+                credentials.as_ref(),
+                client.last_write(),
+                replicas,
+                deadline,
+            )
+            .await;
+            (RespValue::Integer(held as i64), Outcome::Success)
+        })
+        .await
+    }
+
+    /// Take or release the snapshot served on the analytics port
+    pub(crate) async fn handle_snapshot(
+        &mut self,
+        op: SnapshotOp,
+    ) -> Result<Outcome, ConnectionError> {
+        if let SnapshotOp::Close(handle) = op {
+            let (msg, outcome) = match &self.view {
+                Some(view) if handle.is_none_or(|handle| handle == view.handle) => {
+                    self.view = None;
+                    (RespValue::SimpleString("OK".to_string()), Outcome::Success)
+                }
+                _ => (
+                    RespValue::Error("ERR no such read view on this connection".to_string()),
+                    Outcome::Error,
+                ),
+            };
+            self.reply(msg).await?;
+            return Ok(outcome);
+        }
+        let Some(analytics) = &self.context.analytics else {
+            let msg = "ERR no analytics port, start the node with --analytics-addr";
+            self.reply(RespValue::Error(msg.to_string())).await?;
+            return Ok(Outcome::Error);
+        };
+        let (msg, outcome) = match op {
+            SnapshotOp::Take(index) => match analytics.take(
+                index,
+                self.context.raft_stats.applied_entries(),
+                &self.context.data_dir,
+            ) {
+                Ok(()) => (RespValue::SimpleString("OK".to_string()), Outcome::Success),
+                Err(e) => (RespValue::Error(e), Outcome::Error),
+            },
+            SnapshotOp::Release => {
+                analytics.release();
+                (RespValue::SimpleString("OK".to_string()), Outcome::Success)
+            }
+            SnapshotOp::Open(_) | SnapshotOp::Close(_) => unreachable!("handled above"),
+        };
+        self.reply(msg).await?;
+        Ok(outcome)
+    }
+
+    /// Open a read view for the connection, replacing the one it had, once the earlier commands
+    /// of the connection are replied to, replying its handle and the entries it is as of
+    pub(crate) async fn handle_view_open(
+        &mut self,
+        family: CommandFamily,
+        secs: Option<u64>,
+    ) -> Result<(), ConnectionError> {
+        let max = self.context.read_view_max;
+        let lifetime = match secs {
+            None => max,
+            Some(secs) if secs > 0 && secs <= max.as_secs() => Duration::from_secs(secs),
+            Some(_) => {
+                let msg = format!("ERR a read view lasts 1 to {} seconds", max.as_secs());
+                self.reply(RespValue::Error(msg)).await?;
+                self.slo.record(family, Outcome::Error);
+                return Ok(());
+            }
+        };
+        let view = View::new(lifetime);
+        let (handle, slot) = (view.handle, view.slot());
+        self.view = Some(view);
+        let storage_handle = self.storage_handle.clone();
+        let data_dir = self.context.data_dir.clone();
+        self.defer(family, async move {
+            match slot.open(&storage_handle, &data_dir) {
+                Ok(index) => (
+                    RespValue::Array(vec![
+                        RespValue::Integer(handle as i64),
+                        RespValue::Integer(index as i64),
+                    ]),
+                    Outcome::Success,
+                ),
+                Err(e) => (RespValue::Error(format!("ERR {}", e)), Outcome::Error),
+            }
+        })
+        .await
+    }
+
+    /// Serve a read from the view of the connection
+    async fn handle_view_read(
+        &mut self,
+        family: CommandFamily,
+        inner_cmd: InnerCmd,
+    ) -> Result<(), ConnectionError> {
+        // the timer of the view may not have fired yet while commands keep coming
+        if let Some(view) = &mut self.view {
+            if view.deadline().is_some_and(|deadline| deadline <= Instant::now()) {
+                view.expire();
+            }
+        }
+        let slot = self.view.as_ref().map(View::slot).unwrap_or_default();
+        let db = self.db;
+        self.defer(family, async move {
+            let Some(opened) = slot.get() else {
+                return (RespValue::Error(view::EXPIRED.to_string()), Outcome::Error);
+            };
+            match opened.read(&inner_cmd, db) {
+                Ok(msg) => (msg, Outcome::Success),
+                Err(e) => (error_reply(&e), Outcome::Error),
+            }
+        })
+        .await
+    }
+
+    /// Run a CLIENT subcommand against the connections of this node
+    pub(crate) async fn handle_client(&mut self, op: ClientOp) -> Result<Outcome, ConnectionError> {
+        let ok = || RespValue::SimpleString("OK".to_string());
+        let (msg, outcome) = match op {
+            ClientOp::Id => (RespValue::Integer(self.client.id as i64), Outcome::Success),
+            ClientOp::GetName => match self.client.name() {
+                name if name.is_empty() => (RespValue::BulkString(None), Outcome::Success),
+                name => (RespValue::BulkString(Some(name.into())), Outcome::Success),
+            },
+            ClientOp::SetName(name) if !valid_client_name(&name) => (
+                RespValue::Error(INVALID_CLIENT_NAME.to_string()),
+                Outcome::Error,
+            ),
+            ClientOp::SetName(name) => {
+                self.client.set_name(name);
+                (ok(), Outcome::Success)
+            }
+            // the stages are told in attributes, which RESP2 has no room for
+            ClientOp::Trace(true) if self.protocol == Protocol::Resp2 => (
+                RespValue::Error("ERR CLIENT TRACE needs RESP3, switch with HELLO 3".to_string()),
+                Outcome::Error,
+            ),
+            // invalidations are pushed, which RESP2 only has room for in subscriber mode
+            ClientOp::Tracking(true) if self.protocol == Protocol::Resp2 => (
+                RespValue::Error(
+                    "ERR CLIENT TRACKING needs RESP3, switch with HELLO 3".to_string(),
+                ),
+                Outcome::Error,
+            ),
+            ClientOp::Tracking(on) => {
+                self.subscription.set_tracking(on);
+                (ok(), Outcome::Success)
+            }
+            // the index is told in an attribute, as the stages are
+            ClientOp::Watermark(true) if self.protocol == Protocol::Resp2 => (
+                RespValue::Error(
+                    "ERR CLIENT WATERMARK needs RESP3, switch with HELLO 3".to_string(),
+                ),
+                Outcome::Error,
+            ),
+            ClientOp::Watermark(on) => {
+                self.watermark = on;
+                (ok(), Outcome::Success)
+            }
+            ClientOp::Trace(on) => {
+                self.trace = on;
+                // turning it off is not traced either
+                self.stages = None;
+                (ok(), Outcome::Success)
+            }
+            ClientOp::List => (
+                RespValue::BulkString(Some(self.context.clients.list().into())),
+                Outcome::Success,
+            ),
+            ClientOp::Kill(filter) => {
+                let killed = self.context.clients.kill(&filter, self.client.id);
+                match (filter.legacy, killed) {
+                    (true, 0) => (
+                        RespValue::Error("ERR No such client".to_string()),
+                        Outcome::Error,
+                    ),
+                    (true, _) => (ok(), Outcome::Success),
+                    (false, killed) => (RespValue::Integer(killed as i64), Outcome::Success),
+                }
+            }
+            ClientOp::Info(id, history) => {
+                let client = match id {
+                    None => Some(self.client.clone()),
+                    Some(id) => self.context.clients.get(id),
+                };
+                match client {
+                    None => (
+                        RespValue::Error("ERR No such client".to_string()),
+                        Outcome::Error,
+                    ),
+                    Some(client) if history => (
+                        RespValue::Array(
+                            client
+                                .history()
+                                .into_iter()
+                                .map(|line| RespValue::BulkString(Some(line.into())))
+                                .collect(),
+                        ),
+                        Outcome::Success,
+                    ),
+                    Some(client) => (
+                        RespValue::BulkString(Some(client.info().into())),
+                        Outcome::Success,
+                    ),
+                }
+            }
+        };
+        self.reply(msg).await?;
+        Ok(outcome)
+    }
+
+    /// Read or change the runtime settings of the node
+    pub(crate) async fn handle_config(&mut self, op: ConfigOp) -> Result<Outcome, ConnectionError> {
+        let (msg, outcome) = match op {
+            ConfigOp::Get(pattern) => {
+                let settings = self
+                    .context
+                    .config
+                    .get(&pattern)
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            RespValue::BulkString(Some(Bytes::from_static(name.as_bytes()))),
+                            RespValue::BulkString(Some(value.into())),
+                        )
+                    })
+                    .collect();
+                (RespValue::Map(settings), Outcome::Success)
+            }
+            ConfigOp::Set(changes) => match self.context.config.set(&changes) {
+                Ok(()) => (RespValue::SimpleString("OK".to_string()), Outcome::Success),
+                Err(e) => (RespValue::Error(e), Outcome::Error),
+            },
+            ConfigOp::Rewrite => match self.context.config.rewrite() {
+                Ok(()) => (RespValue::SimpleString("OK".to_string()), Outcome::Success),
+                Err(e) => (RespValue::Error(e), Outcome::Error),
+            },
+        };
+        self.reply(msg).await?;
+        Ok(outcome)
+    }
+
+    /// List the feature flags, or set one for the whole group through the sync layer
+    pub(crate) async fn handle_feature(
+        &mut self,
+        family: CommandFamily,
+        id: RequestId,
+        op: FeatureOp,
+    ) -> Result<(), ConnectionError> {
+        let (msg, outcome) = match op {
+            FeatureOp::List => {
+                let features = self
+                    .context
+                    .features
+                    .list()
+                    .into_iter()
+                    .map(|(name, enabled)| {
+                        let state = if enabled { "enabled" } else { "disabled" };
+                        (
+                            RespValue::BulkString(Some(Bytes::from_static(name.as_bytes()))),
+                            RespValue::BulkString(Some(Bytes::from_static(state.as_bytes()))),
+                        )
+                    })
+                    .collect();
+                (RespValue::Map(features), Outcome::Success)
+            }
+            op => match Features::validate(&op) {
+                Ok(()) => return self.handle_write(family, InnerCmd::Feature(id, op)).await,
+                Err(e) => (RespValue::Error(e), Outcome::Error),
+            },
+        };
+        self.reply(msg).await?;
+        self.slo.record(family, outcome);
         Ok(())
     }
 
-    /// Send a PONG response to the client
-    pub(crate) async fn handle_ping(&mut self) -> Result<(), ConnectionError> {
-        let msg = RespValue::SimpleString("PONG".to_string());
-        self.codec.encode(&mut self.writer, &msg).await?;
+    /// Schedule a FLUSHALL, or replicate the one scheduled once confirmed
+    pub(crate) async fn handle_flushall(
+        &mut self,
+        family: CommandFamily,
+        id: RequestId,
+        op: FlushAllOp,
+    ) -> Result<(), ConnectionError> {
+        let (msg, outcome) = match op {
+            FlushAllOp::Unconfirmed(_) | FlushAllOp::Fenced(_, _) => (
+                RespValue::Error(format!(
+                    "ERR FLUSHALL needs FLUSHALL SCHEDULE [ASYNC|SYNC], then FLUSHALL CONFIRM <token> within {} seconds",
+                    self.context.flusher.window().as_secs()
+                )),
+                Outcome::Error,
+            ),
+            FlushAllOp::Schedule(lazy) => match flush::epoch(&self.storage_handle) {
+                Ok(epoch) => {
+                    let token = self.context.flusher.schedule(lazy, epoch);
+                    (RespValue::BulkString(Some(token.into())), Outcome::Success)
+                }
+                Err(e) => (RespValue::Error(format!("ERR {}", e)), Outcome::Error),
+            },
+            FlushAllOp::Confirm(token) => match self.context.flusher.confirm(&token) {
+                Some((lazy, epoch)) => {
+                    let inner_cmd = InnerCmd::FlushAll(id, FlushAllOp::Fenced(lazy, epoch));
+                    return self.handle_write(family, inner_cmd).await;
+                }
+                None => (
+                    RespValue::Error(
+                        "ERR no FLUSHALL scheduled with this token, or its window passed"
+                            .to_string(),
+                    ),
+                    Outcome::Error,
+                ),
+            },
+        };
+        self.reply(msg).await?;
+        self.slo.record(family, outcome);
         Ok(())
     }
+
+    /// Run a DEBUG subcommand
+    pub(crate) async fn handle_debug(&mut self, op: DebugOp) -> Result<Outcome, ConnectionError> {
+        let ok = RespValue::SimpleString("OK".to_string());
+        let result = match op {
+            DebugOp::FailPoint(name, action) => failpoint::set(&name, action).map(|()| ok),
+            DebugOp::Sleep(duration) => {
+                tokio::time::sleep(duration).await;
+                Ok(ok)
+            }
+            DebugOp::RecoveryBench => {
+                let context = self.context.clone();
+                match tokio::task::spawn_blocking(move || recovery::bench(&context)).await {
+                    Ok(Ok(report)) => Ok(RespValue::BulkString(Some(report.into()))),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        };
+        let (msg, outcome) = match result {
+            Ok(msg) => (msg, Outcome::Success),
+            Err(e) => (RespValue::Error(format!("ERR {}", e)), Outcome::Error),
+        };
+        self.reply(msg).await?;
+        Ok(outcome)
+    }
+
+    /// Authenticate the connection as the user, the default user if none is given
+    pub(crate) async fn handle_auth(
+        &mut self,
+        user: Option<String>,
+        password: String,
+    ) -> Result<Outcome, ConnectionError> {
+        let user = user.unwrap_or_else(|| DEFAULT_USER.to_string());
+        if !self.context.acl.authenticate(&user, &password) {
+            self.reply(RespValue::Error(WRONGPASS.to_string())).await?;
+            return Ok(Outcome::Error);
+        }
+        self.user = Some(user.clone());
+        self.credentials = Some((user, password));
+        self.reply(RespValue::SimpleString("OK".to_string()))
+            .await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Run an ACL subcommand against the users of this node
+    pub(crate) async fn handle_acl(&mut self, op: AclOp) -> Result<Outcome, ConnectionError> {
+        let bulk = |s: String| RespValue::BulkString(Some(s.into()));
+        let (msg, outcome) = match op {
+            AclOp::WhoAmI => (
+                bulk(self.user.clone().unwrap_or_default()),
+                Outcome::Success,
+            ),
+            AclOp::List => (
+                RespValue::Array(self.context.acl.list().into_iter().map(bulk).collect()),
+                Outcome::Success,
+            ),
+            AclOp::GetUser(name) => match self.context.acl.get_user(&name) {
+                Some(msg) => (msg, Outcome::Success),
+                None => (RespValue::NullArray, Outcome::Success),
+            },
+            AclOp::SetUser(name, rules) => match self.context.acl.set_user(&name, &rules) {
+                Ok(()) => (RespValue::SimpleString("OK".to_string()), Outcome::Success),
+                Err(e) => (RespValue::Error(format!("ERR {}", e)), Outcome::Error),
+            },
+        };
+        self.reply(msg).await?;
+        Ok(outcome)
+    }
+
+    /// Send the requested INFO sections back to the client
+    pub(crate) async fn handle_info(
+        &mut self,
+        section: Option<String>,
+    ) -> Result<Outcome, ConnectionError> {
+        let mut info = String::new();
+        if matches!(section.as_deref(), None | Some("all") | Some("server")) {
+            info.push_str("# Server\r\n");
+            info.push_str(&format!(
+                "storgata_version:{}\r\nprocess_id:{}\r\nuptime_in_seconds:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                std::process::id(),
+                self.context.started.elapsed().as_secs(),
+            ));
+            // whether the node serves the clients, for load balancers to route them by
+            let ready = !self.context.standby.is_active() && !self.context.maintenance.is_on();
+            info.push_str(&format!("ready:{}\r\n", ready as u8));
+            info.push_str(&self.context.maintenance.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("clients")) {
+            info.push_str("# Clients\r\n");
+            info.push_str(&self.context.clients.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("stats")) {
+            info.push_str("# Stats\r\n");
+            info.push_str(&self.context.stats.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("replication")) {
+            // the first node is this one
+            info.push_str("# Replication\r\n");
+            info.push_str(&format!("self_addr:{}\r\n", self.context.nodes[0]));
+            info.push_str(&format!(
+                "connected_peers:{}\r\n",
+                self.context.nodes.len() - 1
+            ));
+            if !self.context.replica_of.is_empty() {
+                info.push_str(&format!(
+                    "replica_of:{}\r\n",
+                    self.context.replica_of.join(",")
+                ));
+            }
+            info.push_str(&self.context.standby.info());
+            for (i, peer) in self.context.nodes[1..].iter().enumerate() {
+                info.push_str(&format!("peer{}:addr={}\r\n", i, peer));
+            }
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("raft")) {
+            // raft-lite keeps the term of the node to itself
+            info.push_str("# Raft\r\n");
+            info.push_str(&self.context.raft_stats.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("overload")) {
+            info.push_str("# Overload\r\n");
+            info.push_str(&self.context.overload.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("storage")) {
+            info.push_str("# Storage\r\n");
+            match keyspace::data_files(&self.context.data_dir) {
+                Ok(files) => info.push_str(&format!(
+                    "keys:{}\r\ndata_files:{}\r\ndata_files_bytes:{}\r\n",
+                    self.storage_handle.size(),
+                    files.len(),
+                    files.iter().map(|(_, len)| len).sum::<u64>(),
+                )),
+                Err(e) => {
+                    self.reply(RespValue::Error(format!("ERR {}", e))).await?;
+                    return Ok(Outcome::Error);
+                }
+            }
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("expiry")) {
+            info.push_str("# Expiry\r\n");
+            info.push_str(&self.context.expiry.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("memory")) {
+            info.push_str("# Memory\r\n");
+            if let Some(memory) = &self.context.memory {
+                info.push_str(&memory.info());
+            }
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("prefixstats")) {
+            info.push_str("# Prefixstats\r\n");
+            info.push_str(&self.context.prefix_stats.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("slo")) {
+            info.push_str("# Slo\r\n");
+            info.push_str(&self.slo.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("scrubber")) {
+            info.push_str("# Scrubber\r\n");
+            info.push_str(&self.scrub_stats.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("cache")) {
+            info.push_str("# Cache\r\n");
+            if let Some(read_cache) = &self.context.read_cache {
+                info.push_str(&read_cache.info());
+            }
+            if let Some(negative_cache) = &self.context.negative_cache {
+                info.push_str(&negative_cache.info());
+            }
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("analytics")) {
+            info.push_str("# Analytics\r\n");
+            if let Some(analytics) = &self.context.analytics {
+                info.push_str(&analytics.info());
+            }
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("backup")) {
+            info.push_str("# Backup\r\n");
+            info.push_str(&self.context.backups.info());
+        }
+        if matches!(section.as_deref(), None | Some("all") | Some("compaction")) {
+            info.push_str("# Compaction\r\n");
+            info.push_str(&self.context.compaction.info());
+        }
+        let msg = RespValue::BulkString(Some(info.into()));
+        self.reply(msg).await?;
+        Ok(Outcome::Success)
+    }
+
+    /// Update the subscriptions of this connection, replying once per channel or pattern
+    pub(crate) async fn handle_subscription(
+        &mut self,
+        inner_cmd: InnerCmd,
+    ) -> Result<Outcome, ConnectionError> {
+        let (kind, names) = match inner_cmd {
+            InnerCmd::Subscribe(channels) => ("subscribe", channels),
+            InnerCmd::PSubscribe(patterns) => ("psubscribe", patterns),
+            InnerCmd::Unsubscribe(channels) if channels.is_empty() => {
+                ("unsubscribe", self.subscription.channels())
+            }
+            InnerCmd::Unsubscribe(channels) => ("unsubscribe", channels),
+            InnerCmd::PUnsubscribe(patterns) if patterns.is_empty() => {
+                ("punsubscribe", self.subscription.patterns())
+            }
+            InnerCmd::PUnsubscribe(patterns) => ("punsubscribe", patterns),
+            InnerCmd::SSubscribe(channels) => {
+                // as in Redis Cluster, the shard channels of a call share a slot, and so a shard
+                if channels
+                    .windows(2)
+                    .any(|pair| cluster::key_slot(&pair[0]) != cluster::key_slot(&pair[1]))
+                {
+                    let msg = RespValue::Error(
+                        "CROSSSLOT Keys in request don't hash to the same slot".to_string(),
+                    );
+                    self.reply(msg).await?;
+                    return Ok(Outcome::Error);
+                }
+                ("ssubscribe", channels)
+            }
+            InnerCmd::SUnsubscribe(channels) if channels.is_empty() => {
+                ("sunsubscribe", self.subscription.shard_channels())
+            }
+            InnerCmd::SUnsubscribe(channels) => ("sunsubscribe", channels),
+            _ => panic!("Command is not a subscription command"),
+        };
+        // shard channels are counted apart from the channels and patterns
+        let count = |subscription: &Subscription| match kind {
+            "ssubscribe" | "sunsubscribe" => subscription.shard_count(),
+            _ => subscription.count(),
+        } as i64;
+        if names.is_empty() {
+            // unsubscribing from everything while subscribed to nothing still gets one reply
+            let msg = RespValue::Push(vec![
+                RespValue::BulkString(Some(Bytes::from_static(kind.as_bytes()))),
+                RespValue::Null,
+                RespValue::Integer(count(&self.subscription)),
+            ]);
+            self.reply(msg).await?;
+        }
+        for name in names {
+            match kind {
+                "subscribe" => self.subscription.subscribe(&name),
+                "psubscribe" => self.subscription.psubscribe(&name),
+                "unsubscribe" => self.subscription.unsubscribe(&name),
+                "ssubscribe" => self.subscription.ssubscribe(&name),
+                "sunsubscribe" => self.subscription.sunsubscribe(&name),
+                _ => self.subscription.punsubscribe(&name),
+            }
+            let msg = RespValue::Push(vec![
+                RespValue::BulkString(Some(Bytes::from_static(kind.as_bytes()))),
+                RespValue::BulkString(Some(name.into())),
+                RespValue::Integer(count(&self.subscription)),
+            ]);
+            self.reply(msg).await?;
+        }
+        Ok(Outcome::Success)
+    }
+
+    /// Send a PONG response to the client
+    pub(crate) async fn handle_ping(&mut self) -> Result<Outcome, ConnectionError> {
+        let msg = if self.subscription.is_active() && self.protocol == Protocol::Resp2 {
+            // subscriber mode replies to PING like a pushed message
+            RespValue::Array(vec![
+                RespValue::BulkString(Some(Bytes::from_static(b"pong"))),
+                RespValue::BulkString(Some(Bytes::new())),
+            ])
+        } else {
+            RespValue::SimpleString("PONG".to_string())
+        };
+        self.reply(msg).await?;
+        Ok(Outcome::Success)
+    }
+}
+
+impl<S> Drop for Connection<S> {
+    fn drop(&mut self) {
+        self.context.clients.deregister(self.client.id);
+        // the keys set EPHEMERAL are deleted through the log, on every node
+        if self.ephemeral.is_empty() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let keys = std::mem::take(&mut self.ephemeral).into_iter().collect();
+        let op = EphemeralOp::Release(keys, self.session);
+        let release = InnerCmd::Ephemeral(*Uuid::new_v4().as_bytes(), op);
+        let deadline = Instant::now() + self.context.config.write_timeout();
+        let sync_request_tx = self.sync_request_tx.clone();
+        runtime.spawn(async move {
+            let (tx, rx) = oneshot::channel();
+            let span = info_span!("sync_round_trip", request_id = "ephemeral release");
+            let sync_request = SyncRequest::new(release, tx, deadline, span, None);
+            if sync_request_tx.send(sync_request).await.is_err() {
+                return;
+            }
+            // otherwise the keys expire once their lease lapses
+            match timeout_at(deadline, rx).await {
+                Ok(Ok(Ok(released))) => info!("Released ephemeral keys: {:?}", released),
+                _ => warn!("Could not release ephemeral keys"),
+            }
+        });
+    }
 }
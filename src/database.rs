@@ -0,0 +1,133 @@
+//! Numbered databases, selected per connection with SELECT, as isolated keyspaces of the single
+//! storage. The keys of database n are stored under `\xffdb:<n>:`, except those of database 0,
+//! stored as they are so that the data of a node from before databases is database 0. A
+//! connection moves the keys of its commands to its database once they passed the ACL, so that
+//! the rest of the node, the sync layer, the caches and the log, only sees stored keys.
+//!
+//! SWAPDB is a replicated write like FLUSHALL: the keys of both databases are read from a
+//! snapshot of the data files as it is applied, and moved to the other one, through a scratch
+//! namespace no database reads.
+
+use crate::cache::{NegativeCache, ReadCache};
+use crate::keyspace::{self, Snapshot};
+use crate::pubsub::glob_match;
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use std::path::Path;
+use tracing::info;
+
+pub(crate) const KEY_PREFIX: &[u8] = b"\xffdb:";
+
+/// Where SWAPDB moves the keys of the first database while the second one takes their place
+const SCRATCH_PREFIX: &[u8] = b"\xffdb:swap:";
+
+/// The key stored for `key` in database `db`
+pub(crate) fn key(db: u32, key: &[u8]) -> Vec<u8> {
+    if db == 0 {
+        return key.to_vec();
+    }
+    [KEY_PREFIX, db.to_string().as_bytes(), b":", key].concat()
+}
+
+/// The reply to a command naming a key no client may reach
+pub(crate) const RESERVED: &str = "ERR the key is reserved for the node";
+
+/// Whether a key named by a client is one of the node itself, or one stored for another
+/// database, which would reach around SELECT and the ACL of the database the client is in
+pub(crate) fn reserved(key: &[u8]) -> bool {
+    keyspace::internal(key) || key.starts_with(KEY_PREFIX)
+}
+
+/// The database and key of a stored key, None for the keys of the node itself
+pub(crate) fn split(stored: &[u8]) -> Option<(u32, &[u8])> {
+    let Some(rest) = stored.strip_prefix(KEY_PREFIX) else {
+        return (!keyspace::internal(stored)).then_some((0, stored));
+    };
+    let (db, key) = rest.split_at(rest.iter().position(|byte| *byte == b':')?);
+    // digits only, as `key` writes them, so the scratch namespace is in no database
+    if db.is_empty() || !db.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some((std::str::from_utf8(db).ok()?.parse().ok()?, &key[1..]))
+}
+
+/// The key of database `db` stored as `stored`, if it is one
+pub(crate) fn of(db: u32, stored: &[u8]) -> Option<&[u8]> {
+    split(stored)
+        .filter(|(of, _)| *of == db)
+        .map(|(_, key)| key)
+}
+
+/// Whether `stored` is a key of database `db` matching the glob-style pattern
+pub(crate) fn matches(db: u32, pattern: &[u8], stored: &[u8]) -> bool {
+    of(db, stored).is_some_and(|key| glob_match(pattern, key))
+}
+
+/// Swap the keys of databases `first` and `second`, live in the data files, returning how many
+/// were moved
+pub(crate) fn swap(
+    storage: &mut BitCask,
+    data_dir: &Path,
+    first: u32,
+    second: u32,
+    caches: (Option<&ReadCache>, Option<&NegativeCache>),
+) -> Result<usize, BitCaskError> {
+    if first == second {
+        return Ok(0);
+    }
+    let (mut firsts, mut seconds) = (Vec::new(), Vec::new());
+    for stored in Snapshot::take(data_dir)?.keys()? {
+        match split(&stored) {
+            Some((db, key)) if db == first => firsts.push(key.to_vec()),
+            Some((db, key)) if db == second => seconds.push(key.to_vec()),
+            _ => {}
+        }
+    }
+    let scratch = |key: &[u8]| [SCRATCH_PREFIX, key].concat();
+    for key in &firsts {
+        rename(storage, self::key(first, key), scratch(key), caches)?;
+    }
+    for key in &seconds {
+        rename(
+            storage,
+            self::key(second, key),
+            self::key(first, key),
+            caches,
+        )?;
+    }
+    for key in &firsts {
+        rename(storage, scratch(key), self::key(second, key), caches)?;
+    }
+    info!(
+        "SWAPDB {} {} -> {} and {} keys moved",
+        first,
+        second,
+        firsts.len(),
+        seconds.len()
+    );
+    Ok(firsts.len() + seconds.len())
+}
+
+/// Move the value of a stored key to another, with its deadline if any
+fn rename(
+    storage: &mut BitCask,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    (read_cache, negative_cache): (Option<&ReadCache>, Option<&NegativeCache>),
+) -> Result<(), BitCaskError> {
+    let Some(raw) = storage.get(&from) else {
+        return Ok(());
+    };
+    storage.put(&to, &raw)?;
+    storage.delete(&from)?;
+    // invalidate after the writes so no reader can cache the values again
+    for key in [&from, &to] {
+        if let Some(read_cache) = read_cache {
+            read_cache.invalidate(key);
+        }
+        if let Some(negative_cache) = negative_cache {
+            negative_cache.invalidate(key);
+        }
+    }
+    Ok(())
+}
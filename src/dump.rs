@@ -0,0 +1,68 @@
+//! DUMP and RESTORE: the value of a key, of any type, serialized so that it can be set again
+//! under any key, on this cluster or another one, to move keys without knowing their type.
+//!
+//! A serialized value is a version byte, the type tag of the value, its payload as stored, and
+//! the CRC-32 of all of that, big-endian. RESTORE rejects a version it does not know and a
+//! checksum that does not match. The format is StorgataDB's own, not the RDB encoding of Redis.
+
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::error::BitCaskError;
+use crc::{Crc, CRC_32_CKSUM};
+use thiserror::Error;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+const VERSION: u8 = 1;
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Error, Debug)]
+#[error("BUSYKEY Target key name already exists.")]
+pub(crate) struct BusyKey;
+
+pub(crate) fn busy_key() -> BitCaskError {
+    BitCaskError::UnexpectedError(BusyKey.into())
+}
+
+/// Whether the error is a RESTORE onto a key that exists, without REPLACE
+pub(crate) fn is_busy_key(e: &BitCaskError) -> bool {
+    matches!(e, BitCaskError::UnexpectedError(e) if e.is::<BusyKey>())
+}
+
+/// Serialize a stored value for DUMP, without its expiry
+pub(crate) fn serialize(raw: &[u8]) -> Result<Vec<u8>, BitCaskError> {
+    let (value_type, payload) = value::split(raw)?;
+    let mut dump = Vec::with_capacity(2 + payload.len() + CHECKSUM_LEN);
+    dump.push(VERSION);
+    dump.push(value_type as u8);
+    dump.extend_from_slice(&payload);
+    dump.extend_from_slice(&CRC32.checksum(&dump).to_be_bytes());
+    Ok(dump)
+}
+
+/// The type and payload of a value serialized by DUMP
+pub(crate) fn deserialize(dump: &[u8]) -> Result<(ValueType, &[u8]), BitCaskError> {
+    let invalid = || anyhow::anyhow!("DUMP payload version or checksum are wrong").into();
+    let Some((body, checksum)) = dump.split_at_checked(dump.len().saturating_sub(CHECKSUM_LEN))
+    else {
+        return Err(invalid());
+    };
+    if checksum.len() != CHECKSUM_LEN || CRC32.checksum(body).to_be_bytes() != checksum {
+        return Err(invalid());
+    }
+    match body {
+        [VERSION, tag, payload @ ..] => match ValueType::from_tag(*tag) {
+            // the state of the node is not for clients to set
+            Some(
+                ValueType::Proc
+                | ValueType::Feature
+                | ValueType::Raft
+                | ValueType::Script
+                | ValueType::Lock
+                | ValueType::Ephemeral
+                | ValueType::Flush,
+            )
+            | None => Err(invalid()),
+            Some(value_type) => Ok((value_type, payload)),
+        },
+        _ => Err(invalid()),
+    }
+}
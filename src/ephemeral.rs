@@ -0,0 +1,105 @@
+//! Keys owned by the connection that set them with `SET <key> <value> EPHEMERAL`, for presence
+//! and registration: once the connection closes, its node deletes them through the log, so every
+//! node deletes them alike.
+//!
+//! An ephemeral key is set with an expiry, its lease of `ephemeral-lease-ms`, which the node of
+//! the connection renews through the log while the connection stays open. If that node goes away
+//! with the connection, nobody renews the lease and the keys expire once it lapses.
+//!
+//! Each key is recorded with its connection and the deadline of its lease under a reserved
+//! prefix, hidden from KEYS, so that renewing and deleting it leave alone a key another client
+//! set since.
+
+use crate::cmd::{self, Expiry, PutOptionSerde};
+use crate::resp_codec::RespValue;
+use crate::sync_layer::RequestId;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+
+/// Keys holding the owners of the ephemeral keys, not a prefix clients are expected to use
+pub(crate) const KEY_PREFIX: &[u8] = b"\xffephemeral:";
+
+/// A write of the ephemeral keys of a connection
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum EphemeralOp {
+    // Key, value, connection, and NX or XX with the deadline of the lease as the expiry
+    Set(Vec<u8>, Vec<u8>, RequestId, PutOptionSerde),
+    // Keys as stored, connection, lease in milliseconds, unix time in milliseconds on the node of
+    // the connection
+    Renew(Vec<Vec<u8>>, RequestId, u64, u64),
+    // Keys as stored, connection
+    Release(Vec<Vec<u8>>, RequestId),
+}
+
+fn owner_key(key: &[u8]) -> Vec<u8> {
+    [KEY_PREFIX, key].concat()
+}
+
+fn encode_owner(owner: &RequestId, deadline: u64) -> Vec<u8> {
+    let payload = [owner.as_slice(), &deadline.to_le_bytes()].concat();
+    value::encode(ValueType::Ephemeral, &payload)
+}
+
+/// The connection the key was last set EPHEMERAL by, with the deadline of its lease
+fn owner(storage: &BitCask, key: &[u8]) -> Option<(RequestId, u64)> {
+    let record = storage.get(&owner_key(key))?;
+    let payload = value::expect(&record, ValueType::Ephemeral).ok()?;
+    let (owner, deadline) = payload.split_first_chunk::<16>()?;
+    Some((*owner, u64::from_le_bytes(deadline.try_into().ok()?)))
+}
+
+/// The value of the key as stored, if the connection still owns it: it set it last and it did
+/// not expire by `now`
+fn owned(storage: &BitCask, key: &Vec<u8>, connection: &RequestId, now: u64) -> Option<Vec<u8>> {
+    let (owner, deadline) = owner(storage, key)?;
+    let raw = storage.get(key)?;
+    // set by another client since, with another expiry or none
+    let ours = owner == *connection && value::deadline(&raw) == Some(deadline);
+    (ours && !value::expired(&raw, now)).then_some(raw)
+}
+
+/// Run the write against the storage
+pub(crate) fn apply(storage: &mut BitCask, op: &EphemeralOp) -> Result<RespValue, BitCaskError> {
+    match op {
+        EphemeralOp::Set(key, payload, connection, option) => {
+            cmd::set(storage, key, payload, Some(option))?;
+            if let Some(Expiry::At(deadline)) = option.expiry {
+                storage.put(&owner_key(key), &encode_owner(connection, deadline))?;
+            }
+            Ok(RespValue::SimpleString("OK".to_string()))
+        }
+        EphemeralOp::Renew(keys, connection, lease, now) => {
+            let deadline = now.saturating_add(*lease);
+            let mut renewed = 0;
+            for key in keys {
+                let Some(raw) = owned(storage, key, connection, *now) else {
+                    continue;
+                };
+                // a compressed value stays so
+                storage.put(
+                    key,
+                    &value::with_deadline(&raw, ValueType::String, Some(deadline))?,
+                )?;
+                storage.put(&owner_key(key), &encode_owner(connection, deadline))?;
+                renewed += 1;
+            }
+            Ok(RespValue::Integer(renewed))
+        }
+        EphemeralOp::Release(keys, connection) => {
+            let mut released = 0;
+            for key in keys {
+                // an expired key is deleted as well, the node would otherwise expire it
+                if owned(storage, key, connection, 0).is_some() {
+                    storage.delete(key)?;
+                    released += 1;
+                }
+                if owner(storage, key).is_some_and(|(owner, _)| owner == *connection) {
+                    storage.delete(&owner_key(key))?;
+                }
+            }
+            Ok(RespValue::Integer(released))
+        }
+    }
+}
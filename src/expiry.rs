@@ -0,0 +1,201 @@
+//! The forecast of the expirations to come, for operators to anticipate the writes that refresh
+//! the keys expiring together and the compactions after them. A background pass reads the
+//! deadline of every key of the clients, each `--expiry-forecast-interval-secs`, and counts them
+//! by the minute they expire in. INFO expiry reports, as of the time it is asked, how many keys
+//! expire in the next hour, day and week, and how many in each hour of the next day.
+//!
+//! Expired values stay in the storage until their key is written or deleted, and a compaction
+//! keeps them as well, so INFO also counts the expired values the pass found still stored.
+//!
+//! The counts are those of the local replica at the last pass, to the minute: keys written or
+//! deleted since are counted by the next one. The pass reads every value, so it waits while the
+//! node sheds load.
+
+use crate::database;
+use crate::keyspace::Snapshot;
+use crate::overload::Overload;
+use crate::value;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const MINUTE_MS: u64 = 60 * 1000;
+const HOUR: u64 = 60;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+#[derive(Default)]
+struct Forecast {
+    // keys by the unix minute they expire in, up to a week after the pass
+    minutes: BTreeMap<u64, u64>,
+    // keys expiring later than that
+    later: u64,
+    passes: u64,
+    // unix time in milliseconds the last pass started at
+    last_pass: u64,
+}
+
+impl Forecast {
+    /// Keys expiring from minute `from` up to minute `to`, excluded
+    fn between(&self, from: u64, to: u64) -> u64 {
+        self.minutes.range(from..to).map(|(_, keys)| keys).sum()
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ExpiryForecast {
+    forecast: Mutex<Forecast>,
+}
+
+impl ExpiryForecast {
+    /// Count the deadlines on a dedicated thread, a pass per interval, unless it is 0
+    pub(crate) fn spawn(
+        self: Arc<Self>,
+        data_dir: &Path,
+        interval: Duration,
+        overload: Arc<Overload>,
+    ) {
+        if interval.is_zero() {
+            return;
+        }
+        let data_dir = data_dir.to_path_buf();
+        std::thread::Builder::new()
+            .name("expiry-forecast".to_string())
+            .spawn(move || loop {
+                match self.pass(&data_dir, &overload) {
+                    Ok(volatile) => info!("Expiry forecast: {} keys with an expiry", volatile),
+                    Err(e) => warn!("Expiry forecast: pass aborted: {}", e),
+                }
+                std::thread::sleep(interval);
+            })
+            .expect("Could not spawn expiry forecast thread");
+    }
+
+    /// Count the keys of the clients by the minute they expire in, returning how many expire
+    fn pass(&self, data_dir: &Path, overload: &Overload) -> std::io::Result<u64> {
+        let started = value::now();
+        let horizon = started / MINUTE_MS + WEEK;
+        let mut forecast = Forecast::default();
+        let mut volatile = 0;
+        for entry in Snapshot::take(data_dir)?.iter()? {
+            let (key, raw) = entry?;
+            // the keys of the node itself are not the clients' to refresh
+            if database::split(&key).is_none() {
+                continue;
+            }
+            let Some(deadline) = value::deadline(&raw) else {
+                continue;
+            };
+            volatile += 1;
+            match deadline / MINUTE_MS {
+                minute if minute < horizon => *forecast.minutes.entry(minute).or_default() += 1,
+                _ => forecast.later += 1,
+            }
+            // foreground traffic comes first while the node sheds load
+            while overload.shedding() {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+        let mut current = self.forecast.lock().unwrap();
+        forecast.passes = current.passes + 1;
+        forecast.last_pass = started;
+        *current = forecast;
+        Ok(volatile)
+    }
+
+    /// Render the `expiry` section of INFO, as of now
+    pub(crate) fn info(&self) -> String {
+        self.info_at(value::now())
+    }
+
+    /// Render the `expiry` section of INFO, as of the unix time `now_ms` in milliseconds
+    fn info_at(&self, now_ms: u64) -> String {
+        let forecast = self.forecast.lock().unwrap();
+        let now = now_ms / MINUTE_MS;
+        // the keys of the minute under way count as expiring
+        let expired = forecast.between(0, now);
+        let by_hour: Vec<String> = (0..24)
+            .map(|hour| {
+                let from = now + hour * HOUR;
+                forecast.between(from, from + HOUR).to_string()
+            })
+            .collect();
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "expiry_passes:{}\r\nexpiry_last_pass_unix_ms:{}\r\nexpired_keys_stored:{}\r\nexpiring_keys_next_hour:{}\r\nexpiring_keys_next_day:{}\r\nexpiring_keys_next_week:{}\r\nexpiring_keys_later:{}\r\nexpiring_keys_by_hour:{}\r\n",
+            forecast.passes,
+            forecast.last_pass,
+            expired,
+            forecast.between(now, now + HOUR),
+            forecast.between(now, now + DAY),
+            forecast.between(now, now + WEEK),
+            forecast.later + forecast.between(now + WEEK, u64::MAX),
+            by_hour.join(","),
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forecast(minutes: &[(u64, u64)], later: u64) -> Forecast {
+        Forecast {
+            minutes: minutes.iter().copied().collect(),
+            later,
+            passes: 1,
+            last_pass: 0,
+        }
+    }
+
+    #[test]
+    fn between_counts_from_included_to_excluded() {
+        let forecast = forecast(&[(10, 1), (11, 2), (70, 3)], 0);
+        assert_eq!(forecast.between(10, 11), 1);
+        assert_eq!(forecast.between(10, 12), 3);
+        assert_eq!(forecast.between(11, 70), 2);
+        assert_eq!(forecast.between(12, 70), 0);
+        assert_eq!(forecast.between(0, u64::MAX), 6);
+    }
+
+    #[test]
+    fn info_buckets_from_the_minute_under_way() {
+        let now = 1000;
+        let forecast = ExpiryForecast {
+            forecast: Mutex::new(forecast(
+                &[
+                    (now - 1, 2),
+                    (now, 1),
+                    (now + HOUR - 1, 1),
+                    (now + HOUR, 4),
+                    (now + DAY - 1, 1),
+                    (now + DAY, 5),
+                    (now + WEEK, 7),
+                ],
+                3,
+            )),
+        };
+        let info = forecast.info_at(now * MINUTE_MS + 30_000);
+        let field = |name: &str| {
+            info.lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(field("expired_keys_stored"), "2");
+        assert_eq!(field("expiring_keys_next_hour"), "2");
+        assert_eq!(field("expiring_keys_next_day"), "7");
+        assert_eq!(field("expiring_keys_next_week"), "12");
+        assert_eq!(field("expiring_keys_later"), "10");
+        let mut by_hour = vec!["0"; 24];
+        by_hour[0] = "2";
+        by_hour[1] = "4";
+        by_hour[23] = "1";
+        assert_eq!(field("expiring_keys_by_hour"), by_hour.join(","));
+    }
+}
@@ -0,0 +1,178 @@
+//! EXPORT: what the node holds on a data subject, for the data-access requests compliance teams
+//! fulfill. The keys matching a pattern are read from a snapshot of the keyspace with their
+//! values, along with the commands that wrote them among the entries RAFT LOG still keeps, and the
+//! bundle is signed with HMAC-SHA256 under `--export-signing-key`, so that it can be shown to come
+//! from the node unaltered. An export scans the whole keyspace: a node runs at most one per
+//! `--export-min-interval-secs`, and logs who ran each.
+
+use crate::cmd::InnerCmd;
+use crate::database;
+use crate::keyspace::Snapshot;
+use crate::pubsub::glob_match;
+use crate::raft_log::{LogReader, MAX_ENTRIES};
+use crate::s3;
+use crate::value::{self, ValueType};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+pub(crate) struct Exports {
+    // None disables EXPORT
+    signing_key: Option<Vec<u8>>,
+    min_interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+#[derive(Serialize)]
+struct Bundle {
+    node: String,
+    pattern: String,
+    // unix time in seconds
+    exported_at: u64,
+    keys: Vec<Value>,
+    history: Vec<Value>,
+}
+
+impl Exports {
+    pub(crate) fn new(signing_key: Option<&str>, min_interval: Duration) -> Self {
+        Self {
+            signing_key: signing_key.map(|key| key.as_bytes().to_vec()),
+            min_interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Take the turn of the next export, or the error reply if it cannot run now
+    pub(crate) fn admit(&self) -> Result<(), String> {
+        if self.signing_key.is_none() {
+            return Err("ERR EXPORT needs --export-signing-key to sign its bundles".to_string());
+        }
+        let mut last = self.last.lock().unwrap();
+        if let Some(wait) = last.and_then(|last| self.min_interval.checked_sub(last.elapsed())) {
+            return Err(format!(
+                "ERR an export ran recently, try again in {} seconds",
+                wait.as_secs() + 1
+            ));
+        }
+        *last = Some(Instant::now());
+        Ok(())
+    }
+
+    /// The bundle of the keys matching the pattern, as JSON, and its signature in hex
+    pub(crate) fn export(
+        &self,
+        node: &str,
+        data_dir: &Path,
+        log: &impl LogReader,
+        // the database and the pattern its keys match
+        (db, pattern): (u32, &[u8]),
+        hidden: impl Fn(&InnerCmd) -> bool,
+        by: &str,
+    ) -> std::io::Result<(String, String)> {
+        let mut keys = Vec::new();
+        let now = value::now();
+        for entry in Snapshot::take(data_dir)?.iter()? {
+            let (stored, raw) = entry?;
+            let Some(key) = database::of(db, &stored) else {
+                continue;
+            };
+            if glob_match(pattern, key) && !value::expired(&raw, now) {
+                keys.push(exported(key, &raw));
+            }
+        }
+        let bundle = Bundle {
+            node: node.to_string(),
+            pattern: String::from_utf8_lossy(pattern).into_owned(),
+            exported_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            keys,
+            history: history(log, db, pattern, hidden),
+        };
+        // the audit trail, at a level the default --log-level keeps
+        warn!(
+            "Export of the keys matching '{}' by {}: {} keys, {} history entries",
+            bundle.pattern,
+            by,
+            bundle.keys.len(),
+            bundle.history.len()
+        );
+        let bundle = serde_json::to_string(&bundle)?;
+        let signing_key = self.signing_key.as_deref().unwrap_or_default();
+        let signature = s3::hex(&s3::hmac(signing_key, bundle.as_bytes()));
+        Ok((bundle, signature))
+    }
+}
+
+/// A key with its value, as readable as its type allows
+fn exported(key: &[u8], raw: &[u8]) -> Value {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    let (value_type, value) = match value::stored_type(raw) {
+        // without a header, a value written as a string before types were recorded
+        None => ("string", Value::from(text(raw))),
+        Some(ValueType::String) => (
+            "string",
+            Value::from(text(&value::string(raw).unwrap_or_default())),
+        ),
+        Some(ValueType::List) => (
+            "list",
+            value::expect(raw, ValueType::List)
+                .ok()
+                .and_then(|payload| bincode::deserialize::<Vec<Vec<u8>>>(payload).ok())
+                .map(|list| list.iter().map(|element| text(element)).collect())
+                .unwrap_or_default(),
+        ),
+        Some(ValueType::ZSet) => (
+            "zset",
+            value::expect(raw, ValueType::ZSet)
+                .ok()
+                .and_then(|payload| bincode::deserialize::<Vec<(f64, Vec<u8>)>>(payload).ok())
+                .map(|zset| {
+                    zset.iter()
+                        .map(|(score, member)| json!([text(member), score]))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ),
+        // sketches keep no element as it was written, only their encoding is left to show
+        Some(value_type) => (
+            "sketch",
+            Value::from(s3::hex(value::expect(raw, value_type).unwrap_or_default())),
+        ),
+    };
+    json!({ "key": text(key), "type": value_type, "value": value })
+}
+
+/// The commands of the kept entries that wrote a key matching the pattern, oldest first
+fn history(
+    log: &impl LogReader,
+    db: u32,
+    pattern: &[u8],
+    hidden: impl Fn(&InnerCmd) -> bool,
+) -> Vec<Value> {
+    let Some((mut from, last)) = log.bounds() else {
+        return Vec::new();
+    };
+    let mut history = Vec::new();
+    while from <= last {
+        // entries dropped meanwhile to make room end the history early
+        let Ok(entries) = log.read::<InnerCmd>(from, last) else {
+            break;
+        };
+        from += MAX_ENTRIES;
+        for (index, inner_cmd) in entries {
+            let matches = inner_cmd
+                .written_keys()
+                .iter()
+                .any(|key| database::matches(db, pattern, key));
+            if matches && !hidden(&inner_cmd) {
+                history.push(json!({ "index": index, "command": inner_cmd }));
+            }
+        }
+    }
+    history
+}
@@ -0,0 +1,78 @@
+//! Failpoints: named choke points where a test can make the node panic, stall or fail on purpose,
+//! so crash-recovery paths can be exercised deterministically. They are only compiled in with the
+//! `failpoints` feature, and are set at runtime with `DEBUG FAILPOINT <name> <action>`.
+
+use serde::{Deserialize, Serialize};
+
+/// A request received from a client, before it is proposed to Raft
+pub(crate) const BEFORE_PROPOSE: &str = "before-propose";
+/// A committed entry, before it is applied to the storage
+pub(crate) const BEFORE_APPLY: &str = "before-apply";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum Action {
+    Off,
+    Panic,
+    // Milliseconds
+    Sleep(u64),
+    /// Skip the guarded step and fail it
+    Error,
+}
+
+#[cfg(feature = "failpoints")]
+mod registry {
+    use super::{Action, BEFORE_APPLY, BEFORE_PROPOSE};
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use tracing::warn;
+
+    static ACTIONS: Mutex<BTreeMap<&'static str, Action>> = Mutex::new(BTreeMap::new());
+
+    pub(crate) fn set(name: &str, action: Action) -> Result<(), String> {
+        let Some(name) = [BEFORE_PROPOSE, BEFORE_APPLY]
+            .into_iter()
+            .find(|known| *known == name)
+        else {
+            return Err(format!("unknown failpoint '{}'", name));
+        };
+        let mut actions = ACTIONS.lock().unwrap();
+        match action {
+            Action::Off => actions.remove(name),
+            action => actions.insert(name, action),
+        };
+        Ok(())
+    }
+
+    pub(crate) async fn eval(name: &str) -> Result<(), String> {
+        let action = ACTIONS.lock().unwrap().get(name).cloned();
+        match action {
+            None | Some(Action::Off) => Ok(()),
+            Some(Action::Panic) => panic!("failpoint {} triggered", name),
+            Some(Action::Sleep(ms)) => {
+                warn!("Failpoint {}: sleeping {} ms", name, ms);
+                tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+                Ok(())
+            }
+            Some(Action::Error) => Err(format!("failpoint {} triggered", name)),
+        }
+    }
+}
+
+#[cfg(not(feature = "failpoints"))]
+mod registry {
+    use super::Action;
+
+    pub(crate) fn set(_name: &str, _action: Action) -> Result<(), String> {
+        Err("failpoints are not compiled in, build with --features failpoints".to_string())
+    }
+
+    pub(crate) async fn eval(_name: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Set the action of a failpoint, `Off` clears it
+pub(crate) use registry::set;
+
+/// Run the action set on the failpoint, if any. Errors when the guarded step should fail.
+pub(crate) use registry::eval;
@@ -0,0 +1,149 @@
+//! Feature flags: experimental subsystems can ship dark and be enabled gradually, without a new
+//! release. A flag starts from its default, changed on a node by `--enable-features` and
+//! `--disable-features`, and `FEATURE ENABLE` and `FEATURE DISABLE` set it for the whole group:
+//! they are replicated writes, kept under a reserved prefix hidden from KEYS, so every node
+//! agrees on them from then on and keeps them across restarts.
+
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+/// Keys holding the flags set for the group, not a prefix clients are expected to use
+pub(crate) const KEY_PREFIX: &[u8] = b"\xfffeature:";
+
+/// A subsystem behind a flag
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Feature {
+    // HELLO 3
+    Resp3,
+    // SSUBSCRIBE and SPUBLISH
+    ShardedPubSub,
+}
+
+/// Every feature, in name order
+const FEATURES: [Feature; 2] = [Feature::Resp3, Feature::ShardedPubSub];
+
+impl Feature {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Feature::Resp3 => "resp3",
+            Feature::ShardedPubSub => "sharded-pubsub",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        FEATURES
+            .into_iter()
+            .find(|feature| feature.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether the feature runs until a flag turns it off. The features that already shipped
+    /// stay on, new ones start off.
+    fn enabled_by_default(&self) -> bool {
+        match self {
+            Feature::Resp3 | Feature::ShardedPubSub => true,
+        }
+    }
+
+    fn key(&self) -> Vec<u8> {
+        [KEY_PREFIX, self.name().as_bytes()].concat()
+    }
+}
+
+/// A FEATURE subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum FeatureOp {
+    // Name
+    Enable(String),
+    // Name
+    Disable(String),
+    List,
+}
+
+pub(crate) struct Features {
+    // indexed like FEATURES
+    enabled: [AtomicBool; FEATURES.len()],
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            enabled: FEATURES.map(|feature| AtomicBool::new(feature.enabled_by_default())),
+        }
+    }
+}
+
+impl Features {
+    /// The flags of the node: the defaults, changed by the command line, then by the flags set
+    /// for the group
+    pub(crate) fn load(
+        enable: &[String],
+        disable: &[String],
+        storage: &BitCask,
+    ) -> anyhow::Result<Self> {
+        let features = Self::default();
+        for (names, enabled) in [(enable, true), (disable, false)] {
+            for name in names {
+                let feature = Feature::parse(name)
+                    .ok_or_else(|| anyhow::anyhow!("unknown feature '{}'", name))?;
+                features.set(feature, enabled);
+            }
+        }
+        for feature in FEATURES {
+            if let Some(raw) = storage.get(&feature.key()) {
+                features.set(feature, value::expect(&raw, ValueType::Feature)? == b"1");
+            }
+        }
+        Ok(features)
+    }
+
+    pub(crate) fn enabled(&self, feature: Feature) -> bool {
+        self.enabled[feature as usize].load(Ordering::Relaxed)
+    }
+
+    /// Check the name of the feature a FEATURE subcommand changes, before it is replicated
+    pub(crate) fn validate(op: &FeatureOp) -> Result<(), String> {
+        match op {
+            FeatureOp::Enable(name) | FeatureOp::Disable(name)
+                if Feature::parse(name).is_none() =>
+            {
+                Err(format!("ERR unknown feature '{}'", name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Set the flag for the group, in the apply path of every node
+    pub(crate) fn apply(&self, storage: &mut BitCask, op: &FeatureOp) -> Result<(), BitCaskError> {
+        let (name, enabled) = match op {
+            FeatureOp::Enable(name) => (name, true),
+            FeatureOp::Disable(name) => (name, false),
+            FeatureOp::List => panic!("FEATURE LIST is not replicated"),
+        };
+        // validated before it was replicated, unless it was removed since
+        let Some(feature) = Feature::parse(name) else {
+            warn!("Ignoring the flag of the unknown feature {}", name);
+            return Ok(());
+        };
+        let flag: &[u8] = if enabled { b"1" } else { b"0" };
+        storage.put(&feature.key(), &value::encode(ValueType::Feature, flag))?;
+        self.set(feature, enabled);
+        info!("Feature {} enabled: {}", feature.name(), enabled);
+        Ok(())
+    }
+
+    /// Every feature and whether it is enabled, in name order
+    pub(crate) fn list(&self) -> Vec<(&'static str, bool)> {
+        FEATURES
+            .into_iter()
+            .map(|feature| (feature.name(), self.enabled(feature)))
+            .collect()
+    }
+
+    fn set(&self, feature: Feature, enabled: bool) {
+        self.enabled[feature as usize].store(enabled, Ordering::Relaxed);
+    }
+}
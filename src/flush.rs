@@ -0,0 +1,219 @@
+//! FLUSHALL and FLUSHDB: a replicated write deleting every key of the clients, or those of a
+//! database, so that every node clears its keyspace at the same entry of the log. The storage
+//! deletes keys one at a time and has no iterator, so they are read from a snapshot of the data
+//! files taken as the flush is applied, which holds the keyspace as of that entry on every node.
+//! The keys of the node itself, its procedures, feature flags and applied index, stay.
+//!
+//! With ASYNC, the flush replies once applied and its keys are deleted on a thread of their own.
+//! The entries committed after it wait for the thread before they are applied, so none of their
+//! writes is deleted; the reads served locally meanwhile may still see some of the keys.
+//!
+//! FLUSHALL wipes the whole cluster, so it takes two steps: FLUSHALL SCHEDULE replies a token,
+//! and FLUSHALL CONFIRM <token> on the same node within the window proposes the flush. The token
+//! is no secret, the ACL tells who may flush; it makes sure the flush was asked for twice, on
+//! purpose. The entry is fenced by the flush epoch the node applied when it was scheduled: it is
+//! refused if another FLUSHALL applied in between, and bumps the epoch once applied. The tracking
+//! clients are invalidated and the new epoch is published on `__flushall__`, so that the
+//! consumers of changes, which missed the keys deleted, know to start over.
+
+use crate::cache::ReadCache;
+use crate::database;
+use crate::keyspace::Snapshot;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Key holding the flush epoch, the count of FLUSHALL applied
+pub(crate) const EPOCH_KEY: &[u8] = b"\xffflushepoch";
+/// Channel the new flush epoch is published on once a FLUSHALL applies
+pub(crate) const EPOCH_CHANNEL: &[u8] = b"__flushall__";
+
+/// A step of FLUSHALL
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum FlushAllOp {
+    // ASYNC, refused: FLUSHALL alone is never run
+    Unconfirmed(bool),
+    // ASYNC
+    Schedule(bool),
+    // Token
+    Confirm(String),
+    // ASYNC, the flush epoch it was scheduled in, as replicated once confirmed
+    Fenced(bool, u64),
+}
+
+/// A FLUSHALL scheduled, waiting for its confirmation
+struct Scheduled {
+    token: String,
+    lazy: bool,
+    epoch: u64,
+    deadline: Instant,
+}
+
+pub(crate) struct Flusher {
+    // the thread deleting the keys of an ASYNC flush, until an entry waits for it
+    running: Mutex<Option<JoinHandle<()>>>,
+    // the last FLUSHALL scheduled on this node, a new one replaces it
+    scheduled: Mutex<Option<Scheduled>>,
+    schedules: AtomicU64,
+    // how long a scheduled FLUSHALL waits for its confirmation
+    window: Duration,
+}
+
+impl Default for Flusher {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60))
+    }
+}
+
+impl Flusher {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            running: Mutex::new(None),
+            scheduled: Mutex::new(None),
+            schedules: AtomicU64::new(0),
+            window,
+        }
+    }
+
+    pub(crate) fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Schedule a FLUSHALL in the flush epoch the node applied, returning the token confirming it
+    pub(crate) fn schedule(&self, lazy: bool, epoch: u64) -> String {
+        let schedule = self.schedules.fetch_add(1, Ordering::Relaxed);
+        let digest = Sha1::digest(format!("flushall:{}:{}", epoch, schedule));
+        let token: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        *self.scheduled.lock().unwrap() = Some(Scheduled {
+            token: token.clone(),
+            lazy,
+            epoch,
+            deadline: Instant::now() + self.window,
+        });
+        token
+    }
+
+    /// The FLUSHALL to replicate, ASYNC and its epoch, if the token confirms the one scheduled
+    /// within its window
+    pub(crate) fn confirm(&self, token: &str) -> Option<(bool, u64)> {
+        let mut scheduled = self.scheduled.lock().unwrap();
+        if scheduled
+            .as_ref()
+            .is_none_or(|scheduled| scheduled.token != token)
+        {
+            return None;
+        }
+        scheduled
+            .take()
+            .filter(|scheduled| Instant::now() < scheduled.deadline)
+            .map(|scheduled| (scheduled.lazy, scheduled.epoch))
+    }
+
+    /// Flush every database if the flush epoch is still `epoch`, returning the new one
+    pub(crate) fn flush_all(
+        &self,
+        storage: &mut BitCask,
+        data_dir: &Path,
+        read_cache: Option<Arc<ReadCache>>,
+        lazy: bool,
+        epoch: u64,
+    ) -> Result<u64, BitCaskError> {
+        let current = self::epoch(storage)?;
+        if current != epoch {
+            return Err(BitCaskError::UnexpectedError(anyhow::anyhow!(
+                "FLUSHALL was scheduled in flush epoch {}, another one applied since, now in {}",
+                epoch,
+                current
+            )));
+        }
+        self.flush(storage, data_dir, read_cache, None, lazy)?;
+        storage.put(
+            &EPOCH_KEY.to_vec(),
+            &value::encode(ValueType::Flush, &(epoch + 1).to_le_bytes()),
+        )?;
+        Ok(epoch + 1)
+    }
+
+    /// Wait for the keys of an ASYNC flush to be deleted, if one is running
+    pub(crate) fn wait(&self) {
+        if let Some(thread) = self.running.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Delete every key of the clients live in the data files, or those of database `db`, before
+    /// returning unless `lazy`
+    pub(crate) fn flush(
+        &self,
+        storage: &BitCask,
+        data_dir: &Path,
+        read_cache: Option<Arc<ReadCache>>,
+        db: Option<u32>,
+        lazy: bool,
+    ) -> Result<(), BitCaskError> {
+        self.wait();
+        let snapshot = Snapshot::take(data_dir)?;
+        let storage = storage.clone();
+        if !lazy {
+            let deleted = clear(storage, &snapshot, read_cache.as_deref(), db)?;
+            info!("FLUSHALL -> {} keys deleted", deleted);
+            return Ok(());
+        }
+        let thread = std::thread::Builder::new()
+            .name("flusher".to_string())
+            .spawn(
+                move || match clear(storage, &snapshot, read_cache.as_deref(), db) {
+                    Ok(deleted) => info!("FLUSHALL ASYNC -> {} keys deleted", deleted),
+                    // as for any write failing in the apply path, the node may now differ
+                    Err(e) => warn!("FLUSHALL ASYNC: could not delete every key: {}", e),
+                },
+            )?;
+        *self.running.lock().unwrap() = Some(thread);
+        Ok(())
+    }
+}
+
+/// Delete the keys of the clients in the snapshot, or those of database `db`, returning how many
+fn clear(
+    mut storage: BitCask,
+    snapshot: &Snapshot,
+    read_cache: Option<&ReadCache>,
+    db: Option<u32>,
+) -> Result<usize, BitCaskError> {
+    let mut deleted = 0;
+    for key in snapshot.keys()? {
+        let flushed = match db {
+            Some(db) => database::of(db, &key).is_some(),
+            None => database::split(&key).is_some(),
+        };
+        if !flushed {
+            continue;
+        }
+        storage.delete(&key)?;
+        // invalidate after the delete so no reader can cache the value again
+        if let Some(read_cache) = read_cache {
+            read_cache.invalidate(&key);
+        }
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+/// The flush epoch the node applied, the count of FLUSHALL since the cluster started
+pub(crate) fn epoch(storage: &BitCask) -> Result<u64, BitCaskError> {
+    match storage.get(&EPOCH_KEY.to_vec()) {
+        Some(raw) => {
+            let payload = value::expect(&raw, ValueType::Flush)?;
+            Ok(u64::from_le_bytes(payload.try_into().unwrap_or_default()))
+        }
+        None => Ok(0),
+    }
+}
@@ -0,0 +1,336 @@
+//! Golden transcript tests.
+//!
+//! Every `*.resp` file under `tests/golden` is a request/response transcript. Lines starting with
+//! `>` are sent to the server, lines starting with `<` are the bytes expected back, and lines
+//! starting with `#` are comments. Escapes (`\r`, `\n`, `\\`, `\xNN`) are expanded before sending
+//! or comparing, so the comparison is byte-for-byte.
+//!
+//! The transcripts of the commands shared with Redis expect the bytes Redis replies, its errors
+//! included: acl, bitmaps, client, compat, config, databases, dump, inline, keys, lists, ping,
+//! pipeline, protocol_limits, pubsub, resp3, sharded_pubsub, strings, tracking, types and zsets.
+//! The options Redis does not have, such as SET ID or
+//! CLIENT TRACE, and the other transcripts, of the commands of StorgataDB alone, pin replies of
+//! its own: a change of those is a change of StorgataDB, not drift from Redis.
+//!
+//! The sync layer replicates through a stand-in for Raft that commits every entry as it is
+//! proposed, so the transcripts exercise the codec, the command parser, the sync layer and the
+//! apply path without a Raft cluster.
+
+use crate::acl::Acl;
+use crate::analytics::Analytics;
+use crate::backup::Backups;
+use crate::cache::{NegativeCache, ReadCache};
+use crate::clients::Clients;
+use crate::compaction::Compaction;
+use crate::config::Config;
+use crate::cmd::{NodeContext, InnerCmd};
+use crate::connection::Connection;
+use crate::expiry::ExpiryForecast;
+use crate::export::Exports;
+use crate::flush::Flusher;
+use crate::feature::Features;
+use crate::hooks::{CommandHook, Hooks};
+use crate::lock::Locks;
+use crate::maintenance::Maintenance;
+use crate::memory::Memory;
+use crate::monitor::Monitor;
+use crate::overload::Overload;
+use crate::plugin::{self, Buf, CommandSpec, Host, Plugins};
+use crate::prefix_stats::PrefixStats;
+use crate::procedure::Procedures;
+use crate::pubsub::Broker;
+use crate::raft_log::CommittedLog;
+use crate::scrubber::ScrubStats;
+use crate::shred::SecureDelete;
+use crate::shutdown::Shutdown;
+use crate::slowlog::SlowLog;
+use crate::slo::SloTracker;
+use crate::standby::Standby;
+use crate::stats::Stats;
+use crate::sync_layer::{Alone, Batching, RaftStats, SyncLayer, SyncRequest};
+use bitcask_engine_rs::bitcask::BitCask;
+use std::ffi::{c_char, CStr};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Once};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+
+enum Step {
+    Send(Vec<u8>),
+    Expect(Vec<u8>),
+}
+
+fn unescape(line: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 1 < bytes.len() {
+            match bytes[i + 1] {
+                b'r' => out.push(b'\r'),
+                b'n' => out.push(b'\n'),
+                b'\\' => out.push(b'\\'),
+                b'x' if i + 3 < bytes.len() => {
+                    let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap();
+                    out.push(u8::from_str_radix(hex, 16).unwrap());
+                    i += 2;
+                }
+                other => panic!("unknown escape \\{}", other as char),
+            }
+            i += 2;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+fn parse_transcript(content: &str) -> Vec<Step> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_at(1) {
+            (">", rest) => Step::Send(unescape(rest.trim_start())),
+            ("<", rest) => Step::Expect(unescape(rest.trim_start())),
+            _ => panic!("malformed transcript line: {}", line),
+        })
+        .collect()
+}
+
+/// Rejects the commands on keys under `hook:deny:`, so transcripts exercise the hooks
+struct DenyHook;
+
+impl CommandHook for DenyHook {
+    fn before_execute(&self, cmd: &InnerCmd, _user: Option<&str>) -> Result<(), String> {
+        match cmd.key() {
+            Some(key) if key.starts_with(b"hook:deny:") => {
+                Err("ERR denied by a command hook".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The commands of the plugin the transcripts run, registered through the C ABI
+struct GoldenCommands([CommandSpec; 4]);
+
+// SAFETY: the names are static strings
+unsafe impl Sync for GoldenCommands {}
+
+static GOLDEN_COMMANDS: GoldenCommands = GoldenCommands([
+    CommandSpec {
+        name: c"PLUGIN.APPEND".as_ptr(),
+        kind: plugin::KIND_WRITE,
+        arity: 3,
+        key_index: 1,
+    },
+    CommandSpec {
+        name: c"PLUGIN.STRLEN".as_ptr(),
+        kind: plugin::KIND_READ,
+        arity: 2,
+        key_index: 1,
+    },
+    // writes the second key, which a plugin may not do
+    CommandSpec {
+        name: c"PLUGIN.COPY".as_ptr(),
+        kind: plugin::KIND_WRITE,
+        arity: 3,
+        key_index: 1,
+    },
+    CommandSpec {
+        name: std::ptr::null(),
+        kind: 0,
+        arity: 0,
+        key_index: 0,
+    },
+]);
+
+unsafe extern "C" fn golden_commands() -> *const CommandSpec {
+    GOLDEN_COMMANDS.0.as_ptr()
+}
+
+unsafe extern "C" fn golden_execute(
+    host: *const Host,
+    name: *const c_char,
+    args: *const Buf,
+    nargs: usize,
+) {
+    let host = &*host;
+    let args: Vec<&[u8]> = std::slice::from_raw_parts(args, nargs)
+        .iter()
+        .map(|arg| std::slice::from_raw_parts(arg.data, arg.len))
+        .collect();
+    let mut value = Buf {
+        data: std::ptr::null(),
+        len: 0,
+    };
+    let found = (host.get)(host.ctx, args[0].as_ptr(), args[0].len(), &mut value);
+    if found < 0 {
+        return;
+    }
+    let current = if found == 1 {
+        std::slice::from_raw_parts(value.data, value.len).to_vec()
+    } else {
+        Vec::new()
+    };
+    let put = |key: &[u8], value: &[u8]| {
+        (host.put)(host.ctx, key.as_ptr(), key.len(), value.as_ptr(), value.len()) == 0
+    };
+    let reply_integer = |n: usize| {
+        (host.reply)(host.ctx, plugin::REPLY_INTEGER, n as i64, std::ptr::null(), 0)
+    };
+    match CStr::from_ptr(name).to_bytes() {
+        b"plugin.append" => {
+            let appended = [current.as_slice(), args[1]].concat();
+            if put(args[0], &appended) {
+                reply_integer(appended.len());
+            }
+        }
+        b"plugin.strlen" => reply_integer(current.len()),
+        b"plugin.copy" => {
+            if put(args[1], &current) {
+                (host.reply)(host.ctx, plugin::REPLY_STATUS, 0, b"OK".as_ptr(), 2);
+            }
+        }
+        _ => {
+            let msg = b"ERR unknown command";
+            (host.reply)(host.ctx, plugin::REPLY_ERROR, 0, msg.as_ptr(), msg.len());
+        }
+    }
+}
+
+static INSTALL_PLUGINS: Once = Once::new();
+
+async fn start_server(data_dir: &Path) -> DuplexStream {
+    INSTALL_PLUGINS.call_once(|| {
+        let mut plugins = Plugins::default();
+        // SAFETY: the golden plugin follows the ABI and is static
+        unsafe { plugins.register(golden_commands, golden_execute) }.unwrap();
+        plugins.install().unwrap();
+    });
+    let storage = BitCask::new(data_dir).unwrap();
+    let config = Arc::new(Config::default());
+    let raft_stats = Arc::new(RaftStats::default());
+    let mut hooks = Hooks::default();
+    hooks.register(DenyHook);
+    // tracking the keys, unbounded until a transcript sets maxmemory
+    let memory = Memory::load(config.clone(), data_dir).unwrap();
+    let context = NodeContext {
+        acl: Arc::new(Acl::default()),
+        clients: Arc::new(Clients::default()),
+        stats: Arc::new(Stats::default()),
+        slowlog: Arc::new(SlowLog::default()),
+        prefix_stats: Arc::new(PrefixStats::new(&["app=app:".to_string()]).unwrap()),
+        monitor: Arc::new(Monitor::default()),
+        config: config.clone(),
+        data_dir: data_dir.to_path_buf(),
+        nodes: vec!["127.0.0.1:3000".to_string()],
+        peers: Vec::new(),
+        replica_of: Vec::new(),
+        databases: 16,
+        read_view_max: Duration::from_secs(60),
+        raft_stats: raft_stats.clone(),
+        raft_log: Arc::new(CommittedLog::new(1024 * 1024)),
+        overload: Arc::new(Overload::new(config, raft_stats)),
+        hooks: Arc::new(hooks),
+        shutdown: Arc::new(Shutdown::default()),
+        procedures: Arc::new(Procedures::default()),
+        features: Arc::new(Features::default()),
+        locks: Arc::new(Locks::default()),
+        secure_delete: Arc::new(SecureDelete::new(&["secret:".to_string()])),
+        exports: Arc::new(Exports::new(None, Duration::from_secs(60))),
+        flusher: Arc::new(Flusher::default()),
+        analytics: Some(Arc::new(
+            Analytics::new(&data_dir.join("analytics"), Duration::from_secs(60)).unwrap(),
+        )),
+        backups: Arc::new(Backups::new(&data_dir.join("backups"), None, 2, 1, None).unwrap()),
+        compaction: Arc::new(Compaction::new(None, 0.5, 0)),
+        expiry: Arc::new(ExpiryForecast::default()),
+        started: Instant::now(),
+        broker: Arc::new(Broker::default()),
+        // small caches, so transcripts also exercise invalidation from the apply path
+        read_cache: Some(Arc::new(ReadCache::new(1024))),
+        negative_cache: Some(Arc::new(NegativeCache::new(16))),
+        memory: Some(Arc::new(memory)),
+        standby: Arc::new(Standby::default()),
+        maintenance: Arc::new(Maintenance::default()),
+        audit: None,
+    };
+    tokio::spawn(context.locks.clone().notify_expired(context.broker.clone()));
+    let (sync_request_tx, sync_request_rx) = mpsc::channel::<SyncRequest<InnerCmd>>(100);
+    SyncLayer::<InnerCmd>::new(
+        Box::new(Alone),
+        Batching::default(),
+        storage.clone(),
+        context.clone(),
+        context.raft_stats.clone(),
+        context.raft_log.clone(),
+    )
+    .run(sync_request_rx)
+    .await;
+    // the client talks to the connection over an in-memory stream, from a made-up address
+    let (client, stream) = tokio::io::duplex(64 * 1024);
+    let addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+    let mut connection = Connection::new(
+        stream,
+        addr,
+        storage,
+        sync_request_tx,
+        Arc::new(SloTracker::new(60, None)),
+        Arc::new(ScrubStats::default()),
+        context,
+    );
+    tokio::spawn(async move {
+        let _ = connection.handle(addr).await;
+    });
+    client
+}
+
+async fn run_transcript(path: &Path) {
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut client = start_server(data_dir.path()).await;
+    let content = std::fs::read_to_string(path).unwrap();
+    for (n, step) in parse_transcript(&content).into_iter().enumerate() {
+        match step {
+            Step::Send(bytes) => client.write_all(&bytes).await.unwrap(),
+            Step::Expect(expected) => {
+                let mut actual = vec![0u8; expected.len()];
+                timeout(Duration::from_secs(5), client.read_exact(&mut actual))
+                    .await
+                    .unwrap_or_else(|_| panic!("{}: step {} timed out", path.display(), n))
+                    .unwrap();
+                assert_eq!(
+                    String::from_utf8_lossy(&actual),
+                    String::from_utf8_lossy(&expected),
+                    "{}: reply of step {} drifted from the recorded transcript",
+                    path.display(),
+                    n
+                );
+            }
+        }
+    }
+}
+
+fn transcripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "resp"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[tokio::test]
+async fn golden_transcripts() {
+    let paths = transcripts();
+    assert!(!paths.is_empty(), "no golden transcripts found");
+    for path in paths {
+        run_transcript(&path).await;
+    }
+}
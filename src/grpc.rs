@@ -0,0 +1,588 @@
+//! The gRPC API of the node, for services that prefer protobuf contracts to the Redis protocol:
+//! `storgata.v1.Kv` gets, puts, deletes and scans the string keys of the clients, and
+//! `storgata.v1.Admin` reports the status and the members of the node and fails a warm standby
+//! over, as proto/storgata.proto describes them.
+//!
+//! The writes are proposed through the sync layer as those of the RESP connections are, and the
+//! reads follow --read-consistency. A request runs as the user its `username` and `password`
+//! metadata name, or as the default user if it may run without a password, under the ACL, the
+//! hooks, the standby, maintenance and read replica refusals of the RESP commands.
+//!
+//! No protobuf compiler is needed to build the node: the messages are written here after the
+//! proto file, and the service routes the requests by their path itself.
+
+use crate::acl::Category;
+use crate::cli::Args;
+use crate::cmd::{Expiry, InnerCmd, NodeContext, PutOptionSerde};
+use crate::connection::error_reply;
+use crate::keyspace::Snapshot;
+use crate::resp_codec::RespValue;
+use crate::sync_layer::{self, SyncRequest};
+use crate::{database, memory, overload, standby, value};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use std::convert::Infallible;
+use std::future::Future;
+use std::task::{Context, Poll};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+use tonic::body::Body;
+use tonic::codegen::{http, BoxFuture, Service, StdError};
+use tonic::metadata::MetadataMap;
+use tonic::server::{Grpc, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn, Span};
+use uuid::Uuid;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct GetRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub(crate) key: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub(crate) db: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct GetResponse {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub(crate) value: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct PutRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub(crate) key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub(crate) value: Vec<u8>,
+    #[prost(uint32, tag = "3")]
+    pub(crate) db: u32,
+    #[prost(uint64, tag = "4")]
+    pub(crate) ttl_ms: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct PutResponse {
+    #[prost(uint64, tag = "1")]
+    pub(crate) applied_index: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct DeleteRequest {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub(crate) keys: Vec<Vec<u8>>,
+    #[prost(uint32, tag = "2")]
+    pub(crate) db: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct DeleteResponse {
+    #[prost(uint64, tag = "1")]
+    pub(crate) deleted: u64,
+    #[prost(uint64, tag = "2")]
+    pub(crate) applied_index: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ScanRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    pub(crate) prefix: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub(crate) db: u32,
+    #[prost(uint32, tag = "3")]
+    pub(crate) limit: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct KeyValue {
+    #[prost(bytes = "vec", tag = "1")]
+    pub(crate) key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub(crate) value: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ScanResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub(crate) entries: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct StatusRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct StatusResponse {
+    #[prost(string, tag = "1")]
+    pub(crate) node: String,
+    #[prost(uint64, tag = "2")]
+    pub(crate) applied_index: u64,
+    #[prost(uint64, tag = "3")]
+    pub(crate) apply_lag: u64,
+    #[prost(bool, tag = "4")]
+    pub(crate) halted: bool,
+    #[prost(bool, tag = "5")]
+    pub(crate) standby: bool,
+    #[prost(bool, tag = "6")]
+    pub(crate) maintenance: bool,
+    #[prost(string, repeated, tag = "7")]
+    pub(crate) replica_of: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct MembershipRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct Member {
+    #[prost(string, tag = "1")]
+    pub(crate) node: String,
+    #[prost(string, tag = "2")]
+    pub(crate) kv_addr: String,
+    #[prost(bool, tag = "3")]
+    pub(crate) local: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct MembershipResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub(crate) members: Vec<Member>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct FailoverRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct FailoverResponse {}
+
+/// Serve both services on the listener
+pub(crate) async fn serve(
+    listener: TcpListener,
+    args: &Args,
+    sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+    storage: BitCask,
+    context: NodeContext,
+) {
+    let api = Api {
+        members: members(args),
+        sync_request_tx,
+        storage,
+        context,
+    };
+    if let Err(e) = tonic::transport::Server::builder()
+        .serve_with_incoming(api, TcpIncoming::from(listener))
+        .await
+    {
+        warn!("gRPC: the server stopped: {}", e);
+    }
+}
+
+/// The members of the group, from the flags, this node included
+fn members(args: &Args) -> Vec<Member> {
+    let self_addr = args.self_addr();
+    if args.peer_addr().is_empty() {
+        return vec![Member {
+            node: self_addr,
+            kv_addr: String::new(),
+            local: true,
+        }];
+    }
+    let kv_addrs = args.peer_kv_addr();
+    args.peer_addr()
+        .iter()
+        .enumerate()
+        .map(|(i, node)| Member {
+            node: node.clone(),
+            kv_addr: kv_addrs.get(i).cloned().unwrap_or_default(),
+            local: *node == self_addr,
+        })
+        .collect()
+}
+
+/// Both services, routing the requests by their path
+#[derive(Clone)]
+struct Api {
+    members: Vec<Member>,
+    sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+    storage: BitCask,
+    context: NodeContext,
+}
+
+impl<B> Service<http::Request<B>> for Api
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let api = self.clone();
+        match request.uri().path() {
+            "/storgata.v1.Kv/Get" => unary(request, move |r| api.clone().get(r)),
+            "/storgata.v1.Kv/Put" => unary(request, move |r| api.clone().put(r)),
+            "/storgata.v1.Kv/Delete" => unary(request, move |r| api.clone().delete(r)),
+            "/storgata.v1.Kv/Scan" => unary(request, move |r| api.clone().scan(r)),
+            "/storgata.v1.Admin/Status" => unary(request, move |r| api.clone().status(r)),
+            "/storgata.v1.Admin/Membership" => unary(request, move |r| api.clone().membership(r)),
+            "/storgata.v1.Admin/Failover" => unary(request, move |r| api.clone().failover(r)),
+            _ => Box::pin(async move {
+                let mut response = http::Response::new(Body::default());
+                let headers = response.headers_mut();
+                headers.insert(
+                    Status::GRPC_STATUS,
+                    (tonic::Code::Unimplemented as i32).into(),
+                );
+                headers.insert(
+                    http::header::CONTENT_TYPE,
+                    tonic::metadata::GRPC_CONTENT_TYPE,
+                );
+                Ok(response)
+            }),
+        }
+    }
+}
+
+/// A method of a service, decoding its request and encoding its response with prost
+struct Method<F>(F);
+
+impl<Req, Res, F, Fut> UnaryService<Req> for Method<F>
+where
+    F: FnMut(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Res>, Status>>,
+{
+    type Response = Res;
+    type Future = Fut;
+
+    fn call(&mut self, request: Request<Req>) -> Fut {
+        (self.0)(request)
+    }
+}
+
+fn unary<B, Req, Res, F, Fut>(
+    request: http::Request<B>,
+    method: F,
+) -> BoxFuture<http::Response<Body>, Infallible>
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+    Res: prost::Message + Send + 'static,
+    F: FnMut(Request<Req>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<Response<Res>, Status>> + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(tonic_prost::ProstCodec::<Res, Req>::default());
+        Ok(grpc.unary(Method(method), request).await)
+    })
+}
+
+/// The status of an error reply of the RESP commands, by its code
+fn status(msg: &str) -> Status {
+    let code = msg.split(' ').next().unwrap_or_default();
+    match code {
+        "NOAUTH" => Status::unauthenticated(msg),
+        "NOPERM" => Status::permission_denied(msg),
+        "WRONGTYPE" | "READONLY" => Status::failed_precondition(msg),
+        "OOM" | "BUSY" => Status::resource_exhausted(msg),
+        "STANDBY" | "MOVED" | "TRYAGAIN" | "CLUSTERDOWN" => Status::unavailable(msg),
+        _ => Status::internal(msg),
+    }
+}
+
+fn reply_status(reply: RespValue) -> Status {
+    match reply {
+        RespValue::Error(msg) => status(&msg),
+        reply => Status::internal(format!("unexpected reply {:?}", reply)),
+    }
+}
+
+impl Api {
+    async fn get(self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let (metadata, _, request) = request.into_parts();
+        let db = self.database(request.db)?;
+        let id = *Uuid::new_v4().as_bytes();
+        let mut inner_cmd = InnerCmd::Get(id, request.key, None);
+        self.admit(&metadata, &inner_cmd)?;
+        inner_cmd.select(db);
+        let consistency = self.context.config.read_consistency();
+        // a read replica has no say in the order of the log, it serves what it applied so far
+        if consistency.needs_barrier() && self.context.replica_of.is_empty() {
+            self.commit(InnerCmd::ReadBarrier(*Uuid::new_v4().as_bytes()))
+                .await?;
+        }
+        let Some(key) = inner_cmd.key() else {
+            unreachable!("GET has a key");
+        };
+        let value = match self
+            .storage
+            .get(key)
+            .filter(|raw| !value::expired(raw, value::now()))
+        {
+            Some(raw) => Some(
+                value::string_bytes(raw)
+                    .map_err(|e| reply_status(error_reply(&e)))?
+                    .to_vec(),
+            ),
+            None => None,
+        };
+        Ok(Response::new(GetResponse { value }))
+    }
+
+    async fn put(self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let (metadata, _, request) = request.into_parts();
+        let db = self.database(request.db)?;
+        let now = value::now();
+        let option = (request.ttl_ms > 0).then(|| PutOptionSerde {
+            nx: false,
+            xx: false,
+            expiry: Some(Expiry::At(now.saturating_add(request.ttl_ms))),
+            now,
+            compression: None,
+        });
+        let id = *Uuid::new_v4().as_bytes();
+        let mut inner_cmd = InnerCmd::Put(id, request.key, request.value, option);
+        self.admit(&metadata, &inner_cmd)?;
+        inner_cmd.select(db);
+        // compressed before it is proposed, so every node stores the same bytes
+        let inner_cmd = inner_cmd.compress(&self.context.config);
+        let (_, applied_index) = self.write(inner_cmd).await?;
+        Ok(Response::new(PutResponse { applied_index }))
+    }
+
+    async fn delete(
+        self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let (metadata, _, request) = request.into_parts();
+        let db = self.database(request.db)?;
+        if request.keys.is_empty() {
+            return Err(Status::invalid_argument("no key to delete"));
+        }
+        let mut inner_cmd = InnerCmd::Del(*Uuid::new_v4().as_bytes(), request.keys, value::now());
+        self.admit(&metadata, &inner_cmd)?;
+        inner_cmd.select(db);
+        let (reply, applied_index) = self.write(inner_cmd).await?;
+        let deleted = match reply {
+            RespValue::Integer(deleted) => deleted as u64,
+            reply => return Err(reply_status(reply)),
+        };
+        Ok(Response::new(DeleteResponse {
+            deleted,
+            applied_index,
+        }))
+    }
+
+    async fn scan(self, request: Request<ScanRequest>) -> Result<Response<ScanResponse>, Status> {
+        let (metadata, _, request) = request.into_parts();
+        let db = self.database(request.db)?;
+        // allowed as KEYS is, which lists the keys as well
+        let inner_cmd = InnerCmd::Keys(*Uuid::new_v4().as_bytes(), request.prefix.clone());
+        self.admit(&metadata, &inner_cmd)?;
+        let data_dir = self.context.data_dir.clone();
+        let limit = match request.limit {
+            0 => usize::MAX,
+            limit => limit as usize,
+        };
+        let scanned = tokio::task::spawn_blocking(move || {
+            let snapshot = Snapshot::take(&data_dir)?;
+            let now = value::now();
+            let mut entries = Vec::new();
+            for entry in snapshot.iter()? {
+                let (stored, raw) = entry?;
+                let Some(key) = database::of(db, &stored) else {
+                    continue;
+                };
+                if !key.starts_with(&request.prefix) || value::expired(&raw, now) {
+                    continue;
+                }
+                // the other types are not served over gRPC
+                if let Ok(value) = value::string_bytes(raw) {
+                    entries.push(KeyValue {
+                        key: key.to_vec(),
+                        value: value.to_vec(),
+                    });
+                }
+                if entries.len() == limit {
+                    break;
+                }
+            }
+            Ok::<_, std::io::Error>(entries)
+        })
+        .await;
+        match scanned {
+            Ok(Ok(entries)) => Ok(Response::new(ScanResponse { entries })),
+            Ok(Err(e)) => Err(Status::internal(e.to_string())),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn status(
+        self,
+        request: Request<StatusRequest>,
+    ) -> Result<Response<StatusResponse>, Status> {
+        self.authorize(request.metadata(), "info", Category::Admin, &[])?;
+        let raft_stats = &self.context.raft_stats;
+        Ok(Response::new(StatusResponse {
+            node: self.context.nodes[0].clone(),
+            applied_index: raft_stats.applied_entries(),
+            apply_lag: raft_stats.apply_lag() as u64,
+            halted: raft_stats.halted(),
+            standby: self.context.standby.is_active(),
+            maintenance: self.context.maintenance.is_on(),
+            replica_of: self.context.replica_of.clone(),
+        }))
+    }
+
+    async fn membership(
+        self,
+        request: Request<MembershipRequest>,
+    ) -> Result<Response<MembershipResponse>, Status> {
+        self.authorize(request.metadata(), "cluster", Category::Admin, &[])?;
+        Ok(Response::new(MembershipResponse {
+            members: self.members,
+        }))
+    }
+
+    async fn failover(
+        self,
+        request: Request<FailoverRequest>,
+    ) -> Result<Response<FailoverResponse>, Status> {
+        self.authorize(request.metadata(), "promote", Category::Admin, &[])?;
+        if !self.context.standby.promote() {
+            return Err(Status::failed_precondition("the node is not a standby"));
+        }
+        Ok(Response::new(FailoverResponse {}))
+    }
+
+    fn database(&self, db: u32) -> Result<u32, Status> {
+        match db < self.context.databases {
+            true => Ok(db),
+            false => Err(Status::invalid_argument("DB index is out of range")),
+        }
+    }
+
+    /// The user the request runs as, once it may run the command on the keys
+    fn authorize(
+        &self,
+        metadata: &MetadataMap,
+        command: &str,
+        category: Category,
+        keys: &[&Vec<u8>],
+    ) -> Result<String, Status> {
+        let credentials = (metadata.get("username"), metadata.get("password"));
+        let user = match credentials {
+            (Some(user), Some(password)) => {
+                let user = user.to_str().unwrap_or_default();
+                let password = password.to_str().unwrap_or_default();
+                if !self.context.acl.authenticate(user, password) {
+                    return Err(Status::unauthenticated(
+                        "WRONGPASS invalid username-password pair or user is disabled.",
+                    ));
+                }
+                user.to_string()
+            }
+            _ => self
+                .context
+                .acl
+                .auto_login()
+                .ok_or_else(|| Status::unauthenticated("NOAUTH Authentication required."))?,
+        };
+        self.context
+            .acl
+            .check(&mut None, &user, command, category, keys)
+            .map_err(|e| status(&e))?;
+        Ok(user)
+    }
+
+    /// Check that the request may run the command now, as a RESP connection does
+    fn admit(&self, metadata: &MetadataMap, inner_cmd: &InnerCmd) -> Result<(), Status> {
+        if inner_cmd.keys().iter().any(|key| database::reserved(key)) {
+            return Err(status(database::RESERVED));
+        }
+        let category = inner_cmd.category().unwrap_or(Category::Read);
+        let user = self.authorize(metadata, inner_cmd.name(), category, &inner_cmd.keys())?;
+        if self.context.standby.is_active() {
+            return Err(status(standby::STANDBY));
+        }
+        if self.context.maintenance.is_on() {
+            let key = inner_cmd.key().map(Vec::as_slice);
+            return Err(status(
+                &self.context.maintenance.redirect(&self.context.peers, key),
+            ));
+        }
+        if !self.context.replica_of.is_empty() && matches!(category, Category::Write) {
+            return Err(status(
+                "READONLY You can't write against a read only replica.",
+            ));
+        }
+        self.context
+            .hooks
+            .before_execute(inner_cmd, Some(&user))
+            .map_err(|e| status(&e))
+    }
+
+    /// Propose the write unless the node sheds it, replying its reply and the index of the log
+    /// the node applied once it was
+    async fn write(&self, inner_cmd: InnerCmd) -> Result<(RespValue, u64), Status> {
+        // deletions free space and relieve the node, they are never shed
+        if self.context.overload.shedding() && !matches!(inner_cmd, InnerCmd::Del(_, _, _)) {
+            self.context.overload.write_shed();
+            return Err(status(overload::BUSY));
+        }
+        if self.context.raft_stats.halted() {
+            return Err(status(sync_layer::CLUSTERDOWN));
+        }
+        if !inner_cmd.frees_memory() {
+            self.make_room().await?;
+        }
+        if let Some(max) = self.context.config.proposal_max_bytes() {
+            let size = bincode::serialized_size(&inner_cmd).unwrap_or_default();
+            if size > max {
+                return Err(Status::invalid_argument(format!(
+                    "write of {} bytes is larger than proposal-max-bytes {}",
+                    size, max
+                )));
+            }
+        }
+        let reply = self.commit(inner_cmd).await?;
+        // the entry is counted as applied before it is answered
+        Ok((reply, self.context.raft_stats.applied_entries()))
+    }
+
+    /// Propose the eviction of the keys the maxmemory policy picks while the keys take more than
+    /// maxmemory, as the RESP connections do
+    async fn make_room(&self) -> Result<(), Status> {
+        let Some(memory) = &self.context.memory else {
+            return Ok(());
+        };
+        if !memory.over() {
+            return Ok(());
+        }
+        let Some(keys) = memory.victims() else {
+            info!("gRPC: write rejected over maxmemory");
+            return Err(status(memory::OOM));
+        };
+        self.commit(InnerCmd::Evict(*Uuid::new_v4().as_bytes(), keys))
+            .await
+            .map(drop)
+    }
+
+    /// Propose the command and wait until it is applied
+    async fn commit(&self, inner_cmd: InnerCmd) -> Result<RespValue, Status> {
+        let deadline = Instant::now() + self.context.config.write_timeout();
+        let (tx, rx) = oneshot::channel();
+        let request = SyncRequest::new(inner_cmd, tx, deadline, Span::current(), None);
+        // if the sync layer is gone, the request is dropped with its answer channel
+        let _ = self.sync_request_tx.send(request).await;
+        match timeout_at(deadline, rx).await {
+            Ok(Ok(Ok(reply))) => Ok(reply),
+            Ok(Ok(Err(e))) => Err(reply_status(error_reply(&e))),
+            Ok(Err(_)) if Instant::now() < deadline => Err(status(sync_layer::CLUSTERDOWN)),
+            _ => Err(Status::deadline_exceeded("Request timeout")),
+        }
+    }
+}
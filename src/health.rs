@@ -0,0 +1,110 @@
+//! The probes of the HTTP gateway, for Kubernetes and load balancers to stop routing to a node
+//! that cannot serve: `/healthz` whether the node is up, `/readyz` whether it should get
+//! clients, and `/raftz` how far it is in applying the log. They need no credentials.
+//!
+//! raft-lite does not tell a node who leads the group, so `/readyz` replicates a barrier entry,
+//! as a read at `leader` consistency does: it commits only through an elected leader, and once
+//! this node applied it, the node caught up with every entry committed before. A partitioned
+//! node, or one still electing, fails the probe once --ready-timeout-ms passes.
+
+use crate::cmd::{InnerCmd, NodeContext};
+use crate::sync_layer::SyncRequest;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+use tracing::Span;
+use uuid::Uuid;
+
+pub(crate) struct Probes {
+    sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+    context: NodeContext,
+    // how long the barrier of /readyz may take to be applied
+    ready_timeout: Duration,
+    // committed entries waiting to be applied past which the node is not ready
+    max_apply_lag: usize,
+}
+
+impl Probes {
+    pub(crate) fn new(
+        sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+        context: NodeContext,
+        ready_timeout: Duration,
+        max_apply_lag: usize,
+    ) -> Self {
+        Self {
+            sync_request_tx,
+            context,
+            ready_timeout,
+            max_apply_lag,
+        }
+    }
+
+    /// Whether the node is up: it answers, and its sync layer still replicates writes
+    pub(crate) fn live(&self) -> (bool, Value) {
+        let halted = self.context.raft_stats.halted();
+        (
+            !halted,
+            json!({ "status": status(!halted), "halted": halted }),
+        )
+    }
+
+    /// Whether the node applies the log and keeps up with it
+    pub(crate) fn raft(&self) -> (bool, Value) {
+        let stats = &self.context.raft_stats;
+        let caught_up = !stats.halted() && stats.apply_lag() <= self.max_apply_lag;
+        (
+            caught_up,
+            json!({
+                "status": status(caught_up),
+                "applied": stats.applied_entries(),
+                "apply_lag": stats.apply_lag(),
+                "commit_lag": stats.commit_lag(),
+                "halted": stats.halted(),
+            }),
+        )
+    }
+
+    /// Whether the node should get clients: it caught up with the log, a barrier commits
+    /// through a leader within the timeout, and it neither is a standby nor in maintenance
+    pub(crate) async fn ready(&self) -> (bool, Value) {
+        let (caught_up, _) = self.raft();
+        let standby = self.context.standby.is_active();
+        let maintenance = self.context.maintenance.is_on();
+        // a read replica has no say in the order of the log, what it applied is what it serves
+        let leader = match self.context.replica_of.is_empty() {
+            true => caught_up && self.barrier().await,
+            false => caught_up,
+        };
+        let ready = caught_up && leader && !standby && !maintenance;
+        (
+            ready,
+            json!({
+                "status": status(ready),
+                "caught_up": caught_up,
+                "leader": leader,
+                "standby": standby,
+                "maintenance": maintenance,
+            }),
+        )
+    }
+
+    /// Whether a barrier entry is committed and applied here before the timeout
+    async fn barrier(&self) -> bool {
+        let deadline = Instant::now() + self.ready_timeout;
+        let (tx, rx) = oneshot::channel();
+        let inner_cmd = InnerCmd::ReadBarrier(*Uuid::new_v4().as_bytes());
+        let request = SyncRequest::new(inner_cmd, tx, deadline, Span::current(), None);
+        if self.sync_request_tx.send(request).await.is_err() {
+            return false;
+        }
+        matches!(timeout_at(deadline, rx).await, Ok(Ok(Ok(_))))
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    match ok {
+        true => "ok",
+        false => "unavailable",
+    }
+}
@@ -0,0 +1,55 @@
+//! Command hooks, for extensions compiled into the node: auditing, metrics or validation that
+//! needs to see every command without changing the dispatch code.
+//!
+//! A hook sees a command twice. Before it executes, on the node the client is connected to,
+//! where it may reject the command; and after a write is applied, on every node of the group,
+//! with the outcome of the write. The apply path runs one entry at a time, so `after_apply`
+//! must be quick and must not block.
+
+use crate::cmd::InnerCmd;
+use crate::resp_codec::RespValue;
+use bitcask_engine_rs::error::BitCaskError;
+use std::sync::Arc;
+
+pub(crate) trait CommandHook: Send + Sync {
+    /// Called once the command is parsed and permitted, with the user of the connection. An
+    /// error rejects the command and is replied to the client as is.
+    fn before_execute(&self, _cmd: &InnerCmd, _user: Option<&str>) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Called after a write is applied to the storage
+    fn after_apply(&self, _cmd: &InnerCmd, _outcome: &Result<RespValue, BitCaskError>) {}
+}
+
+/// The hooks of the node, run in the order they were registered
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<Arc<dyn CommandHook>>,
+}
+
+impl Hooks {
+    /// The hooks compiled into the node, extensions register theirs here
+    pub(crate) fn compiled_in() -> Self {
+        Self::default()
+    }
+
+    // only the golden transcripts register a hook until an extension is compiled in
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn register(&mut self, hook: impl CommandHook + 'static) {
+        self.hooks.push(Arc::new(hook));
+    }
+
+    /// The first rejection of the command, if any hook rejects it
+    pub(crate) fn before_execute(&self, cmd: &InnerCmd, user: Option<&str>) -> Result<(), String> {
+        self.hooks
+            .iter()
+            .try_for_each(|hook| hook.before_execute(cmd, user))
+    }
+
+    pub(crate) fn after_apply(&self, cmd: &InnerCmd, outcome: &Result<RespValue, BitCaskError>) {
+        for hook in &self.hooks {
+            hook.after_apply(cmd, outcome);
+        }
+    }
+}
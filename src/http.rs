@@ -0,0 +1,520 @@
+//! The HTTP gateway of the node, served on --http-addr for scripts, curl and dashboards without a
+//! Redis client: `GET`, `PUT` and `DELETE /keys/{key}` get, set and delete a string key, and
+//! `POST /batch` runs a JSON array of those operations in order, replying with their results.
+//!
+//! A request is translated into RESP commands run over an in-memory connection of the node, as
+//! ADMIN BROADCAST runs its command locally, so it goes through the command and sync layers of
+//! the clients: the ACL, the hooks, --read-consistency, maxmemory and the refusals of a standby
+//! or a read replica apply alike. A request runs as the user its HTTP Basic credentials name, or
+//! as the default user if it may run without a password, in the database `?db=` picks, 0 unless.
+//!
+//! `GET /healthz`, `/readyz` and `/raftz` are the probes of [`crate::health`], served without
+//! credentials, with `200` when the node passes them and `503` otherwise. `GET /v1/commands`
+//! replies the schema of the commands of [`crate::schema`], without credentials either.
+//!
+//! HTTP/1.1 and 1.0 are spoken, with keep-alive, and bodies are read sent with a length or in
+//! chunks.
+
+use crate::admin;
+use crate::health::Probes;
+use crate::resp_codec::{RespCodec, RespValue};
+use crate::schema;
+use futures::SinkExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+
+/// Bytes of the request line and headers of a request, past which it is rejected
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    // decoded, as sent
+    query: Vec<(String, Vec<u8>)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // whether the connection stays open for the next request
+    keep_alive: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn param(&self, name: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_slice())
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn empty(status: u16) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: Vec::new(),
+        }
+    }
+
+    fn json(status: u16, value: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, msg: &str) -> Self {
+        Self::json(status, json!({ "error": msg }))
+    }
+
+    /// The response to an error reply of the node, by its code
+    fn reply_error(msg: &str) -> Self {
+        let status = match msg.split(' ').next().unwrap_or_default() {
+            "NOAUTH" | "WRONGPASS" => 401,
+            "NOPERM" => 403,
+            "WRONGTYPE" | "READONLY" => 409,
+            "OOM" | "BUSY" | "STANDBY" | "MOVED" | "TRYAGAIN" | "CLUSTERDOWN" => 503,
+            _ if msg == "Request timeout" => 504,
+            _ => 500,
+        };
+        Self::error(status, msg)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Server Error",
+    }
+}
+
+/// An operation of a batch
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum Op {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+        // 0 for a value that never expires
+        #[serde(default)]
+        ttl_ms: u64,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl Op {
+    fn command(&self) -> RespValue {
+        match self {
+            Op::Get { key } => admin::command(&[b"GET", key.as_bytes()]),
+            Op::Put { key, value, ttl_ms } => put(key.as_bytes(), value.as_bytes(), *ttl_ms),
+            Op::Delete { key } => admin::command(&[b"DEL", key.as_bytes()]),
+        }
+    }
+
+    /// The result of the operation in the response of the batch
+    fn result(&self, reply: RespValue) -> Value {
+        match (self, reply) {
+            (_, RespValue::Error(msg)) => json!({ "error": msg }),
+            (Op::Get { .. }, RespValue::BulkString(value)) => {
+                json!({ "value": value.map(|value| String::from_utf8_lossy(&value).into_owned()) })
+            }
+            (Op::Put { .. }, _) => json!({ "ok": true }),
+            (Op::Delete { .. }, RespValue::Integer(deleted)) => json!({ "deleted": deleted > 0 }),
+            (_, reply) => json!({ "error": format!("unexpected reply {:?}", reply) }),
+        }
+    }
+}
+
+fn put(key: &[u8], value: &[u8], ttl_ms: u64) -> RespValue {
+    match ttl_ms {
+        0 => admin::command(&[b"SET", key, value]),
+        ttl_ms => admin::command(&[b"SET", key, value, b"PX", ttl_ms.to_string().as_bytes()]),
+    }
+}
+
+/// Serve the requests of a client until it closes the connection, each over an in-memory
+/// connection of the node `connect` opens
+pub(crate) async fn handle(
+    socket: TcpStream,
+    max_body_len: usize,
+    probes: &Probes,
+    connect: impl Fn() -> DuplexStream,
+) -> io::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let request = match read_request(&mut reader, max_body_len).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            // what follows a request that could not be read cannot be either
+            Err(response) => return write_response(&mut writer, &response, false).await,
+        };
+        let response = match probe(&request, probes).await {
+            Some(response) => response,
+            None if request.path == format!("/v{}/commands", schema::VERSION) => {
+                match request.method.as_str() {
+                    "GET" => Response::json(200, schema::export()),
+                    _ => Response::error(405, "use GET"),
+                }
+            }
+            None => match route(&request, connect()).await {
+                Ok(response) => response,
+                Err(e) => Response::error(500, &e.to_string()),
+            },
+        };
+        write_response(&mut writer, &response, request.keep_alive).await?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+/// The next request, None once the client closed the connection, or the response rejecting it
+async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_body_len: usize,
+) -> Result<Option<Request>, Response> {
+    let invalid = |e: io::Error| Response::error(400, &e.to_string());
+    let mut head = Vec::new();
+    let mut head_len = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut *reader)
+            .take((MAX_HEAD_LEN - head_len) as u64)
+            .read_line(&mut line)
+            .await
+            .map_err(invalid)?;
+        head_len += read;
+        if read == 0 && head.is_empty() {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(match head_len < MAX_HEAD_LEN {
+                true => Response::error(400, "the connection closed within the headers"),
+                false => Response::error(431, "the headers are too long"),
+            });
+        }
+        let line = line.trim_end();
+        match line.is_empty() {
+            // blank lines ahead of the request line are ignored, as RFC 9112 allows
+            true if head.is_empty() => continue,
+            true => break,
+            false => head.push(line.to_string()),
+        }
+    }
+    let mut request_line = head[0].split(' ');
+    let (Some(method), Some(target), Some(version), None) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(Response::error(400, "invalid request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            Some((name.to_string(), percent_decode(value)?))
+        })
+        .collect::<Option<_>>()
+        .ok_or_else(|| Response::error(400, "invalid query"))?;
+    let headers = head[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers,
+        body: Vec::new(),
+        keep_alive: false,
+    };
+    let connection = request.header("Connection").map(str::to_lowercase);
+    request.keep_alive = match version {
+        "HTTP/1.1" => connection.as_deref() != Some("close"),
+        "HTTP/1.0" => connection.as_deref() == Some("keep-alive"),
+        _ => return Err(Response::error(400, "only HTTP/1.1 is supported")),
+    };
+    request.body = read_body(reader, &request, max_body_len).await?;
+    Ok(Some(request))
+}
+
+/// The body of the request, sent with a length or in chunks
+async fn read_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    request: &Request,
+    max_body_len: usize,
+) -> Result<Vec<u8>, Response> {
+    let invalid = |e: io::Error| Response::error(400, &e.to_string());
+    let too_large = || Response::error(413, "the body is too large");
+    let mut body = Vec::new();
+    if request
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            (&mut *reader)
+                .take(MAX_HEAD_LEN as u64)
+                .read_line(&mut line)
+                .await
+                .map_err(invalid)?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| Response::error(400, "invalid chunk size"))?;
+            if size == 0 {
+                // the trailers, up to the blank line ending the request
+                loop {
+                    line.clear();
+                    (&mut *reader)
+                        .take(MAX_HEAD_LEN as u64)
+                        .read_line(&mut line)
+                        .await
+                        .map_err(invalid)?;
+                    if line.trim_end().is_empty() {
+                        return Ok(body);
+                    }
+                }
+            }
+            if body.len() + size > max_body_len {
+                return Err(too_large());
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader
+                .read_exact(&mut body[start..])
+                .await
+                .map_err(invalid)?;
+            line.clear();
+            reader.read_line(&mut line).await.map_err(invalid)?;
+        }
+    }
+    let Some(len) = request.header("Content-Length") else {
+        return Ok(body);
+    };
+    let len: usize = len
+        .parse()
+        .map_err(|_| Response::error(400, "invalid Content-Length"))?;
+    if len > max_body_len {
+        return Err(too_large());
+    }
+    body.resize(len, 0);
+    reader.read_exact(&mut body).await.map_err(invalid)?;
+    Ok(body)
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &Response,
+    keep_alive: bool,
+) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    if response.status != 204 {
+        head.push_str(&format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\n",
+            response.content_type,
+            response.body.len()
+        ));
+    }
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await
+}
+
+/// The response to a probe, None if the request is not one
+async fn probe(request: &Request, probes: &Probes) -> Option<Response> {
+    let path = request.path.as_str();
+    if !matches!(path, "/healthz" | "/readyz" | "/raftz") {
+        return None;
+    }
+    if request.method != "GET" {
+        return Some(Response::error(405, "use GET"));
+    }
+    let (ok, report) = match path {
+        "/healthz" => probes.live(),
+        "/readyz" => probes.ready().await,
+        _ => probes.raft(),
+    };
+    Some(Response::json(if ok { 200 } else { 503 }, report))
+}
+
+/// Run the request over a connection of the node
+async fn route(request: &Request, stream: DuplexStream) -> anyhow::Result<Response> {
+    let mut framed = Framed::new(stream, RespCodec::new());
+    // run before the commands of the request, so that they never run as another user
+    let mut prelude = Vec::new();
+    if let Some(credentials) = request.header("Authorization") {
+        let Some((user, password)) = basic_credentials(credentials) else {
+            return Ok(Response::error(400, "invalid Authorization header"));
+        };
+        prelude.push(admin::command(&[b"AUTH", &user, &password]));
+    }
+    if let Some(db) = request.param("db") {
+        prelude.push(admin::command(&[b"SELECT", db]));
+    }
+    for command in prelude {
+        framed.send(command).await?;
+        if let RespValue::Error(msg) = admin::next(&mut framed).await? {
+            return Ok(match msg.starts_with("ERR") {
+                // the database does not exist
+                true => Response::error(400, &msg),
+                false => Response::reply_error(&msg),
+            });
+        }
+    }
+    let key = request
+        .path
+        .strip_prefix("/keys/")
+        .filter(|key| !key.is_empty());
+    match (request.method.as_str(), key, request.path.as_str()) {
+        (method @ ("GET" | "PUT" | "DELETE"), Some(key), _) => {
+            let Some(key) = percent_decode(key) else {
+                return Ok(Response::error(400, "invalid key"));
+            };
+            let ttl_ms = match request.param("ttl_ms").map(std::str::from_utf8) {
+                None => 0,
+                Some(Ok(ttl_ms)) => match ttl_ms.parse::<u64>() {
+                    Ok(ttl_ms) => ttl_ms,
+                    Err(_) => return Ok(Response::error(400, "invalid ttl_ms")),
+                },
+                Some(Err(_)) => return Ok(Response::error(400, "invalid ttl_ms")),
+            };
+            let command = match method {
+                "GET" => admin::command(&[b"GET", &key]),
+                "PUT" => put(&key, &request.body, ttl_ms),
+                _ => admin::command(&[b"DEL", &key]),
+            };
+            framed.send(command).await?;
+            Ok(match admin::next(&mut framed).await? {
+                RespValue::Error(msg) => Response::reply_error(&msg),
+                RespValue::BulkString(Some(value)) => Response {
+                    status: 200,
+                    content_type: "application/octet-stream",
+                    body: value.to_vec(),
+                },
+                RespValue::BulkString(None) | RespValue::Integer(0) => {
+                    Response::error(404, "no such key")
+                }
+                _ => Response::empty(204),
+            })
+        }
+        (_, Some(_), _) => Ok(Response::error(405, "use GET, PUT or DELETE")),
+        ("POST", None, "/batch") => {
+            let ops: Vec<Op> = match serde_json::from_slice(&request.body) {
+                Ok(ops) => ops,
+                Err(e) => return Ok(Response::error(400, &e.to_string())),
+            };
+            // pipelined, the connection replies in order
+            for op in &ops {
+                framed.feed(op.command()).await?;
+            }
+            framed.flush().await?;
+            let mut results = Vec::with_capacity(ops.len());
+            for op in &ops {
+                results.push(op.result(admin::next(&mut framed).await?));
+            }
+            Ok(Response::json(200, Value::Array(results)))
+        }
+        (_, None, "/batch") => Ok(Response::error(405, "use POST")),
+        _ => Ok(Response::error(404, "no such endpoint")),
+    }
+}
+
+/// The user and password of an `Authorization: Basic` header
+fn basic_credentials(header: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (scheme, encoded) = header.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64_decode(encoded.trim())?;
+    let colon = decoded.iter().position(|b| *b == b':')?;
+    Some((decoded[..colon].to_vec(), decoded[colon + 1..].to_vec()))
+}
+
+/// The bytes of standard base64, None if it is not
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut bits, mut len) = (0u32, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6 | sextet as u32) & 0xffff;
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// The bytes of a percent-encoded part of a URL, None if it is not
+fn percent_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => decoded.push(b),
+        }
+    }
+    Some(decoded)
+}
@@ -0,0 +1,532 @@
+//! Iteration over every live key of the node, for the consumers that need the whole keyspace.
+//!
+//! bitcask-engine-rs has neither an iterator nor read views, so a snapshot is read from the data
+//! files directly. Their lengths are frozen when the snapshot is taken and, as the files are
+//! append-only, replaying them up to those lengths yields the keyspace as of that moment while
+//! writes keep appending. Every command is applied as a single put or delete, so any prefix of
+//! the files is a state the node went through.
+//!
+//! A compaction moves the storage to another directory, so the files are listed from the
+//! directory the storage is in, and no snapshot is taken while it moves. The files it leaves
+//! behind are removed once the snapshots taken from them are dropped.
+
+use crate::scrubber::{DATA_FILE_EXT, HEADER_SIZE};
+use crate::{chunk, ephemeral, feature, flush, lock, procedure, script, sync_layer};
+use std::collections::btree_map;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, LazyLock, Mutex, MutexGuard};
+
+/// Where the storage of each data directory is, and the snapshots taken from each directory
+#[derive(Default)]
+struct Storages {
+    moved: HashMap<PathBuf, PathBuf>,
+    // how many times the storage moved since the node started
+    moves: HashMap<PathBuf, u64>,
+    moving: HashSet<PathBuf>,
+    snapshots: HashMap<PathBuf, usize>,
+}
+
+static STORAGES: LazyLock<(Mutex<Storages>, Condvar)> = LazyLock::new(Default::default);
+
+/// Whether the key holds state of the node rather than data of the clients
+pub(crate) fn internal(key: &[u8]) -> bool {
+    key.starts_with(procedure::KEY_PREFIX)
+        || key.starts_with(feature::KEY_PREFIX)
+        || key.starts_with(chunk::KEY_PREFIX)
+        || key.starts_with(sync_layer::KEY_PREFIX)
+        || key.starts_with(script::KEY_PREFIX)
+        || key.starts_with(lock::KEY_PREFIX)
+        || key == lock::FENCE_KEY
+        || key.starts_with(ephemeral::KEY_PREFIX)
+        || key == flush::EPOCH_KEY
+}
+
+/// The data files of the storage with their lengths, oldest first
+pub(crate) fn data_files(data_dir: &Path) -> std::io::Result<Vec<(PathBuf, u64)>> {
+    list(&storage_dir(data_dir))
+}
+
+/// The directory the storage of the data directory keeps its files in
+pub(crate) fn storage_dir(data_dir: &Path) -> PathBuf {
+    let storages = STORAGES.0.lock().unwrap();
+    storages
+        .moved
+        .get(data_dir)
+        .cloned()
+        .unwrap_or_else(|| data_dir.to_path_buf())
+}
+
+/// Hold the snapshots of the data directory back while its storage moves to another directory
+pub(crate) fn moving(data_dir: &Path) -> Moving {
+    STORAGES
+        .0
+        .lock()
+        .unwrap()
+        .moving
+        .insert(data_dir.to_path_buf());
+    Moving {
+        data_dir: data_dir.to_path_buf(),
+    }
+}
+
+/// The storage of a data directory moving, the snapshots wait until it is dropped
+pub(crate) struct Moving {
+    data_dir: PathBuf,
+}
+
+impl Moving {
+    /// Record that the storage moved to `to`, the snapshots taken from now on listing its files
+    pub(crate) fn moved(self, to: &Path) -> Left {
+        let (storages, released) = &*STORAGES;
+        let mut storages = storages.lock().unwrap();
+        let left = storages
+            .moved
+            .insert(self.data_dir.clone(), to.to_path_buf())
+            .unwrap_or_else(|| self.data_dir.clone());
+        if to == self.data_dir {
+            storages.moved.remove(&self.data_dir);
+        }
+        *storages.moves.entry(self.data_dir.clone()).or_default() += 1;
+        storages.moving.remove(&self.data_dir);
+        released.notify_all();
+        Left { dir: left }
+    }
+}
+
+impl Drop for Moving {
+    fn drop(&mut self) {
+        let (storages, released) = &*STORAGES;
+        storages.lock().unwrap().moving.remove(&self.data_dir);
+        released.notify_all();
+    }
+}
+
+/// The directory a storage moved from
+pub(crate) struct Left {
+    dir: PathBuf,
+}
+
+impl Left {
+    /// Remove the data files of the directory once the snapshots taken from them are dropped
+    pub(crate) fn remove(self) -> std::io::Result<()> {
+        let (storages, released) = &*STORAGES;
+        let storages = released
+            .wait_while(storages.lock().unwrap(), |storages| {
+                storages.snapshots.contains_key(&self.dir)
+            })
+            .unwrap();
+        for (path, _) in list(&self.dir)? {
+            std::fs::remove_file(path)?;
+        }
+        drop(storages);
+        Ok(())
+    }
+}
+
+/// Held by the apply path while it writes to the storage, so that a compaction can compare the
+/// storage with its files. The thread of an ASYNC flush writes without it.
+pub(crate) fn writing() -> MutexGuard<'static, ()> {
+    static WRITES: Mutex<()> = Mutex::new(());
+    WRITES.lock().unwrap()
+}
+
+/// The data files in the directory with their lengths, oldest first
+fn list(dir: &Path) -> std::io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        // the engine names its files after their sequence number
+        let Some(file_id) = Some(&path)
+            .filter(|path| path.extension().is_some_and(|ext| ext == DATA_FILE_EXT))
+            .and_then(|path| path.file_stem())
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        let len = std::fs::metadata(&path)?.len();
+        files.push((file_id, path, len));
+    }
+    files.sort_by_key(|(file_id, _, _)| *file_id);
+    Ok(files
+        .into_iter()
+        .map(|(_, path, len)| (path, len))
+        .collect())
+}
+
+/// Where the latest value of a key is, in the files of a snapshot
+struct Location {
+    file: usize,
+    offset: u64,
+    size: u64,
+}
+
+/// A value a later entry of its key overwrote or deleted, in the file of a snapshot
+pub(crate) struct Superseded {
+    // offset of its entry, which starts with the checksum
+    pub(crate) entry: u64,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+/// The data files of a node and their lengths at a point in time
+pub(crate) struct Snapshot {
+    files: Vec<(PathBuf, u64)>,
+    // the directory of the storage the files are in, kept by a compaction until it is dropped
+    storage: Option<PathBuf>,
+    moves: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn take(data_dir: &Path) -> std::io::Result<Self> {
+        let (storages, released) = &*STORAGES;
+        let mut storages = released
+            .wait_while(storages.lock().unwrap(), |storages| {
+                storages.moving.contains(data_dir)
+            })
+            .unwrap();
+        let dir = storages
+            .moved
+            .get(data_dir)
+            .cloned()
+            .unwrap_or_else(|| data_dir.to_path_buf());
+        let files = list(&dir)?;
+        *storages.snapshots.entry(dir.clone()).or_default() += 1;
+        Ok(Self {
+            files,
+            storage: Some(dir),
+            moves: storages.moves.get(data_dir).copied().unwrap_or_default(),
+        })
+    }
+
+    /// Hard-link the data files into `dir`, so that the snapshot keeps them if the storage
+    /// removes them. The file still appended to is shared with the storage until `detach`.
+    pub(crate) fn link(&self, dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut files = Vec::with_capacity(self.files.len());
+        for (path, len) in &self.files {
+            let link = dir.join(path.file_name().unwrap_or_default());
+            std::fs::hard_link(path, &link)?;
+            files.push((link, *len));
+        }
+        Ok(Self {
+            files,
+            storage: None,
+            moves: self.moves,
+        })
+    }
+
+    /// Replace the file the storage appends to by a copy of its frozen part, so that the files
+    /// hold the snapshot and nothing else. The older files are immutable.
+    pub(crate) fn detach(&self) -> std::io::Result<()> {
+        let Some((path, len)) = self.files.last() else {
+            return Ok(());
+        };
+        let copy = path.with_extension("copy");
+        std::io::copy(&mut File::open(path)?.take(*len), &mut File::create(&copy)?)?;
+        std::fs::rename(&copy, path)
+    }
+
+    /// Copy the files, up to the last entry written in full before the snapshot was taken, into
+    /// `dir`, from the lengths of `since` for the files it has and whole for the others,
+    /// returning the length each file is copied up to. Unlike `link`, the copy may be on another
+    /// filesystem and shares nothing with the storage.
+    pub(crate) fn copy(
+        &self,
+        dir: &Path,
+        since: &BTreeMap<String, u64>,
+    ) -> std::io::Result<BTreeMap<String, u64>> {
+        std::fs::create_dir_all(dir)?;
+        let mut lengths = BTreeMap::new();
+        for (path, len) in &self.files {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let from = since.get(name.as_ref()).copied();
+            let len = complete_len(path, from.unwrap_or(0), *len)?;
+            if from.is_none_or(|from| len > from) {
+                let mut source = File::open(path)?;
+                source.seek(SeekFrom::Start(from.unwrap_or(0)))?;
+                let mut file = File::create(dir.join(name.as_ref()))?;
+                std::io::copy(&mut source.take(len - from.unwrap_or(0)), &mut file)?;
+                file.sync_all()?;
+            }
+            lengths.insert(name.into_owned(), len);
+        }
+        Ok(lengths)
+    }
+
+    /// How many times a compaction moved the storage since the node started, before the snapshot
+    /// was taken. The files it wrote may have the names and lengths of the files it replaced.
+    pub(crate) fn moves(&self) -> u64 {
+        self.moves
+    }
+
+    /// Whether the files only grew since they had the lengths, so that copying what was appended
+    /// since then brings a copy of them up to the snapshot
+    pub(crate) fn extends(&self, lengths: &BTreeMap<String, u64>) -> bool {
+        lengths.iter().all(|(name, len)| {
+            self.files.iter().any(|(path, snapshot_len)| {
+                path.file_name().is_some_and(|file| file == name.as_str()) && snapshot_len >= len
+            })
+        })
+    }
+
+    /// How many data files the snapshot has, and their bytes
+    pub(crate) fn size(&self) -> (usize, u64) {
+        (
+            self.files.len(),
+            self.files.iter().map(|(_, len)| len).sum(),
+        )
+    }
+
+    /// The bytes of the entries of the live keys, the rest of the files being values overwritten
+    /// or deleted since, and tombstones
+    pub(crate) fn live_bytes(&self) -> std::io::Result<u64> {
+        Ok(self
+            .index()?
+            .iter()
+            .map(|(key, location)| HEADER_SIZE + key.len() as u64 + location.size)
+            .sum())
+    }
+
+    /// The last entry of each key written in the files after the first one, with its value or
+    /// None for a deletion
+    pub(crate) fn latest_after_first(&self) -> std::io::Result<BTreeMap<Vec<u8>, Option<Vec<u8>>>> {
+        let mut latest = BTreeMap::new();
+        for (path, len) in self.files.iter().skip(1) {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut offset = 0u64;
+            while offset + HEADER_SIZE <= *len {
+                let mut header = [0u8; HEADER_SIZE as usize];
+                reader.read_exact(&mut header)?;
+                let key_size = u64::from_be_bytes(header[4..12].try_into().unwrap());
+                let value_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+                let entry_size = HEADER_SIZE + key_size + value_size;
+                if offset + entry_size > *len {
+                    // appended after the snapshot was taken
+                    break;
+                }
+                let mut key = vec![0u8; key_size as usize];
+                let mut value = vec![0u8; value_size as usize];
+                reader.read_exact(&mut key)?;
+                reader.read_exact(&mut value)?;
+                // tombstones carry no value
+                latest.insert(key, (value_size > 0).then_some(value));
+                offset += entry_size;
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Every live key with its value, in key order. Values are read as the iteration goes.
+    pub(crate) fn iter(&self) -> std::io::Result<SnapshotIter<'_>> {
+        Ok(SnapshotIter {
+            snapshot: self,
+            entries: self.index()?.into_iter(),
+            readers: self.files.iter().map(|_| None).collect(),
+        })
+    }
+
+    /// Every live key, in key order, without reading the values
+    pub(crate) fn keys(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        Ok(self.index()?.into_keys().collect())
+    }
+
+    /// Index the live keys once, for reading them one by one as of the snapshot
+    pub(crate) fn pin(self) -> std::io::Result<Pinned> {
+        let index = self.index()?;
+        Ok(Pinned {
+            snapshot: self,
+            index,
+        })
+    }
+
+    /// The values of the keys in `covered` that are no longer live, by file, oldest first
+    pub(crate) fn superseded(
+        &self,
+        covered: impl Fn(&[u8]) -> bool,
+    ) -> std::io::Result<Vec<(PathBuf, Vec<Superseded>)>> {
+        let mut superseded: Vec<(PathBuf, Vec<Superseded>)> = self
+            .files
+            .iter()
+            .map(|(path, _)| (path.clone(), Vec::new()))
+            .collect();
+        let mut live: BTreeMap<Vec<u8>, (usize, Superseded)> = BTreeMap::new();
+        for (file, (path, len)) in self.files.iter().enumerate() {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut offset = 0u64;
+            while offset + HEADER_SIZE <= *len {
+                let mut header = [0u8; HEADER_SIZE as usize];
+                reader.read_exact(&mut header)?;
+                let key_size = u64::from_be_bytes(header[4..12].try_into().unwrap());
+                let value_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+                let entry_size = HEADER_SIZE + key_size + value_size;
+                if offset + entry_size > *len {
+                    // appended after the snapshot was taken
+                    break;
+                }
+                let mut key = vec![0u8; key_size as usize];
+                reader.read_exact(&mut key)?;
+                reader.seek_relative(value_size as i64)?;
+                if covered(&key) {
+                    // tombstones carry no value
+                    let value = (value_size > 0).then(|| {
+                        let value = Superseded {
+                            entry: offset,
+                            offset: offset + HEADER_SIZE + key_size,
+                            size: value_size,
+                        };
+                        (file, value)
+                    });
+                    let previous = match value {
+                        Some(value) => live.insert(key, value),
+                        None => live.remove(&key),
+                    };
+                    if let Some((file, value)) = previous {
+                        superseded[file].1.push(value);
+                    }
+                }
+                offset += entry_size;
+            }
+        }
+        superseded.retain(|(_, values)| !values.is_empty());
+        Ok(superseded)
+    }
+
+    /// Replay the files up to their frozen lengths, the last entry of a key wins
+    fn index(&self) -> std::io::Result<BTreeMap<Vec<u8>, Location>> {
+        let mut index = BTreeMap::new();
+        for (file, (path, len)) in self.files.iter().enumerate() {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut offset = 0u64;
+            while offset + HEADER_SIZE <= *len {
+                let mut header = [0u8; HEADER_SIZE as usize];
+                reader.read_exact(&mut header)?;
+                let key_size = u64::from_be_bytes(header[4..12].try_into().unwrap());
+                let value_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+                let entry_size = HEADER_SIZE + key_size + value_size;
+                if offset + entry_size > *len {
+                    // appended after the snapshot was taken
+                    break;
+                }
+                let mut key = vec![0u8; key_size as usize];
+                reader.read_exact(&mut key)?;
+                reader.seek_relative(value_size as i64)?;
+                // tombstones carry no value
+                if value_size == 0 {
+                    index.remove(&key);
+                } else {
+                    let location = Location {
+                        file,
+                        offset: offset + HEADER_SIZE + key_size,
+                        size: value_size,
+                    };
+                    index.insert(key, location);
+                }
+                offset += entry_size;
+            }
+        }
+        Ok(index)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.storage {
+            let (storages, released) = &*STORAGES;
+            let mut storages = storages.lock().unwrap();
+            if let Some(taken) = storages.snapshots.get_mut(dir) {
+                *taken -= 1;
+                if *taken == 0 {
+                    storages.snapshots.remove(dir);
+                }
+            }
+            released.notify_all();
+        }
+    }
+}
+
+/// The length of the entries of the file written in full within its first `len` bytes, scanning
+/// them from `from`, where an entry starts
+fn complete_len(path: &Path, from: u64, len: u64) -> std::io::Result<u64> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(from))?;
+    let mut reader = BufReader::new(file);
+    let mut offset = from;
+    while offset + HEADER_SIZE <= len {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        let key_size = u64::from_be_bytes(header[4..12].try_into().unwrap());
+        let value_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+        let entry_size = HEADER_SIZE + key_size + value_size;
+        if offset + entry_size > len {
+            break;
+        }
+        reader.seek_relative((key_size + value_size) as i64)?;
+        offset += entry_size;
+    }
+    Ok(offset)
+}
+
+/// A snapshot with the location of each live key, whose values are read on demand
+pub(crate) struct Pinned {
+    snapshot: Snapshot,
+    index: BTreeMap<Vec<u8>, Location>,
+}
+
+impl Pinned {
+    /// The value of the key as of the snapshot
+    pub(crate) fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        let Some(location) = self.index.get(key) else {
+            return Ok(None);
+        };
+        let mut reader = File::open(&self.snapshot.files[location.file].0)?;
+        read_at(&mut reader, location).map(Some)
+    }
+
+    /// Every live key as of the snapshot, in key order
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.index.keys().map(Vec::as_slice)
+    }
+}
+
+/// Read the value at the location, in the file of the snapshot it is in
+fn read_at(reader: &mut File, location: &Location) -> std::io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(location.offset))?;
+    let mut value = vec![0u8; location.size as usize];
+    reader.read_exact(&mut value).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => {
+            std::io::Error::new(ErrorKind::NotFound, "data file shrank under the snapshot")
+        }
+        _ => e,
+    })?;
+    Ok(value)
+}
+
+pub(crate) struct SnapshotIter<'a> {
+    snapshot: &'a Snapshot,
+    entries: btree_map::IntoIter<Vec<u8>, Location>,
+    // opened on first use
+    readers: Vec<Option<File>>,
+}
+
+impl SnapshotIter<'_> {
+    fn read(&mut self, location: &Location) -> std::io::Result<Vec<u8>> {
+        let reader = match &mut self.readers[location.file] {
+            Some(reader) => reader,
+            reader => reader.insert(File::open(&self.snapshot.files[location.file].0)?),
+        };
+        read_at(reader, location)
+    }
+}
+
+impl Iterator for SnapshotIter<'_> {
+    type Item = std::io::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, location) = self.entries.next()?;
+        Some(self.read(&location).map(|value| (key, value)))
+    }
+}
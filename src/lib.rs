@@ -0,0 +1,80 @@
+//! StorgataDB, a key-value store speaking the Redis protocol, replicated with Raft.
+//!
+//! The binary runs a node from its flags; an application embeds one, or a test runs one in
+//! process, with [`StorgataServer`] and a [`Config`] parsed as the flags are.
+
+mod acl;
+mod admin;
+mod analytics;
+mod audit;
+mod backup;
+mod bitmap;
+mod bloom;
+mod cache;
+mod chunk;
+mod cli;
+mod clients;
+mod cluster;
+mod cmd;
+mod compaction;
+mod compat;
+mod compression;
+mod config;
+mod config_file;
+mod connection;
+mod database;
+mod dump;
+mod ephemeral;
+mod expiry;
+mod export;
+mod failpoint;
+mod feature;
+mod flush;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(test)]
+mod golden;
+mod health;
+mod hooks;
+mod http;
+mod keyspace;
+mod list;
+mod lock;
+mod logger;
+mod maintenance;
+mod memory;
+mod monitor;
+mod node;
+mod overload;
+mod plugin;
+mod prefix_stats;
+mod procedure;
+mod pubsub;
+mod raft_log;
+mod rdb;
+mod recovery;
+mod replica;
+mod resp_codec;
+mod s3;
+mod schema;
+mod script;
+mod scrubber;
+mod server;
+mod shred;
+mod shutdown;
+mod sketch;
+mod slo;
+mod slowlog;
+mod stages;
+mod standby;
+mod stats;
+mod sync_layer;
+mod tls;
+mod units;
+mod value;
+mod view;
+mod wait;
+mod zset;
+
+pub use crate::cli::{parse_args, parse_args_from, Args as Config};
+pub use crate::node::{Logging, ShutdownHandle, StorgataServer};
@@ -0,0 +1,130 @@
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// A list is stored as a single bincode-encoded value under its key.
+/// All mutations happen in the apply path, so every replica computes the same encoding.
+type List = VecDeque<Vec<u8>>;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum End {
+    Left,
+    Right,
+}
+
+/// The list with the deadline it expires at, if any
+fn load(storage: &BitCask, key: &Vec<u8>, now: u64) -> Result<(List, Option<u64>), BitCaskError> {
+    // an expired value is no longer the key's, whatever its type
+    match storage.get(key).filter(|raw| !value::expired(raw, now)) {
+        Some(raw) => bincode::deserialize::<List>(value::expect(&raw, ValueType::List)?)
+            .map(|list| (list, value::deadline(&raw)))
+            .map_err(|_| BitCaskError::CorruptedData("value is not a list".to_string())),
+        None => Ok((List::new(), None)),
+    }
+}
+
+/// Store the list, keeping the deadline it was loaded with
+fn store(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    list: &List,
+    deadline: Option<u64>,
+) -> Result<(), BitCaskError> {
+    // Redis semantics: a list that becomes empty is removed
+    if list.is_empty() {
+        return storage.delete(key);
+    }
+    let payload = bincode::serialize(list).map_err(|e| BitCaskError::UnexpectedError(e.into()))?;
+    storage.put(
+        key,
+        &value::encode_expiring(ValueType::List, &payload, deadline),
+    )
+}
+
+/// Push `values` one by one to the given end of the list, returning the new length.
+pub(crate) fn push(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    values: &[Vec<u8>],
+    end: End,
+    now: u64,
+) -> Result<usize, BitCaskError> {
+    let (mut list, deadline) = load(storage, key, now)?;
+    for value in values {
+        match end {
+            End::Left => list.push_front(value.clone()),
+            End::Right => list.push_back(value.clone()),
+        }
+    }
+    store(storage, key, &list, deadline)?;
+    Ok(list.len())
+}
+
+/// Pop up to `count` elements from the given end of the list.
+/// Returns `None` if the key does not exist.
+pub(crate) fn pop(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    count: usize,
+    end: End,
+    now: u64,
+) -> Result<Option<Vec<Vec<u8>>>, BitCaskError> {
+    let (mut list, deadline) = load(storage, key, now)?;
+    if list.is_empty() {
+        return Ok(None);
+    }
+    let mut popped = Vec::with_capacity(count.min(list.len()));
+    while popped.len() < count {
+        let value = match end {
+            End::Left => list.pop_front(),
+            End::Right => list.pop_back(),
+        };
+        match value {
+            Some(value) => popped.push(value),
+            None => break,
+        }
+    }
+    store(storage, key, &list, deadline)?;
+    Ok(Some(popped))
+}
+
+/// Resolve Redis-style inclusive `start`/`stop` indexes against a sequence of `len` elements.
+/// Negative indexes count from the tail. Returns `None` if the range is empty.
+pub(crate) fn index_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+/// Return the elements between `start` and `stop` (both inclusive).
+pub(crate) fn range(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    start: i64,
+    stop: i64,
+    now: u64,
+) -> Result<Vec<Vec<u8>>, BitCaskError> {
+    let (list, _) = load(storage, key, now)?;
+    match index_range(list.len(), start, stop) {
+        Some((start, stop)) => Ok(list.range(start..=stop).cloned().collect()),
+        None => Ok(vec![]),
+    }
+}
+
+pub(crate) fn len(storage: &BitCask, key: &Vec<u8>, now: u64) -> Result<usize, BitCaskError> {
+    Ok(load(storage, key, now)?.0.len())
+}
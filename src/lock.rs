@@ -0,0 +1,257 @@
+//! Locks on keys that release themselves after a time: `LOCK <key> <ttl> [NOTIFY]` takes the lock
+//! for ttl milliseconds unless it is held, replying a fencing token, and `UNLOCK <key> <token>`
+//! releases it early. Both are replicated writes, so every node agrees on who holds which lock.
+//!
+//! A token is one more than the last token given, which is kept with the locks, so every node
+//! replies the same token and a later holder always has a greater one: the resources a lock
+//! guards can reject the writes of a holder whose lock expired meanwhile, by their older token.
+//!
+//! With NOTIFY, each node publishes `released` or `expired` on the channel `__lock@<db>__:<key>`
+//! to its own subscribers, as the lock is released or once it expires by the clock of the node.
+//! Locks are kept under a reserved prefix, apart from the keys of the clients and hidden from
+//! KEYS.
+
+use crate::database;
+use crate::keyspace::Snapshot;
+use crate::pubsub::Broker;
+use crate::resp_codec::RespValue;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Keys holding the locks, not a prefix clients are expected to use
+pub(crate) const KEY_PREFIX: &[u8] = b"\xfflock:";
+/// Key holding the last fencing token given
+pub(crate) const FENCE_KEY: &[u8] = b"\xfflockfence";
+/// How often a node looks for the locks that expired, to notify them
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A LOCK or UNLOCK
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum LockOp {
+    // Key, ttl in milliseconds, NOTIFY, unix time in milliseconds on the node it was sent to
+    Lock(Vec<u8>, u64, bool, u64),
+    // Key, fencing token, unix time in milliseconds on the node it was sent to
+    Unlock(Vec<u8>, u64, u64),
+}
+
+impl LockOp {
+    pub(crate) fn key(&self) -> &Vec<u8> {
+        match self {
+            LockOp::Lock(key, _, _, _) | LockOp::Unlock(key, _, _) => key,
+        }
+    }
+
+    pub(crate) fn key_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            LockOp::Lock(key, _, _, _) | LockOp::Unlock(key, _, _) => key,
+        }
+    }
+}
+
+/// A lock as stored
+struct Held {
+    token: u64,
+    // unix time in milliseconds
+    deadline: u64,
+    notify: bool,
+}
+
+impl Held {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(17);
+        payload.extend_from_slice(&self.token.to_le_bytes());
+        payload.extend_from_slice(&self.deadline.to_le_bytes());
+        payload.push(self.notify as u8);
+        value::encode(ValueType::Lock, &payload)
+    }
+
+    fn decode(raw: &[u8]) -> Result<Self, BitCaskError> {
+        let payload = value::expect(raw, ValueType::Lock)?;
+        let invalid = || BitCaskError::UnexpectedError(anyhow::anyhow!("invalid lock"));
+        let (token, rest) = payload.split_first_chunk::<8>().ok_or_else(invalid)?;
+        let (deadline, notify) = rest.split_first_chunk::<8>().ok_or_else(invalid)?;
+        Ok(Self {
+            token: u64::from_le_bytes(*token),
+            deadline: u64::from_le_bytes(*deadline),
+            notify: notify == [1],
+        })
+    }
+}
+
+fn lock_key(key: &[u8]) -> Vec<u8> {
+    [KEY_PREFIX, key].concat()
+}
+
+/// The channel the changes of the lock of a key are published on
+fn channel(key: &[u8]) -> Vec<u8> {
+    let (db, key) = database::split(key).unwrap_or((0, key));
+    [format!("__lock@{}__:", db).as_bytes(), key].concat()
+}
+
+/// The locks taken with NOTIFY, whose expiry the node publishes
+#[derive(Default)]
+pub(crate) struct Locks {
+    // by key: their token and deadline
+    armed: Mutex<HashMap<Vec<u8>, (u64, u64)>>,
+}
+
+impl Locks {
+    /// Watch the expiry of the locks taken with NOTIFY in the data files
+    pub(crate) fn load(data_dir: &Path) -> anyhow::Result<Self> {
+        let locks = Self::default();
+        for entry in Snapshot::take(data_dir)?.iter()? {
+            let (key, raw) = entry?;
+            let Some(key) = key.strip_prefix(KEY_PREFIX) else {
+                continue;
+            };
+            let held = Held::decode(&raw)?;
+            if held.notify {
+                locks.arm(key, &held);
+            }
+        }
+        Ok(locks)
+    }
+
+    fn arm(&self, key: &[u8], held: &Held) {
+        let mut armed = self.armed.lock().unwrap();
+        armed.insert(key.to_vec(), (held.token, held.deadline));
+    }
+
+    /// Stop watching the lock, returning whether it was watched
+    fn disarm(&self, key: &[u8], token: u64) -> bool {
+        let mut armed = self.armed.lock().unwrap();
+        match armed.get(key) {
+            Some((armed_token, _)) if *armed_token == token => armed.remove(key).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Run a LOCK or UNLOCK against the storage
+    pub(crate) fn apply(
+        &self,
+        storage: &mut BitCask,
+        broker: &Broker,
+        op: &LockOp,
+    ) -> Result<RespValue, BitCaskError> {
+        let key = op.key();
+        let held = match storage.get(&lock_key(key)) {
+            Some(raw) => Some(Held::decode(&raw)?),
+            None => None,
+        };
+        match op {
+            LockOp::Lock(_, ttl, notify, now) => {
+                if let Some(held) = held {
+                    if held.deadline > *now {
+                        return Ok(RespValue::BulkString(None));
+                    }
+                    // expired before the node noticed
+                    if self.disarm(key, held.token) {
+                        broker.publish(&channel(key), b"expired");
+                    }
+                }
+                let fence = match storage.get(&FENCE_KEY.to_vec()) {
+                    Some(raw) => {
+                        let payload = value::expect(&raw, ValueType::Lock)?;
+                        u64::from_le_bytes(payload.try_into().unwrap_or_default())
+                    }
+                    None => 0,
+                };
+                let held = Held {
+                    token: fence + 1,
+                    deadline: now.saturating_add(*ttl),
+                    notify: *notify,
+                };
+                storage.put(
+                    &FENCE_KEY.to_vec(),
+                    &value::encode(ValueType::Lock, &held.token.to_le_bytes()),
+                )?;
+                storage.put(&lock_key(key), &held.encode())?;
+                if held.notify {
+                    self.arm(key, &held);
+                }
+                Ok(RespValue::Integer(held.token as i64))
+            }
+            LockOp::Unlock(_, token, now) => {
+                let Some(held) = held else {
+                    return Ok(RespValue::Integer(0));
+                };
+                // an expired lock is released by the next LOCK or UNLOCK, whatever its token
+                let expired = held.deadline <= *now;
+                if !expired && held.token != *token {
+                    return Ok(RespValue::Integer(0));
+                }
+                storage.delete(&lock_key(key))?;
+                if self.disarm(key, held.token) {
+                    let event: &[u8] = if expired { b"expired" } else { b"released" };
+                    broker.publish(&channel(key), event);
+                }
+                Ok(RespValue::Integer(!expired as i64))
+            }
+        }
+    }
+
+    /// Publish the expiry of the locks taken with NOTIFY, by the clock of the node
+    pub(crate) async fn notify_expired(self: Arc<Self>, broker: Arc<Broker>) {
+        let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = value::now();
+            let mut expired = Vec::new();
+            self.armed.lock().unwrap().retain(|key, (_, deadline)| {
+                if *deadline > now {
+                    return true;
+                }
+                expired.push(key.clone());
+                false
+            });
+            for key in expired {
+                broker.publish(&channel(&key), b"expired");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_follow_the_replicated_fence_not_the_node() {
+        let broker = Broker::default();
+        let ops = [
+            LockOp::Lock(b"k".to_vec(), 1000, false, 10),
+            LockOp::Unlock(b"k".to_vec(), 1, 20),
+            LockOp::Lock(b"k".to_vec(), 1000, false, 30),
+            // taken again once expired
+            LockOp::Lock(b"k".to_vec(), 1000, false, 2000),
+            LockOp::Unlock(b"k".to_vec(), 2, 2010),
+        ];
+        let mut replies = Vec::new();
+        // the second node applied other entries before, as one replaying the log or restored
+        // from a backup has counted more of them
+        for others in [0, 5] {
+            let dir = tempfile::tempdir().unwrap();
+            let mut storage = BitCask::new(dir.path()).unwrap();
+            for i in 0..others {
+                storage.put(&vec![i], &b"v".to_vec()).unwrap();
+            }
+            let locks = Locks::default();
+            let node: Vec<i64> = ops
+                .iter()
+                .map(|op| match locks.apply(&mut storage, &broker, op).unwrap() {
+                    RespValue::Integer(reply) => reply,
+                    reply => panic!("unexpected reply {:?}", reply),
+                })
+                .collect();
+            replies.push(node);
+        }
+        assert_eq!(replies[0], [1, 1, 2, 3, 0]);
+        assert_eq!(replies[0], replies[1]);
+    }
+}
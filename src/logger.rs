@@ -1,20 +1,21 @@
 /// This module is copied from https://github.com/robatipoor/rustfulapi
+use crate::config::LogReload;
 use std::io;
 use tracing::{subscriber, Subscriber};
 use tracing_appender::{
     non_blocking::WorkerGuard,
     rolling::{RollingFileAppender, Rotation},
 };
-use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+use tracing_bunyan_formatter::BunyanFormattingLayer;
 use tracing_log::LogTracer;
-use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, EnvFilter, Registry};
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan, reload, EnvFilter, Registry};
 use tracing_subscriber::fmt::Layer;
 
 fn create_subscriber<W>(
     name: &str,
-    env_filter: EnvFilter,
+    env_filter: reload::Layer<EnvFilter, Registry>,
     writer: W,
-) -> impl Subscriber + Sync + Send
+) -> impl Subscriber + for<'span> LookupSpan<'span> + Sync + Send
     where
         W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
 {
@@ -36,21 +37,91 @@ pub fn init_subscriber<S>(subscriber: S) -> anyhow::Result<()>
     Ok(())
 }
 
+/// Flushes the log file, and exports the spans not sent yet, when dropped
+pub struct LogGuard {
+    _file_appender_guard: WorkerGuard,
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take() {
+            if let Err(e) = tracer_provider.shutdown() {
+                eprintln!("Could not export the last spans: {e}");
+            }
+        }
+    }
+}
+
+/// Export the spans to the OpenTelemetry collector at `endpoint`, in batches from a thread of
+/// the exporter
+#[cfg(feature = "otlp")]
+fn tracer_provider(
+    endpoint: &str,
+    service_name: &str,
+) -> anyhow::Result<opentelemetry_sdk::trace::SdkTracerProvider> {
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name.to_string()).build())
+        .build())
+}
+
+/// Set up logging, returning the guard flushing the log file and a function changing the level.
+/// With an OTLP endpoint, the spans are also exported to an OpenTelemetry collector.
 pub fn init(
     level: String,
-    rust_log: &str
-) -> anyhow::Result<WorkerGuard> {
+    rust_log: &str,
+    otlp_endpoint: Option<&str>,
+) -> anyhow::Result<(LogGuard, LogReload)> {
     let project_name = env!("CARGO_PKG_NAME");
     let underscored_project_name = project_name.replace("-", "_");
+    // kept to rebuild the filter when the level changes
+    let base_filter = rust_log.to_string();
     let rust_log = format!("{rust_log},{underscored_project_name}={level}");
     std::env::set_var("RUST_LOG", rust_log);
 
     let file_appender = RollingFileAppender::new(Rotation::DAILY, "./data/logs", "kv.log");
     let (file_appender, file_appender_guard) = tracing_appender::non_blocking(file_appender);
-    init_subscriber(create_subscriber(
+    let (env_filter, reload_handle) = reload::Layer::new(EnvFilter::from_default_env());
+    let subscriber = create_subscriber(
         "kv",
-        EnvFilter::from_default_env(),
+        env_filter,
         file_appender,
-    ))?;
-    Ok(file_appender_guard)
+    );
+    #[cfg(feature = "otlp")]
+    let (subscriber, tracer_provider) = {
+        use opentelemetry::trace::TracerProvider;
+        let tracer_provider = otlp_endpoint
+            .map(|endpoint| tracer_provider(endpoint, project_name))
+            .transpose()?;
+        let layer = tracer_provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(project_name)));
+        (subscriber.with(layer), tracer_provider)
+    };
+    #[cfg(not(feature = "otlp"))]
+    if otlp_endpoint.is_some() {
+        anyhow::bail!("--otlp-endpoint needs a build with the otlp feature");
+    }
+    init_subscriber(subscriber)?;
+    let log_reload: LogReload = Box::new(move |level| {
+        let filter = EnvFilter::try_new(format!("{base_filter},{underscored_project_name}={level}"))?;
+        reload_handle.reload(filter)?;
+        Ok(())
+    });
+    let guard = LogGuard {
+        _file_appender_guard: file_appender_guard,
+        #[cfg(feature = "otlp")]
+        tracer_provider,
+    };
+    Ok((guard, log_reload))
 }
@@ -0,0 +1,64 @@
+//! ADMIN MAINTENANCE ON|OFF: take a node out of service for OS patching or a restart, without
+//! request errors. The node stops reporting itself ready in INFO, and each client sending it a
+//! read, write or pub/sub command is redirected to a peer with `-MOVED <slot> <kv addr>`, as a
+//! Redis Cluster node redirects the keys it does not own, then its connection is closed once the
+//! replies to its earlier commands are sent. The node keeps replicating and applying the log, so
+//! it is up to date when maintenance ends, and keeps serving the admin commands.
+//!
+//! raft-lite neither tells a node whether it leads the group nor hands leadership over, so a
+//! leader in maintenance stays one until it stops, when the others elect a new one within an
+//! election timeout; the writes proposed meanwhile wait for it rather than fail.
+
+use crate::admin::Peer;
+use crate::cluster;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use tracing::info;
+
+/// Replied to the commands of the clients when no peer is known to redirect them to
+pub(crate) const TRYAGAIN: &str = "TRYAGAIN the node is in maintenance, retry on another node";
+
+#[derive(Default)]
+pub(crate) struct Maintenance {
+    on: AtomicBool,
+    // the next peer to redirect a client to, modulo the peers
+    next: AtomicUsize,
+    redirected: AtomicU64,
+}
+
+impl Maintenance {
+    /// Turn maintenance on or off, returning whether it changed
+    pub(crate) fn set(&self, on: bool) -> bool {
+        let changed = self.on.swap(on, Ordering::Relaxed) != on;
+        if changed {
+            info!("Maintenance {}", if on { "on" } else { "off" });
+        }
+        changed
+    }
+
+    pub(crate) fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    /// The error redirecting a client to one of the peers in turn, for its command on `key`
+    pub(crate) fn redirect(&self, peers: &[Peer], key: Option<&[u8]>) -> String {
+        self.redirected.fetch_add(1, Ordering::Relaxed);
+        if peers.is_empty() {
+            return TRYAGAIN.to_string();
+        }
+        let peer = &peers[self.next.fetch_add(1, Ordering::Relaxed) % peers.len()];
+        format!(
+            "MOVED {} {}",
+            key.map_or(0, cluster::key_slot),
+            peer.kv_addr
+        )
+    }
+
+    /// Render the maintenance fields of INFO server
+    pub(crate) fn info(&self) -> String {
+        format!(
+            "maintenance:{}\r\nmaintenance_redirected_clients:{}\r\n",
+            self.is_on() as u8,
+            self.redirected.load(Ordering::Relaxed)
+        )
+    }
+}
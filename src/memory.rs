@@ -0,0 +1,349 @@
+//! --maxmemory: a bound on the memory the keys of the clients take, their keys in the keydir and
+//! their values in the data files. The node counts the bytes of each key and value as the apply
+//! path writes them, so every node counts the same bytes at the same entry of the log. Once they
+//! are over the bound, a write that may take more is rejected with -OOM, or with an eviction
+//! policy, the node it is sent to proposes the eviction of keys before it: the keys are deleted
+//! through the log, so every node evicts the same ones.
+//!
+//! The keys are only tracked on a node started with a bound, which CONFIG SET maxmemory and
+//! maxmemory-policy change from then on.
+//!
+//! - noeviction: nothing is evicted
+//! - allkeys-lru: of a few keys drawn at random, the one read or written least recently on the
+//!   node proposing the eviction. A read stamps the slot of its key in a table of access times
+//!   rather than the key itself, so that it takes no lock: keys sharing a slot look as recent as
+//!   the most recent of them.
+//! - allkeys-random: keys drawn at random
+//! - volatile-ttl: of a few keys with an expiry drawn at random, the one expiring first. Keys
+//!   without an expiry are never evicted, so writes are rejected once none is left.
+
+use crate::config::Config;
+use crate::database;
+use crate::keyspace::Snapshot;
+use crate::value;
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::info;
+use uuid::Uuid;
+
+/// Replied to the writes rejected over maxmemory, as Redis does
+pub(crate) const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Bytes a key takes beside itself and its value: its location in the keydir, the header of its
+/// entry in the data files and its entry in the tracking below, roughly
+const ENTRY_OVERHEAD: u64 = 64;
+/// Keys drawn at random for each key evicted by allkeys-lru and volatile-ttl, as Redis does
+const SAMPLES: usize = 5;
+/// Keys evicted at most ahead of a write, the next write evicting more if needed
+const MAX_EVICTED: usize = 64;
+/// Slots of the table of access times of allkeys-lru
+const ACCESS_SLOTS: usize = 1 << 16;
+
+/// What is evicted once the keys take more than maxmemory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Policy {
+    #[value(name = "noeviction")]
+    NoEviction,
+    AllkeysLru,
+    AllkeysRandom,
+    VolatileTtl,
+}
+
+impl Policy {
+    pub(crate) fn parse(policy: &str) -> Option<Self> {
+        match policy.to_lowercase().as_str() {
+            "noeviction" => Some(Self::NoEviction),
+            "allkeys-lru" => Some(Self::AllkeysLru),
+            "allkeys-random" => Some(Self::AllkeysRandom),
+            "volatile-ttl" => Some(Self::VolatileTtl),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::NoEviction => "noeviction",
+            Self::AllkeysLru => "allkeys-lru",
+            Self::AllkeysRandom => "allkeys-random",
+            Self::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+struct Entry {
+    size: u64,
+    // unix time in milliseconds
+    deadline: Option<u64>,
+    // positions in the lists of keys to draw from
+    all: usize,
+    volatile: Option<usize>,
+}
+
+/// The keys of the clients with their size, and lists of them to draw from at random
+#[derive(Default)]
+struct Keys {
+    entries: HashMap<Vec<u8>, Entry>,
+    all: Vec<Vec<u8>>,
+    // those with an expiry
+    volatile: Vec<Vec<u8>>,
+}
+
+impl Keys {
+    /// Track the key with its size and deadline, returning the size it had
+    fn insert(&mut self, key: &[u8], size: u64, deadline: Option<u64>) -> u64 {
+        let old = self.remove(key);
+        let volatile = deadline.map(|_| {
+            self.volatile.push(key.to_vec());
+            self.volatile.len() - 1
+        });
+        self.all.push(key.to_vec());
+        let entry = Entry {
+            size,
+            deadline,
+            all: self.all.len() - 1,
+            volatile,
+        };
+        self.entries.insert(key.to_vec(), entry);
+        old
+    }
+
+    /// Stop tracking the key, returning its size, 0 if it was not tracked
+    fn remove(&mut self, key: &[u8]) -> u64 {
+        let Some(entry) = self.entries.remove(key) else {
+            return 0;
+        };
+        // the last key of each list takes the place of the removed one
+        self.all.swap_remove(entry.all);
+        if let Some(moved) = self.all.get(entry.all) {
+            self.entries.get_mut(moved).unwrap().all = entry.all;
+        }
+        if let Some(index) = entry.volatile {
+            self.volatile.swap_remove(index);
+            if let Some(moved) = self.volatile.get(index) {
+                self.entries.get_mut(moved).unwrap().volatile = Some(index);
+            }
+        }
+        entry.size
+    }
+}
+
+/// Delete the keys of an eviction still in the storage, returning how many. A node applies the
+/// evictions proposed by the others whatever its own bound.
+pub(crate) fn evict(storage: &mut BitCask, keys: &[Vec<u8>]) -> Result<u64, BitCaskError> {
+    let mut evicted = 0;
+    for key in keys {
+        if storage.get(key).is_some() {
+            storage.delete(key)?;
+            evicted += 1;
+        }
+    }
+    Ok(evicted)
+}
+
+/// A number drawn at random below `n`, from the random bits of a v4 uuid
+fn below(n: usize) -> usize {
+    (Uuid::new_v4().as_u64_pair().1 % n as u64) as usize
+}
+
+pub(crate) struct Memory {
+    // maxmemory and maxmemory-policy
+    config: Arc<Config>,
+    // the bytes of the keys tracked, read by every write
+    used: AtomicU64,
+    keys: Mutex<Keys>,
+    // in seconds since `started`, per slot of the hash of the keys, stamped under allkeys-lru
+    accessed: Box<[AtomicU32]>,
+    hasher: RandomState,
+    started: Instant,
+    evicted: AtomicU64,
+}
+
+impl Memory {
+    /// Track the keys live in the data files
+    pub(crate) fn load(config: Arc<Config>, data_dir: &Path) -> anyhow::Result<Self> {
+        let memory = Self {
+            config,
+            used: AtomicU64::new(0),
+            keys: Mutex::default(),
+            accessed: (0..ACCESS_SLOTS).map(|_| AtomicU32::new(0)).collect(),
+            hasher: RandomState::new(),
+            started: Instant::now(),
+            evicted: AtomicU64::new(0),
+        };
+        for entry in Snapshot::take(data_dir)?.iter()? {
+            let (key, raw) = entry?;
+            memory.track(&key, Some(&raw));
+        }
+        info!(
+            "Maxmemory: {} of {:?} bytes used, evicting with {}",
+            memory.used(),
+            memory.config.maxmemory(),
+            memory.config.maxmemory_policy().name()
+        );
+        Ok(memory)
+    }
+
+    fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether the keys take more than maxmemory, so that the writes evict or are rejected
+    pub(crate) fn over(&self) -> bool {
+        self.config
+            .maxmemory()
+            .is_some_and(|limit| self.used() > limit)
+    }
+
+    /// Count the key at the size of its value, or not at all without one, unless it is a key of
+    /// the node itself
+    fn track(&self, key: &[u8], raw: Option<&[u8]>) {
+        if database::split(key).is_none() {
+            return;
+        }
+        let mut keys = self.keys.lock().unwrap();
+        let old = match raw {
+            Some(raw) => {
+                let size = key.len() as u64 + raw.len() as u64 + ENTRY_OVERHEAD;
+                let old = keys.insert(key, size, value::deadline(raw));
+                self.used.fetch_add(size, Ordering::Relaxed);
+                old
+            }
+            None => keys.remove(key),
+        };
+        self.used.fetch_sub(old, Ordering::Relaxed);
+    }
+
+    /// Count a key the apply path wrote at the size it now has in the storage
+    pub(crate) fn written(&self, storage: &BitCask, key: &[u8]) {
+        self.track(key, storage.get(&key.to_vec()).as_deref());
+        self.accessed(key);
+    }
+
+    /// Stop counting the keys of a flush, or those of database `db`, as deleted once it applies
+    pub(crate) fn flushed(&self, db: Option<u32>) {
+        let mut keys = self.keys.lock().unwrap();
+        let flushed: Vec<_> = keys
+            .all
+            .iter()
+            .filter(|key| db.is_none_or(|db| database::of(db, key).is_some()))
+            .cloned()
+            .collect();
+        for key in flushed {
+            let size = keys.remove(&key);
+            self.used.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Count the keys of databases `first` and `second` in each other's, as SWAPDB moves them
+    pub(crate) fn swapped(&self, first: u32, second: u32) {
+        let mut keys = self.keys.lock().unwrap();
+        let moved: Vec<_> = keys
+            .all
+            .iter()
+            .filter_map(|stored| match database::split(stored)? {
+                (db, key) if db == first => Some((stored.clone(), database::key(second, key))),
+                (db, key) if db == second => Some((stored.clone(), database::key(first, key))),
+                _ => None,
+            })
+            .collect();
+        let moved: Vec<_> = moved
+            .into_iter()
+            .map(|(from, to)| {
+                let entry = &keys.entries[&from];
+                let (size, deadline) = (entry.size, entry.deadline);
+                keys.remove(&from);
+                (to, size, deadline)
+            })
+            .collect();
+        for (key, size, deadline) in moved {
+            keys.insert(&key, size, deadline);
+        }
+    }
+
+    /// Stamp the access time of a key read or written, for allkeys-lru
+    pub(crate) fn accessed(&self, key: &[u8]) {
+        if !self.tracks_access() {
+            return;
+        }
+        let slot = self.hasher.hash_one(key) as usize % self.accessed.len();
+        let now = self.started.elapsed().as_secs() as u32;
+        self.accessed[slot].store(now, Ordering::Relaxed);
+    }
+
+    /// Whether reads stamp the access time of their key
+    pub(crate) fn tracks_access(&self) -> bool {
+        self.config.maxmemory_policy() == Policy::AllkeysLru
+    }
+
+    fn access_time(&self, key: &[u8]) -> u32 {
+        let slot = self.hasher.hash_one(key) as usize % self.accessed.len();
+        self.accessed[slot].load(Ordering::Relaxed)
+    }
+
+    /// The keys to evict for the keys to take maxmemory at most, as the policy picks them, None
+    /// if it picks none
+    pub(crate) fn victims(&self) -> Option<Vec<Vec<u8>>> {
+        let keys = self.keys.lock().unwrap();
+        let policy = self.config.maxmemory_policy();
+        let limit = self.config.maxmemory()?;
+        let excess = self.used().saturating_sub(limit);
+        let (mut victims, mut freed) = (HashSet::new(), 0);
+        while freed < excess && victims.len() < MAX_EVICTED {
+            let pool = match policy {
+                Policy::NoEviction => return None,
+                Policy::VolatileTtl => &keys.volatile,
+                Policy::AllkeysLru | Policy::AllkeysRandom => &keys.all,
+            };
+            // the keys picked already, given to the eviction, are not drawn again
+            if pool.len() <= victims.len() {
+                break;
+            }
+            let samples = match policy {
+                Policy::AllkeysRandom => 1,
+                _ => SAMPLES,
+            };
+            let mut drawn = (0..samples * 2)
+                .map(|_| &pool[below(pool.len())])
+                .filter(|key| !victims.contains(*key))
+                .take(samples);
+            let victim = match policy {
+                Policy::AllkeysLru => drawn.min_by_key(|key| self.access_time(key)),
+                Policy::VolatileTtl => drawn.min_by_key(|key| keys.entries[*key].deadline),
+                _ => drawn.next(),
+            };
+            // every key drawn was picked already, the next draw may find others
+            let Some(victim) = victim else {
+                continue;
+            };
+            freed += keys.entries[victim].size;
+            victims.insert(victim.clone());
+        }
+        (!victims.is_empty()).then(|| victims.into_iter().collect())
+    }
+
+    /// Count the keys an eviction deleted
+    pub(crate) fn evicted(&self, evicted: u64) {
+        self.evicted.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    /// Render the `memory` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let keys = self.keys.lock().unwrap();
+        format!(
+            "used_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\ntracked_keys:{}\r\nvolatile_keys:{}\r\nevicted_keys:{}\r\n",
+            self.used(),
+            self.config.maxmemory().unwrap_or_default(),
+            self.config.maxmemory_policy().name(),
+            keys.entries.len(),
+            keys.volatile.len(),
+            self.evicted.load(Ordering::Relaxed),
+        )
+    }
+}
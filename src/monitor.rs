@@ -0,0 +1,123 @@
+//! MONITOR: every command the node runs, streamed to the clients monitoring it. As in Redis, a
+//! line holds the time, the client and the arguments of the command, the arguments of the
+//! commands carrying passwords are redacted, and the admin commands are not streamed.
+
+use crate::resp_codec::RespValue;
+use bytes::Bytes;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Lines queued for a monitor that does not read them fast enough are dropped beyond this limit
+const PENDING_LINES_LIMIT: usize = 4096;
+
+pub(crate) struct Monitor {
+    feed: broadcast::Sender<String>,
+    // the receivers of the feed, counted apart as the channel locks to count them, and every
+    // command checks for them
+    monitors: Arc<AtomicUsize>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            feed: broadcast::channel(PENDING_LINES_LIMIT).0,
+            monitors: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+/// The feed of a monitoring connection, which stops counting as a monitor once dropped
+pub(crate) struct Monitoring {
+    lines: broadcast::Receiver<String>,
+    monitors: Arc<AtomicUsize>,
+}
+
+impl Monitoring {
+    pub(crate) async fn recv(&mut self) -> Result<String, RecvError> {
+        self.lines.recv().await
+    }
+}
+
+impl Drop for Monitoring {
+    fn drop(&mut self) {
+        self.monitors.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Monitor {
+    /// Stream the command, as received, to the monitors
+    pub(crate) fn feed(&self, frame: &RespValue, addr: SocketAddr) {
+        // the line is not worth formatting when nobody reads it
+        if self.monitors.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = format!("{}.{:06} [0 {}]", now.as_secs(), now.subsec_micros(), addr);
+        for arg in args(frame) {
+            line.push(' ');
+            repr(&mut line, &arg);
+        }
+        // no monitor left since the check is fine
+        let _ = self.feed.send(line);
+    }
+
+    pub(crate) fn subscribe(&self) -> Monitoring {
+        self.monitors.fetch_add(1, Ordering::Relaxed);
+        Monitoring {
+            lines: self.feed.subscribe(),
+            monitors: self.monitors.clone(),
+        }
+    }
+}
+
+/// The arguments of a command, but none of those of the commands carrying passwords
+pub(crate) fn args(frame: &RespValue) -> Vec<Bytes> {
+    let RespValue::Array(items) = frame else {
+        return Vec::new();
+    };
+    let mut args: Vec<Bytes> = items
+        .iter()
+        .map(|item| match item {
+            RespValue::BulkString(Some(bytes)) => bytes.clone(),
+            RespValue::SimpleString(s) => Bytes::from(s.clone()),
+            _ => Bytes::new(),
+        })
+        .collect();
+    let secret = args.first().is_some_and(|name| {
+        [&b"AUTH"[..], b"HELLO", b"ACL"]
+            .iter()
+            .any(|secret| name.eq_ignore_ascii_case(secret))
+    });
+    if secret && args.len() > 1 {
+        args.truncate(1);
+        args.push(Bytes::from_static(b"(redacted)"));
+    }
+    args
+}
+
+/// Quote the argument like Redis does, escaping the bytes that are not printable
+fn repr(line: &mut String, arg: &[u8]) {
+    line.push('"');
+    for &byte in arg {
+        match byte {
+            b'\\' => line.push_str("\\\\"),
+            b'"' => line.push_str("\\\""),
+            b'\n' => line.push_str("\\n"),
+            b'\r' => line.push_str("\\r"),
+            b'\t' => line.push_str("\\t"),
+            0x07 => line.push_str("\\a"),
+            0x08 => line.push_str("\\b"),
+            b' '..=b'~' => line.push(byte as char),
+            _ => {
+                let _ = write!(line, "\\x{:02x}", byte);
+            }
+        }
+    }
+    line.push('"');
+}
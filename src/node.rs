@@ -0,0 +1,370 @@
+//! The node as a library, for an application to embed it or a test to run it in process:
+//! [`StorgataServer`] runs a node from the flags of the binary, on a runtime of its own as the
+//! binary does or on the runtime of the application, and a [`ShutdownHandle`] stops it as
+//! SHUTDOWN does.
+
+use crate::acl::Acl;
+use crate::analytics::Analytics;
+use crate::audit::Audit;
+use crate::backup::{self, Backups, Remote, Schedule, Store};
+use crate::cache::{NegativeCache, ReadCache};
+use crate::cli::Args;
+use crate::clients::Clients;
+use crate::cmd::{InnerCmd, NodeContext};
+use crate::compaction::{self, Compaction};
+use crate::config::{Config, LogReload};
+use crate::expiry::ExpiryForecast;
+use crate::export::Exports;
+use crate::feature::Features;
+use crate::flush::Flusher;
+use crate::hooks::Hooks;
+use crate::lock::Locks;
+use crate::logger::{self, LogGuard};
+use crate::maintenance::Maintenance;
+use crate::memory::Memory;
+use crate::monitor::Monitor;
+use crate::overload::Overload;
+use crate::plugin::Plugins;
+use crate::prefix_stats::PrefixStats;
+use crate::procedure::Procedures;
+use crate::pubsub::Broker;
+use crate::raft_log::CommittedLog;
+use crate::s3::Location;
+use crate::scrubber::{ScrubStats, Scrubber};
+use crate::shred::SecureDelete;
+use crate::shutdown::{SaveMode, Shutdown};
+use crate::slowlog::SlowLog;
+use crate::standby::Standby;
+use crate::stats::Stats;
+use crate::sync_layer::{self, Batching, RaftStats, SyncLayer};
+use crate::{admin, rdb, server, tls};
+use bitcask_engine_rs::bitcask::BitCask;
+use futures::FutureExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// The logging of the binary, set up from the log flags, which `CONFIG SET loglevel` changes the
+/// level of. It flushes the log file once dropped, so it is kept as long as the node runs.
+pub struct Logging {
+    guard: LogGuard,
+    reload: LogReload,
+}
+
+impl Logging {
+    pub fn init(args: &Args) -> anyhow::Result<Self> {
+        let (guard, reload) =
+            logger::init(args.log_level(), args.rust_log(), args.otlp_endpoint())?;
+        Ok(Self { guard, reload })
+    }
+}
+
+/// Stops a running node as SHUTDOWN does
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Shutdown>);
+
+impl ShutdownHandle {
+    /// Stop the node, saving its statistics unless `save` is false
+    pub fn shutdown(&self, save: bool) {
+        self.0.request(match save {
+            true => SaveMode::Default,
+            false => SaveMode::NoSave,
+        });
+    }
+}
+
+/// A node of the replicated store, run until it shuts down
+pub struct StorgataServer {
+    args: Args,
+    // None when the application logs as it sees fit
+    logging: Option<Logging>,
+    shutdown: Arc<Shutdown>,
+}
+
+/// A node set up to serve
+struct Node {
+    args: Args,
+    storage: BitCask,
+    scrub_stats: Arc<ScrubStats>,
+    context: NodeContext,
+}
+
+impl StorgataServer {
+    pub fn new(args: Args) -> Self {
+        Self {
+            args,
+            logging: None,
+            shutdown: Arc::new(Shutdown::default()),
+        }
+    }
+
+    /// Log as the binary does, rather than to the subscriber the application installed, if any
+    pub fn with_logging(mut self, logging: Logging) -> Self {
+        self.logging = Some(logging);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// Run the node on a runtime of its own, stopping it on SIGINT or SIGTERM too, as the binary
+    /// does
+    pub fn run_blocking(self) -> anyhow::Result<()> {
+        let (node, _log_guard) = self.set_up()?;
+        let rt = tokio::runtime::Runtime::new()?;
+        let result = rt.block_on(async {
+            let on_signal = node.context.shutdown.clone();
+            tokio::spawn(async move { on_signal.on_signal().await });
+            node.serve().await
+        });
+        info!("Shutting down");
+        rt.shutdown_timeout(Duration::from_secs(1));
+        result
+    }
+
+    /// Run the node on the current runtime, until the shutdown handle, SHUTDOWN or the end of
+    /// `--import-rdb` stops it. The tasks of the consensus end with the runtime rather than the
+    /// node.
+    pub async fn run(self) -> anyhow::Result<()> {
+        // restoring a backup and opening the storage block
+        let (node, _log_guard) = tokio::task::spawn_blocking(move || self.set_up()).await??;
+        let result = node.serve().await;
+        info!("Shutting down");
+        result
+    }
+
+    /// Restore the backup asked for, open the storage and set up the state of the node
+    fn set_up(self) -> anyhow::Result<(Node, Option<LogGuard>)> {
+        let Self {
+            args,
+            logging,
+            shutdown,
+        } = self;
+        let (log_guard, log_reload) = match logging {
+            Some(logging) => (Some(logging.guard), Some(logging.reload)),
+            None => (None, None),
+        };
+        info!("Starting with args: {:?}", args);
+        debug!("Starting debug");
+        for deprecated in args.deprecated() {
+            warn!(
+                "--{} is deprecated, set --{} instead",
+                deprecated.name, deprecated.replacement
+            );
+        }
+        #[cfg(not(feature = "grpc"))]
+        if args.grpc_addr().is_some() {
+            anyhow::bail!("--grpc-addr needs a build with the grpc feature");
+        }
+        if let Some(from) = args.restore_from() {
+            let store = if from.starts_with("s3://") {
+                Store::S3(args.s3()?, Location::parse(from)?)
+            } else {
+                Store::Dir(from.into())
+            };
+            backup::restore(&store, args.data_dir())?;
+        }
+        let storage = compaction::open(args.data_dir())?;
+        let acl = match args.acl_file() {
+            Some(path) => Acl::load(path)?,
+            None => Acl::default(),
+        };
+        Plugins::load(args.plugin())?.install()?;
+        let config = Arc::new(Config::new(&args, log_reload));
+        // the keys are tracked from the data files as they are before the log is replayed
+        let memory = match args.maxmemory() {
+            0 => None,
+            _ => Some(Arc::new(Memory::load(config.clone(), args.data_dir())?)),
+        };
+        let audit = match args.audit_dir() {
+            Some(dir) => Some(Arc::new(Audit::open(dir, args.audit_rotation())?)),
+            None => None,
+        };
+        let raft_stats = Arc::new(RaftStats::default());
+        let overload = Arc::new(Overload::new(config.clone(), raft_stats.clone()));
+        let scrub_stats = Arc::new(ScrubStats::default());
+        if let Some(rate_mb) = args.scrub_rate_mb() {
+            Scrubber::new(
+                args.data_dir(),
+                rate_mb,
+                Duration::from_secs(args.scrub_interval_secs()),
+                scrub_stats.clone(),
+                overload.clone(),
+            )
+            .spawn();
+        }
+        let expiry = Arc::new(ExpiryForecast::default());
+        expiry.clone().spawn(
+            args.data_dir(),
+            Duration::from_secs(args.expiry_forecast_interval_secs()),
+            overload.clone(),
+        );
+        let secure_delete = Arc::new(SecureDelete::new(args.secure_delete_prefix()));
+        secure_delete.clone().spawn(
+            args.data_dir(),
+            Duration::from_secs(args.secure_delete_interval_secs()),
+        );
+        let context = NodeContext {
+            acl: Arc::new(acl),
+            clients: Arc::new(Clients::new(args.node_id())),
+            stats: Arc::new(Stats::load(&args.stats_file())?),
+            slowlog: Arc::new(SlowLog::default()),
+            prefix_stats: Arc::new(PrefixStats::new(args.metrics_key_prefix())?),
+            monitor: Arc::new(Monitor::default()),
+            config,
+            data_dir: args.data_dir().to_path_buf(),
+            // the peers of Raft include this node
+            nodes: std::iter::once(args.self_addr())
+                .chain(
+                    args.peer_addr()
+                        .into_iter()
+                        .filter(|peer| *peer != args.self_addr()),
+                )
+                .collect(),
+            peers: admin::peers(&args.self_addr(), &args.peer_addr(), args.peer_kv_addr())?,
+            replica_of: args.replica_of().to_vec(),
+            databases: args.databases(),
+            read_view_max: Duration::from_secs(args.read_view_max_secs()),
+            raft_stats,
+            raft_log: Arc::new(CommittedLog::new(args.raft_log_kept() as usize)),
+            overload,
+            hooks: Arc::new(Hooks::compiled_in()),
+            shutdown,
+            procedures: Arc::new(Procedures::default()),
+            features: Arc::new(Features::load(
+                args.enable_features(),
+                args.disable_features(),
+                &storage,
+            )?),
+            locks: Arc::new(Locks::load(args.data_dir())?),
+            secure_delete,
+            exports: Arc::new(Exports::new(
+                args.export_signing_key(),
+                Duration::from_secs(args.export_min_interval_secs()),
+            )),
+            flusher: Arc::new(Flusher::new(Duration::from_secs(
+                args.flushall_confirm_secs(),
+            ))),
+            analytics: match args.analytics_addr() {
+                Some(_) => Some(Arc::new(Analytics::new(
+                    args.analytics_dir(),
+                    Duration::from_secs(args.analytics_ttl_secs()),
+                )?)),
+                None => None,
+            },
+            backups: Arc::new(Backups::new(
+                args.backup_dir(),
+                args.backup_schedule().map(Schedule::parse).transpose()?,
+                args.backup_retain(),
+                args.backup_full_every(),
+                match args.backup_s3_url() {
+                    Some(url) => Some(Remote::new(args.s3()?, Location::parse(url)?)),
+                    None => None,
+                },
+            )?),
+            compaction: Arc::new(Compaction::new(
+                match args.compaction_interval_secs() {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                },
+                args.compaction_dead_ratio(),
+                args.compaction_max_files(),
+            )),
+            expiry,
+            started: Instant::now(),
+            broker: Arc::new(Broker::default()),
+            read_cache: args
+                .read_cache_size()
+                .map(|bytes| Arc::new(ReadCache::new(bytes as usize))),
+            negative_cache: args
+                .negative_cache_keys()
+                .map(|keys| Arc::new(NegativeCache::new(keys))),
+            memory,
+            standby: Arc::new(Standby::new(args.standby())),
+            maintenance: Arc::new(Maintenance::default()),
+            audit,
+        };
+        Ok((
+            Node {
+                args,
+                storage,
+                scrub_stats,
+                context,
+            },
+            log_guard,
+        ))
+    }
+}
+
+impl Node {
+    /// Serve the clients and apply the log until the node shuts down
+    async fn serve(self) -> anyhow::Result<()> {
+        let Self {
+            args,
+            storage,
+            scrub_stats,
+            context,
+        } = self;
+        // aborted as the node stops
+        let mut tasks = JoinSet::new();
+        tasks.spawn(context.stats.clone().save_periodically(args.stats_file()));
+        tasks.spawn(context.backups.clone().run(args.data_dir().to_path_buf()));
+        tasks.spawn(
+            context
+                .compaction
+                .clone()
+                .run(storage.clone(), context.clone()),
+        );
+        tasks.spawn(context.locks.clone().notify_expired(context.broker.clone()));
+        let (sync_request_tx, sync_request_rx) = tokio::sync::mpsc::channel::<
+            sync_layer::SyncRequest<InnerCmd>,
+        >(args.proposal_queue_len());
+        let mut sync_layer = SyncLayer::<InnerCmd>::new(
+            sync_layer::consensus(&args),
+            Batching::new(&args),
+            storage.clone(),
+            context.clone(),
+            context.raft_stats.clone(),
+            context.raft_log.clone(),
+        );
+        let sync_layer_task = sync_layer.run(sync_request_rx);
+        let (stats, stats_file, shutdown) = (
+            context.stats.clone(),
+            args.stats_file(),
+            context.shutdown.clone(),
+        );
+        // proposed through the sync layer like the writes of clients, the node exits once done
+        let import = match args.import_rdb() {
+            Some(path) => {
+                rdb::import(path.to_path_buf(), sync_request_tx.clone(), context.clone()).boxed()
+            }
+            None => std::future::pending().boxed(),
+        };
+        let tls = match (args.tls_cert(), args.tls_key()) {
+            (Some(cert), Some(key)) => Some(tls::acceptor(cert, key, args.tls_ca())?),
+            _ => None,
+        };
+        let mut server =
+            server::Server::new(args, sync_request_tx, storage, scrub_stats, context, tls);
+        let server_task = server.run();
+        // the node stops accepting connections and applying entries as the tasks are dropped
+        let (save, result) = tokio::select! {
+            _ = async { tokio::join!(sync_layer_task, server_task) } => (false, Ok(())),
+            save = shutdown.requested() => (save, Ok(())),
+            imported = import => {
+                if let Ok(imported) = &imported {
+                    info!("Imported the RDB file: {:?}", imported);
+                }
+                (true, imported.map(drop))
+            }
+        };
+        if save {
+            if let Err(e) = stats.save(&stats_file) {
+                warn!("Could not save the stats on shutdown: {}", e);
+            }
+        }
+        result
+    }
+}
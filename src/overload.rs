@@ -0,0 +1,84 @@
+//! Load shedding when the node falls behind applying the committed log.
+//!
+//! The apply lag is the number of entries Raft committed and handed to the sync layer that are
+//! not applied yet. Past the `busy-apply-lag` threshold the node sheds load: writes other than
+//! deletions are rejected with BUSY, and the scrubber pauses. Shedding stops once the lag drains
+//! to half the threshold, so that the node does not flap around it.
+//!
+//! Writes are also rejected with BUSY before they are queued for the sync layer, when their
+//! connection has `--max-in-flight-writes` waiting for a reply or the queue shared by all the
+//! connections holds `--proposal-queue-len`, so that one client pipelining writes cannot keep the
+//! others waiting.
+
+use crate::config::Config;
+use crate::sync_layer::RaftStats;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+pub(crate) const BUSY: &str = "BUSY the node is behind applying committed writes, try again later";
+
+pub(crate) const BUSY_IN_FLIGHT: &str =
+    "BUSY too many writes of the connection wait for their reply, try again later";
+
+pub(crate) const BUSY_PROPOSALS: &str = "BUSY too many writes wait to be proposed, try again later";
+
+pub(crate) struct Overload {
+    config: Arc<Config>,
+    raft_stats: Arc<RaftStats>,
+    shedding: AtomicBool,
+    writes_shed: AtomicU64,
+}
+
+impl Overload {
+    pub(crate) fn new(config: Arc<Config>, raft_stats: Arc<RaftStats>) -> Self {
+        Self {
+            config,
+            raft_stats,
+            shedding: AtomicBool::new(false),
+            writes_shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether load is being shed, re-evaluated against the current lag
+    pub(crate) fn shedding(&self) -> bool {
+        let lag = self.raft_stats.apply_lag();
+        let threshold = self.config.busy_apply_lag();
+        let was_shedding = self.shedding.load(Ordering::Relaxed);
+        let shedding = threshold > 0
+            && if was_shedding {
+                lag > threshold / 2
+            } else {
+                lag > threshold
+            };
+        if shedding != was_shedding
+            && self
+                .shedding
+                .compare_exchange(was_shedding, shedding, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            if shedding {
+                warn!(
+                    "Overload: apply lag {} over {}, shedding load",
+                    lag, threshold
+                );
+            } else {
+                warn!("Overload: apply lag drained to {}, no longer shedding", lag);
+            }
+        }
+        shedding
+    }
+
+    pub(crate) fn write_shed(&self) {
+        self.writes_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn info(&self) -> String {
+        format!(
+            "busy_apply_lag:{}\r\nshedding:{}\r\nwrites_shed:{}\r\n",
+            self.config.busy_apply_lag(),
+            self.shedding() as u8,
+            self.writes_shed.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,394 @@
+//! Plugins: shared libraries loaded when the node starts, adding commands through a stable C ABI
+//! declared in `include/storgata_plugin.h`.
+//!
+//! A plugin declares each of its commands as a read or a write. Reads run against the local
+//! replica, like the built-in reads. Writes are replicated as Raft entries holding the name and
+//! the arguments of the command, and run in the apply path of every node: they must be
+//! deterministic, the same arguments against the same data giving the same changes and reply on
+//! every node, so no clocks, randomness or I/O. Every node of the group must load the same
+//! plugins, in the same versions.
+//!
+//! Plugins only see string values, through the get, put and delete callbacks of the host. A write
+//! command may only change the key at its key position, so that the node knows which cached
+//! values to drop.
+
+use crate::resp_codec::RespValue;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use bytes::Bytes;
+use libloading::Library;
+use std::collections::HashMap;
+use std::ffi::{c_char, c_void, CStr};
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::info;
+
+/// The version of the ABI, plugins built against another one are refused
+pub(crate) const ABI_VERSION: u32 = 1;
+
+pub(crate) const KIND_READ: u32 = 0;
+pub(crate) const KIND_WRITE: u32 = 1;
+
+pub(crate) const REPLY_NIL: u32 = 0;
+pub(crate) const REPLY_STATUS: u32 = 1;
+pub(crate) const REPLY_BULK: u32 = 2;
+pub(crate) const REPLY_INTEGER: u32 = 3;
+pub(crate) const REPLY_ERROR: u32 = 4;
+
+/// A command of a plugin, the list of them ends with a null name
+#[repr(C)]
+pub(crate) struct CommandSpec {
+    pub(crate) name: *const c_char,
+    pub(crate) kind: u32,
+    // as in Redis, counting the name: N for exactly N, -N for at least N
+    pub(crate) arity: i32,
+    // position of the key, counting the name, 0 for none
+    pub(crate) key_index: i32,
+}
+
+#[repr(C)]
+pub(crate) struct Buf {
+    pub(crate) data: *const u8,
+    pub(crate) len: usize,
+}
+
+/// The callbacks of the node a command runs with, each taking `ctx` first
+#[repr(C)]
+pub(crate) struct Host {
+    pub(crate) ctx: *mut c_void,
+    // 1 and the value, valid until the next callback, 0 if the key is missing, -1 on error
+    pub(crate) get: extern "C" fn(*mut c_void, *const u8, usize, *mut Buf) -> i32,
+    // 0, or -1 on error
+    pub(crate) put: extern "C" fn(*mut c_void, *const u8, usize, *const u8, usize) -> i32,
+    // 1 if the key was deleted, 0 if it is missing, -1 on error
+    pub(crate) del: extern "C" fn(*mut c_void, *const u8, usize) -> i32,
+    // kind, integer, data: the last reply set is sent
+    pub(crate) reply: extern "C" fn(*mut c_void, u32, i64, *const u8, usize),
+}
+
+pub(crate) type AbiVersionFn = unsafe extern "C" fn() -> u32;
+pub(crate) type CommandsFn = unsafe extern "C" fn() -> *const CommandSpec;
+pub(crate) type ExecuteFn = unsafe extern "C" fn(*const Host, *const c_char, *const Buf, usize);
+
+pub(crate) struct Command {
+    // lowercase
+    pub(crate) name: &'static str,
+    pub(crate) write: bool,
+    arity: i32,
+    key_index: usize,
+    execute: ExecuteFn,
+}
+
+impl Command {
+    /// Whether the command takes that many arguments, its name excluded
+    pub(crate) fn accepts(&self, args: usize) -> bool {
+        let given = args as i32 + 1;
+        if self.arity >= 0 {
+            given == self.arity
+        } else {
+            given >= -self.arity
+        }
+    }
+
+    /// The arguments it takes, its name included, as Redis counts them: negative for at least
+    pub(crate) fn arity(&self) -> i32 {
+        self.arity
+    }
+
+    /// The key among the arguments
+    pub(crate) fn key<'a>(&self, args: &'a [Vec<u8>]) -> Option<&'a Vec<u8>> {
+        self.key_index.checked_sub(1).and_then(|i| args.get(i))
+    }
+
+    pub(crate) fn key_mut<'a>(&self, args: &'a mut [Vec<u8>]) -> Option<&'a mut Vec<u8>> {
+        self.key_index.checked_sub(1).and_then(|i| args.get_mut(i))
+    }
+}
+
+/// The commands of the loaded plugins
+#[derive(Default)]
+pub(crate) struct Plugins {
+    commands: HashMap<&'static str, Command>,
+    // kept loaded for as long as their commands may run
+    libraries: Vec<Library>,
+}
+
+static PLUGINS: OnceLock<Plugins> = OnceLock::new();
+
+impl Plugins {
+    /// Load the plugins at the paths
+    pub(crate) fn load(paths: &[impl AsRef<Path>]) -> anyhow::Result<Self> {
+        let mut plugins = Self::default();
+        for path in paths {
+            let path = path.as_ref();
+            let fail = |e: &dyn std::fmt::Display| {
+                anyhow::anyhow!("could not load plugin {}: {}", path.display(), e)
+            };
+            // SAFETY: loading runs the initializers of the library, which is trusted like the node
+            let library = unsafe { Library::new(path) }.map_err(|e| fail(&e))?;
+            // SAFETY: the symbols are declared with these types in the header
+            unsafe {
+                let version = library
+                    .get::<AbiVersionFn>(b"storgata_plugin_abi_version\0")
+                    .map_err(|e| fail(&e))?;
+                if version() != ABI_VERSION {
+                    return Err(fail(&format!(
+                        "built against ABI version {}, expected {}",
+                        version(),
+                        ABI_VERSION
+                    )));
+                }
+                let commands = *library
+                    .get::<CommandsFn>(b"storgata_plugin_commands\0")
+                    .map_err(|e| fail(&e))?;
+                let execute = *library
+                    .get::<ExecuteFn>(b"storgata_plugin_execute\0")
+                    .map_err(|e| fail(&e))?;
+                plugins.register(commands, execute).map_err(|e| fail(&e))?;
+            }
+            info!("Loaded plugin {}", path.display());
+            plugins.libraries.push(library);
+        }
+        Ok(plugins)
+    }
+
+    /// Register the commands of a plugin from its entry points.
+    ///
+    /// # Safety
+    /// `commands` must return a list of valid specs ending with a null name, and `execute` must
+    /// follow the ABI; both must stay valid for the life of the process.
+    pub(crate) unsafe fn register(
+        &mut self,
+        commands: CommandsFn,
+        execute: ExecuteFn,
+    ) -> anyhow::Result<()> {
+        let mut spec = commands();
+        while !spec.is_null() && !(*spec).name.is_null() {
+            let name = CStr::from_ptr((*spec).name).to_str()?.to_ascii_lowercase();
+            if self.commands.contains_key(name.as_str()) {
+                return Err(anyhow::anyhow!("command {} is already registered", name));
+            }
+            let write = match (*spec).kind {
+                KIND_READ => false,
+                KIND_WRITE => true,
+                kind => {
+                    return Err(anyhow::anyhow!(
+                        "command {} has unknown kind {}",
+                        name,
+                        kind
+                    ))
+                }
+            };
+            let command = Command {
+                // registered for the life of the process
+                name: Box::leak(name.into_boxed_str()),
+                write,
+                arity: (*spec).arity,
+                key_index: (*spec).key_index.max(0) as usize,
+                execute,
+            };
+            self.commands.insert(command.name, command);
+            spec = spec.add(1);
+        }
+        Ok(())
+    }
+
+    /// Make the commands available to clients, once per process
+    pub(crate) fn install(self) -> anyhow::Result<()> {
+        PLUGINS
+            .set(self)
+            .map_err(|_| anyhow::anyhow!("plugins are already installed"))
+    }
+}
+
+/// The commands of the plugins installed, in name order
+pub(crate) fn commands() -> Vec<&'static Command> {
+    let mut commands: Vec<&Command> = PLUGINS
+        .get()
+        .map(|plugins| plugins.commands.values().collect())
+        .unwrap_or_default();
+    commands.sort_by_key(|command| command.name);
+    commands
+}
+
+/// The plugin command of that name, if any
+pub(crate) fn command(name: &str) -> Option<&'static Command> {
+    PLUGINS
+        .get()?
+        .commands
+        .get(name.to_ascii_lowercase().as_str())
+}
+
+/// The storage a command runs against, read-only for reads
+pub(crate) enum Storage<'a> {
+    Read(&'a BitCask),
+    Write(&'a mut BitCask),
+}
+
+struct Call<'a> {
+    storage: Storage<'a>,
+    // the only key a write may change
+    key: Option<&'a Vec<u8>>,
+    // the last value read, lent to the plugin
+    value: Vec<u8>,
+    reply: Option<RespValue>,
+    error: Option<BitCaskError>,
+}
+
+/// Run a plugin command against the storage
+pub(crate) fn execute(
+    name: &str,
+    args: &[Vec<u8>],
+    storage: Storage<'_>,
+) -> Result<RespValue, BitCaskError> {
+    let command = command(name).ok_or_else(|| {
+        // every node must load the same plugins, or their replicas diverge
+        BitCaskError::UnexpectedError(anyhow::anyhow!("unknown plugin command '{}'", name))
+    })?;
+    let mut call = Call {
+        storage,
+        key: command.key(args),
+        value: Vec::new(),
+        reply: None,
+        error: None,
+    };
+    let host = Host {
+        ctx: &mut call as *mut Call as *mut c_void,
+        get: host_get,
+        put: host_put,
+        del: host_del,
+        reply: host_reply,
+    };
+    let c_name = std::ffi::CString::new(command.name).expect("names come from C strings");
+    let bufs: Vec<Buf> = args
+        .iter()
+        .map(|arg| Buf {
+            data: arg.as_ptr(),
+            len: arg.len(),
+        })
+        .collect();
+    // SAFETY: the host and the arguments outlive the call, as the ABI requires
+    unsafe { (command.execute)(&host, c_name.as_ptr(), bufs.as_ptr(), bufs.len()) };
+    if let Some(e) = call.error {
+        return Err(e);
+    }
+    call.reply.ok_or_else(|| {
+        BitCaskError::UnexpectedError(anyhow::anyhow!(
+            "plugin command '{}' did not reply",
+            command.name
+        ))
+    })
+}
+
+/// # Safety
+/// `ctx` is the call the host was made for
+unsafe fn call<'a>(ctx: *mut c_void) -> &'a mut Call<'a> {
+    &mut *(ctx as *mut Call)
+}
+
+/// # Safety
+/// `data` points to `len` bytes, or `len` is 0
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len)
+    }
+}
+
+extern "C" fn host_get(ctx: *mut c_void, key: *const u8, key_len: usize, out: *mut Buf) -> i32 {
+    // SAFETY: the plugin passes back the context and buffers as the ABI requires
+    let (call, key) = unsafe { (call(ctx), bytes(key, key_len).to_vec()) };
+    let raw = match &call.storage {
+        Storage::Read(storage) => storage.get(&key),
+        Storage::Write(storage) => storage.get(&key),
+    };
+    let Some(raw) = raw else {
+        return 0;
+    };
+    match value::string(&raw) {
+        Ok(payload) => {
+            call.value = payload.into_owned();
+            // SAFETY: as above
+            unsafe {
+                *out = Buf {
+                    data: call.value.as_ptr(),
+                    len: call.value.len(),
+                }
+            };
+            1
+        }
+        Err(e) => {
+            call.error = Some(e);
+            -1
+        }
+    }
+}
+
+extern "C" fn host_put(
+    ctx: *mut c_void,
+    key: *const u8,
+    key_len: usize,
+    data: *const u8,
+    len: usize,
+) -> i32 {
+    // SAFETY: as in host_get
+    let (call, key, data) = unsafe { (call(ctx), bytes(key, key_len).to_vec(), bytes(data, len)) };
+    let result = match &mut call.storage {
+        Storage::Write(storage) if call.key == Some(&key) => {
+            storage.put(&key, &value::encode(ValueType::String, data))
+        }
+        Storage::Write(_) => Err(not_writable()),
+        Storage::Read(_) => Err(read_only()),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            call.error = Some(e);
+            -1
+        }
+    }
+}
+
+extern "C" fn host_del(ctx: *mut c_void, key: *const u8, key_len: usize) -> i32 {
+    // SAFETY: as in host_get
+    let (call, key) = unsafe { (call(ctx), bytes(key, key_len).to_vec()) };
+    let result = match &mut call.storage {
+        Storage::Write(storage) if call.key == Some(&key) => storage.delete(&key),
+        Storage::Write(_) => Err(not_writable()),
+        Storage::Read(_) => Err(read_only()),
+    };
+    match result {
+        Ok(()) => 1,
+        Err(BitCaskError::KeyNotFound) => 0,
+        Err(e) => {
+            call.error = Some(e);
+            -1
+        }
+    }
+}
+
+extern "C" fn host_reply(ctx: *mut c_void, kind: u32, integer: i64, data: *const u8, len: usize) {
+    // SAFETY: as in host_get
+    let (call, data) = unsafe { (call(ctx), bytes(data, len)) };
+    let text = || String::from_utf8_lossy(data).into_owned();
+    call.reply = Some(match kind {
+        REPLY_NIL => RespValue::BulkString(None),
+        REPLY_STATUS => RespValue::SimpleString(text()),
+        REPLY_BULK => RespValue::BulkString(Some(Bytes::copy_from_slice(data))),
+        REPLY_INTEGER => RespValue::Integer(integer),
+        REPLY_ERROR => RespValue::Error(text()),
+        kind => RespValue::Error(format!("ERR plugin replied with unknown kind {}", kind)),
+    });
+}
+
+fn read_only() -> BitCaskError {
+    BitCaskError::UnexpectedError(anyhow::anyhow!("a read command of a plugin cannot write"))
+}
+
+fn not_writable() -> BitCaskError {
+    BitCaskError::UnexpectedError(anyhow::anyhow!(
+        "a write command of a plugin can only change its key"
+    ))
+}
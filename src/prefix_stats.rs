@@ -0,0 +1,133 @@
+//! Command metrics per key prefix, to tell which application sharing the group sends what load.
+//! Each `--metrics-key-prefix name=prefix` counts the commands on the keys under its prefix, per
+//! command, with the microseconds they took from being read to their reply being ready, as the
+//! slow log measures them, and the bytes of their request and reply. The prefixstats section of
+//! INFO reports them in the format of the commandstats lines of Redis, one per name and command,
+//! for an exporter to turn both into Prometheus labels.
+//!
+//! A command counts under the longest prefix its first key is under, and under none without a
+//! key. Names and prefixes are few and given at start, so the labels stay bounded.
+
+use crate::resp_codec::{Protocol, RespCodec, RespValue};
+use anyhow::bail;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Prefixes a node counts under at most, each adding a series per command to the metrics
+pub(crate) const MAX_PREFIXES: usize = 32;
+
+#[derive(Default)]
+struct Counters {
+    calls: u64,
+    usec: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+pub(crate) struct PrefixStats {
+    // name and prefix, longest prefix first
+    prefixes: Vec<(String, Vec<u8>)>,
+    // per index of the prefix and command name
+    counters: Mutex<BTreeMap<(usize, &'static str), Counters>>,
+}
+
+/// A command counted under a prefix, until its reply is ready
+pub(crate) struct Tally {
+    prefix: usize,
+    command: &'static str,
+    started: Instant,
+    bytes_in: u64,
+}
+
+impl PrefixStats {
+    /// The prefixes given as `name=prefix`, the name made of letters, digits and dashes
+    pub(crate) fn new(specs: &[String]) -> anyhow::Result<Self> {
+        if specs.len() > MAX_PREFIXES {
+            bail!("At most {} --metrics-key-prefix can be given", MAX_PREFIXES);
+        }
+        let mut prefixes = Vec::new();
+        for spec in specs {
+            let Some((name, prefix)) = spec.split_once('=') else {
+                bail!("--metrics-key-prefix {} is not name=prefix", spec);
+            };
+            // the first underscore of a line separates the name from the command
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                bail!(
+                    "--metrics-key-prefix {}: the name is made of letters, digits and dashes",
+                    spec
+                );
+            }
+            if prefixes.iter().any(|(known, _)| known == name) {
+                bail!("--metrics-key-prefix {}: {} is named twice", spec, name);
+            }
+            prefixes.push((name.to_string(), prefix.as_bytes().to_vec()));
+        }
+        prefixes.sort_by_key(|(_, prefix)| Reverse(prefix.len()));
+        Ok(Self {
+            prefixes,
+            counters: Mutex::default(),
+        })
+    }
+
+    /// Start counting the command, received as `frame` at `started`, if its key is under a prefix
+    pub(crate) fn tally(
+        &self,
+        key: Option<&[u8]>,
+        command: &'static str,
+        started: Instant,
+        frame: &RespValue,
+    ) -> Option<Tally> {
+        let key = key?;
+        let prefix = self
+            .prefixes
+            .iter()
+            .position(|(_, prefix)| key.starts_with(prefix))?;
+        Some(Tally {
+            prefix,
+            command,
+            started,
+            bytes_in: RespCodec::new().encoded_len(frame) as u64,
+        })
+    }
+
+    /// Count the command once its reply is ready, encoded with `protocol`
+    pub(crate) fn record(&self, tally: Tally, reply: &RespValue, protocol: Protocol) {
+        let mut codec = RespCodec::new();
+        codec.set_protocol(protocol);
+        let bytes_out = codec.encoded_len(reply) as u64;
+        let mut counters = self.counters.lock().unwrap();
+        let counters = counters.entry((tally.prefix, tally.command)).or_default();
+        counters.calls += 1;
+        counters.usec += tally.started.elapsed().as_micros() as u64;
+        counters.bytes_in += tally.bytes_in;
+        counters.bytes_out += bytes_out;
+    }
+
+    /// The prefixstats section of INFO
+    pub(crate) fn info(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut lines: Vec<_> = counters
+            .iter()
+            .map(|((prefix, command), counters)| (&self.prefixes[*prefix].0, command, counters))
+            .collect();
+        lines.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        let mut info = String::new();
+        for (name, command, counters) in lines {
+            let _ = write!(
+                info,
+                "prefixstat_{}_{}:calls={},usec={},usec_per_call={:.2},bytes_in={},bytes_out={}\r\n",
+                name,
+                command,
+                counters.calls,
+                counters.usec,
+                counters.usec as f64 / counters.calls as f64,
+                counters.bytes_in,
+                counters.bytes_out
+            );
+        }
+        info
+    }
+}
@@ -0,0 +1,390 @@
+//! Stored procedures: WebAssembly modules uploaded with `PROC LOAD` and run with `PROC CALL`, as
+//! a sandboxed alternative to scripting.
+//!
+//! Loading, calling and deleting a procedure are replicated writes, run in the apply path of
+//! every node. So that every node computes the same result, modules run in an interpreter
+//! without floating point instructions, with a fixed amount of fuel and memory, and only see the
+//! key and the arguments of the call through the host functions of the `storgata` import module:
+//!
+//! - `arg_len(i: i32) -> i32` and `arg_read(i: i32, ptr: i32) -> i32` read an argument, -1 if
+//!   there is no such argument
+//! - `get(ptr: i32, cap: i32) -> i32` reads the string value of the key, -1 if it is missing; the
+//!   length is returned, and the value copied only if it fits in `cap` bytes
+//! - `put(ptr: i32, len: i32)` and `del()` change the key, once the call succeeds
+//! - `reply_nil()`, `reply_int(i64)`, `reply_status(ptr, len)`, `reply_bulk(ptr, len)` and
+//!   `reply_error(ptr, len)` set the reply, nil if none is set
+//!
+//! A module exports its `memory` and a `run` function taking and returning nothing. Modules are
+//! stored as values of their own type under a reserved prefix, hidden from KEYS.
+
+use crate::resp_codec::RespValue;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use wasmi::{
+    Caller, Config, Engine, Extern, ExternType, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+/// Keys holding procedures, not a prefix clients are expected to use
+pub(crate) const KEY_PREFIX: &[u8] = b"\xffproc:";
+const IMPORT_MODULE: &str = "storgata";
+/// Instructions a call may run, roughly
+const FUEL: u64 = 10_000_000;
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+const HOST_FUNCTIONS: [&str; 10] = [
+    "arg_len",
+    "arg_read",
+    "get",
+    "put",
+    "del",
+    "reply_nil",
+    "reply_int",
+    "reply_status",
+    "reply_bulk",
+    "reply_error",
+];
+
+/// A PROC subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum ProcOp {
+    // Name, Module
+    Load(String, Vec<u8>),
+    // Name, Key, Arguments
+    Call(String, Vec<u8>, Vec<Vec<u8>>),
+    // Name
+    Delete(String),
+}
+
+impl ProcOp {
+    /// The key a call runs against
+    pub(crate) fn key(&self) -> Option<&Vec<u8>> {
+        match self {
+            ProcOp::Call(_, key, _) => Some(key),
+            ProcOp::Load(_, _) | ProcOp::Delete(_) => None,
+        }
+    }
+
+    pub(crate) fn key_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            ProcOp::Call(_, key, _) => Some(key),
+            ProcOp::Load(_, _) | ProcOp::Delete(_) => None,
+        }
+    }
+}
+
+fn procedure_key(name: &str) -> Vec<u8> {
+    [KEY_PREFIX, name.as_bytes()].concat()
+}
+
+fn error(msg: String) -> BitCaskError {
+    BitCaskError::UnexpectedError(anyhow::anyhow!(msg))
+}
+
+/// The compiled modules, shared by the calls of the node
+pub(crate) struct Procedures {
+    engine: Engine,
+    linker: Linker<Call>,
+    // name to module, compiled on first call
+    modules: Mutex<HashMap<String, Arc<Module>>>,
+}
+
+impl Default for Procedures {
+    fn default() -> Self {
+        let mut config = Config::default();
+        // NaN bit patterns differ between hosts, floats could make replicas diverge
+        config.floats(false).consume_fuel(true);
+        let engine = Engine::new(&config);
+        let linker = host_functions(&engine);
+        Self {
+            engine,
+            linker,
+            modules: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Procedures {
+    /// Run a PROC subcommand against the storage
+    pub(crate) fn apply(
+        &self,
+        storage: &mut BitCask,
+        op: &ProcOp,
+    ) -> Result<RespValue, BitCaskError> {
+        match op {
+            ProcOp::Load(name, wasm) => {
+                let module = self.compile(wasm)?;
+                storage.put(&procedure_key(name), &value::encode(ValueType::Proc, wasm))?;
+                self.modules
+                    .lock()
+                    .unwrap()
+                    .insert(name.clone(), Arc::new(module));
+                Ok(RespValue::SimpleString("OK".to_string()))
+            }
+            ProcOp::Delete(name) => {
+                self.modules.lock().unwrap().remove(name);
+                let key = procedure_key(name);
+                if storage.get(&key).is_none() {
+                    return Ok(RespValue::Integer(0));
+                }
+                storage.delete(&key)?;
+                Ok(RespValue::Integer(1))
+            }
+            ProcOp::Call(name, key, args) => self.call(storage, name, key, args),
+        }
+    }
+
+    /// Compile a module, checking that it links against the host functions and exports `run`
+    fn compile(&self, wasm: &[u8]) -> Result<Module, BitCaskError> {
+        let invalid = |e: &dyn std::fmt::Display| error(format!("invalid procedure module: {}", e));
+        let module = Module::new(&self.engine, wasm).map_err(|e| invalid(&e))?;
+        if let Some(import) = module.imports().find(|import| {
+            import.module() != IMPORT_MODULE || !HOST_FUNCTIONS.contains(&import.name())
+        }) {
+            return Err(invalid(&format!(
+                "unknown import {}.{}",
+                import.module(),
+                import.name()
+            )));
+        }
+        // instantiating without starting checks the types of the imports, and runs nothing
+        let mut store = self.store(Call::new(Vec::new(), None));
+        self.linker
+            .instantiate(&mut store, &module)
+            .map_err(|e| invalid(&e))?;
+        let exported = |name: &str, memory: bool| {
+            module.exports().any(|export| {
+                export.name() == name && matches!(export.ty(), ExternType::Memory(_)) == memory
+            })
+        };
+        if !exported("memory", true) || !exported("run", false) {
+            return Err(invalid(&"it must export its memory and a run function"));
+        }
+        Ok(module)
+    }
+
+    /// A store for a call, within the limits of fuel and memory
+    fn store(&self, call: Call) -> Store<Call> {
+        let mut store = Store::new(&self.engine, call);
+        store.limiter(|call| &mut call.limits);
+        store.set_fuel(FUEL).expect("fuel is enabled");
+        store
+    }
+
+    fn call(
+        &self,
+        storage: &mut BitCask,
+        name: &str,
+        key: &[u8],
+        args: &[Vec<u8>],
+    ) -> Result<RespValue, BitCaskError> {
+        let module = {
+            let mut modules = self.modules.lock().unwrap();
+            match modules.get(name) {
+                Some(module) => module.clone(),
+                None => {
+                    let raw = storage
+                        .get(&procedure_key(name))
+                        .ok_or_else(|| error(format!("no such procedure '{}'", name)))?;
+                    let module = Arc::new(self.compile(value::expect(&raw, ValueType::Proc)?)?);
+                    modules.insert(name.to_string(), module.clone());
+                    module
+                }
+            }
+        };
+        let value = match storage.get(&key.to_vec()) {
+            Some(raw) => Some(value::string(&raw)?.into_owned()),
+            None => None,
+        };
+        let mut store = self.store(Call::new(args.to_vec(), value));
+        let run = self
+            .linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .and_then(|instance| instance.get_typed_func::<(), ()>(&store, "run"))
+            .and_then(|run| run.call(&mut store, ()));
+        if let Err(e) = run {
+            let e = match e.as_trap_code() {
+                Some(wasmi::core::TrapCode::OutOfFuel) => "ran out of fuel".to_string(),
+                _ => e.to_string(),
+            };
+            return Err(error(format!("procedure '{}' failed: {}", name, e)));
+        }
+        let call = store.into_data();
+        if call.changed {
+            let key = key.to_vec();
+            match call.value {
+                Some(value) => storage.put(&key, &value::encode(ValueType::String, &value))?,
+                None => match storage.delete(&key) {
+                    Ok(()) | Err(BitCaskError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        Ok(call.reply.unwrap_or(RespValue::BulkString(None)))
+    }
+}
+
+/// The state of a call, seen by the host functions
+struct Call {
+    args: Vec<Vec<u8>>,
+    // the value of the key, as changed by the call so far
+    value: Option<Vec<u8>>,
+    changed: bool,
+    reply: Option<RespValue>,
+    limits: StoreLimits,
+}
+
+impl Call {
+    fn new(args: Vec<Vec<u8>>, value: Option<Vec<u8>>) -> Self {
+        Self {
+            args,
+            value,
+            changed: false,
+            reply: None,
+            limits: StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build(),
+        }
+    }
+}
+
+fn trap(msg: &str) -> wasmi::Error {
+    wasmi::Error::new(msg.to_string())
+}
+
+/// Read `len` bytes of the memory of the module at `ptr`
+fn read(caller: &Caller<'_, Call>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap("no memory exported"))?;
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= MEMORY_LIMIT)
+        .ok_or_else(|| trap("invalid length"))?;
+    let mut buf = vec![0; len];
+    memory.read(caller, ptr as u32 as usize, &mut buf)?;
+    Ok(buf)
+}
+
+/// Write the bytes to the memory of the module at `ptr`
+fn write(caller: &mut Caller<'_, Call>, ptr: i32, bytes: &[u8]) -> Result<(), wasmi::Error> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap("no memory exported"))?;
+    memory.write(caller, ptr as u32 as usize, bytes)?;
+    Ok(())
+}
+
+/// Turns the bytes a module replies with into the reply
+type ToReply = fn(Vec<u8>) -> RespValue;
+
+fn host_functions(engine: &Engine) -> Linker<Call> {
+    let mut linker = Linker::new(engine);
+    let reply = |caller: &mut Caller<'_, Call>, reply: RespValue| {
+        caller.data_mut().reply = Some(reply);
+    };
+    linker
+        .func_wrap(
+            IMPORT_MODULE,
+            "arg_len",
+            |caller: Caller<'_, Call>, i: i32| {
+                usize::try_from(i)
+                    .ok()
+                    .and_then(|i| caller.data().args.get(i))
+                    .map_or(-1, |arg| arg.len() as i32)
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            IMPORT_MODULE,
+            "arg_read",
+            |mut caller: Caller<'_, Call>, i: i32, ptr: i32| -> Result<i32, wasmi::Error> {
+                let Some(arg) = usize::try_from(i)
+                    .ok()
+                    .and_then(|i| caller.data().args.get(i).cloned())
+                else {
+                    return Ok(-1);
+                };
+                write(&mut caller, ptr, &arg)?;
+                Ok(arg.len() as i32)
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            IMPORT_MODULE,
+            "get",
+            |mut caller: Caller<'_, Call>, ptr: i32, cap: i32| -> Result<i32, wasmi::Error> {
+                let Some(value) = caller.data().value.clone() else {
+                    return Ok(-1);
+                };
+                if value.len() <= cap.max(0) as usize {
+                    write(&mut caller, ptr, &value)?;
+                }
+                Ok(value.len() as i32)
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            IMPORT_MODULE,
+            "put",
+            |mut caller: Caller<'_, Call>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+                let value = read(&caller, ptr, len)?;
+                let call = caller.data_mut();
+                call.value = Some(value);
+                call.changed = true;
+                Ok(())
+            },
+        )
+        .unwrap()
+        .func_wrap(IMPORT_MODULE, "del", |mut caller: Caller<'_, Call>| {
+            let call = caller.data_mut();
+            call.value = None;
+            call.changed = true;
+        })
+        .unwrap()
+        .func_wrap(
+            IMPORT_MODULE,
+            "reply_nil",
+            move |mut caller: Caller<'_, Call>| reply(&mut caller, RespValue::BulkString(None)),
+        )
+        .unwrap()
+        .func_wrap(
+            IMPORT_MODULE,
+            "reply_int",
+            move |mut caller: Caller<'_, Call>, n: i64| reply(&mut caller, RespValue::Integer(n)),
+        )
+        .unwrap();
+    let replies: [(&str, ToReply); 3] = [
+        ("reply_status", |text| {
+            RespValue::SimpleString(String::from_utf8_lossy(&text).into_owned())
+        }),
+        ("reply_bulk", |bytes| {
+            RespValue::BulkString(Some(Bytes::from(bytes)))
+        }),
+        ("reply_error", |text| {
+            RespValue::Error(String::from_utf8_lossy(&text).into_owned())
+        }),
+    ];
+    for (name, to_reply) in replies {
+        linker
+            .func_wrap(
+                IMPORT_MODULE,
+                name,
+                move |mut caller: Caller<'_, Call>,
+                      ptr: i32,
+                      len: i32|
+                      -> Result<(), wasmi::Error> {
+                    let bytes = read(&caller, ptr, len)?;
+                    reply(&mut caller, to_reply(bytes));
+                    Ok(())
+                },
+            )
+            .unwrap();
+    }
+    linker
+}
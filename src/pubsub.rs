@@ -0,0 +1,369 @@
+use crate::database;
+use crate::resp_codec::RespValue;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Messages queued for a subscriber that does not read them fast enough are dropped
+/// beyond this limit, instead of growing the memory of the node without bound.
+const PENDING_MESSAGES_LIMIT: usize = 1024;
+
+type SubscriberId = u64;
+type Outbox = mpsc::Sender<RespValue>;
+
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<Vec<u8>, HashMap<SubscriberId, Outbox>>,
+    patterns: HashMap<Vec<u8>, HashMap<SubscriberId, Outbox>>,
+    // shard channels are apart from the channels, a name may be both
+    shard_channels: HashMap<Vec<u8>, HashMap<SubscriberId, Outbox>>,
+    // the keys read by the clients with CLIENT TRACKING on, as stored
+    tracked: HashMap<Vec<u8>, HashMap<SubscriberId, Outbox>>,
+}
+
+/// Routes published messages to the subscribers connected to this node.
+///
+/// PUBLISH is replicated through Raft and every node delivers the message to its own subscribers
+/// when applying it, so a message published on any node reaches subscribers on all of them.
+/// Being log entries, messages are also applied again when the log is replayed on restart,
+/// which may redeliver old messages to clients subscribing during the replay.
+///
+/// Shard channels (SSUBSCRIBE, SPUBLISH) are scoped to the slot of their name as in Redis 7: a
+/// message only goes through the Raft group owning the slot and reaches the subscribers on its
+/// nodes, while PUBLISH is meant to reach every node. With a single group both reach every node.
+///
+/// The clients with CLIENT TRACKING on are pushed an invalidation of the keys they read, as in
+/// Redis, once a write to them is applied on this node; the key is forgotten until read again.
+#[derive(Default)]
+pub(crate) struct Broker {
+    next_id: AtomicU64,
+    registry: Mutex<Registry>,
+}
+
+impl Broker {
+    /// Deliver a message to the subscribers of `channel` and of every pattern matching it,
+    /// returning the number of subscribers that received it.
+    pub(crate) fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let registry = self.registry.lock().unwrap();
+        let mut receivers = 0;
+        if let Some(subscribers) = registry.channels.get(channel) {
+            for outbox in subscribers.values() {
+                let msg = RespValue::Push(vec![bulk(b"message"), bulk(channel), bulk(message)]);
+                receivers += deliver(outbox, msg);
+            }
+        }
+        for (pattern, subscribers) in registry.patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for outbox in subscribers.values() {
+                let msg = RespValue::Push(vec![
+                    bulk(b"pmessage"),
+                    bulk(pattern),
+                    bulk(channel),
+                    bulk(message),
+                ]);
+                receivers += deliver(outbox, msg);
+            }
+        }
+        receivers
+    }
+
+    /// Deliver a message to the subscribers of the shard channel, returning their number
+    pub(crate) fn spublish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let registry = self.registry.lock().unwrap();
+        let mut receivers = 0;
+        if let Some(subscribers) = registry.shard_channels.get(channel) {
+            for outbox in subscribers.values() {
+                let msg = RespValue::Push(vec![bulk(b"smessage"), bulk(channel), bulk(message)]);
+                receivers += deliver(outbox, msg);
+            }
+        }
+        receivers
+    }
+
+    /// Push an invalidation of the key to the clients tracking it, then stop tracking it
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        let mut registry = self.registry.lock().unwrap();
+        let Some(trackers) = registry.tracked.remove(key) else {
+            return;
+        };
+        let key = database::split(key).map_or(key, |(_, key)| key);
+        for outbox in trackers.values() {
+            let msg = RespValue::Push(vec![bulk(b"invalidate"), RespValue::Array(vec![bulk(key)])]);
+            deliver(outbox, msg);
+        }
+    }
+
+    /// Push an invalidation of every key to the clients tracking any, as a flush applies
+    pub(crate) fn invalidate_all(&self) {
+        let mut registry = self.registry.lock().unwrap();
+        let mut trackers = HashMap::new();
+        for (_, tracking) in registry.tracked.drain() {
+            trackers.extend(tracking);
+        }
+        for outbox in trackers.values() {
+            deliver(
+                outbox,
+                RespValue::Push(vec![bulk(b"invalidate"), RespValue::Null]),
+            );
+        }
+    }
+
+    pub(crate) fn subscription(self: &Arc<Self>) -> Subscription {
+        let (outbox, inbox) = mpsc::channel(PENDING_MESSAGES_LIMIT);
+        Subscription {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            broker: self.clone(),
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+            tracking: false,
+            outbox,
+            inbox,
+        }
+    }
+}
+
+fn bulk(bytes: &[u8]) -> RespValue {
+    RespValue::BulkString(Some(Bytes::copy_from_slice(bytes)))
+}
+
+fn deliver(outbox: &Outbox, msg: RespValue) -> usize {
+    match outbox.try_send(msg) {
+        Ok(()) => 1,
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            warn!("PubSub: subscriber is too slow, dropping message");
+            0
+        }
+        // the connection is going away and will unregister itself
+        Err(mpsc::error::TrySendError::Closed(_)) => 0,
+    }
+}
+
+/// The channels and patterns a single connection is subscribed to.
+/// Dropping it removes all of them from the broker.
+pub(crate) struct Subscription {
+    id: SubscriberId,
+    broker: Arc<Broker>,
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    shard_channels: HashSet<Vec<u8>>,
+    // CLIENT TRACKING
+    tracking: bool,
+    outbox: Outbox,
+    inbox: mpsc::Receiver<RespValue>,
+}
+
+impl Subscription {
+    /// Total number of channels and patterns, as reported in (un)subscribe replies
+    pub(crate) fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Number of shard channels, as reported in s(un)subscribe replies
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shard_channels.len()
+    }
+
+    /// A connection with at least one subscription is in subscriber mode
+    pub(crate) fn is_active(&self) -> bool {
+        self.count() + self.shard_count() > 0
+    }
+
+    pub(crate) fn channels(&self) -> Vec<Vec<u8>> {
+        self.channels.iter().cloned().collect()
+    }
+
+    pub(crate) fn patterns(&self) -> Vec<Vec<u8>> {
+        self.patterns.iter().cloned().collect()
+    }
+
+    pub(crate) fn shard_channels(&self) -> Vec<Vec<u8>> {
+        self.shard_channels.iter().cloned().collect()
+    }
+
+    pub(crate) fn subscribe(&mut self, channel: &[u8]) {
+        if self.channels.insert(channel.to_vec()) {
+            let mut registry = self.broker.registry.lock().unwrap();
+            registry
+                .channels
+                .entry(channel.to_vec())
+                .or_default()
+                .insert(self.id, self.outbox.clone());
+        }
+    }
+
+    pub(crate) fn unsubscribe(&mut self, channel: &[u8]) {
+        if self.channels.remove(channel) {
+            let mut registry = self.broker.registry.lock().unwrap();
+            remove(&mut registry.channels, channel, self.id);
+        }
+    }
+
+    pub(crate) fn psubscribe(&mut self, pattern: &[u8]) {
+        if self.patterns.insert(pattern.to_vec()) {
+            let mut registry = self.broker.registry.lock().unwrap();
+            registry
+                .patterns
+                .entry(pattern.to_vec())
+                .or_default()
+                .insert(self.id, self.outbox.clone());
+        }
+    }
+
+    pub(crate) fn punsubscribe(&mut self, pattern: &[u8]) {
+        if self.patterns.remove(pattern) {
+            let mut registry = self.broker.registry.lock().unwrap();
+            remove(&mut registry.patterns, pattern, self.id);
+        }
+    }
+
+    pub(crate) fn ssubscribe(&mut self, channel: &[u8]) {
+        if self.shard_channels.insert(channel.to_vec()) {
+            let mut registry = self.broker.registry.lock().unwrap();
+            registry
+                .shard_channels
+                .entry(channel.to_vec())
+                .or_default()
+                .insert(self.id, self.outbox.clone());
+        }
+    }
+
+    pub(crate) fn sunsubscribe(&mut self, channel: &[u8]) {
+        if self.shard_channels.remove(channel) {
+            let mut registry = self.broker.registry.lock().unwrap();
+            remove(&mut registry.shard_channels, channel, self.id);
+        }
+    }
+
+    /// Whether the keys read are tracked, for the connection to push their invalidations
+    pub(crate) fn is_tracking(&self) -> bool {
+        self.tracking
+    }
+
+    /// Turn CLIENT TRACKING on or off, forgetting the keys tracked so far
+    pub(crate) fn set_tracking(&mut self, on: bool) {
+        if self.tracking && !on {
+            untrack(&mut self.broker.registry.lock().unwrap(), self.id);
+        }
+        self.tracking = on;
+    }
+
+    /// Track a key read, as stored, until a write to it invalidates it
+    pub(crate) fn track(&mut self, key: &[u8]) {
+        if !self.tracking {
+            return;
+        }
+        let mut registry = self.broker.registry.lock().unwrap();
+        registry
+            .tracked
+            .entry(key.to_vec())
+            .or_default()
+            .insert(self.id, self.outbox.clone());
+    }
+
+    /// Wait for the next message published to one of the subscriptions
+    pub(crate) async fn recv(&mut self) -> RespValue {
+        // the subscription holds a sender itself, so the channel is never closed
+        self.inbox.recv().await.expect("subscription inbox closed")
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if !self.is_active() && !self.tracking {
+            return;
+        }
+        let mut registry = self.broker.registry.lock().unwrap();
+        if self.tracking {
+            untrack(&mut registry, self.id);
+        }
+        for channel in self.channels.iter() {
+            remove(&mut registry.channels, channel, self.id);
+        }
+        for pattern in self.patterns.iter() {
+            remove(&mut registry.patterns, pattern, self.id);
+        }
+        for channel in self.shard_channels.iter() {
+            remove(&mut registry.shard_channels, channel, self.id);
+        }
+    }
+}
+
+fn remove(
+    map: &mut HashMap<Vec<u8>, HashMap<SubscriberId, Outbox>>,
+    name: &[u8],
+    id: SubscriberId,
+) {
+    if let Some(subscribers) = map.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+/// Stop tracking the keys read by a subscriber
+fn untrack(registry: &mut Registry, id: SubscriberId) {
+    registry.tracked.retain(|_, trackers| {
+        trackers.remove(&id);
+        !trackers.is_empty()
+    });
+}
+
+/// Glob-style matching as used by PSUBSCRIBE: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` escapes
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.first() {
+        None => string.is_empty(),
+        Some(b'*') => (0..=string.len()).any(|i| glob_match(&pattern[1..], &string[i..])),
+        Some(b'?') => !string.is_empty() && glob_match(&pattern[1..], &string[1..]),
+        Some(b'[') => {
+            let Some((&c, rest)) = string.split_first() else {
+                return false;
+            };
+            let mut p = &pattern[1..];
+            let negate = p.first() == Some(&b'^');
+            if negate {
+                p = &p[1..];
+            }
+            let mut matched = false;
+            loop {
+                match p {
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        p = tail;
+                        break;
+                    }
+                    [b'\\', escaped, tail @ ..] => {
+                        matched |= *escaped == c;
+                        p = tail;
+                    }
+                    [low, b'-', high, tail @ ..] if *high != b']' => {
+                        let (low, high) = if low <= high {
+                            (*low, *high)
+                        } else {
+                            (*high, *low)
+                        };
+                        matched |= low <= c && c <= high;
+                        p = tail;
+                    }
+                    [other, tail @ ..] => {
+                        matched |= *other == c;
+                        p = tail;
+                    }
+                }
+            }
+            matched != negate && glob_match(p, rest)
+        }
+        Some(b'\\') if pattern.len() > 1 => {
+            string.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &string[1..])
+        }
+        Some(&literal) => {
+            string.first() == Some(&literal) && glob_match(&pattern[1..], &string[1..])
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! The committed Raft log, read by external consumers such as change data capture, audit
+//! pipelines or someone debugging a node. raft-lite keeps its log to itself, so the apply path
+//! keeps the latest committed entries, as they were replicated, up to `--raft-log-kept`. They
+//! are numbered like `raft_entries_applied`, from the start of the node, and `RAFT LOG` reads them
+//! with their command as JSON. Read replicas tail them as replicated with `RAFT TAIL`.
+
+use crate::resp_codec::RespValue;
+use crate::sync_layer::RequestId;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Entries in a single reply, a consumer reads on from the index following the last one
+pub(crate) const MAX_ENTRIES: u64 = 1000;
+
+/// A RAFT subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum RaftOp {
+    // First and last index, up to the latest entry without a last one
+    Log(u64, Option<u64>),
+    // First index
+    Tail(u64),
+}
+
+/// Reads the committed entries of the log by index
+pub(crate) trait LogReader {
+    /// The first and last index that can be read, None before any entry is kept
+    fn bounds(&self) -> Option<(u64, u64)>;
+
+    /// The entries from `from` to `to`, both included and as many as are kept, decoded as they
+    /// were replicated, or the error reply if `from` is no longer kept
+    fn read<M: DeserializeOwned>(&self, from: u64, to: u64) -> Result<Vec<(u64, M)>, String>;
+}
+
+struct Kept {
+    // oldest first
+    entries: VecDeque<Vec<u8>>,
+    // index of the oldest entry, or of the next one without any
+    first: u64,
+    bytes: usize,
+    // the entry numbered 1, kept for good as it tells the log apart from a later one
+    origin: Option<Vec<u8>>,
+}
+
+pub(crate) struct CommittedLog {
+    max_bytes: usize,
+    kept: Mutex<Kept>,
+}
+
+impl CommittedLog {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            kept: Mutex::new(Kept {
+                entries: VecDeque::new(),
+                first: 1,
+                bytes: 0,
+                origin: None,
+            }),
+        }
+    }
+
+    /// Keep the entry committed next, the oldest ones making room past the size of the log
+    pub(crate) fn push(&self, raw_payload: Vec<u8>) {
+        let mut kept = self.kept.lock().unwrap();
+        if kept.origin.is_none() {
+            kept.origin = Some(raw_payload.clone());
+        }
+        kept.bytes += raw_payload.len();
+        kept.entries.push_back(raw_payload);
+        while kept.bytes > self.max_bytes {
+            let Some(oldest) = kept.entries.pop_front() else {
+                break;
+            };
+            kept.bytes -= oldest.len();
+            kept.first += 1;
+        }
+    }
+
+    /// Every entry kept, oldest first, as they were replicated
+    pub(crate) fn kept(&self) -> Vec<Vec<u8>> {
+        self.kept.lock().unwrap().entries.iter().cloned().collect()
+    }
+
+    /// The entries from `from` as they were replicated, as many as a reply holds, or the error
+    /// reply if `from` is no longer kept
+    fn read_raw(&self, from: u64, to: u64) -> Result<Vec<(u64, Vec<u8>)>, String> {
+        let kept = self.kept.lock().unwrap();
+        if from < kept.first {
+            return Err(format!(
+                "ERR entry {} is no longer kept, the oldest is {}",
+                from, kept.first
+            ));
+        }
+        let skip = (from - kept.first) as usize;
+        let take = to.saturating_sub(from).saturating_add(1).min(MAX_ENTRIES) as usize;
+        Ok(kept
+            .entries
+            .iter()
+            .skip(skip)
+            .take(take)
+            .cloned()
+            .zip(from..)
+            .map(|(raw_payload, index)| (index, raw_payload))
+            .collect())
+    }
+}
+
+impl LogReader for CommittedLog {
+    fn bounds(&self) -> Option<(u64, u64)> {
+        let kept = self.kept.lock().unwrap();
+        let len = kept.entries.len() as u64;
+        (len > 0).then(|| (kept.first, kept.first + len - 1))
+    }
+
+    fn read<M: DeserializeOwned>(&self, from: u64, to: u64) -> Result<Vec<(u64, M)>, String> {
+        self.read_raw(from, to)?
+            .into_iter()
+            .map(|(index, raw_payload)| {
+                bincode::deserialize(&raw_payload)
+                    .map(|message| (index, message))
+                    .map_err(|e| format!("ERR could not decode entry {}: {}", index, e))
+            })
+            .collect()
+    }
+}
+
+/// The reply to RAFT LOG: the index of each entry and its command as JSON, oldest first, nil for
+/// the commands that are `hidden`
+pub(crate) fn log<M: DeserializeOwned + Serialize>(
+    reader: &impl LogReader,
+    from: u64,
+    to: Option<u64>,
+    hidden: impl Fn(&M) -> bool,
+) -> RespValue {
+    if from == 0 || to.is_some_and(|to| to < from) {
+        return RespValue::Error("ERR invalid range of indexes".to_string());
+    }
+    let to = match (to, reader.bounds()) {
+        (Some(to), _) => to,
+        (None, Some((_, last))) => last,
+        (None, None) => from,
+    };
+    match reader.read::<M>(from, to) {
+        Ok(entries) => RespValue::Array(
+            entries
+                .into_iter()
+                .map(|(index, message)| {
+                    let json = (!hidden(&message))
+                        .then(|| serde_json::to_string(&message).unwrap_or_default());
+                    RespValue::Array(vec![
+                        RespValue::Integer(index as i64),
+                        RespValue::BulkString(json.map(Into::into)),
+                    ])
+                })
+                .collect(),
+        ),
+        Err(e) => RespValue::Error(e),
+    }
+}
+
+/// The reply to RAFT TAIL: the request id of the first entry of the log, nil before any, then the
+/// index of each entry from `from` on and its encoding as replicated, none hidden as a replica
+/// applies them all
+pub(crate) fn tail<M: DeserializeOwned>(
+    log: &CommittedLog,
+    from: u64,
+    request_id: impl Fn(&M) -> RequestId,
+) -> RespValue {
+    if from == 0 {
+        return RespValue::Error("ERR invalid range of indexes".to_string());
+    }
+    let origin = log.kept.lock().unwrap().origin.clone();
+    let origin = origin
+        .and_then(|raw_payload| bincode::deserialize::<M>(&raw_payload).ok())
+        .map(|message| Bytes::copy_from_slice(&request_id(&message)));
+    match log.read_raw(from, u64::MAX) {
+        Ok(entries) => RespValue::Array(vec![
+            RespValue::BulkString(origin),
+            RespValue::Array(
+                entries
+                    .into_iter()
+                    .map(|(index, raw_payload)| {
+                        RespValue::Array(vec![
+                            RespValue::Integer(index as i64),
+                            RespValue::BulkString(Some(raw_payload.into())),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]),
+        Err(e) => RespValue::Error(e),
+    }
+}
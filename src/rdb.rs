@@ -0,0 +1,455 @@
+//! One-shot import of a Redis RDB file on startup, `--import-rdb`, to move a dataset over from
+//! Redis. The string keys of the file are proposed as SETs with their expiry, so every node of
+//! the group applies them like any other write. Keys of the other types are counted and skipped,
+//! and so are the keys that expired already. The node shuts down once every key is applied.
+//!
+//! The file is parsed as it is read, on a blocking thread, and never held in memory whole. RDB
+//! versions up to 12 are read, checked against their checksum first unless Redis wrote it as zero.
+//! Module data and streams cannot be skipped without parsing them, the import fails at the first
+//! one, with the keys before it set.
+
+use crate::cmd::{Expiry, InnerCmd, NodeContext, PutOptionSerde};
+use crate::config::Config;
+use crate::database;
+use crate::sync_layer::SyncRequest;
+use crate::value;
+use anyhow::{anyhow, bail, Context};
+use crc::{Crc, CRC_64_REDIS};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, warn, Span};
+use uuid::Uuid;
+
+static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+const MAGIC: &[u8; 5] = b"REDIS";
+const MAX_VERSION: u32 = 12;
+
+/// How many SETs are proposed at once
+const WINDOW: usize = 64;
+
+/// How many times a SET left unanswered is proposed, the group may still be electing a leader
+const ATTEMPTS: usize = 5;
+
+// opcodes
+const OPCODE_SLOT_INFO: u8 = 0xf4;
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xf6;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+// value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+// string encodings, after a length of 0b11
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// A string key of the file
+struct Entry {
+    db: u32,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    // unix time in milliseconds
+    deadline: Option<u64>,
+}
+
+/// What an import did with the keys of the file
+#[derive(Debug, Default)]
+pub(crate) struct Imported {
+    pub(crate) strings: u64,
+    pub(crate) expired: u64,
+    // keys of another type than string
+    pub(crate) skipped: u64,
+}
+
+/// Set the string keys of the RDB file, each once applied by the group
+pub(crate) async fn import(
+    path: PathBuf,
+    sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+    context: NodeContext,
+) -> anyhow::Result<Imported> {
+    info!("Importing {}", path.display());
+    let (entries_tx, mut entries) = mpsc::channel(WINDOW);
+    let databases = context.databases;
+    let parser = tokio::task::spawn_blocking(move || {
+        parse(&path, databases, |entry| {
+            entries_tx
+                .blocking_send(entry)
+                .map_err(|_| anyhow!("the import stopped"))
+        })
+        .with_context(|| format!("Could not read {}", path.display()))
+    });
+    let mut imported = Imported::default();
+    let mut in_flight = FuturesUnordered::new();
+    while let Some(entry) = entries.recv().await {
+        if entry
+            .deadline
+            .is_some_and(|deadline| deadline <= value::now())
+        {
+            imported.expired += 1;
+            continue;
+        }
+        if in_flight.len() >= WINDOW {
+            in_flight.next().await.unwrap_or(Ok(()))?;
+        }
+        in_flight.push(set(sync_request_tx.clone(), context.config.clone(), entry));
+        imported.strings += 1;
+    }
+    while let Some(result) = in_flight.next().await {
+        result?;
+    }
+    imported.skipped = parser.await??;
+    Ok(imported)
+}
+
+/// Propose a SET of the key, again if it is left unanswered
+async fn set(
+    sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
+    config: Arc<Config>,
+    entry: Entry,
+) -> anyhow::Result<()> {
+    // the same id for every attempt, as a client retrying a SET with ID
+    let id = *Uuid::new_v4().as_bytes();
+    let key = database::key(entry.db, &entry.key);
+    let option = entry.deadline.map(|deadline| PutOptionSerde {
+        nx: false,
+        xx: false,
+        expiry: Some(Expiry::At(deadline)),
+        now: value::now(),
+        compression: None,
+    });
+    // compressed as the SETs of the clients are
+    let put = InnerCmd::Put(id, key.clone(), entry.value, option).compress(&config);
+    for attempt in 1..=ATTEMPTS {
+        let deadline = Instant::now() + config.write_timeout();
+        let inner_cmd = put.clone();
+        let (tx, rx) = oneshot::channel();
+        sync_request_tx
+            .send(SyncRequest::new(
+                inner_cmd,
+                tx,
+                deadline,
+                Span::none(),
+                None,
+            ))
+            .await
+            .map_err(|_| anyhow!("the node stopped replicating writes"))?;
+        match timeout_at(deadline, rx).await {
+            Ok(Ok(Ok(_))) => return Ok(()),
+            Ok(Ok(Err(e))) => bail!("Could not set {:?}: {}", key, e),
+            _ => warn!("Import: no answer for {:?}, attempt {}", key, attempt),
+        }
+    }
+    bail!("Could not set {:?} in {} attempts", key, ATTEMPTS)
+}
+
+/// The RDB version of the file, from its header
+fn version(header: &[u8; 9]) -> anyhow::Result<u32> {
+    let version = match header.strip_prefix(MAGIC) {
+        Some(version) => std::str::from_utf8(version)?.parse::<u32>()?,
+        None => bail!("not an RDB file"),
+    };
+    if version > MAX_VERSION {
+        bail!(
+            "RDB version {} is not supported, {} at most",
+            version,
+            MAX_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// Check the file against the checksum it ends with, before any of its keys is set
+fn verify(path: &Path) -> anyhow::Result<()> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = [0; 9];
+    file.read_exact(&mut header)?;
+    // files from before version 5 end with EOF
+    if version(&header)? < 5 {
+        return Ok(());
+    }
+    let len = file.get_ref().metadata()?.len();
+    let body = len
+        .checked_sub((header.len() + 8) as u64)
+        .ok_or_else(|| anyhow!("the file is truncated"))?;
+    let mut digest = CRC64.digest();
+    digest.update(&header);
+    let mut body = (&mut file).take(body);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = body.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    let mut checksum = [0; 8];
+    file.read_exact(&mut checksum)?;
+    let checksum = u64::from_le_bytes(checksum);
+    if checksum != 0 && checksum != digest.finalize() {
+        bail!("the checksum does not match, the file is corrupted");
+    }
+    Ok(())
+}
+
+/// Hand each string key of the file to `each`, returning how many keys of other types there were
+fn parse(
+    path: &Path,
+    databases: u32,
+    mut each: impl FnMut(Entry) -> anyhow::Result<()>,
+) -> anyhow::Result<u64> {
+    verify(path)?;
+    let mut reader = Reader {
+        inner: BufReader::new(File::open(path)?),
+    };
+    reader.bytes::<9>()?;
+    let (mut db, mut deadline, mut skipped) = (0, None, 0);
+    loop {
+        match reader.byte()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => {
+                db = u32::try_from(reader.len()?)?;
+                if db >= databases {
+                    bail!(
+                        "the file has keys in database {}, there are {}",
+                        db,
+                        databases
+                    );
+                }
+            }
+            OPCODE_RESIZEDB => {
+                reader.len()?;
+                reader.len()?;
+            }
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OPCODE_EXPIRETIME => {
+                let seconds = u32::from_le_bytes(reader.bytes()?);
+                deadline = Some(seconds as u64 * 1000);
+            }
+            OPCODE_EXPIRETIME_MS => deadline = Some(u64::from_le_bytes(reader.bytes()?)),
+            // eviction hints, the node does not evict by them
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_IDLE => {
+                reader.len()?;
+            }
+            OPCODE_FUNCTION2 => {
+                reader.string()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.len()?;
+                }
+            }
+            OPCODE_MODULE_AUX | OPCODE_FUNCTION_PRE_GA => bail!("module data is not supported"),
+            TYPE_STRING => {
+                let key = reader.string()?;
+                let value = reader.string()?;
+                each(Entry {
+                    db,
+                    key,
+                    value,
+                    deadline: deadline.take(),
+                })?;
+            }
+            value_type => {
+                reader.string()?;
+                reader.skip_value(value_type)?;
+                deadline = None;
+                skipped += 1;
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+/// Reads the fields of the file
+struct Reader<R> {
+    inner: R,
+}
+
+/// A length, or the encoding of a string stored in a special format
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.inner.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn exact(&mut self, len: u64) -> anyhow::Result<Vec<u8>> {
+        // read in pieces, so a corrupted length fails at the end of the file, not on allocation
+        let mut bytes = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len {
+            bail!("the file is truncated");
+        }
+        Ok(bytes)
+    }
+
+    fn length(&mut self) -> anyhow::Result<Length> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0b00 => Length::Len((first & 0x3f) as u64),
+            0b01 => Length::Len(((first & 0x3f) as u64) << 8 | self.byte()? as u64),
+            0b10 if first == 0x80 => Length::Len(u32::from_be_bytes(self.bytes()?) as u64),
+            0b10 if first == 0x81 => Length::Len(u64::from_be_bytes(self.bytes()?)),
+            0b11 => Length::Encoded(first & 0x3f),
+            _ => bail!("invalid length {:#x}", first),
+        })
+    }
+
+    fn len(&mut self) -> anyhow::Result<u64> {
+        match self.length()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => bail!("a length is encoded as a string"),
+        }
+    }
+
+    fn string(&mut self) -> anyhow::Result<Vec<u8>> {
+        match self.length()? {
+            Length::Len(len) => self.exact(len),
+            // integers are stored as their digits
+            Length::Encoded(ENC_INT8) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => {
+                Ok(i16::from_le_bytes(self.bytes()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_INT32) => {
+                Ok(i32::from_le_bytes(self.bytes()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.len()?;
+                let len = self.len()?;
+                let compressed = self.exact(compressed_len)?;
+                lzf_decompress(&compressed, len as usize)
+            }
+            Length::Encoded(encoding) => bail!("invalid string encoding {}", encoding),
+        }
+    }
+
+    /// Read past a value of a type other than string
+    fn skip_value(&mut self, value_type: u8) -> anyhow::Result<()> {
+        match value_type {
+            TYPE_LIST | TYPE_SET | TYPE_LIST_QUICKLIST => {
+                for _ in 0..self.len()? {
+                    self.string()?;
+                }
+            }
+            TYPE_ZSET => {
+                for _ in 0..self.len()? {
+                    self.string()?;
+                    // a score as its digits, after their count, or 253 to 255 for NaN and
+                    // the infinities
+                    let len = self.byte()?;
+                    if len < 253 {
+                        self.exact(len as u64)?;
+                    }
+                }
+            }
+            TYPE_HASH => {
+                for _ in 0..self.len()? {
+                    self.string()?;
+                    self.string()?;
+                }
+            }
+            TYPE_ZSET_2 => {
+                for _ in 0..self.len()? {
+                    self.string()?;
+                    self.bytes::<8>()?;
+                }
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                for _ in 0..self.len()? {
+                    self.len()?;
+                    self.string()?;
+                }
+            }
+            // types stored in a single string
+            TYPE_HASH_ZIPMAP | TYPE_LIST_ZIPLIST | TYPE_SET_INTSET | TYPE_ZSET_ZIPLIST
+            | TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK | TYPE_ZSET_LISTPACK | TYPE_SET_LISTPACK => {
+                self.string()?;
+            }
+            value_type => bail!("value type {} is not supported", value_type),
+        }
+        Ok(())
+    }
+}
+
+/// Decompress LZF, as Redis compresses the strings of an RDB file
+fn lzf_decompress(input: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let invalid = || anyhow!("invalid LZF string");
+    // the length is read from the file: a corrupted one must not abort on allocation, and LZF
+    // rarely expands a string more than a few times
+    let mut output = Vec::with_capacity(len.min(input.len().saturating_mul(4)));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 1 << 5 {
+            // a run of ctrl + 1 literal bytes
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(invalid)?;
+            output.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            // a back reference to bytes already output
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(invalid)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(invalid)? as usize + 1;
+            i += 1;
+            let start = output.len().checked_sub(offset).ok_or_else(invalid)?;
+            // byte by byte, the reference may overlap what it outputs
+            for j in start..start + run + 2 {
+                output.push(output[j]);
+            }
+        }
+    }
+    if output.len() != len {
+        return Err(invalid());
+    }
+    Ok(output)
+}
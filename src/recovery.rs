@@ -0,0 +1,125 @@
+//! DEBUG RECOVERY-BENCH: how long a restart would take on the data the node holds now, phase by
+//! phase, to plan for failovers and restarts. Each phase runs as it does on startup, on a copy
+//! made next to the data directory and removed afterwards:
+//!
+//! - snapshot load: laying the latest backup of `--backup-dir`, with those it builds on, into a
+//!   data directory, as `--restore-from` does for a new node
+//! - keydir rebuild: opening the storage, which reads every data file to index the keys, on hard
+//!   links to the data files
+//! - raft log replay: raft-lite keeps its log in memory only, so a restarted node is sent the
+//!   log of the group from its first entry and decodes each one, skipping those it applied
+//!   before. The entries kept for RAFT LOG are decoded, and the time scaled to the entries the
+//!   node applied since it started, which the log of the group holds at least.
+
+use crate::backup::{self, Store};
+use crate::cmd::{InnerCmd, NodeContext};
+use crate::keyspace::{self, Snapshot};
+use crate::sync_layer;
+use anyhow::bail;
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Extension of the directory the copies are made in, next to the data directory
+const SCRATCH_EXT: &str = "recovery-bench";
+
+/// Whether a bench is running, a second one would share its copies
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Run the phases of a startup, returning the report, a `name:value` line per measure
+pub(crate) fn bench(context: &NodeContext) -> anyhow::Result<String> {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        bail!("a recovery bench is already running");
+    }
+    let scratch = context.data_dir.with_extension(SCRATCH_EXT);
+    let report = remove(&scratch).and_then(|()| run(context, &scratch));
+    if let Err(e) = remove(&scratch) {
+        warn!(
+            "Recovery bench: could not remove {}: {}",
+            scratch.display(),
+            e
+        );
+    }
+    RUNNING.store(false, Ordering::Release);
+    report
+}
+
+fn remove(dir: &Path) -> anyhow::Result<()> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+fn run(context: &NodeContext, scratch: &Path) -> anyhow::Result<String> {
+    let mut report = String::new();
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+    let latest = context.backups.last_save();
+    let restored = scratch.join("snapshot");
+    let snapshot_load = if latest > 0 {
+        let started = Instant::now();
+        backup::restore(&Store::Dir(context.backups.dir().to_path_buf()), &restored)?;
+        started.elapsed()
+    } else {
+        Duration::ZERO
+    };
+    let restored_bytes: u64 = match latest {
+        0 => 0,
+        _ => keyspace::data_files(&restored)?
+            .iter()
+            .map(|(_, len)| len)
+            .sum(),
+    };
+    writeln!(report, "snapshot_load_backup:{}", latest)?;
+    writeln!(report, "snapshot_load_bytes:{}", restored_bytes)?;
+    writeln!(report, "snapshot_load_ms:{:.3}", ms(snapshot_load))?;
+
+    let snapshot = Snapshot::take(&context.data_dir)?;
+    let linked = snapshot.link(&scratch.join("keydir"))?;
+    linked.detach()?;
+    let started = Instant::now();
+    let storage = BitCask::new(scratch.join("keydir"))?;
+    let keydir_rebuild = started.elapsed();
+    let (files, bytes) = snapshot.size();
+    writeln!(report, "keydir_files:{}", files)?;
+    writeln!(report, "keydir_bytes:{}", bytes)?;
+    writeln!(report, "keydir_keys:{}", storage.size())?;
+    writeln!(report, "keydir_rebuild_ms:{:.3}", ms(keydir_rebuild))?;
+    drop(storage);
+
+    let entries = context.raft_log.kept();
+    let kept = entries.len() as u64;
+    let kept_bytes: usize = entries.iter().map(Vec::len).sum();
+    let started = Instant::now();
+    let mut commands = 0;
+    for raw_payload in entries {
+        commands += sync_layer::messages::<InnerCmd>(raw_payload)?.len();
+    }
+    let decode = started.elapsed();
+    let applied = context.raft_stats.applied_entries();
+    let replay = match kept {
+        0 => Duration::ZERO,
+        kept => decode.mul_f64(applied.max(kept) as f64 / kept as f64),
+    };
+    writeln!(report, "raft_log_kept_entries:{}", kept)?;
+    writeln!(report, "raft_log_kept_bytes:{}", kept_bytes)?;
+    writeln!(report, "raft_log_kept_commands:{}", commands)?;
+    writeln!(report, "raft_log_decode_ms:{:.3}", ms(decode))?;
+    writeln!(report, "raft_log_applied_entries:{}", applied)?;
+    writeln!(report, "raft_log_replay_estimate_ms:{:.3}", ms(replay))?;
+
+    // a node restarting on its own data files, and a new one seeded from the backup
+    let restart = keydir_rebuild + replay;
+    writeln!(report, "restart_estimate_ms:{:.3}", ms(restart))?;
+    writeln!(
+        report,
+        "seed_estimate_ms:{:.3}",
+        ms(snapshot_load + restart)
+    )?;
+    info!("Recovery bench: {}", report.trim_end().replace('\n', " "));
+    Ok(report)
+}
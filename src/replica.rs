@@ -0,0 +1,177 @@
+//! Read replicas: nodes outside the Raft group that serve reads, for more read capacity without
+//! more voters. raft-lite has a fixed set of peers, so a node started with `--replica-of` takes no
+//! part in it: it tails the committed log of a member with `RAFT TAIL`, as a consensus whose
+//! entries arrive committed already, and applies them through the same sync layer as a member
+//! does. It votes in no election and counts in no quorum, so its reads lag behind the group, and
+//! it refuses writes.
+//!
+//! A replica starts from the keyspace the group started from, laid with `--restore-from` or
+//! `storgata-cli cluster init`, and follows the log from its first entry, which the member must
+//! still keep within `--raft-log-kept`. Entries are numbered alike on every member, so the
+//! replica moves on to the next member given when one fails. When the group starts a new log, as
+//! after all of its nodes restarted, the replica stops applying until it restarts too.
+
+use crate::admin;
+use crate::raft_log::MAX_ENTRIES;
+use crate::resp_codec::{RespCodec, RespValue};
+use crate::sync_layer::{Channels, Consensus, Role};
+use anyhow::{anyhow, bail};
+use futures::SinkExt;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::Framed;
+use tracing::{info, warn};
+
+/// Pause before asking again once the member has no more entries
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Pause before trying the next member once one failed
+const RETRY_PAUSE: Duration = Duration::from_secs(1);
+/// A member not replying within this time has failed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The consensus of a read replica, the entries committed by the group as a member tells them
+pub(crate) struct Tail {
+    // kv addresses of the members, tried in turn
+    members: Vec<String>,
+    credentials: Option<(String, String)>,
+}
+
+/// Where the replica is in the log of the group
+struct Position {
+    // index of the next entry to apply
+    next: u64,
+    // request id of the first entry, once applied
+    origin: Option<Vec<u8>>,
+}
+
+impl Tail {
+    pub(crate) fn new(members: &[String], credentials: Option<(String, String)>) -> Self {
+        Self {
+            members: members.to_vec(),
+            credentials,
+        }
+    }
+}
+
+impl Consensus for Tail {
+    fn start(&mut self) -> Channels {
+        let (btx, mut brx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let (mtx, mrx) = mpsc::unbounded_channel();
+        // writes are refused before they reach the sync layer, what is proposed all the same is
+        // never committed
+        tokio::spawn(async move {
+            while brx.recv().await.is_some() {
+                warn!("Replica: dropping a proposal, a replica does not write");
+            }
+        });
+        let members = self.members.clone();
+        let credentials = self.credentials.clone();
+        tokio::spawn(async move {
+            let mut position = Position {
+                next: 1,
+                origin: None,
+            };
+            for member in members.iter().cycle() {
+                match follow(member, credentials.as_ref(), &mut position, &mtx).await {
+                    // dropping the sender stops the sync layer from applying
+                    Ok(()) => return,
+                    Err(e) => {
+                        warn!("Replica: stopped tailing {}: {}", member, e);
+                        sleep(RETRY_PAUSE).await;
+                    }
+                }
+            }
+        });
+        // a replica follows the group, it never leads it
+        let (rtx, roles) = mpsc::unbounded_channel();
+        let _ = rtx.send(Role::Follower);
+        Channels {
+            proposals: btx,
+            committed: mrx,
+            roles,
+        }
+    }
+}
+
+/// Hand the entries the member committed to the sync layer, until the replica can no longer
+/// follow the log of the group, or an error to try the next member
+async fn follow(
+    member: &str,
+    credentials: Option<&(String, String)>,
+    position: &mut Position,
+    committed: &UnboundedSender<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let stream = timeout(REQUEST_TIMEOUT, TcpStream::connect(member)).await??;
+    stream.set_nodelay(true)?;
+    let mut framed = Framed::new(stream, RespCodec::new());
+    if let Some((user, password)) = credentials {
+        let auth = [b"AUTH", user.as_bytes(), password.as_bytes()];
+        framed.send(admin::command(&auth)).await?;
+        if let RespValue::Error(e) = admin::next(&mut framed).await? {
+            bail!("{}", e);
+        }
+    }
+    info!("Replica: tailing {} from entry {}", member, position.next);
+    loop {
+        let from = position.next.to_string();
+        framed
+            .send(admin::command(&[b"RAFT", b"TAIL", from.as_bytes()]))
+            .await?;
+        let reply = timeout(REQUEST_TIMEOUT, admin::next(&mut framed))
+            .await
+            .map_err(|_| anyhow!("no reply after {:?}", REQUEST_TIMEOUT))??;
+        let (origin, entries) = match reply {
+            RespValue::Array(mut reply) if reply.len() == 2 => match (reply.pop(), reply.pop()) {
+                (Some(RespValue::Array(entries)), Some(RespValue::BulkString(origin))) => {
+                    (origin, entries)
+                }
+                _ => bail!("unexpected reply to RAFT TAIL"),
+            },
+            RespValue::Error(e) => bail!("{}", e),
+            _ => bail!("unexpected reply to RAFT TAIL"),
+        };
+        // a member that restarted has no entry until it caught up again
+        let Some(origin) = origin else {
+            sleep(POLL_INTERVAL).await;
+            continue;
+        };
+        if position
+            .origin
+            .as_deref()
+            .is_some_and(|mine| mine != &origin[..])
+        {
+            warn!("Replica: the group started a new log, restart the replica to follow it");
+            return Ok(());
+        }
+        let full = entries.len() as u64 >= MAX_ENTRIES;
+        for entry in entries {
+            let (index, raw_payload) = entry_of(entry)?;
+            if index != position.next {
+                bail!("entry {} came instead of {}", index, position.next);
+            }
+            if index == 1 {
+                position.origin = Some(origin.to_vec());
+            }
+            if committed.send(raw_payload).is_err() {
+                // the node is shutting down
+                return Ok(());
+            }
+            position.next += 1;
+        }
+        if !full {
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// The index of an entry in the reply to RAFT TAIL, and its encoding
+fn entry_of(entry: RespValue) -> anyhow::Result<(u64, Vec<u8>)> {
+    if let RespValue::Array(entry) = entry {
+        if let [RespValue::Integer(index), RespValue::BulkString(Some(raw_payload))] = &entry[..] {
+            return Ok((*index as u64, raw_payload.to_vec()));
+        }
+    }
+    bail!("unexpected entry in the reply to RAFT TAIL")
+}
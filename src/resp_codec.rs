@@ -1,21 +1,73 @@
 use crate::connection::ConnectionError;
-use async_recursion::async_recursion;
-use std::fmt::Debug;
-use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt::{Debug, Write};
+use tokio_util::codec::{Decoder, Encoder};
 use tracing::debug;
 
+/// Longest inline command, so that a line never ended cannot fill the memory
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// What a client may send, past which the connection is closed with a protocol error rather
+/// than buffering, or recursing into, whatever the headers announce
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    pub(crate) max_bulk_len: usize,
+    // elements of an array, set or push, entries of a map
+    pub(crate) max_array_len: usize,
+    pub(crate) max_nesting: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_nesting: 32,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) enum RespValue {
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(Option<Vec<u8>>),
+    // sliced from the frame read from the socket, so decoding does not copy payloads
+    BulkString(Option<Bytes>),
     Array(Vec<RespValue>),
+    NullArray,
+    // RESP3 types, downgraded to their RESP2 equivalent when the connection speaks RESP2
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    Map(Vec<(RespValue, RespValue)>),
+    Push(Vec<RespValue>),
+    // metadata about the reply that follows it, dropped when the connection speaks RESP2
+    Attribute(Vec<(RespValue, RespValue)>, Box<RespValue>),
 }
 
-pub(crate) fn convert_bulk_string_to_string(bulk_string: Option<Vec<u8>>) -> String {
+/// Protocol negotiated with HELLO, RESP2 until the client asks for RESP3
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// Format a double the way Redis replies with it
+pub(crate) fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+pub(crate) fn convert_bulk_string_to_string(bulk_string: Option<Bytes>) -> String {
     match bulk_string {
-        Some(bytes) => String::from_utf8(bytes).unwrap_or_else(|_| String::new()),
+        Some(bytes) => String::from_utf8(bytes.into()).unwrap_or_else(|_| String::new()),
         None => String::new(),
     }
 }
@@ -31,148 +83,450 @@ impl Debug for RespValue {
                 write!(f, "BulkString({})", bs)
             }
             RespValue::Array(array) => write!(f, "Array({:?})", array),
+            RespValue::NullArray => write!(f, "NullArray"),
+            RespValue::Null => write!(f, "Null"),
+            RespValue::Boolean(b) => write!(f, "Boolean({})", b),
+            RespValue::Double(d) => write!(f, "Double({})", d),
+            RespValue::BigNumber(n) => write!(f, "BigNumber({})", n),
+            RespValue::Map(map) => write!(f, "Map({:?})", map),
+            RespValue::Push(push) => write!(f, "Push({:?})", push),
+            RespValue::Attribute(attributes, msg) => {
+                write!(f, "Attribute({:?}, {:?})", attributes, msg)
+            }
         }
     }
 }
 
+/// Frames RESP values over a connection, see `tokio_util::codec::Framed`
 #[derive(Clone, Debug)]
-pub(crate) struct RespCodec;
+pub(crate) struct RespCodec {
+    protocol: Protocol,
+    limits: Limits,
+}
 
-impl RespCodec {
-    pub(crate) fn new() -> Self {
-        Self {}
-    }
-
-    #[async_recursion]
-    pub(crate) async fn decode<T: AsyncBufRead + Unpin + Send>(
-        &mut self,
-        input: &mut T,
-    ) -> Result<RespValue, ConnectionError> {
-        // Read the first byte to determine the type of the RESP value
-        let mut buf = [0u8; 1];
-        input.read_exact(&mut buf).await?;
-        let value = match buf[0] {
-            b'+' => {
-                // Simple String
-                let mut buf = Vec::new();
-                input.read_until(b'\n', &mut buf).await?;
-                let len = buf.len();
-                if len < 2 || buf[len - 2] != b'\r' {
-                    return Err(ConnectionError::IncompleteData);
-                }
-                RespValue::SimpleString(String::from_utf8(buf[..len - 2].to_vec())?)
-            }
-            b'-' => {
-                // Error
-                let mut buf = Vec::new();
-                input.read_until(b'\n', &mut buf).await?;
-                let len = buf.len();
-                if len < 2 || buf[len - 2] != b'\r' {
-                    return Err(ConnectionError::IncompleteData);
-                }
-                RespValue::Error(String::from_utf8(buf[..len - 2].to_vec())?)
-            }
-            b':' => {
-                // Integer
-                let mut buf = Vec::new();
-                input.read_until(b'\n', &mut buf).await?;
-                let len = buf.len();
-                if len < 2 || buf[len - 2] != b'\r' {
-                    return Err(ConnectionError::IncompleteData);
-                }
-                RespValue::Integer(String::from_utf8(buf[..len - 2].to_vec())?.parse::<i64>()?)
-            }
-            b'$' => {
-                // Bulk String
-                let mut buf = Vec::new();
-                input.read_until(b'\n', &mut buf).await?;
-                let len = buf.len();
-                if len < 2 || buf[len - 2] != b'\r' {
-                    return Err(ConnectionError::IncompleteData);
-                }
-                let len = String::from_utf8(buf[..len - 2].to_vec())?;
-                let len = len.parse::<i32>()?;
-                if len == -1 {
-                    RespValue::BulkString(None)
-                } else {
-                    let mut buf = vec![0u8; len as usize + 2];
-                    input.read_exact(&mut buf).await?;
-                    if buf[len as usize] != b'\r' || buf[len as usize + 1] != b'\n' {
-                        return Err(ConnectionError::IncompleteData);
+/// Find the line starting at `pos`, returning it without its CRLF and the position after it.
+/// `None` if the line is not complete yet.
+fn line(buf: &[u8], pos: usize) -> Result<Option<(&[u8], usize)>, ConnectionError> {
+    let Some(len) = buf
+        .get(pos..)
+        .and_then(|rest| rest.iter().position(|b| *b == b'\n'))
+    else {
+        return Ok(None);
+    };
+    if len == 0 || buf[pos + len - 1] != b'\r' {
+        return Err(ConnectionError::IncompleteData);
+    }
+    Ok(Some((&buf[pos..pos + len - 1], pos + len + 1)))
+}
+
+fn parse_int<N>(line: &[u8]) -> Result<N, ConnectionError>
+where
+    N: std::str::FromStr<Err = std::num::ParseIntError>,
+{
+    Ok(std::str::from_utf8(line)?.parse::<N>()?)
+}
+
+/// Length of a bulk string or aggregate, `None` for the RESP2 null encodings
+fn parse_len(line: &[u8]) -> Result<Option<usize>, ConnectionError> {
+    match line {
+        b"-1" => Ok(None),
+        _ => Ok(Some(parse_int::<usize>(line)?)),
+    }
+}
+
+/// Position right after the complete value starting at `pos`, or `None` if more bytes are needed.
+/// Only scans the buffer, so an incomplete frame costs no allocation.
+fn frame_end(
+    buf: &[u8],
+    pos: usize,
+    depth: usize,
+    limits: &Limits,
+) -> Result<Option<usize>, ConnectionError> {
+    if depth > limits.max_nesting {
+        return Err(ConnectionError::ProtocolLimit("too deep nesting"));
+    }
+    let Some((header, next)) = line(buf, pos + 1)? else {
+        return Ok(None);
+    };
+    let len = match buf[pos] {
+        b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => return Ok(Some(next)),
+        b'$' => {
+            return match parse_len(header)? {
+                None => Ok(Some(next)),
+                Some(len) if len > limits.max_bulk_len => {
+                    Err(ConnectionError::ProtocolLimit("invalid bulk length"))
+                }
+                Some(len) if buf.len() < next + len + 2 => Ok(None),
+                Some(len) if &buf[next + len..next + len + 2] != b"\r\n" => {
+                    Err(ConnectionError::IncompleteData)
+                }
+                Some(len) => Ok(Some(next + len + 2)),
+            }
+        }
+        b'*' => parse_len(header)?.unwrap_or(0),
+        b'%' => parse_int::<usize>(header)?,
+        b'>' => parse_int::<usize>(header)?,
+        _ => return Err(ConnectionError::UnrecognizedType),
+    };
+    if len > limits.max_array_len {
+        return Err(ConnectionError::ProtocolLimit("invalid multibulk length"));
+    }
+    // a map holds a key and a value per entry
+    let len = if buf[pos] == b'%' { len * 2 } else { len };
+    let mut pos = next;
+    for _ in 0..len {
+        match frame_end(buf, pos, depth + 1, limits)? {
+            Some(end) => pos = end,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(pos))
+}
+
+/// Parse the value starting at `pos` of a frame already checked complete by `frame_end`,
+/// returning it and the position after it. Bulk strings are slices of the frame.
+fn parse(frame: &Bytes, pos: usize) -> Result<(RespValue, usize), ConnectionError> {
+    let (header, next) = line(frame, pos + 1)?.ok_or(ConnectionError::IncompleteData)?;
+    let text = || std::str::from_utf8(header).map(str::to_string);
+    let value = match frame[pos] {
+        // Simple String
+        b'+' => RespValue::SimpleString(text()?),
+        // Error
+        b'-' => RespValue::Error(text()?),
+        // Integer
+        b':' => RespValue::Integer(parse_int::<i64>(header)?),
+        // Bulk String
+        b'$' => match parse_len(header)? {
+            None => RespValue::BulkString(None),
+            Some(len) => {
+                let bulk = frame.slice(next..next + len);
+                return Ok((RespValue::BulkString(Some(bulk)), next + len + 2));
+            }
+        },
+        // Array
+        b'*' => match parse_len(header)? {
+            None => RespValue::NullArray,
+            Some(len) => {
+                let (items, end) = parse_items(frame, next, len)?;
+                return Ok((RespValue::Array(items), end));
+            }
+        },
+        // Null
+        b'_' => RespValue::Null,
+        // Boolean
+        b'#' => match header {
+            b"t" => RespValue::Boolean(true),
+            b"f" => RespValue::Boolean(false),
+            _ => return Err(ConnectionError::UnrecognizedType),
+        },
+        // Double
+        b',' => RespValue::Double(
+            text()?
+                .parse::<f64>()
+                .map_err(|_| ConnectionError::UnrecognizedType)?,
+        ),
+        // Big Number
+        b'(' => RespValue::BigNumber(text()?),
+        // Map
+        b'%' => {
+            let len = parse_int::<usize>(header)?;
+            let (items, end) = parse_items(frame, next, len * 2)?;
+            let mut items = items.into_iter();
+            let mut map = Vec::with_capacity(len);
+            while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                map.push((key, value));
+            }
+            return Ok((RespValue::Map(map), end));
+        }
+        // Push
+        b'>' => {
+            let len = parse_int::<usize>(header)?;
+            let (items, end) = parse_items(frame, next, len)?;
+            return Ok((RespValue::Push(items), end));
+        }
+        _ => return Err(ConnectionError::UnrecognizedType),
+    };
+    Ok((value, next))
+}
+
+fn parse_items(
+    frame: &Bytes,
+    mut pos: usize,
+    len: usize,
+) -> Result<(Vec<RespValue>, usize), ConnectionError> {
+    let mut items = Vec::with_capacity(len);
+    for _ in 0..len {
+        let (item, end) = parse(frame, pos)?;
+        items.push(item);
+        pos = end;
+    }
+    Ok((items, pos))
+}
+
+/// Split an inline command into arguments the way redis-cli and Redis do: arguments are
+/// separated by whitespace, and may be "double quoted" with escapes or 'single quoted'.
+/// Returns `None` if quotes are unbalanced.
+fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        match line[i] {
+            b'"' => {
+                i += 1;
+                loop {
+                    match *line.get(i)? {
+                        b'"' => break,
+                        b'\\' => {
+                            let escaped = *line.get(i + 1)?;
+                            let hex = line
+                                .get(i + 2..i + 4)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                            match (escaped, hex) {
+                                (b'x', Some(byte)) => {
+                                    arg.push(byte);
+                                    i += 2;
+                                }
+                                (b'n', _) => arg.push(b'\n'),
+                                (b'r', _) => arg.push(b'\r'),
+                                (b't', _) => arg.push(b'\t'),
+                                (b'b', _) => arg.push(0x08),
+                                (b'a', _) => arg.push(0x07),
+                                (other, _) => arg.push(other),
+                            }
+                            i += 2;
+                        }
+                        byte => {
+                            arg.push(byte);
+                            i += 1;
+                        }
                     }
-                    RespValue::BulkString(Some(buf[..len as usize].to_vec()))
+                }
+                i += 1;
+                // the closing quote must be followed by a space or the end of the line
+                if line.get(i).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                    return None;
                 }
             }
-            b'*' => {
-                // Array
-                let mut buf = Vec::new();
-                input.read_until(b'\n', &mut buf).await?;
-                let len = buf.len();
-                if len < 2 || buf[len - 2] != b'\r' {
-                    return Err(ConnectionError::IncompleteData);
+            b'\'' => {
+                i += 1;
+                loop {
+                    match *line.get(i)? {
+                        b'\'' => break,
+                        b'\\' if line.get(i + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            i += 2;
+                        }
+                        byte => {
+                            arg.push(byte);
+                            i += 1;
+                        }
+                    }
                 }
-                let len = String::from_utf8(buf[..len - 2].to_vec())?;
-                let len = len.parse::<usize>()?;
-                let mut array = Vec::with_capacity(len);
-                for _ in 0..len {
-                    array.push(self.decode(input).await?);
+                i += 1;
+                if line.get(i).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+                    return None;
                 }
-                RespValue::Array(array)
             }
-            _ => return Err(ConnectionError::UnrecognizedType),
-        };
-        match value.clone() {
-            RespValue::BulkString(_) => {}
             _ => {
-                debug!("Received {:?}", value);
+                while i < line.len() && !line[i].is_ascii_whitespace() {
+                    arg.push(line[i]);
+                    i += 1;
+                }
             }
         }
-        Ok(value)
+        args.push(arg);
     }
+}
 
-    #[async_recursion]
-    pub(crate) async fn encode<T: AsyncWrite + Unpin + Send>(
-        &mut self,
-        output: &mut T,
-        data: &RespValue,
-    ) -> Result<(), ConnectionError> {
-        debug!("Sending {:?}", data);
+impl RespCodec {
+    pub(crate) fn new() -> Self {
+        Self {
+            protocol: Protocol::Resp2,
+            limits: Limits::default(),
+        }
+    }
+
+    pub(crate) fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub(crate) fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// The bytes the value takes once encoded, for the metrics of the commands sending it
+    pub(crate) fn encoded_len(&self, data: &RespValue) -> usize {
+        let mut dst = BytesMut::new();
+        self.encode_value(data, &mut dst);
+        dst.len()
+    }
+
+    fn encode_header(dst: &mut BytesMut, prefix: u8, len: impl std::fmt::Display) {
+        dst.put_u8(prefix);
+        let _ = write!(dst, "{}\r\n", len);
+    }
+
+    fn encode_value(&self, data: &RespValue, dst: &mut BytesMut) {
         match data {
-            RespValue::SimpleString(s) => {
-                output.write_all(b"+").await?;
-                output.write_all(s.as_bytes()).await?;
-                output.write_all(b"\r\n").await?;
+            RespValue::SimpleString(s) => Self::encode_header(dst, b'+', s),
+            RespValue::Error(e) => Self::encode_header(dst, b'-', e),
+            RespValue::Integer(i) => Self::encode_header(dst, b':', i),
+            RespValue::BulkString(None) | RespValue::NullArray | RespValue::Null
+                if self.protocol == Protocol::Resp3 =>
+            {
+                dst.put_slice(b"_\r\n");
             }
-            RespValue::Error(e) => {
-                output.write_all(b"-").await?;
-                output.write_all(e.as_bytes()).await?;
-                output.write_all(b"\r\n").await?;
+            RespValue::BulkString(Some(bs)) => {
+                Self::encode_header(dst, b'$', bs.len());
+                dst.put_slice(bs);
+                dst.put_slice(b"\r\n");
             }
-            RespValue::Integer(i) => {
-                output.write_all(b":").await?;
-                output.write_all(i.to_string().as_bytes()).await?;
-                output.write_all(b"\r\n").await?;
+            RespValue::BulkString(None) | RespValue::Null => dst.put_slice(b"$-1\r\n"),
+            RespValue::Array(array) => {
+                Self::encode_header(dst, b'*', array.len());
+                for item in array {
+                    self.encode_value(item, dst);
+                }
             }
-            RespValue::BulkString(bs) => {
-                output.write_all(b"$").await?;
-                if let Some(bs) = bs {
-                    output.write_all(bs.len().to_string().as_bytes()).await?;
-                    output.write_all(b"\r\n").await?;
-                    output.write_all(bs).await?;
-                    output.write_all(b"\r\n").await?;
-                } else {
-                    output.write_all(b"-1\r\n").await?;
+            RespValue::NullArray => dst.put_slice(b"*-1\r\n"),
+            RespValue::Boolean(b) => match self.protocol {
+                Protocol::Resp2 => dst.put_slice(if *b { b":1\r\n" } else { b":0\r\n" }),
+                Protocol::Resp3 => dst.put_slice(if *b { b"#t\r\n" } else { b"#f\r\n" }),
+            },
+            RespValue::Double(d) => match self.protocol {
+                Protocol::Resp2 => {
+                    let d = format_double(*d);
+                    Self::encode_header(dst, b'$', d.len());
+                    dst.put_slice(d.as_bytes());
+                    dst.put_slice(b"\r\n");
+                }
+                Protocol::Resp3 => Self::encode_header(dst, b',', format_double(*d)),
+            },
+            RespValue::BigNumber(n) => match self.protocol {
+                Protocol::Resp2 => {
+                    Self::encode_header(dst, b'$', n.len());
+                    dst.put_slice(n.as_bytes());
+                    dst.put_slice(b"\r\n");
+                }
+                Protocol::Resp3 => Self::encode_header(dst, b'(', n),
+            },
+            RespValue::Map(map) => {
+                // RESP2 has no maps, they are sent as flat arrays of keys and values
+                match self.protocol {
+                    Protocol::Resp2 => Self::encode_header(dst, b'*', map.len() * 2),
+                    Protocol::Resp3 => Self::encode_header(dst, b'%', map.len()),
+                }
+                for (key, value) in map {
+                    self.encode_value(key, dst);
+                    self.encode_value(value, dst);
                 }
             }
-            RespValue::Array(array) => {
-                output.write_all(b"*").await?;
-                output.write_all(array.len().to_string().as_bytes()).await?;
-                output.write_all(b"\r\n").await?;
-                for item in array {
-                    self.encode(output, item).await?;
+            RespValue::Push(push) => {
+                // RESP2 has no out-of-band frames, pushed messages are plain arrays
+                let prefix = match self.protocol {
+                    Protocol::Resp2 => b'*',
+                    Protocol::Resp3 => b'>',
+                };
+                Self::encode_header(dst, prefix, push.len());
+                for item in push {
+                    self.encode_value(item, dst);
                 }
             }
+            RespValue::Attribute(attributes, msg) => {
+                if self.protocol == Protocol::Resp3 {
+                    Self::encode_header(dst, b'|', attributes.len());
+                    for (key, value) in attributes {
+                        self.encode_value(key, dst);
+                        self.encode_value(value, dst);
+                    }
+                }
+                self.encode_value(msg, dst);
+            }
+        }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = RespValue;
+    type Error = ConnectionError;
+
+    /// Decode a command, sent either as a RESP value or inline like `GET foo\r\n`
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<RespValue>, ConnectionError> {
+        loop {
+            let Some(&first) = src.first() else {
+                return Ok(None);
+            };
+            match first {
+                b'+' | b'-' | b':' | b'$' | b'*' | b'_' | b'#' | b',' | b'(' | b'%' | b'>' => {
+                    let Some(end) = frame_end(src, 0, 0, &self.limits)? else {
+                        return Ok(None);
+                    };
+                    let frame = src.split_to(end).freeze();
+                    let (value, _) = parse(&frame, 0)?;
+                    if !matches!(value, RespValue::BulkString(_)) {
+                        debug!("Received {:?}", value);
+                    }
+                    return Ok(Some(value));
+                }
+                // Inline command typed by a human operator, e.g. through telnet
+                _ => {
+                    let Some(len) = src.iter().position(|b| *b == b'\n') else {
+                        if src.len() > MAX_INLINE_LEN {
+                            return Err(ConnectionError::ProtocolLimit("too big inline request"));
+                        }
+                        return Ok(None);
+                    };
+                    let mut line = &src.split_to(len + 1)[..];
+                    // like Redis, accept a bare LF as the terminator
+                    while let [rest @ .., b'\n' | b'\r'] = line {
+                        line = rest;
+                    }
+                    let args = split_inline_args(line).ok_or(ConnectionError::UnbalancedQuotes)?;
+                    if args.is_empty() {
+                        // empty lines are skipped
+                        continue;
+                    }
+                    let value = RespValue::Array(
+                        args.into_iter()
+                            .map(|arg| RespValue::BulkString(Some(arg.into())))
+                            .collect(),
+                    );
+                    debug!("Received inline {:?}", value);
+                    return Ok(Some(value));
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<RespValue>, ConnectionError> {
+        match self.decode(src)? {
+            Some(value) => Ok(Some(value)),
+            None => {
+                // the client hung up in the middle of a command, there is nothing to reply to
+                if !src.is_empty() {
+                    debug!("Dropping {} bytes of incomplete command", src.len());
+                    src.clear();
+                }
+                Ok(None)
+            }
         }
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    type Error = ConnectionError;
+
+    fn encode(&mut self, data: RespValue, dst: &mut BytesMut) -> Result<(), ConnectionError> {
+        debug!("Sending {:?}", data);
+        self.encode_value(&data, dst);
         Ok(())
     }
 }
@@ -0,0 +1,519 @@
+//! A client for the few calls of the S3 API that backups need, against any S3-compatible store:
+//! objects are written whole or in parts, read, listed and deleted, in buckets addressed by path
+//! and with requests signed with AWS Signature Version 4. It speaks plain HTTP/1.1 over a
+//! blocking socket, one connection per request, as it runs off the async runtime: there is no
+//! TLS client among the dependencies, so the store is reached over `http://`, such as a MinIO
+//! next to the nodes or a TLS-terminating proxy.
+
+use crate::backup::date;
+use anyhow::{anyhow, bail, Context};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Objects larger than this are uploaded in parts of this size, S3 needs at least 5 MiB
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// A store not answering within this time fails the request
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Objects under a prefix of a bucket, written `s3://bucket/prefix`
+#[derive(Clone, Debug)]
+pub(crate) struct Location {
+    pub(crate) bucket: String,
+    // without a trailing slash, empty for the whole bucket
+    pub(crate) prefix: String,
+}
+
+impl Location {
+    pub(crate) fn parse(url: &str) -> anyhow::Result<Self> {
+        let path = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| anyhow!("not an s3:// URL: '{}'", url))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            bail!("no bucket in '{}'", url);
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// The key of an object under the prefix
+    pub(crate) fn key(&self, name: &str) -> String {
+        match self.prefix.as_str() {
+            "" => name.to_string(),
+            prefix => format!("{}/{}", prefix, name),
+        }
+    }
+
+    /// The location of the objects under a name of the prefix
+    pub(crate) fn join(&self, name: &str) -> Self {
+        Self {
+            bucket: self.bucket.clone(),
+            prefix: self.key(name),
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.prefix)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct S3 {
+    // host:port
+    host: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+struct Response {
+    headers: Vec<(String, String)>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl S3 {
+    pub(crate) fn new(
+        endpoint: &str,
+        region: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+    ) -> anyhow::Result<Self> {
+        if endpoint.starts_with("https://") {
+            bail!(
+                "'{}' needs TLS, which the node has no client for: reach the store over http://",
+                endpoint
+            );
+        }
+        let host = endpoint.strip_prefix("http://").unwrap_or(endpoint);
+        Ok(Self {
+            host: host.trim_end_matches('/').to_string(),
+            region: region.to_string(),
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+        })
+    }
+
+    pub(crate) fn put(&self, bucket: &str, key: &str, body: &[u8]) -> anyhow::Result<()> {
+        self.call("PUT", bucket, key, &[], body, &mut Vec::new())?;
+        Ok(())
+    }
+
+    /// Write the object from the reader, in parts past PART_SIZE, returning its size and the
+    /// SHA-256 of its content
+    pub(crate) fn upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        reader: &mut impl Read,
+    ) -> anyhow::Result<(u64, [u8; 32])> {
+        let mut hasher = Sha256::new();
+        let mut part = read_part(reader)?;
+        if part.len() < PART_SIZE {
+            hasher.update(&part);
+            self.put(bucket, key, &part)?;
+            return Ok((part.len() as u64, hasher.finalize().into()));
+        }
+        let mut created = Vec::new();
+        self.call("POST", bucket, key, &[("uploads", "")], &[], &mut created)?;
+        let upload_id = tag(&String::from_utf8_lossy(&created), "UploadId")
+            .ok_or_else(|| anyhow!("no upload id for {}", key))?;
+        let mut size = 0;
+        let mut etags = Vec::new();
+        let uploaded = (|| {
+            while !part.is_empty() {
+                hasher.update(&part);
+                size += part.len() as u64;
+                let number = (etags.len() + 1).to_string();
+                let response = self.call(
+                    "PUT",
+                    bucket,
+                    key,
+                    &[("partNumber", &number), ("uploadId", &upload_id)],
+                    &part,
+                    &mut Vec::new(),
+                )?;
+                let etag = response
+                    .header("ETag")
+                    .ok_or_else(|| anyhow!("no ETag for part {} of {}", number, key))?;
+                etags.push(etag.to_string());
+                part = read_part(reader)?;
+            }
+            let mut complete = String::from("<CompleteMultipartUpload>");
+            for (number, etag) in etags.iter().enumerate() {
+                let _ = write!(
+                    complete,
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    number + 1,
+                    escape(etag)
+                );
+            }
+            complete.push_str("</CompleteMultipartUpload>");
+            let mut completed = Vec::new();
+            self.call(
+                "POST",
+                bucket,
+                key,
+                &[("uploadId", &upload_id)],
+                complete.as_bytes(),
+                &mut completed,
+            )?;
+            // a failure past the headers comes in a body with a 200 status
+            let completed = String::from_utf8_lossy(&completed);
+            if completed.contains("<Error>") {
+                bail!("{}", error_message(&completed));
+            }
+            Ok(())
+        })();
+        if let Err(e) = uploaded {
+            // the parts of an upload that is neither completed nor aborted are kept, and billed
+            let _ = self.call(
+                "DELETE",
+                bucket,
+                key,
+                &[("uploadId", &upload_id)],
+                &[],
+                &mut Vec::new(),
+            );
+            return Err(e);
+        }
+        Ok((size, hasher.finalize().into()))
+    }
+
+    /// Read the object into the writer, returning its size
+    pub(crate) fn get(&self, bucket: &str, key: &str, out: &mut impl Write) -> anyhow::Result<u64> {
+        let mut counted = Counted { inner: out, len: 0 };
+        self.call("GET", bucket, key, &[], &[], &mut counted)?;
+        Ok(counted.len)
+    }
+
+    /// The keys of the objects starting with the prefix, in key order
+    pub(crate) fn list(&self, bucket: &str, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let mut listed = Vec::new();
+            self.call("GET", bucket, "", &query, &[], &mut listed)?;
+            let listed = String::from_utf8_lossy(&listed);
+            let mut rest = listed.as_ref();
+            while let Some(start) = rest.find("<Contents>") {
+                rest = &rest[start..];
+                let end = rest.find("</Contents>").unwrap_or(rest.len());
+                if let Some(key) = tag(&rest[..end], "Key") {
+                    keys.push(key);
+                }
+                rest = &rest[end..];
+            }
+            if tag(&listed, "IsTruncated").as_deref() != Some("true") {
+                return Ok(keys);
+            }
+            token = Some(
+                tag(&listed, "NextContinuationToken")
+                    .ok_or_else(|| anyhow!("a truncated listing without a continuation token"))?,
+            );
+        }
+    }
+
+    pub(crate) fn delete(&self, bucket: &str, key: &str) -> anyhow::Result<()> {
+        self.call("DELETE", bucket, key, &[], &[], &mut Vec::new())?;
+        Ok(())
+    }
+
+    /// Send a signed request and write the body of its reply to out, failing on an error status
+    fn call(
+        &self,
+        method: &str,
+        bucket: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+        out: &mut dyn Write,
+    ) -> anyhow::Result<Response> {
+        let path = format!("/{}/{}", encode(bucket, true), encode(key, false));
+        let mut params: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (encode(name, true), encode(value, true)))
+            .collect();
+        params.sort();
+        let query = params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        let authorization = self.sign(method, &path, &query, body, SystemTime::now());
+        let target = match query.as_str() {
+            "" => path,
+            query => format!("{}?{}", path, query),
+        };
+        let stream = TcpStream::connect(&self.host)
+            .with_context(|| format!("could not reach {}", self.host))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, target, self.host);
+        for (name, value) in &authorization {
+            let _ = write!(request, "{}: {}\r\n", name, value);
+        }
+        let _ = write!(
+            request,
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        let mut writer = &stream;
+        writer.write_all(request.as_bytes())?;
+        writer.write_all(body)?;
+        let mut reader = BufReader::new(&stream);
+        let (status, headers) = read_head(&mut reader)?;
+        let response = Response { headers };
+        if !(200..300).contains(&status) {
+            let mut error = Vec::new();
+            // the status tells enough when the error cannot be read
+            let _ = read_body(&mut reader, &response, &mut error);
+            bail!(
+                "{} {} failed with {}: {}",
+                method,
+                target,
+                status,
+                error_message(&String::from_utf8_lossy(&error))
+            );
+        }
+        read_body(&mut reader, &response, out)?;
+        Ok(response)
+    }
+
+    /// The headers authenticating the request with Signature Version 4
+    fn sign(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        body: &[u8],
+        now: SystemTime,
+    ) -> Vec<(&'static str, String)> {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let (year, month, day) = date(secs / 86_400);
+        let day_stamp = format!("{:04}{:02}{:02}", year, month, day);
+        let time_stamp = format!(
+            "{}T{:02}{:02}{:02}Z",
+            day_stamp,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60
+        );
+        let payload_hash = hex(&Sha256::digest(body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, query, self.host, payload_hash, time_stamp, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", day_stamp, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            time_stamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = hmac(
+            format!("AWS4{}", self.secret_access_key).as_bytes(),
+            day_stamp.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+        vec![
+            ("x-amz-content-sha256", payload_hash),
+            ("x-amz-date", time_stamp),
+            (
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key_id, scope, signed_headers, signature
+                ),
+            ),
+        ]
+    }
+}
+
+/// Read up to PART_SIZE bytes, fewer only at the end of the reader
+fn read_part(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    reader.take(PART_SIZE as u64).read_to_end(&mut part)?;
+    Ok(part)
+}
+
+/// The status and the headers of a response
+fn read_head(reader: &mut impl BufRead) -> anyhow::Result<(u16, Vec<(String, String)>)> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("not an HTTP response: '{}'", line.trim_end()))?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("the connection closed within the headers");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok((status, headers));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+}
+
+/// Copy the body of the response to out, sent with a length, in chunks or up to the end
+fn read_body(
+    reader: &mut impl BufRead,
+    response: &Response,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    if response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = u64::from_str_radix(size, 16)
+                .map_err(|_| anyhow!("invalid chunk size '{}'", line.trim_end()))?;
+            if size == 0 {
+                return Ok(());
+            }
+            let copied = std::io::copy(&mut reader.take(size), out)?;
+            if copied < size {
+                bail!("the connection closed within a chunk");
+            }
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    }
+    match response.header("Content-Length") {
+        Some(len) => {
+            let len: u64 = len
+                .parse()
+                .map_err(|_| anyhow!("invalid length '{}'", len))?;
+            let copied = std::io::copy(&mut reader.take(len), out)?;
+            if copied < len {
+                bail!("the connection closed after {} of {} bytes", copied, len);
+            }
+        }
+        None => {
+            std::io::copy(reader, out)?;
+        }
+    }
+    Ok(())
+}
+
+struct Counted<'a, W> {
+    inner: &'a mut W,
+    len: u64,
+}
+
+impl<W: Write> Write for Counted<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The content of the first element of the tag, unescaped
+fn tag(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(
+        xml[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// The code and the message of an error reply
+fn error_message(xml: &str) -> String {
+    match (tag(xml, "Code"), tag(xml, "Message")) {
+        (Some(code), Some(message)) => format!("{} {}", code, message),
+        (Some(code), None) => code,
+        _ => "no error message".to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encode like Signature Version 4 expects, keeping the slashes of a path
+fn encode(text: &str, slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !slash => encoded.push('/'),
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(data)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
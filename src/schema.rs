@@ -0,0 +1,240 @@
+//! The schema of the commands the node serves, as JSON on `GET /v1/commands` of the HTTP gateway,
+//! for client generators and compatibility checkers to track what a release supports, as
+//! `COMMAND DOCS` does for Redis: the name of each command, its arity, its flags, its
+//! subcommands and the release it first shipped in, then the commands of the plugins installed.
+//!
+//! The arity counts the name of the command, negative for at least that many arguments. The
+//! flags are `write` for the commands replicated through the log, `readonly` for those served
+//! from the local replica, `admin`, `pubsub` and `connection` after the ACL categories,
+//! `deprecated` for the names rewritten into another command, and `plugin` for the commands of
+//! the plugins, which have no `since`.
+//!
+//! The version of the schema is in the path: fields are only added to it, a change of their
+//! meaning or a removal moves it to `/v2`. A command added to the table carries the version of
+//! the crate it is added in.
+
+use crate::plugin;
+use serde_json::{json, Value};
+
+/// The version of the schema, in the path it is served on
+pub(crate) const VERSION: u32 = 1;
+
+const WRITE: &str = "write";
+const READONLY: &str = "readonly";
+const ADMIN: &str = "admin";
+const PUBSUB: &str = "pubsub";
+const CONNECTION: &str = "connection";
+const DEPRECATED: &str = "deprecated";
+const PLUGIN: &str = "plugin";
+
+struct Command {
+    name: &'static str,
+    arity: i32,
+    flags: &'static [&'static str],
+    since: &'static str,
+    subcommands: &'static [&'static str],
+}
+
+const fn command(
+    name: &'static str,
+    arity: i32,
+    flags: &'static [&'static str],
+    since: &'static str,
+    subcommands: &'static [&'static str],
+) -> Command {
+    Command {
+        name,
+        arity,
+        flags,
+        since,
+        subcommands,
+    }
+}
+
+/// Every command the parser knows, in name order
+const COMMANDS: &[Command] = &[
+    command(
+        "acl",
+        -2,
+        &[ADMIN],
+        "0.1.0",
+        &["getuser", "list", "setuser", "whoami"],
+    ),
+    command(
+        "admin",
+        -2,
+        &[ADMIN],
+        "0.1.0",
+        &["broadcast", "maintenance"],
+    ),
+    command("auth", -2, &[CONNECTION], "0.1.0", &[]),
+    command("bf.add", 3, &[WRITE], "0.1.0", &[]),
+    command("bf.exists", 3, &[READONLY], "0.1.0", &[]),
+    command("bf.madd", -3, &[WRITE], "0.1.0", &[]),
+    command("bgsave", 1, &[ADMIN], "0.1.0", &[]),
+    command("bitcount", -2, &[READONLY], "0.1.0", &[]),
+    command("bitpos", -3, &[READONLY], "0.1.0", &[]),
+    command("cas", 4, &[WRITE], "0.1.0", &[]),
+    command(
+        "client",
+        -2,
+        &[ADMIN],
+        "0.1.0",
+        &[
+            "addr",
+            "getname",
+            "id",
+            "info",
+            "kill",
+            "list",
+            "setname",
+            "trace",
+            "tracking",
+            "watermark",
+        ],
+    ),
+    command("cluster", 3, &[ADMIN], "0.1.0", &["keyowner", "keyslot"]),
+    command("cms.incrby", -4, &[WRITE], "0.1.0", &[]),
+    command("cms.initbydim", 4, &[WRITE], "0.1.0", &[]),
+    command("cms.query", -3, &[READONLY], "0.1.0", &[]),
+    command("compact", 1, &[ADMIN], "0.1.0", &[]),
+    command("config", -2, &[ADMIN], "0.1.0", &["get", "rewrite", "set"]),
+    command("copy", -3, &[WRITE], "0.1.0", &[]),
+    command("dbsize", 1, &[READONLY], "0.1.0", &[]),
+    command(
+        "debug",
+        -2,
+        &[ADMIN],
+        "0.1.0",
+        &["failpoint", "recovery-bench", "sleep"],
+    ),
+    command("del", -2, &[WRITE], "0.1.0", &[]),
+    command("dump", 2, &[READONLY], "0.1.0", &[]),
+    command("eval", -3, &[WRITE], "0.1.0", &[]),
+    command("evalsha", -3, &[WRITE], "0.1.0", &[]),
+    command("export", 2, &[ADMIN], "0.1.0", &[]),
+    command(
+        "feature",
+        -2,
+        &[ADMIN],
+        "0.1.0",
+        &["disable", "enable", "list"],
+    ),
+    command(
+        "flushall",
+        -1,
+        &[WRITE, ADMIN],
+        "0.1.0",
+        &["confirm", "schedule"],
+    ),
+    command("flushdb", -1, &[WRITE, ADMIN], "0.1.0", &[]),
+    command("get", -2, &[READONLY], "0.1.0", &[]),
+    command("getbit", 3, &[READONLY], "0.1.0", &[]),
+    command("getdel", 2, &[WRITE], "0.1.0", &[]),
+    command("getex", -2, &[WRITE], "0.1.0", &[]),
+    command("getrange", 4, &[READONLY], "0.1.0", &[]),
+    command("getset", 3, &[WRITE, DEPRECATED], "0.1.0", &[]),
+    command("hello", -1, &[CONNECTION], "0.1.0", &[]),
+    command("if", -5, &[WRITE], "0.1.0", &[]),
+    command("info", -1, &[ADMIN], "0.1.0", &[]),
+    command("keys", 2, &[READONLY], "0.1.0", &[]),
+    command("lastsave", 1, &[ADMIN], "0.1.0", &[]),
+    command("llen", 2, &[READONLY], "0.1.0", &[]),
+    command("lock", -3, &[WRITE], "0.1.0", &[]),
+    command("lpop", -2, &[WRITE], "0.1.0", &[]),
+    command("lpush", -3, &[WRITE], "0.1.0", &[]),
+    command("lrange", 4, &[READONLY], "0.1.0", &[]),
+    command("mgetsnapshot", -2, &[READONLY], "0.1.0", &[]),
+    command("monitor", 1, &[ADMIN], "0.1.0", &[]),
+    command("ping", 1, &[CONNECTION], "0.1.0", &[]),
+    command("proc", -3, &[WRITE], "0.1.0", &["call", "delete", "load"]),
+    command("promote", 1, &[ADMIN], "0.1.0", &[]),
+    command("psubscribe", -2, &[PUBSUB], "0.1.0", &[]),
+    command("publish", 3, &[WRITE, PUBSUB], "0.1.0", &[]),
+    command("punsubscribe", -1, &[PUBSUB], "0.1.0", &[]),
+    command("raft", -3, &[ADMIN], "0.1.0", &["log", "tail"]),
+    command("rename", 3, &[WRITE], "0.1.0", &[]),
+    command("renamenx", 3, &[WRITE], "0.1.0", &[]),
+    command("restore", -4, &[WRITE], "0.1.0", &[]),
+    command("rpop", -2, &[WRITE], "0.1.0", &[]),
+    command("rpush", -3, &[WRITE], "0.1.0", &[]),
+    command(
+        "script",
+        -2,
+        &[WRITE],
+        "0.1.0",
+        &["exists", "flush", "load"],
+    ),
+    command("select", 2, &[CONNECTION], "0.1.0", &[]),
+    command("set", -3, &[WRITE], "0.1.0", &[]),
+    command("setbit", 4, &[WRITE], "0.1.0", &[]),
+    command("shutdown", -1, &[ADMIN], "0.1.0", &[]),
+    command("slowlog", -2, &[ADMIN], "0.1.0", &["get", "len", "reset"]),
+    command(
+        "snapshot",
+        -2,
+        &[ADMIN],
+        "0.1.0",
+        &["close", "open", "release", "take"],
+    ),
+    command("spublish", 3, &[WRITE, PUBSUB], "0.1.0", &[]),
+    command("ssubscribe", -2, &[PUBSUB], "0.1.0", &[]),
+    command("subscribe", -2, &[PUBSUB], "0.1.0", &[]),
+    command("substr", 4, &[READONLY, DEPRECATED], "0.1.0", &[]),
+    command("sunsubscribe", -1, &[PUBSUB], "0.1.0", &[]),
+    command("swapdb", 3, &[WRITE, ADMIN], "0.1.0", &[]),
+    command("topk.add", -3, &[WRITE], "0.1.0", &[]),
+    command("topk.list", -2, &[READONLY], "0.1.0", &[]),
+    command("topk.reserve", -3, &[WRITE], "0.1.0", &[]),
+    command("unlink", -2, &[WRITE], "0.1.0", &[]),
+    command("unlock", 3, &[WRITE], "0.1.0", &[]),
+    command("unsubscribe", -1, &[PUBSUB], "0.1.0", &[]),
+    command("wait", 3, &[CONNECTION], "0.1.0", &[]),
+    command("zadd", -4, &[WRITE], "0.1.0", &[]),
+    command("zrange", -4, &[READONLY], "0.1.0", &[]),
+    command("zrangebyscore", -4, &[READONLY], "0.1.0", &[]),
+    command("zrem", -3, &[WRITE], "0.1.0", &[]),
+    command("zscore", 3, &[READONLY], "0.1.0", &[]),
+];
+
+/// The arity of the command of that name, in lowercase, None for no such command
+pub(crate) fn arity(name: &str) -> Option<i32> {
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => Some(command.arity),
+        None => plugin::command(name).map(|command| command.arity()),
+    }
+}
+
+/// The schema of the commands, with the version of the node
+pub(crate) fn export() -> Value {
+    let mut commands: Vec<Value> = COMMANDS
+        .iter()
+        .map(|command| {
+            json!({
+                "name": command.name,
+                "arity": command.arity,
+                "flags": command.flags,
+                "since": command.since,
+                "subcommands": command.subcommands,
+            })
+        })
+        .collect();
+    // a plugin has no release of the node it shipped in
+    commands.extend(plugin::commands().into_iter().map(|command| {
+        json!({
+            "name": command.name,
+            "arity": command.arity(),
+            "flags": [if command.write { WRITE } else { READONLY }, PLUGIN],
+            "since": null,
+            "subcommands": [],
+        })
+    }));
+    json!({
+        "version": VERSION,
+        "server": {
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "commands": commands,
+    })
+}
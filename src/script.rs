@@ -0,0 +1,368 @@
+//! Lua scripts run with `EVAL` and `EVALSHA`, as in Redis.
+//!
+//! A script is replicated as its source, or its digest, with its keys and arguments, and runs in
+//! the apply path of every node, at the same entry of the log: its commands are applied at once,
+//! with no other write between them. So that every node computes the same writes, a script runs
+//! in a state of its own with only the base, table, string and math libraries, at the time of
+//! the node it was sent to, with `math.random` seeded alike, and within a fixed number of
+//! instructions and memory. Iterating a table with `pairs` may visit its keys in another order on
+//! each node, so scripts that write in the order they iterate should sort the keys first.
+//!
+//! Scripts call commands with `redis.call`, raising their errors, and `redis.pcall`, returning
+//! them as a table with an `err` field; the commands run through the dispatcher of the clients,
+//! in the database of the client that sent the script. `redis.status_reply` and
+//! `redis.error_reply` build the replies of those types.
+//!
+//! Scripts are stored by the SHA1 digest of their source under a reserved prefix, hidden from
+//! KEYS, once loaded with `SCRIPT LOAD` or run with `EVAL`, until `SCRIPT FLUSH`.
+
+use crate::cmd::{Cmd, InnerCmd, NodeContext};
+use crate::connection;
+use crate::database;
+use crate::keyspace::Snapshot;
+use crate::resp_codec::{self, RespValue};
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use bytes::Bytes;
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Table, Value, Variadic};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::cell::{Cell, RefCell};
+use thiserror::Error;
+
+/// Keys holding scripts, not a prefix clients are expected to use
+pub(crate) const KEY_PREFIX: &[u8] = b"\xffscript:";
+/// Instructions a script may run
+const INSTRUCTIONS: u64 = 10_000_000;
+/// Instructions between two checks of the count
+const INSTRUCTIONS_PER_CHECK: u32 = 1000;
+const MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+/// Nesting of the tables a script replies with, deeper ones are cut
+const MAX_REPLY_DEPTH: usize = 32;
+/// The helpers of the `redis` table written in Lua, run before each script
+const PRELUDE: &str = r#"
+function redis.call(...)
+    local reply = redis.pcall(...)
+    if type(reply) == "table" and reply.err then
+        error(reply.err, 0)
+    end
+    return reply
+end
+function redis.error_reply(msg)
+    return { err = msg }
+end
+function redis.status_reply(msg)
+    return { ok = msg }
+end
+math.randomseed(0)
+"#;
+
+/// A script run with the keys and arguments of its call
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Call {
+    pub(crate) keys: Vec<Vec<u8>>,
+    pub(crate) args: Vec<Vec<u8>>,
+    // the database of the client, the commands of the script run in
+    pub(crate) db: u32,
+    // unix time in milliseconds on the node the script was sent to, the time of its commands
+    pub(crate) now: u64,
+}
+
+/// An EVAL, EVALSHA or SCRIPT subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum ScriptOp {
+    // Source
+    Eval(Vec<u8>, Call),
+    // Digest of the source
+    EvalSha(String, Call),
+    // Source
+    Load(Vec<u8>),
+    // Digests
+    Exists(Vec<String>),
+    Flush,
+}
+
+impl ScriptOp {
+    /// The keys the script declares, as stored
+    pub(crate) fn keys(&self) -> Vec<&Vec<u8>> {
+        match self {
+            ScriptOp::Eval(_, call) | ScriptOp::EvalSha(_, call) => call.keys.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub(crate) fn keys_mut(&mut self) -> Vec<&mut Vec<u8>> {
+        match self {
+            ScriptOp::Eval(_, call) | ScriptOp::EvalSha(_, call) => call.keys.iter_mut().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Run the script in database `db`
+    pub(crate) fn select(&mut self, db: u32) {
+        if let ScriptOp::Eval(_, call) | ScriptOp::EvalSha(_, call) = self {
+            call.db = db;
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("NOSCRIPT No matching script. Please use EVAL.")]
+pub(crate) struct NoScript;
+
+/// Whether the error is an EVALSHA of a script that is not stored
+pub(crate) fn is_no_script(e: &BitCaskError) -> bool {
+    matches!(e, BitCaskError::UnexpectedError(e) if e.is::<NoScript>())
+}
+
+fn error(msg: String) -> BitCaskError {
+    BitCaskError::UnexpectedError(anyhow::anyhow!(msg))
+}
+
+/// The SHA1 digest of a script in lowercase hex, the name EVALSHA runs it by
+pub(crate) fn digest(script: &[u8]) -> String {
+    Sha1::digest(script)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn script_key(digest: &str) -> Vec<u8> {
+    [KEY_PREFIX, digest.to_lowercase().as_bytes()].concat()
+}
+
+/// Store a script unless it is already, returning its digest
+fn store(storage: &mut BitCask, script: &[u8]) -> Result<String, BitCaskError> {
+    let digest = digest(script);
+    let key = script_key(&digest);
+    if storage.get(&key).is_none() {
+        storage.put(&key, &value::encode(ValueType::Script, script))?;
+    }
+    Ok(digest)
+}
+
+/// Whether each script is stored, for SCRIPT EXISTS
+pub(crate) fn exists(storage: &BitCask, digests: &[String]) -> RespValue {
+    RespValue::Array(
+        digests
+            .iter()
+            .map(|digest| RespValue::Integer(storage.get(&script_key(digest)).is_some() as i64))
+            .collect(),
+    )
+}
+
+/// Run a replicated script subcommand against the storage
+pub(crate) fn apply(
+    storage: &mut BitCask,
+    context: &NodeContext,
+    op: &ScriptOp,
+) -> Result<RespValue, BitCaskError> {
+    match op {
+        ScriptOp::Eval(script, call) => {
+            store(storage, script)?;
+            run(storage, context, script, call)
+        }
+        ScriptOp::EvalSha(digest, call) => {
+            let raw = storage
+                .get(&script_key(digest))
+                .ok_or_else(|| BitCaskError::UnexpectedError(NoScript.into()))?;
+            let script = value::expect(&raw, ValueType::Script)?.to_vec();
+            run(storage, context, &script, call)
+        }
+        ScriptOp::Load(script) => {
+            let digest = store(storage, script)?;
+            Ok(RespValue::BulkString(Some(Bytes::from(digest))))
+        }
+        ScriptOp::Flush => {
+            let snapshot = Snapshot::take(&context.data_dir)?;
+            for key in snapshot.keys()? {
+                if key.starts_with(KEY_PREFIX) && storage.get(&key).is_some() {
+                    storage.delete(&key)?;
+                }
+            }
+            Ok(RespValue::SimpleString("OK".to_string()))
+        }
+        ScriptOp::Exists(digests) => Ok(exists(storage, digests)),
+    }
+}
+
+/// The message of an error raised by a script, without the wrapping of the callbacks it went
+/// through nor its stack traceback, on one line as errors are replied
+fn message(e: &mlua::Error) -> String {
+    let msg = match e {
+        mlua::Error::RuntimeError(msg) | mlua::Error::MemoryError(msg) => msg.clone(),
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        mlua::Error::CallbackError { cause, .. } => return message(cause),
+        e => e.to_string(),
+    };
+    msg.lines().next().unwrap_or_default().to_string()
+}
+
+/// Run a script in a state of its own, at the time of its call
+fn run(
+    storage: &mut BitCask,
+    context: &NodeContext,
+    script: &[u8],
+    call: &Call,
+) -> Result<RespValue, BitCaskError> {
+    let failed = |e: mlua::Error| error(format!("script failed: {}", message(&e)));
+    let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH;
+    let lua = Lua::new_with(libs, LuaOptions::new()).map_err(failed)?;
+    lua.set_memory_limit(MEMORY_LIMIT).map_err(failed)?;
+    let executed = Cell::new(0);
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_CHECK),
+        move |_, _| {
+            executed.set(executed.get() + INSTRUCTIONS_PER_CHECK as u64);
+            match executed.get() > INSTRUCTIONS {
+                true => Err(mlua::Error::RuntimeError(
+                    "ran out of instructions".to_string(),
+                )),
+                false => Ok(()),
+            }
+        },
+    );
+    let storage = RefCell::new(storage);
+    let reply = value::frozen(call.now, || {
+        lua.scope(|scope| {
+            let globals = lua.globals();
+            for name in ["print", "dofile", "loadfile"] {
+                globals.set(name, Value::Nil)?;
+            }
+            let keys: Vec<&[u8]> = call
+                .keys
+                .iter()
+                .map(|key| database::of(call.db, key).unwrap_or(key))
+                .collect();
+            globals.set("KEYS", byte_strings(&lua, &keys)?)?;
+            let args: Vec<&[u8]> = call.args.iter().map(Vec::as_slice).collect();
+            globals.set("ARGV", byte_strings(&lua, &args)?)?;
+            let redis = lua.create_table()?;
+            let pcall = scope.create_function(|lua, args: Variadic<Value>| {
+                let args = args
+                    .into_iter()
+                    .map(|arg| match arg {
+                        Value::String(_) | Value::Integer(_) | Value::Number(_) => {
+                            let arg = lua.coerce_string(arg)?.expect("a string or number");
+                            Ok(RespValue::BulkString(Some(Bytes::copy_from_slice(
+                                arg.as_bytes(),
+                            ))))
+                        }
+                        _ => Err(mlua::Error::RuntimeError(
+                            "the arguments of a command must be strings or numbers".to_string(),
+                        )),
+                    })
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                let reply = dispatch(&mut storage.borrow_mut(), context, call.db, args);
+                lua_value(lua, reply)
+            })?;
+            redis.set("pcall", pcall)?;
+            globals.set("redis", redis)?;
+            lua.load(PRELUDE).set_name("=prelude").exec()?;
+            let reply = lua.load(script).set_name("=user_script").eval::<Value>()?;
+            Ok(resp_value(reply, 0))
+        })
+    });
+    reply.map_err(failed)
+}
+
+/// A Lua array of the byte strings
+fn byte_strings<'lua>(lua: &'lua Lua, strings: &[&[u8]]) -> mlua::Result<Table<'lua>> {
+    lua.create_sequence_from(
+        strings
+            .iter()
+            .map(|s| lua.create_string(s))
+            .collect::<mlua::Result<Vec<_>>>()?,
+    )
+}
+
+/// Run a command a script calls, replying with its errors
+fn dispatch(
+    storage: &mut BitCask,
+    context: &NodeContext,
+    db: u32,
+    args: Vec<RespValue>,
+) -> RespValue {
+    let Some(RespValue::BulkString(Some(name))) = args.first() else {
+        return RespValue::Error("ERR a script must call a command".to_string());
+    };
+    let name = String::from_utf8_lossy(name).to_lowercase();
+    let Ok(inner_cmd) = InnerCmd::new(Cmd::from(RespValue::Array(args))) else {
+        return RespValue::Error(format!(
+            "ERR unknown command '{}' called from a script",
+            name
+        ));
+    };
+    if inner_cmd.keys().iter().any(|key| database::reserved(key)) {
+        return RespValue::Error(database::RESERVED.to_string());
+    }
+    match inner_cmd.run_in_script(storage, context, db) {
+        Ok(reply) => reply,
+        Err(e) => connection::error_reply(&e),
+    }
+}
+
+/// The Lua value of the reply of a command, as Redis converts them
+fn lua_value(lua: &Lua, reply: RespValue) -> mlua::Result<Value<'_>> {
+    let table = |field: &str, msg: String| -> mlua::Result<Value<'_>> {
+        let table = lua.create_table()?;
+        table.set(field, msg)?;
+        Ok(Value::Table(table))
+    };
+    let sequence = |replies: Vec<RespValue>| -> mlua::Result<Value<'_>> {
+        let values = replies
+            .into_iter()
+            .map(|reply| lua_value(lua, reply))
+            .collect::<mlua::Result<Vec<_>>>()?;
+        Ok(Value::Table(lua.create_sequence_from(values)?))
+    };
+    match reply {
+        RespValue::Integer(i) => Ok(Value::Integer(i)),
+        RespValue::BulkString(Some(bytes)) => Ok(Value::String(lua.create_string(&bytes)?)),
+        RespValue::BulkString(None) | RespValue::NullArray | RespValue::Null => {
+            Ok(Value::Boolean(false))
+        }
+        RespValue::Boolean(b) => Ok(match b {
+            true => Value::Integer(1),
+            false => Value::Boolean(false),
+        }),
+        RespValue::SimpleString(s) => table("ok", s),
+        RespValue::Error(e) => table("err", e),
+        RespValue::Double(d) => Ok(Value::String(
+            lua.create_string(resp_codec::format_double(d))?,
+        )),
+        RespValue::BigNumber(n) => Ok(Value::String(lua.create_string(n)?)),
+        RespValue::Array(replies) | RespValue::Push(replies) => sequence(replies),
+        RespValue::Map(pairs) => sequence(pairs.into_iter().flat_map(|(k, v)| [k, v]).collect()),
+        RespValue::Attribute(_, reply) => lua_value(lua, *reply),
+    }
+}
+
+/// The reply of the value a script returns, as Redis converts them
+fn resp_value(value: Value, depth: usize) -> RespValue {
+    match value {
+        Value::Integer(i) => RespValue::Integer(i),
+        // truncated, as in Redis
+        Value::Number(n) => RespValue::Integer(n as i64),
+        Value::String(s) => RespValue::BulkString(Some(Bytes::copy_from_slice(s.as_bytes()))),
+        Value::Boolean(true) => RespValue::Integer(1),
+        Value::Table(table) if depth < MAX_REPLY_DEPTH => {
+            if let Ok(Value::String(err)) = table.raw_get("err") {
+                return RespValue::Error(err.to_string_lossy().into_owned());
+            }
+            if let Ok(Value::String(ok)) = table.raw_get("ok") {
+                return RespValue::SimpleString(ok.to_string_lossy().into_owned());
+            }
+            // up to the first nil, as in Redis
+            RespValue::Array(
+                table
+                    .sequence_values::<Value>()
+                    .map_while(Result::ok)
+                    .map(|value| resp_value(value, depth + 1))
+                    .collect(),
+            )
+        }
+        _ => RespValue::BulkString(None),
+    }
+}
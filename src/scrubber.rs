@@ -0,0 +1,183 @@
+use crate::keyspace;
+use crate::overload::Overload;
+use crc::{Crc, CRC_32_CKSUM};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Same checksum as the one bitcask-engine-rs stores in front of every entry
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+pub(crate) const DATA_FILE_EXT: &str = "bitcask";
+/// Checksum (4 bytes) + key size (8 bytes) + value size (8 bytes)
+pub(crate) const HEADER_SIZE: u64 = 20;
+
+#[derive(Default)]
+pub(crate) struct ScrubStats {
+    passes: AtomicU64,
+    entries_scanned: AtomicU64,
+    bytes_scanned: AtomicU64,
+    corrupt_entries: AtomicU64,
+    last_pass_secs: AtomicU64,
+}
+
+impl ScrubStats {
+    /// Render the `scrubber` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "scrub_passes:{}\r\nscrub_entries_scanned:{}\r\nscrub_bytes_scanned:{}\r\nscrub_corrupt_entries:{}\r\nscrub_last_pass_seconds:{}\r\n",
+            self.passes.load(Ordering::Relaxed),
+            self.entries_scanned.load(Ordering::Relaxed),
+            self.bytes_scanned.load(Ordering::Relaxed),
+            self.corrupt_entries.load(Ordering::Relaxed),
+            self.last_pass_secs.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+/// Background task re-reading every entry of the bitcask data files and verifying its checksum.
+///
+/// Reads are paced to `rate_bytes_per_sec` so the scrubber never competes with foreground traffic.
+/// Corrupt entries are reported in the log and counted in INFO; they are left in place because
+/// there is no healthy copy to repair them from on this node.
+pub(crate) struct Scrubber {
+    data_dir: PathBuf,
+    rate_bytes_per_sec: u64,
+    pass_interval: Duration,
+    stats: Arc<ScrubStats>,
+    overload: Arc<Overload>,
+}
+
+impl Scrubber {
+    pub(crate) fn new(
+        data_dir: &Path,
+        rate_mb_per_sec: f64,
+        pass_interval: Duration,
+        stats: Arc<ScrubStats>,
+        overload: Arc<Overload>,
+    ) -> Self {
+        Self {
+            data_dir: data_dir.to_path_buf(),
+            rate_bytes_per_sec: ((rate_mb_per_sec * 1024.0 * 1024.0) as u64).max(1),
+            pass_interval,
+            stats,
+            overload,
+        }
+    }
+
+    /// Run the scrubber on a dedicated low-priority thread
+    pub(crate) fn spawn(self) {
+        std::thread::Builder::new()
+            .name("scrubber".to_string())
+            .spawn(move || loop {
+                let start = Instant::now();
+                match self.scrub_pass() {
+                    Ok(()) => {
+                        self.stats.passes.fetch_add(1, Ordering::Relaxed);
+                        self.stats
+                            .last_pass_secs
+                            .store(start.elapsed().as_secs(), Ordering::Relaxed);
+                        info!("Scrubber: pass finished in {:?}", start.elapsed());
+                    }
+                    Err(e) => warn!("Scrubber: pass aborted: {}", e),
+                }
+                std::thread::sleep(self.pass_interval);
+            })
+            .expect("Could not spawn scrubber thread");
+    }
+
+    fn scrub_pass(&self) -> std::io::Result<()> {
+        for (path, _) in keyspace::data_files(&self.data_dir)? {
+            match self.scrub_file(&path) {
+                // a compaction moved the storage since the files were listed
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    fn scrub_file(&self, path: &Path) -> std::io::Result<()> {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0u64;
+        let mut budget_start = Instant::now();
+        let mut budget_bytes = 0u64;
+        loop {
+            let mut header = [0u8; HEADER_SIZE as usize];
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                // end of file, or a partially appended entry at the tail of the active file
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let check_sum = u32::from_be_bytes(header[0..4].try_into().unwrap());
+            let key_size = u64::from_be_bytes(header[4..12].try_into().unwrap());
+            let value_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+            let remaining = file_size.saturating_sub(offset + HEADER_SIZE);
+            if key_size.saturating_add(value_size) > remaining {
+                // a corrupt header makes the rest of the file unparseable, nothing left to resync on
+                self.stats.corrupt_entries.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Scrubber: corrupt entry header in {} at offset {}, skipping the rest of the file",
+                    path.display(),
+                    offset
+                );
+                return Ok(());
+            }
+            let mut key = vec![0u8; key_size as usize];
+            let mut value = vec![0u8; value_size as usize];
+            match reader
+                .read_exact(&mut key)
+                .and_then(|_| reader.read_exact(&mut value))
+            {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            // tombstones carry no value and a zero checksum
+            if value_size > 0 && CRC32.checksum(&value) != check_sum {
+                self.stats.corrupt_entries.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Scrubber: corrupt entry for key {:?} in {} at offset {}",
+                    String::from_utf8_lossy(&key),
+                    path.display(),
+                    offset
+                );
+            }
+            let entry_size = HEADER_SIZE + key_size + value_size;
+            offset += entry_size;
+            self.stats.entries_scanned.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .bytes_scanned
+                .fetch_add(entry_size, Ordering::Relaxed);
+
+            // pace the reads: sleep until the bytes read so far fit in the configured rate
+            budget_bytes += entry_size;
+            let expected =
+                Duration::from_secs_f64(budget_bytes as f64 / self.rate_bytes_per_sec as f64);
+            let elapsed = budget_start.elapsed();
+            if expected > elapsed {
+                std::thread::sleep(expected - elapsed);
+            }
+            // foreground traffic comes first while the node sheds load
+            while self.overload.shedding() {
+                std::thread::sleep(Duration::from_millis(100));
+                budget_start = Instant::now();
+                budget_bytes = 0;
+            }
+            if budget_start.elapsed() > Duration::from_secs(1) {
+                budget_start = Instant::now();
+                budget_bytes = 0;
+            }
+        }
+    }
+}
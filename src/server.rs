@@ -1,15 +1,34 @@
 use crate::cli::Args;
-use crate::cmd::InnerCmd;
+use crate::cmd::{InnerCmd, NodeContext};
 use crate::connection;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::health::Probes;
+use crate::http;
+use crate::scrubber::ScrubStats;
+use crate::slo::SloTracker;
 use crate::sync_layer::SyncRequest;
+use crate::tls;
 use bitcask_engine_rs::bitcask::BitCask;
+use socket2::{Domain, Socket, Type};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tracing::warn;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
 
 pub(crate) struct Server {
     args: Args,
     sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
     storage: BitCask,
+    slo: Arc<SloTracker>,
+    scrub_stats: Arc<ScrubStats>,
+    context: NodeContext,
+    // the handshakes of the --tls-addr listeners, None without --tls-cert
+    tls: Option<TlsAcceptor>,
 }
 
 impl Server {
@@ -17,30 +36,250 @@ impl Server {
         args: Args,
         sync_request_tx: mpsc::Sender<SyncRequest<InnerCmd>>,
         storage: BitCask,
+        scrub_stats: Arc<ScrubStats>,
+        context: NodeContext,
+        tls: Option<TlsAcceptor>,
     ) -> Self {
+        let slo = Arc::new(SloTracker::new(
+            args.slo_window_secs(),
+            args.slo_write_error_threshold(),
+        ));
         Self {
             args,
             sync_request_tx,
             storage,
+            slo,
+            scrub_stats,
+            context,
+            tls,
         }
     }
 
     pub(crate) async fn run(&mut self) {
-        let listener = tokio::net::TcpListener::bind(self.args.kv_addr())
-            .await
-            .unwrap();
+        let mut listeners = Vec::new();
+        for addr in self.args.kv_addr() {
+            listeners.push(bind(&addr).await.unwrap());
+        }
+        let mut tls_listeners = Vec::new();
+        for addr in self.args.tls_addr() {
+            tls_listeners.push(bind(addr).await.unwrap());
+        }
+        let analytics = match self.args.analytics_addr() {
+            Some(addr) => Some(bind(addr).await.unwrap()),
+            None => None,
+        };
+        #[cfg(feature = "grpc")]
+        let grpc = match self.args.grpc_addr() {
+            Some(addr) => Some(bind(addr).await.unwrap()),
+            None => None,
+        };
+        let http = match self.args.http_addr() {
+            Some(addr) => Some(bind(addr).await.unwrap()),
+            None => None,
+        };
+        futures::future::join4(
+            futures::future::join(
+                futures::future::join_all(listeners.into_iter().map(|listener| self.serve(listener))),
+                futures::future::join_all(
+                    tls_listeners
+                        .into_iter()
+                        .map(|listener| self.serve_tls(listener)),
+                ),
+            ),
+            async {
+                if let Some(listener) = analytics {
+                    self.serve_analytics(listener).await;
+                }
+            },
+            async {
+                #[cfg(feature = "grpc")]
+                if let Some(listener) = grpc {
+                    let (sync_request_tx, storage, context) = (
+                        self.sync_request_tx.clone(),
+                        self.storage.clone(),
+                        self.context.clone(),
+                    );
+                    grpc::serve(listener, &self.args, sync_request_tx, storage, context).await;
+                }
+            },
+            async {
+                if let Some(listener) = http {
+                    self.serve_http(listener).await;
+                }
+            },
+        )
+        .await;
+    }
+
+    async fn serve(&self, listener: TcpListener) {
         loop {
             let (socket, peer_addr) = listener.accept().await.unwrap();
             let mut connection = connection::Connection::new(
                 socket,
+                peer_addr,
                 self.storage.clone(),
                 self.sync_request_tx.clone(),
+                self.slo.clone(),
+                self.scrub_stats.clone(),
+                self.context.clone(),
+            )
+            .limits(self.args.proto_limits())
+            .max_in_flight_writes(self.args.max_in_flight_writes());
+            tokio::spawn(async move {
+                connection.handle(peer_addr).await.unwrap_or_else(|e| {
+                    warn!("Connection {} error: {}", peer_addr, e);
+                });
+            });
+        }
+    }
+
+    /// Serve the clients of a --tls-addr listener, each once its handshake completes
+    async fn serve_tls(&self, listener: TcpListener) {
+        let Some(acceptor) = &self.tls else {
+            return;
+        };
+        loop {
+            let (socket, peer_addr) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let (storage, sync_request_tx, slo, scrub_stats, context) = (
+                self.storage.clone(),
+                self.sync_request_tx.clone(),
+                self.slo.clone(),
+                self.scrub_stats.clone(),
+                self.context.clone(),
             );
+            let (limits, max_in_flight_writes) =
+                (self.args.proto_limits(), self.args.max_in_flight_writes());
+            // a client slow to complete its handshake holds up no other
             tokio::spawn(async move {
+                let stream = match timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => return warn!("TLS handshake with {} failed: {}", peer_addr, e),
+                    Err(_) => return warn!("TLS handshake with {} timed out", peer_addr),
+                };
+                let mut connection = connection::Connection::new(
+                    stream,
+                    peer_addr,
+                    storage,
+                    sync_request_tx,
+                    slo,
+                    scrub_stats,
+                    context,
+                )
+                .limits(limits)
+                .max_in_flight_writes(max_in_flight_writes);
                 connection.handle(peer_addr).await.unwrap_or_else(|e| {
                     warn!("Connection {} error: {}", peer_addr, e);
                 });
             });
         }
     }
+
+    /// Serve the HTTP gateway, each request over an in-memory connection of its own
+    async fn serve_http(&self, listener: TcpListener) {
+        let limits = self.args.proto_limits();
+        let probes = Arc::new(Probes::new(
+            self.sync_request_tx.clone(),
+            self.context.clone(),
+            Duration::from_millis(self.args.ready_timeout_ms()),
+            self.args.ready_max_apply_lag(),
+        ));
+        loop {
+            let (socket, peer_addr) = listener.accept().await.unwrap();
+            let (storage, sync_request_tx, slo, scrub_stats, context) = (
+                self.storage.clone(),
+                self.sync_request_tx.clone(),
+                self.slo.clone(),
+                self.scrub_stats.clone(),
+                self.context.clone(),
+            );
+            let connect = move || {
+                let (stream, local) = tokio::io::duplex(64 * 1024);
+                let connection = connection::Connection::new(
+                    local,
+                    peer_addr,
+                    storage.clone(),
+                    sync_request_tx.clone(),
+                    slo.clone(),
+                    scrub_stats.clone(),
+                    context.clone(),
+                )
+                .limits(limits);
+                tokio::spawn(connection::serve_local(connection));
+                stream
+            };
+            let probes = probes.clone();
+            tokio::spawn(async move {
+                if let Err(e) = http::handle(socket, limits.max_bulk_len, &probes, connect).await {
+                    warn!("HTTP connection {} error: {}", peer_addr, e);
+                }
+            });
+        }
+    }
+
+    /// Serve the snapshot taken for analytics read-only, each connection until it is released
+    async fn serve_analytics(&self, listener: TcpListener) {
+        let Some(analytics) = &self.context.analytics else {
+            return;
+        };
+        // scans are slow, they are kept out of the SLO of the live traffic
+        let slo = Arc::new(SloTracker::new(self.args.slo_window_secs(), None));
+        loop {
+            let (mut socket, peer_addr) = listener.accept().await.unwrap();
+            let Some(frozen) = analytics.current() else {
+                let _ = socket
+                    .write_all(b"-ERR no snapshot to serve, take one with SNAPSHOT TAKE\r\n")
+                    .await;
+                continue;
+            };
+            // the reads of the snapshot must not go through the caches of the live keyspace
+            let context = NodeContext {
+                data_dir: frozen.dir.clone(),
+                read_cache: None,
+                negative_cache: None,
+                ..self.context.clone()
+            };
+            let mut connection = connection::Connection::new(
+                socket,
+                peer_addr,
+                frozen.storage.clone(),
+                self.sync_request_tx.clone(),
+                slo.clone(),
+                self.scrub_stats.clone(),
+                context,
+            )
+            .read_only();
+            tokio::spawn(async move {
+                tokio::select! {
+                    result = connection.handle(peer_addr) => result.unwrap_or_else(|e| {
+                        warn!("Analytics connection {} error: {}", peer_addr, e);
+                    }),
+                    _ = frozen.closed() => {
+                        info!("Closing analytics connection {}, its snapshot was released", peer_addr);
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Bind a listener to the first address the host resolves to. IPv6 listeners only accept IPv6,
+/// so that `0.0.0.0` and `[::]` can both be bound on the same port.
+async fn bind(addr: &str) -> std::io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} does not resolve", addr),
+        )
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    info!("Listening on {}", addr);
+    TcpListener::from_std(socket.into())
 }
@@ -0,0 +1,141 @@
+//! Secure delete, for the keys under the prefixes given with `--secure-delete-prefix`, such as the
+//! personal data an erasure request covers. Their commands are kept out of CLIENT HISTORY, the
+//! slow log, MONITOR, RAFT LOG and the logs of the node, and the values they no longer hold are
+//! shredded: the storage only appends, so a deleted or overwritten value stays in the data files
+//! until a pass overwrites it with zeros in place, with the checksum of the zeros so the scrubber
+//! still finds the entry sound. bitcask-engine-rs is never compacted by the node, so the pass runs
+//! every `--secure-delete-interval-secs` instead.
+
+use crate::cmd::InnerCmd;
+use crate::database;
+use crate::keyspace::Snapshot;
+use crc::{Crc, CRC_32_CKSUM};
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// A command in the logs, by its name alone when it reads or writes a key under secure delete, as
+/// ACL SETUSER keeps its rules out of them
+pub(crate) struct Logged<'a> {
+    inner_cmd: &'a InnerCmd,
+    hidden: bool,
+}
+
+impl<'a> Logged<'a> {
+    pub(crate) fn new(inner_cmd: &'a InnerCmd, hidden: bool) -> Self {
+        Self { inner_cmd, hidden }
+    }
+}
+
+impl Debug for Logged<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.hidden {
+            write!(f, "{} ...", self.inner_cmd.name().to_uppercase())
+        } else {
+            write!(f, "{:?}", self.inner_cmd)
+        }
+    }
+}
+
+/// Same checksum as the one bitcask-engine-rs stores in front of every entry
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+pub(crate) struct SecureDelete {
+    prefixes: Vec<Vec<u8>>,
+}
+
+impl SecureDelete {
+    pub(crate) fn new(prefixes: &[String]) -> Self {
+        Self {
+            prefixes: prefixes
+                .iter()
+                .map(|prefix| prefix.as_bytes().to_vec())
+                .collect(),
+        }
+    }
+
+    /// Whether the key is under a prefix, in any database
+    pub(crate) fn covers(&self, key: &[u8]) -> bool {
+        let key = database::split(key).map_or(key, |(_, key)| key);
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+
+    /// Whether the command reads or writes a key under secure delete, and is kept out of history
+    pub(crate) fn hides(&self, inner_cmd: &InnerCmd) -> bool {
+        !self.prefixes.is_empty() && inner_cmd.keys().iter().any(|key| self.covers(key))
+    }
+
+    /// The command as the logs show it
+    pub(crate) fn logged<'a>(&self, inner_cmd: &'a InnerCmd) -> Logged<'a> {
+        Logged::new(inner_cmd, self.hides(inner_cmd))
+    }
+
+    /// Shred on a dedicated thread, a pass per interval, unless no prefix is under secure delete
+    pub(crate) fn spawn(self: Arc<Self>, data_dir: &Path, interval: Duration) {
+        if self.prefixes.is_empty() {
+            return;
+        }
+        let data_dir = data_dir.to_path_buf();
+        std::thread::Builder::new()
+            .name("shredder".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                match self.shred(&data_dir) {
+                    Ok(0) => {}
+                    Ok(shredded) => info!("Shredder: {} values overwritten", shredded),
+                    Err(e) => warn!("Shredder: pass aborted: {}", e),
+                }
+            })
+            .expect("Could not spawn shredder thread");
+    }
+
+    /// Overwrite the values the keys under secure delete no longer hold, returning how many were
+    fn shred(&self, data_dir: &Path) -> std::io::Result<u64> {
+        let mut shredded = 0;
+        for (path, values) in Snapshot::take(data_dir)?.superseded(|key| self.covers(key))? {
+            let file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let mut written = false;
+            for value in values {
+                let mut bytes = vec![0u8; value.size as usize];
+                file.read_exact_at(&mut bytes, value.offset)?;
+                // shredded by an earlier pass
+                if bytes.iter().all(|byte| *byte == 0) {
+                    continue;
+                }
+                bytes.fill(0);
+                file.write_all_at(&bytes, value.offset)?;
+                file.write_all_at(&CRC32.checksum(&bytes).to_be_bytes(), value.entry)?;
+                written = true;
+                shredded += 1;
+            }
+            if written {
+                file.sync_data()?;
+            }
+        }
+        Ok(shredded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logged_commands_keep_secure_delete_values_out() {
+        let secure_delete = SecureDelete::new(&["pii:".to_string()]);
+        let hidden = InnerCmd::Put([0; 16], b"pii:42".to_vec(), b"secret".to_vec(), None);
+        assert_eq!(format!("{:?}", secure_delete.logged(&hidden)), "SET ...");
+        let hidden = InnerCmd::SetGet([0; 16], b"pii:42".to_vec(), b"secret".to_vec(), None);
+        assert_eq!(format!("{:?}", secure_delete.logged(&hidden)), "SET ...");
+        let shown = InnerCmd::Put([0; 16], b"k".to_vec(), b"v".to_vec(), None);
+        assert_eq!(
+            format!("{:?}", secure_delete.logged(&shown)),
+            format!("{:?}", shown)
+        );
+        assert!(!SecureDelete::new(&[]).hides(&hidden));
+    }
+}
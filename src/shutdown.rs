@@ -0,0 +1,68 @@
+//! Graceful shutdown, on SIGINT or SIGTERM or on SHUTDOWN from a client. The node stops
+//! accepting connections, saves the cumulative statistics unless told not to, and exits. The
+//! storage writes every entry as it is applied, so there is no dataset to save.
+
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// What SHUTDOWN saves before exiting
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum SaveMode {
+    Default,
+    Save,
+    NoSave,
+}
+
+pub(crate) struct Shutdown {
+    // whether to save, once requested
+    requested: watch::Sender<Option<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            requested: watch::channel(None).0,
+        }
+    }
+}
+
+impl Shutdown {
+    pub(crate) fn request(&self, mode: SaveMode) {
+        info!("Shutdown requested, {:?}", mode);
+        let save = !matches!(mode, SaveMode::NoSave);
+        // the first request wins
+        self.requested.send_if_modified(|requested| {
+            if requested.is_none() {
+                *requested = Some(save);
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Wait for a shutdown request, returning whether to save
+    pub(crate) async fn requested(&self) -> bool {
+        let mut requested = self.requested.subscribe();
+        let save = requested.wait_for(Option::is_some).await;
+        save.map_or(true, |save| save.unwrap_or(true))
+    }
+
+    /// Request a shutdown on SIGINT or SIGTERM
+    pub(crate) async fn on_signal(&self) {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("Shutdown: could not listen for SIGTERM: {}", e);
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        self.request(SaveMode::Default);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::bloom::hash;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Sketch dimensions used by TOPK.RESERVE when only `topk` is given
+pub(crate) const DEFAULT_TOPK_WIDTH: u32 = 2000;
+pub(crate) const DEFAULT_TOPK_DEPTH: u32 = 5;
+
+/// Count-min sketch: `depth` rows of `width` counters, an item is counted once per row and
+/// its estimate is the smallest of its counters. Estimates never undercount.
+#[derive(Serialize, Deserialize)]
+struct CountMinSketch {
+    width: u32,
+    depth: u32,
+    counters: Vec<u64>,
+}
+
+impl CountMinSketch {
+    fn new(width: u32, depth: u32) -> Self {
+        Self {
+            width,
+            depth,
+            counters: vec![0; width as usize * depth as usize],
+        }
+    }
+
+    /// Counter of the item in each row, by double hashing
+    fn indexes(&self, item: &[u8]) -> impl Iterator<Item = usize> + '_ {
+        let h1 = hash(item, 0x9e37_79b9_7f4a_7c15);
+        let h2 = hash(item, 0x7f4a_7c15_9e37_79b9) | 1;
+        (0..self.depth as u64).map(move |row| {
+            let col = h1.wrapping_add(row.wrapping_mul(h2)) % self.width as u64;
+            (row * self.width as u64 + col) as usize
+        })
+    }
+
+    /// Add `increment` to the item and return its new estimate
+    fn incr(&mut self, item: &[u8], increment: u64) -> u64 {
+        let indexes: Vec<usize> = self.indexes(item).collect();
+        let mut estimate = u64::MAX;
+        for index in indexes {
+            let counter = &mut self.counters[index];
+            *counter = counter.saturating_add(increment);
+            estimate = estimate.min(*counter);
+        }
+        estimate
+    }
+
+    fn query(&self, item: &[u8]) -> u64 {
+        self.indexes(item)
+            .map(|index| self.counters[index])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Top-K heavy hitters: a count-min sketch estimates the frequencies and the `k` items with
+/// the highest estimates are kept, highest first. Unlike RedisBloom's HeavyKeeper there is no
+/// random decay, so every replica computes the same list from the same log.
+#[derive(Serialize, Deserialize)]
+struct TopK {
+    k: u32,
+    sketch: CountMinSketch,
+    top: Vec<(u64, Vec<u8>)>,
+}
+
+impl TopK {
+    /// Count the item, returning the item expelled from the list to make room for it, if any
+    fn add(&mut self, item: &[u8]) -> Option<Vec<u8>> {
+        let count = self.sketch.incr(item, 1);
+        let mut expelled = None;
+        if let Some(entry) = self.top.iter_mut().find(|(_, i)| i == item) {
+            entry.0 = count;
+        } else if self.top.len() < self.k as usize {
+            self.top.push((count, item.to_vec()));
+        } else if self.top.last().is_some_and(|(min, _)| count > *min) {
+            expelled = self.top.pop().map(|(_, item)| item);
+            self.top.push((count, item.to_vec()));
+        }
+        self.top
+            .sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        expelled
+    }
+}
+
+/// The sketch with the deadline it expires at, if any
+fn load<T: DeserializeOwned>(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    value_type: ValueType,
+    kind: &str,
+    now: u64,
+) -> Result<(T, Option<u64>), BitCaskError> {
+    // an expired value is no longer the key's, whatever its type
+    match storage.get(key).filter(|raw| !value::expired(raw, now)) {
+        Some(raw) => bincode::deserialize::<T>(value::expect(&raw, value_type)?)
+            .map(|sketch| (sketch, value::deadline(&raw)))
+            .map_err(|_| BitCaskError::CorruptedData(format!("value is not a {}", kind))),
+        // RedisBloom does not create sketches implicitly
+        None => Err(BitCaskError::UnexpectedError(anyhow::anyhow!(
+            "{}: key does not exist",
+            kind
+        ))),
+    }
+}
+
+fn create<T: Serialize>(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    value_type: ValueType,
+    kind: &str,
+    value: &T,
+    now: u64,
+) -> Result<(), BitCaskError> {
+    if storage
+        .get(key)
+        .is_some_and(|raw| !value::expired(&raw, now))
+    {
+        return Err(BitCaskError::UnexpectedError(anyhow::anyhow!(
+            "{}: key already exists",
+            kind
+        )));
+    }
+    store(storage, key, value_type, value, None)
+}
+
+/// Store the sketch, keeping the deadline it was loaded with
+fn store<T: Serialize>(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    value_type: ValueType,
+    value: &T,
+    deadline: Option<u64>,
+) -> Result<(), BitCaskError> {
+    let payload = bincode::serialize(value).map_err(|e| BitCaskError::UnexpectedError(e.into()))?;
+    storage.put(key, &value::encode_expiring(value_type, &payload, deadline))
+}
+
+pub(crate) fn cms_init(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    width: u32,
+    depth: u32,
+    now: u64,
+) -> Result<(), BitCaskError> {
+    create(
+        storage,
+        key,
+        ValueType::Cms,
+        "CMS",
+        &CountMinSketch::new(width, depth),
+        now,
+    )
+}
+
+/// Increase the count of each item, returning the new estimates
+pub(crate) fn cms_incr_by(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    increments: &[(Vec<u8>, u64)],
+    now: u64,
+) -> Result<Vec<u64>, BitCaskError> {
+    let (mut sketch, deadline): (CountMinSketch, _) =
+        load(storage, key, ValueType::Cms, "CMS", now)?;
+    let estimates = increments
+        .iter()
+        .map(|(item, increment)| sketch.incr(item, *increment))
+        .collect();
+    store(storage, key, ValueType::Cms, &sketch, deadline)?;
+    Ok(estimates)
+}
+
+pub(crate) fn cms_query(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    items: &[Vec<u8>],
+    now: u64,
+) -> Result<Vec<u64>, BitCaskError> {
+    let (sketch, _): (CountMinSketch, _) = load(storage, key, ValueType::Cms, "CMS", now)?;
+    Ok(items.iter().map(|item| sketch.query(item)).collect())
+}
+
+pub(crate) fn topk_reserve(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    k: u32,
+    width: u32,
+    depth: u32,
+    now: u64,
+) -> Result<(), BitCaskError> {
+    let topk = TopK {
+        k,
+        sketch: CountMinSketch::new(width, depth),
+        top: Vec::new(),
+    };
+    create(storage, key, ValueType::TopK, "TopK", &topk, now)
+}
+
+/// Count each item, returning for each the item it expelled from the list, if any
+pub(crate) fn topk_add(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    items: &[Vec<u8>],
+    now: u64,
+) -> Result<Vec<Option<Vec<u8>>>, BitCaskError> {
+    let (mut topk, deadline): (TopK, _) = load(storage, key, ValueType::TopK, "TopK", now)?;
+    let expelled = items.iter().map(|item| topk.add(item)).collect();
+    store(storage, key, ValueType::TopK, &topk, deadline)?;
+    Ok(expelled)
+}
+
+/// The current top items with their estimated counts, highest first
+pub(crate) fn topk_list(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    now: u64,
+) -> Result<Vec<(u64, Vec<u8>)>, BitCaskError> {
+    let (topk, _): (TopK, _) = load(storage, key, ValueType::TopK, "TopK", now)?;
+    Ok(topk.top)
+}
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Minimum number of writes in the window before the error-rate alert may fire,
+/// so a single failed write on an idle node does not page anyone.
+const MIN_ALERT_SAMPLES: u64 = 20;
+/// Minimum time between two consecutive alerts.
+const ALERT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CommandFamily {
+    Read,
+    Write,
+    Other,
+}
+
+impl CommandFamily {
+    const ALL: [CommandFamily; 3] = [
+        CommandFamily::Read,
+        CommandFamily::Write,
+        CommandFamily::Other,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CommandFamily::Read => "read",
+            CommandFamily::Write => "write",
+            CommandFamily::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Success,
+    Error,
+    Timeout,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+struct Counts {
+    success: u64,
+    error: u64,
+    timeout: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.success += other.success;
+        self.error += other.error;
+        self.timeout += other.timeout;
+    }
+
+    fn total(&self) -> u64 {
+        self.success + self.error + self.timeout
+    }
+
+    /// Errors and timeouts both burn the error budget
+    fn error_rate(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => (self.error + self.timeout) as f64 / total as f64,
+        }
+    }
+}
+
+/// One bucket per second of the window, counting the outcomes of each command family
+#[derive(Default)]
+struct Bucket {
+    second: AtomicU64,
+    counts: [[AtomicU64; 3]; 3],
+}
+
+impl Bucket {
+    fn counts(&self, family: CommandFamily) -> Counts {
+        let [success, error, timeout] = &self.counts[family as usize];
+        Counts {
+            success: success.load(Ordering::Relaxed),
+            error: error.load(Ordering::Relaxed),
+            timeout: timeout.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Tracks success, error and timeout counts per command family over a sliding window.
+///
+/// Every command records its outcome, so the counts are atomics: a lock is only taken to recycle
+/// the bucket of an expired second, once per second, and to raise an alert.
+pub(crate) struct SloTracker {
+    start: Instant,
+    window_secs: u64,
+    write_error_threshold: Option<f64>,
+    buckets: Vec<Bucket>,
+    recycling: Mutex<()>,
+    last_alert: Mutex<Option<Instant>>,
+}
+
+impl SloTracker {
+    pub(crate) fn new(window_secs: u64, write_error_threshold: Option<f64>) -> Self {
+        let window_secs = window_secs.max(1);
+        Self {
+            start: Instant::now(),
+            window_secs,
+            write_error_threshold,
+            buckets: (0..window_secs).map(|_| Bucket::default()).collect(),
+            recycling: Mutex::new(()),
+            last_alert: Mutex::new(None),
+        }
+    }
+
+    fn now_second(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    pub(crate) fn record(&self, family: CommandFamily, outcome: Outcome) {
+        let second = self.now_second();
+        let bucket = &self.buckets[(second % self.window_secs) as usize];
+        if bucket.second.load(Ordering::Acquire) != second {
+            let _recycling = self.recycling.lock().unwrap();
+            // the bucket belongs to an expired second, unless another command recycled it since
+            if bucket.second.load(Ordering::Acquire) != second {
+                for count in bucket.counts.iter().flatten() {
+                    count.store(0, Ordering::Relaxed);
+                }
+                bucket.second.store(second, Ordering::Release);
+            }
+        }
+        bucket.counts[family as usize][outcome as usize].fetch_add(1, Ordering::Relaxed);
+        if family == CommandFamily::Write && outcome != Outcome::Success {
+            self.maybe_alert(second);
+        }
+    }
+
+    fn sum(&self, second: u64, family: CommandFamily) -> Counts {
+        let mut total = Counts::default();
+        for bucket in &self.buckets {
+            // a bucket may have been recycled for a second after `second` meanwhile
+            if second.saturating_sub(bucket.second.load(Ordering::Acquire)) < self.window_secs {
+                total.add(&bucket.counts(family));
+            }
+        }
+        total
+    }
+
+    fn maybe_alert(&self, second: u64) {
+        let threshold = match self.write_error_threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let counts = self.sum(second, CommandFamily::Write);
+        if counts.total() < MIN_ALERT_SAMPLES || counts.error_rate() <= threshold {
+            return;
+        }
+        let mut last_alert = self.last_alert.lock().unwrap();
+        if last_alert.is_some_and(|last_alert| last_alert.elapsed() < ALERT_INTERVAL) {
+            return;
+        }
+        *last_alert = Some(Instant::now());
+        warn!(
+            "SLO: write error rate {:.4} over the last {}s exceeds threshold {:.4} ({} errors, {} timeouts, {} total)",
+            counts.error_rate(),
+            self.window_secs,
+            threshold,
+            counts.error,
+            counts.timeout,
+            counts.total()
+        );
+    }
+
+    /// Render the `slo` section of INFO
+    pub(crate) fn info(&self) -> String {
+        let second = self.now_second();
+        let mut out = String::new();
+        let _ = write!(out, "slo_window_seconds:{}\r\n", self.window_secs);
+        for family in CommandFamily::ALL {
+            let counts = self.sum(second, family);
+            let _ = write!(
+                out,
+                "slo_{}:success={},error={},timeout={},error_rate={:.4}\r\n",
+                family.name(),
+                counts.success,
+                counts.error,
+                counts.timeout,
+                counts.error_rate()
+            );
+        }
+        out
+    }
+}
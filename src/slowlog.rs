@@ -0,0 +1,141 @@
+//! The slow log: the latest commands whose handling took longer than `slowlog-log-slower-than`,
+//! from the moment the command is read to the moment its reply is ready, which includes the Raft
+//! round trip of writes. Kept in memory, the oldest entries make room past `slowlog-max-len`.
+
+use crate::clients::{Client, ClientId};
+use crate::config::Config;
+use crate::monitor;
+use crate::resp_codec::RespValue;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// like Redis, only the beginning of long commands is kept
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+/// A SLOWLOG subcommand
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum SlowLogOp {
+    // Count of entries, all of them if negative
+    Get(i64),
+    Len,
+    Reset,
+}
+
+struct Entry {
+    id: u64,
+    // unix time the command was logged at, in seconds
+    timestamp: u64,
+    duration: Duration,
+    args: Vec<Bytes>,
+    addr: String,
+    name: String,
+    client_id: ClientId,
+}
+
+#[derive(Default)]
+pub(crate) struct SlowLog {
+    // newest first
+    entries: Mutex<VecDeque<Entry>>,
+    // ids keep growing across resets
+    next_id: AtomicU64,
+}
+
+impl SlowLog {
+    /// Log the command, as received, if it took longer than the threshold of the config
+    pub(crate) fn record(
+        &self,
+        config: &Config,
+        duration: Duration,
+        frame: &RespValue,
+        client: &Client,
+    ) {
+        if config
+            .slowlog_log_slower_than()
+            .is_none_or(|threshold| duration < threshold)
+        {
+            return;
+        }
+        let entry = Entry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration,
+            args: args(frame),
+            addr: client.addr().to_string(),
+            name: client.name(),
+            client_id: client.id,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front(entry);
+        entries.truncate(config.slowlog_max_len());
+    }
+
+    /// The reply to SLOWLOG GET, the newest entries first
+    pub(crate) fn get(&self, count: i64) -> RespValue {
+        let entries = self.entries.lock().unwrap();
+        let count = usize::try_from(count).unwrap_or(entries.len());
+        RespValue::Array(
+            entries
+                .iter()
+                .take(count)
+                .map(|entry| {
+                    RespValue::Array(vec![
+                        RespValue::Integer(entry.id as i64),
+                        RespValue::Integer(entry.timestamp as i64),
+                        RespValue::Integer(entry.duration.as_micros() as i64),
+                        RespValue::Array(
+                            entry
+                                .args
+                                .iter()
+                                .map(|arg| RespValue::BulkString(Some(arg.clone())))
+                                .collect(),
+                        ),
+                        RespValue::BulkString(Some(entry.addr.clone().into())),
+                        RespValue::BulkString(Some(entry.name.clone().into())),
+                        // past the fields of Redis, which its clients read up to
+                        RespValue::Integer(entry.client_id as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub(crate) fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// The arguments of a command as the slow log keeps them: the first ones, each shortened, and
+/// none of those of the commands carrying passwords
+fn args(frame: &RespValue) -> Vec<Bytes> {
+    let mut args = monitor::args(frame);
+    if args.len() > MAX_ARGS {
+        let more = args.len() - MAX_ARGS + 1;
+        args.truncate(MAX_ARGS - 1);
+        args.push(Bytes::from(format!("... ({} more arguments)", more)));
+    }
+    args.into_iter()
+        .map(|arg| {
+            if arg.len() > MAX_ARG_LEN {
+                let mut short = arg[..MAX_ARG_LEN].to_vec();
+                short.extend_from_slice(
+                    format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).as_bytes(),
+                );
+                Bytes::from(short)
+            } else {
+                arg
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,69 @@
+//! CLIENT TRACE: where the latency of a command goes, for the application developers wondering.
+//! Once a connection turns it on, each reply comes with a RESP3 attribute telling how long the
+//! command spent in each stage, in microseconds: `parse`, then for the writes and the reads
+//! replicated first `queue` until the sync layer takes the request, `propose` until its batch is
+//! handed to raft-lite, `commit` until the entry comes back committed and `apply`, then `read` for
+//! the reads, and `reply` until the reply is ready, after the replies to the earlier commands.
+
+use crate::resp_codec::RespValue;
+use bytes::Bytes;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Marks {
+    // end of the last stage
+    last: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+/// The stages a traced command went through so far, shared with the sync layer
+#[derive(Clone)]
+pub(crate) struct Stages(Arc<Mutex<Marks>>);
+
+impl Stages {
+    /// The stages of a command received at `started`
+    pub(crate) fn start(started: Instant) -> Self {
+        Self(Arc::new(Mutex::new(Marks {
+            last: started,
+            stages: Vec::new(),
+        })))
+    }
+
+    /// End the current stage
+    pub(crate) fn mark(&self, stage: &'static str) {
+        let mut marks = self.0.lock().unwrap();
+        let now = Instant::now();
+        let duration = now.duration_since(marks.last);
+        marks.stages.push((stage, duration));
+        marks.last = now;
+    }
+
+    /// End the `reply` stage, and attach the stages to the reply
+    pub(crate) fn attach(&self, msg: RespValue) -> RespValue {
+        self.mark("reply");
+        let field =
+            |name: &'static str| RespValue::BulkString(Some(Bytes::from_static(name.as_bytes())));
+        let stages = self
+            .0
+            .lock()
+            .unwrap()
+            .stages
+            .iter()
+            .map(|(stage, duration)| {
+                (
+                    field(stage),
+                    RespValue::Integer(duration.as_micros() as i64),
+                )
+            })
+            .collect();
+        let trace = (field("trace"), RespValue::Map(stages));
+        match msg {
+            // alongside the other attributes of the reply
+            RespValue::Attribute(mut attributes, msg) => {
+                attributes.push(trace);
+                RespValue::Attribute(attributes, msg)
+            }
+            msg => RespValue::Attribute(vec![trace], Box::new(msg)),
+        }
+    }
+}
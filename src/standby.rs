@@ -0,0 +1,58 @@
+//! Warm standby: a node started with --standby replicates and applies every entry as any member
+//! of the group does, or as a read replica with --replica-of, but refuses the commands of the
+//! clients. PROMOTE turns it into serving them at once, since it already holds what the others
+//! do, for blue/green replacements of a node and maintenance windows.
+//!
+//! The admin commands and those of the connection itself, such as AUTH, PING or INFO, are
+//! served throughout, so that the standby can be watched and promoted, and its peers reach it.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Replied to the commands of the clients refused by a standby
+pub(crate) const STANDBY: &str = "STANDBY the node is a warm standby, PROMOTE it to serve clients";
+
+#[derive(Default)]
+pub(crate) struct Standby {
+    active: AtomicBool,
+    // unix time in milliseconds, 0 until promoted
+    promoted_at: AtomicU64,
+}
+
+impl Standby {
+    pub(crate) fn new(active: bool) -> Self {
+        Self {
+            active: AtomicBool::new(active),
+            promoted_at: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the node refuses the commands of the clients
+    pub(crate) fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Serve the clients from now on, returning whether the node was a standby
+    pub(crate) fn promote(&self) -> bool {
+        if !self.active.swap(false, Ordering::Relaxed) {
+            return false;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.promoted_at.store(now, Ordering::Relaxed);
+        info!("Promoted from standby, serving clients");
+        true
+    }
+
+    /// Render the standby fields of INFO replication
+    pub(crate) fn info(&self) -> String {
+        format!(
+            "standby:{}\r\npromoted_at:{}\r\n",
+            self.is_active() as u8,
+            self.promoted_at.load(Ordering::Relaxed)
+        )
+    }
+}
@@ -0,0 +1,109 @@
+//! Cumulative statistics of the node, reported in the stats section of INFO. They are saved to
+//! a file every few seconds and loaded back on start, so that they survive restarts; what was
+//! counted since the last save is lost if the node crashes.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub(crate) struct Stats {
+    connections_received: AtomicU64,
+    commands_processed: AtomicU64,
+    net_input_bytes: AtomicU64,
+    net_output_bytes: AtomicU64,
+    writes_skipped_unchanged: AtomicU64,
+}
+
+impl Stats {
+    /// The statistics saved to the file, from zero if there is none yet
+    pub(crate) fn load(path: &Path) -> std::io::Result<Self> {
+        let stats = Self::default();
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(stats),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines() {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let Ok(value) = value.parse() else {
+                continue;
+            };
+            if let Some(counter) = stats.counters().find(|(known, _)| *known == name) {
+                counter.1.store(value, Ordering::Relaxed);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Save the statistics to the file periodically
+    pub(crate) async fn save_periodically(self: Arc<Self>, path: PathBuf) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.save(&path) {
+                warn!("Stats: could not save to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Replace the file, so that a crash while saving leaves the previous statistics intact
+    pub(crate) fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, self.info().replace("\r\n", "\n"))?;
+        std::fs::rename(tmp, path)
+    }
+
+    fn counters(&self) -> impl Iterator<Item = (&'static str, &AtomicU64)> {
+        [
+            ("total_connections_received", &self.connections_received),
+            ("total_commands_processed", &self.commands_processed),
+            ("total_net_input_bytes", &self.net_input_bytes),
+            ("total_net_output_bytes", &self.net_output_bytes),
+            (
+                "total_writes_skipped_unchanged",
+                &self.writes_skipped_unchanged,
+            ),
+        ]
+        .into_iter()
+    }
+
+    pub(crate) fn connection_received(&self) {
+        self.connections_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn command_processed(&self) {
+        self.commands_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn net_input(&self, bytes: usize) {
+        self.net_input_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn net_output(&self, bytes: usize) {
+        self.net_output_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn write_skipped_unchanged(&self) {
+        self.writes_skipped_unchanged
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The stats section of INFO
+    pub(crate) fn info(&self) -> String {
+        self.counters()
+            .map(|(name, counter)| format!("{}:{}\r\n", name, counter.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
@@ -1,87 +1,612 @@
 use crate::cli::Args;
-use bitcask_engine_rs::bitcask::BitCask;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
 use raft_lite::config::{RaftConfig, RaftParams};
 use raft_lite::persister::AsyncFilePersister;
 use raft_lite::raft::Raft;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::collections::HashMap;
-use std::fmt::{Debug};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot, Mutex};
-use tracing::warn;
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, info_span, warn, Instrument, Span};
 use bitcask_engine_rs::error::BitCaskError;
-use crate::cmd::InnerCmd;
+use crate::failpoint;
+use crate::keyspace;
+use crate::raft_log::CommittedLog;
+use crate::replica::Tail;
+use crate::stages::Stages;
+use uuid::Uuid;
 
 pub(crate) type RequestId = [u8; 16];
 
+/// The reply to the writes once the node can no longer replicate them, until it restarts
+pub(crate) const CLUSTERDOWN: &str = "CLUSTERDOWN the node stopped replicating writes";
+
+/// Keys the sync layer keeps its own state under, not a prefix clients are expected to use
+pub(crate) const KEY_PREFIX: &[u8] = b"\xffraft:";
+
+/// Requests whose ids are remembered, so that one committed again, as a client retries under the
+/// same id, is not applied twice. Counted in requests, the coalesced ones included, every node
+/// applying the same log remembers the same ones.
+const DEDUP_WINDOW: usize = 100_000;
+
+/// Starts a Raft entry holding a batch of messages. A message proposed alone starts with the
+/// index of its variant, never as high.
+const BATCH_MAGIC: [u8; 4] = [0xff; 4];
+
+/// Starts a Raft entry holding a batch of messages, each with the request ids of the writes
+/// coalesced into it, so that every node remembers them with its result
+const COALESCED_MAGIC: [u8; 4] = [0xff, 0xff, 0xff, 0xfe];
+
+/// Starts a Raft entry holding a batch of messages, each with the requests coalesced into it and
+/// those among them whose clients chose their ids, the only ones every node keeps the reply of
+const KEPT_MAGIC: [u8; 4] = [0xff, 0xff, 0xff, 0xfd];
+
+/// The first wait of adaptive batching once the batches fill up
+const ADAPTIVE_LINGER: Duration = Duration::from_micros(10);
+
 pub(crate) trait Syncable: Serialize + DeserializeOwned + Send {
-    fn handle(&self, storage: &mut BitCask) -> Result<(), BitCaskError>;
+    /// Payload handed back to the waiting client once the message is applied
+    type Output: Clone + Send + 'static;
+    /// Node-local state the apply path acts on besides the storage
+    type Context: Clone + Send + 'static;
+    fn handle(
+        &self,
+        storage: &mut BitCask,
+        context: &Self::Context,
+    ) -> Result<Self::Output, BitCaskError>;
     fn get_request_id(&self) -> RequestId;
+    /// The key the message sets whole, whatever it held, so that an earlier such message on the
+    /// same key queued with it may be left out of the batch
+    fn overwrites(&self) -> Option<&[u8]>;
 }
 
+pub(crate) type SyncAnswer<M> = oneshot::Sender<Result<<M as Syncable>::Output, BitCaskError>>;
+
+// A request coalesced into a later one, answered with its result, with its stages if traced
+type Rider<M> = (RequestId, SyncAnswer<M>, Option<Stages>);
+
+// with the span of the request, parent of the span applying it, its stages if traced and the
+// requests coalesced into it
+type RequestMap<M> =
+    Arc<Mutex<HashMap<RequestId, (SyncAnswer<M>, Span, Option<Stages>, Vec<Rider<M>>)>>>;
+
 pub(crate) struct SyncRequest<M: Syncable> {
     pub(crate) message: M,
-    pub(crate) answer: oneshot::Sender<Result<(), BitCaskError>>,
-}
-
-impl Debug for SyncRequest<InnerCmd> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.message)
-    }
+    pub(crate) answer: SyncAnswer<M>,
+    // when the client stops waiting for the answer
+    pub(crate) deadline: Instant,
+    pub(crate) span: Span,
+    // marked as the request is proposed, committed and applied, for CLIENT TRACE
+    pub(crate) stages: Option<Stages>,
+    // the earlier requests it supersedes in its batch, never proposed
+    riders: Vec<Rider<M>>,
+    // the request ids, its own or of its riders, the client chose to retry the request under
+    kept: Vec<RequestId>,
 }
 
 impl<M: Syncable> SyncRequest<M> {
-    pub(crate) fn new(message: M, tx: oneshot::Sender<Result<(), BitCaskError>>) -> Self {
+    pub(crate) fn new(
+        message: M,
+        tx: SyncAnswer<M>,
+        deadline: Instant,
+        span: Span,
+        stages: Option<Stages>,
+    ) -> Self {
         Self {
             message,
             answer: tx,
+            deadline,
+            span,
+            stages,
+            riders: Vec::new(),
+            kept: Vec::new(),
+        }
+    }
+
+    /// Whether the client chose the request id, to retry the request under it. Every node keeps
+    /// the reply of such a request, to answer the retry as the request first was.
+    pub(crate) fn identified(mut self, identified: bool) -> Self {
+        if identified {
+            self.kept.push(self.message.get_request_id());
         }
+        self
+    }
+
+    /// Nobody waits for the answer anymore, so the request is not worth proposing
+    pub(crate) fn expired(&self) -> bool {
+        Instant::now() >= self.deadline || self.answer.is_closed()
     }
 }
 
-pub(crate) struct SyncLayer {
-    args: Args,
+/// Applies the committed entries in order, each request once. The node records how far in the
+/// log it applied, with the first entry of the log, so that once it restarts and raft-lite sends
+/// it the whole log again, it skips the entries it applied before, unless the log is a new one.
+pub(crate) struct Applier<M: Syncable> {
     storage: BitCask,
-    request_map: Arc<Mutex<HashMap<RequestId, oneshot::Sender<Result<(), BitCaskError>>>>>,
+    // entries of the log so far, which is the index of the last one
+    index: u64,
+    first: RequestId,
+    // the first entry of the log and the entries applied, as recorded before the node restarted
+    recorded: Option<(RequestId, u64)>,
+    // the request ids of the latest entries, oldest first
+    window: VecDeque<RequestId>,
+    // their results, kept only for the requests whose clients chose their ids, None for the
+    // others and for the entries applied before the node restarted
+    results: HashMap<RequestId, Option<Result<M::Output, BitCaskError>>>,
+}
+
+impl<M: Syncable> Applier<M> {
+    pub(crate) fn new(storage: BitCask) -> Self {
+        let recorded = storage.get(&applied_key()).and_then(|raw| {
+            let payload = value::expect(&raw, ValueType::Raft).ok()?;
+            let (first, applied) = payload.split_first_chunk::<16>()?;
+            Some((*first, u64::from_le_bytes(applied.try_into().ok()?)))
+        });
+        Self {
+            storage,
+            index: 0,
+            first: RequestId::default(),
+            recorded,
+            window: VecDeque::new(),
+            results: HashMap::new(),
+        }
+    }
+
+    /// Apply the next committed entry, unless its request already was, returning its result, the
+    /// one of the requests coalesced into it as well. The result is kept for the requests `kept`
+    /// names, the others committed again are told they were already applied.
+    pub(crate) fn apply(
+        &mut self,
+        message: &M,
+        riders: &[RequestId],
+        kept: &[RequestId],
+        apply: impl FnOnce(&mut BitCask) -> Result<M::Output, BitCaskError>,
+    ) -> Result<M::Output, BitCaskError> {
+        self.index += 1;
+        let request_id = message.get_request_id();
+        // held until the entry is recorded, so that a read holding it sees the entries up to the
+        // one recorded, and none after
+        let _writing = keyspace::writing();
+        if self.index == 1 {
+            // a log starting with another entry is a new one, none of it was applied
+            if self.recorded.is_some_and(|(first, _)| first != request_id) {
+                self.recorded = None;
+            }
+            self.first = request_id;
+        }
+        if self.recorded.is_some_and(|(_, applied)| self.index <= applied) {
+            self.remember(request_id, None);
+            for rider in riders {
+                self.remember(*rider, None);
+            }
+            return Err(already_applied());
+        }
+        let result = match self.results.get(&request_id) {
+            Some(Some(result)) => copy(result),
+            Some(None) => Err(already_applied()),
+            None => {
+                let result = apply(&mut self.storage);
+                self.remember(request_id, kept.contains(&request_id).then(|| copy(&result)));
+                result
+            }
+        };
+        // a request retried as a later write superseded it is answered as it first was
+        for rider in riders {
+            if !self.results.contains_key(rider) {
+                self.remember(*rider, kept.contains(rider).then(|| copy(&result)));
+            }
+        }
+        let mut record = self.first.to_vec();
+        record.extend_from_slice(&self.index.to_le_bytes());
+        if let Err(e) = self
+            .storage
+            .put(&applied_key(), &value::encode(ValueType::Raft, &record))
+        {
+            warn!("SyncLayer: could not record the entries applied: {}", e);
+        }
+        result
+    }
+
+    fn remember(&mut self, request_id: RequestId, result: Option<Result<M::Output, BitCaskError>>) {
+        if self.window.len() == DEDUP_WINDOW {
+            if let Some(oldest) = self.window.pop_front() {
+                self.results.remove(&oldest);
+            }
+        }
+        self.window.push_back(request_id);
+        self.results.insert(request_id, result);
+    }
+}
+
+fn applied_key() -> Vec<u8> {
+    [KEY_PREFIX, b"applied"].concat()
+}
+
+/// The index of the last entry the storage holds the writes of, 0 before any. Read while holding
+/// [`keyspace::writing`], it is the index of what the storage holds then.
+pub(crate) fn applied_index(storage: &BitCask) -> u64 {
+    storage
+        .get(&applied_key())
+        .and_then(|raw| {
+            let payload = value::expect(&raw, ValueType::Raft).ok()?;
+            let (_, applied) = payload.split_first_chunk::<16>()?;
+            Some(u64::from_le_bytes(applied.try_into().ok()?))
+        })
+        .unwrap_or_default()
+}
+
+fn already_applied() -> BitCaskError {
+    BitCaskError::UnexpectedError(anyhow::anyhow!("the request was already applied"))
+}
+
+/// The result of a request, for the same request committed again
+fn copy<T: Clone>(result: &Result<T, BitCaskError>) -> Result<T, BitCaskError> {
+    match result {
+        Ok(output) => Ok(output.clone()),
+        // NX and XX tell their aborts apart by the error
+        Err(BitCaskError::KeyExists) => Err(BitCaskError::KeyExists),
+        Err(BitCaskError::KeyNotFound) => Err(BitCaskError::KeyNotFound),
+        Err(e) => Err(BitCaskError::UnexpectedError(anyhow::anyhow!("{}", e))),
+    }
+}
+
+/// Progress of the replication as seen from this node, reported in INFO
+#[derive(Default)]
+pub(crate) struct RaftStats {
+    proposed: AtomicU64,
+    // dropped before being proposed as their clients timed out
+    expired: AtomicU64,
+    applied: AtomicU64,
+    // proposed by this node, not yet applied here
+    pending: AtomicU64,
+    // committed, waiting to be applied
+    apply_lag: AtomicUsize,
+    // left out of their batch for a later write to the same key
+    coalesced: AtomicU64,
+    // the sync layer stopped, writes are no longer replicated
+    halted: AtomicBool,
+    // the role of the node, 0 until its consensus tells it
+    role: AtomicU8,
+}
+
+impl RaftStats {
+    pub(crate) fn proposed(&self) {
+        self.proposed.fetch_add(1, Ordering::Relaxed);
+        self.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn expired(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `local` when the entry was proposed by this node
+    pub(crate) fn applied(&self, local: bool) {
+        self.applied.fetch_add(1, Ordering::Relaxed);
+        if local {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Entries applied since the node started, which is the index of the last one
+    pub(crate) fn applied_entries(&self) -> u64 {
+        self.applied.load(Ordering::Relaxed)
+    }
+
+    /// Entries proposed by this node, not yet applied here
+    pub(crate) fn commit_lag(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_apply_lag(&self, lag: usize) {
+        self.apply_lag.store(lag, Ordering::Relaxed);
+    }
+
+    pub(crate) fn apply_lag(&self) -> usize {
+        self.apply_lag.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn halt(&self) {
+        self.halted.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn halted(&self) -> bool {
+        self.halted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_role(&self, role: Role) {
+        self.role.store(role as u8 + 1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn role(&self) -> Option<Role> {
+        match self.role.load(Ordering::Relaxed) {
+            1 => Some(Role::Leader),
+            2 => Some(Role::Follower),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn info(&self) -> String {
+        let role = match self.role() {
+            Some(Role::Leader) => "leader",
+            Some(Role::Follower) => "follower",
+            // raft-lite keeps the role of the node to itself
+            None => "unknown",
+        };
+        format!(
+            "raft_role:{}\r\nraft_entries_proposed:{}\r\nraft_proposals_expired:{}\r\nraft_entries_applied:{}\r\nraft_commit_lag:{}\r\nraft_apply_lag:{}\r\nraft_writes_coalesced:{}\r\n",
+            role,
+            self.proposed.load(Ordering::Relaxed),
+            self.expired.load(Ordering::Relaxed),
+            self.applied_entries(),
+            self.commit_lag(),
+            self.apply_lag(),
+            self.coalesced.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// The role of the node in its group, as its consensus tells it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Role {
+    /// The node commits the entries, proposed by itself or forwarded by the others
+    Leader,
+    /// The node forwards its proposals and applies the entries another one committed
+    Follower,
+}
+
+/// The ends of a consensus the sync layer holds once it started
+pub(crate) struct Channels {
+    /// where to propose entries
+    pub(crate) proposals: mpsc::UnboundedSender<Vec<u8>>,
+    /// the committed entries, in log order
+    pub(crate) committed: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// the role of the node each time it changes, closed without any by a consensus that does
+    /// not know it
+    pub(crate) roles: mpsc::UnboundedReceiver<Role>,
+}
+
+/// The consensus the sync layer replicates its entries through: raft-lite on a node, the node
+/// deciding alone with --standalone and in tests, or a member telling a read replica.
+pub(crate) trait Consensus: Send {
+    /// Join the group, returning where to propose entries and where the committed ones and the
+    /// changes of role arrive
+    fn start(&mut self) -> Channels;
+}
+
+impl Consensus for Raft {
+    fn start(&mut self) -> Channels {
+        let (proposals, committed) = self.run();
+        // raft-lite tells nobody when the node changes role
+        let (_, roles) = mpsc::unbounded_channel();
+        Channels {
+            proposals,
+            committed,
+            roles,
+        }
+    }
+}
+
+/// The consensus of a single node, committing every entry as soon as it is proposed
+pub(crate) struct Alone;
+
+impl Consensus for Alone {
+    fn start(&mut self) -> Channels {
+        let (btx, mrx) = mpsc::unbounded_channel();
+        // held by a task like raft-lite holds its end, so entries stop being committed only as
+        // the runtime shuts down, rather than as the node stops proposing
+        let held = btx.clone();
+        tokio::spawn(async move {
+            let _held = held;
+            std::future::pending::<()>().await
+        });
+        let (rtx, roles) = mpsc::unbounded_channel();
+        let _ = rtx.send(Role::Leader);
+        Channels {
+            proposals: btx,
+            committed: mrx,
+            roles,
+        }
+    }
+}
+
+/// raft-lite with the peers of the command line, the node alone with --standalone, or the log
+/// of the group tailed with --replica-of
+pub(crate) fn consensus(args: &Args) -> Box<dyn Consensus> {
+    if args.standalone() {
+        return Box::new(Alone);
+    }
+    if !args.replica_of().is_empty() {
+        return Box::new(Tail::new(args.replica_of(), args.replica_credentials()));
+    }
+    Box::new(Raft::new(RaftConfig::new(
+        args.peer_addr(),
+        args.self_addr(),
+        RaftParams::default(),
+        Box::new(AsyncFilePersister::new(args.raft_state_file())),
+    )))
+}
+
+/// How the writes queued together are proposed as a single entry
+#[derive(Clone)]
+pub(crate) struct Batching {
+    max_entries: usize,
+    max_bytes: usize,
+    max_linger: Duration,
+    adaptive: bool,
+    // whether a SET leaves out of the batch an earlier SET of the same key
+    coalesce: bool,
+    // how long the next batch waits for more writes
+    linger: Duration,
+}
+
+/// One write per entry, as by default
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            max_entries: 1,
+            max_bytes: 1024 * 1024,
+            max_linger: Duration::ZERO,
+            adaptive: false,
+            coalesce: false,
+            linger: Duration::ZERO,
+        }
+    }
+}
+
+impl Batching {
+    pub(crate) fn new(args: &Args) -> Self {
+        let max_linger = Duration::from_micros(args.batch_linger_us());
+        Self {
+            max_entries: args.batch_max_entries(),
+            max_bytes: args.batch_max_bytes(),
+            max_linger,
+            adaptive: args.batch_adaptive(),
+            coalesce: args.batch_coalesce(),
+            linger: if args.batch_adaptive() {
+                Duration::ZERO
+            } else {
+                max_linger
+            },
+        }
+    }
+
+    /// Adapt the wait to the batch just proposed
+    fn adapt(&mut self, entries: usize, full: bool) {
+        if !self.adaptive {
+            return;
+        }
+        if full {
+            self.linger = (self.linger * 2).max(ADAPTIVE_LINGER).min(self.max_linger);
+        } else if entries <= 1 {
+            self.linger /= 2;
+            if self.linger < ADAPTIVE_LINGER {
+                self.linger = Duration::ZERO;
+            }
+        }
+    }
 }
 
-impl SyncLayer {
-    pub(crate) fn new(args: Args, storage: BitCask) -> Self {
+pub(crate) struct SyncLayer<M: Syncable> {
+    consensus: Box<dyn Consensus>,
+    batching: Batching,
+    storage: BitCask,
+    context: M::Context,
+    request_map: RequestMap<M>,
+    stats: Arc<RaftStats>,
+    log: Arc<CommittedLog>,
+}
+
+impl<M: Syncable + 'static> SyncLayer<M> {
+    pub(crate) fn new(
+        consensus: Box<dyn Consensus>,
+        batching: Batching,
+        storage: BitCask,
+        context: M::Context,
+        stats: Arc<RaftStats>,
+        log: Arc<CommittedLog>,
+    ) -> Self {
         let request_map = Arc::new(Mutex::new(HashMap::new()));
         Self {
-            args,
+            consensus,
+            batching,
             storage,
+            context,
             request_map,
+            stats,
+            log,
         }
     }
 
-    pub(crate) async fn run<M: Syncable + 'static>(
-        &mut self,
-        mut sync_request_rx: mpsc::Receiver<SyncRequest<M>>,
-    ) {
-        let raft_config = RaftConfig::new(
-            self.args.peer_addr(),
-            self.args.self_addr(),
-            RaftParams::default(),
-            Box::new(AsyncFilePersister::new(self.args.raft_state_file())),
-        );
-        let mut raft = Raft::new(raft_config);
-        let (btx, mut mrx) = raft.run();
+    pub(crate) async fn run(&mut self, mut sync_request_rx: mpsc::Receiver<SyncRequest<M>>) {
+        let Channels {
+            proposals: btx,
+            committed: mut mrx,
+            mut roles,
+        } = self.consensus.start();
+        // the role a consensus knows at once is there before the first request
+        while let Ok(role) = roles.try_recv() {
+            self.stats.set_role(role);
+        }
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            while let Some(role) = roles.recv().await {
+                info!("SyncLayer: the node is now a {:?}", role);
+                stats.set_role(role);
+            }
+        });
 
         // receive message from lower layer (Raft)
         let request_map = self.request_map.clone();
-        let mut storage = self.storage.clone();
+        let mut applier = Applier::<M>::new(self.storage.clone());
+        let context = self.context.clone();
+        let stats = self.stats.clone();
+        let log = self.log.clone();
         tokio::spawn(async move {
             loop {
-                let raw_payload = mrx.recv().await.unwrap();
-                let sync_message: M = bincode::deserialize::<M>(&raw_payload).unwrap();
-                let result = sync_message.handle(&mut storage);
-                let request_id = sync_message.get_request_id();
-                let mut request_map = request_map.lock().await;
-                if let Some(tx) = request_map.remove(&request_id) {
-                    if tx.send(result).is_err() {
-                        warn!("SyncLayer: request_id {:?} is committed but the client is not aware of it", request_id);
+                let Some(raw_payload) = mrx.recv().await else {
+                    warn!("SyncLayer: raft-lite stopped, no more entries are committed");
+                    stats.halt();
+                    // the clients waiting for their entries are told the node is down
+                    request_map.lock().await.clear();
+                    return;
+                };
+                stats.set_apply_lag(mrx.len());
+                let messages = match messages::<M>(raw_payload) {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        // skipping the entry would make this node diverge from the others
+                        warn!("SyncLayer: could not decode a committed entry, the node stops applying the log: {}", e);
+                        stats.halt();
+                        request_map.lock().await.clear();
+                        return;
+                    }
+                };
+                for (sync_message, raw_payload, riders, kept) in messages {
+                    log.push(raw_payload);
+                    let request_id = sync_message.get_request_id();
+                    // only the node the request was proposed by has a client waiting for it
+                    let answer = request_map.lock().await.remove(&request_id);
+                    let id = Uuid::from_bytes(request_id);
+                    if let Some((_, _, Some(stages), _)) = &answer {
+                        stages.mark("commit");
+                    }
+                    let span = match &answer {
+                        Some((_, parent, _, _)) => info_span!(parent: parent, "raft_apply", request_id = %id),
+                        None => info_span!(parent: None, "raft_apply", request_id = %id),
+                    };
+                    let failpoint = failpoint::eval(failpoint::BEFORE_APPLY).instrument(span.clone()).await;
+                    let result = span.in_scope(|| {
+                        applier.apply(&sync_message, &riders, &kept, |storage| match failpoint {
+                            Ok(()) => sync_message.handle(storage, &context),
+                            Err(e) => Err(BitCaskError::UnexpectedError(anyhow::anyhow!(e))),
+                        })
+                    });
+                    stats.applied(answer.is_some());
+                    if let Some((tx, _, stages, riders)) = answer {
+                        // the requests coalesced into it are answered as if they were applied
+                        // right before it
+                        for (_, rider, stages) in riders {
+                            if let Some(stages) = stages {
+                                stages.mark("commit");
+                                stages.mark("apply");
+                            }
+                            let _ = rider.send(copy(&result));
+                        }
+                        if let Some(stages) = stages {
+                            stages.mark("apply");
+                        }
+                        if tx.send(result).is_err() {
+                            warn!("SyncLayer: request_id {:?} is committed but the client is not aware of it", request_id);
+                        }
                     }
                 }
             }
@@ -89,18 +614,612 @@ impl SyncLayer {
 
         // receive request from upper layer (application)
         let request_map = self.request_map.clone();
+        let stats = self.stats.clone();
+        let mut batching = self.batching.clone();
         tokio::spawn(async move {
-            loop {
-                let request = sync_request_rx
-                    .recv()
-                    .await
-                    .expect("sync_request_rx closed");
-                let raw_payload = bincode::serialize(&request.message).unwrap();
-                let request_id = request.message.get_request_id();
+            // the connections are gone once the node shuts down
+            while let Some(request) = sync_request_rx.recv().await {
+                // dropped, their clients are told the node is down
+                if stats.halted() {
+                    continue;
+                }
+                let mut next = Some(request);
+                // the writes queued meanwhile, or arriving within the wait, join the first one
+                let deadline = Instant::now() + batching.linger;
+                let mut batch = Vec::new();
+                let mut bytes = 0;
+                let mut full = false;
+                while let Some(request) = next.take() {
+                    if let Some(mut request) = admit(request, &stats).await {
+                        if batching.coalesce {
+                            if let Some(superseded) = superseded(&batch, &request.message) {
+                                let superseded = batch.remove(superseded);
+                                bytes -= bincode::serialized_size(&superseded.message).unwrap_or_default() as usize;
+                                request.riders.extend(superseded.riders);
+                                request.kept.extend(superseded.kept);
+                                request.riders.push((superseded.message.get_request_id(), superseded.answer, superseded.stages));
+                                stats.coalesced();
+                            }
+                        }
+                        bytes += bincode::serialized_size(&request.message).unwrap_or_default() as usize;
+                        batch.push(request);
+                    }
+                    full = batch.len() >= batching.max_entries || bytes >= batching.max_bytes;
+                    if full {
+                        break;
+                    }
+                    next = if batching.linger.is_zero() {
+                        sync_request_rx.try_recv().ok()
+                    } else {
+                        timeout_at(deadline, sync_request_rx.recv()).await.ok().flatten()
+                    };
+                }
+                batching.adapt(batch.len(), full);
+                if batch.is_empty() {
+                    continue;
+                }
+                let raw_payload = match encode(&batch) {
+                    Ok(raw_payload) => raw_payload,
+                    Err(e) => {
+                        warn!("SyncLayer: could not encode {} requests: {}", batch.len(), e);
+                        for request in batch {
+                            for answer in request.riders.into_iter().map(|(_, answer, _)| answer).chain([request.answer]) {
+                                let _ = answer.send(Err(BitCaskError::UnexpectedError(anyhow::anyhow!("could not encode the request: {}", e))));
+                            }
+                        }
+                        continue;
+                    }
+                };
                 let mut request_map = request_map.lock().await;
-                request_map.insert(request_id, request.answer);
-                btx.send(raw_payload).unwrap();
+                let request_ids: Vec<RequestId> = batch.iter().map(|request| request.message.get_request_id()).collect();
+                for request in batch {
+                    for stages in request.riders.iter().filter_map(|(_, _, stages)| stages.as_ref()).chain(&request.stages) {
+                        stages.mark("propose");
+                    }
+                    request_map.insert(request.message.get_request_id(), (request.answer, request.span, request.stages, request.riders));
+                    stats.proposed();
+                }
+                // on a follower, raft-lite forwards the proposal to the leader, holding it until
+                // one is elected, and the entry comes back here to be applied and answered
+                if btx.send(raw_payload).is_err() {
+                    warn!("SyncLayer: raft-lite stopped, no more entries are proposed");
+                    stats.halt();
+                    for request_id in request_ids {
+                        request_map.remove(&request_id);
+                    }
+                }
             }
         });
     }
 }
+
+/// The request, unless it failed at the failpoint or its client stopped waiting for it
+async fn admit<M: Syncable>(request: SyncRequest<M>, stats: &RaftStats) -> Option<SyncRequest<M>> {
+    if let Err(e) = failpoint::eval(failpoint::BEFORE_PROPOSE).await {
+        let _ = request
+            .answer
+            .send(Err(BitCaskError::UnexpectedError(anyhow::anyhow!(e))));
+        return None;
+    }
+    // checked after the failpoint, which may have delayed the request
+    if request.expired() {
+        stats.expired();
+        let request_id = Uuid::from_bytes(request.message.get_request_id());
+        warn!("SyncLayer: dropping request {}, its client timed out", request_id);
+        return None;
+    }
+    Some(request)
+}
+
+/// The position in the batch of the request the message supersedes: the last one setting the
+/// same key whole, as long as the requests after it only set other keys whole
+fn superseded<M: Syncable>(batch: &[SyncRequest<M>], message: &M) -> Option<usize> {
+    let key = message.overwrites()?;
+    for (position, request) in batch.iter().enumerate().rev() {
+        match request.message.overwrites() {
+            Some(overwritten) if overwritten == key => return Some(position),
+            Some(_) => continue,
+            // any other write may read the key, or move it
+            None => return None,
+        }
+    }
+    None
+}
+
+/// The entry proposing the requests, batched unless there is only one
+fn encode<M: Syncable>(batch: &[SyncRequest<M>]) -> bincode::Result<Vec<u8>> {
+    if batch.iter().any(|request| !request.kept.is_empty()) {
+        let messages: Vec<(&M, Vec<RequestId>, &Vec<RequestId>)> = batch
+            .iter()
+            .map(|request| {
+                let riders = request.riders.iter().map(|(id, _, _)| *id).collect();
+                (&request.message, riders, &request.kept)
+            })
+            .collect();
+        let mut raw_payload = KEPT_MAGIC.to_vec();
+        bincode::serialize_into(&mut raw_payload, &messages)?;
+        return Ok(raw_payload);
+    }
+    if batch.iter().any(|request| !request.riders.is_empty()) {
+        let messages: Vec<(&M, Vec<RequestId>)> = batch
+            .iter()
+            .map(|request| {
+                let riders = request.riders.iter().map(|(id, _, _)| *id).collect();
+                (&request.message, riders)
+            })
+            .collect();
+        let mut raw_payload = COALESCED_MAGIC.to_vec();
+        bincode::serialize_into(&mut raw_payload, &messages)?;
+        return Ok(raw_payload);
+    }
+    if let [request] = batch {
+        return bincode::serialize(&request.message);
+    }
+    let messages: Vec<&M> = batch.iter().map(|request| &request.message).collect();
+    let mut raw_payload = BATCH_MAGIC.to_vec();
+    bincode::serialize_into(&mut raw_payload, &messages)?;
+    Ok(raw_payload)
+}
+
+// A message of a committed entry, with its encoding, the request ids of the writes coalesced
+// into it and those among them, its own included, whose replies are kept
+type Committed<M> = (M, Vec<u8>, Vec<RequestId>, Vec<RequestId>);
+
+/// The messages of a committed entry
+pub(crate) fn messages<M: Syncable>(raw_payload: Vec<u8>) -> bincode::Result<Vec<Committed<M>>> {
+    if let Some(batch) = raw_payload.strip_prefix(&KEPT_MAGIC) {
+        return bincode::deserialize::<Vec<(M, Vec<RequestId>, Vec<RequestId>)>>(batch)?
+            .into_iter()
+            .map(|(message, riders, kept)| {
+                bincode::serialize(&message)
+                    .map(|raw_payload| (message, raw_payload, riders, kept))
+            })
+            .collect();
+    }
+    if let Some(batch) = raw_payload.strip_prefix(&COALESCED_MAGIC) {
+        return bincode::deserialize::<Vec<(M, Vec<RequestId>)>>(batch)?
+            .into_iter()
+            .map(|(message, riders)| {
+                bincode::serialize(&message)
+                    .map(|raw_payload| (message, raw_payload, riders, Vec::new()))
+            })
+            .collect();
+    }
+    if let Some(batch) = raw_payload.strip_prefix(&BATCH_MAGIC) {
+        return bincode::deserialize::<Vec<M>>(batch)?
+            .into_iter()
+            .map(|message| {
+                bincode::serialize(&message)
+                    .map(|raw_payload| (message, raw_payload, Vec::new(), Vec::new()))
+            })
+            .collect();
+    }
+    let message = bincode::deserialize::<M>(&raw_payload)?;
+    Ok(vec![(message, raw_payload, Vec::new(), Vec::new())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use tempfile::TempDir;
+
+    /// Appends its value to its key, or sets the key to it whole, returning the length of the key
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Append {
+        id: RequestId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        whole: bool,
+    }
+
+    impl Syncable for Append {
+        type Output = usize;
+        type Context = ();
+
+        fn handle(&self, storage: &mut BitCask, _: &()) -> Result<usize, BitCaskError> {
+            let mut value = match self.whole {
+                true => Vec::new(),
+                false => storage.get(&self.key).unwrap_or_default(),
+            };
+            value.extend_from_slice(&self.value);
+            storage.put(&self.key, &value)?;
+            Ok(value.len())
+        }
+
+        fn get_request_id(&self) -> RequestId {
+            self.id
+        }
+
+        fn overwrites(&self) -> Option<&[u8]> {
+            self.whole.then_some(&self.key)
+        }
+    }
+
+    fn append(id: u8, key: &str, value: &str) -> Append {
+        Append {
+            id: [id; 16],
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            whole: false,
+        }
+    }
+
+    fn set(id: u8, key: &str, value: &str) -> Append {
+        Append {
+            whole: true,
+            ..append(id, key, value)
+        }
+    }
+
+    /// A consensus committing what the test hands it, in the order and as many times as the test
+    /// says, as a group may commit the proposals of its nodes in another order and commit again
+    /// an entry proposed again
+    struct Scripted {
+        proposals: Option<mpsc::UnboundedSender<Vec<u8>>>,
+        committed: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+        roles: Option<mpsc::UnboundedReceiver<Role>>,
+    }
+
+    impl Consensus for Scripted {
+        fn start(&mut self) -> Channels {
+            Channels {
+                proposals: self.proposals.take().unwrap(),
+                committed: self.committed.take().unwrap(),
+                roles: self.roles.take().unwrap(),
+            }
+        }
+    }
+
+    /// A node running the sync layer over a scripted consensus
+    struct Node {
+        requests: mpsc::Sender<SyncRequest<Append>>,
+        // the entries the node proposed
+        proposed: mpsc::UnboundedReceiver<Vec<u8>>,
+        commit: mpsc::UnboundedSender<Vec<u8>>,
+        role: mpsc::UnboundedSender<Role>,
+        stats: Arc<RaftStats>,
+        storage: BitCask,
+        _dir: TempDir,
+    }
+
+    impl Node {
+        async fn start(batching: Batching) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let storage = BitCask::new(dir.path()).unwrap();
+            let stats = Arc::new(RaftStats::default());
+            let (proposals, proposed) = mpsc::unbounded_channel();
+            let (commit, committed) = mpsc::unbounded_channel();
+            let (role, roles) = mpsc::unbounded_channel();
+            let consensus = Scripted {
+                proposals: Some(proposals),
+                committed: Some(committed),
+                roles: Some(roles),
+            };
+            let (requests, requests_rx) = mpsc::channel(100);
+            SyncLayer::<Append>::new(
+                Box::new(consensus),
+                batching,
+                storage.clone(),
+                (),
+                stats.clone(),
+                Arc::new(CommittedLog::new(1024 * 1024)),
+            )
+            .run(requests_rx)
+            .await;
+            Self {
+                requests,
+                proposed,
+                commit,
+                role,
+                stats,
+                storage,
+                _dir: dir,
+            }
+        }
+
+        /// Send the request, returning where its answer arrives
+        async fn send(&self, message: Append) -> oneshot::Receiver<Result<usize, BitCaskError>> {
+            self.send_as(message, false).await
+        }
+
+        /// Send the request under the id its client chose, to retry it
+        async fn retry(&self, message: Append) -> oneshot::Receiver<Result<usize, BitCaskError>> {
+            self.send_as(message, true).await
+        }
+
+        async fn send_as(
+            &self,
+            message: Append,
+            identified: bool,
+        ) -> oneshot::Receiver<Result<usize, BitCaskError>> {
+            let (tx, rx) = oneshot::channel();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let request =
+                SyncRequest::new(message, tx, deadline, Span::none(), None).identified(identified);
+            self.requests.send(request).await.unwrap();
+            rx
+        }
+
+        /// The request ids of the messages of the next entry proposed
+        async fn next_proposed(&mut self) -> (Vec<u8>, Vec<u8>) {
+            let entry = self.proposed.recv().await.unwrap();
+            let ids = messages::<Append>(entry.clone())
+                .unwrap()
+                .into_iter()
+                .map(|(message, _, _, _)| message.id[0])
+                .collect();
+            (entry, ids)
+        }
+
+        fn value(&self, key: &str) -> Vec<u8> {
+            self.storage
+                .get(&key.as_bytes().to_vec())
+                .unwrap_or_default()
+        }
+    }
+
+    async fn answer(rx: oneshot::Receiver<Result<usize, BitCaskError>>) -> usize {
+        rx.await.unwrap().unwrap()
+    }
+
+    /// Wait for the apply task to get there
+    async fn until(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not met after 5s");
+    }
+
+    #[test]
+    fn applier_skips_duplicates_and_entries_applied_before_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = BitCask::new(dir.path()).unwrap();
+        // the third entry is the first request committed again, as its client retried it
+        let log = [
+            append(1, "k", "a"),
+            append(2, "k", "b"),
+            append(1, "k", "a"),
+            append(3, "k", "c"),
+        ];
+        let mut applier = Applier::<Append>::new(storage.clone());
+        let results: Vec<usize> = log
+            .iter()
+            .map(|message| {
+                applier.apply(message, &[], &[[1; 16]], |storage| message.handle(storage, &()))
+            })
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(results, [1, 2, 1, 3]);
+        assert_eq!(storage.get(&b"k".to_vec()).unwrap(), b"abc");
+
+        // restarted, the node is sent the whole log again and applies only what follows it
+        let mut applier = Applier::<Append>::new(storage.clone());
+        for message in &log {
+            assert!(applier
+                .apply(message, &[], &[], |storage| message.handle(storage, &()))
+                .is_err());
+        }
+        let next = append(4, "k", "d");
+        assert_eq!(
+            applier
+                .apply(&next, &[], &[], |storage| next.handle(storage, &()))
+                .unwrap(),
+            4
+        );
+        assert_eq!(applied_index(&storage), 5);
+
+        // a log starting with another entry is a new one, applied whole
+        let mut applier = Applier::<Append>::new(storage.clone());
+        let first = append(5, "k", "e");
+        assert_eq!(
+            applier
+                .apply(&first, &[], &[], |storage| first.handle(storage, &()))
+                .unwrap(),
+            5
+        );
+        assert_eq!(applied_index(&storage), 1);
+    }
+
+    #[test]
+    fn applier_keeps_the_replies_of_the_requests_whose_clients_chose_their_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = BitCask::new(dir.path()).unwrap();
+        let mut applier = Applier::<Append>::new(storage.clone());
+        let chosen = append(1, "k", "a");
+        let generated = append(2, "k", "b");
+        let coalesced = [[3; 16], [4; 16]];
+        for _ in 0..2 {
+            let _ = applier.apply(&chosen, &coalesced, &[[1; 16], [3; 16]], |storage| {
+                chosen.handle(storage, &())
+            });
+            let _ = applier.apply(&generated, &[], &[], |storage| generated.handle(storage, &()));
+        }
+        assert_eq!(storage.get(&b"k".to_vec()).unwrap(), b"ab");
+        // committed again, a request without the id of its client is only known to be applied
+        let kept: Vec<RequestId> = applier
+            .results
+            .iter()
+            .filter(|(_, result)| result.is_some())
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        assert_eq!(kept.len(), 2);
+        assert!(kept.contains(&[1; 16]) && kept.contains(&[3; 16]));
+        assert_eq!(applier.window.len(), 4);
+        assert!(applier
+            .apply(&generated, &[], &[], |storage| generated.handle(storage, &()))
+            .is_err());
+        assert_eq!(
+            applier
+                .apply(&chosen, &[], &[], |storage| chosen.handle(storage, &()))
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn entries_reordered_and_duplicated_apply_alike_on_every_node() {
+        let mut leader = Node::start(Batching::default()).await;
+        let follower = Node::start(Batching::default()).await;
+        let answers = [
+            leader.send(append(1, "k", "a")).await,
+            leader.send(append(2, "k", "b")).await,
+            leader.send(append(3, "k", "c")).await,
+        ];
+        let mut entries = Vec::new();
+        for id in 1..=3 {
+            let (entry, ids) = leader.next_proposed().await;
+            assert_eq!(ids, [id]);
+            entries.push(entry);
+        }
+        // the group commits the last proposal first, and the first one twice
+        for entry in [&entries[2], &entries[0], &entries[0], &entries[1]] {
+            leader.commit.send(entry.clone()).unwrap();
+            follower.commit.send(entry.clone()).unwrap();
+        }
+        let [first, second, third] = answers;
+        assert_eq!(answer(third).await, 1);
+        assert_eq!(answer(first).await, 2);
+        assert_eq!(answer(second).await, 3);
+        until(|| follower.stats.applied_entries() == 4).await;
+        until(|| leader.stats.applied_entries() == 4).await;
+        assert_eq!(leader.value("k"), b"cab");
+        assert_eq!(follower.value("k"), b"cab");
+        assert_eq!(leader.stats.commit_lag(), 0);
+    }
+
+    #[tokio::test]
+    async fn batches_split_at_max_entries_and_max_bytes() {
+        let size = bincode::serialized_size(&append(1, "k", "a")).unwrap() as usize;
+        for batching in [
+            Batching {
+                max_entries: 2,
+                ..Batching::default()
+            },
+            Batching {
+                max_entries: 10,
+                max_bytes: size + 1,
+                ..Batching::default()
+            },
+        ] {
+            let mut node = Node::start(Batching {
+                max_linger: Duration::from_millis(100),
+                linger: Duration::from_millis(100),
+                ..batching
+            })
+            .await;
+            let mut answers = Vec::new();
+            for id in 1..=3 {
+                answers.push(node.send(append(id, "k", "a")).await);
+            }
+            let (batch, ids) = node.next_proposed().await;
+            assert!(batch.starts_with(&BATCH_MAGIC));
+            assert_eq!(ids, [1, 2]);
+            // the last one, alone, is proposed without the batch header
+            let (alone, ids) = node.next_proposed().await;
+            assert!(!alone.starts_with(&BATCH_MAGIC));
+            assert_eq!(ids, [3]);
+            node.commit.send(batch).unwrap();
+            node.commit.send(alone).unwrap();
+            let mut results = Vec::new();
+            for rx in answers {
+                results.push(answer(rx).await);
+            }
+            assert_eq!(results, [1, 2, 3]);
+            assert_eq!(node.stats.applied_entries(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesced_writes_are_answered_with_the_later_one() {
+        let mut node = Node::start(Batching {
+            max_entries: 10,
+            max_linger: Duration::from_millis(100),
+            coalesce: true,
+            linger: Duration::from_millis(100),
+            ..Batching::default()
+        })
+        .await;
+        let answers = [
+            node.send(set(1, "k", "a")).await,
+            node.send(set(2, "k", "bb")).await,
+            node.send(set(3, "j", "c")).await,
+            // appending reads the key, so the set before it stays
+            node.send(append(4, "j", "d")).await,
+            node.send(set(5, "j", "e")).await,
+        ];
+        let (entry, ids) = node.next_proposed().await;
+        assert_eq!(ids, [2, 3, 4, 5]);
+        assert!(entry.starts_with(&COALESCED_MAGIC));
+        node.commit.send(entry.clone()).unwrap();
+        let mut results = Vec::new();
+        for rx in answers {
+            results.push(answer(rx).await);
+        }
+        assert_eq!(results, [2, 2, 1, 2, 1]);
+        assert_eq!(node.value("k"), b"bb");
+        assert_eq!(node.value("j"), b"e");
+        assert!(node.stats.info().contains("raft_writes_coalesced:1\r\n"));
+        assert_eq!(node.stats.commit_lag(), 0);
+
+        // the coalesced write, retried under the id its client chose after a later one, is
+        // answered as it was and not applied again, on the node it was sent to as on the others
+        let follower = Node::start(Batching::default()).await;
+        let chosen = node.retry(set(6, "k", "g")).await;
+        let superseding = node.send(set(7, "k", "hhh")).await;
+        let (entry, ids) = node.next_proposed().await;
+        assert_eq!(ids, [7]);
+        assert!(entry.starts_with(&KEPT_MAGIC));
+        let later = node.send(set(8, "k", "f")).await;
+        let (later_entry, _) = node.next_proposed().await;
+        let retried = node.retry(set(6, "k", "g")).await;
+        let (retried_entry, ids) = node.next_proposed().await;
+        assert_eq!(ids, [6]);
+        for entry in [&entry, &later_entry, &retried_entry] {
+            node.commit.send(entry.clone()).unwrap();
+            follower.commit.send(entry.clone()).unwrap();
+        }
+        assert_eq!(answer(chosen).await, 3);
+        assert_eq!(answer(superseding).await, 3);
+        assert_eq!(answer(later).await, 1);
+        assert_eq!(answer(retried).await, 3);
+        until(|| follower.stats.applied_entries() == 3).await;
+        assert_eq!(node.value("k"), b"f");
+        assert_eq!(follower.value("k"), b"f");
+    }
+
+    #[tokio::test]
+    async fn an_undecodable_entry_halts_the_node() {
+        let mut node = Node::start(Batching::default()).await;
+        let waiting = node.send(append(1, "k", "a")).await;
+        node.next_proposed().await;
+        node.commit.send(b"\x00\x01".to_vec()).unwrap();
+        // the client is told the node is down rather than waiting for its deadline
+        assert!(waiting.await.is_err());
+        assert!(node.stats.halted());
+        // the entries after it are no longer taken, nor are writes proposed
+        assert!(node
+            .commit
+            .send(bincode::serialize(&append(2, "k", "b")).unwrap())
+            .is_err());
+        let dropped = node.send(append(3, "k", "c")).await;
+        assert!(dropped.await.is_err());
+        assert!(node.proposed.try_recv().is_err());
+        assert_eq!(node.stats.applied_entries(), 0);
+        assert!(node.value("k").is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_role_is_the_one_the_consensus_tells_last() {
+        let node = Node::start(Batching::default()).await;
+        assert_eq!(node.stats.role(), None);
+        assert!(node.stats.info().starts_with("raft_role:unknown\r\n"));
+        node.role.send(Role::Follower).unwrap();
+        node.role.send(Role::Leader).unwrap();
+        until(|| node.stats.role() == Some(Role::Leader)).await;
+        assert!(node.stats.info().starts_with("raft_role:leader\r\n"));
+    }
+}
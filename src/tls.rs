@@ -0,0 +1,56 @@
+//! TLS for the RESP endpoint: the listeners of --tls-addr serve the commands of --kv-addr over
+//! rustls, with the certificate chain of --tls-cert and the private key of --tls-key, so that
+//! clients can reach the node across untrusted networks. With --tls-ca the client must present
+//! a certificate issued by one of its authorities, mutual TLS, or the handshake fails.
+//!
+//! As `tls-port` does for Redis, --tls-addr adds listeners rather than turning --kv-addr into
+//! TLS: ADMIN BROADCAST, the read replicas and storgata-cli reach the other nodes in plaintext
+//! on their --kv-addr, which is then bound to the private network of the cluster.
+
+use anyhow::{anyhow, Context};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// How long a client may take to complete its handshake, past which the connection is dropped
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The acceptor of the TLS connections, from the PEM files of the certificate chain, of its
+/// private key and, for mutual TLS, of the authorities the certificates of the clients chain to
+pub(crate) fn acceptor(cert: &Path, key: &Path, ca: Option<&Path>) -> anyhow::Result<TlsAcceptor> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("--tls-cert {}", cert.display()))?;
+    if chain.is_empty() {
+        return Err(anyhow!("--tls-cert {} holds no certificate", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("--tls-key {}", key.display()))?;
+    let provider = Arc::new(ring::default_provider());
+    let builder =
+        ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+    let builder = match ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for authority in CertificateDer::pem_file_iter(ca)
+                .with_context(|| format!("--tls-ca {}", ca.display()))?
+            {
+                roots.add(authority.with_context(|| format!("--tls-ca {}", ca.display()))?)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(chain, key)
+        .context("--tls-key does not match --tls-cert")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,144 @@
+//! Sizes and durations in the settings, with a unit as Redis takes them in its configuration: a
+//! size is a number of bytes or of `k`, `m` or `g` for powers of 1000, `kb`, `mb` or `gb` for
+//! powers of 1024, as in `512mb`; a duration is a number of `ms`, `s`, `m`, `h` or `d`, as in
+//! `30s`. A bare number is in the unit of the setting, the one its name ends with, so that the
+//! values given before units existed keep their meaning. Units are case-insensitive.
+
+/// Split a number from its unit, the letters it ends with
+fn split(value: &str) -> Result<(u64, String), String> {
+    let value = value.trim();
+    let digits = value.len() - value.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let number = value[..digits]
+        .parse()
+        .map_err(|_| format!("`{}` is not a number with a unit", value))?;
+    Ok((number, value[digits..].to_ascii_lowercase()))
+}
+
+/// A size in bytes
+pub(crate) fn bytes(value: &str) -> Result<u64, String> {
+    let (number, unit) = split(value)?;
+    let factor: u64 = match unit.as_str() {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("`{}` is not a size, such as 512mb", value)),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("`{}` is too large", value))
+}
+
+/// A size in bytes that fits the memory of the node
+pub(crate) fn bytes_usize(value: &str) -> Result<usize, String> {
+    usize::try_from(bytes(value)?).map_err(|_| format!("`{}` is too large", value))
+}
+
+/// A duration in milliseconds, the unit of a bare number being `unit_ms`
+fn duration(value: &str, unit_ms: u64) -> Result<u64, String> {
+    let (number, unit) = split(value)?;
+    let factor = match unit.as_str() {
+        "" => unit_ms,
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("`{}` is not a duration, such as 30s", value)),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("`{}` is too long", value))
+}
+
+/// A duration in milliseconds
+pub(crate) fn millis(value: &str) -> Result<u64, String> {
+    duration(value, 1)
+}
+
+/// A duration in whole seconds
+pub(crate) fn secs(value: &str) -> Result<u64, String> {
+    let ms = duration(value, 1000)?;
+    if ms % 1000 != 0 {
+        return Err(format!("`{}` is not a whole number of seconds", value));
+    }
+    Ok(ms / 1000)
+}
+
+/// The parser rejecting 0, for the settings that must be positive
+pub(crate) fn nonzero<T: PartialEq + Default + 'static>(
+    parse: fn(&str) -> Result<T, String>,
+) -> impl Fn(&str) -> Result<T, String> + Clone + Send + Sync + 'static {
+    move |value| match parse(value)? {
+        parsed if parsed == T::default() => Err("must be greater than 0".to_string()),
+        parsed => Ok(parsed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_take_decimal_and_binary_units() {
+        assert_eq!(bytes("512"), Ok(512));
+        assert_eq!(bytes("64k"), Ok(64_000));
+        assert_eq!(bytes("64kb"), Ok(64 * 1024));
+        assert_eq!(bytes("512MB"), Ok(512 * 1024 * 1024));
+        assert_eq!(bytes("4gb"), Ok(4 * 1024 * 1024 * 1024));
+        assert_eq!(bytes(" 2g "), Ok(2_000_000_000));
+    }
+
+    #[test]
+    fn bytes_reject_overflow_and_unknown_units() {
+        assert!(bytes("99999999999gb").is_err());
+        assert!(bytes("99999999999999999999").is_err());
+        assert!(bytes("10tb").is_err());
+        assert!(bytes("1.5mb").is_err());
+        assert!(bytes("mb").is_err());
+        assert!(bytes("-1").is_err());
+        assert!(bytes("").is_err());
+    }
+
+    #[test]
+    fn bytes_usize_fit_the_memory_of_the_node() {
+        assert_eq!(bytes_usize("512mb"), Ok(512 * 1024 * 1024));
+        assert!(bytes_usize("99999999999gb").is_err());
+    }
+
+    #[test]
+    fn millis_default_to_milliseconds() {
+        assert_eq!(millis("250"), Ok(250));
+        assert_eq!(millis("250ms"), Ok(250));
+        assert_eq!(millis("30s"), Ok(30_000));
+        assert_eq!(millis("5m"), Ok(300_000));
+        assert_eq!(millis("2H"), Ok(7_200_000));
+        assert_eq!(millis("7d"), Ok(604_800_000));
+        assert!(millis("30sec").is_err());
+        assert!(millis("1.5s").is_err());
+        assert!(millis("99999999999999999d").is_err());
+    }
+
+    #[test]
+    fn secs_default_to_seconds_and_must_be_whole() {
+        assert_eq!(secs("60"), Ok(60));
+        assert_eq!(secs("2000ms"), Ok(2));
+        assert_eq!(secs("5m"), Ok(300));
+        assert!(secs("1500ms").is_err());
+        assert!(secs("0.5").is_err());
+        assert!(secs("1w").is_err());
+    }
+
+    #[test]
+    fn nonzero_rejects_zero_only() {
+        let parse = nonzero(secs);
+        assert_eq!(parse("1m"), Ok(60));
+        assert!(parse("0").is_err());
+        assert!(parse("0ms").is_err());
+        assert!(parse("1500ms").is_err());
+        assert_eq!(nonzero(bytes)("1kb"), Ok(1024));
+    }
+}
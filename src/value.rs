@@ -0,0 +1,258 @@
+//! Every value is stored behind a small header recording its type, so that commands can reject
+//! keys holding another type with WRONGTYPE instead of misreading or overwriting their encoding.
+//!
+//! Values written before the header existed have none. They are still accepted by every command,
+//! as before, and get a header the next time their key is written, so existing data directories
+//! migrate lazily without a rewrite pass.
+//!
+//! A value set with an expiry has a flag in its type tag, and its deadline follows the tag. The
+//! deadline is a unix time in milliseconds, decided on the node the write was sent to, so every
+//! node stores the same value. Expired values are not removed, they read as missing.
+//!
+//! A string value stored compressed has another flag in its tag, and the codec follows the tag
+//! and deadline, see crate::compression. Its readers decompress it, with `string`.
+
+use crate::compression::{self, Compression};
+use bitcask_engine_rs::error::BitCaskError;
+use bytes::Bytes;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Marks a value with a header. Legacy values are raw strings or bincode encodings, which are
+/// very unlikely to start with this sequence.
+const MAGIC: [u8; 3] = [0xff, b'S', b'G'];
+const HEADER_LEN: usize = MAGIC.len() + 1;
+/// Flag of the type tag of a value followed by its deadline
+const EXPIRES: u8 = 0x80;
+const DEADLINE_LEN: usize = 8;
+/// Flag of the type tag of a compressed value, followed by its codec after the deadline
+const COMPRESSED: u8 = 0x40;
+const FLAGS: u8 = EXPIRES | COMPRESSED;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ValueType {
+    String = 1,
+    List = 2,
+    ZSet = 3,
+    Bloom = 4,
+    Cms = 5,
+    TopK = 6,
+    Proc = 7,
+    Feature = 8,
+    Raft = 9,
+    Script = 10,
+    Lock = 11,
+    Ephemeral = 12,
+    Flush = 13,
+}
+
+impl ValueType {
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(ValueType::String),
+            2 => Some(ValueType::List),
+            3 => Some(ValueType::ZSet),
+            4 => Some(ValueType::Bloom),
+            5 => Some(ValueType::Cms),
+            6 => Some(ValueType::TopK),
+            7 => Some(ValueType::Proc),
+            8 => Some(ValueType::Feature),
+            9 => Some(ValueType::Raft),
+            10 => Some(ValueType::Script),
+            11 => Some(ValueType::Lock),
+            12 => Some(ValueType::Ephemeral),
+            13 => Some(ValueType::Flush),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+pub(crate) struct WrongType;
+
+pub(crate) fn wrong_type() -> BitCaskError {
+    BitCaskError::UnexpectedError(WrongType.into())
+}
+
+/// Whether the error is a command run against a key of another type
+pub(crate) fn is_wrong_type(e: &BitCaskError) -> bool {
+    matches!(e, BitCaskError::UnexpectedError(e) if e.is::<WrongType>())
+}
+
+/// A buffer holding the header of a value of the given type, to append the payload to
+pub(crate) fn header(value_type: ValueType) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(HEADER_LEN);
+    raw.extend_from_slice(&MAGIC);
+    raw.push(value_type as u8);
+    raw
+}
+
+/// Encode a value of the given type for the storage
+pub(crate) fn encode(value_type: ValueType, payload: &[u8]) -> Vec<u8> {
+    let mut raw = header(value_type);
+    raw.extend_from_slice(payload);
+    raw
+}
+
+/// Encode a value of the given type for the storage, expiring at the deadline if any
+pub(crate) fn encode_expiring(
+    value_type: ValueType,
+    payload: &[u8],
+    deadline: Option<u64>,
+) -> Vec<u8> {
+    let Some(deadline) = deadline else {
+        return encode(value_type, payload);
+    };
+    let mut raw = header(value_type);
+    raw[MAGIC.len()] |= EXPIRES;
+    raw.extend_from_slice(&deadline.to_be_bytes());
+    raw.extend_from_slice(payload);
+    raw
+}
+
+/// Encode a string value compressed with the codec, expiring at the deadline if any
+pub(crate) fn encode_compressed(
+    codec: Compression,
+    compressed: &[u8],
+    deadline: Option<u64>,
+) -> Vec<u8> {
+    let mut raw = encode_expiring(ValueType::String, &[codec as u8], deadline);
+    raw[MAGIC.len()] |= COMPRESSED;
+    raw.extend_from_slice(compressed);
+    raw
+}
+
+/// The type of a stored value, None for a legacy value without a header
+pub(crate) fn stored_type(raw: &[u8]) -> Option<ValueType> {
+    raw.strip_prefix(&MAGIC)
+        .and_then(|rest| rest.first())
+        .and_then(|tag| ValueType::from_tag(*tag & !FLAGS))
+}
+
+/// The stored value with its deadline replaced, its payload kept as it is stored
+pub(crate) fn with_deadline(
+    raw: &[u8],
+    value_type: ValueType,
+    deadline: Option<u64>,
+) -> Result<Vec<u8>, BitCaskError> {
+    let mut updated = encode_expiring(value_type, expect_stored(raw, value_type)?, deadline);
+    if compressed(raw) {
+        updated[MAGIC.len()] |= COMPRESSED;
+    }
+    Ok(updated)
+}
+
+/// Whether a stored value is compressed
+fn compressed(raw: &[u8]) -> bool {
+    raw.strip_prefix(&MAGIC)
+        .and_then(|rest| rest.first())
+        .is_some_and(|tag| tag & COMPRESSED != 0)
+}
+
+/// When a stored value expires, as a unix time in milliseconds, None if it never does
+pub(crate) fn deadline(raw: &[u8]) -> Option<u64> {
+    let tag = *raw.strip_prefix(&MAGIC)?.first()?;
+    if tag & EXPIRES == 0 {
+        return None;
+    }
+    let deadline = raw.get(HEADER_LEN..HEADER_LEN + DEADLINE_LEN)?;
+    Some(u64::from_be_bytes(deadline.try_into().ok()?))
+}
+
+/// Whether a stored value expired at the given unix time in milliseconds
+pub(crate) fn expired(raw: &[u8], now: u64) -> bool {
+    deadline(raw).is_some_and(|deadline| deadline <= now)
+}
+
+thread_local! {
+    // the time of the script the thread runs, see `frozen`
+    static FROZEN: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// The unix time in milliseconds, as deadlines are given
+pub(crate) fn now() -> u64 {
+    if let Some(now) = FROZEN.get() {
+        return now;
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Run `f` at the time `now`, which every read and write it makes sees as the current time, so
+/// that a script expires keys and sets deadlines alike on every node
+pub(crate) fn frozen<T>(now: u64, f: impl FnOnce() -> T) -> T {
+    let previous = FROZEN.replace(Some(now));
+    let result = f();
+    FROZEN.set(previous);
+    result
+}
+
+/// The type and payload of a stored value, a legacy value without a header being a string
+pub(crate) fn split(raw: &[u8]) -> Result<(ValueType, Cow<'_, [u8]>), BitCaskError> {
+    match stored_type(raw).unwrap_or(ValueType::String) {
+        ValueType::String => Ok((ValueType::String, string(raw)?)),
+        value_type => Ok((value_type, Cow::Borrowed(expect(raw, value_type)?))),
+    }
+}
+
+/// The payload of a stored string, decompressed if it is compressed
+pub(crate) fn string(raw: &[u8]) -> Result<Cow<'_, [u8]>, BitCaskError> {
+    if !compressed(raw) {
+        return expect(raw, ValueType::String).map(Cow::Borrowed);
+    }
+    match expect_stored(raw, ValueType::String)? {
+        [codec, compressed @ ..] => Ok(Cow::Owned(compression::decompress(*codec, compressed)?)),
+        [] => Err(BitCaskError::CorruptedData(
+            "truncated value codec".to_string(),
+        )),
+    }
+}
+
+/// The payload of a stored string, sharing the buffer of the value unless it is compressed
+pub(crate) fn string_bytes(raw: Vec<u8>) -> Result<Bytes, BitCaskError> {
+    if compressed(&raw) {
+        return Ok(Bytes::from(string(&raw)?.into_owned()));
+    }
+    let offset = raw.len() - expect(&raw, ValueType::String)?.len();
+    Ok(Bytes::from(raw).slice(offset..))
+}
+
+/// The payload of a stored value, checking that it is of the expected type.
+/// Legacy values without a header are returned as is. Strings may be compressed, so they are
+/// read with `string`.
+pub(crate) fn expect(raw: &[u8], value_type: ValueType) -> Result<&[u8], BitCaskError> {
+    let payload = expect_stored(raw, value_type)?;
+    if compressed(raw) {
+        return Err(BitCaskError::CorruptedData(
+            "compressed value read without decompressing it".to_string(),
+        ));
+    }
+    Ok(payload)
+}
+
+/// The payload of a stored value as stored, checking that it is of the expected type
+fn expect_stored(raw: &[u8], value_type: ValueType) -> Result<&[u8], BitCaskError> {
+    if !raw.starts_with(&MAGIC) || raw.len() < HEADER_LEN {
+        return Ok(raw);
+    }
+    let tag = raw[MAGIC.len()];
+    let start = match tag & EXPIRES {
+        0 => HEADER_LEN,
+        _ => HEADER_LEN + DEADLINE_LEN,
+    };
+    match ValueType::from_tag(tag & !FLAGS) {
+        Some(_) if raw.len() < start => Err(BitCaskError::CorruptedData(
+            "truncated value deadline".to_string(),
+        )),
+        Some(stored) if stored == value_type => Ok(&raw[start..]),
+        Some(_) => Err(wrong_type()),
+        None => Err(BitCaskError::CorruptedData(
+            "unknown value type".to_string(),
+        )),
+    }
+}
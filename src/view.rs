@@ -0,0 +1,151 @@
+//! Read views: SNAPSHOT OPEN pins the keyspace of the node as of an entry of the log, and the
+//! GET, MGETSNAPSHOT and KEYS of the connection read it until SNAPSHOT CLOSE, so that an
+//! application reads related keys in several steps, all as of one point, without a transaction.
+//! The other reads of the keyspace, such as GETRANGE, LRANGE or DUMP, fail meanwhile rather than
+//! read the live keyspace; writes run as usual.
+//!
+//! A view is a snapshot of the data files (see `keyspace.rs`) taken while no entry is applied,
+//! its live keys indexed once. It is opened once the earlier commands of the connection are
+//! replied to, so it sees their writes, and the values are read from the files as asked for. It
+//! keeps the files a compaction leaves behind until it is dropped, so it lasts
+//! --read-view-max-secs at most; past that, the reads of the connection fail until it closes the
+//! view, rather than quietly reading the live keyspace again.
+
+use crate::cmd::InnerCmd;
+use crate::database;
+use crate::keyspace::{self, Pinned, Snapshot};
+use crate::pubsub::glob_match;
+use crate::resp_codec::RespValue;
+use crate::sync_layer;
+use crate::value;
+use bitcask_engine_rs::bitcask::BitCask;
+use bitcask_engine_rs::error::BitCaskError;
+use bytes::Bytes;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Replied to the reads of a connection whose view expired or could not be opened
+pub(crate) const EXPIRED: &str = "ERR the read view of the connection expired or could not be opened, SNAPSHOT CLOSE it or SNAPSHOT OPEN another";
+
+// handles are unique on the node, so that a client closing a view it no longer has notices
+static HANDLES: AtomicU64 = AtomicU64::new(1);
+
+/// The read view a connection opened
+pub(crate) struct View {
+    pub(crate) handle: u64,
+    // when the view is dropped, None once it is
+    deadline: Option<Instant>,
+    opened: Slot,
+}
+
+impl View {
+    pub(crate) fn new(lifetime: Duration) -> Self {
+        Self {
+            handle: HANDLES.fetch_add(1, Ordering::Relaxed),
+            deadline: Some(Instant::now() + lifetime),
+            opened: Slot::default(),
+        }
+    }
+
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Where the view is, for the replies computed once the earlier ones are
+    pub(crate) fn slot(&self) -> Slot {
+        self.opened.clone()
+    }
+
+    /// Drop the snapshot, releasing its files; the reads fail until the view is closed
+    pub(crate) fn expire(&mut self) {
+        self.deadline = None;
+        self.opened.0.lock().unwrap().take();
+    }
+}
+
+/// The snapshot of a view, set once it is opened and emptied once it expires
+#[derive(Clone, Default)]
+pub(crate) struct Slot(Arc<Mutex<Option<Arc<Opened>>>>);
+
+impl Slot {
+    /// Pin the keyspace the node applied so far, returning the entries it applied
+    pub(crate) fn open(&self, storage: &BitCask, data_dir: &Path) -> std::io::Result<u64> {
+        let (snapshot, index) = {
+            // no entry is applied while the files are frozen, they are as of the index recorded
+            let _writing = keyspace::writing();
+            (
+                Snapshot::take(data_dir)?,
+                sync_layer::applied_index(storage),
+            )
+        };
+        let opened = Opened {
+            keys: snapshot.pin()?,
+            index,
+            now: value::now(),
+        };
+        *self.0.lock().unwrap() = Some(Arc::new(opened));
+        Ok(index)
+    }
+
+    /// The view, unless it expired or could not be opened
+    pub(crate) fn get(&self) -> Option<Arc<Opened>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+pub(crate) struct Opened {
+    keys: Pinned,
+    // the entries the node applied when the view was opened
+    index: u64,
+    // the unix time in milliseconds the values expire as of
+    now: u64,
+}
+
+impl Opened {
+    /// Run a read of the connection, its keys as stored, against the view
+    pub(crate) fn read(&self, inner_cmd: &InnerCmd, db: u32) -> Result<RespValue, BitCaskError> {
+        match inner_cmd {
+            InnerCmd::Get(_, key, _) => Ok(RespValue::BulkString(match self.get(key)? {
+                Some(raw) => Some(value::string_bytes(raw)?),
+                None => None,
+            })),
+            InnerCmd::MGetSnapshot(_, keys) => {
+                let mut values = Vec::with_capacity(keys.len());
+                for key in keys {
+                    // nil for a value of another type, as MGET
+                    let value = self.get(key)?.and_then(|raw| value::string_bytes(raw).ok());
+                    values.push(RespValue::BulkString(value));
+                }
+                Ok(RespValue::Array(vec![
+                    RespValue::Integer(self.index as i64),
+                    RespValue::Array(values),
+                ]))
+            }
+            InnerCmd::Keys(_, pattern) => {
+                let mut keys = Vec::new();
+                for stored in self.keys.keys() {
+                    let Some(key) = database::of(db, stored) else {
+                        continue;
+                    };
+                    // the values are read for their deadline
+                    if glob_match(pattern, key) && self.get(stored)?.is_some() {
+                        keys.push(RespValue::BulkString(Some(Bytes::copy_from_slice(key))));
+                    }
+                }
+                Ok(RespValue::Array(keys))
+            }
+            _ => panic!("Command is not read from views"),
+        }
+    }
+
+    /// The value of the key, unless it expired when the view was opened
+    fn get(&self, key: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self
+            .keys
+            .get(key)?
+            .filter(|raw| !value::expired(raw, self.now)))
+    }
+}
@@ -0,0 +1,76 @@
+//! WAIT: block until the previous writes of the client are known to be held by enough other
+//! nodes, for the writes an application wants kept through more failures than usual.
+//!
+//! A write is answered once its entry is committed, so a majority of the nodes, this one
+//! included, already hold it. raft-lite does not tell which peers acknowledged which entries, so
+//! the node learns more by asking the peers reachable on `--peer-kv-addr` how many entries they
+//! applied, as in `INFO raft`: entries are numbered alike on every node, as raft-lite sends its
+//! whole log to a node that restarts.
+
+use crate::admin::{self, Peer};
+use crate::resp_codec::{convert_bulk_string_to_string, RespValue};
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+/// Pause between two rounds of asking the peers
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Wait until `wanted` other nodes are known to hold the entries up to the index, or until the
+/// deadline if any, returning how many are
+pub(crate) async fn wait(
+    nodes: usize,
+    peers: &[Peer],
+    credentials: Option<&(String, String)>,
+    index: u64,
+    wanted: usize,
+    deadline: Option<Instant>,
+) -> usize {
+    // with nothing written every node holds it all, otherwise the commit reached a majority
+    let mut replicas = if index == 0 { nodes - 1 } else { nodes / 2 };
+    // without peers to ask, no more can be known
+    while replicas < wanted && !peers.is_empty() {
+        let round = applied_by(peers, credentials, index);
+        let applied = match deadline {
+            Some(deadline) => match timeout_at(deadline, round).await {
+                Ok(applied) => applied,
+                Err(_) => break,
+            },
+            None => round.await,
+        };
+        replicas = replicas.max(applied);
+        if replicas >= wanted {
+            break;
+        }
+        let next = Instant::now() + POLL_INTERVAL;
+        match deadline {
+            Some(deadline) if deadline <= next => break,
+            _ => tokio::time::sleep_until(next).await,
+        }
+    }
+    replicas
+}
+
+/// Peers that applied the entries up to the index
+async fn applied_by(peers: &[Peer], credentials: Option<&(String, String)>, index: u64) -> usize {
+    let args = [b"INFO".to_vec(), b"raft".to_vec()];
+    futures::future::join_all(
+        peers
+            .iter()
+            .map(|peer| admin::call_peer(peer, credentials, &args)),
+    )
+    .await
+    .into_iter()
+    .filter(|reply| applied_entries(reply).is_some_and(|applied| applied >= index))
+    .count()
+}
+
+/// The entries applied by a node, from its reply to `INFO raft`
+fn applied_entries(reply: &RespValue) -> Option<u64> {
+    let RespValue::BulkString(info) = reply else {
+        return None;
+    };
+    convert_bulk_string_to_string(info.clone())
+        .lines()
+        .find_map(|line| line.strip_prefix("raft_entries_applied:"))
+        .and_then(|applied| applied.parse().ok())
+}
@@ -0,0 +1,172 @@
+use crate::list::index_range;
+use crate::value::{self, ValueType};
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage};
+use bitcask_engine_rs::error::BitCaskError;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// A sorted set is stored as a single bincode-encoded value under its key: the members ordered
+/// by (score, member), as Redis orders them. The ordering is maintained in the apply path, so
+/// range queries can be answered from any replica by slicing the decoded vector.
+type ZSet = Vec<(f64, Vec<u8>)>;
+
+/// One end of a ZRANGEBYSCORE interval
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct ScoreBound {
+    pub(crate) score: f64,
+    pub(crate) exclusive: bool,
+}
+
+impl ScoreBound {
+    /// Parse `1.5`, `(1.5`, `-inf` or `+inf`
+    pub(crate) fn parse(s: &str) -> anyhow::Result<Self> {
+        let (exclusive, s) = match s.strip_prefix('(') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let score = parse_score(s)?;
+        Ok(Self { score, exclusive })
+    }
+
+    fn below(&self, score: f64) -> bool {
+        if self.exclusive {
+            self.score < score
+        } else {
+            self.score <= score
+        }
+    }
+
+    fn above(&self, score: f64) -> bool {
+        if self.exclusive {
+            score < self.score
+        } else {
+            score <= self.score
+        }
+    }
+}
+
+pub(crate) fn parse_score(s: &str) -> anyhow::Result<f64> {
+    match s.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => Err(anyhow::anyhow!("Value is not a valid float")),
+    }
+}
+
+fn compare(a: &(f64, Vec<u8>), b: &(f64, Vec<u8>)) -> Ordering {
+    a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1))
+}
+
+/// The sorted set with the deadline it expires at, if any
+fn load(storage: &BitCask, key: &Vec<u8>, now: u64) -> Result<(ZSet, Option<u64>), BitCaskError> {
+    // an expired value is no longer the key's, whatever its type
+    match storage.get(key).filter(|raw| !value::expired(raw, now)) {
+        Some(raw) => bincode::deserialize::<ZSet>(value::expect(&raw, ValueType::ZSet)?)
+            .map(|zset| (zset, value::deadline(&raw)))
+            .map_err(|_| BitCaskError::CorruptedData("value is not a sorted set".to_string())),
+        None => Ok((ZSet::new(), None)),
+    }
+}
+
+/// Store the sorted set, keeping the deadline it was loaded with
+fn store(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    zset: &ZSet,
+    deadline: Option<u64>,
+) -> Result<(), BitCaskError> {
+    // Redis semantics: a sorted set that becomes empty is removed
+    if zset.is_empty() {
+        return storage.delete(key);
+    }
+    let payload = bincode::serialize(zset).map_err(|e| BitCaskError::UnexpectedError(e.into()))?;
+    storage.put(
+        key,
+        &value::encode_expiring(ValueType::ZSet, &payload, deadline),
+    )
+}
+
+/// Add members or update their scores, returning the number of newly added members.
+pub(crate) fn add(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    members: &[(f64, Vec<u8>)],
+    now: u64,
+) -> Result<usize, BitCaskError> {
+    let (mut zset, deadline) = load(storage, key, now)?;
+    let mut added = 0;
+    for (score, member) in members {
+        match zset.iter().position(|(_, m)| m == member) {
+            Some(pos) => {
+                zset.remove(pos);
+            }
+            None => added += 1,
+        }
+        let entry = (*score, member.clone());
+        let pos = zset
+            .binary_search_by(|probe| compare(probe, &entry))
+            .unwrap_or_else(|pos| pos);
+        zset.insert(pos, entry);
+    }
+    store(storage, key, &zset, deadline)?;
+    Ok(added)
+}
+
+/// Remove members, returning the number of members actually removed.
+pub(crate) fn remove(
+    storage: &mut BitCask,
+    key: &Vec<u8>,
+    members: &[Vec<u8>],
+    now: u64,
+) -> Result<usize, BitCaskError> {
+    let (mut zset, deadline) = load(storage, key, now)?;
+    let len = zset.len();
+    zset.retain(|(_, m)| !members.contains(m));
+    let removed = len - zset.len();
+    if removed > 0 {
+        store(storage, key, &zset, deadline)?;
+    }
+    Ok(removed)
+}
+
+pub(crate) fn score(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    member: &Vec<u8>,
+    now: u64,
+) -> Result<Option<f64>, BitCaskError> {
+    Ok(load(storage, key, now)?
+        .0
+        .into_iter()
+        .find(|(_, m)| m == member)
+        .map(|(score, _)| score))
+}
+
+/// Return the members between ranks `start` and `stop` (both inclusive), lowest score first.
+pub(crate) fn range(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    start: i64,
+    stop: i64,
+    now: u64,
+) -> Result<ZSet, BitCaskError> {
+    let (zset, _) = load(storage, key, now)?;
+    match index_range(zset.len(), start, stop) {
+        Some((start, stop)) => Ok(zset[start..=stop].to_vec()),
+        None => Ok(vec![]),
+    }
+}
+
+/// Return the members with a score between `min` and `max`, lowest score first.
+pub(crate) fn range_by_score(
+    storage: &BitCask,
+    key: &Vec<u8>,
+    min: ScoreBound,
+    max: ScoreBound,
+    now: u64,
+) -> Result<ZSet, BitCaskError> {
+    Ok(load(storage, key, now)?
+        .0
+        .into_iter()
+        .filter(|(score, _)| min.below(*score) && max.above(*score))
+        .collect())
+}
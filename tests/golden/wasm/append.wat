@@ -0,0 +1,13 @@
+(module
+  (import "storgata" "get" (func $get (param i32 i32) (result i32)))
+  (import "storgata" "put" (func $put (param i32 i32)))
+  (import "storgata" "arg_read" (func $arg_read (param i32 i32) (result i32)))
+  (import "storgata" "reply_int" (func $reply_int (param i64)))
+  (memory (export "memory") 1)
+  (func (export "run") (local $len i32)
+    (local.set $len (call $get (i32.const 0) (i32.const 1024)))
+    (if (i32.lt_s (local.get $len) (i32.const 0)) (then (local.set $len (i32.const 0))))
+    (local.set $len
+      (i32.add (local.get $len) (call $arg_read (i32.const 0) (local.get $len))))
+    (call $put (i32.const 0) (local.get $len))
+    (call $reply_int (i64.extend_i32_u (local.get $len)))))
@@ -0,0 +1,4 @@
+(module
+  (import "env" "clock" (func $clock (result i64)))
+  (memory (export "memory") 1)
+  (func (export "run")))
@@ -0,0 +1,4 @@
+(module
+  (import "storgata" "del" (func $del))
+  (memory (export "memory") 1)
+  (func (export "run") (call $del) (loop $forever (br $forever))))